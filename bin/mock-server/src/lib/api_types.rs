@@ -35,6 +35,8 @@ pub struct InstanceSerialParams {
     /// recently buffered data retrieved from the instance. (See note on `from_start` about mutual
     /// exclusivity)
     pub most_recent: Option<u64>,
+    /// The serial port to connect to. Defaults to COM1.
+    pub port: Option<types::SerialPortNumber>,
 }
 
 #[derive(JsonSchema, Serialize, Deserialize)]
@@ -51,4 +53,6 @@ pub struct InstanceSerialHistoryParams {
     /// range runs to the end of the available buffer, the data returned will be shorter than
     /// `max_bytes`.
     pub max_bytes: Option<u64>,
+    /// The serial port whose history to read. Defaults to COM1.
+    pub port: Option<types::SerialPortNumber>,
 }
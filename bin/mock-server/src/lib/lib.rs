@@ -109,7 +109,8 @@ impl InstanceContext {
                         })
                         .map_err(|_| Error::TransitionSendFail)
                 }
-                api::InstanceStateRequested::Stop => {
+                api::InstanceStateRequested::Stop
+                | api::InstanceStateRequested::SoftStop => {
                     self.state = api::InstanceState::Stopped;
                     self.serial_task.shutdown().await;
                     Ok(())
@@ -329,6 +330,10 @@ async fn instance_serial(
             let serial = instance_ctx.serial.clone();
 
             let query_params = query.into_inner();
+            if !is_mock_serial_port(query_params.port) {
+                ws_stream.send(Message::Close(None)).await?;
+                return Err("Mock instance only has a COM1 console".into());
+            }
             let history_query = serial::HistoryQuery::from_query(
                 query_params.from_start,
                 query_params.most_recent,
@@ -359,6 +364,10 @@ async fn instance_serial_history_get(
 ) -> Result<HttpResponseOk<api::InstanceSerialConsoleHistoryResponse>, HttpError>
 {
     let query_params = query.into_inner();
+    if !is_mock_serial_port(query_params.port) {
+        let msg = "Mock instance only has a COM1 console".to_string();
+        return Err(HttpError::for_not_found(Some(msg.clone()), msg));
+    }
 
     let history_query = serial::HistoryQuery::from_query(
         query_params.from_start,
@@ -393,6 +402,13 @@ async fn instance_serial_history_get(
     }))
 }
 
+/// The mock instance only has a serial console on COM1.
+fn is_mock_serial_port(
+    port: Option<api_types::types::SerialPortNumber>,
+) -> bool {
+    matches!(port, None | Some(api_types::types::SerialPortNumber::Com1))
+}
+
 mod serial {
     use std::sync::atomic::{AtomicBool, Ordering};
     use std::sync::Arc;
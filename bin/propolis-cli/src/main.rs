@@ -13,7 +13,7 @@ use std::{
 
 use anyhow::{anyhow, Context};
 use clap::{Parser, Subcommand};
-use futures::{future, SinkExt};
+use futures::{future, SinkExt, StreamExt};
 use newtype_uuid::{GenericUuid, TypedUuid, TypedUuidKind, TypedUuidTag};
 use propolis_client::types::InstanceMetadata;
 use slog::{o, Drain, Level, Logger};
@@ -28,12 +28,15 @@ use propolis_client::{
     support::{InstanceSerialConsoleHelper, WSClientOffset},
     types::{
         DiskRequest, InstanceEnsureRequest, InstanceMigrateInitiateRequest,
-        InstanceProperties, InstanceStateRequested, InstanceVcrReplace,
-        MigrationState,
+        InstanceProperties, InstanceSpecEnsureRequest, InstanceSpecV0,
+        InstanceStateRequested, InstanceVcrReplace, MigrationState,
+        NicCaptureStartRequest, VersionedInstanceSpec,
     },
     Client,
 };
 
+mod spec;
+
 #[derive(Debug, Parser)]
 #[clap(about, version)]
 /// A simple CLI tool to manipulate propolis-server
@@ -82,6 +85,17 @@ enum Command {
         #[clap(long, action)]
         cloud_init: Option<PathBuf>,
 
+        /// File describing the instance's devices, from which a full instance
+        /// spec is built and submitted: either a JSON InstanceSpecV0 (with a
+        /// `.json` extension), or a propolis-server config TOML whose devices
+        /// are attached to a board with the requested vCPUs and memory.
+        #[clap(
+            long,
+            action,
+            conflicts_with_all = ["crucible_disks", "cloud_init"]
+        )]
+        spec: Option<PathBuf>,
+
         /// A UUID to use for the instance's silo, attached to instance metrics.
         #[clap(long)]
         silo_id: Option<TypedUuid<SiloKind>>,
@@ -130,6 +144,23 @@ enum Command {
         byte_offset: Option<i64>,
     },
 
+    /// Save a screenshot of the instance's display as a PNG file
+    ///
+    /// Unlike a VNC session, this does not displace any connected VNC client.
+    Screenshot {
+        /// Path of the file to write
+        #[clap(short, long, action)]
+        output: PathBuf,
+
+        /// Maximum width of the screenshot, in pixels
+        #[clap(long)]
+        max_width: Option<u16>,
+
+        /// Maximum height of the screenshot, in pixels
+        #[clap(long)]
+        max_height: Option<u16>,
+    },
+
     /// Migrate instance to new propolis-server
     Migrate {
         /// Destination propolis-server address
@@ -165,6 +196,47 @@ enum Command {
         #[clap(long, action)]
         vcr_replace: PathBuf,
     },
+
+    /// Capture the packets sent and received by one of the instance's NICs
+    NicCapture {
+        /// The name of the NIC in the instance spec
+        #[clap(action)]
+        nic: String,
+
+        #[clap(subcommand)]
+        cmd: NicCaptureCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum NicCaptureCommand {
+    /// Start a new capture, discarding any previous one
+    Start {
+        /// Maximum size of the capture file in bytes (default 4 MiB)
+        #[clap(long)]
+        max_bytes: Option<u64>,
+
+        /// Maximum number of packets to capture
+        #[clap(long)]
+        max_packets: Option<u64>,
+
+        /// Number of bytes of each packet to capture (default 65535)
+        #[clap(long)]
+        snaplen: Option<u32>,
+    },
+
+    /// Show the progress of the most recent capture
+    Status,
+
+    /// Stop the running capture
+    Stop,
+
+    /// Download the most recent capture as a pcapng file
+    Fetch {
+        /// Path of the file to write
+        #[clap(short, long, action)]
+        output: PathBuf,
+    },
 }
 
 fn parse_state(state: &str) -> anyhow::Result<InstanceStateRequested> {
@@ -172,8 +244,10 @@ fn parse_state(state: &str) -> anyhow::Result<InstanceStateRequested> {
         "run" => Ok(InstanceStateRequested::Run),
         "stop" => Ok(InstanceStateRequested::Stop),
         "reboot" => Ok(InstanceStateRequested::Reboot),
+        "soft-stop" => Ok(InstanceStateRequested::SoftStop),
         _ => Err(anyhow!(
-            "invalid requested state, must be one of: 'run', 'stop', 'reboot"
+            "invalid requested state, must be one of: 'run', 'stop', 'reboot', \
+            'soft-stop'"
         )),
     }
 }
@@ -281,6 +355,44 @@ async fn new_instance(
     Ok(())
 }
 
+async fn new_instance_from_spec(
+    client: &Client,
+    name: String,
+    id: Uuid,
+    spec: InstanceSpecV0,
+    metadata: InstanceMetadata,
+) -> anyhow::Result<()> {
+    let properties = InstanceProperties {
+        id,
+        name,
+        description: "propolis-cli generated instance".to_string(),
+        metadata,
+        // TODO: Use real UUID
+        image_id: Uuid::default(),
+        // TODO: Use real UUID
+        bootrom_id: Uuid::default(),
+        memory: spec.devices.board.memory_mb,
+        vcpus: spec.devices.board.cpus,
+    };
+
+    let request = InstanceSpecEnsureRequest {
+        properties,
+        instance_spec: VersionedInstanceSpec::V0(spec),
+        migrate: None,
+        restore: None,
+    };
+
+    // Try to create the instance
+    client
+        .instance_spec_ensure()
+        .body(request)
+        .send()
+        .await
+        .with_context(|| anyhow!("failed to create instance from spec"))?;
+
+    Ok(())
+}
+
 async fn replace_vcr(
     client: &Client,
     id: Uuid,
@@ -640,6 +752,96 @@ async fn inject_nmi(client: &Client) -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn screenshot(
+    client: &Client,
+    output: &Path,
+    max_width: Option<u16>,
+    max_height: Option<u16>,
+) -> anyhow::Result<()> {
+    let mut req = client.instance_screenshot();
+    if let Some(width) = max_width {
+        req = req.max_width(width);
+    }
+    if let Some(height) = max_height {
+        req = req.max_height(height);
+    }
+
+    let mut stream = req
+        .send()
+        .await
+        .with_context(|| anyhow!("failed to take screenshot"))?
+        .into_inner()
+        .into_inner();
+    let mut file = tokio::fs::File::create(output)
+        .await
+        .with_context(|| anyhow!("failed to create {output:?}"))?;
+    while let Some(chunk) = stream.next().await {
+        file.write_all(&chunk?).await?;
+    }
+    file.flush().await?;
+
+    Ok(())
+}
+
+async fn nic_capture(
+    client: &Client,
+    nic: &str,
+    cmd: NicCaptureCommand,
+) -> anyhow::Result<()> {
+    match cmd {
+        NicCaptureCommand::Start { max_bytes, max_packets, snaplen } => {
+            client
+                .instance_nic_capture_start()
+                .name(nic)
+                .body(NicCaptureStartRequest {
+                    max_bytes,
+                    max_packets,
+                    snaplen,
+                })
+                .send()
+                .await
+                .with_context(|| anyhow!("failed to start capture"))?;
+        }
+        NicCaptureCommand::Status => {
+            let status = client
+                .instance_nic_capture_status()
+                .name(nic)
+                .send()
+                .await
+                .with_context(|| anyhow!("failed to get capture status"))?;
+            println!("{:#?}", status.into_inner());
+        }
+        NicCaptureCommand::Stop => {
+            let status = client
+                .instance_nic_capture_stop()
+                .name(nic)
+                .send()
+                .await
+                .with_context(|| anyhow!("failed to stop capture"))?;
+            println!("{:#?}", status.into_inner());
+        }
+        NicCaptureCommand::Fetch { output } => {
+            let mut stream = client
+                .instance_nic_capture_get()
+                .name(nic)
+                .send()
+                .await
+                .with_context(|| anyhow!("failed to fetch capture"))?
+                .into_inner()
+                .into_inner();
+            let mut file = tokio::fs::File::create(&output)
+                .await
+                .with_context(|| anyhow!("failed to create {output:?}"))?;
+            while let Some(chunk) = stream.next().await {
+                file.write_all(&chunk?).await?;
+            }
+            file.flush().await?;
+        }
+    }
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opt = Opt::parse();
@@ -656,6 +858,7 @@ async fn main() -> anyhow::Result<()> {
             memory,
             crucible_disks,
             cloud_init,
+            spec: spec_path,
             silo_id,
             project_id,
             sled_id,
@@ -663,19 +866,6 @@ async fn main() -> anyhow::Result<()> {
             sled_revision,
             sled_serial,
         } => {
-            let disks = if let Some(crucible_disks) = crucible_disks {
-                parse_json_file(&crucible_disks)?
-            } else {
-                vec![]
-            };
-            let cloud_init_bytes = if let Some(cloud_init) = cloud_init {
-                Some(base64::Engine::encode(
-                    &base64::engine::general_purpose::STANDARD,
-                    std::fs::read(cloud_init)?,
-                ))
-            } else {
-                None
-            };
             let metadata = InstanceMetadata {
                 project_id: project_id
                     .unwrap_or_else(TypedUuid::new_v4)
@@ -690,23 +880,47 @@ async fn main() -> anyhow::Result<()> {
                 sled_revision,
                 sled_serial,
             };
-            new_instance(
-                &client,
-                name.to_string(),
-                uuid.unwrap_or_else(Uuid::new_v4),
-                vcpus,
-                memory,
-                disks,
-                cloud_init_bytes,
-                metadata,
-            )
-            .await?
+            let id = uuid.unwrap_or_else(Uuid::new_v4);
+
+            if let Some(spec_path) = spec_path {
+                let spec = spec::load(&spec_path, vcpus, memory)?;
+                new_instance_from_spec(&client, name, id, spec, metadata)
+                    .await?
+            } else {
+                let disks = if let Some(crucible_disks) = crucible_disks {
+                    parse_json_file(&crucible_disks)?
+                } else {
+                    vec![]
+                };
+                let cloud_init_bytes = if let Some(cloud_init) = cloud_init {
+                    Some(base64::Engine::encode(
+                        &base64::engine::general_purpose::STANDARD,
+                        std::fs::read(cloud_init)?,
+                    ))
+                } else {
+                    None
+                };
+                new_instance(
+                    &client,
+                    name.to_string(),
+                    id,
+                    vcpus,
+                    memory,
+                    disks,
+                    cloud_init_bytes,
+                    metadata,
+                )
+                .await?
+            }
         }
         Command::Get => get_instance(&client).await?,
         Command::State { state } => put_instance(&client, state).await?,
         Command::Serial { byte_offset } => {
             serial(addr, byte_offset, log).await?
         }
+        Command::Screenshot { output, max_width, max_height } => {
+            screenshot(&client, &output, max_width, max_height).await?
+        }
         Command::Migrate { dst_server, dst_port, dst_uuid, crucible_disks } => {
             let dst_addr = SocketAddr::new(dst_server, dst_port);
             let dst_client = Client::new(&format!("http://{dst_addr}"));
@@ -724,6 +938,9 @@ async fn main() -> anyhow::Result<()> {
             let replace: InstanceVcrReplace = parse_json_file(&vcr_replace)?;
             replace_vcr(&client, uuid, replace).await?
         }
+        Command::NicCapture { nic, cmd } => {
+            nic_capture(&client, &nic, cmd).await?
+        }
     }
 
     Ok(())
@@ -0,0 +1,181 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Authoring instance specs to submit via the `/instance/spec` endpoint.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use propolis_client::instance_spec::SpecBuilderV0;
+use propolis_client::types::{
+    Chipset, InstanceSpecV0, NetworkDeviceV0, StorageDeviceV0,
+};
+
+/// Reads an instance spec from the description in `path`, which is either:
+///
+/// - a JSON file (with a `.json` extension) holding a complete
+///   `InstanceSpecV0`, or
+/// - a config TOML in the format accepted by propolis-server, whose devices
+///   are attached to a board with `vcpus` vCPUs and `memory` MiB of memory.
+///
+/// The resulting spec is checked for conflicting devices before it is
+/// returned.
+pub(crate) fn load(
+    path: &Path,
+    vcpus: u8,
+    memory: u64,
+) -> anyhow::Result<InstanceSpecV0> {
+    let spec = if path.extension().map_or(false, |ext| ext == "json") {
+        crate::parse_json_file(path)
+            .with_context(|| format!("failed to parse spec {path:?}"))?
+    } else {
+        let config = propolis_server_config::parse(path)
+            .with_context(|| format!("failed to parse config {path:?}"))?;
+        let spec =
+            propolis_config_toml::spec_from_config(vcpus, memory, &config)
+                .with_context(|| format!("invalid config {path:?}"))?;
+
+        // The API's spec types and the client's generated ones share a
+        // JSON representation, which is used to convert between them.
+        serde_json::from_value(serde_json::to_value(spec)?)?
+    };
+
+    validate(&spec)?;
+    Ok(spec)
+}
+
+/// Checks `spec` for the errors the server would reject it for: devices with
+/// conflicting names, PCI paths or serial ports, and devices whose backends
+/// are missing.
+fn validate(spec: &InstanceSpecV0) -> anyhow::Result<()> {
+    let devices = &spec.devices;
+    let backends = &spec.backends;
+
+    let Chipset::I440Fx(i440fx) = &devices.board.chipset;
+    let mut builder = SpecBuilderV0::new(
+        devices.board.cpus,
+        devices.board.memory_mb,
+        i440fx.enable_pcie,
+    );
+
+    for (name, device) in devices.storage_devices.iter() {
+        let backend_name = match device {
+            StorageDeviceV0::VirtioDisk(disk) => &disk.backend_name,
+            StorageDeviceV0::NvmeDisk(disk) => &disk.backend_name,
+        };
+        let backend =
+            backends.storage_backends.get(backend_name).ok_or_else(|| {
+                anyhow!(
+                    "storage device {name:?} has no backend {backend_name:?}"
+                )
+            })?;
+        builder.add_storage_device(
+            name.clone(),
+            device.clone(),
+            backend_name.clone(),
+            backend.clone(),
+        )?;
+    }
+
+    for (name, device) in devices.network_devices.iter() {
+        let backend_name = match device {
+            NetworkDeviceV0::VirtioNic(nic) => &nic.backend_name,
+        };
+        let backend =
+            backends.network_backends.get(backend_name).ok_or_else(|| {
+                anyhow!(
+                    "network device {name:?} has no backend {backend_name:?}"
+                )
+            })?;
+        builder.add_network_device(
+            name.clone(),
+            device.clone(),
+            backend_name.clone(),
+            backend.clone(),
+        )?;
+    }
+
+    for (name, bridge) in devices.pci_pci_bridges.iter() {
+        builder.add_pci_bridge(name.clone(), bridge.clone())?;
+    }
+
+    for port in devices.serial_ports.values() {
+        builder.add_serial_port(port.num)?;
+    }
+
+    if let Some(p9fs) = &devices.p9fs {
+        builder.set_p9fs(p9fs.clone())?;
+    }
+
+    for (name, fs) in devices.virtio_fs.iter() {
+        builder.add_virtio_fs(name.clone(), fs.clone())?;
+    }
+
+    if let Some(clipboard) = &devices.clipboard {
+        builder.set_clipboard(clipboard.clone())?;
+    }
+
+    for (name, serial) in devices.virtio_serial.iter() {
+        builder.add_virtio_serial(name.clone(), serial.clone())?;
+    }
+
+    if let Some(agent) = &devices.guest_agent {
+        builder.set_guest_agent(agent.clone())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use propolis_client::types::{
+        FileStorageBackend, NvmeDisk, PciPath, PciPciBridge, StorageBackendV0,
+    };
+
+    fn spec_with_disk() -> InstanceSpecV0 {
+        let mut builder = SpecBuilderV0::new(2, 1024, false);
+        builder
+            .add_storage_device(
+                "disk0".to_string(),
+                StorageDeviceV0::NvmeDisk(NvmeDisk {
+                    backend_name: "disk0_be".to_string(),
+                    pci_path: PciPath::new(0, 5, 0).unwrap(),
+                }),
+                "disk0_be".to_string(),
+                StorageBackendV0::File(FileStorageBackend {
+                    path: "/path/to/disk.img".to_string(),
+                    readonly: false,
+                }),
+            )
+            .unwrap();
+        builder.finish()
+    }
+
+    #[test]
+    fn valid_spec() {
+        assert!(validate(&spec_with_disk()).is_ok());
+    }
+
+    #[test]
+    fn missing_backend() {
+        let mut spec = spec_with_disk();
+        spec.backends.storage_backends.clear();
+        assert!(validate(&spec).is_err());
+    }
+
+    #[test]
+    fn conflicting_pci_path() {
+        let mut spec = spec_with_disk();
+        spec.devices.pci_pci_bridges.insert(
+            "bridge0".to_string(),
+            PciPciBridge {
+                downstream_bus: 1,
+                pci_path: PciPath::new(0, 5, 0).unwrap(),
+            },
+        );
+        assert!(validate(&spec).is_err());
+    }
+}
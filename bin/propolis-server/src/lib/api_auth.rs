@@ -0,0 +1,295 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Authentication of API clients and of live migration peers.
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use dropshot::HttpError;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::config;
+
+/// Mutual TLS configuration for the migration channel between two servers.
+pub(crate) struct MigrationTls {
+    /// Accepts connections from migration destinations, requiring them to
+    /// present a certificate signed by the configured authority.
+    pub(crate) acceptor: TlsAcceptor,
+
+    /// Connects to migration sources, presenting this server's certificate.
+    pub(crate) connector: TlsConnector,
+}
+
+/// Authentication required of API clients, and used between servers during
+/// live migration
+#[derive(Default)]
+pub struct ApiAuth {
+    /// Bearer token which API clients must present with every request
+    token: Option<String>,
+
+    /// Client configuration for reaching a migration source's API over TLS,
+    /// if this server's own API is served that way
+    source_api_tls: Option<TlsConnector>,
+
+    /// Mutual TLS for migration connections
+    migration_tls: Option<MigrationTls>,
+}
+
+impl ApiAuth {
+    /// Loads the token and certificates named by `api` and `migration`.
+    pub fn load(
+        api: &config::Api,
+        migration: &config::Migration,
+    ) -> io::Result<Self> {
+        let token = api.token_file.as_deref().map(read_secret).transpose()?;
+
+        let roots = match &migration.ca_cert {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(path)? {
+                    roots.add(cert).map_err(invalid_data)?;
+                }
+                Some(Arc::new(roots))
+            }
+            None => None,
+        };
+
+        let source_api_tls = match (&api.tls_cert, &api.tls_key) {
+            // The API certificate itself is loaded by dropshot, but migration
+            // sources are assumed to serve their APIs the same way this server
+            // does, and need to be verified.
+            (Some(_), Some(_)) => {
+                let roots = roots.clone().ok_or_else(|| {
+                    invalid_input(
+                        "serving the API over TLS requires migration.ca_cert, \
+                        to verify the APIs of migration sources",
+                    )
+                })?;
+                let config = ClientConfig::builder()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+                Some(TlsConnector::from(Arc::new(config)))
+            }
+            (None, None) => None,
+            _ => {
+                return Err(invalid_input(
+                    "API TLS requires both a certificate and a key",
+                ));
+            }
+        };
+
+        let migration_tls = match (&migration.cert, &migration.key) {
+            (Some(cert), Some(key)) => {
+                let roots = roots.ok_or_else(|| {
+                    invalid_input(
+                        "migration TLS requires migration.ca_cert, to verify \
+                        migration peers",
+                    )
+                })?;
+                let certs = load_certs(cert)?;
+                let key = load_private_key(key)?;
+
+                let verifier = WebPkiClientVerifier::builder(roots.clone())
+                    .build()
+                    .map_err(invalid_data)?;
+                let server = ServerConfig::builder()
+                    .with_client_cert_verifier(verifier)
+                    .with_single_cert(certs.clone(), key.clone_key())
+                    .map_err(invalid_data)?;
+                let client = ClientConfig::builder()
+                    .with_root_certificates(roots)
+                    .with_client_auth_cert(certs, key)
+                    .map_err(invalid_data)?;
+
+                Some(MigrationTls {
+                    acceptor: TlsAcceptor::from(Arc::new(server)),
+                    connector: TlsConnector::from(Arc::new(client)),
+                })
+            }
+            (None, None) => None,
+            _ => {
+                return Err(invalid_input(
+                    "migration TLS requires both a certificate and a key",
+                ));
+            }
+        };
+
+        Ok(Self { token, source_api_tls, migration_tls })
+    }
+
+    /// Rejects requests whose `Authorization` header does not carry the
+    /// required bearer token, if any.
+    pub(crate) fn authorize(
+        &self,
+        headers: &http::HeaderMap,
+    ) -> Result<(), HttpError> {
+        let authorization = headers
+            .get(http::header::AUTHORIZATION)
+            .and_then(|hdr| hdr.to_str().ok());
+        if check_bearer_token(self.token.as_deref(), authorization) {
+            Ok(())
+        } else {
+            Err(HttpError::for_client_error(
+                None,
+                http::StatusCode::UNAUTHORIZED,
+                "missing or invalid bearer token".to_string(),
+            ))
+        }
+    }
+
+    /// The bearer token this server requires, which it also presents to
+    /// migration sources that do not authenticate it with TLS.
+    pub(crate) fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub(crate) fn source_api_tls(&self) -> Option<&TlsConnector> {
+        self.source_api_tls.as_ref()
+    }
+
+    pub(crate) fn migration_tls(&self) -> Option<&MigrationTls> {
+        self.migration_tls.as_ref()
+    }
+}
+
+/// Checks an `Authorization` header against the bearer token `token`, if any.
+pub(crate) fn check_bearer_token(
+    token: Option<&str>,
+    authorization: Option<&str>,
+) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    let Some(presented) =
+        authorization.and_then(|hdr| hdr.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+
+    // Compare every byte, so that the time taken does not reveal how much of
+    // the token was correct.
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Reads a secret (password or token) from the first line of `path`.
+pub(crate) fn read_secret(path: &Path) -> io::Result<String> {
+    let contents = std::fs::read_to_string(path)?;
+    let secret = contents.lines().next().unwrap_or_default();
+    if secret.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} holds no secret", path.display()),
+        ));
+    }
+    Ok(secret.to_string())
+}
+
+/// Reads the PEM-encoded certificates at `path`.
+pub(crate) fn load_certs(
+    path: &Path,
+) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut rdr = io::BufReader::new(std::fs::File::open(path)?);
+    rustls_pemfile::certs(&mut rdr).collect()
+}
+
+/// Reads the PEM-encoded private key at `path`.
+pub(crate) fn load_private_key(
+    path: &Path,
+) -> io::Result<PrivateKeyDer<'static>> {
+    let mut rdr = io::BufReader::new(std::fs::File::open(path)?);
+    rustls_pemfile::private_key(&mut rdr)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no private key found in {}", path.display()),
+        )
+    })
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn invalid_data<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bearer_token() {
+        assert!(check_bearer_token(None, None));
+        assert!(check_bearer_token(None, Some("Bearer whatever")));
+
+        let token = Some("t0ken");
+        assert!(check_bearer_token(token, Some("Bearer t0ken")));
+        assert!(!check_bearer_token(token, None));
+        assert!(!check_bearer_token(token, Some("t0ken")));
+        assert!(!check_bearer_token(token, Some("Bearer t0ke")));
+        assert!(!check_bearer_token(token, Some("Bearer t0ken2")));
+        assert!(!check_bearer_token(token, Some("Basic t0ken")));
+    }
+
+    #[test]
+    fn authorize_requests() {
+        let open = ApiAuth::default();
+        assert!(open.authorize(&http::HeaderMap::new()).is_ok());
+
+        let auth =
+            ApiAuth { token: Some("t0ken".to_string()), ..Default::default() };
+        let mut headers = http::HeaderMap::new();
+        let err = auth.authorize(&headers).unwrap_err();
+        assert_eq!(err.status_code, http::StatusCode::UNAUTHORIZED);
+
+        headers.insert(
+            http::header::AUTHORIZATION,
+            http::HeaderValue::from_static("Bearer t0ken"),
+        );
+        assert!(auth.authorize(&headers).is_ok());
+    }
+
+    #[test]
+    fn mismatched_tls_settings() {
+        let api = config::Api {
+            tls_cert: Some("/nonexistent/cert.pem".into()),
+            ..Default::default()
+        };
+        let err =
+            ApiAuth::load(&api, &config::Migration::default()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // Serving the API over TLS requires a CA with which to verify the APIs
+        // of migration sources.
+        let api = config::Api {
+            tls_cert: Some("/nonexistent/cert.pem".into()),
+            tls_key: Some("/nonexistent/key.pem".into()),
+            ..Default::default()
+        };
+        let err =
+            ApiAuth::load(&api, &config::Migration::default()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let migration = config::Migration {
+            key: Some("/nonexistent/key.pem".into()),
+            ..Default::default()
+        };
+        let err =
+            ApiAuth::load(&config::Api::default(), &migration).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,232 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The clipboard channel between VNC clients and the guest.
+//!
+//! An agent in the guest exchanges clipboard text with the host over the
+//! virtio-serial port named [PORT_NAME] (which Linux guests find at
+//! `/dev/virtio-ports/org.oxide.clipboard.0`).  In each direction, the port
+//! carries a stream of messages, each of which is a header:
+//!
+//! | Offset | Size | Field                                  |
+//! |--------|------|----------------------------------------|
+//! | 0      | 1    | Message type                           |
+//! | 1      | 4    | Payload length (little-endian)         |
+//!
+//! followed by the payload.  The only message type is [MSG_CLIPBOARD] (1),
+//! the payload of which is the new contents of the clipboard, as UTF-8 text.
+//! The agent sends one whenever the guest's clipboard changes, and is sent one
+//! whenever a VNC client's clipboard does.  Messages of other types are
+//! ignored, as are those with payloads longer than [MAX_TEXT_LEN].
+
+use std::sync::Arc;
+
+use propolis::chardev::{Sink, Source};
+use propolis::hw::virtio::console::ConsolePort;
+use slog::{debug, warn, Logger};
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio::task::JoinHandle;
+
+/// Name of the virtio-serial port through which the guest agent connects
+pub const PORT_NAME: &str = "org.oxide.clipboard.0";
+
+/// Message type of clipboard text
+pub const MSG_CLIPBOARD: u8 = 1;
+
+/// Maximum length of clipboard text, in bytes
+pub const MAX_TEXT_LEN: usize = 1024 * 1024;
+
+const HEADER_LEN: usize = 5;
+
+/// Encodes a message updating the guest's clipboard to hold `text`.
+pub fn encode(text: &str) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(HEADER_LEN + text.len());
+    msg.push(MSG_CLIPBOARD);
+    msg.extend_from_slice(&(text.len() as u32).to_le_bytes());
+    msg.extend_from_slice(text.as_bytes());
+    msg
+}
+
+/// Decodes the stream of messages sent by the guest agent.
+#[derive(Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+    /// Bytes remaining of an oversized payload being discarded
+    skip: usize,
+}
+impl Decoder {
+    /// Consumes `data`, returning the clipboard text of any messages it
+    /// completes.
+    pub fn push(&mut self, data: &[u8]) -> Vec<String> {
+        let skipped = self.skip.min(data.len());
+        self.skip -= skipped;
+        self.buf.extend_from_slice(&data[skipped..]);
+
+        let mut texts = Vec::new();
+        while self.buf.len() >= HEADER_LEN {
+            let kind = self.buf[0];
+            let len =
+                u32::from_le_bytes(self.buf[1..HEADER_LEN].try_into().unwrap())
+                    as usize;
+            let total = HEADER_LEN + len;
+
+            if len > MAX_TEXT_LEN {
+                // Rather than buffer the payload, discard it as it arrives
+                let buffered = self.buf.len().min(total);
+                self.buf.drain(..buffered);
+                self.skip = total - buffered;
+                continue;
+            }
+            if self.buf.len() < total {
+                break;
+            }
+
+            let msg: Vec<u8> = self.buf.drain(..total).collect();
+            if kind == MSG_CLIPBOARD {
+                let text = String::from_utf8_lossy(&msg[HEADER_LEN..]);
+                texts.push(text.into_owned());
+            }
+        }
+        texts
+    }
+}
+
+/// Relays clipboard text between a guest's clipboard channel and VNC clients
+pub struct Bridge {
+    to_guest: mpsc::Sender<String>,
+    task: JoinHandle<()>,
+}
+impl Bridge {
+    /// Starts relaying clipboard text between the guest agent on `port` and
+    /// `from_guest`, to which the text sent by the guest is published.
+    pub fn start(
+        port: Arc<ConsolePort>,
+        from_guest: broadcast::Sender<String>,
+        log: Logger,
+    ) -> Self {
+        let (to_guest, to_guest_rx) = mpsc::channel(4);
+        let task = tokio::spawn(async move {
+            run(port, from_guest, to_guest_rx, log).await;
+        });
+        Self { to_guest, task }
+    }
+
+    /// Sets the guest's clipboard to hold `text`.  If the guest is still busy
+    /// with earlier updates, the text is dropped.
+    pub fn send(&self, text: String) {
+        let _ = self.to_guest.try_send(text);
+    }
+}
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn run(
+    port: Arc<ConsolePort>,
+    from_guest: broadcast::Sender<String>,
+    mut to_guest: mpsc::Receiver<String>,
+    log: Logger,
+) {
+    // The messages are small and infrequent, so rather than through the
+    // chardev pollers, the port is read and written directly when it is
+    // notified to be ready.
+    let readable = Arc::new(Notify::new());
+    let writable = Arc::new(Notify::new());
+    port.set_autodiscard(false);
+    let notify = readable.clone();
+    Source::set_notifier(
+        port.as_ref(),
+        Some(Box::new(move |_| notify.notify_one())),
+    );
+    let notify = writable.clone();
+    Sink::set_notifier(
+        port.as_ref(),
+        Some(Box::new(move |_| notify.notify_one())),
+    );
+    // Pick up anything sent before the notifier was in place
+    readable.notify_one();
+
+    let mut decoder = Decoder::default();
+    let mut outgoing = Vec::new();
+    let mut buf = vec![0u8; 4096];
+    loop {
+        if !outgoing.is_empty() {
+            let len = port.write_bytes(&outgoing);
+            outgoing.drain(..len);
+        }
+
+        tokio::select! {
+            _ = readable.notified() => {
+                loop {
+                    let len = port.read_bytes(&mut buf);
+                    if len == 0 {
+                        break;
+                    }
+                    for text in decoder.push(&buf[..len]) {
+                        // Nobody need be listening
+                        let _ = from_guest.send(text);
+                    }
+                }
+            }
+            _ = writable.notified(), if !outgoing.is_empty() => {}
+            text = to_guest.recv(), if outgoing.is_empty() => {
+                let Some(text) = text else {
+                    break;
+                };
+                if text.len() > MAX_TEXT_LEN {
+                    warn!(log, "clipboard text too long for guest";
+                          "len" => text.len());
+                } else if !port.guest_connected() {
+                    debug!(log, "no guest clipboard agent, dropping text");
+                } else {
+                    outgoing = encode(&text);
+                }
+            }
+        }
+    }
+
+    Source::set_notifier(port.as_ref(), None);
+    Sink::set_notifier(port.as_ref(), None);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut msgs = encode("hello");
+        msgs.extend(encode(""));
+        msgs.extend(encode("caf\u{e9} \u{263a}"));
+
+        // Messages are decoded however the stream is split up
+        for chunk in [1, 3, msgs.len()] {
+            let mut decoder = Decoder::default();
+            let texts: Vec<String> =
+                msgs.chunks(chunk).flat_map(|c| decoder.push(c)).collect();
+            assert_eq!(texts, ["hello", "", "caf\u{e9} \u{263a}"]);
+        }
+    }
+
+    #[test]
+    fn skip_ignored() {
+        let mut decoder = Decoder::default();
+
+        // Unknown message types are skipped
+        let mut data = vec![7, 3, 0, 0, 0, 1, 2, 3];
+        data.extend(encode("one"));
+        assert_eq!(decoder.push(&data), ["one"]);
+
+        // So are oversized payloads, however they arrive
+        let len = MAX_TEXT_LEN + 1;
+        let mut data = vec![MSG_CLIPBOARD];
+        data.extend_from_slice(&(len as u32).to_le_bytes());
+        data.extend(vec![b'x'; 100]);
+        assert!(decoder.push(&data).is_empty());
+        assert!(decoder.push(&vec![b'x'; len - 100]).is_empty());
+        assert_eq!(decoder.push(&encode("two")), ["two"]);
+    }
+}
@@ -0,0 +1,674 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A client for a qemu-guest-agent running in the guest.
+//!
+//! The agent is reached over a serial channel (a COM port or a virtio-serial
+//! port, usually the one named `org.qemu.guest_agent.0`) that is reserved for
+//! it.  Each request is a JSON object naming a command and its arguments, e.g.
+//! `{"execute": "guest-fsfreeze-freeze"}`, and the agent answers each with a
+//! line holding a JSON object with either the command's `return` value or an
+//! `error`.
+//!
+//! Requests carry no identifiers, so a reply to a request that was given up on
+//! could be mistaken for the reply to the next one.  Before its first request,
+//! and after any request goes unanswered, the client therefore resynchronizes
+//! with the agent using `guest-sync-delimited`, discarding everything the agent
+//! sent before its reply.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use propolis::chardev::{Sink, Source};
+use propolis_api_types as api;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use slog::{info, Logger};
+use thiserror::Error;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::serial::Serial;
+
+/// How long to wait for the agent to answer a request.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for the agent to freeze or thaw the guest's filesystems,
+/// which involves flushing them.
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often to ask whether a program run in the guest has exited.
+const EXEC_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The byte the agent sends ahead of its reply to `guest-sync-delimited`.
+/// Sent to the agent, it discards any partial request it has received.
+const SYNC_DELIMITER: u8 = 0xff;
+
+/// The longest reply the client accepts.  The agent caps the output it
+/// captures from programs at 16 MiB, which grows by a third when encoded.
+const MAX_REPLY_LEN: usize = 24 * 1024 * 1024;
+
+/// The number of bytes read or written by each file request.
+const FILE_CHUNK_LEN: usize = 48 * 1024;
+
+/// The largest guest file that can be read.
+pub const MAX_FILE_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Error)]
+pub enum GuestAgentError {
+    #[error("guest agent did not respond")]
+    Timeout,
+
+    #[error("guest agent channel closed")]
+    ChannelClosed,
+
+    #[error("malformed reply from guest agent: {0}")]
+    Protocol(String),
+
+    #[error("guest agent error ({class}): {desc}")]
+    Guest { class: String, desc: String },
+
+    #[error("guest program {0} did not exit in time")]
+    ExecTimeout(i64),
+
+    #[error("guest file is larger than {MAX_FILE_LEN} bytes")]
+    FileTooLarge,
+}
+
+/// A byte stream to and from the guest agent.
+#[async_trait::async_trait]
+pub trait Channel: Send + Sync {
+    /// Reads bytes sent by the guest into `buf`, returning the number read,
+    /// or `None` if the channel has closed.
+    async fn read(&self, buf: &mut [u8]) -> Option<usize>;
+
+    /// Sends the bytes in `data` to the guest, returning the number sent, or
+    /// `None` if the channel has closed.
+    async fn write(&self, data: &[u8]) -> Option<usize>;
+}
+
+#[async_trait::async_trait]
+impl<Device: Sink + Source> Channel for Serial<Device> {
+    async fn read(&self, buf: &mut [u8]) -> Option<usize> {
+        self.read_source(buf).await
+    }
+
+    async fn write(&self, data: &[u8]) -> Option<usize> {
+        self.write_sink(data).await
+    }
+}
+
+struct Connection {
+    /// The lines sent by the agent that have yet to be consumed.
+    replies: mpsc::Receiver<Vec<u8>>,
+
+    /// False if the next reply might not be to the next request.
+    synced: bool,
+
+    /// The identifier to use for the next `guest-sync-delimited` request.
+    next_sync_id: u64,
+}
+
+/// A connection to the guest agent.
+pub struct GuestAgent {
+    channel: Arc<dyn Channel>,
+
+    /// Serializes requests, each of which holds this for the duration of its
+    /// exchange with the agent.
+    conn: Mutex<Connection>,
+
+    reader: JoinHandle<()>,
+    log: Logger,
+}
+
+impl GuestAgent {
+    /// Starts reading the agent's replies from `channel`.
+    pub fn new(channel: Arc<dyn Channel>, log: Logger) -> Self {
+        let (replies_tx, replies) = mpsc::channel(16);
+        let reader = tokio::spawn(read_replies(channel.clone(), replies_tx));
+
+        // Start from an arbitrary identifier, so that replies to a previous
+        // server's requests aren't mistaken for replies to this one's.
+        let next_sync_id = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+
+        Self {
+            channel,
+            conn: Mutex::new(Connection {
+                replies,
+                synced: false,
+                next_sync_id,
+            }),
+            reader,
+            log,
+        }
+    }
+
+    /// Checks that the agent is responding.
+    pub async fn ping(&self) -> Result<(), GuestAgentError> {
+        let _: IgnoredAny =
+            self.execute("guest-ping", None, REPLY_TIMEOUT).await?;
+        Ok(())
+    }
+
+    /// Freezes the guest's filesystems, returning the number frozen.  The
+    /// filesystems stay frozen until thawed, so that disk snapshots taken in
+    /// the meantime are consistent.
+    pub async fn fs_freeze(&self) -> Result<u32, GuestAgentError> {
+        self.execute("guest-fsfreeze-freeze", None, FREEZE_TIMEOUT).await
+    }
+
+    /// Thaws the guest's filesystems, returning the number thawed.
+    pub async fn fs_thaw(&self) -> Result<u32, GuestAgentError> {
+        self.execute("guest-fsfreeze-thaw", None, FREEZE_TIMEOUT).await
+    }
+
+    /// Lists the guest's network interfaces and their addresses.
+    pub async fn network_interfaces(
+        &self,
+    ) -> Result<Vec<api::GuestNetworkInterface>, GuestAgentError> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "kebab-case")]
+        struct Interface {
+            name: String,
+            hardware_address: Option<String>,
+            #[serde(default)]
+            ip_addresses: Vec<IpAddress>,
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "kebab-case")]
+        struct IpAddress {
+            ip_address: String,
+            prefix: u8,
+        }
+
+        let interfaces: Vec<Interface> = self
+            .execute("guest-network-get-interfaces", None, REPLY_TIMEOUT)
+            .await?;
+        Ok(interfaces
+            .into_iter()
+            .map(|iface| api::GuestNetworkInterface {
+                name: iface.name,
+                hardware_address: iface.hardware_address,
+                ip_addresses: iface
+                    .ip_addresses
+                    .into_iter()
+                    .filter_map(|addr| {
+                        Some(api::GuestIpAddress {
+                            address: addr.ip_address.parse().ok()?,
+                            prefix: addr.prefix,
+                        })
+                    })
+                    .collect(),
+            })
+            .collect())
+    }
+
+    /// Runs the program at `path` in the guest, waiting up to `timeout` for
+    /// it to exit.
+    pub async fn exec(
+        &self,
+        path: &str,
+        args: &[String],
+        input: &[u8],
+        timeout: Duration,
+    ) -> Result<api::GuestAgentExecResponse, GuestAgentError> {
+        #[derive(Deserialize)]
+        struct Exec {
+            pid: i64,
+        }
+        #[derive(Deserialize)]
+        #[serde(rename_all = "kebab-case")]
+        struct ExecStatus {
+            exited: bool,
+            exitcode: Option<i32>,
+            signal: Option<i32>,
+            out_data: Option<String>,
+            err_data: Option<String>,
+            #[serde(default)]
+            out_truncated: bool,
+            #[serde(default)]
+            err_truncated: bool,
+        }
+
+        let mut arguments =
+            json!({ "path": path, "arg": args, "capture-output": true });
+        if !input.is_empty() {
+            arguments["input-data"] = encode(input).into();
+        }
+        let Exec { pid } =
+            self.execute("guest-exec", Some(arguments), REPLY_TIMEOUT).await?;
+        info!(self.log, "started guest program"; "path" => path, "pid" => pid);
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let status: ExecStatus = self
+                .execute(
+                    "guest-exec-status",
+                    Some(json!({ "pid": pid })),
+                    REPLY_TIMEOUT,
+                )
+                .await?;
+            if status.exited {
+                return Ok(api::GuestAgentExecResponse {
+                    exit_code: status.exitcode,
+                    signal: status.signal,
+                    stdout: decode(status.out_data.as_deref())?,
+                    stderr: decode(status.err_data.as_deref())?,
+                    truncated: status.out_truncated || status.err_truncated,
+                });
+            }
+            if Instant::now() >= deadline {
+                return Err(GuestAgentError::ExecTimeout(pid));
+            }
+            tokio::time::sleep(EXEC_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Reads the guest file at `path`.
+    pub async fn read_file(
+        &self,
+        path: &str,
+    ) -> Result<Vec<u8>, GuestAgentError> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "kebab-case")]
+        struct Read {
+            buf_b64: String,
+            eof: bool,
+        }
+
+        let handle = self.open_file(path, "r").await?;
+        let mut data = Vec::new();
+        let result = loop {
+            let read: Result<Read, _> = self
+                .execute(
+                    "guest-file-read",
+                    Some(json!({ "handle": handle, "count": FILE_CHUNK_LEN })),
+                    REPLY_TIMEOUT,
+                )
+                .await;
+            let read = match read {
+                Ok(read) => read,
+                Err(e) => break Err(e),
+            };
+            match decode(Some(&read.buf_b64)) {
+                Ok(chunk) => data.extend(chunk),
+                Err(e) => break Err(e),
+            }
+            if data.len() > MAX_FILE_LEN {
+                break Err(GuestAgentError::FileTooLarge);
+            }
+            if read.eof {
+                break Ok(());
+            }
+        };
+        self.close_file(handle, result).await.map(|_| data)
+    }
+
+    /// Writes `data` to the guest file at `path`, replacing its contents.
+    pub async fn write_file(
+        &self,
+        path: &str,
+        data: &[u8],
+    ) -> Result<(), GuestAgentError> {
+        let handle = self.open_file(path, "w").await?;
+        let mut result = Ok(());
+        for chunk in data.chunks(FILE_CHUNK_LEN) {
+            let written: Result<IgnoredAny, _> = self
+                .execute(
+                    "guest-file-write",
+                    Some(json!({ "handle": handle, "buf-b64": encode(chunk) })),
+                    REPLY_TIMEOUT,
+                )
+                .await;
+            if let Err(e) = written {
+                result = Err(e);
+                break;
+            }
+        }
+        self.close_file(handle, result).await
+    }
+
+    async fn open_file(
+        &self,
+        path: &str,
+        mode: &str,
+    ) -> Result<i64, GuestAgentError> {
+        self.execute(
+            "guest-file-open",
+            Some(json!({ "path": path, "mode": mode })),
+            REPLY_TIMEOUT,
+        )
+        .await
+    }
+
+    /// Closes the file `handle`, returning `result` unless it was successful
+    /// and closing the file wasn't.
+    async fn close_file(
+        &self,
+        handle: i64,
+        result: Result<(), GuestAgentError>,
+    ) -> Result<(), GuestAgentError> {
+        let closed: Result<IgnoredAny, _> = self
+            .execute(
+                "guest-file-close",
+                Some(json!({ "handle": handle })),
+                REPLY_TIMEOUT,
+            )
+            .await;
+        result.and(closed.map(|_| ()))
+    }
+
+    /// Sends the agent `command` and waits up to `timeout` for its reply.
+    async fn execute<T: DeserializeOwned>(
+        &self,
+        command: &str,
+        arguments: Option<Value>,
+        timeout: Duration,
+    ) -> Result<T, GuestAgentError> {
+        let mut conn = self.conn.lock().await;
+        if !conn.synced {
+            self.sync(&mut conn).await?;
+        }
+
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        self.send(&request).await?;
+        let reply = tokio::time::timeout(timeout, conn.replies.recv())
+            .await
+            .map_err(|_| GuestAgentError::Timeout)
+            .and_then(|line| line.ok_or(GuestAgentError::ChannelClosed))
+            .and_then(|line| {
+                serde_json::from_slice(&line)
+                    .map_err(|e| GuestAgentError::Protocol(e.to_string()))
+            });
+        if reply.is_err() {
+            conn.synced = false;
+        }
+
+        parse_reply(reply?)
+    }
+
+    /// Discards any replies to earlier requests.
+    async fn sync(&self, conn: &mut Connection) -> Result<(), GuestAgentError> {
+        while conn.replies.try_recv().is_ok() {}
+
+        let id = conn.next_sync_id;
+        conn.next_sync_id = id.wrapping_add(1);
+        self.write_all(&[SYNC_DELIMITER]).await?;
+        self.send(&json!({
+            "execute": "guest-sync-delimited",
+            "arguments": { "id": id },
+        }))
+        .await?;
+
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        loop {
+            let line = tokio::time::timeout_at(deadline, conn.replies.recv())
+                .await
+                .map_err(|_| GuestAgentError::Timeout)?
+                .ok_or(GuestAgentError::ChannelClosed)?;
+            let reply: Option<Value> = serde_json::from_slice(&line).ok();
+            if reply.and_then(|r| r.get("return")?.as_u64()) == Some(id) {
+                conn.synced = true;
+                return Ok(());
+            }
+        }
+    }
+
+    async fn send(&self, request: &Value) -> Result<(), GuestAgentError> {
+        let mut msg = serde_json::to_vec(request).unwrap();
+        msg.push(b'\n');
+        self.write_all(&msg).await
+    }
+
+    async fn write_all(&self, mut data: &[u8]) -> Result<(), GuestAgentError> {
+        while !data.is_empty() {
+            let written = self
+                .channel
+                .write(data)
+                .await
+                .ok_or(GuestAgentError::ChannelClosed)?;
+            data = &data[written..];
+        }
+        Ok(())
+    }
+}
+
+impl Drop for GuestAgent {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Splits the agent's output into lines, passing them to `replies`.  The sync
+/// delimiter ends a line, so that a partial line sent before it is discarded.
+async fn read_replies(
+    channel: Arc<dyn Channel>,
+    replies: mpsc::Sender<Vec<u8>>,
+) {
+    let mut buf = vec![0u8; 4096];
+    let mut line = Vec::new();
+    let mut overlong = false;
+    while let Some(len) = channel.read(&mut buf).await {
+        for &b in &buf[..len] {
+            match b {
+                b'\n' | SYNC_DELIMITER => {
+                    let line = std::mem::take(&mut line);
+                    if !line.is_empty() && !overlong {
+                        // Nobody is waiting for lines sent while the queue
+                        // is full, so drop them rather than stall the guest.
+                        let _ = replies.try_send(line);
+                    }
+                    overlong = false;
+                }
+                _ if overlong => {}
+                _ => {
+                    line.push(b);
+                    if line.len() > MAX_REPLY_LEN {
+                        line = Vec::new();
+                        overlong = true;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn parse_reply<T: DeserializeOwned>(
+    mut reply: Value,
+) -> Result<T, GuestAgentError> {
+    #[derive(Deserialize)]
+    struct AgentError {
+        class: String,
+        desc: String,
+    }
+
+    if let Some(error) = reply.get_mut("error") {
+        let AgentError { class, desc } =
+            serde_json::from_value(error.take())
+                .map_err(|e| GuestAgentError::Protocol(e.to_string()))?;
+        return Err(GuestAgentError::Guest { class, desc });
+    }
+    let ret = reply.get_mut("return").ok_or_else(|| {
+        GuestAgentError::Protocol("reply has no return value".to_string())
+    })?;
+    serde_json::from_value(ret.take())
+        .map_err(|e| GuestAgentError::Protocol(e.to_string()))
+}
+
+fn encode(data: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(data)
+}
+
+fn decode(data: Option<&str>) -> Result<Vec<u8>, GuestAgentError> {
+    let Some(data) = data else {
+        return Ok(Vec::new());
+    };
+    base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| GuestAgentError::Protocol(e.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A channel to a fake agent, which answers each request with the
+    /// result of `respond`.
+    struct FakeAgent {
+        to_client: mpsc::Sender<Vec<u8>>,
+        from_client: Mutex<mpsc::Receiver<Vec<u8>>>,
+        request: std::sync::Mutex<Vec<u8>>,
+        respond: Box<dyn Fn(&Value) -> Option<Value> + Send + Sync>,
+    }
+
+    impl FakeAgent {
+        fn new(
+            respond: impl Fn(&Value) -> Option<Value> + Send + Sync + 'static,
+        ) -> Arc<Self> {
+            let (to_client, from_client) = mpsc::channel(16);
+            Arc::new(Self {
+                to_client,
+                from_client: Mutex::new(from_client),
+                request: Default::default(),
+                respond: Box::new(respond),
+            })
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Channel for FakeAgent {
+        async fn read(&self, buf: &mut [u8]) -> Option<usize> {
+            let data = self.from_client.lock().await.recv().await?;
+            buf[..data.len()].copy_from_slice(&data);
+            Some(data.len())
+        }
+
+        async fn write(&self, data: &[u8]) -> Option<usize> {
+            let mut replies = Vec::new();
+            {
+                let mut request = self.request.lock().unwrap();
+                for &b in data {
+                    if b == SYNC_DELIMITER {
+                        request.clear();
+                    } else if b == b'\n' {
+                        let req: Value = serde_json::from_slice(&request)
+                            .expect("request is valid JSON");
+                        request.clear();
+                        if req["execute"] == "guest-sync-delimited" {
+                            let mut reply = vec![SYNC_DELIMITER];
+                            reply.extend(
+                                json!({ "return": req["arguments"]["id"] })
+                                    .to_string()
+                                    .into_bytes(),
+                            );
+                            replies.push(reply);
+                        } else if let Some(reply) = (self.respond)(&req) {
+                            replies.push(reply.to_string().into_bytes());
+                        }
+                    } else {
+                        request.push(b);
+                    }
+                }
+            }
+            for mut reply in replies {
+                reply.push(b'\n');
+                self.to_client.send(reply).await.ok()?;
+            }
+            Some(data.len())
+        }
+    }
+
+    fn test_logger() -> Logger {
+        Logger::root(slog::Discard, slog::o!())
+    }
+
+    #[tokio::test]
+    async fn requests_and_errors() {
+        let fake = FakeAgent::new(|req| match req["execute"].as_str() {
+            Some("guest-ping") => Some(json!({ "return": {} })),
+            Some("guest-fsfreeze-freeze") => Some(json!({ "return": 3 })),
+            _ => Some(json!({
+                "error": { "class": "CommandNotFound", "desc": "nope" }
+            })),
+        });
+        let agent = GuestAgent::new(fake, test_logger());
+
+        agent.ping().await.unwrap();
+        assert_eq!(agent.fs_freeze().await.unwrap(), 3);
+        match agent.fs_thaw().await {
+            Err(GuestAgentError::Guest { class, .. }) => {
+                assert_eq!(class, "CommandNotFound")
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn read_file_in_chunks() {
+        let contents: Vec<u8> =
+            (0..FILE_CHUNK_LEN * 2 + 100).map(|i| i as u8).collect();
+        let file = contents.clone();
+        let offset = std::sync::Mutex::new(0);
+        let fake = FakeAgent::new(move |req| {
+            let ret = match req["execute"].as_str()? {
+                "guest-file-open" => json!(7),
+                "guest-file-close" => json!({}),
+                "guest-file-read" => {
+                    assert_eq!(req["arguments"]["handle"], 7);
+                    let mut offset = offset.lock().unwrap();
+                    let count = req["arguments"]["count"].as_u64()? as usize;
+                    let end = (*offset + count).min(file.len());
+                    let chunk = &file[*offset..end];
+                    *offset = end;
+                    json!({
+                        "count": chunk.len(),
+                        "buf-b64": encode(chunk),
+                        "eof": end == file.len(),
+                    })
+                }
+                _ => return None,
+            };
+            Some(json!({ "return": ret }))
+        });
+        let agent = GuestAgent::new(fake, test_logger());
+
+        assert_eq!(agent.read_file("/etc/motd").await.unwrap(), contents);
+    }
+
+    #[tokio::test]
+    async fn read_file_error_closes_handle() {
+        let closed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let was_closed = closed.clone();
+        let fake = FakeAgent::new(move |req| {
+            let ret = match req["execute"].as_str()? {
+                "guest-file-open" => json!(7),
+                "guest-file-close" => {
+                    closed.store(true, std::sync::atomic::Ordering::SeqCst);
+                    json!({})
+                }
+                "guest-file-read" => {
+                    json!({ "buf-b64": "not base64!", "eof": false })
+                }
+                _ => return None,
+            };
+            Some(json!({ "return": ret }))
+        });
+        let agent = GuestAgent::new(fake, test_logger());
+
+        match agent.read_file("/etc/motd").await {
+            Err(GuestAgentError::Protocol(_)) => {}
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert!(was_closed.load(std::sync::atomic::Ordering::SeqCst));
+    }
+}
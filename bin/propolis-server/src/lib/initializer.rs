@@ -2,6 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::fs::File;
 use std::io::{Error, ErrorKind};
@@ -10,9 +11,15 @@ use std::os::unix::fs::FileTypeExt;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::guest_agent::GuestAgent;
+use crate::serial::logfile::SerialLogFile;
 use crate::serial::Serial;
 use crate::stats::virtual_machine::VirtualMachine;
-use crate::vm::{BlockBackendMap, CrucibleBackendMap, DeviceMap};
+use crate::vm::events::EventLog;
+use crate::vm::{
+    BlockBackendMap, CrucibleBackendMap, DeviceMap, NetworkDeviceMap,
+    SerialPortMap, VirtioSerialPortMap,
+};
 use anyhow::{Context, Result};
 use crucible_client_types::VolumeConstructionRequest;
 pub use nexus_client::Client as NexusClient;
@@ -30,11 +37,14 @@ use propolis::hw::ps2::ctrl::PS2Ctrl;
 use propolis::hw::qemu::pvpanic::QemuPvpanic;
 use propolis::hw::qemu::{debug::QemuDebugPort, fwcfg, ramfb};
 use propolis::hw::uart::LpcUart;
+use propolis::hw::virtio::pci::{PciVirtio, PciVirtioState};
 use propolis::hw::{nvme, virtio};
 use propolis::intr_pins;
 use propolis::vmm::{self, Builder, Machine};
-use propolis_api_types::instance_spec::{self, v0::InstanceSpecV0};
-use propolis_api_types::InstanceProperties;
+use propolis_api_types::instance_spec::{
+    self, components::devices::SerialPortNumber, v0::InstanceSpecV0,
+};
+use propolis_api_types::{InstanceEventKind, InstanceProperties};
 use slog::info;
 
 // Arbitrary ROM limit for now
@@ -84,6 +94,7 @@ pub fn build_instance(
 pub struct RegisteredChipset {
     chipset: Arc<dyn Chipset>,
     isa: Arc<i440fx::Piix3Lpc>,
+    pm: Arc<i440fx::Piix3PM>,
 }
 impl RegisteredChipset {
     pub fn pci_attach(&self, bdf: pci::Bdf, dev: Arc<dyn pci::Endpoint>) {
@@ -95,6 +106,10 @@ impl RegisteredChipset {
     fn reset_pin(&self) -> Arc<dyn intr_pins::IntrPin> {
         self.chipset.reset_pin()
     }
+    /// Yields the chipset's ACPI power management device.
+    pub fn pm(&self) -> &Arc<i440fx::Piix3PM> {
+        &self.pm
+    }
 }
 
 struct StorageBackendInstance {
@@ -102,6 +117,17 @@ struct StorageBackendInstance {
     crucible: Option<(uuid::Uuid, Arc<block::CrucibleBackend>)>,
 }
 
+/// The host ends of a VM's virtio-serial ports.
+#[derive(Default)]
+pub struct VirtioSerialPorts {
+    /// The ports reachable through the server's websocket.
+    pub buffered: VirtioSerialPortMap,
+
+    /// The ports connected to Unix domain sockets, which have yet to be
+    /// spawned.
+    pub sockets: Vec<(Arc<chardev::UDSock>, Arc<virtio::console::ConsolePort>)>,
+}
+
 #[derive(Default)]
 pub struct MachineInitializerState {
     rom_size_bytes: Option<usize>,
@@ -117,6 +143,7 @@ pub struct MachineInitializer<'a> {
     pub(crate) properties: &'a InstanceProperties,
     pub(crate) toml_config: &'a crate::server::VmTomlConfig,
     pub(crate) producer_registry: Option<ProducerRegistry>,
+    pub(crate) events: Arc<EventLog>,
     pub(crate) state: MachineInitializerState,
 }
 
@@ -243,6 +270,7 @@ impl<'a> MachineInitializer<'a> {
                 let chipset_pm = i440fx::Piix3PM::create(
                     self.machine.hdl.clone(),
                     chipset_hb.power_pin(),
+                    chipset_lpc.sci_pin(),
                     self.log.new(slog::o!("device" => "piix3pm")),
                 );
 
@@ -270,7 +298,8 @@ impl<'a> MachineInitializer<'a> {
                     chipset_lpc.type_name().into(),
                     chipset_lpc.clone(),
                 );
-                self.devices.insert(chipset_pm.type_name().into(), chipset_pm);
+                self.devices
+                    .insert(chipset_pm.type_name().into(), chipset_pm.clone());
 
                 // Record attachment for any bridges in PCI topology too
                 for (bdf, bridge) in bridges {
@@ -280,18 +309,23 @@ impl<'a> MachineInitializer<'a> {
                     );
                 }
 
-                Ok(RegisteredChipset { chipset: chipset_hb, isa: chipset_lpc })
+                Ok(RegisteredChipset {
+                    chipset: chipset_hb,
+                    isa: chipset_lpc,
+                    pm: chipset_pm,
+                })
             }
         }
     }
 
-    pub fn initialize_uart(
+    pub fn initialize_uarts(
         &mut self,
         chipset: &RegisteredChipset,
-    ) -> Result<Serial<LpcUart>, Error> {
-        use instance_spec::components::devices::SerialPortNumber;
+    ) -> Result<BTreeMap<SerialPortNumber, Serial<LpcUart>>, Error> {
+        let sink_size = NonZeroUsize::new(64).unwrap();
+        let source_size = NonZeroUsize::new(1024).unwrap();
 
-        let mut com1 = None;
+        let mut ports = BTreeMap::new();
         for (name, serial_spec) in &self.spec.devices.serial_ports {
             let (irq, port) = match serial_spec.num {
                 SerialPortNumber::Com1 => (ibmpc::IRQ_COM1, ibmpc::PORT_COM1),
@@ -304,15 +338,26 @@ impl<'a> MachineInitializer<'a> {
             dev.set_autodiscard(true);
             LpcUart::attach(&dev, &self.machine.bus_pio, port);
             self.devices.insert(name.clone(), dev.clone());
-            if matches!(serial_spec.num, SerialPortNumber::Com1) {
-                assert!(com1.is_none());
-                com1 = Some(dev);
-            }
+            let logfile = self
+                .toml_config
+                .serial_log
+                .as_ref()
+                .map(|config| {
+                    SerialLogFile::open(
+                        config,
+                        serial_spec.num,
+                        self.log.clone(),
+                    )
+                })
+                .transpose()?;
+            let prev = ports.insert(
+                serial_spec.num,
+                Serial::new(dev, sink_size, source_size, logfile),
+            );
+            assert!(prev.is_none(), "serial port {:?} in use", serial_spec.num);
         }
 
-        let sink_size = NonZeroUsize::new(64).unwrap();
-        let source_size = NonZeroUsize::new(1024).unwrap();
-        Ok(Serial::new(com1.unwrap(), sink_size, source_size))
+        Ok(ports)
     }
 
     pub fn initialize_ps2(
@@ -353,6 +398,16 @@ impl<'a> MachineInitializer<'a> {
                     self.log.new(slog::o!("dev" => "qemu-pvpanic")),
                 );
                 pvpanic.attach_pio(&self.machine.bus_pio);
+
+                let events = self.events.clone();
+                pvpanic.set_notifier(Box::new(
+                    move |host_handled, guest_handled| {
+                        events.record(InstanceEventKind::GuestPanic {
+                            host_handled,
+                            guest_handled,
+                        })
+                    },
+                ));
                 self.devices
                     .insert(pvpanic.type_name().into(), pvpanic.clone());
 
@@ -371,6 +426,19 @@ impl<'a> MachineInitializer<'a> {
         Ok(())
     }
 
+    /// Records an event each time the virtio device named `name` in the
+    /// instance spec enters the "Needs Reset" state.
+    fn report_needs_reset(&self, name: &str, state: &PciVirtioState) {
+        let events = self.events.clone();
+        let device = name.to_string();
+        state.set_needs_reset_notifier(Box::new(move || {
+            events.record(InstanceEventKind::DeviceError {
+                device: device.clone(),
+                message: "device needs reset".to_string(),
+            })
+        }));
+    }
+
     async fn create_storage_backend_from_spec(
         &self,
         backend_spec: &instance_spec::v0::StorageBackendV0,
@@ -545,6 +613,7 @@ impl<'a> MachineInitializer<'a> {
             match device_interface {
                 DeviceInterface::Virtio => {
                     let vioblk = virtio::PciVirtioBlock::new(0x100);
+                    self.report_needs_reset(name, vioblk.virtio_state());
 
                     self.devices
                         .insert(format!("pci-virtio-{}", bdf), vioblk.clone());
@@ -584,7 +653,8 @@ impl<'a> MachineInitializer<'a> {
     pub fn initialize_network_devices(
         &mut self,
         chipset: &RegisteredChipset,
-    ) -> Result<(), Error> {
+    ) -> Result<NetworkDeviceMap, Error> {
+        let mut network_devices = NetworkDeviceMap::new();
         for (name, vnic_spec) in &self.spec.devices.network_devices {
             info!(self.log, "Creating vNIC {}", name);
             let instance_spec::v0::NetworkDeviceV0::VirtioNic(vnic_spec) =
@@ -626,16 +696,37 @@ impl<'a> MachineInitializer<'a> {
                 }
             };
 
+            let mac_addr = vnic_spec
+                .mac_address
+                .as_deref()
+                .map(|mac| {
+                    virtio::viona::parse_mac_addr(mac).ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            format!(
+                                "Invalid MAC address {:?} for vNIC {}",
+                                mac, name
+                            ),
+                        )
+                    })
+                })
+                .transpose()?;
+
             let viona = virtio::PciVirtioViona::new(
                 vnic_name,
                 0x100,
+                mac_addr,
+                vnic_spec.mtu,
+                vnic_spec.allow_promiscuous,
                 &self.machine.hdl,
             )?;
+            self.report_needs_reset(name, viona.virtio_state());
             self.devices
                 .insert(format!("pci-virtio-viona-{}", bdf), viona.clone());
+            network_devices.insert(name.clone(), viona.clone());
             chipset.pci_attach(bdf, viona);
         }
-        Ok(())
+        Ok(network_devices)
     }
 
     #[cfg(not(feature = "omicron-build"))]
@@ -823,7 +914,6 @@ impl<'a> MachineInitializer<'a> {
         Ok(())
     }
 
-    #[cfg(feature = "falcon")]
     pub fn initialize_9pfs(
         &mut self,
         chipset: &RegisteredChipset,
@@ -846,14 +936,186 @@ impl<'a> MachineInitializer<'a> {
             p9fs.source.to_owned(),
             p9fs.target.to_owned(),
             p9fs.chunk_size,
+            p9fs.read_only,
             self.log.clone(),
         );
         let vio9p = virtio::p9fs::PciVirtio9pfs::new(0x40, Arc::new(handler));
-        self.devices.insert("falcon-p9fs".to_string(), vio9p.clone());
+        self.devices.insert("p9fs".to_string(), vio9p.clone());
         chipset.pci_attach(bdf, vio9p);
         Ok(())
     }
 
+    pub fn initialize_virtio_fs(
+        &mut self,
+        chipset: &RegisteredChipset,
+    ) -> Result<(), Error> {
+        use instance_spec::components::devices::VirtioFsCachePolicy;
+
+        for (name, fs) in &self.spec.devices.virtio_fs {
+            info!(self.log, "Creating virtio-fs device {}", name);
+            let bdf: pci::Bdf = fs.pci_path.try_into().map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Couldn't get PCI BDF for virtio-fs device {}: {}",
+                        name, e
+                    ),
+                )
+            })?;
+
+            let cache = match fs.cache {
+                VirtioFsCachePolicy::Never => {
+                    virtio::virtiofs::CachePolicy::Never
+                }
+                VirtioFsCachePolicy::Auto => {
+                    virtio::virtiofs::CachePolicy::Auto
+                }
+                VirtioFsCachePolicy::Always => {
+                    virtio::virtiofs::CachePolicy::Always
+                }
+            };
+            let vfs = virtio::PciVirtioFs::new(
+                0x100,
+                &fs.tag,
+                std::path::Path::new(&fs.source),
+                cache,
+                fs.read_only,
+                self.log.new(slog::o!("dev" => name.clone())),
+            )?;
+            self.devices.insert(name.clone(), vfs.clone());
+            chipset.pci_attach(bdf, vfs);
+        }
+        Ok(())
+    }
+
+    /// Creates the clipboard channel device, if the spec has one, returning
+    /// the virtio-serial port through which clipboard text is exchanged.
+    pub fn initialize_clipboard(
+        &mut self,
+        chipset: &RegisteredChipset,
+    ) -> Result<Option<Arc<virtio::console::ConsolePort>>, Error> {
+        let Some(clipboard) = &self.spec.devices.clipboard else {
+            return Ok(None);
+        };
+
+        let bdf: pci::Bdf = clipboard.pci_path.try_into().map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Couldn't get PCI BDF for clipboard device: {}", e),
+            )
+        })?;
+
+        let console = virtio::PciVirtioConsole::new(
+            0x40,
+            &[crate::clipboard::PORT_NAME],
+        )?;
+        let port = console.ports()[0].clone();
+        self.devices.insert("clipboard".to_string(), console.clone());
+        chipset.pci_attach(bdf, console);
+        Ok(Some(port))
+    }
+
+    /// Creates the spec's virtio-serial devices, returning the host ends of
+    /// their ports.
+    pub fn initialize_virtio_serial(
+        &mut self,
+        chipset: &RegisteredChipset,
+    ) -> Result<VirtioSerialPorts, Error> {
+        use instance_spec::components::devices::VirtioSerialPortBackend;
+
+        let sink_size = NonZeroUsize::new(4096).unwrap();
+        let source_size = NonZeroUsize::new(4096).unwrap();
+
+        let mut ports = VirtioSerialPorts::default();
+        for (name, serial) in &self.spec.devices.virtio_serial {
+            info!(self.log, "Creating virtio-serial device {}", name);
+            let bdf: pci::Bdf = serial.pci_path.try_into().map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "Couldn't get PCI BDF for virtio-serial device {}: {}",
+                        name, e
+                    ),
+                )
+            })?;
+
+            let names: Vec<&str> =
+                serial.ports.iter().map(|port| port.name.as_str()).collect();
+            let console = virtio::PciVirtioConsole::new(0x40, &names)?;
+            for (port_spec, port) in serial.ports.iter().zip(console.ports()) {
+                let logfile = match &port_spec.backend {
+                    VirtioSerialPortBackend::Buffer => None,
+                    VirtioSerialPortBackend::File(path) => {
+                        Some(SerialLogFile::open_path(
+                            path.into(),
+                            self.log.clone(),
+                        )?)
+                    }
+                    VirtioSerialPortBackend::UnixSocket(path) => {
+                        let sock =
+                            chardev::UDSock::bind(std::path::Path::new(path))?;
+                        ports.sockets.push((sock, port.clone()));
+                        continue;
+                    }
+                };
+                ports.buffered.insert(
+                    (name.clone(), port_spec.name.clone()),
+                    Arc::new(Serial::new(
+                        port.clone(),
+                        sink_size,
+                        source_size,
+                        logfile,
+                    )),
+                );
+            }
+            self.devices.insert(name.clone(), console.clone());
+            chipset.pci_attach(bdf, console);
+        }
+        Ok(ports)
+    }
+
+    /// Connects to the guest agent over the channel named in the spec, taking
+    /// that channel from the serial ports that would otherwise be reachable
+    /// through the server's websockets.
+    pub fn initialize_guest_agent(
+        &self,
+        serial_ports: &mut SerialPortMap,
+        virtio_serial: &mut VirtioSerialPorts,
+    ) -> Result<Option<Arc<GuestAgent>>, Error> {
+        use instance_spec::components::devices::GuestAgentChannel;
+
+        let Some(agent) = &self.spec.devices.guest_agent else {
+            return Ok(None);
+        };
+
+        let not_found = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Guest agent channel {:?} is not a buffered serial port",
+                    agent.channel
+                ),
+            )
+        };
+        let channel: Arc<dyn crate::guest_agent::Channel> = match &agent.channel
+        {
+            GuestAgentChannel::SerialPort(port) => {
+                serial_ports.remove(port).ok_or_else(not_found)?
+            }
+            GuestAgentChannel::VirtioSerial { device, port } => virtio_serial
+                .buffered
+                .remove(&(device.clone(), port.clone()))
+                .ok_or_else(not_found)?,
+        };
+
+        info!(self.log, "Connecting to guest agent";
+              "channel" => ?agent.channel);
+        Ok(Some(Arc::new(GuestAgent::new(
+            channel,
+            self.log.new(slog::o!("component" => "guest_agent")),
+        ))))
+    }
+
     fn generate_smbios(&self) -> smbios::TableBytes {
         use propolis::cpuid;
         use smbios::table::{type0, type1, type16, type4};
@@ -2,7 +2,10 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod api_auth;
+pub mod clipboard;
 pub mod config;
+mod guest_agent;
 mod initializer;
 mod migrate;
 mod serial;
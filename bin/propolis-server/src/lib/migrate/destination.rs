@@ -4,22 +4,25 @@
 
 use bitvec::prelude as bv;
 use futures::{SinkExt, StreamExt};
-use propolis::common::{GuestAddr, Lifecycle, PAGE_SIZE};
-use propolis::migrate::{
-    MigrateCtx, MigrateStateError, Migrator, PayloadOffer, PayloadOffers,
-};
+use propolis::common::{GuestAddr, PAGE_SIZE};
 use propolis::vmm;
+use propolis_api_types::instance_spec::components::devices::SerialPortNumber;
 use propolis_api_types::InstanceMigrateInitiateRequest;
 use slog::{error, info, trace, warn};
 use std::convert::TryInto;
 use std::io;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
 use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
-use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Role};
 use tokio_tungstenite::{tungstenite, WebSocketStream};
 use uuid::Uuid;
 
+use crate::api_auth::ApiAuth;
 use crate::migrate::codec;
 use crate::migrate::memx;
 use crate::migrate::preamble::Preamble;
@@ -59,6 +62,7 @@ pub(crate) async fn initiate(
     log: &slog::Logger,
     migrate_info: &InstanceMigrateInitiateRequest,
     local_addr: SocketAddr,
+    auth: &ApiAuth,
 ) -> Result<impl DestinationProtocol, MigrateError> {
     let migration_id = migrate_info.migration_id;
 
@@ -70,17 +74,8 @@ pub(crate) async fn initiate(
 
     info!(log, "negotiating migration as destination");
 
-    // Build upgrade request to the source instance
-    // (we do this by hand because it's hidden from the OpenAPI spec)
-    // TODO(#165): https (wss)
     // TODO: We need to make sure the src_addr is a valid target
-    let src_migrate_url = format!(
-        "ws://{}/instance/migrate/{}/start",
-        migrate_info.src_addr, migration_id,
-    );
-    info!(log, "Begin migration"; "src_migrate_url" => &src_migrate_url);
-    let (mut conn, _) =
-        tokio_tungstenite::connect_async(src_migrate_url).await?;
+    let mut conn = connect_to_source(&log, migrate_info, auth).await?;
 
     // Generate a list of protocols that this target supports, then send them to
     // the source and allow it to choose its favorite.
@@ -139,15 +134,131 @@ pub(crate) async fn initiate(
         };
 
     Ok(match selected {
-        Protocol::RonV0 => RonV0::new(log, migration_id, conn, local_addr),
+        Protocol::RonV0 | Protocol::RonV1 => {
+            RonV0::new(log, selected, migration_id, conn, local_addr)
+        }
     })
 }
 
-/// The runner for version 0 of the LM protocol, using RON encoding.
+/// A connection to a migration source, which may or may not be protected by
+/// TLS.
+type SourceConn = Box<dyn MigrateConn + Sync>;
+
+/// Opens a websocket to the migration source described by `migrate_info`.
+///
+/// The websocket is carried over TLS if this server serves its own API that way
+/// (on the assumption that the source does too). If migration peers are
+/// configured to authenticate one another, a second TLS session, in which each
+/// side presents its certificate, is established beneath the websocket;
+/// otherwise, this server's bearer token is presented with the upgrade request.
+async fn connect_to_source(
+    log: &slog::Logger,
+    migrate_info: &InstanceMigrateInitiateRequest,
+    auth: &ApiAuth,
+) -> Result<WebSocketStream<SourceConn>, MigrateError> {
+    let src_addr = migrate_info.src_addr;
+    let path = format!("/instance/migrate/{}/start", migrate_info.migration_id);
+
+    // Sources are addressed by IP, so their certificates must name their IPs.
+    let server_name = ServerName::IpAddress(src_addr.ip().into());
+
+    let tcp = TcpStream::connect(src_addr)
+        .await
+        .map_err(|e| MigrateError::Websocket(e.to_string()))?;
+    let (scheme, mut stream): (_, SourceConn) = match auth.source_api_tls() {
+        Some(connector) => {
+            let tls = connector
+                .connect(server_name.clone(), tcp)
+                .await
+                .map_err(|e| MigrateError::Tls(e.to_string()))?;
+            ("wss", Box::new(tls))
+        }
+        None => ("ws", Box::new(tcp)),
+    };
+    let src_migrate_url = format!("{scheme}://{src_addr}{path}");
+    info!(log, "Begin migration"; "src_migrate_url" => &src_migrate_url);
+
+    let Some(migration_tls) = auth.migration_tls() else {
+        let mut request = src_migrate_url.into_client_request()?;
+        if let Some(token) = auth.token() {
+            let value = format!("Bearer {token}").parse().map_err(|_| {
+                MigrateError::Websocket("invalid bearer token".to_string())
+            })?;
+            request.headers_mut().insert(AUTHORIZATION, value);
+        }
+        let (conn, _) =
+            tokio_tungstenite::client_async(request, stream).await?;
+        return Ok(conn);
+    };
+
+    // The websocket's frames have to be carried inside the TLS session, so
+    // the connection is upgraded by hand before the session is established.
+    // The source is authenticated by its certificate rather than its reply
+    // to the upgrade request, so the reply's status is all that is checked.
+    let key = tungstenite::handshake::client::generate_key();
+    let request = format!(
+        "GET {path} HTTP/1.1\r\n\
+        Host: {src_addr}\r\n\
+        Connection: Upgrade\r\n\
+        Upgrade: websocket\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Key: {key}\r\n\r\n"
+    );
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| MigrateError::Websocket(e.to_string()))?;
+    let response = read_response_head(&mut stream).await?;
+    if !response.starts_with("HTTP/1.1 101 ") {
+        error!(log, "source refused to upgrade migration connection";
+               "response" => response.lines().next());
+        return Err(MigrateError::UpgradeExpected);
+    }
+
+    let tls = migration_tls
+        .connector
+        .connect(server_name, stream)
+        .await
+        .map_err(|e| MigrateError::Tls(e.to_string()))?;
+    Ok(WebSocketStream::from_raw_socket(
+        Box::new(tls) as SourceConn,
+        Role::Client,
+        None,
+    )
+    .await)
+}
+
+/// Reads the head of an HTTP response from `stream`, one byte at a time so as
+/// to leave whatever follows it unread.
+async fn read_response_head(
+    stream: &mut SourceConn,
+) -> Result<String, MigrateError> {
+    const MAX_HEAD_LEN: usize = 8192;
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() == MAX_HEAD_LEN {
+            return Err(MigrateError::UpgradeExpected);
+        }
+        let byte = stream
+            .read_u8()
+            .await
+            .map_err(|e| MigrateError::Websocket(e.to_string()))?;
+        head.push(byte);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// The runner for version 0 of the LM protocol, using RON encoding. Version 1
+/// differs only in how serial console history is transferred, so this runner
+/// handles both.
 struct RonV0<T: MigrateConn> {
     /// The ID for this migration.
     migration_id: Uuid,
 
+    /// The negotiated protocol version.
+    protocol: Protocol,
+
     /// The logger for messages from this protocol.
     log: slog::Logger,
 
@@ -232,11 +343,12 @@ impl<T: MigrateConn + Sync> DestinationProtocol for RonV0<T> {
 impl<T: MigrateConn> RonV0<T> {
     fn new(
         log: slog::Logger,
+        protocol: Protocol,
         migration_id: Uuid,
         conn: WebSocketStream<T>,
         local_addr: SocketAddr,
     ) -> Self {
-        Self { log, migration_id, conn, local_addr }
+        Self { log, protocol, migration_id, conn, local_addr }
     }
 
     fn log(&self) -> &slog::Logger {
@@ -495,21 +607,7 @@ impl<T: MigrateConn> RonV0<T> {
 
         {
             let vm_objects = ensure_ctx.vm_objects().lock_shared().await;
-            let migrate_ctx =
-                MigrateCtx { mem: &vm_objects.access_mem().unwrap() };
-            for device in devices {
-                info!(
-                    self.log(),
-                    "Applying state to device {}", device.instance_name
-                );
-
-                let target = vm_objects
-                    .device_by_name(&device.instance_name)
-                    .ok_or_else(|| {
-                    MigrateError::UnknownDevice(device.instance_name.clone())
-                })?;
-                self.import_device(&target, &device, &migrate_ctx)?;
-            }
+            super::import_device_states(self.log(), &vm_objects, devices)?;
         }
 
         self.send_msg(codec::Message::Okay).await
@@ -537,188 +635,14 @@ impl<T: MigrateConn> RonV0<T> {
                     e
                 ))
             })?;
-        probes::migrate_time_data_before!(|| {
-            (
-                time_data_src.guest_freq,
-                time_data_src.guest_tsc,
-                time_data_src.boot_hrtime,
-            )
-        });
-
-        // Take a snapshot of the host hrtime/wall clock time, then adjust
-        // time data appropriately.
+
         let vmm_hdl =
             &ensure_ctx.vm_objects().lock_shared().await.vmm_hdl().clone();
-
-        let (dst_hrt, dst_wc) = vmm::time::host_time_snapshot(vmm_hdl)
-            .map_err(|e| {
-                MigrateError::TimeData(format!(
-                    "could not read host time: {}",
-                    e
-                ))
-            })?;
-        let (time_data_dst, adjust) =
-            vmm::time::adjust_time_data(time_data_src, dst_hrt, dst_wc)
-                .map_err(|e| {
-                    MigrateError::TimeData(format!(
-                        "could not adjust VMM Time Data: {}",
-                        e
-                    ))
-                })?;
-
-        // In case import fails, log adjustments made to time data and fire
-        // dtrace probe first
-        if adjust.migrate_delta_negative {
-            warn!(
-                self.log(),
-                "Found negative wall clock delta between target import \
-                and source export:\n\
-                - source wall clock time: {:?}\n\
-                - target wall clock time: {:?}\n",
-                time_data_src.wall_clock(),
-                dst_wc
-            );
-        }
-        info!(
-            self.log(),
-            "Time data adjustments:\n\
-            - guest TSC freq: {} Hz = {} GHz\n\
-            - guest uptime ns: {:?}\n\
-            - migration time delta: {:?}\n\
-            - guest_tsc adjustment = {} + {} = {}\n\
-            - boot_hrtime adjustment = {} ---> {} - {} = {}\n\
-            - dest highres clock time: {}\n\
-            - dest wall clock time: {:?}",
-            time_data_dst.guest_freq,
-            time_data_dst.guest_freq as f64 / vmm::time::NS_PER_SEC as f64,
-            adjust.guest_uptime_ns,
-            adjust.migrate_delta,
-            time_data_src.guest_tsc,
-            adjust.guest_tsc_delta,
-            time_data_dst.guest_tsc,
-            time_data_src.boot_hrtime,
-            dst_hrt,
-            adjust.boot_hrtime_delta,
-            time_data_dst.boot_hrtime,
-            dst_hrt,
-            dst_wc
-        );
-        probes::migrate_time_data_after!(|| {
-            (
-                time_data_dst.guest_freq,
-                time_data_dst.guest_tsc,
-                time_data_dst.boot_hrtime,
-                adjust.guest_uptime_ns,
-                adjust.migrate_delta.as_nanos() as u64,
-                adjust.migrate_delta_negative,
-            )
-        });
-
-        // Import the adjusted time data
-        vmm::time::import_time_data(vmm_hdl, time_data_dst).map_err(|e| {
-            MigrateError::TimeData(format!("VMM Time Data import error: {}", e))
-        })?;
+        super::import_time_data(self.log(), vmm_hdl, time_data_src)?;
 
         self.send_msg(codec::Message::Okay).await
     }
 
-    fn import_device(
-        &self,
-        target: &Arc<dyn Lifecycle>,
-        device: &Device,
-        migrate_ctx: &MigrateCtx,
-    ) -> Result<(), MigrateError> {
-        match target.migrate() {
-            Migrator::NonMigratable => {
-                error!(
-                    self.log(),
-                    "Can't migrate instance with non-migratable \
-                               device ({})",
-                    device.instance_name
-                );
-                return Err(MigrateStateError::NonMigratable.into());
-            }
-            Migrator::Empty => {
-                // The source shouldn't be sending devices with empty payloads
-                warn!(
-                    self.log(),
-                    "received unexpected device state for device {}",
-                    device.instance_name
-                );
-            }
-            Migrator::Single(mech) => {
-                if device.payload.len() != 1 {
-                    return Err(MigrateError::DeviceState(format!(
-                        "Unexpected payload count {}",
-                        device.payload.len()
-                    )));
-                }
-
-                let payload = &device.payload[0];
-                let ron_data = &mut ron::Deserializer::from_str(&payload.data)
-                    .map_err(codec::ProtocolError::from)?;
-                let clean =
-                    Box::new(<dyn erased_serde::Deserializer>::erase(ron_data));
-                let offer = PayloadOffer {
-                    kind: &payload.kind,
-                    version: payload.version,
-                    payload: clean,
-                };
-
-                mech.import(offer, migrate_ctx)?;
-            }
-            Migrator::Multi(mech) => {
-                // Assembling the collection of PayloadOffers looks a bit more
-                // verbose than ideal, but gathering the borrows (those split
-                // from Device, and the mutable Deserializer) all at once
-                // requires a delicate dance.
-                let mut payload_desers: Vec<ron::Deserializer> =
-                    Vec::with_capacity(device.payload.len());
-                let mut metadata: Vec<(&str, u32)> =
-                    Vec::with_capacity(device.payload.len());
-                for payload in device.payload.iter() {
-                    payload_desers.push(
-                        ron::Deserializer::from_str(&payload.data)
-                            .map_err(codec::ProtocolError::from)?,
-                    );
-                    metadata.push((&payload.kind, payload.version));
-                }
-                let offer_iter = metadata
-                    .iter()
-                    .zip(payload_desers.iter_mut())
-                    .map(|(meta, deser)| PayloadOffer {
-                        kind: meta.0,
-                        version: meta.1,
-                        payload: Box::new(
-                            <dyn erased_serde::Deserializer>::erase(deser),
-                        ),
-                    });
-
-                let mut offer = PayloadOffers::new(offer_iter);
-                mech.import(&mut offer, migrate_ctx)?;
-
-                let mut count = 0;
-                for offer in offer.remaining() {
-                    error!(
-                        self.log(),
-                        "Unexpected payload - device:{} kind:{} version:{}",
-                        &device.instance_name,
-                        offer.kind,
-                        offer.version,
-                    );
-                    count += 1;
-                }
-                if count != 0 {
-                    return Err(MigrateError::DeviceState(format!(
-                        "Found {} unconsumed payload(s) for device {}",
-                        count, &device.instance_name,
-                    )));
-                }
-            }
-        }
-        Ok(())
-    }
-
     async fn ram_pull(
         &mut self,
         ensure_ctx: &mut VmEnsureActive<'_>,
@@ -743,7 +667,7 @@ impl<T: MigrateConn> RonV0<T> {
                 .map_err(codec::ProtocolError::from)?,
         ))
         .await?;
-        let com1_history = match self.read_msg().await? {
+        let encoded = match self.read_msg().await? {
             codec::Message::Serialized(encoded) => encoded,
             msg => {
                 error!(self.log(), "server_state: unexpected message: {msg:?}");
@@ -751,14 +675,21 @@ impl<T: MigrateConn> RonV0<T> {
             }
         };
 
-        ensure_ctx
-            .vm_objects()
-            .lock_shared()
-            .await
-            .com1()
-            .import(&com1_history)
-            .await
-            .map_err(|e| MigrateError::Codec(e.to_string()))?;
+        let histories = match self.protocol {
+            Protocol::RonV0 => super::SerialHistories::from([(
+                SerialPortNumber::Com1,
+                encoded,
+            )]),
+            Protocol::RonV1 => {
+                ron::from_str(&encoded).map_err(codec::ProtocolError::from)?
+            }
+        };
+
+        super::import_serial_histories(
+            &*ensure_ctx.vm_objects().lock_shared().await,
+            histories,
+        )
+        .await?;
 
         self.send_msg(codec::Message::Okay).await
     }
@@ -2,20 +2,32 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
 use bit_field::BitField;
 use dropshot::HttpError;
-use propolis::migrate::MigrateStateError;
+use propolis::common::Lifecycle;
+use propolis::migrate::{
+    MigrateCtx, MigrateStateError, Migrator, PayloadOffer, PayloadOffers,
+    PayloadOutputs,
+};
+use propolis::vmm::{self, VmmHdl};
+use propolis_api_types::instance_spec::components::devices::SerialPortNumber;
 use propolis_api_types::MigrationState;
 use serde::{Deserialize, Serialize};
-use slog::error;
+use slog::{error, info, warn};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
 
+use crate::vm::objects::VmObjectsLocked;
+
 mod codec;
 pub mod destination;
 mod memx;
 mod preamble;
 pub mod protocol;
+pub mod savefile;
 pub mod source;
 
 /// Trait bounds for connection objects used in live migrations.
@@ -24,8 +36,9 @@ pub(crate) trait MigrateConn:
 {
 }
 
-impl MigrateConn for tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream> {}
-impl MigrateConn for hyper::upgrade::Upgraded {}
+// Migration connections may be plain TCP streams or upgraded HTTP connections,
+// either of which may be wrapped in TLS (or boxed to erase the difference).
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> MigrateConn for T {}
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum MigrateRole {
@@ -73,6 +86,10 @@ pub enum MigrateError {
     #[error("Websocket error: {0}")]
     Websocket(String),
 
+    /// Failed to authenticate the other end of the migration connection
+    #[error("TLS error: {0}")]
+    Tls(String),
+
     /// Failed to initiate the migration protocol
     #[error("couldn't establish migration connection to source instance")]
     Initiate,
@@ -143,6 +160,10 @@ pub enum MigrateError {
     /// The other end of the migration ran into an error
     #[error("{0:?} migration instance encountered error: {1}")]
     RemoteError(MigrateRole, String),
+
+    /// Failed to read or write a saved instance state file
+    #[error("saved state file error: {0}")]
+    SaveFile(String),
 }
 
 impl From<tokio_tungstenite::tungstenite::Error> for MigrateError {
@@ -168,6 +189,7 @@ impl From<MigrateError> for HttpError {
         let msg = format!("migration failed: {}", err);
         match &err {
             MigrateError::Websocket(_)
+            | MigrateError::Tls(_)
             | MigrateError::Initiate
             | MigrateError::ProtocolParse(_, _)
             | MigrateError::NoMatchingProtocol(_, _)
@@ -180,6 +202,7 @@ impl From<MigrateError> for HttpError {
             | MigrateError::TimeData(_)
             | MigrateError::DeviceState(_)
             | MigrateError::RemoteError(_, _)
+            | MigrateError::SaveFile(_)
             | MigrateError::StateMachine(_) => {
                 HttpError::for_internal_error(msg)
             }
@@ -215,6 +238,286 @@ struct DevicePayload {
     pub data: String,
 }
 
+/// Serialized serial console history buffers, keyed by the COM port to which
+/// each belongs.
+type SerialHistories = BTreeMap<SerialPortNumber, String>;
+
+/// Collects the serialized state of each of the devices in `objects`.
+///
+/// Returns an error if any device is not migratable. Devices with no state to
+/// transfer are omitted from the output.
+fn export_device_states(
+    log: &slog::Logger,
+    objects: &VmObjectsLocked,
+) -> Result<Vec<Device>, MigrateError> {
+    let mut device_states = vec![];
+    let migrate_ctx = MigrateCtx { mem: &objects.access_mem().unwrap() };
+
+    objects.for_each_device_fallible(|name, devop| {
+        let mut dev =
+            Device { instance_name: name.to_string(), payload: Vec::new() };
+        match devop.migrate() {
+            Migrator::NonMigratable => {
+                error!(
+                    log,
+                    "Can't migrate instance with non-migratable device ({})",
+                    name
+                );
+                return Err(MigrateError::DeviceState(
+                    MigrateStateError::NonMigratable.to_string(),
+                ));
+            }
+            // No device state needs to be trasmitted for 'Empty' devices
+            Migrator::Empty => {}
+            Migrator::Single(mech) => {
+                let out = mech.export(&migrate_ctx)?;
+                dev.payload.push(DevicePayload {
+                    kind: out.kind.to_owned(),
+                    version: out.version,
+                    data: ron::ser::to_string(&out.payload)
+                        .map_err(codec::ProtocolError::from)?,
+                });
+                device_states.push(dev);
+            }
+            Migrator::Multi(mech) => {
+                let mut outputs = PayloadOutputs::new();
+                mech.export(&mut outputs, &migrate_ctx)?;
+
+                for part in outputs {
+                    dev.payload.push(DevicePayload {
+                        kind: part.kind.to_owned(),
+                        version: part.version,
+                        data: ron::ser::to_string(&part.payload)
+                            .map_err(codec::ProtocolError::from)?,
+                    });
+                }
+                device_states.push(dev);
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(device_states)
+}
+
+/// Applies each of the serialized device states in `devices` to the
+/// correspondingly-named device in `objects`.
+fn import_device_states(
+    log: &slog::Logger,
+    objects: &VmObjectsLocked,
+    devices: Vec<Device>,
+) -> Result<(), MigrateError> {
+    let migrate_ctx = MigrateCtx { mem: &objects.access_mem().unwrap() };
+    for device in devices {
+        info!(log, "Applying state to device {}", device.instance_name);
+
+        let target =
+            objects.device_by_name(&device.instance_name).ok_or_else(|| {
+                MigrateError::UnknownDevice(device.instance_name.clone())
+            })?;
+        import_device(log, &target, &device, &migrate_ctx)?;
+    }
+
+    Ok(())
+}
+
+fn import_device(
+    log: &slog::Logger,
+    target: &Arc<dyn Lifecycle>,
+    device: &Device,
+    migrate_ctx: &MigrateCtx,
+) -> Result<(), MigrateError> {
+    match target.migrate() {
+        Migrator::NonMigratable => {
+            error!(
+                log,
+                "Can't migrate instance with non-migratable device ({})",
+                device.instance_name
+            );
+            return Err(MigrateStateError::NonMigratable.into());
+        }
+        Migrator::Empty => {
+            // The source shouldn't be sending devices with empty payloads
+            warn!(
+                log,
+                "received unexpected device state for device {}",
+                device.instance_name
+            );
+        }
+        Migrator::Single(mech) => {
+            if device.payload.len() != 1 {
+                return Err(MigrateError::DeviceState(format!(
+                    "Unexpected payload count {}",
+                    device.payload.len()
+                )));
+            }
+
+            let payload = &device.payload[0];
+            let ron_data = &mut ron::Deserializer::from_str(&payload.data)
+                .map_err(codec::ProtocolError::from)?;
+            let clean =
+                Box::new(<dyn erased_serde::Deserializer>::erase(ron_data));
+            let offer = PayloadOffer {
+                kind: &payload.kind,
+                version: payload.version,
+                payload: clean,
+            };
+
+            mech.import(offer, migrate_ctx)?;
+        }
+        Migrator::Multi(mech) => {
+            // Assembling the collection of PayloadOffers looks a bit more
+            // verbose than ideal, but gathering the borrows (those split from
+            // Device, and the mutable Deserializer) all at once requires a
+            // delicate dance.
+            let mut payload_desers: Vec<ron::Deserializer> =
+                Vec::with_capacity(device.payload.len());
+            let mut metadata: Vec<(&str, u32)> =
+                Vec::with_capacity(device.payload.len());
+            for payload in device.payload.iter() {
+                payload_desers.push(
+                    ron::Deserializer::from_str(&payload.data)
+                        .map_err(codec::ProtocolError::from)?,
+                );
+                metadata.push((&payload.kind, payload.version));
+            }
+            let offer_iter = metadata
+                .iter()
+                .zip(payload_desers.iter_mut())
+                .map(|(meta, deser)| PayloadOffer {
+                    kind: meta.0,
+                    version: meta.1,
+                    payload: Box::new(<dyn erased_serde::Deserializer>::erase(
+                        deser,
+                    )),
+                });
+
+            let mut offer = PayloadOffers::new(offer_iter);
+            mech.import(&mut offer, migrate_ctx)?;
+
+            let mut count = 0;
+            for offer in offer.remaining() {
+                error!(
+                    log,
+                    "Unexpected payload - device:{} kind:{} version:{}",
+                    &device.instance_name,
+                    offer.kind,
+                    offer.version,
+                );
+                count += 1;
+            }
+            if count != 0 {
+                return Err(MigrateError::DeviceState(format!(
+                    "Found {} unconsumed payload(s) for device {}",
+                    count, &device.instance_name,
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adjusts time data exported from another VMM (or at an earlier time) to
+/// account for the current host's clocks, then writes it to `vmm_hdl`.
+fn import_time_data(
+    log: &slog::Logger,
+    vmm_hdl: &VmmHdl,
+    time_data_src: vmm::time::VmTimeData,
+) -> Result<(), MigrateError> {
+    probes::migrate_time_data_before!(|| {
+        (
+            time_data_src.guest_freq,
+            time_data_src.guest_tsc,
+            time_data_src.boot_hrtime,
+        )
+    });
+
+    // Take a snapshot of the host hrtime/wall clock time, then adjust
+    // time data appropriately.
+    let (dst_hrt, dst_wc) =
+        vmm::time::host_time_snapshot(vmm_hdl).map_err(|e| {
+            MigrateError::TimeData(format!("could not read host time: {}", e))
+        })?;
+    let (time_data_dst, adjust) = vmm::time::adjust_time_data(
+        time_data_src,
+        dst_hrt,
+        dst_wc,
+    )
+    .map_err(|e| {
+        MigrateError::TimeData(format!("could not adjust VMM Time Data: {}", e))
+    })?;
+
+    // In case import fails, log adjustments made to time data and fire
+    // dtrace probe first
+    if adjust.migrate_delta_negative {
+        warn!(
+            log,
+            "Found negative wall clock delta between target import \
+            and source export:\n\
+            - source wall clock time: {:?}\n\
+            - target wall clock time: {:?}\n",
+            time_data_src.wall_clock(),
+            dst_wc
+        );
+    }
+    info!(
+        log,
+        "Time data adjustments:\n\
+        - guest TSC freq: {} Hz = {} GHz\n\
+        - guest uptime ns: {:?}\n\
+        - migration time delta: {:?}\n\
+        - guest_tsc adjustment = {} + {} = {}\n\
+        - boot_hrtime adjustment = {} ---> {} - {} = {}\n\
+        - dest highres clock time: {}\n\
+        - dest wall clock time: {:?}",
+        time_data_dst.guest_freq,
+        time_data_dst.guest_freq as f64 / vmm::time::NS_PER_SEC as f64,
+        adjust.guest_uptime_ns,
+        adjust.migrate_delta,
+        time_data_src.guest_tsc,
+        adjust.guest_tsc_delta,
+        time_data_dst.guest_tsc,
+        time_data_src.boot_hrtime,
+        dst_hrt,
+        adjust.boot_hrtime_delta,
+        time_data_dst.boot_hrtime,
+        dst_hrt,
+        dst_wc
+    );
+    probes::migrate_time_data_after!(|| {
+        (
+            time_data_dst.guest_freq,
+            time_data_dst.guest_tsc,
+            time_data_dst.boot_hrtime,
+            adjust.guest_uptime_ns,
+            adjust.migrate_delta.as_nanos() as u64,
+            adjust.migrate_delta_negative,
+        )
+    });
+
+    // Import the adjusted time data
+    vmm::time::import_time_data(vmm_hdl, time_data_dst).map_err(|e| {
+        MigrateError::TimeData(format!("VMM Time Data import error: {}", e))
+    })
+}
+
+/// Imports each of the serial console histories in `histories` into the
+/// matching COM port in `objects`.
+async fn import_serial_histories(
+    objects: &VmObjectsLocked,
+    histories: SerialHistories,
+) -> Result<(), MigrateError> {
+    for (port, history) in histories {
+        objects
+            .serial_port(port)
+            .ok_or_else(|| MigrateError::UnknownDevice(format!("{port:?}")))?
+            .import(&history)
+            .await?;
+    }
+
+    Ok(())
+}
+
 // We should probably turn this into some kind of ValidatedBitmap
 // data structure, so that we're only parsing it once.
 struct PageIter<'a> {
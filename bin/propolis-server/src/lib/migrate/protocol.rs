@@ -21,9 +21,14 @@ use thiserror::Error;
 
 /// The complete set of protocols supported by this version of the migration
 /// library.
-#[derive(Debug, Clone, Copy, EnumIter)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumIter)]
 pub enum Protocol {
+    /// RON encoding, transferring serial console history for COM1 only.
     RonV0,
+
+    /// RON encoding, transferring serial console history for every COM port
+    /// in the instance.
+    RonV1,
 }
 
 impl Protocol {
@@ -42,6 +47,9 @@ impl TryFrom<ProtocolParts> for Protocol {
             ProtocolParts { encoding: Encoding::Ron, version: 0 } => {
                 Self::RonV0
             }
+            ProtocolParts { encoding: Encoding::Ron, version: 1 } => {
+                Self::RonV1
+            }
             _ => anyhow::bail!(format!(
                 "no protocol matching definition: {:?}",
                 value
@@ -141,6 +149,9 @@ impl From<Protocol> for ProtocolParts {
             Protocol::RonV0 => {
                 ProtocolParts { version: 0, encoding: Encoding::Ron }
             }
+            Protocol::RonV1 => {
+                ProtocolParts { version: 1, encoding: Encoding::Ron }
+            }
         }
     }
 }
@@ -326,4 +337,18 @@ mod test {
         )
         .is_err());
     }
+
+    #[test]
+    fn supported_protocols_round_trip() {
+        for protocol in Protocol::iter() {
+            let selected =
+                select_protocol_from_offer(&protocol.offer_string()).unwrap();
+            assert_eq!(selected, Some(protocol));
+        }
+
+        assert_eq!(
+            select_protocol_from_offer(&make_protocol_offer()).unwrap(),
+            Some(Protocol::RonV1)
+        );
+    }
 }
@@ -0,0 +1,372 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Saving instance state to, and restoring it from, a file on the local host.
+//!
+//! A save file holds the same state that a live migration source sends to its
+//! target, encoded with the live migration protocol's message codec. After an
+//! eight-byte magic number, the file consists of a sequence of codec messages,
+//! each prefixed by its encoded length as a little-endian `u64`:
+//!
+//! 1. A serialized [`Preamble`] describing the saved instance's devices and
+//!    backends. Restoring requires an instance spec that is
+//!    migration-compatible with this preamble.
+//! 2. Guest RAM: zero or more `MemXfer` messages, each followed by one `Page`
+//!    message for every page set in its bitmap, terminated by `MemDone`. Pages
+//!    that are entirely zero are omitted, since a newly created instance's
+//!    memory is already zero-filled.
+//! 3. Serialized VMM time data.
+//! 4. Serialized device state payloads.
+//! 5. Serialized serial console histories for each COM port.
+
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+use propolis::common::{GuestAddr, PAGE_SIZE};
+use propolis::vmm;
+use propolis_api_types::instance_spec::{
+    v0::InstanceSpecV0, VersionedInstanceSpec,
+};
+use serde::Serialize;
+use slog::{error, info};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio_tungstenite::tungstenite;
+
+use crate::migrate::codec;
+use crate::migrate::memx;
+use crate::migrate::preamble::Preamble;
+use crate::migrate::{Device, MigrateError, PageIter};
+use crate::vm::ensure::{VmEnsureActive, VmEnsureNotStarted};
+use crate::vm::objects::{VmObjects, VmObjectsLocked};
+
+/// Identifies a file as a Propolis save file.
+const SAVE_FILE_MAGIC: &[u8; 8] = b"PRPLSAV0";
+
+/// The largest single message a save file reader will accept. This keeps a
+/// corrupt length prefix from causing an enormous allocation.
+const MAX_MESSAGE_LEN: u64 = 1 << 30;
+
+/// The number of pages described by each `MemXfer` message in a save file.
+const PAGES_PER_XFER: usize = 4096 * 8;
+
+fn io_error(e: std::io::Error) -> MigrateError {
+    MigrateError::SaveFile(e.to_string())
+}
+
+struct SaveFileWriter {
+    file: BufWriter<File>,
+}
+
+impl SaveFileWriter {
+    async fn create(path: &Path) -> Result<Self, MigrateError> {
+        let mut file =
+            BufWriter::new(File::create(path).await.map_err(io_error)?);
+        file.write_all(SAVE_FILE_MAGIC).await.map_err(io_error)?;
+        Ok(Self { file })
+    }
+
+    async fn send_msg(
+        &mut self,
+        m: codec::Message,
+    ) -> Result<(), MigrateError> {
+        let encoded: tungstenite::Message = m.try_into()?;
+        let tungstenite::Message::Binary(bytes) = encoded else {
+            unreachable!("codec messages always encode as binary frames");
+        };
+
+        self.file.write_u64_le(bytes.len() as u64).await.map_err(io_error)?;
+        self.file.write_all(&bytes).await.map_err(io_error)
+    }
+
+    async fn send_serialized<T: Serialize + Sync>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), MigrateError> {
+        let s =
+            ron::ser::to_string(value).map_err(codec::ProtocolError::from)?;
+        self.send_msg(codec::Message::Serialized(s)).await
+    }
+
+    async fn finish(mut self) -> Result<(), MigrateError> {
+        self.file.flush().await.map_err(io_error)?;
+        self.file.get_ref().sync_all().await.map_err(io_error)
+    }
+}
+
+struct SaveFileReader {
+    file: BufReader<File>,
+}
+
+impl SaveFileReader {
+    async fn open(path: &Path) -> Result<Self, MigrateError> {
+        let mut file =
+            BufReader::new(File::open(path).await.map_err(io_error)?);
+        let mut magic = [0u8; SAVE_FILE_MAGIC.len()];
+        file.read_exact(&mut magic).await.map_err(io_error)?;
+        if &magic != SAVE_FILE_MAGIC {
+            return Err(MigrateError::SaveFile(format!(
+                "{} is not a Propolis save file",
+                path.display()
+            )));
+        }
+
+        Ok(Self { file })
+    }
+
+    async fn read_msg(&mut self) -> Result<codec::Message, MigrateError> {
+        let len = self.file.read_u64_le().await.map_err(io_error)?;
+        if len > MAX_MESSAGE_LEN {
+            return Err(MigrateError::SaveFile(format!(
+                "message length {len} exceeds maximum of {MAX_MESSAGE_LEN}"
+            )));
+        }
+
+        let mut buf = vec![0u8; len as usize];
+        self.file.read_exact(&mut buf).await.map_err(io_error)?;
+        Ok(tungstenite::Message::Binary(buf).try_into()?)
+    }
+
+    async fn read_serialized(&mut self) -> Result<String, MigrateError> {
+        match self.read_msg().await? {
+            codec::Message::Serialized(s) => Ok(s),
+            _ => Err(MigrateError::UnexpectedMessage),
+        }
+    }
+}
+
+/// Writes the state of the VM in `vm_objects` to the file at `path`.
+///
+/// The caller must pause the VM before calling this routine and is responsible
+/// for resuming it afterward. The file is written under a temporary name and
+/// renamed into place only once it is complete, so a failed save never leaves
+/// a truncated file at `path`.
+pub(crate) async fn save(
+    log: &slog::Logger,
+    vm_objects: &VmObjects,
+    path: &Path,
+) -> Result<(), MigrateError> {
+    info!(log, "saving instance state"; "path" => %path.display());
+
+    let tmp_path = {
+        let mut p = path.as_os_str().to_owned();
+        p.push(".partial");
+        PathBuf::from(p)
+    };
+
+    let objects = vm_objects.lock_shared().await;
+    match write_save_file(log, &objects, &tmp_path).await {
+        Ok(()) => tokio::fs::rename(&tmp_path, path).await.map_err(io_error),
+        Err(e) => {
+            error!(log, "failed to save instance state"; "error" => %e);
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            Err(e)
+        }
+    }
+}
+
+async fn write_save_file(
+    log: &slog::Logger,
+    objects: &VmObjectsLocked,
+    path: &Path,
+) -> Result<(), MigrateError> {
+    let mut out = SaveFileWriter::create(path).await?;
+
+    let preamble = Preamble::new(VersionedInstanceSpec::V0(
+        objects.instance_spec().clone(),
+    ));
+    out.send_serialized(&preamble).await?;
+
+    write_ram(objects, &mut out).await?;
+
+    let time_data =
+        vmm::time::export_time_data(objects.vmm_hdl()).map_err(|e| {
+            MigrateError::TimeData(format!("VMM Time Data export error: {}", e))
+        })?;
+    info!(log, "VMM Time Data: {:#?}", time_data);
+    out.send_serialized(&time_data).await?;
+
+    let devices = super::export_device_states(log, objects)?;
+    out.send_serialized(&devices).await?;
+
+    let mut histories = super::SerialHistories::new();
+    for (port, serial) in objects.serial_ports() {
+        histories.insert(*port, serial.save_history().await?);
+    }
+    out.send_serialized(&histories).await?;
+
+    out.finish().await
+}
+
+/// Reads the page at `gpa` into `buf`, returning `true` if the page is mapped
+/// and contains any nonzero bytes.
+fn read_nonzero_page(
+    objects: &VmObjectsLocked,
+    gpa: u64,
+    buf: &mut [u8; PAGE_SIZE],
+) -> bool {
+    let memctx = objects.access_mem().unwrap();
+    memctx.direct_read_into(GuestAddr(gpa), buf, PAGE_SIZE) == Some(PAGE_SIZE)
+        && buf.iter().any(|&b| b != 0)
+}
+
+async fn write_ram(
+    objects: &VmObjectsLocked,
+    out: &mut SaveFileWriter,
+) -> Result<(), MigrateError> {
+    let bounds = objects
+        .access_mem()
+        .unwrap()
+        .mem_bounds()
+        .ok_or(MigrateError::InvalidInstanceState)?;
+
+    // The memory bounds are inclusive; convert them to a page-aligned
+    // half-open range.
+    let start_gpa = bounds.start().0;
+    let end_gpa = bounds.end().0 + 1;
+
+    let mut page = [0u8; PAGE_SIZE];
+    let step = (PAGES_PER_XFER * PAGE_SIZE) as u64;
+    for gpa in (start_gpa..end_gpa).step_by(step as usize) {
+        let end = end_gpa.min(gpa + step);
+        let npages = ((end - gpa) / PAGE_SIZE as u64) as usize;
+
+        // The guest is paused, so its memory can safely be scanned once to
+        // build the bitmap and again to write out the selected pages.
+        let mut bits = vec![0u8; npages.div_ceil(8)];
+        for i in 0..npages {
+            let addr = gpa + (i * PAGE_SIZE) as u64;
+            if read_nonzero_page(objects, addr, &mut page) {
+                bits[i / 8] |= 1 << (i % 8);
+            }
+        }
+
+        if bits.iter().all(|&b| b == 0) {
+            continue;
+        }
+
+        out.send_msg(memx::make_mem_xfer(gpa, end, &bits)).await?;
+        for addr in PageIter::new(gpa, end, &bits) {
+            read_nonzero_page(objects, addr, &mut page);
+            out.send_msg(codec::Message::Page(page.to_vec())).await?;
+        }
+    }
+
+    out.send_msg(codec::Message::MemDone).await
+}
+
+/// Creates a new VM using the instance spec in `ensure` and initializes its
+/// state from the save file at `path`.
+///
+/// On success, returns an active VM placeholder whose objects are ready to be
+/// started as though they had just been migrated in. On failure, unwinds the
+/// ensure operation.
+pub(crate) async fn restore<'ensure>(
+    log: &slog::Logger,
+    path: &Path,
+    ensure: VmEnsureNotStarted<'ensure>,
+) -> Result<VmEnsureActive<'ensure>, MigrateError> {
+    info!(log, "restoring instance from saved state";
+          "path" => %path.display());
+
+    let mut input = match open_and_check(path, ensure.instance_spec()).await {
+        Ok(input) => input,
+        Err(e) => {
+            error!(log, "saved state file can't be restored"; "error" => %e);
+            let e = ensure.fail(e.into()).await;
+            return Err(e
+                .downcast::<MigrateError>()
+                .expect("original error was a MigrateError"));
+        }
+    };
+
+    let mut objects_created = ensure.create_objects().await.map_err(|e| {
+        MigrateError::TargetInstanceInitializationFailed(e.to_string())
+    })?;
+    objects_created.prepare_for_migration().await;
+    let ensure = objects_created.ensure_active().await;
+
+    if let Err(e) = import_state(log, &mut input, ensure.vm_objects()).await {
+        error!(log, "failed to restore instance state"; "error" => %e);
+        ensure.fail().await;
+        return Err(e);
+    }
+
+    info!(log, "restored instance from saved state");
+    Ok(ensure)
+}
+
+async fn open_and_check(
+    path: &Path,
+    spec: &InstanceSpecV0,
+) -> Result<SaveFileReader, MigrateError> {
+    let mut input = SaveFileReader::open(path).await?;
+    let preamble: Preamble = ron::from_str(&input.read_serialized().await?)
+        .map_err(codec::ProtocolError::from)?;
+
+    preamble.is_migration_compatible(spec).map_err(|e| {
+        MigrateError::SaveFile(format!(
+            "saved instance is incompatible with the requested spec: {e}"
+        ))
+    })?;
+
+    Ok(input)
+}
+
+async fn import_state(
+    log: &slog::Logger,
+    input: &mut SaveFileReader,
+    vm_objects: &VmObjects,
+) -> Result<(), MigrateError> {
+    let objects = vm_objects.lock_shared().await;
+
+    // As with live migration, RAM and time data must be in place before
+    // device state is imported.
+    read_ram(input, &objects).await?;
+
+    let time_data: vmm::time::VmTimeData =
+        ron::from_str(&input.read_serialized().await?).map_err(|e| {
+            MigrateError::TimeData(format!(
+                "VMM Time Data deserialization error: {}",
+                e
+            ))
+        })?;
+    super::import_time_data(log, objects.vmm_hdl(), time_data)?;
+
+    let devices: Vec<Device> = ron::from_str(&input.read_serialized().await?)
+        .map_err(codec::ProtocolError::from)?;
+    super::import_device_states(log, &objects, devices)?;
+
+    let histories: super::SerialHistories =
+        ron::from_str(&input.read_serialized().await?)
+            .map_err(codec::ProtocolError::from)?;
+    super::import_serial_histories(&objects, histories).await
+}
+
+async fn read_ram(
+    input: &mut SaveFileReader,
+    objects: &VmObjectsLocked,
+) -> Result<(), MigrateError> {
+    loop {
+        match input.read_msg().await? {
+            codec::Message::MemXfer(start, end, bits) => {
+                if !memx::validate_bitmap(start, end, &bits) {
+                    return Err(MigrateError::Phase);
+                }
+
+                for addr in PageIter::new(start, end, &bits) {
+                    let page = match input.read_msg().await? {
+                        codec::Message::Page(bytes) => bytes,
+                        _ => return Err(MigrateError::UnexpectedMessage),
+                    };
+
+                    let memctx = objects.access_mem().unwrap();
+                    memctx.write_from(GuestAddr(addr), &page, page.len());
+                }
+            }
+            codec::Message::MemDone => return Ok(()),
+            _ => return Err(MigrateError::UnexpectedMessage),
+        }
+    }
+}
@@ -5,11 +5,10 @@
 use bitvec::prelude::{BitSlice, Lsb0};
 use futures::{SinkExt, StreamExt};
 use propolis::common::{GuestAddr, PAGE_SIZE};
-use propolis::migrate::{
-    MigrateCtx, MigrateStateError, Migrator, PayloadOutputs,
-};
 use propolis::vmm;
-use propolis_api_types::instance_spec::VersionedInstanceSpec;
+use propolis_api_types::instance_spec::{
+    components::devices::SerialPortNumber, VersionedInstanceSpec,
+};
 use slog::{debug, error, info, trace, warn};
 use std::collections::HashMap;
 use std::convert::TryInto;
@@ -27,8 +26,7 @@ use crate::migrate::probes;
 use crate::migrate::protocol::Protocol;
 use crate::migrate::{codec, protocol};
 use crate::migrate::{
-    Device, DevicePayload, MigrateError, MigratePhase, MigrateRole,
-    MigrationState, PageIter,
+    MigrateError, MigratePhase, MigrateRole, MigrationState, PageIter,
 };
 
 use crate::vm::objects::VmObjects;
@@ -218,8 +216,9 @@ pub(crate) async fn initiate<T: MigrateConn>(
 
     info!(log, "selected protocol {:?}", selected);
     match selected {
-        Protocol::RonV0 => Ok(RonV0::new(
+        Protocol::RonV0 | Protocol::RonV1 => Ok(RonV0::new(
             log,
+            selected,
             vm_objects,
             migration_id,
             conn,
@@ -244,10 +243,15 @@ pub(crate) struct PersistentState {
 }
 
 /// Context for the source side of protocol version 0 using the RON encoding.
+/// Version 1 differs only in how serial console history is transferred, so
+/// this context handles both.
 struct RonV0<T: MigrateConn> {
     /// The logger to which to log messages from this migration attempt.
     log: slog::Logger,
 
+    /// The negotiated protocol version.
+    protocol: Protocol,
+
     /// The migration's ID.
     migration_id: Uuid,
 
@@ -282,6 +286,7 @@ type PageBitmap = [u8; PAGE_BITMAP_SIZE];
 impl<T: MigrateConn> RonV0<T> {
     async fn new(
         log: slog::Logger,
+        protocol: Protocol,
         vm: &VmObjects,
         migration_id: Uuid,
         conn: WebSocketStream<T>,
@@ -309,7 +314,7 @@ impl<T: MigrateConn> RonV0<T> {
                 None
             }
         };
-        Self { log, migration_id, conn, dirt }
+        Self { log, protocol, migration_id, conn, dirt }
     }
 }
 
@@ -323,6 +328,7 @@ impl<T: MigrateConn> SourceProtocol for RonV0<T> {
     ) -> Result<(), MigrateError> {
         let mut runner = RonV0Runner {
             log: self.log,
+            protocol: self.protocol,
             migration_id: self.migration_id,
             conn: self.conn,
             dirt: self.dirt,
@@ -338,6 +344,7 @@ impl<T: MigrateConn> SourceProtocol for RonV0<T> {
 
 struct RonV0Runner<'vm, T: MigrateConn> {
     log: slog::Logger,
+    protocol: Protocol,
     migration_id: Uuid,
     conn: WebSocketStream<T>,
     dirt: Option<HashMap<GuestAddr, PageBitmap>>,
@@ -676,56 +683,10 @@ impl<'vm, T: MigrateConn> RonV0Runner<'vm, T> {
 
     async fn device_state(&mut self) -> Result<(), MigrateError> {
         self.update_state(MigrationState::Device);
-        let mut device_states = vec![];
-        {
+        let device_states = {
             let objects = self.vm.lock_shared().await;
-            let migrate_ctx =
-                MigrateCtx { mem: &objects.access_mem().unwrap() };
-
-            // Collect together the serialized state for all the devices
-            objects.for_each_device_fallible(|name, devop| {
-                let mut dev = Device {
-                    instance_name: name.to_string(),
-                    payload: Vec::new(),
-                };
-                match devop.migrate() {
-                    Migrator::NonMigratable => {
-                        error!(self.log(),
-                            "Can't migrate instance with non-migratable device ({})",
-                            name);
-                        return Err(MigrateError::DeviceState(
-                                MigrateStateError::NonMigratable.to_string()));
-                    },
-                    // No device state needs to be trasmitted for 'Empty' devices
-                    Migrator::Empty => {},
-                    Migrator::Single(mech) => {
-                        let out = mech.export(&migrate_ctx)?;
-                        dev.payload.push(DevicePayload {
-                            kind: out.kind.to_owned(),
-                            version: out.version,
-                            data: ron::ser::to_string(&out.payload)
-                                .map_err(codec::ProtocolError::from)?,
-                        });
-                        device_states.push(dev);
-                    }
-                    Migrator::Multi(mech) => {
-                        let mut outputs = PayloadOutputs::new();
-                        mech.export(&mut outputs, &migrate_ctx)?;
-
-                        for part in outputs {
-                            dev.payload.push(DevicePayload {
-                                kind: part.kind.to_owned(),
-                                version: part.version,
-                                data: ron::ser::to_string(&part.payload)
-                                    .map_err(codec::ProtocolError::from)?,
-                            });
-                        }
-                        device_states.push(dev);
-                    }
-                }
-                Ok(())
-            })?;
-        }
+            super::export_device_states(self.log(), &objects)?
+        };
 
         info!(self.log(), "Device States: {device_states:#?}");
 
@@ -779,14 +740,33 @@ impl<'vm, T: MigrateConn> RonV0Runner<'vm, T> {
             }
             _ => return Err(MigrateError::UnexpectedMessage),
         };
-        let com1_history = self
-            .vm
-            .lock_shared()
-            .await
-            .com1()
-            .export_history(remote_addr)
-            .await?;
-        self.send_msg(codec::Message::Serialized(com1_history)).await?;
+
+        // Version 0 of the protocol only carries COM1's history. Leave clients
+        // of other ports connected in that case, since the destination won't
+        // have their history to offer them.
+        let mut histories = super::SerialHistories::new();
+        {
+            let objects = self.vm.lock_shared().await;
+            for (port, serial) in objects.serial_ports() {
+                if self.protocol == Protocol::RonV0
+                    && *port != SerialPortNumber::Com1
+                {
+                    continue;
+                }
+
+                histories
+                    .insert(*port, serial.export_history(remote_addr).await?);
+            }
+        }
+
+        let encoded = match self.protocol {
+            Protocol::RonV0 => histories
+                .remove(&SerialPortNumber::Com1)
+                .ok_or(MigrateError::InvalidInstanceState)?,
+            Protocol::RonV1 => ron::to_string(&histories)
+                .map_err(codec::ProtocolError::from)?,
+        };
+        self.send_msg(codec::Message::Serialized(encoded)).await?;
         self.read_ok().await
     }
 
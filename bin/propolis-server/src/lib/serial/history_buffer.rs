@@ -54,10 +54,31 @@ impl TryFrom<&api::InstanceSerialConsoleStreamRequest> for SerialHistoryOffset {
             api::InstanceSerialConsoleStreamRequest {
                 from_start: Some(offset),
                 most_recent: None,
+                ..
             } => Ok(SerialHistoryOffset::FromStart(*offset as usize)),
             api::InstanceSerialConsoleStreamRequest {
                 from_start: None,
                 most_recent: Some(offset),
+                ..
+            } => Ok(SerialHistoryOffset::MostRecent(*offset as usize)),
+            _ => Err(()),
+        }
+    }
+}
+
+impl TryFrom<&api::InstanceVirtioSerialStreamRequest> for SerialHistoryOffset {
+    type Error = ();
+    fn try_from(
+        req: &api::InstanceVirtioSerialStreamRequest,
+    ) -> Result<Self, ()> {
+        match req {
+            api::InstanceVirtioSerialStreamRequest {
+                from_start: Some(offset),
+                most_recent: None,
+            } => Ok(SerialHistoryOffset::FromStart(*offset as usize)),
+            api::InstanceVirtioSerialStreamRequest {
+                from_start: None,
+                most_recent: Some(offset),
             } => Ok(SerialHistoryOffset::MostRecent(*offset as usize)),
             _ => Err(()),
         }
@@ -219,6 +240,12 @@ impl HistoryBuffer {
         }
     }
 
+    /// Returns the most recently buffered bytes, i.e. the contents of the
+    /// rolling buffer.
+    pub fn recent_contents(&self) -> Vec<u8> {
+        self.rolling.iter().copied().collect()
+    }
+
     /// Returns the number of bytes output since instance boot.
     pub fn bytes_from_start(&self) -> usize {
         self.total_bytes
@@ -0,0 +1,331 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Persists serial console output to size-rotated log files.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use chrono::{SecondsFormat, Utc};
+use propolis_api_types::instance_spec::components::devices::SerialPortNumber;
+use slog::{warn, Logger};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::config::SerialLog;
+
+/// The number of reads from a serial port that may await the logging task
+/// before further output is left out of the log.
+const WRITER_QUEUE_DEPTH: usize = 64;
+
+/// A log file to which a serial port's output is appended.
+pub(crate) struct SerialLogFile {
+    /// The path to the file currently being written. Rotated files have the
+    /// same path with a numeric suffix appended, e.g. `com1.log.1`, with
+    /// larger suffixes denoting older files.
+    path: PathBuf,
+
+    /// The currently open file, or `None` if logging has been abandoned after
+    /// an I/O error.
+    file: Option<File>,
+
+    /// The number of bytes in the currently open file.
+    size: u64,
+
+    /// The size past which the file is rotated, if it should be rotated.
+    max_size: Option<u64>,
+
+    /// The number of rotated files to keep.
+    max_files: usize,
+
+    /// Whether to prefix each line with a timestamp.
+    timestamps: bool,
+
+    /// True if the next byte written begins a new line.
+    at_line_start: bool,
+
+    log: Logger,
+}
+
+impl SerialLogFile {
+    /// Opens (or creates) the log file for serial port `port` in the directory
+    /// specified by `config`.
+    pub(crate) fn open(
+        config: &SerialLog,
+        port: SerialPortNumber,
+        log: Logger,
+    ) -> io::Result<Self> {
+        let file_name = match port {
+            SerialPortNumber::Com1 => "com1.log",
+            SerialPortNumber::Com2 => "com2.log",
+            SerialPortNumber::Com3 => "com3.log",
+            SerialPortNumber::Com4 => "com4.log",
+        };
+
+        std::fs::create_dir_all(&config.directory)?;
+        let path = config.directory.join(file_name);
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file: Some(File::from_std(file)),
+            size,
+            max_size: config.max_size,
+            max_files: config.max_files,
+            timestamps: config.timestamps,
+            at_line_start: true,
+            log,
+        })
+    }
+
+    /// Opens (or creates) a log file at `path`, which is appended to without
+    /// rotation or timestamps.
+    pub(crate) fn open_path(path: PathBuf, log: Logger) -> io::Result<Self> {
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file: Some(File::from_std(file)),
+            size,
+            max_size: None,
+            max_files: 0,
+            timestamps: false,
+            at_line_start: true,
+            log,
+        })
+    }
+
+    /// Appends `data` to the log, rotating the log first if necessary.
+    ///
+    /// Errors are logged rather than returned, and stop any further output
+    /// from being written, so that a full or broken disk does not interfere
+    /// with the serial console itself.
+    pub(crate) async fn write(&mut self, data: &[u8]) {
+        if self.file.is_none() {
+            return;
+        }
+
+        if let Err(e) = self.try_write(data).await {
+            warn!(self.log, "failed to write serial log, disabling it";
+                  "path" => %self.path.display(),
+                  "error" => %e);
+            self.file = None;
+        }
+    }
+
+    async fn try_write(&mut self, data: &[u8]) -> io::Result<()> {
+        let buf = if self.timestamps {
+            self.add_timestamps(data)
+        } else {
+            data.to_vec()
+        };
+
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + buf.len() as u64 > max_size {
+                self.rotate().await?;
+            }
+        }
+
+        let file = self.file.as_mut().expect("log file is open");
+        file.write_all(&buf).await?;
+        file.flush().await?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Returns a copy of `data` with a timestamp inserted at the start of each
+    /// line.
+    fn add_timestamps(&mut self, data: &[u8]) -> Vec<u8> {
+        let stamp = format!(
+            "[{}] ",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+        );
+        let mut buf = Vec::with_capacity(data.len() + stamp.len());
+        for &b in data {
+            if self.at_line_start {
+                buf.extend_from_slice(stamp.as_bytes());
+            }
+            buf.push(b);
+            self.at_line_start = b == b'\n';
+        }
+
+        buf
+    }
+
+    /// Shifts each existing rotated file to the next-highest suffix, moves the
+    /// current file to suffix 1, and starts a new, empty current file.
+    async fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path).await?;
+        } else {
+            let _ =
+                fs::remove_file(rotated_path(&self.path, self.max_files)).await;
+            for n in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, n);
+                if fs::try_exists(&from).await? {
+                    fs::rename(&from, rotated_path(&self.path, n + 1)).await?;
+                }
+            }
+
+            fs::rename(&self.path, rotated_path(&self.path, 1)).await?;
+        }
+
+        self.file = Some(File::from_std(open_append(&self.path)?));
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Appends a serial port's output to a [`SerialLogFile`] from a separate task,
+/// so that reading from the port never waits for the disk.
+pub(crate) struct SerialLogWriter {
+    tx: mpsc::Sender<Vec<u8>>,
+
+    /// True if output has been left out of the log since the last write that
+    /// could be queued.
+    dropping: AtomicBool,
+
+    path: PathBuf,
+    log: Logger,
+}
+
+impl SerialLogWriter {
+    /// Spawns a task which appends everything passed to [`Self::write()`] to
+    /// `file`. The task exits once the writer is dropped and all of the output
+    /// queued before then has been written.
+    pub(crate) fn spawn(mut file: SerialLogFile) -> Self {
+        let (path, log) = (file.path.clone(), file.log.clone());
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(WRITER_QUEUE_DEPTH);
+        tokio::spawn(async move {
+            while let Some(data) = rx.recv().await {
+                file.write(&data).await;
+            }
+        });
+
+        Self { tx, dropping: AtomicBool::new(false), path, log }
+    }
+
+    /// Queues `data` to be appended to the log. If the logging task has fallen
+    /// too far behind, `data` is left out of the log instead.
+    pub(crate) fn write(&self, data: &[u8]) {
+        match self.tx.try_send(data.to_vec()) {
+            Ok(()) => self.dropping.store(false, Ordering::Relaxed),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                if !self.dropping.swap(true, Ordering::Relaxed) {
+                    warn!(self.log, "serial log fell behind, dropping output";
+                          "path" => %self.path.display());
+                }
+            }
+            // The task has been cancelled by the runtime shutting down.
+            Err(mpsc::error::TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<std::fs::File> {
+    std::fs::OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    name.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_logger() -> Logger {
+        Logger::root(slog::Discard, slog::o!())
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "propolis-serial-log-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[tokio::test]
+    async fn rotates_at_max_size() {
+        let dir = test_dir("rotate");
+        let config = SerialLog {
+            directory: dir.clone(),
+            max_size: Some(8),
+            max_files: 2,
+            timestamps: false,
+        };
+
+        let mut log =
+            SerialLogFile::open(&config, SerialPortNumber::Com1, test_logger())
+                .unwrap();
+        for chunk in [b"aaaaaa", b"bbbbbb", b"cccccc", b"dddddd"] {
+            log.write(chunk).await;
+        }
+
+        let read = |name: &str| std::fs::read(dir.join(name)).unwrap();
+        assert_eq!(read("com1.log"), b"dddddd");
+        assert_eq!(read("com1.log.1"), b"cccccc");
+        assert_eq!(read("com1.log.2"), b"bbbbbb");
+        assert!(!dir.join("com1.log.3").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn timestamps_each_line() {
+        let dir = test_dir("timestamps");
+        let config = SerialLog {
+            directory: dir.clone(),
+            max_size: None,
+            max_files: 0,
+            timestamps: true,
+        };
+
+        let mut log =
+            SerialLogFile::open(&config, SerialPortNumber::Com1, test_logger())
+                .unwrap();
+        log.write(b"first\nsec").await;
+        log.write(b"ond\n").await;
+
+        let contents =
+            String::from_utf8(std::fs::read(dir.join("com1.log")).unwrap())
+                .unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with('[') && lines[0].ends_with("] first"));
+        assert!(lines[1].starts_with('[') && lines[1].ends_with("] second"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writer_appends_in_background() {
+        let dir = test_dir("writer");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("port.log");
+
+        let file =
+            SerialLogFile::open_path(path.clone(), test_logger()).unwrap();
+        let writer = SerialLogWriter::spawn(file);
+        writer.write(b"hello, ");
+        writer.write(b"world");
+
+        let deadline =
+            std::time::Instant::now() + std::time::Duration::from_secs(10);
+        while std::fs::read(&path).unwrap() != b"hello, world" {
+            assert!(std::time::Instant::now() < deadline, "log not written");
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -14,6 +14,8 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use crate::serial::history_buffer::{HistoryBuffer, SerialHistoryOffset};
+use crate::serial::logfile::{SerialLogFile, SerialLogWriter};
+use crate::serial::terminal::TerminalModel;
 use futures::future::Fuse;
 use futures::stream::SplitSink;
 use futures::{FutureExt, SinkExt, StreamExt};
@@ -31,6 +33,8 @@ use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::{tungstenite, WebSocketStream};
 
 pub(crate) mod history_buffer;
+pub(crate) mod logfile;
+pub(crate) mod terminal;
 
 #[usdt::provider(provider = "propolis")]
 mod probes {
@@ -289,6 +293,8 @@ pub struct Serial<Device: Sink + Source> {
     sink_poller: Arc<pollers::SinkBuffer>,
     source_poller: Arc<pollers::SourceBuffer>,
     history: AsyncRwLock<HistoryBuffer>,
+    terminal: Mutex<TerminalModel>,
+    logfile: Option<SerialLogWriter>,
 }
 
 impl<Device: Sink + Source> Serial<Device> {
@@ -302,10 +308,13 @@ impl<Device: Sink + Source> Serial<Device> {
     /// * `uart` - The device which data will be read from / written to.
     /// * `sink_size` - A lower bound on the size of the writeback buffer.
     /// * `source_size` - A lower bound on the size of the read buffer.
+    /// * `logfile` - An optional file to which to append all data read from
+    ///   the device, which is written by a separate task.
     pub fn new(
         uart: Arc<Device>,
         sink_size: NonZeroUsize,
         source_size: NonZeroUsize,
+        logfile: Option<SerialLogFile>,
     ) -> Serial<Device> {
         let sink_poller = pollers::SinkBuffer::new(sink_size);
         let source_poller = pollers::SourceBuffer::new(pollers::Params {
@@ -314,19 +323,33 @@ impl<Device: Sink + Source> Serial<Device> {
             poll_miss_thresh: 5,
         });
         let history = Default::default();
+        let terminal = Default::default();
+        let logfile = logfile.map(SerialLogWriter::spawn);
         sink_poller.attach(uart.as_ref());
         source_poller.attach(uart.as_ref());
         uart.set_autodiscard(false);
 
         let task_control_ch = Default::default();
 
-        Serial { uart, task_control_ch, sink_poller, source_poller, history }
+        Serial {
+            uart,
+            task_control_ch,
+            sink_poller,
+            source_poller,
+            history,
+            terminal,
+            logfile,
+        }
     }
 
     pub async fn read_source(&self, buf: &mut [u8]) -> Option<usize> {
         let uart = self.uart.clone();
         let bytes_read = self.source_poller.read(buf, uart.as_ref()).await?;
         self.history.write().await.consume(&buf[..bytes_read]);
+        self.terminal.lock().await.consume(&buf[..bytes_read]);
+        if let Some(logfile) = &self.logfile {
+            logfile.write(&buf[..bytes_read]);
+        }
         Some(bytes_read)
     }
 
@@ -343,6 +366,12 @@ impl<Device: Sink + Source> Serial<Device> {
         self.history.read().await.contents_vec(byte_offset, max_bytes)
     }
 
+    /// Returns a byte sequence that redraws the current contents of the
+    /// terminal screen this console is driving.
+    pub(crate) async fn terminal_redraw(&self) -> Vec<u8> {
+        self.terminal.lock().await.redraw()
+    }
+
     // provide the channel through which we inform connected websocket clients
     // that a migration has occurred, and where to reconnect.
     // (the server's serial-to-websocket task -- and thus the receiving end of
@@ -375,6 +404,13 @@ impl<Device: Sink + Source> Serial<Device> {
         Ok(encoded)
     }
 
+    /// Serializes this console's history buffer without notifying any
+    /// connected clients that the instance is moving elsewhere.
+    pub(crate) async fn save_history(&self) -> Result<String, MigrateError> {
+        ron::to_string(&*self.history.read().await)
+            .map_err(|e| MigrateError::Codec(e.to_string()))
+    }
+
     pub(crate) async fn import(
         &self,
         serialized_hist: &str,
@@ -382,8 +418,15 @@ impl<Device: Sink + Source> Serial<Device> {
         self.sink_poller.attach(self.uart.as_ref());
         self.source_poller.attach(self.uart.as_ref());
         self.uart.set_autodiscard(false);
-        let decoded = ron::from_str(serialized_hist)
+        let decoded: HistoryBuffer = ron::from_str(serialized_hist)
             .map_err(|e| MigrateError::Codec(e.to_string()))?;
+
+        // The terminal model isn't transferred with the history; rebuild it
+        // from the most recent output instead.
+        let mut terminal = TerminalModel::new();
+        terminal.consume(&decoded.recent_contents());
+        *self.terminal.lock().await = terminal;
+
         let mut write_hist = self.history.write().await;
         *write_hist = decoded;
         Ok(())
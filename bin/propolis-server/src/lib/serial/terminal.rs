@@ -0,0 +1,367 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Tracks the screen contents of a VT100-compatible terminal driven by a
+//! serial console so that newly-attached clients can be shown the current
+//! screen instead of a raw replay of the console's history.
+
+use std::fmt::Write;
+
+use termwiz::cell::{AttributeChange, CellAttributes, Intensity, Underline};
+use termwiz::color::ColorAttribute;
+use termwiz::escape::csi::{
+    Cursor, Edit, EraseInDisplay, EraseInLine, Sgr, CSI,
+};
+use termwiz::escape::parser::Parser;
+use termwiz::escape::{Action, ControlCode};
+use termwiz::surface::{Change, Position, Surface};
+
+/// The width of the modeled terminal, in character cells.
+const TERMINAL_COLS: usize = 80;
+
+/// The height of the modeled terminal, in character cells.
+const TERMINAL_ROWS: usize = 24;
+
+/// The distance between the terminal's (fixed) tab stops.
+const TAB_WIDTH: usize = 8;
+
+/// A model of an 80x24 VT100 terminal that is fed the output of a serial
+/// console.
+///
+/// The model understands enough of the VT100 command set to track the output
+/// of line-oriented consoles and simple full-screen programs: printable text,
+/// carriage control, absolute and relative cursor motion, erasure, and
+/// character attributes. Commands it does not understand are ignored.
+pub(crate) struct TerminalModel {
+    /// The parsing state machine that converts incoming bytes into VT100
+    /// commands.
+    parser: Parser,
+
+    /// The current contents of the virtual screen.
+    surface: Surface,
+
+    /// The attributes that will be applied to the next character printed to
+    /// the screen.
+    pen: CellAttributes,
+}
+
+impl Default for TerminalModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TerminalModel {
+    pub(crate) fn new() -> Self {
+        Self {
+            parser: Parser::new(),
+            surface: Surface::new(TERMINAL_COLS, TERMINAL_ROWS),
+            pen: CellAttributes::default(),
+        }
+    }
+
+    /// Feeds the model new bytes from the serial console.
+    pub(crate) fn consume(&mut self, data: &[u8]) {
+        let actions = self.parser.parse_as_vec(data);
+        let mut seq = None;
+        for action in actions {
+            let changes = self.action_to_changes(action);
+            if !changes.is_empty() {
+                seq = Some(self.surface.add_changes(changes));
+            }
+        }
+
+        if let Some(seq) = seq {
+            self.surface.flush_changes_older_than(seq);
+        }
+    }
+
+    /// Produces a byte sequence that, when written to a VT100-compatible
+    /// terminal, clears it and redraws the modeled screen contents, leaving
+    /// the cursor and the current character attributes as the model has them.
+    pub(crate) fn redraw(&mut self) -> Vec<u8> {
+        let mut out = String::from("\x1b[0m\x1b[2J");
+        let mut current = CellAttributes::default();
+        for (row, cells) in self.surface.screen_cells().iter().enumerate() {
+            // Skip trailing blank cells; they were cleared by the "erase
+            // display" command above.
+            let len = cells
+                .iter()
+                .rposition(|cell| {
+                    cell.str() != " " || *cell.attrs() != Default::default()
+                })
+                .map_or(0, |pos| pos + 1);
+
+            if len == 0 {
+                continue;
+            }
+
+            write!(out, "\x1b[{};1H", row + 1).unwrap();
+            let mut skip = 0;
+            for cell in &cells[..len] {
+                // Cells that follow a wide character are covered by it and
+                // must not be printed separately.
+                if skip > 0 {
+                    skip -= 1;
+                    continue;
+                }
+
+                if *cell.attrs() != current {
+                    current = cell.attrs().clone();
+                    write_sgr(&mut out, &current);
+                }
+
+                out.push_str(cell.str());
+                skip = cell.width().saturating_sub(1);
+            }
+        }
+
+        let (x, y) = self.surface.cursor_position();
+        write!(out, "\x1b[{};{}H", y + 1, x + 1).unwrap();
+        write_sgr(&mut out, &self.pen);
+        out.into_bytes()
+    }
+
+    /// Converts a single VT100 action into the changes needed to apply it to
+    /// the modeled screen.
+    fn action_to_changes(&mut self, action: Action) -> Vec<Change> {
+        let (x, y) = self.surface.cursor_position();
+        match action {
+            Action::Print(c) => vec![Change::from(c)],
+            Action::PrintString(s) => vec![Change::from(s)],
+            Action::Control(ctrl) => match ctrl {
+                ControlCode::LineFeed => vec![Change::from('\n')],
+                ControlCode::CarriageReturn => vec![Change::from('\r')],
+                ControlCode::Backspace => {
+                    vec![cursor_to(x.saturating_sub(1), y)]
+                }
+                ControlCode::HorizontalTab => {
+                    let col = ((x / TAB_WIDTH) + 1) * TAB_WIDTH;
+                    vec![cursor_to(col.min(TERMINAL_COLS - 1), y)]
+                }
+                _ => vec![],
+            },
+            Action::CSI(csi) => match csi {
+                CSI::Cursor(cursor) => match cursor {
+                    Cursor::Position { line, col } => vec![cursor_to(
+                        col.as_zero_based() as usize,
+                        line.as_zero_based() as usize,
+                    )],
+                    Cursor::Up(n) => {
+                        vec![cursor_to(x, y.saturating_sub(n as usize))]
+                    }
+                    Cursor::Down(n) => {
+                        let row = y.saturating_add(n as usize);
+                        vec![cursor_to(x, row.min(TERMINAL_ROWS - 1))]
+                    }
+                    Cursor::Left(n) => {
+                        vec![cursor_to(x.saturating_sub(n as usize), y)]
+                    }
+                    Cursor::Right(n) => {
+                        let col = x.saturating_add(n as usize);
+                        vec![cursor_to(col.min(TERMINAL_COLS - 1), y)]
+                    }
+                    _ => vec![],
+                },
+                CSI::Edit(Edit::EraseInLine(erase)) => match erase {
+                    EraseInLine::EraseToEndOfLine => {
+                        vec![Change::ClearToEndOfLine(self.background())]
+                    }
+                    EraseInLine::EraseLine => vec![
+                        cursor_to(0, y),
+                        Change::ClearToEndOfLine(self.background()),
+                        cursor_to(x, y),
+                    ],
+                    _ => vec![],
+                },
+                CSI::Edit(Edit::EraseInDisplay(erase)) => match erase {
+                    EraseInDisplay::EraseToEndOfDisplay => {
+                        vec![Change::ClearToEndOfScreen(self.background())]
+                    }
+                    // Clearing the whole surface also homes its cursor, but
+                    // erasing the display on a real terminal leaves the cursor
+                    // where it was.
+                    EraseInDisplay::EraseDisplay => vec![
+                        Change::ClearScreen(self.background()),
+                        cursor_to(x, y),
+                    ],
+                    _ => vec![],
+                },
+                CSI::Sgr(sgr) => self.apply_sgr(sgr).into_iter().collect(),
+                _ => vec![],
+            },
+            _ => vec![],
+        }
+    }
+
+    /// Updates the model's pen to reflect a "select graphic rendition"
+    /// command and returns the corresponding change to the screen's pen.
+    fn apply_sgr(&mut self, sgr: Sgr) -> Option<Change> {
+        let change = match sgr {
+            Sgr::Reset => {
+                self.pen = CellAttributes::default();
+                return Some(Change::AllAttributes(self.pen.clone()));
+            }
+            Sgr::Intensity(i) => AttributeChange::Intensity(i),
+            Sgr::Underline(u) => AttributeChange::Underline(u),
+            Sgr::Italic(on) => AttributeChange::Italic(on),
+            Sgr::Inverse(on) => AttributeChange::Reverse(on),
+            Sgr::Foreground(color) => AttributeChange::Foreground(color.into()),
+            Sgr::Background(color) => AttributeChange::Background(color.into()),
+            _ => return None,
+        };
+
+        match &change {
+            AttributeChange::Intensity(i) => self.pen.set_intensity(*i),
+            AttributeChange::Underline(u) => self.pen.set_underline(*u),
+            AttributeChange::Italic(on) => self.pen.set_italic(*on),
+            AttributeChange::Reverse(on) => self.pen.set_reverse(*on),
+            AttributeChange::Foreground(c) => self.pen.set_foreground(*c),
+            AttributeChange::Background(c) => self.pen.set_background(*c),
+            _ => unreachable!("only handled attributes are converted"),
+        };
+
+        Some(Change::Attribute(change))
+    }
+
+    /// Returns the color with which erased cells are filled.
+    fn background(&self) -> ColorAttribute {
+        self.pen.background()
+    }
+}
+
+/// Provides shorthand to create a termwiz `CursorPosition` from zero-based
+/// column and row indices.
+fn cursor_to(col: usize, row: usize) -> Change {
+    Change::CursorPosition {
+        x: Position::Absolute(col),
+        y: Position::Absolute(row),
+    }
+}
+
+/// Appends to `out` a "select graphic rendition" command that resets the
+/// terminal's attributes and then selects those in `attrs`.
+fn write_sgr(out: &mut String, attrs: &CellAttributes) {
+    out.push_str("\x1b[0");
+    match attrs.intensity() {
+        Intensity::Normal => {}
+        Intensity::Bold => out.push_str(";1"),
+        Intensity::Half => out.push_str(";2"),
+    }
+    if attrs.italic() {
+        out.push_str(";3");
+    }
+    if attrs.underline() != Underline::None {
+        out.push_str(";4");
+    }
+    if attrs.reverse() {
+        out.push_str(";7");
+    }
+    write_color(out, attrs.foreground(), 30, 90, 38);
+    write_color(out, attrs.background(), 40, 100, 48);
+    out.push('m');
+}
+
+/// Appends the SGR parameters that select `color`. `base` and `bright_base`
+/// are the parameters for palette entries 0 and 8, respectively, and
+/// `extended` introduces 256-color and direct-color selections.
+fn write_color(
+    out: &mut String,
+    color: ColorAttribute,
+    base: u8,
+    bright_base: u8,
+    extended: u8,
+) {
+    match color {
+        ColorAttribute::Default => {}
+        ColorAttribute::PaletteIndex(idx)
+        | ColorAttribute::TrueColorWithPaletteFallback(_, idx) => {
+            if idx < 8 {
+                write!(out, ";{}", base + idx).unwrap();
+            } else if idx < 16 {
+                write!(out, ";{}", bright_base + idx - 8).unwrap();
+            } else {
+                write!(out, ";{};5;{}", extended, idx).unwrap();
+            }
+        }
+        ColorAttribute::TrueColorWithDefaultFallback(rgba) => {
+            let (r, g, b, _) = rgba.to_srgb_u8();
+            write!(out, ";{};2;{};{};{}", extended, r, g, b).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Checks that replaying `model`'s redraw sequence into a fresh model
+    /// reproduces the original model's screen, cursor, and pen.
+    fn assert_redraw_matches(model: &mut TerminalModel) {
+        let mut replayed = TerminalModel::new();
+        replayed.consume(&model.redraw());
+
+        assert_eq!(
+            replayed.surface.screen_chars_to_string(),
+            model.surface.screen_chars_to_string()
+        );
+        assert_eq!(
+            replayed.surface.cursor_position(),
+            model.surface.cursor_position()
+        );
+        assert_eq!(replayed.pen, model.pen);
+
+        let original = model.surface.screen_cells();
+        for (row, cells) in replayed.surface.screen_cells().iter().enumerate() {
+            for (col, cell) in cells.iter().enumerate() {
+                assert_eq!(
+                    cell.attrs(),
+                    original[row][col].attrs(),
+                    "attributes differ at row {row}, column {col}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn redraw_reproduces_text_and_cursor() {
+        let mut model = TerminalModel::new();
+        model.consume(
+            b"login: root\r\nPassword: \r\n\r\n# ls\r\nfoo\tbar\r\n# ",
+        );
+        assert_redraw_matches(&mut model);
+        assert_eq!(model.surface.cursor_position(), (2, 5));
+    }
+
+    #[test]
+    fn redraw_reproduces_attributes() {
+        let mut model = TerminalModel::new();
+        model.consume(b"\x1b[1;31mbold red\x1b[0m plain \x1b[7;44minverse");
+        assert_redraw_matches(&mut model);
+    }
+
+    #[test]
+    fn redraw_reflects_cursor_motion_and_erasure() {
+        let mut model = TerminalModel::new();
+        model.consume(b"first line\r\nsecond line\r\nthird line");
+        model.consume(b"\x1b[2;1H\x1b[2K\x1b[1;6H\x1b[K\x1b[3;3H");
+
+        let contents = model.surface.screen_chars_to_string();
+        let mut lines = contents.lines();
+        assert_eq!(lines.next().unwrap().trim_end(), "first");
+        assert_eq!(lines.next().unwrap().trim_end(), "");
+        assert_eq!(lines.next().unwrap().trim_end(), "third line");
+        assert_eq!(model.surface.cursor_position(), (2, 2));
+        assert_redraw_matches(&mut model);
+    }
+
+    #[test]
+    fn erase_display_keeps_cursor() {
+        let mut model = TerminalModel::new();
+        model.consume(b"some text\x1b[2J");
+        assert!(model.surface.screen_chars_to_string().trim().is_empty());
+        assert_eq!(model.surface.cursor_position(), (9, 0));
+        assert_redraw_matches(&mut model);
+    }
+}
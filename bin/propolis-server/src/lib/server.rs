@@ -16,6 +16,9 @@ use std::net::SocketAddr;
 use std::net::SocketAddrV6;
 use std::sync::Arc;
 
+use crate::api_auth::{check_bearer_token, ApiAuth};
+use crate::guest_agent::{GuestAgent, GuestAgentError};
+use crate::migrate::MigrateConn;
 use crate::serial::history_buffer::SerialHistoryOffset;
 use crate::vm::VmError;
 use dropshot::{
@@ -23,20 +26,27 @@ use dropshot::{
     HttpResponseOk, HttpResponseUpdatedNoContent, Path, Query, RequestContext,
     TypedBody, WebsocketConnection,
 };
-use futures::SinkExt;
+use futures::{SinkExt, StreamExt};
+use hyper::{Body, Response};
 use internal_dns::resolver::{ResolveError, Resolver};
 use internal_dns::ServiceName;
 pub use nexus_client::Client as NexusClient;
 use oximeter::types::ProducerRegistry;
 use propolis_api_types as api;
-use propolis_api_types::instance_spec::{self, VersionedInstanceSpec};
+use propolis_api_types::instance_spec::{
+    self, components::devices::SerialPortNumber, VersionedInstanceSpec,
+};
 
 pub use propolis_server_config::Config as VmTomlConfig;
 use rfb::tungstenite::BinaryWs;
 use slog::{error, warn, Logger};
 use thiserror::Error;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::MutexGuard;
-use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{
+    CloseFrame, Role, WebSocketConfig,
+};
 use tokio_tungstenite::WebSocketStream;
 
 use crate::spec::{ServerSpecBuilder, ServerSpecBuilderError};
@@ -77,6 +87,7 @@ pub struct DropshotEndpointContext {
     static_config: StaticConfig,
     pub vnc_server: Arc<VncServer>,
     pub(crate) vm: Arc<crate::vm::Vm>,
+    api_auth: Arc<ApiAuth>,
     log: Logger,
 }
 
@@ -87,8 +98,10 @@ impl DropshotEndpointContext {
         use_reservoir: bool,
         log: slog::Logger,
         metric_config: Option<MetricsEndpointConfig>,
+        vnc_auth: vnc::VncAuth,
+        api_auth: ApiAuth,
     ) -> Self {
-        let vnc_server = VncServer::new(log.clone());
+        let vnc_server = VncServer::new(log.clone(), vnc_auth);
         Self {
             static_config: StaticConfig {
                 vm: Arc::new(config),
@@ -97,11 +110,37 @@ impl DropshotEndpointContext {
             },
             vnc_server,
             vm: crate::vm::Vm::new(&log),
+            api_auth: Arc::new(api_auth),
             log,
         }
     }
 }
 
+/// Rejects requests which do not carry the bearer token this server requires,
+/// if any.
+fn authorize(
+    rqctx: &RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<(), HttpError> {
+    rqctx.context().api_auth.authorize(rqctx.request.headers())
+}
+
+/// Checks the bearer token presented by a client of the instance's display.
+/// A token specific to VNC, if there is one, stands in for the API's.
+fn display_authorized(
+    rqctx: &RequestContext<Arc<DropshotEndpointContext>>,
+) -> bool {
+    let ctx = rqctx.context();
+    let authorization = rqctx
+        .request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|hdr| hdr.to_str().ok());
+    match ctx.vnc_server.auth().token {
+        Some(_) => ctx.vnc_server.auth().check_token(authorization),
+        None => check_bearer_token(ctx.api_auth.token(), authorization),
+    }
+}
+
 #[derive(Debug, Error)]
 enum SpecCreationError {
     #[error(transparent)]
@@ -130,16 +169,7 @@ fn instance_spec_from_request(
     }
 
     spec_builder.add_devices_from_config(toml_config)?;
-    for port in [
-        instance_spec::components::devices::SerialPortNumber::Com1,
-        instance_spec::components::devices::SerialPortNumber::Com2,
-        instance_spec::components::devices::SerialPortNumber::Com3,
-        // SoftNpu uses this port for ASIC management.
-        #[cfg(not(feature = "falcon"))]
-        instance_spec::components::devices::SerialPortNumber::Com4,
-    ] {
-        spec_builder.add_serial_port(port)?;
-    }
+    spec_builder.add_default_serial_ports()?;
 
     Ok(VersionedInstanceSpec::V0(spec_builder.finish()))
 }
@@ -224,6 +254,13 @@ async fn instance_ensure_common(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
     request: api::InstanceSpecEnsureRequest,
 ) -> Result<HttpResponseCreated<api::InstanceEnsureResponse>, HttpError> {
+    if request.migrate.is_some() && request.restore.is_some() {
+        return Err(HttpError::for_bad_request(
+            None,
+            "cannot both migrate and restore an instance".to_string(),
+        ));
+    }
+
     let server_context = rqctx.context();
     let oximeter_registry = server_context
         .static_config
@@ -243,6 +280,7 @@ async fn instance_ensure_common(
         nexus_client,
         vnc_server: server_context.vnc_server.clone(),
         local_server_addr: rqctx.server.local_addr,
+        api_auth: server_context.api_auth.clone(),
     };
 
     server_context
@@ -278,6 +316,7 @@ async fn instance_ensure(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
     request: TypedBody<api::InstanceEnsureRequest>,
 ) -> Result<HttpResponseCreated<api::InstanceEnsureResponse>, HttpError> {
+    authorize(&rqctx)?;
     let server_context = rqctx.context();
     let request = request.into_inner();
     let instance_spec =
@@ -298,6 +337,7 @@ async fn instance_ensure(
             properties: request.properties,
             instance_spec,
             migrate: request.migrate,
+            restore: None,
         },
     )
     .await
@@ -311,6 +351,7 @@ async fn instance_spec_ensure(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
     request: TypedBody<api::InstanceSpecEnsureRequest>,
 ) -> Result<HttpResponseCreated<api::InstanceEnsureResponse>, HttpError> {
+    authorize(&rqctx)?;
     instance_ensure_common(rqctx, request.into_inner()).await
 }
 
@@ -335,6 +376,7 @@ async fn instance_get_common(
 async fn instance_spec_get(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
 ) -> Result<HttpResponseOk<api::InstanceSpecGetResponse>, HttpError> {
+    authorize(&rqctx)?;
     Ok(HttpResponseOk(instance_get_common(&rqctx).await?))
 }
 
@@ -345,6 +387,7 @@ async fn instance_spec_get(
 async fn instance_get(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
 ) -> Result<HttpResponseOk<api::InstanceGetResponse>, HttpError> {
+    authorize(&rqctx)?;
     instance_get_common(&rqctx).await.map(|full| {
         HttpResponseOk(api::InstanceGetResponse {
             instance: api::Instance {
@@ -365,6 +408,7 @@ async fn instance_state_monitor(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
     request: TypedBody<api::InstanceStateMonitorRequest>,
 ) -> Result<HttpResponseOk<api::InstanceStateMonitorResponse>, HttpError> {
+    authorize(&rqctx)?;
     let ctx = rqctx.context();
     let gen = request.into_inner().gen;
     let mut state_watcher =
@@ -408,6 +452,7 @@ async fn instance_state_put(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
     request: TypedBody<api::InstanceStateRequested>,
 ) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx)?;
     let ctx = rqctx.context();
     let requested_state = request.into_inner();
     let vm = ctx.vm.active_vm().await.ok_or_else(not_created_error)?;
@@ -451,10 +496,18 @@ async fn instance_serial_history_get(
     query: Query<api::InstanceSerialConsoleHistoryRequest>,
 ) -> Result<HttpResponseOk<api::InstanceSerialConsoleHistoryResponse>, HttpError>
 {
+    authorize(&rqctx)?;
     let ctx = rqctx.context();
     let vm = ctx.vm.active_vm().await.ok_or_else(not_created_error)?;
-    let serial = vm.objects().lock_shared().await.com1().clone();
     let query_params = query.into_inner();
+    let port = query_params.port.unwrap_or(SerialPortNumber::Com1);
+    let serial = vm
+        .objects()
+        .lock_shared()
+        .await
+        .serial_port(port)
+        .ok_or_else(|| serial_port_not_found(port))?
+        .clone();
 
     let byte_offset = SerialHistoryOffset::try_from(&query_params)?;
 
@@ -479,9 +532,18 @@ async fn instance_serial(
     query: Query<api::InstanceSerialConsoleStreamRequest>,
     websock: WebsocketConnection,
 ) -> dropshot::WebsocketChannelResult {
+    authorize(&rqctx)?;
     let ctx = rqctx.context();
     let vm = ctx.vm.active_vm().await.ok_or_else(not_created_error)?;
-    let serial = vm.objects().lock_shared().await.com1().clone();
+    let query = query.into_inner();
+    let port = query.port.unwrap_or(SerialPortNumber::Com1);
+    let serial = vm
+        .objects()
+        .lock_shared()
+        .await
+        .serial_port(port)
+        .ok_or_else(|| serial_port_not_found(port))?
+        .clone();
 
     // Use the default buffering paramters for the websocket configuration
     //
@@ -497,8 +559,14 @@ async fn instance_serial(
     )
     .await;
 
-    let byte_offset = SerialHistoryOffset::try_from(&query.into_inner()).ok();
-    if let Some(mut byte_offset) = byte_offset {
+    if query.from_start.is_none() && query.most_recent.is_none() {
+        // With no offset to replay from, show the client what the terminal
+        // currently looks like.
+        let redraw = serial.terminal_redraw().await;
+        ws_stream
+            .send(tokio_tungstenite::tungstenite::Message::Binary(redraw))
+            .await?;
+    } else if let Ok(mut byte_offset) = SerialHistoryOffset::try_from(&query) {
         loop {
             let (data, offset) = serial.history_vec(byte_offset, None).await?;
             if data.is_empty() {
@@ -512,16 +580,141 @@ async fn instance_serial(
     }
 
     // Get serial task's handle and send it the websocket stream
-    let serial_task = vm.services().serial_task.lock().await;
-    serial_task
-        .as_ref()
-        .ok_or("Instance has no serial task")?
+    let serial_tasks = vm.services().serial_tasks.lock().await;
+    serial_tasks
+        .get(&port)
+        .ok_or("Instance has no serial task for this port")?
         .websocks_ch
         .send(ws_stream)
         .await
         .map_err(|e| format!("Serial socket hand-off failed: {}", e).into())
 }
 
+#[channel {
+    protocol = WEBSOCKETS,
+    path = "/instance/virtio-serial/{device}/{port}",
+}]
+async fn instance_virtio_serial(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::VirtioSerialPathParams>,
+    query: Query<api::InstanceVirtioSerialStreamRequest>,
+    websock: WebsocketConnection,
+) -> dropshot::WebsocketChannelResult {
+    authorize(&rqctx)?;
+    let ctx = rqctx.context();
+    let vm = ctx.vm.active_vm().await.ok_or_else(not_created_error)?;
+    let path_params = path_params.into_inner();
+    let query = query.into_inner();
+    let key = (path_params.device, path_params.port);
+    let serial = vm
+        .objects()
+        .lock_shared()
+        .await
+        .virtio_serial_port(&key.0, &key.1)
+        .ok_or_else(|| virtio_serial_port_not_found(&key.0, &key.1))?
+        .clone();
+
+    let mut ws_stream = WebSocketStream::from_raw_socket(
+        websock.into_inner(),
+        Role::Server,
+        Some(WebSocketConfig::default()),
+    )
+    .await;
+
+    // Unlike on the serial console, there's no terminal to redraw: the port
+    // may carry any protocol, so only history that was asked for is replayed.
+    if let Ok(mut byte_offset) = SerialHistoryOffset::try_from(&query) {
+        loop {
+            let (data, offset) = serial.history_vec(byte_offset, None).await?;
+            if data.is_empty() {
+                break;
+            }
+            ws_stream
+                .send(tokio_tungstenite::tungstenite::Message::Binary(data))
+                .await?;
+            byte_offset = SerialHistoryOffset::FromStart(offset);
+        }
+    }
+
+    let serial_tasks = vm.services().virtio_serial_tasks.lock().await;
+    serial_tasks
+        .get(&key)
+        .ok_or("Instance has no serial task for this port")?
+        .websocks_ch
+        .send(ws_stream)
+        .await
+        .map_err(|e| format!("Serial socket hand-off failed: {}", e).into())
+}
+
+#[channel {
+    protocol = WEBSOCKETS,
+    path = "/instance/events",
+}]
+async fn instance_events(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    query: Query<api::InstanceEventStreamRequest>,
+    websock: WebsocketConnection,
+) -> dropshot::WebsocketChannelResult {
+    authorize(&rqctx)?;
+    let ctx = rqctx.context();
+    let query = query.into_inner();
+    let (replay, mut events) =
+        ctx.vm.events().subscribe(query.from_seq, query.stream_id);
+
+    let mut ws_stream = WebSocketStream::from_raw_socket(
+        websock.into_inner(),
+        Role::Server,
+        Some(WebSocketConfig::default()),
+    )
+    .await;
+
+    for msg in replay {
+        ws_stream
+            .send(tokio_tungstenite::tungstenite::Message::Text(
+                serde_json::to_string(&msg)?,
+            ))
+            .await?;
+    }
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    let msg = api::InstanceEventMessage::Event(event);
+                    ws_stream
+                        .send(tokio_tungstenite::tungstenite::Message::Text(
+                            serde_json::to_string(&msg)?,
+                        ))
+                        .await?;
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    // Rather than silently skip events, disconnect the client,
+                    // which can reconnect from the stream and sequence number
+                    // of the last event it received.
+                    let _ = ws_stream
+                        .close(Some(CloseFrame {
+                            code: CloseCode::Again,
+                            reason: format!("fell behind by {missed} events")
+                                .into(),
+                        }))
+                        .await;
+                    return Ok(());
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+
+            // Clients aren't expected to send anything, but their messages
+            // must be read to notice when they go away.
+            msg = ws_stream.next() => match msg {
+                Some(Ok(tokio_tungstenite::tungstenite::Message::Close(_)))
+                | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+        }
+    }
+}
+
 #[channel {
     protocol = WEBSOCKETS,
     path = "/instance/vnc",
@@ -529,23 +722,37 @@ async fn instance_serial(
 }]
 async fn instance_vnc(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
-    _query: Query<()>,
+    query: Query<api::InstanceVncRequest>,
     websock: WebsocketConnection,
 ) -> dropshot::WebsocketChannelResult {
     let ctx = rqctx.context();
+    let read_only = query.into_inner().read_only;
 
-    let ws_stream = WebSocketStream::from_raw_socket(
+    let mut ws_stream = WebSocketStream::from_raw_socket(
         websock.into_inner(),
         Role::Server,
         None,
     )
     .await;
 
+    if !display_authorized(&rqctx) {
+        // The connection has already been upgraded, so the rejection must be
+        // delivered as a websocket close.
+        let _ = ws_stream
+            .close(Some(CloseFrame {
+                code: CloseCode::Policy,
+                reason: "missing or invalid bearer token".into(),
+            }))
+            .await;
+        return Err("VNC client did not present a valid bearer token".into());
+    }
+
     if let Err(e) = ctx
         .vnc_server
         .connect(
             Box::new(BinaryWs::new(ws_stream)) as Box<dyn vnc::Connection>,
             rqctx.request_id.clone(),
+            read_only,
         )
         .await
     {
@@ -557,6 +764,51 @@ async fn instance_vnc(
     Ok(())
 }
 
+/// Captures the instance's display as a PNG image.
+///
+/// This does not require (or disturb) a VNC connection to the instance.
+#[endpoint {
+    method = GET,
+    path = "/instance/screenshot",
+}]
+async fn instance_screenshot(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    query: Query<api::InstanceScreenshotRequest>,
+) -> Result<Response<Body>, HttpError> {
+    // The screenshot shows as much as a VNC client sees, so it is guarded by
+    // the same token.
+    if !display_authorized(&rqctx) {
+        return Err(HttpError::for_client_error(
+            None,
+            http::StatusCode::UNAUTHORIZED,
+            "missing or invalid bearer token".to_string(),
+        ));
+    }
+    let query = query.into_inner();
+    let frame = rqctx
+        .context()
+        .vnc_server
+        .screenshot(query.max_width, query.max_height)
+        .ok_or_else(not_created_error)?;
+
+    // Encoding the image is CPU-bound, so do it off of the request handling
+    // task.
+    let png = tokio::task::spawn_blocking(move || frame.to_png())
+        .await
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
+
+    Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "image/png")
+        .body(png.into())
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))
+}
+
+/// How long a migration destination has to complete the TLS handshake with
+/// which it authenticates itself.
+const MIGRATION_TLS_TIMEOUT: std::time::Duration =
+    std::time::Duration::from_secs(10);
+
 // This endpoint is meant to only be called during a migration from the
 // destination instance to the source instance as part of the HTTP connection
 // upgrade used to establish the migration link. We don't actually want this
@@ -573,8 +825,35 @@ async fn instance_migrate_start(
 ) -> dropshot::WebsocketChannelResult {
     let ctx = rqctx.context();
     let migration_id = path_params.into_inner().migration_id;
+
+    // Destinations which authenticate with a certificate do so in a TLS session
+    // established beneath the upgraded connection, and so need not present
+    // this server's token. The handshake completes (or times out) here, so
+    // that the VM's state driver never waits on an unauthenticated peer.
+    if ctx.api_auth.migration_tls().is_none() {
+        authorize(&rqctx)?;
+    }
+    let upgraded = websock.into_inner();
+    let conn: Box<dyn MigrateConn> = match ctx.api_auth.migration_tls() {
+        Some(tls) => {
+            let accept = tls.acceptor.accept(upgraded);
+            match tokio::time::timeout(MIGRATION_TLS_TIMEOUT, accept).await {
+                Ok(Ok(conn)) => Box::new(conn),
+                Ok(Err(e)) => {
+                    return Err(
+                        format!("migration TLS handshake failed: {e}").into()
+                    );
+                }
+                Err(_) => {
+                    return Err("migration TLS handshake timed out".into());
+                }
+            }
+        }
+        None => Box::new(upgraded),
+    };
+
     let vm = ctx.vm.active_vm().await.ok_or_else(not_created_error)?;
-    Ok(vm.request_migration_out(migration_id, websock).await?)
+    Ok(vm.request_migration_out(migration_id, conn).await?)
 }
 
 #[endpoint {
@@ -584,6 +863,7 @@ async fn instance_migrate_start(
 async fn instance_migrate_status(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
 ) -> Result<HttpResponseOk<api::InstanceMigrateStatusResponse>, HttpError> {
+    authorize(&rqctx)?;
     let ctx = rqctx.context();
     ctx.vm
         .state_watcher()
@@ -608,6 +888,7 @@ async fn instance_issue_crucible_snapshot_request(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
     path_params: Path<api::SnapshotRequestPathParams>,
 ) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx)?;
     let vm =
         rqctx.context().vm.active_vm().await.ok_or_else(not_created_error)?;
     let objects = vm.objects().lock_shared().await;
@@ -634,6 +915,7 @@ async fn disk_volume_status(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
     path_params: Path<api::VolumeStatusPathParams>,
 ) -> Result<HttpResponseOk<api::VolumeStatus>, HttpError> {
+    authorize(&rqctx)?;
     let path_params = path_params.into_inner();
     let vm =
         rqctx.context().vm.active_vm().await.ok_or_else(not_created_error)?;
@@ -661,6 +943,7 @@ async fn instance_issue_crucible_vcr_request(
     path_params: Path<api::VCRRequestPathParams>,
     request: TypedBody<api::InstanceVCRReplace>,
 ) -> Result<HttpResponseOk<crucible_client_types::ReplaceResult>, HttpError> {
+    authorize(&rqctx)?;
     let path_params = path_params.into_inner();
     let request = request.into_inner();
     let new_vcr_json = request.vcr_json;
@@ -690,6 +973,50 @@ async fn instance_issue_crucible_vcr_request(
     result.map(HttpResponseOk)
 }
 
+/// Saves the instance's state to a file on the server's host.
+///
+/// The instance is paused while its state is written. Once the save completes,
+/// it either resumes or stops, depending on the request.
+#[endpoint {
+    method = POST,
+    path = "/instance/save",
+}]
+async fn instance_save(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::InstanceSaveRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx)?;
+    let request = request.into_inner();
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let vm =
+        rqctx.context().vm.active_vm().await.ok_or_else(not_created_error)?;
+
+    vm.request_save(
+        std::path::PathBuf::from(request.path),
+        request.stop_after_save,
+        tx,
+    )
+    .map_err(|e| match e {
+        VmError::ForbiddenStateChange(reason) => HttpError::for_status(
+            Some(format!("instance state change not allowed: {}", reason)),
+            http::status::StatusCode::FORBIDDEN,
+        ),
+        _ => HttpError::for_internal_error(format!(
+            "unexpected error from VM controller: {e}"
+        )),
+    })?;
+
+    let result = rx.await.map_err(|_| {
+        HttpError::for_internal_error(
+            "VM worker task unexpectedly dropped result channel".to_string(),
+        )
+    })?;
+
+    result.map(|_| HttpResponseUpdatedNoContent {}).map_err(|e| {
+        HttpError::for_internal_error(format!("failed to save instance: {e}"))
+    })
+}
+
 /// Issues an NMI to the instance.
 #[endpoint {
     method = POST,
@@ -698,6 +1025,7 @@ async fn instance_issue_crucible_vcr_request(
 async fn instance_issue_nmi(
     rqctx: RequestContext<Arc<DropshotEndpointContext>>,
 ) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx)?;
     let vm =
         rqctx.context().vm.active_vm().await.ok_or_else(not_created_error)?;
     let _ = vm.objects().lock_shared().await.machine().inject_nmi();
@@ -705,6 +1033,358 @@ async fn instance_issue_nmi(
     Ok(HttpResponseOk(()))
 }
 
+/// Looks up the network device named in a request.
+async fn network_device_by_name(
+    rqctx: &RequestContext<Arc<DropshotEndpointContext>>,
+    name: &str,
+) -> Result<Arc<propolis::hw::virtio::PciVirtioViona>, HttpError> {
+    let vm =
+        rqctx.context().vm.active_vm().await.ok_or_else(not_created_error)?;
+    let objects = vm.objects().lock_shared().await;
+    objects.network_device(name).cloned().ok_or_else(|| {
+        let s = format!("no network device named {name}");
+        HttpError::for_not_found(Some(s.clone()), s)
+    })
+}
+
+/// Sets the link state a network device presents to the guest.
+///
+/// The guest is notified of the change through a configuration change
+/// interrupt, as it would be if a cable were pulled or reconnected. Packets
+/// continue to flow while the link is reported down, but guests generally stop
+/// sending them.
+#[endpoint {
+    method = PUT,
+    path = "/instance/nic/{name}/link",
+}]
+async fn instance_nic_link_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::NicPathParams>,
+    request: TypedBody<api::NicLinkStateRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx)?;
+    let name = path_params.into_inner().name;
+    let nic = network_device_by_name(&rqctx, &name).await?;
+    nic.set_link_up(request.into_inner().link_up);
+
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// The default and maximum sizes of a packet capture file, and the maximum
+/// (and default) number of bytes of each packet to capture.
+const NIC_CAPTURE_DEFAULT_BYTES: u64 = 4 * 1024 * 1024;
+const NIC_CAPTURE_MAX_BYTES: u64 = 64 * 1024 * 1024;
+const NIC_CAPTURE_MAX_SNAPLEN: u32 = 65535;
+
+fn nic_capture_status(
+    status: propolis::hw::virtio::capture::CaptureStatus,
+) -> api::NicCaptureStatus {
+    api::NicCaptureStatus {
+        running: status.running,
+        packets: status.packets,
+        bytes: status.bytes as u64,
+        limit_reached: status.limit_reached,
+        error: status.error,
+    }
+}
+
+/// Starts capturing the packets sent and received by a network device.
+///
+/// Any previous capture from the device is discarded. The capture stops when
+/// it is stopped explicitly or when it reaches its size or packet limit,
+/// whichever comes first.
+#[endpoint {
+    method = PUT,
+    path = "/instance/nic/{name}/capture",
+}]
+async fn instance_nic_capture_start(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::NicPathParams>,
+    request: TypedBody<api::NicCaptureStartRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx)?;
+    let name = path_params.into_inner().name;
+    let request = request.into_inner();
+    let max_bytes = request.max_bytes.unwrap_or(NIC_CAPTURE_DEFAULT_BYTES);
+    if max_bytes > NIC_CAPTURE_MAX_BYTES {
+        let s = format!(
+            "capture size {max_bytes} exceeds limit of {NIC_CAPTURE_MAX_BYTES}"
+        );
+        return Err(HttpError::for_bad_request(Some(s.clone()), s));
+    }
+    let snaplen = request.snaplen.unwrap_or(NIC_CAPTURE_MAX_SNAPLEN);
+    if snaplen == 0 || snaplen > NIC_CAPTURE_MAX_SNAPLEN {
+        let s = format!(
+            "capture snap length {snaplen} must be between 1 and \
+            {NIC_CAPTURE_MAX_SNAPLEN}"
+        );
+        return Err(HttpError::for_bad_request(Some(s.clone()), s));
+    }
+    let limits = propolis::hw::virtio::capture::CaptureLimits {
+        max_bytes: max_bytes as usize,
+        max_packets: request.max_packets,
+        snaplen,
+    };
+
+    let nic = network_device_by_name(&rqctx, &name).await?;
+
+    // Starting opens the vNIC and waits for any previous capture's thread to
+    // exit, so do it off of the request handling task.
+    let res = tokio::task::spawn_blocking(move || nic.capture().start(limits))
+        .await
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
+    res.map_err(|e| {
+        if e.kind() == std::io::ErrorKind::AlreadyExists {
+            HttpError::for_client_error(
+                None,
+                http::StatusCode::CONFLICT,
+                e.to_string(),
+            )
+        } else {
+            HttpError::for_internal_error(format!(
+                "failed to start capture on {name}: {e}"
+            ))
+        }
+    })?;
+
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
+/// Gets the status of a network device's most recent packet capture.
+#[endpoint {
+    method = GET,
+    path = "/instance/nic/{name}/capture",
+}]
+async fn instance_nic_capture_status(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::NicPathParams>,
+) -> Result<HttpResponseOk<api::NicCaptureStatus>, HttpError> {
+    authorize(&rqctx)?;
+    let name = path_params.into_inner().name;
+    let nic = network_device_by_name(&rqctx, &name).await?;
+    let status =
+        nic.capture().status().ok_or_else(|| no_capture_error(&name))?;
+
+    Ok(HttpResponseOk(nic_capture_status(status)))
+}
+
+/// Stops a network device's packet capture, keeping the packets captured so
+/// far available for download.
+#[endpoint {
+    method = DELETE,
+    path = "/instance/nic/{name}/capture",
+}]
+async fn instance_nic_capture_stop(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::NicPathParams>,
+) -> Result<HttpResponseOk<api::NicCaptureStatus>, HttpError> {
+    authorize(&rqctx)?;
+    let name = path_params.into_inner().name;
+    let nic = network_device_by_name(&rqctx, &name).await?;
+
+    // Stopping waits for the capture thread to notice, so do it off of the
+    // request handling task.
+    let status = tokio::task::spawn_blocking(move || {
+        nic.capture().stop();
+        nic.capture().status()
+    })
+    .await
+    .map_err(|e| HttpError::for_internal_error(e.to_string()))?
+    .ok_or_else(|| no_capture_error(&name))?;
+
+    Ok(HttpResponseOk(nic_capture_status(status)))
+}
+
+/// Downloads a network device's most recent packet capture as a pcapng file.
+///
+/// If the capture is still running, the file holds the packets captured so
+/// far.
+#[endpoint {
+    method = GET,
+    path = "/instance/nic/{name}/capture/pcapng",
+}]
+async fn instance_nic_capture_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    path_params: Path<api::NicPathParams>,
+) -> Result<Response<Body>, HttpError> {
+    authorize(&rqctx)?;
+    let name = path_params.into_inner().name;
+    let nic = network_device_by_name(&rqctx, &name).await?;
+    let data = nic.capture().data().ok_or_else(|| no_capture_error(&name))?;
+
+    Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/octet-stream")
+        .header(
+            http::header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{name}.pcapng\""),
+        )
+        .body(data.into())
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))
+}
+
+/// How long to wait for a program run through the guest agent to exit, unless
+/// the request says otherwise.
+const GUEST_AGENT_EXEC_DEFAULT_TIMEOUT_SECS: u64 = 30;
+const GUEST_AGENT_EXEC_MAX_TIMEOUT_SECS: u64 = 600;
+
+/// Looks up the connection to the instance's guest agent.
+async fn guest_agent(
+    rqctx: &RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<Arc<GuestAgent>, HttpError> {
+    let vm =
+        rqctx.context().vm.active_vm().await.ok_or_else(not_created_error)?;
+    let objects = vm.objects().lock_shared().await;
+    objects.guest_agent().clone().ok_or_else(|| {
+        let s = "instance has no guest agent".to_string();
+        HttpError::for_not_found(Some(s.clone()), s)
+    })
+}
+
+fn guest_agent_error(e: GuestAgentError) -> HttpError {
+    let msg = e.to_string();
+    match e {
+        GuestAgentError::Guest { .. } | GuestAgentError::FileTooLarge => {
+            HttpError::for_bad_request(None, msg)
+        }
+        GuestAgentError::Timeout
+        | GuestAgentError::ChannelClosed
+        | GuestAgentError::ExecTimeout(_) => HttpError::for_client_error(
+            None,
+            http::StatusCode::FAILED_DEPENDENCY,
+            msg,
+        ),
+        GuestAgentError::Protocol(_) => HttpError::for_internal_error(msg),
+    }
+}
+
+/// Checks that the guest agent is responding.
+#[endpoint {
+    method = POST,
+    path = "/instance/guest-agent/ping",
+}]
+async fn instance_guest_agent_ping(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    authorize(&rqctx)?;
+    let agent = guest_agent(&rqctx).await?;
+    agent.ping().await.map_err(guest_agent_error)?;
+    Ok(HttpResponseOk(()))
+}
+
+/// Freezes the guest's filesystems, so that snapshots of its disks taken
+/// before they are thawed are consistent.
+#[endpoint {
+    method = POST,
+    path = "/instance/guest-agent/fs-freeze",
+}]
+async fn instance_guest_agent_fs_freeze(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::GuestAgentFsFreezeResponse>, HttpError> {
+    authorize(&rqctx)?;
+    let agent = guest_agent(&rqctx).await?;
+    let filesystems = agent.fs_freeze().await.map_err(guest_agent_error)?;
+    Ok(HttpResponseOk(api::GuestAgentFsFreezeResponse { filesystems }))
+}
+
+/// Thaws the guest's filesystems.
+#[endpoint {
+    method = POST,
+    path = "/instance/guest-agent/fs-thaw",
+}]
+async fn instance_guest_agent_fs_thaw(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<api::GuestAgentFsFreezeResponse>, HttpError> {
+    authorize(&rqctx)?;
+    let agent = guest_agent(&rqctx).await?;
+    let filesystems = agent.fs_thaw().await.map_err(guest_agent_error)?;
+    Ok(HttpResponseOk(api::GuestAgentFsFreezeResponse { filesystems }))
+}
+
+/// Lists the guest's network interfaces and their addresses.
+#[endpoint {
+    method = GET,
+    path = "/instance/guest-agent/network-interfaces",
+}]
+async fn instance_guest_agent_network_interfaces(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+) -> Result<HttpResponseOk<Vec<api::GuestNetworkInterface>>, HttpError> {
+    authorize(&rqctx)?;
+    let agent = guest_agent(&rqctx).await?;
+    let interfaces =
+        agent.network_interfaces().await.map_err(guest_agent_error)?;
+    Ok(HttpResponseOk(interfaces))
+}
+
+/// Runs a program in the guest and waits for it to exit.
+#[endpoint {
+    method = POST,
+    path = "/instance/guest-agent/exec",
+}]
+async fn instance_guest_agent_exec(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::GuestAgentExecRequest>,
+) -> Result<HttpResponseOk<api::GuestAgentExecResponse>, HttpError> {
+    authorize(&rqctx)?;
+    let request = request.into_inner();
+    let timeout =
+        request.timeout_secs.unwrap_or(GUEST_AGENT_EXEC_DEFAULT_TIMEOUT_SECS);
+    if timeout > GUEST_AGENT_EXEC_MAX_TIMEOUT_SECS {
+        let s = format!(
+            "timeout {timeout} exceeds limit of \
+            {GUEST_AGENT_EXEC_MAX_TIMEOUT_SECS} seconds"
+        );
+        return Err(HttpError::for_bad_request(Some(s.clone()), s));
+    }
+
+    let agent = guest_agent(&rqctx).await?;
+    let response = agent
+        .exec(
+            &request.path,
+            &request.args,
+            &request.input,
+            std::time::Duration::from_secs(timeout),
+        )
+        .await
+        .map_err(guest_agent_error)?;
+    Ok(HttpResponseOk(response))
+}
+
+/// Reads a file in the guest.
+#[endpoint {
+    method = GET,
+    path = "/instance/guest-agent/file",
+}]
+async fn instance_guest_agent_file_get(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    query: Query<api::GuestAgentFileRequest>,
+) -> Result<HttpResponseOk<api::GuestAgentFileContents>, HttpError> {
+    authorize(&rqctx)?;
+    let path = query.into_inner().path;
+    let agent = guest_agent(&rqctx).await?;
+    let data = agent.read_file(&path).await.map_err(guest_agent_error)?;
+    Ok(HttpResponseOk(api::GuestAgentFileContents { data }))
+}
+
+/// Writes a file in the guest, replacing any existing contents.
+#[endpoint {
+    method = PUT,
+    path = "/instance/guest-agent/file",
+}]
+async fn instance_guest_agent_file_put(
+    rqctx: RequestContext<Arc<DropshotEndpointContext>>,
+    request: TypedBody<api::GuestAgentFileWriteRequest>,
+) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+    authorize(&rqctx)?;
+    let request = request.into_inner();
+    let agent = guest_agent(&rqctx).await?;
+    agent
+        .write_file(&request.path, &request.data)
+        .await
+        .map_err(guest_agent_error)?;
+    Ok(HttpResponseUpdatedNoContent {})
+}
+
 /// Returns a Dropshot [`ApiDescription`] object to launch a server.
 pub fn api() -> ApiDescription<Arc<DropshotEndpointContext>> {
     let mut api = ApiDescription::new();
@@ -716,13 +1396,29 @@ pub fn api() -> ApiDescription<Arc<DropshotEndpointContext>> {
     api.register(instance_state_put).unwrap();
     api.register(instance_serial).unwrap();
     api.register(instance_serial_history_get).unwrap();
+    api.register(instance_virtio_serial).unwrap();
+    api.register(instance_events).unwrap();
     api.register(instance_migrate_start).unwrap();
     api.register(instance_migrate_status).unwrap();
     api.register(instance_issue_crucible_snapshot_request).unwrap();
     api.register(disk_volume_status).unwrap();
     api.register(instance_issue_crucible_vcr_request).unwrap();
     api.register(instance_issue_nmi).unwrap();
+    api.register(instance_save).unwrap();
     api.register(instance_vnc).unwrap();
+    api.register(instance_screenshot).unwrap();
+    api.register(instance_nic_link_put).unwrap();
+    api.register(instance_nic_capture_start).unwrap();
+    api.register(instance_nic_capture_status).unwrap();
+    api.register(instance_nic_capture_stop).unwrap();
+    api.register(instance_nic_capture_get).unwrap();
+    api.register(instance_guest_agent_ping).unwrap();
+    api.register(instance_guest_agent_fs_freeze).unwrap();
+    api.register(instance_guest_agent_fs_thaw).unwrap();
+    api.register(instance_guest_agent_network_interfaces).unwrap();
+    api.register(instance_guest_agent_exec).unwrap();
+    api.register(instance_guest_agent_file_get).unwrap();
+    api.register(instance_guest_agent_file_put).unwrap();
 
     api
 }
@@ -735,6 +1431,24 @@ fn not_created_error() -> HttpError {
     )
 }
 
+fn no_capture_error(name: &str) -> HttpError {
+    let msg = format!("no packets have been captured from {name}");
+    HttpError::for_not_found(Some(msg.clone()), msg)
+}
+
+fn serial_port_not_found(port: SerialPortNumber) -> HttpError {
+    let msg = format!("instance has no serial port {port:?}");
+    HttpError::for_not_found(Some(msg.clone()), msg)
+}
+
+fn virtio_serial_port_not_found(device: &str, port: &str) -> HttpError {
+    let msg = format!(
+        "instance has no virtio-serial port {port:?} on device {device:?} \
+        that is reachable through a websocket"
+    );
+    HttpError::for_not_found(Some(msg.clone()), msg)
+}
+
 #[cfg(test)]
 mod test {
     #[test]
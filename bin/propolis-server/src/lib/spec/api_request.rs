@@ -25,7 +25,9 @@ use propolis_api_types::{
 };
 use thiserror::Error;
 
-use super::{ParsedNetworkDevice, ParsedStorageDevice};
+use propolis_config_toml::{
+    pci_path_to_nic_names, ParsedNetworkDevice, ParsedStorageDevice,
+};
 
 #[derive(Debug, Error)]
 pub(crate) enum DeviceRequestError {
@@ -135,10 +137,13 @@ pub(super) fn parse_nic_from_request(
     nic: &NetworkInterfaceRequest,
 ) -> Result<ParsedNetworkDevice, DeviceRequestError> {
     let pci_path = slot_to_pci_path(nic.slot, SlotType::Nic)?;
-    let (device_name, backend_name) = super::pci_path_to_nic_names(pci_path);
+    let (device_name, backend_name) = pci_path_to_nic_names(pci_path);
     let device_spec = NetworkDeviceV0::VirtioNic(VirtioNic {
         backend_name: backend_name.clone(),
         pci_path,
+        mac_address: None,
+        mtu: None,
+        allow_promiscuous: false,
     });
 
     let backend_spec = NetworkBackendV0::Virtio(VirtioNetworkBackend {
@@ -6,41 +6,16 @@
 
 use crate::config;
 use api_request::DeviceRequestError;
-use builder::SpecBuilder;
-use config_toml::ConfigTomlError;
-use propolis_api_types::instance_spec::components::board::{Chipset, I440Fx};
-use propolis_api_types::instance_spec::components::devices::{
-    QemuPvpanic, SerialPortNumber,
-};
-use propolis_api_types::instance_spec::{
-    components::board::Board, v0::*, PciPath,
-};
+use propolis_api_types::instance_spec::components::devices::SerialPortNumber;
+use propolis_api_types::instance_spec::v0::*;
 use propolis_api_types::{
     DiskRequest, InstanceProperties, NetworkInterfaceRequest,
 };
+use propolis_config_toml::builder::{self, SpecBuilder};
+use propolis_config_toml::SpecFromConfigError;
 use thiserror::Error;
 
 mod api_request;
-pub(crate) mod builder;
-mod config_toml;
-
-/// Describes a storage device/backend pair parsed from an input source like an
-/// API request or a config TOML entry.
-struct ParsedStorageDevice {
-    device_name: String,
-    device_spec: StorageDeviceV0,
-    backend_name: String,
-    backend_spec: StorageBackendV0,
-}
-
-/// Describes a network device/backend pair parsed from an input source like an
-/// API request or a config TOML entry.
-struct ParsedNetworkDevice {
-    device_name: String,
-    device_spec: NetworkDeviceV0,
-    backend_name: String,
-    backend_spec: NetworkBackendV0,
-}
 
 /// Errors that can occur while building an instance spec from component parts.
 #[derive(Debug, Error)]
@@ -48,27 +23,13 @@ pub(crate) enum ServerSpecBuilderError {
     #[error(transparent)]
     InnerBuilderError(#[from] builder::SpecBuilderError),
 
-    #[error("error parsing config TOML")]
-    ConfigToml(#[from] ConfigTomlError),
+    #[error(transparent)]
+    ConfigToml(#[from] SpecFromConfigError),
 
     #[error("error parsing device in ensure request")]
     DeviceRequest(#[from] DeviceRequestError),
 }
 
-/// Generates NIC device and backend names from the NIC's PCI path. This is
-/// needed because the `name` field in a propolis-client
-/// `NetworkInterfaceRequest` is actually the name of the host vNIC to bind to,
-/// and that can change between incarnations of an instance. The PCI path is
-/// unique to each NIC but must remain stable over a migration, so it's suitable
-/// for use in this naming scheme.
-///
-/// N.B. Migrating a NIC requires the source and target to agree on these names,
-///      so changing this routine's behavior will prevent Propolis processes
-///      with the old behavior from migrating processes with the new behavior.
-fn pci_path_to_nic_names(path: PciPath) -> (String, String) {
-    (format!("vnic-{}", path), format!("vnic-{}-backend", path))
-}
-
 /// A helper for building instance specs out of component parts.
 pub struct ServerSpecBuilder {
     builder: SpecBuilder,
@@ -81,27 +42,11 @@ impl ServerSpecBuilder {
         properties: &InstanceProperties,
         config: &config::Config,
     ) -> Result<Self, ServerSpecBuilderError> {
-        let enable_pcie =
-            config.chipset.options.get("enable-pcie").map_or_else(
-                || Ok(false),
-                |v| {
-                    v.as_bool().ok_or_else(|| {
-                        ServerSpecBuilderError::ConfigToml(
-                            ConfigTomlError::EnablePcieParseFailed(
-                                v.to_string(),
-                            ),
-                        )
-                    })
-                },
-            )?;
-
-        let mut builder = SpecBuilder::new(Board {
-            cpus: properties.vcpus,
-            memory_mb: properties.memory,
-            chipset: Chipset::I440Fx(I440Fx { enable_pcie }),
-        });
-
-        builder.add_pvpanic_device(QemuPvpanic { enable_isa: true })?;
+        let builder = propolis_config_toml::builder_from_config(
+            properties.vcpus,
+            properties.memory,
+            config,
+        )?;
 
         Ok(Self { builder })
     }
@@ -149,55 +94,28 @@ impl ServerSpecBuilder {
         &mut self,
         config: &config::Config,
     ) -> Result<(), ServerSpecBuilderError> {
-        let parsed = config_toml::ParsedConfig::try_from(config)?;
-        for disk in parsed.disks {
-            self.builder.add_storage_device(disk)?;
-        }
-
-        for nic in parsed.nics {
-            self.builder.add_network_device(nic)?;
-        }
-
-        for bridge in parsed.pci_bridges {
-            self.builder.add_pci_bridge(bridge.name, bridge.bridge)?;
-        }
-
-        #[cfg(feature = "falcon")]
-        self.add_parsed_softnpu_devices(parsed.softnpu)?;
-
+        propolis_config_toml::add_devices_from_config(
+            &mut self.builder,
+            config,
+        )?;
         Ok(())
     }
 
-    #[cfg(feature = "falcon")]
-    fn add_parsed_softnpu_devices(
+    /// Adds a serial port specification to the spec under construction.
+    pub fn add_serial_port(
         &mut self,
-        devices: config_toml::ParsedSoftNpu,
+        port: SerialPortNumber,
     ) -> Result<(), ServerSpecBuilderError> {
-        for pci_port in devices.pci_ports {
-            self.builder.set_softnpu_pci_port(pci_port)?;
-        }
-
-        for port in devices.ports {
-            self.builder.add_softnpu_port(port.name.clone(), port)?;
-        }
-
-        for p9 in devices.p9_devices {
-            self.builder.set_softnpu_p9(p9)?;
-        }
-
-        for p9fs in devices.p9fs {
-            self.builder.set_p9fs(p9fs)?;
-        }
-
+        self.builder.add_serial_port(port)?;
         Ok(())
     }
 
-    /// Adds a serial port specification to the spec under construction.
-    pub fn add_serial_port(
+    /// Adds the serial ports given to every instance: COM1 through COM4,
+    /// except when SoftNpu has claimed COM4 for ASIC management.
+    pub fn add_default_serial_ports(
         &mut self,
-        port: SerialPortNumber,
     ) -> Result<(), ServerSpecBuilderError> {
-        self.builder.add_serial_port(port)?;
+        propolis_config_toml::add_default_serial_ports(&mut self.builder)?;
         Ok(())
     }
 
@@ -4,17 +4,18 @@
 
 //! Implements a wrapper around an active VM.
 
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
 use propolis_api_types::{InstanceProperties, InstanceStateRequested};
 use slog::info;
 use uuid::Uuid;
 
+use crate::migrate::MigrateConn;
 use crate::vm::request_queue::ExternalRequest;
 
 use super::{
     objects::VmObjects, services::VmServices, CrucibleReplaceResultTx,
-    InstanceStateRx, VmError,
+    InstanceStateRx, SaveResultTx, VmError,
 };
 
 /// The components and services that make up an active Propolis VM.
@@ -64,22 +65,23 @@ impl ActiveVm {
                 InstanceStateRequested::Run => ExternalRequest::Start,
                 InstanceStateRequested::Stop => ExternalRequest::Stop,
                 InstanceStateRequested::Reboot => ExternalRequest::Reboot,
+                InstanceStateRequested::SoftStop => ExternalRequest::SoftStop,
             })
             .map_err(Into::into)
     }
 
     /// Pushes a request to migrate out of a VM to the VM's state change queue.
     /// The migration protocol will communicate with the destination over the
-    /// provided websocket.
+    /// provided connection, over which a websocket will be established.
     pub(crate) async fn request_migration_out(
         &self,
         migration_id: Uuid,
-        websock: dropshot::WebsocketConnection,
+        conn: Box<dyn MigrateConn>,
     ) -> Result<(), VmError> {
         Ok(self.state_driver_queue.queue_external_request(
             ExternalRequest::MigrateAsSource {
                 migration_id,
-                websock: websock.into(),
+                conn: conn.into(),
             },
         )?)
     }
@@ -116,6 +118,31 @@ impl ActiveVm {
             .map_err(Into::into)
     }
 
+    /// Pushes a request to save the VM's state to a file to the VM's state
+    /// change queue.
+    ///
+    /// # Arguments
+    ///
+    /// - `path`: The path of the file to which to write the VM's state.
+    /// - `stop_after_save`: If true, the VM stops once its state is saved;
+    ///   otherwise it resumes.
+    /// - `result_tx`: The channel to which the state driver should send the
+    ///   result of the save after it completes this operation.
+    pub(crate) fn request_save(
+        &self,
+        path: PathBuf,
+        stop_after_save: bool,
+        result_tx: SaveResultTx,
+    ) -> Result<(), VmError> {
+        self.state_driver_queue
+            .queue_external_request(ExternalRequest::Save {
+                path,
+                stop_after_save,
+                result_tx,
+            })
+            .map_err(Into::into)
+    }
+
     /// Yields a reference to this VM's services.
     pub(crate) fn services(&self) -> &VmServices {
         &self.services
@@ -97,9 +97,12 @@ impl<'a> VmEnsureNotStarted<'a> {
 
         let input_queue = Arc::new(InputQueue::new(
             self.log.new(slog::o!("component" => "request_queue")),
-            match &self.ensure_request.migrate {
-                Some(_) => InstanceAutoStart::Yes,
-                None => InstanceAutoStart::No,
+            if self.ensure_request.migrate.is_some()
+                || self.ensure_request.restore.is_some()
+            {
+                InstanceAutoStart::Yes
+            } else {
+                InstanceAutoStart::No
             },
         ));
 
@@ -177,6 +180,7 @@ impl<'a> VmEnsureNotStarted<'a> {
             properties,
             toml_config: &options.toml_config,
             producer_registry: options.oximeter_registry.clone(),
+            events: self.state_publisher.events().clone(),
             state: MachineInitializerState::default(),
         };
 
@@ -189,11 +193,15 @@ impl<'a> VmEnsureNotStarted<'a> {
         init.initialize_rtc(&chipset)?;
         init.initialize_hpet()?;
 
-        let com1 = Arc::new(init.initialize_uart(&chipset)?);
+        let mut serial_ports = init
+            .initialize_uarts(&chipset)?
+            .into_iter()
+            .map(|(port, serial)| (port, Arc::new(serial)))
+            .collect();
         let ps2ctrl = init.initialize_ps2(&chipset)?;
         init.initialize_qemu_debug_port()?;
         init.initialize_qemu_pvpanic(properties.into())?;
-        init.initialize_network_devices(&chipset)?;
+        let network_devices = init.initialize_network_devices(&chipset)?;
 
         #[cfg(not(feature = "omicron-build"))]
         init.initialize_test_devices(&options.toml_config.devices)?;
@@ -203,11 +211,15 @@ impl<'a> VmEnsureNotStarted<'a> {
             "`omicron-build` feature enabled, ignoring any test devices"
         );
 
+        init.initialize_9pfs(&chipset)?;
+        init.initialize_virtio_fs(&chipset)?;
+        let clipboard = init.initialize_clipboard(&chipset)?;
+        let mut virtio_serial = init.initialize_virtio_serial(&chipset)?;
+        let guest_agent =
+            init.initialize_guest_agent(&mut serial_ports, &mut virtio_serial)?;
+
         #[cfg(feature = "falcon")]
-        {
-            init.initialize_softnpu_ports(&chipset)?;
-            init.initialize_9pfs(&chipset)?;
-        }
+        init.initialize_softnpu_ports(&chipset)?;
 
         init.initialize_storage_devices(&chipset, options.nexus_client.clone())
             .await?;
@@ -235,9 +247,14 @@ impl<'a> VmEnsureNotStarted<'a> {
             devices,
             block_backends,
             crucible_backends,
-            com1,
+            network_devices,
+            serial_ports,
             framebuffer: Some(ramfb),
             ps2ctrl,
+            chipset_pm: chipset.pm().clone(),
+            clipboard,
+            virtio_serial,
+            guest_agent,
         })
     }
 }
@@ -0,0 +1,221 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A log of notable events in the lives of this server's instances, which API
+//! clients can follow and replay.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use chrono::Utc;
+use propolis_api_types::{
+    InstanceEvent, InstanceEventGap, InstanceEventKind, InstanceEventMessage,
+};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// The number of past events retained for replay.
+const MAX_RETAINED_EVENTS: usize = 1024;
+
+/// The number of events a subscriber may fall behind before it starts missing
+/// them.
+const SUBSCRIBER_QUEUE_LEN: usize = 256;
+
+/// A log of instance events, each assigned a sequence number one greater than
+/// that of the event before it.
+///
+/// Each log is a new stream of events, with its own ID, so that clients can
+/// tell sequence numbers from this process apart from those of others.
+pub(crate) struct EventLog {
+    stream_id: Uuid,
+    inner: Mutex<EventLogInner>,
+    tx: broadcast::Sender<InstanceEvent>,
+}
+
+struct EventLogInner {
+    /// The most recently recorded events, oldest first.
+    retained: VecDeque<InstanceEvent>,
+
+    /// The sequence number to assign to the next event.
+    next_seq: u64,
+}
+
+impl EventLog {
+    pub(crate) fn new() -> Self {
+        let (tx, _) = broadcast::channel(SUBSCRIBER_QUEUE_LEN);
+        Self {
+            stream_id: Uuid::new_v4(),
+            inner: Mutex::new(EventLogInner {
+                retained: VecDeque::with_capacity(MAX_RETAINED_EVENTS),
+                next_seq: 0,
+            }),
+            tx,
+        }
+    }
+
+    /// Records an event, sending it to all current subscribers.
+    pub(crate) fn record(&self, kind: InstanceEventKind) {
+        let mut inner = self.inner.lock().unwrap();
+        let event = InstanceEvent {
+            stream_id: self.stream_id,
+            seq: inner.next_seq,
+            time: Utc::now(),
+            kind,
+        };
+        inner.next_seq += 1;
+
+        if inner.retained.len() == MAX_RETAINED_EVENTS {
+            inner.retained.pop_front();
+        }
+        inner.retained.push_back(event.clone());
+
+        // Sending only fails if there are no subscribers, which is fine. The
+        // lock is held until the event is sent so that `subscribe` can't see it
+        // both in the retained events and on its new receiver.
+        let _ = self.tx.send(event);
+    }
+
+    /// Returns the messages replaying the retained events from `from_seq` of
+    /// stream `stream_id` (if they are provided), along with a receiver for
+    /// the events recorded after them.
+    ///
+    /// Should some of the requested events be unavailable, because they were
+    /// discarded or `stream_id` is not this log's stream, the replay starts
+    /// with a gap message, followed by all of the retained events after it.
+    pub(crate) fn subscribe(
+        &self,
+        from_seq: Option<u64>,
+        stream_id: Option<Uuid>,
+    ) -> (Vec<InstanceEventMessage>, broadcast::Receiver<InstanceEvent>) {
+        let inner = self.inner.lock().unwrap();
+        let mut replay = Vec::new();
+        if let Some(from_seq) = from_seq {
+            let oldest_seq =
+                inner.retained.front().map_or(inner.next_seq, |ev| ev.seq);
+            let same_stream = stream_id.map_or(true, |id| id == self.stream_id);
+            let start_seq = if same_stream && from_seq >= oldest_seq {
+                from_seq
+            } else {
+                replay.push(InstanceEventMessage::Gap(InstanceEventGap {
+                    stream_id: self.stream_id,
+                    next_seq: oldest_seq,
+                }));
+                oldest_seq
+            };
+
+            replay.extend(
+                inner
+                    .retained
+                    .iter()
+                    .filter(|event| event.seq >= start_seq)
+                    .cloned()
+                    .map(InstanceEventMessage::Event),
+            );
+        }
+
+        (replay, self.tx.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use propolis_api_types::RebootCause;
+
+    fn reboot(vcpu: i32) -> InstanceEventKind {
+        InstanceEventKind::Reboot { cause: RebootCause::TripleFault { vcpu } }
+    }
+
+    /// Splits a replay into its gap message, if it has one, and its events.
+    fn split(
+        replay: Vec<InstanceEventMessage>,
+    ) -> (Option<InstanceEventGap>, Vec<InstanceEvent>) {
+        let mut gap = None;
+        let mut events = Vec::new();
+        for msg in replay {
+            match msg {
+                InstanceEventMessage::Gap(g) => {
+                    assert!(events.is_empty(), "gap follows events");
+                    assert!(gap.replace(g).is_none(), "more than one gap");
+                }
+                InstanceEventMessage::Event(event) => events.push(event),
+            }
+        }
+        (gap, events)
+    }
+
+    #[test]
+    fn replay_from_offset() {
+        let log = EventLog::new();
+        for vcpu in 0..4 {
+            log.record(reboot(vcpu));
+        }
+
+        let (replay, mut rx) = log.subscribe(Some(2), Some(log.stream_id));
+        let (gap, events) = split(replay);
+        assert!(gap.is_none());
+        let seqs: Vec<u64> = events.iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, vec![2, 3]);
+        assert_eq!(events[0].kind, reboot(2));
+        assert_eq!(events[0].stream_id, log.stream_id);
+
+        // Later events arrive on the receiver, and only there.
+        log.record(reboot(4));
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event.seq, 4);
+        assert_eq!(event.kind, reboot(4));
+
+        let (replay, _rx) = log.subscribe(None, None);
+        assert!(replay.is_empty());
+        let (replay, _rx) = log.subscribe(Some(5), None);
+        assert!(replay.is_empty());
+    }
+
+    #[test]
+    fn old_events_are_discarded() {
+        let log = EventLog::new();
+        for _ in 0..MAX_RETAINED_EVENTS + 10 {
+            log.record(reboot(0));
+        }
+
+        let (replay, _rx) = log.subscribe(Some(0), None);
+        let (gap, events) = split(replay);
+        assert_eq!(
+            gap,
+            Some(InstanceEventGap { stream_id: log.stream_id, next_seq: 10 })
+        );
+        assert_eq!(events.len(), MAX_RETAINED_EVENTS);
+        assert_eq!(events[0].seq, 10);
+        assert_eq!(
+            events.last().unwrap().seq,
+            (MAX_RETAINED_EVENTS + 9) as u64
+        );
+
+        // Nothing is missing from the oldest retained event on.
+        let (replay, _rx) = log.subscribe(Some(10), None);
+        let (gap, events) = split(replay);
+        assert!(gap.is_none());
+        assert_eq!(events.len(), MAX_RETAINED_EVENTS);
+    }
+
+    #[test]
+    fn replay_from_other_stream() {
+        let log = EventLog::new();
+        for vcpu in 0..3 {
+            log.record(reboot(vcpu));
+        }
+
+        // The sequence number means nothing in this stream, so everything
+        // retained is sent.
+        let (replay, _rx) = log.subscribe(Some(2), Some(Uuid::new_v4()));
+        let (gap, events) = split(replay);
+        assert_eq!(
+            gap,
+            Some(InstanceEventGap { stream_id: log.stream_id, next_seq: 0 })
+        );
+        let seqs: Vec<u64> = events.iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, vec![0, 1, 2]);
+    }
+}
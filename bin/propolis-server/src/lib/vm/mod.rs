@@ -82,6 +82,7 @@
 use std::{collections::BTreeMap, net::SocketAddr, sync::Arc};
 
 use active::ActiveVm;
+use events::EventLog;
 use oximeter::types::ProducerRegistry;
 use propolis_api_types::{
     instance_spec::{v0::InstanceSpecV0, VersionedInstanceSpec},
@@ -95,10 +96,11 @@ use state_driver::StateDriverOutput;
 use state_publisher::StatePublisher;
 use tokio::sync::{oneshot, watch, RwLock, RwLockReadGuard};
 
-use crate::{server::MetricsEndpointConfig, vnc::VncServer};
+use crate::{api_auth::ApiAuth, server::MetricsEndpointConfig, vnc::VncServer};
 
 mod active;
 pub(crate) mod ensure;
+pub(crate) mod events;
 pub(crate) mod guest_event;
 pub(crate) mod objects;
 mod request_queue;
@@ -119,6 +121,23 @@ pub(crate) type BlockBackendMap =
 pub(crate) type CrucibleBackendMap =
     BTreeMap<uuid::Uuid, Arc<propolis::block::CrucibleBackend>>;
 
+/// Maps network device names to the devices themselves.
+pub(crate) type NetworkDeviceMap =
+    BTreeMap<String, Arc<propolis::hw::virtio::PciVirtioViona>>;
+
+/// Maps serial port numbers to the serial console connections for those ports.
+pub(crate) type SerialPortMap = BTreeMap<
+    propolis_api_types::instance_spec::components::devices::SerialPortNumber,
+    Arc<crate::serial::Serial<propolis::hw::uart::LpcUart>>,
+>;
+
+/// Maps virtio-serial device and port names to the console connections for
+/// those ports.
+pub(crate) type VirtioSerialPortMap = BTreeMap<
+    (String, String),
+    Arc<crate::serial::Serial<propolis::hw::virtio::console::ConsolePort>>,
+>;
+
 /// Type alias for the sender side of the channel that receives
 /// externally-visible instance state updates.
 type InstanceStateTx = watch::Sender<InstanceStateMonitorResponse>;
@@ -137,6 +156,14 @@ pub(crate) type CrucibleReplaceResult =
 pub(crate) type CrucibleReplaceResultTx =
     oneshot::Sender<CrucibleReplaceResult>;
 
+/// Type alias for the result of an attempt to save an instance's state to a
+/// file.
+pub(crate) type SaveResult = Result<(), crate::migrate::MigrateError>;
+
+/// Type alias for the sender side of a channel that receives instance save
+/// results.
+pub(crate) type SaveResultTx = oneshot::Sender<SaveResult>;
+
 /// Type alias for the sender side of a channel that receives the results of
 /// instance-ensure API calls.
 type InstanceEnsureResponseTx =
@@ -189,6 +216,9 @@ pub(crate) struct Vm {
     /// Routines that drive the VM state machine acquire this lock exclusively.
     inner: RwLock<VmInner>,
 
+    /// The log of events in the lives of this server's VMs.
+    events: Arc<EventLog>,
+
     /// A logger for this VM.
     log: slog::Logger,
 }
@@ -282,6 +312,11 @@ pub(super) struct EnsureOptions {
     /// The address of this Propolis process, used by the live migration
     /// protocol to transfer serial console connections.
     pub(super) local_server_addr: SocketAddr,
+
+    /// The server's API authentication settings, which also determine how
+    /// this server authenticates itself to, and authenticates, migration
+    /// peers.
+    pub(super) api_auth: Arc<ApiAuth>,
 }
 
 impl Vm {
@@ -289,7 +324,16 @@ impl Vm {
     pub fn new(log: &slog::Logger) -> Arc<Self> {
         let log = log.new(slog::o!("component" => "vm_wrapper"));
         let inner = VmInner { state: VmState::NoVm, driver: None };
-        Arc::new(Self { inner: RwLock::new(inner), log })
+        Arc::new(Self {
+            inner: RwLock::new(inner),
+            events: Arc::new(EventLog::new()),
+            log,
+        })
+    }
+
+    /// Yields the log of events in the lives of this server's VMs.
+    pub(crate) fn events(&self) -> &EventLog {
+        &self.events
     }
 
     /// If the VM is `Active`, yields a shared lock guard with a reference to
@@ -513,6 +557,7 @@ impl Vm {
         // the channel will move to the state driver task.
         let (external_publisher, external_rx) = StatePublisher::new(
             &log_for_driver,
+            self.events.clone(),
             InstanceStateMonitorResponse {
                 gen: 1,
                 state: if ensure_request.migrate.is_some() {
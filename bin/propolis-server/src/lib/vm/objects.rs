@@ -13,18 +13,27 @@ use std::{
 
 use futures::{future::BoxFuture, stream::FuturesUnordered, StreamExt};
 use propolis::{
-    hw::{ps2::ctrl::PS2Ctrl, qemu::ramfb::RamFb, uart::LpcUart},
+    hw::{
+        chipset::i440fx::Piix3PM, ps2::ctrl::PS2Ctrl, qemu::ramfb::RamFb,
+        uart::LpcUart, virtio::console::ConsolePort,
+    },
     vmm::VmmHdl,
     Machine,
 };
-use propolis_api_types::instance_spec::v0::InstanceSpecV0;
+use propolis_api_types::instance_spec::{
+    components::devices::SerialPortNumber, v0::InstanceSpecV0,
+};
 use slog::{error, info};
 use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
-use crate::{serial::Serial, vcpu_tasks::VcpuTaskController};
+use crate::{
+    guest_agent::GuestAgent, initializer::VirtioSerialPorts, serial::Serial,
+    vcpu_tasks::VcpuTaskController,
+};
 
 use super::{
-    state_driver::VmStartReason, BlockBackendMap, CrucibleBackendMap, DeviceMap,
+    state_driver::VmStartReason, BlockBackendMap, CrucibleBackendMap,
+    DeviceMap, NetworkDeviceMap, SerialPortMap,
 };
 
 /// A collection of components that make up a Propolis VM instance.
@@ -50,9 +59,14 @@ pub(super) struct InputVmObjects {
     pub devices: DeviceMap,
     pub block_backends: BlockBackendMap,
     pub crucible_backends: CrucibleBackendMap,
-    pub com1: Arc<Serial<LpcUart>>,
+    pub network_devices: NetworkDeviceMap,
+    pub serial_ports: SerialPortMap,
     pub framebuffer: Option<Arc<RamFb>>,
     pub ps2ctrl: Arc<PS2Ctrl>,
+    pub chipset_pm: Arc<Piix3PM>,
+    pub clipboard: Option<Arc<ConsolePort>>,
+    pub virtio_serial: VirtioSerialPorts,
+    pub guest_agent: Option<Arc<GuestAgent>>,
 }
 
 /// The collection of objects and state that make up a Propolis instance.
@@ -80,14 +94,30 @@ pub(crate) struct VmObjectsLocked {
     /// Maps from component names to Crucible backend objects.
     crucible_backends: CrucibleBackendMap,
 
-    /// A handle to the serial console connection to the VM's first COM port.
-    com1: Arc<Serial<LpcUart>>,
+    /// Maps from network device names to the devices.
+    network_devices: NetworkDeviceMap,
+
+    /// Handles to the serial console connections to each of the VM's COM
+    /// ports.
+    serial_ports: SerialPortMap,
 
     /// A handle to the VM's framebuffer.
     framebuffer: Option<Arc<RamFb>>,
 
     /// A handle to the VM's PS/2 controller.
     ps2ctrl: Arc<PS2Ctrl>,
+
+    /// A handle to the VM's ACPI power management device.
+    chipset_pm: Arc<Piix3PM>,
+
+    /// The port of the VM's clipboard channel device, if it has one.
+    clipboard: Option<Arc<ConsolePort>>,
+
+    /// The host ends of the ports of the VM's virtio-serial devices.
+    virtio_serial: VirtioSerialPorts,
+
+    /// The connection to the guest agent, if the VM has one.
+    guest_agent: Option<Arc<GuestAgent>>,
 }
 
 impl VmObjects {
@@ -125,9 +155,14 @@ impl VmObjectsLocked {
             devices: input.devices,
             block_backends: input.block_backends,
             crucible_backends: input.crucible_backends,
-            com1: input.com1,
+            network_devices: input.network_devices,
+            serial_ports: input.serial_ports,
             framebuffer: input.framebuffer,
             ps2ctrl: input.ps2ctrl,
+            chipset_pm: input.chipset_pm,
+            clipboard: input.clipboard,
+            virtio_serial: input.virtio_serial,
+            guest_agent: input.guest_agent,
         }
     }
 
@@ -173,10 +208,26 @@ impl VmObjectsLocked {
         &self.crucible_backends
     }
 
-    /// Yields a clonable reference to the serial console for this VM's first
-    /// COM port.
-    pub(crate) fn com1(&self) -> &Arc<Serial<LpcUart>> {
-        &self.com1
+    /// Yields the network device with the supplied spec name, if there is one.
+    pub(crate) fn network_device(
+        &self,
+        name: &str,
+    ) -> Option<&Arc<propolis::hw::virtio::PciVirtioViona>> {
+        self.network_devices.get(name)
+    }
+
+    /// Yields a clonable reference to the serial console for the supplied COM
+    /// port, if the VM has that port.
+    pub(crate) fn serial_port(
+        &self,
+        port: SerialPortNumber,
+    ) -> Option<&Arc<Serial<LpcUart>>> {
+        self.serial_ports.get(&port)
+    }
+
+    /// Yields the serial consoles for all of this VM's COM ports.
+    pub(crate) fn serial_ports(&self) -> &SerialPortMap {
+        &self.serial_ports
     }
 
     /// Yields a clonable reference to this VM's framebuffer.
@@ -189,6 +240,38 @@ impl VmObjectsLocked {
         &self.ps2ctrl
     }
 
+    /// Presses the VM's ACPI power button, returning `false` if the guest
+    /// hasn't enabled power button events and so won't respond to the press.
+    pub(crate) fn press_power_button(&self) -> bool {
+        self.chipset_pm.press_power_button()
+    }
+
+    /// Yields the port of this VM's clipboard channel, if it has one.
+    pub(crate) fn clipboard(&self) -> &Option<Arc<ConsolePort>> {
+        &self.clipboard
+    }
+
+    /// Yields a clonable reference to the console connection for the named
+    /// virtio-serial port, if the VM has that port and it is reachable
+    /// through the server's websocket.
+    pub(crate) fn virtio_serial_port(
+        &self,
+        device: &str,
+        port: &str,
+    ) -> Option<&Arc<Serial<ConsolePort>>> {
+        self.virtio_serial.buffered.get(&(device.to_owned(), port.to_owned()))
+    }
+
+    /// Yields the host ends of this VM's virtio-serial ports.
+    pub(crate) fn virtio_serial_ports(&self) -> &VirtioSerialPorts {
+        &self.virtio_serial
+    }
+
+    /// Yields the connection to this VM's guest agent, if it has one.
+    pub(crate) fn guest_agent(&self) -> &Option<Arc<GuestAgent>> {
+        &self.guest_agent
+    }
+
     /// Iterates over all of the lifecycle trait objects in this VM and calls
     /// `func` on each one.
     pub(crate) fn for_each_device(
@@ -21,30 +21,34 @@
 //! Users who want to share a queue must wrap it in the synchronization objects
 //! of their choice.
 
-use std::collections::VecDeque;
+use std::{collections::VecDeque, path::PathBuf};
 
 use slog::{debug, info, Logger};
 use thiserror::Error;
 use uuid::Uuid;
 
-/// Wraps a [`dropshot::WebsocketConnection`] for inclusion in an
-/// [`ExternalRequest`].
+use crate::migrate::MigrateConn;
+
+/// Wraps a migration destination's connection for inclusion in an
+/// [`ExternalRequest`]. This is the upgraded websocket connection, or, if
+/// migration peers authenticate one another, the TLS session established
+/// beneath it.
 //
 // This newtype allows this module's tests (which want to verify queuing
 // dispositions and don't care about request contents) to construct a
 // `MigrateAsSource` request without having to conjure up a real websocket
 // connection.
-pub(crate) struct WebsocketConnection(Option<dropshot::WebsocketConnection>);
+pub(crate) struct MigrationConnection(Option<Box<dyn MigrateConn>>);
 
-impl From<dropshot::WebsocketConnection> for WebsocketConnection {
-    fn from(value: dropshot::WebsocketConnection) -> Self {
+impl From<Box<dyn MigrateConn>> for MigrationConnection {
+    fn from(value: Box<dyn MigrateConn>) -> Self {
         Self(Some(value))
     }
 }
 
-impl WebsocketConnection {
-    /// Yields the wrapped [`dropshot::WebsocketConnection`].
-    pub(crate) fn into_inner(self) -> dropshot::WebsocketConnection {
+impl MigrationConnection {
+    /// Yields the wrapped connection.
+    pub(crate) fn into_inner(self) -> Box<dyn MigrateConn> {
         // Unwrapping is safe here because the only way an external consumer can
         // get an instance of this wrapper is to use the From impl, which always
         // wraps a `Some`.
@@ -60,7 +64,7 @@ pub enum ExternalRequest {
     Start,
 
     /// Asks the state worker to start a migration-source task.
-    MigrateAsSource { migration_id: Uuid, websock: WebsocketConnection },
+    MigrateAsSource { migration_id: Uuid, conn: MigrationConnection },
 
     /// Resets the guest by pausing all devices, resetting them to their
     /// cold-boot states, and resuming the devices. Note that this is not a
@@ -71,6 +75,11 @@ pub enum ExternalRequest {
     /// coordinate with guest software.
     Stop,
 
+    /// Presses the VM's ACPI power button and waits for the guest to power
+    /// off, halting the VM if it hasn't done so once the state driver's soft
+    /// stop timeout elapses.
+    SoftStop,
+
     /// Attempts to update the volume construction request for the supplied
     /// Crucible volume.
     ///
@@ -91,6 +100,19 @@ pub enum ExternalRequest {
         /// The sink for the result of this operation.
         result_tx: super::CrucibleReplaceResultTx,
     },
+
+    /// Pauses the VM, writes its state to a file, and then either resumes or
+    /// stops it.
+    Save {
+        /// The path of the file to write.
+        path: PathBuf,
+
+        /// True if the VM should stop after its state is saved.
+        stop_after_save: bool,
+
+        /// The sink for the result of this operation.
+        result_tx: super::SaveResultTx,
+    },
 }
 
 impl std::fmt::Debug for ExternalRequest {
@@ -103,6 +125,7 @@ impl std::fmt::Debug for ExternalRequest {
                 .finish(),
             Self::Reboot => write!(f, "Reboot"),
             Self::Stop => write!(f, "Stop"),
+            Self::SoftStop => write!(f, "SoftStop"),
             Self::ReconfigureCrucibleVolume {
                 disk_name, backend_id, ..
             } => f
@@ -110,6 +133,11 @@ impl std::fmt::Debug for ExternalRequest {
                 .field("disk_name", disk_name)
                 .field("backend_id", backend_id)
                 .finish(),
+            Self::Save { path, stop_after_save, .. } => f
+                .debug_struct("Save")
+                .field("path", path)
+                .field("stop_after_save", stop_after_save)
+                .finish(),
         }
     }
 }
@@ -176,7 +204,9 @@ struct AllowedRequests {
     migrate_as_source: RequestDisposition,
     reboot: RequestDisposition,
     mutate: RequestDisposition,
+    save: RequestDisposition,
     stop: RequestDisposition,
+    soft_stop: RequestDisposition,
 }
 
 /// A queue for external requests to change an instance's state.
@@ -218,7 +248,13 @@ impl ExternalRequestQueue {
                 mutate: RequestDisposition::Deny(
                     RequestDeniedReason::InstanceNotActive,
                 ),
+                save: RequestDisposition::Deny(
+                    RequestDeniedReason::InstanceNotActive,
+                ),
                 stop: RequestDisposition::Enqueue,
+                soft_stop: RequestDisposition::Deny(
+                    RequestDeniedReason::InstanceNotActive,
+                ),
             },
             log,
         }
@@ -250,11 +286,15 @@ impl ExternalRequestQueue {
             ExternalRequest::ReconfigureCrucibleVolume { .. } => {
                 self.allowed.mutate
             }
+            ExternalRequest::Save { .. } => self.allowed.save,
 
             // Requests to stop always succeed. Note that a request to stop a VM
             // that hasn't started should still be queued to the state worker so
             // that the worker can exit and drop its references to the instance.
             ExternalRequest::Stop => self.allowed.stop,
+
+            // Soft stops need a running guest to respond to them.
+            ExternalRequest::SoftStop => self.allowed.soft_stop,
         };
 
         info!(&self.log, "Queuing external request";
@@ -301,7 +341,9 @@ impl ExternalRequestQueue {
                     migrate_as_source: Disposition::Deny(reason),
                     reboot: Disposition::Deny(reason),
                     mutate: Disposition::Deny(reason),
+                    save: Disposition::Deny(reason),
                     stop: self.allowed.stop,
+                    soft_stop: Disposition::Deny(reason),
                 }
             }
             ChangeReason::ApiRequest(ExternalRequest::MigrateAsSource {
@@ -324,7 +366,13 @@ impl ExternalRequestQueue {
                     mutate: Disposition::Deny(
                         DenyReason::InvalidRequestForMigrationSource,
                     ),
+                    save: Disposition::Deny(
+                        DenyReason::InvalidRequestForMigrationSource,
+                    ),
                     stop: self.allowed.stop,
+                    soft_stop: Disposition::Deny(
+                        DenyReason::InvalidRequestForMigrationSource,
+                    ),
                 }
             }
 
@@ -348,7 +396,25 @@ impl ExternalRequestQueue {
                     migrate_as_source: Disposition::Deny(reason),
                     reboot: Disposition::Deny(reason),
                     mutate: Disposition::Deny(reason),
+                    save: Disposition::Deny(reason),
                     stop: Disposition::Ignore,
+                    soft_stop: Disposition::Ignore,
+                }
+            }
+
+            // Requests to stop the instance softly likewise block other
+            // requests, except for requests to stop it forcibly, which allow
+            // callers to stop waiting for the guest.
+            ChangeReason::ApiRequest(ExternalRequest::SoftStop) => {
+                let reason = DenyReason::HaltPending;
+                AllowedRequests {
+                    start: Disposition::Deny(reason),
+                    migrate_as_source: Disposition::Deny(reason),
+                    reboot: Disposition::Deny(reason),
+                    mutate: Disposition::Deny(reason),
+                    save: Disposition::Deny(reason),
+                    stop: self.allowed.stop,
+                    soft_stop: Disposition::Ignore,
                 }
             }
 
@@ -358,6 +424,31 @@ impl ExternalRequestQueue {
                 ExternalRequest::ReconfigureCrucibleVolume { .. },
             ) => self.allowed,
 
+            // A save that resumes the VM afterward leaves it in the state it
+            // started in, so it doesn't change any dispositions. A save that
+            // stops the VM behaves like a request to stop: the state driver
+            // queues its own stop request once the save succeeds, and nothing
+            // else should be queued behind it.
+            ChangeReason::ApiRequest(ExternalRequest::Save {
+                stop_after_save,
+                ..
+            }) => {
+                if *stop_after_save {
+                    let reason = DenyReason::HaltPending;
+                    AllowedRequests {
+                        start: Disposition::Deny(reason),
+                        migrate_as_source: Disposition::Deny(reason),
+                        reboot: Disposition::Deny(reason),
+                        mutate: Disposition::Deny(reason),
+                        save: Disposition::Deny(reason),
+                        stop: self.allowed.stop,
+                        soft_stop: Disposition::Deny(reason),
+                    }
+                } else {
+                    self.allowed
+                }
+            }
+
             // When an instance begins running, requests to migrate out of it or
             // to reboot it become valid.
             ChangeReason::StateChange(InstanceStateChange::StartedRunning) => {
@@ -366,7 +457,9 @@ impl ExternalRequestQueue {
                     migrate_as_source: Disposition::Enqueue,
                     reboot: Disposition::Enqueue,
                     mutate: Disposition::Enqueue,
+                    save: Disposition::Enqueue,
                     stop: self.allowed.stop,
+                    soft_stop: Disposition::Enqueue,
                 }
             }
 
@@ -396,7 +489,9 @@ impl ExternalRequestQueue {
                     migrate_as_source: Disposition::Deny(reason),
                     reboot: Disposition::Deny(reason),
                     mutate: Disposition::Deny(reason),
+                    save: Disposition::Deny(reason),
                     stop: Disposition::Ignore,
+                    soft_stop: Disposition::Ignore,
                 }
             }
             ChangeReason::StateChange(InstanceStateChange::Failed) => {
@@ -406,7 +501,9 @@ impl ExternalRequestQueue {
                     migrate_as_source: Disposition::Deny(reason),
                     reboot: Disposition::Deny(reason),
                     mutate: Disposition::Deny(reason),
+                    save: Disposition::Deny(reason),
                     stop: self.allowed.stop,
+                    soft_stop: Disposition::Deny(reason),
                 }
             }
         }
@@ -437,12 +534,23 @@ impl Drop for ExternalRequestQueue {
                         )));
                 }
 
+                // Save requestors also wait for their requests to be retired.
+                ExternalRequest::Save { result_tx, .. } => {
+                    let _ = result_tx.send(Err(
+                        crate::migrate::MigrateError::StateMachine(
+                            "VM destroyed before request could be handled"
+                                .to_string(),
+                        ),
+                    ));
+                }
+
                 // Requests to start, reboot, and stop are handled
                 // asynchronously (calls to change the instance's state return
                 // as soon as they're queued).
                 ExternalRequest::Start
                 | ExternalRequest::Reboot
-                | ExternalRequest::Stop => {}
+                | ExternalRequest::Stop
+                | ExternalRequest::SoftStop => {}
 
                 // Dropping a request to migrate out drops the embedded
                 // connection to the migration target, thus notifying it that
@@ -466,7 +574,7 @@ mod test {
     fn make_migrate_as_source_request() -> ExternalRequest {
         ExternalRequest::MigrateAsSource {
             migration_id: Uuid::new_v4(),
-            websock: WebsocketConnection(None),
+            conn: MigrationConnection(None),
         }
     }
 
@@ -480,6 +588,15 @@ mod test {
         }
     }
 
+    fn make_save_request(stop_after_save: bool) -> ExternalRequest {
+        let (tx, _rx) = tokio::sync::oneshot::channel();
+        ExternalRequest::Save {
+            path: PathBuf::from("/tmp/instance.save"),
+            stop_after_save,
+            result_tx: tx,
+        }
+    }
+
     #[tokio::test]
     async fn start_requests_become_idempotent_after_first_request() {
         let mut queue =
@@ -623,4 +740,79 @@ mod test {
         queue.notify_instance_state_change(InstanceStateChange::Stopped);
         assert!(queue.try_queue(make_reconfigure_crucible_request()).is_err());
     }
+
+    #[tokio::test]
+    async fn save_requires_running_and_not_migrating_out() {
+        let mut queue =
+            ExternalRequestQueue::new(test_logger(), InstanceAutoStart::No);
+
+        // Saving a VM before it has started is not allowed.
+        assert!(queue.try_queue(make_save_request(false)).is_err());
+        queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
+        assert!(queue.try_queue(make_save_request(false)).is_ok());
+
+        // A save that resumes the VM doesn't block other requests.
+        assert!(queue.try_queue(ExternalRequest::Reboot).is_ok());
+        assert!(queue.try_queue(make_save_request(false)).is_ok());
+        assert!(matches!(
+            queue.pop_front(),
+            Some(ExternalRequest::Save { .. })
+        ));
+        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Reboot)));
+        assert!(matches!(
+            queue.pop_front(),
+            Some(ExternalRequest::Save { .. })
+        ));
+
+        // Saves are denied while a migration out is pending.
+        assert!(queue.try_queue(make_migrate_as_source_request()).is_ok());
+        assert!(queue.try_queue(make_save_request(false)).is_err());
+    }
+
+    #[tokio::test]
+    async fn save_and_stop_blocks_later_requests() {
+        let mut queue =
+            ExternalRequestQueue::new(test_logger(), InstanceAutoStart::Yes);
+        queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
+
+        assert!(queue.try_queue(make_save_request(true)).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Reboot).is_err());
+        assert!(queue.try_queue(make_migrate_as_source_request()).is_err());
+        assert!(queue.try_queue(make_save_request(false)).is_err());
+
+        // The state driver must still be able to queue its own stop request
+        // once the save completes.
+        assert!(queue.try_queue(ExternalRequest::Stop).is_ok());
+        assert!(matches!(
+            queue.pop_front(),
+            Some(ExternalRequest::Save { .. })
+        ));
+        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Stop)));
+    }
+
+    #[tokio::test]
+    async fn soft_stop_requires_running_and_allows_escalation() {
+        let mut queue =
+            ExternalRequestQueue::new(test_logger(), InstanceAutoStart::No);
+
+        // There's no guest to ask to shut down until the instance runs.
+        assert!(queue.try_queue(ExternalRequest::SoftStop).is_err());
+        queue.notify_instance_state_change(InstanceStateChange::StartedRunning);
+
+        // Once a soft stop is queued, further soft stops are ignored and other
+        // requests are denied, but the instance can still be stopped forcibly.
+        assert!(queue.try_queue(ExternalRequest::SoftStop).is_ok());
+        assert!(queue.try_queue(ExternalRequest::SoftStop).is_ok());
+        assert!(queue.try_queue(ExternalRequest::Reboot).is_err());
+        assert!(queue.try_queue(make_save_request(false)).is_err());
+        assert!(queue.try_queue(ExternalRequest::Stop).is_ok());
+        assert!(matches!(queue.pop_front(), Some(ExternalRequest::SoftStop)));
+        assert!(matches!(queue.pop_front(), Some(ExternalRequest::Stop)));
+        assert!(queue.is_empty());
+
+        // Soft stops of a stopped instance succeed without being queued.
+        queue.notify_instance_state_change(InstanceStateChange::Stopped);
+        assert!(queue.try_queue(ExternalRequest::SoftStop).is_ok());
+        assert!(queue.is_empty());
+    }
 }
@@ -5,18 +5,24 @@
 //! Services visible to consumers outside this Propolis that depend on
 //! functionality supplied by an extant VM.
 
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
 use oximeter::types::ProducerRegistry;
-use propolis_api_types::InstanceProperties;
+use propolis::chardev::{Sink, Source};
+use propolis_api_types::{
+    instance_spec::components::devices::SerialPortNumber, InstanceProperties,
+};
 use slog::{error, info, Logger};
+use tokio::task::JoinHandle;
 
 use crate::{
-    serial::SerialTaskControlMessage, server::MetricsEndpointConfig,
-    stats::virtual_machine::VirtualMachine, vnc::VncServer,
+    serial::{Serial, SerialTask, SerialTaskControlMessage},
+    server::MetricsEndpointConfig,
+    stats::virtual_machine::VirtualMachine,
+    vnc::VncServer,
 };
 
-use super::objects::{VmObjects, VmObjectsShared};
+use super::objects::VmObjects;
 
 /// Information used to serve Oximeter metrics.
 #[derive(Default)]
@@ -32,8 +38,18 @@ pub(crate) struct OximeterState {
 /// A collection of services visible to consumers outside this Propolis that
 /// depend on the functionality supplied by an extant VM.
 pub(crate) struct VmServices {
-    /// A VM's serial console handler task.
-    pub serial_task: tokio::sync::Mutex<Option<crate::serial::SerialTask>>,
+    /// A VM's serial console handler tasks, one for each of its COM ports.
+    pub serial_tasks:
+        tokio::sync::Mutex<BTreeMap<SerialPortNumber, SerialTask>>,
+
+    /// A VM's virtio-serial console handler tasks, keyed by device and port
+    /// name, for each port reachable through the server's websocket.
+    pub virtio_serial_tasks:
+        tokio::sync::Mutex<BTreeMap<(String, String), SerialTask>>,
+
+    /// The tasks relaying a VM's other virtio-serial ports to and from their
+    /// Unix domain sockets.
+    virtio_serial_sockets: tokio::sync::Mutex<Vec<JoinHandle<()>>>,
 
     /// A VM's Oximeter server.
     pub oximeter: tokio::sync::Mutex<OximeterState>,
@@ -65,11 +81,44 @@ impl VmServices {
         if let Some(ramfb) = vm_objects.framebuffer() {
             vnc_server.attach(vm_objects.ps2ctrl().clone(), ramfb.clone());
         }
+        if let Some(port) = vm_objects.clipboard() {
+            vnc_server.attach_clipboard(port.clone());
+        }
 
-        let serial_task = start_serial_task(log, &vm_objects).await;
+        let mut serial_tasks = BTreeMap::new();
+        for (port, serial) in vm_objects.serial_ports() {
+            let log = log.new(slog::o!("port" => ?port));
+            serial_tasks
+                .insert(*port, start_serial_task(&log, serial.clone()).await);
+        }
+
+        let virtio_serial = vm_objects.virtio_serial_ports();
+        let mut virtio_serial_tasks = BTreeMap::new();
+        for ((device, port), serial) in &virtio_serial.buffered {
+            let log = log.new(slog::o!("device" => device.clone(),
+                                       "port" => port.clone()));
+            virtio_serial_tasks.insert(
+                (device.clone(), port.clone()),
+                start_serial_task(&log, serial.clone()).await,
+            );
+        }
+        let virtio_serial_sockets = virtio_serial
+            .sockets
+            .iter()
+            .map(|(sock, port)| {
+                sock.spawn(
+                    port.clone() as Arc<dyn Sink>,
+                    port.clone() as Arc<dyn Source>,
+                )
+            })
+            .collect();
 
         Self {
-            serial_task: tokio::sync::Mutex::new(Some(serial_task)),
+            serial_tasks: tokio::sync::Mutex::new(serial_tasks),
+            virtio_serial_tasks: tokio::sync::Mutex::new(virtio_serial_tasks),
+            virtio_serial_sockets: tokio::sync::Mutex::new(
+                virtio_serial_sockets,
+            ),
             oximeter: tokio::sync::Mutex::new(oximeter_state),
             vnc_server,
         }
@@ -79,7 +128,12 @@ impl VmServices {
     pub(super) async fn stop(&self, log: &Logger) {
         self.vnc_server.stop().await;
 
-        if let Some(serial_task) = self.serial_task.lock().await.take() {
+        let serial_tasks = std::mem::take(&mut *self.serial_tasks.lock().await);
+        let virtio_serial_tasks =
+            std::mem::take(&mut *self.virtio_serial_tasks.lock().await);
+        for serial_task in
+            serial_tasks.into_values().chain(virtio_serial_tasks.into_values())
+        {
             let _ = serial_task
                 .control_ch
                 .send(SerialTaskControlMessage::Stopping)
@@ -87,6 +141,10 @@ impl VmServices {
             let _ = serial_task.task.await;
         }
 
+        for task in self.virtio_serial_sockets.lock().await.drain(..) {
+            task.abort();
+        }
+
         let mut oximeter_state = self.oximeter.lock().await;
         if let Some(server) = oximeter_state.server.take() {
             if let Err(e) = server.close().await {
@@ -162,15 +220,15 @@ async fn register_oximeter_producer(
     oximeter_state
 }
 
-/// Launches a serial console handler task.
-async fn start_serial_task<'vm>(
+/// Launches a serial console handler task for the supplied serial connection,
+/// which may be to a COM port or a virtio-serial port.
+async fn start_serial_task<Device: Sink + Source>(
     log: &slog::Logger,
-    vm_objects: &VmObjectsShared<'vm>,
-) -> crate::serial::SerialTask {
+    serial: Arc<Serial<Device>>,
+) -> SerialTask {
     let (websocks_ch, websocks_recv) = tokio::sync::mpsc::channel(1);
     let (control_ch, control_recv) = tokio::sync::mpsc::channel(1);
 
-    let serial = vm_objects.com1().clone();
     serial.set_task_control_sender(control_ch.clone()).await;
     let err_log = log.new(slog::o!("component" => "serial task"));
     let task = tokio::spawn(async move {
@@ -186,5 +244,5 @@ async fn start_serial_task<'vm>(
         }
     });
 
-    crate::serial::SerialTask { task, control_ch, websocks_ch }
+    SerialTask { task, control_ch, websocks_ch }
 }
@@ -5,6 +5,7 @@
 //! A task to handle requests to change a VM's state or configuration.
 
 use std::{
+    path::Path,
     sync::{Arc, Mutex},
     time::Duration,
 };
@@ -14,15 +15,17 @@ use propolis_api_types::{
     instance_spec::{
         components::backends::CrucibleStorageBackend, v0::StorageBackendV0,
     },
-    InstanceSpecEnsureRequest, InstanceState, MigrationState,
+    InstanceEventKind, InstanceSpecEnsureRequest, InstanceState,
+    MigrationState, RebootCause,
 };
-use slog::{error, info};
+use slog::{error, info, warn};
 use tokio::sync::Notify;
 use uuid::Uuid;
 
 use crate::{
     migrate::{
-        destination::DestinationProtocol, source::SourceProtocol, MigrateRole,
+        destination::DestinationProtocol, source::SourceProtocol, MigrateConn,
+        MigrateRole,
     },
     vm::state_publisher::ExternalStateUpdate,
 };
@@ -243,6 +246,14 @@ struct StateDriver {
 
     /// State persisted from previous attempts to migrate out of this VM.
     migration_src_state: crate::migrate::source::PersistentState,
+
+    /// How long to wait for the guest to power off after a soft stop request
+    /// presses its power button.
+    soft_stop_timeout: Duration,
+
+    /// The time at which a pending soft stop gives up on the guest and halts
+    /// the VM, if one is pending.
+    soft_stop_deadline: Option<tokio::time::Instant>,
 }
 
 /// The values returned by a state driver task when it exits.
@@ -286,6 +297,9 @@ pub(super) async fn run_state_driver(
     };
 
     let (objects, input_queue) = activated_vm.into_inner();
+    let soft_stop_timeout = Duration::from_secs(
+        ensure_options.toml_config.shutdown.soft_stop_timeout_secs,
+    );
     let state_driver = StateDriver {
         log,
         objects,
@@ -293,11 +307,18 @@ pub(super) async fn run_state_driver(
         external_state: state_publisher,
         paused: false,
         migration_src_state: Default::default(),
+        soft_stop_timeout,
+        soft_stop_deadline: None,
     };
 
     // Run the VM until it exits, then set rundown on the parent VM so that no
     // new external callers can access its objects or services.
-    let output = state_driver.run(ensure_request.migrate.is_some()).await;
+    let output = state_driver
+        .run(
+            ensure_request.migrate.is_some()
+                || ensure_request.restore.is_some(),
+        )
+        .await;
     vm.set_rundown().await;
     output
 }
@@ -326,6 +347,7 @@ async fn create_and_activate_vm<'a>(
             log,
             migrate_request,
             ensure_options.local_server_addr,
+            &ensure_options.api_auth,
         )
         .await
         {
@@ -346,6 +368,16 @@ async fn create_and_activate_vm<'a>(
             .run(ensure)
             .await
             .context("running live migration protocol")?)
+    } else if let Some(restore_request) = ensure_request.restore.as_ref() {
+        // As with migration, the restore routine is responsible for reporting
+        // failure to the ensure caller if it can't activate the VM.
+        Ok(crate::migrate::savefile::restore(
+            log,
+            Path::new(&restore_request.path),
+            ensure,
+        )
+        .await
+        .context("restoring instance from saved state")?)
     } else {
         let created = ensure
             .create_objects()
@@ -376,7 +408,19 @@ impl StateDriver {
     async fn run_loop(&mut self) -> InstanceState {
         info!(self.log, "state driver entered main loop");
         loop {
-            let event = self.input_queue.wait_for_next_event().await;
+            // Waiting for the next event is cancel-safe: events are only
+            // removed from the queue once the wait is over.
+            let event = match self.soft_stop_deadline {
+                Some(deadline) => tokio::select! {
+                    event = self.input_queue.wait_for_next_event() => event,
+                    _ = tokio::time::sleep_until(deadline) => {
+                        warn!(self.log, "guest did not power off in time";
+                              "timeout" => ?self.soft_stop_timeout);
+                        InputQueueEvent::ExternalRequest(ExternalRequest::Stop)
+                    }
+                },
+                None => self.input_queue.wait_for_next_event().await,
+            };
             info!(self.log, "state driver handling event"; "event" => ?event);
 
             let outcome = match event {
@@ -437,7 +481,7 @@ impl StateDriver {
             }
             GuestEvent::VcpuSuspendReset(_when) => {
                 info!(self.log, "Resetting due to VM suspend event");
-                self.do_reboot().await;
+                self.do_reboot(RebootCause::VcpuReset).await;
                 HandleEventOutcome::Continue
             }
             GuestEvent::VcpuSuspendTripleFault(vcpu_id, _when) => {
@@ -445,7 +489,8 @@ impl StateDriver {
                     self.log,
                     "Resetting due to triple fault on vCPU {}", vcpu_id
                 );
-                self.do_reboot().await;
+                self.do_reboot(RebootCause::TripleFault { vcpu: vcpu_id })
+                    .await;
                 HandleEventOutcome::Continue
             }
             GuestEvent::ChipsetHalt => {
@@ -457,7 +502,7 @@ impl StateDriver {
             }
             GuestEvent::ChipsetReset => {
                 info!(self.log, "Resetting due to chipset-driven reset");
-                self.do_reboot().await;
+                self.do_reboot(RebootCause::ChipsetReset).await;
                 HandleEventOutcome::Continue
             }
         }
@@ -476,9 +521,8 @@ impl StateDriver {
                     },
                 }
             }
-            ExternalRequest::MigrateAsSource { migration_id, websock } => {
-                self.migrate_as_source(migration_id, websock.into_inner())
-                    .await;
+            ExternalRequest::MigrateAsSource { migration_id, conn } => {
+                self.migrate_as_source(migration_id, conn.into_inner()).await;
 
                 // The callee either queues its own stop request (on a
                 // successful migration out) or resumes the VM (on a failed
@@ -487,7 +531,7 @@ impl StateDriver {
                 HandleEventOutcome::Continue
             }
             ExternalRequest::Reboot => {
-                self.do_reboot().await;
+                self.do_reboot(RebootCause::Requested).await;
                 HandleEventOutcome::Continue
             }
             ExternalRequest::Stop => {
@@ -496,6 +540,7 @@ impl StateDriver {
                     final_state: InstanceState::Destroyed,
                 }
             }
+            ExternalRequest::SoftStop => self.do_soft_stop().await,
             ExternalRequest::ReconfigureCrucibleVolume {
                 disk_name,
                 backend_id,
@@ -512,11 +557,21 @@ impl StateDriver {
                 );
                 HandleEventOutcome::Continue
             }
+            ExternalRequest::Save { path, stop_after_save, result_tx } => {
+                let _ = result_tx.send(self.save(&path, stop_after_save).await);
+
+                // As with migration, a successful save-and-stop queues its own
+                // stop request, so the main loop can proceed as normal.
+                HandleEventOutcome::Continue
+            }
         }
     }
 
-    async fn do_reboot(&mut self) {
-        info!(self.log, "resetting instance");
+    async fn do_reboot(&mut self, cause: RebootCause) {
+        info!(self.log, "resetting instance"; "cause" => ?cause);
+        self.external_state
+            .events()
+            .record(InstanceEventKind::Reboot { cause });
 
         self.external_state
             .update(ExternalStateUpdate::Instance(InstanceState::Rebooting));
@@ -532,6 +587,30 @@ impl StateDriver {
             .update(ExternalStateUpdate::Instance(InstanceState::Running));
     }
 
+    /// Presses the guest's power button, leaving the guest to power off (which
+    /// it reports through the chipset) before the soft stop timeout elapses.
+    async fn do_soft_stop(&mut self) -> HandleEventOutcome {
+        let listening = self.objects.lock_shared().await.press_power_button();
+        if !listening {
+            warn!(
+                self.log,
+                "guest hasn't enabled power button events, halting"
+            );
+            self.do_halt().await;
+            return HandleEventOutcome::Exit {
+                final_state: InstanceState::Destroyed,
+            };
+        }
+
+        info!(self.log, "pressed power button, waiting for guest to power off";
+              "timeout" => ?self.soft_stop_timeout);
+        self.external_state
+            .update(ExternalStateUpdate::Instance(InstanceState::Stopping));
+        self.soft_stop_deadline =
+            Some(tokio::time::Instant::now() + self.soft_stop_timeout);
+        HandleEventOutcome::Continue
+    }
+
     async fn do_halt(&mut self) {
         info!(self.log, "stopping instance");
         self.external_state
@@ -576,10 +655,10 @@ impl StateDriver {
     async fn migrate_as_source(
         &mut self,
         migration_id: Uuid,
-        websock: dropshot::WebsocketConnection,
+        conn: Box<dyn MigrateConn>,
     ) {
         let conn = tokio_tungstenite::WebSocketStream::from_raw_socket(
-            websock.into_inner(),
+            conn,
             tokio_tungstenite::tungstenite::protocol::Role::Server,
             None,
         )
@@ -645,6 +724,50 @@ impl StateDriver {
         }
     }
 
+    async fn save(
+        &mut self,
+        path: &Path,
+        stop_after_save: bool,
+    ) -> super::SaveResult {
+        info!(self.log, "saving instance state";
+              "path" => %path.display(),
+              "stop_after_save" => stop_after_save);
+
+        self.objects.lock_exclusive().await.pause().await;
+        self.paused = true;
+
+        let result =
+            crate::migrate::savefile::save(&self.log, &self.objects, path)
+                .await;
+
+        match &result {
+            Ok(()) if stop_after_save => {
+                info!(self.log, "instance state saved, queuing stop");
+                self.input_queue
+                    .queue_external_request(ExternalRequest::Stop)
+                    .expect("can always queue a request to stop");
+            }
+            _ => {
+                if let Err(e) = &result {
+                    error!(self.log, "failed to save instance state, resuming";
+                           "error" => %e);
+                } else {
+                    info!(self.log, "instance state saved, resuming");
+                }
+
+                self.objects.lock_exclusive().await.resume();
+                self.paused = false;
+
+                // A failed save-and-stop request left the request queue
+                // expecting the VM to halt. Republish that the VM is running so
+                // that it will accept new requests again.
+                self.publish_steady_state(InstanceState::Running);
+            }
+        }
+
+        result
+    }
+
     async fn reconfigure_crucible_volume(
         &self,
         disk_name: String,
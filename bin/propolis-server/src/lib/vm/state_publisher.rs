@@ -5,15 +5,18 @@
 //! Helper types for publishing instance states as made visible through the
 //! external API.
 
+use std::sync::Arc;
+
 use propolis_api_types::{
-    InstanceMigrateStatusResponse, InstanceMigrationStatus, InstanceState,
-    InstanceStateMonitorResponse,
+    InstanceEventKind, InstanceMigrateStatusResponse, InstanceMigrationStatus,
+    InstanceState, InstanceStateMonitorResponse,
 };
 use slog::info;
 use uuid::Uuid;
 
 use crate::migrate::MigrateRole;
 
+use super::events::EventLog;
 use super::{InstanceStateRx, InstanceStateTx};
 
 /// An update to an instance's migration's state.
@@ -62,18 +65,28 @@ pub(crate) enum ExternalStateUpdate {
 }
 
 /// A channel to which to publish externally-visible instance state updates.
+/// Changes in state are also recorded in the server's event log.
 pub(crate) struct StatePublisher {
     tx: InstanceStateTx,
+    events: Arc<EventLog>,
     log: slog::Logger,
 }
 
 impl StatePublisher {
     pub(super) fn new(
         log: &slog::Logger,
+        events: Arc<EventLog>,
         initial_state: InstanceStateMonitorResponse,
     ) -> (Self, InstanceStateRx) {
+        record_changes(&events, None, &initial_state);
         let (tx, rx) = tokio::sync::watch::channel(initial_state);
-        (Self { tx, log: log.clone() }, rx)
+        (Self { tx, events, log: log.clone() }, rx)
+    }
+
+    /// Yields the event log to which this publisher records state changes, so
+    /// that other events in the instance's life can be recorded there too.
+    pub(crate) fn events(&self) -> &Arc<EventLog> {
+        &self.events
     }
 
     /// Updates an instance's externally-visible state and publishes that state
@@ -85,11 +98,12 @@ impl StatePublisher {
             ExternalStateUpdate::Complete(i, m) => (Some(i), Some(m)),
         };
 
+        let old = self.tx.borrow().clone();
         let InstanceStateMonitorResponse {
             state: old_instance,
             migration: old_migration,
             gen: old_gen,
-        } = self.tx.borrow().clone();
+        } = old.clone();
 
         let state = instance_state.unwrap_or(old_instance);
         let migration = if let Some(migration_state) = migration_state {
@@ -104,11 +118,41 @@ impl StatePublisher {
               "state" => ?state,
               "migration" => ?migration);
 
-        let _ =
-            self.tx.send(propolis_api_types::InstanceStateMonitorResponse {
-                gen,
-                state,
-                migration,
-            });
+        let new = propolis_api_types::InstanceStateMonitorResponse {
+            gen,
+            state,
+            migration,
+        };
+        record_changes(&self.events, Some(&old), &new);
+        let _ = self.tx.send(new);
+    }
+}
+
+/// Records the differences between an instance's `old` and `new` states (or
+/// its `new` state in full, if it has no old one) in `events`.
+fn record_changes(
+    events: &EventLog,
+    old: Option<&InstanceStateMonitorResponse>,
+    new: &InstanceStateMonitorResponse,
+) {
+    if old.map_or(true, |old| old.state != new.state) {
+        events.record(InstanceEventKind::StateChange {
+            gen: new.gen,
+            state: new.state,
+        });
+    }
+
+    let migration_changed = match old {
+        Some(old) => old.migration != new.migration,
+        None => {
+            new.migration.migration_in.is_some()
+                || new.migration.migration_out.is_some()
+        }
+    };
+    if migration_changed {
+        events.record(InstanceEventKind::MigrationChange {
+            gen: new.gen,
+            migration: new.migration.clone(),
+        });
     }
 }
@@ -2,36 +2,50 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::io;
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use propolis::hw::ps2::ctrl::PS2Ctrl;
 use propolis::hw::qemu::ramfb::{FrameSnap, RamFb};
+use propolis::hw::virtio::console::ConsolePort;
 
 use futures::StreamExt;
+use rfb::auth::{Security, VncPassword};
 use rfb::encodings::{EncodingType, RawEncoding};
 use rfb::proto::{
-    ClientMessage, FramebufferUpdate, FramebufferUpdateRequest, Position,
-    ProtoVersion, ProtocolError, Rectangle, Resolution, SecurityType,
-    SecurityTypes,
+    ClientMessage, ClientMessageDecoder, FramebufferUpdate,
+    FramebufferUpdateRequest, Position, ProtoVersion, ProtocolError, Rectangle,
+    Resolution, ServerCutText,
 };
+use rfb::server::RfbStream;
 use rgb_frame::{FourCC, Frame, Spec};
 use slog::{error, trace, Logger};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpListener;
-use tokio::sync::{oneshot, Notify};
+use tokio::sync::{broadcast, oneshot, Notify};
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
+use tokio_rustls::rustls::ServerConfig;
 use tokio_util::codec::FramedRead;
 
+use crate::api_auth::{
+    check_bearer_token, load_certs, load_private_key, read_secret,
+};
+use crate::clipboard;
+use crate::config;
+
 /// Arbitrary maximum valid resolution
 const MAX_RES: Resolution = Resolution { width: 1920, height: 1200 };
 const UNINIT_RES: Resolution = Resolution { width: 800, height: 600 };
 const UNINIT_FOURCC: FourCC = FourCC::XR24;
 const SERVER_NAME: &str = "propolis-vnc";
+/// Maximum number of simultaneously connected clients
+const MAX_CLIENTS: usize = 8;
 /// Frame interval (in us) for 10fps
 const FRAME_US_10FPS: usize = 1000000 / 10;
 
@@ -50,6 +64,10 @@ enum FrameKind {
 struct State {
     devices: Option<Devices>,
     is_stopped: bool,
+    /// The most recent capture of the framebuffer, shared by all clients
+    last_capture: Option<FrameSnap>,
+    /// Relay of clipboard text to and from the guest
+    clipboard: Option<clipboard::Bridge>,
 }
 
 struct ClientState {
@@ -57,28 +75,93 @@ struct ClientState {
     fbu_req: Option<FramebufferUpdateRequest>,
     encodings: BTreeSet<EncodingType>,
     output_fourcc: FourCC,
+    /// Discard input (key and pointer) events from this client
+    read_only: bool,
 }
-impl Default for ClientState {
-    fn default() -> Self {
+impl ClientState {
+    fn new(read_only: bool) -> Self {
         Self {
             last_snap: None,
             fbu_req: None,
             encodings: BTreeSet::new(),
             output_fourcc: UNINIT_FOURCC,
+            read_only,
         }
     }
 }
 
-#[derive(Default)]
 pub struct Client {
     hup: Option<oneshot::Sender<()>>,
-    id: Option<String>,
+    id: String,
+}
+
+#[derive(Default)]
+struct Clients {
+    next_key: u64,
+    active: BTreeMap<u64, Client>,
+}
+
+/// Authentication required of VNC clients
+#[derive(Clone, Default)]
+pub struct VncAuth {
+    /// Security required during the RFB handshake
+    pub security: Security,
+    /// Bearer token which clients of the `/instance/vnc` websocket must
+    /// present
+    pub token: Option<String>,
+}
+impl VncAuth {
+    /// Loads the password, TLS certificate, and token named by `cfg`.
+    pub fn load(cfg: &config::Vnc) -> io::Result<Self> {
+        let password = match &cfg.password_file {
+            Some(path) => Some(VncPassword::new(read_secret(path)?.as_bytes())),
+            None => None,
+        };
+        let security = match (&cfg.tls_cert, &cfg.tls_key) {
+            (Some(cert), Some(key)) => Security::VeNCrypt {
+                tls: load_tls_config(cert, key)?,
+                password,
+            },
+            (None, None) => match password {
+                Some(password) => Security::Vnc(password),
+                None => Security::None,
+            },
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "VNC TLS requires both a certificate and a key",
+                ));
+            }
+        };
+        let token = cfg.token_file.as_deref().map(read_secret).transpose()?;
+
+        Ok(Self { security, token })
+    }
+
+    /// Checks the `Authorization` header presented by a websocket client
+    /// against the required bearer token, if any.
+    pub fn check_token(&self, authorization: Option<&str>) -> bool {
+        check_bearer_token(self.token.as_deref(), authorization)
+    }
+}
+
+/// Builds a TLS configuration from the PEM-encoded certificate chain and
+/// private key at `cert` and `key`.
+fn load_tls_config(cert: &Path, key: &Path) -> io::Result<Arc<ServerConfig>> {
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert)?, load_private_key(key)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Arc::new(config))
 }
 
 pub struct VncServer {
     state: Mutex<State>,
-    client: Mutex<Client>,
+    clients: Mutex<Clients>,
     notify: Notify,
+    auth: VncAuth,
+    /// Clipboard text sent by the guest, for relay to all clients
+    cut_text: broadcast::Sender<String>,
     /// Minimum frame interval (in us)
     frame_int_us: usize,
     log: Logger,
@@ -92,6 +175,8 @@ pub enum ConnectError {
     InitError(#[from] rfb::server::InitError),
     #[error("VNC server is stopped")]
     ServerStopped,
+    #[error("VNC server already has {MAX_CLIENTS} clients")]
+    TooManyClients,
 }
 
 /// Alias trait to cut down on verbosity
@@ -103,13 +188,16 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Connection
 }
 impl Connection for tokio::net::TcpStream {}
 impl Connection for Box<dyn Connection> {}
+impl<T: Connection> Connection for RfbStream<T> {}
 
 impl VncServer {
-    pub fn new(log: Logger) -> Arc<Self> {
+    pub fn new(log: Logger, auth: VncAuth) -> Arc<Self> {
         Arc::new(Self {
             state: Mutex::new(State::default()),
-            client: Mutex::new(Client::default()),
+            clients: Mutex::new(Clients::default()),
             notify: Notify::new(),
+            auth,
+            cut_text: broadcast::channel(4).0,
             frame_int_us: FRAME_US_10FPS,
             log,
         })
@@ -118,10 +206,28 @@ impl VncServer {
         let mut state = self.state.lock().unwrap();
         state.devices = Some(Devices { keyboard: ps2, display: fb });
     }
+    /// Relays clipboard text between clients and the guest's clipboard
+    /// channel on `port`.
+    pub fn attach_clipboard(&self, port: Arc<ConsolePort>) {
+        let bridge = clipboard::Bridge::start(
+            port,
+            self.cut_text.clone(),
+            self.log.new(slog::o!("component" => "clipboard")),
+        );
+        self.state.lock().unwrap().clipboard = Some(bridge);
+    }
+    /// Authentication required of clients
+    pub fn auth(&self) -> &VncAuth {
+        &self.auth
+    }
+    /// Serves a new client on `conn`, alongside any already connected.  A
+    /// `read_only` client is sent the display, but not the guest's clipboard,
+    /// and its key and pointer events are discarded.
     pub async fn connect(
         self: &Arc<Self>,
-        mut conn: impl Connection,
+        conn: impl Connection,
         client_id: String,
+        read_only: bool,
     ) -> Result<(), ConnectError> {
         let (resolution, fourcc) = {
             let state = self.state.lock().unwrap();
@@ -140,16 +246,11 @@ impl VncServer {
             }
         };
 
-        let _client_init = rfb::server::initialize(
-            &mut conn,
+        let (conn, _client_init) = rfb::server::initialize(
+            conn,
             rfb::server::InitParams {
                 version: ProtoVersion::Rfb38,
-                // vncviewer won't work without offering VncAuth, even though it
-                // doesn't ask to use it.
-                sec_types: SecurityTypes(vec![
-                    SecurityType::None,
-                    SecurityType::VncAuthentication,
-                ]),
+                security: self.auth.security.clone(),
                 name: SERVER_NAME.to_string(),
                 resolution,
                 format: fourcc.into(),
@@ -157,47 +258,74 @@ impl VncServer {
         )
         .await?;
 
-        let hup_recv = self.replace_client(client_id).await;
+        let (key, hup_recv) = self.add_client(client_id, read_only)?;
+        // The guest's clipboard may hold anything it has copied, which is
+        // more than a read-only client is entitled to see.
+        let cut_text = (!read_only).then(|| self.cut_text.subscribe());
 
         let this = self.clone();
         tokio::spawn(async move {
-            if let Err(e) = this.run(conn, hup_recv).await {
+            if let Err(e) = this.run(conn, hup_recv, cut_text, read_only).await
+            {
                 error!(this.log, "VNC error, hanging up: {:?}", e);
             }
-            this.hup_client();
+            this.remove_client(key);
         });
 
         Ok(())
     }
 
-    async fn replace_client(&self, new_id: String) -> oneshot::Receiver<()> {
-        let mut client = self.wait_client_gone().await;
+    fn add_client(
+        &self,
+        id: String,
+        read_only: bool,
+    ) -> Result<(u64, oneshot::Receiver<()>), ConnectError> {
+        let mut clients = self.clients.lock().unwrap();
+        // Checked with the client list locked, so that a client cannot slip
+        // in after `stop()` has hung up on the others.
+        if self.state.lock().unwrap().is_stopped {
+            return Err(ConnectError::ServerStopped);
+        }
+        if clients.active.len() >= MAX_CLIENTS {
+            return Err(ConnectError::TooManyClients);
+        }
+
+        slog::info!(
+            self.log,
+            "VNC client connected";
+            "id" => &id,
+            "read_only" => read_only,
+            "clients" => clients.active.len() + 1,
+        );
 
         let (send, recv) = oneshot::channel();
-        client.id = Some(new_id);
-        client.hup = Some(send);
+        let key = clients.next_key;
+        clients.next_key += 1;
+        clients.active.insert(key, Client { hup: Some(send), id });
 
-        recv
+        Ok((key, recv))
     }
-    fn hup_client(&self) {
-        let mut client = self.client.lock().unwrap();
-        client.hup.take();
-        client.id.take();
+    fn remove_client(&self, key: u64) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.active.remove(&key) {
+            slog::info!(self.log, "VNC client disconnected"; "id" => client.id);
+        }
         self.notify.notify_one();
     }
-    async fn wait_client_gone(&self) -> MutexGuard<Client> {
+    async fn wait_clients_gone(&self) {
         loop {
             {
-                let mut client = self.client.lock().unwrap();
-                // tell any existing client to hang up
-                if let Some(hup) = client.hup.take() {
-                    let _ = hup.send(());
+                let mut clients = self.clients.lock().unwrap();
+                // tell all existing clients to hang up
+                for client in clients.active.values_mut() {
+                    if let Some(hup) = client.hup.take() {
+                        let _ = hup.send(());
+                    }
                 }
-                // and once it is gone, go on to install ourself as active
-                if client.id.is_none() {
-                    return client;
+                // and wait for them to be gone
+                if clients.active.is_empty() {
+                    return;
                 }
-                drop(client);
             }
 
             self.notify.notified().await;
@@ -208,10 +336,15 @@ impl VncServer {
         &self,
         conn: impl Connection,
         mut close_recv: oneshot::Receiver<()>,
+        mut cut_text: Option<broadcast::Receiver<String>>,
+        read_only: bool,
     ) -> Result<(), ProtocolError> {
-        let mut decoder =
-            FramedRead::new(conn, rfb::proto::ClientMessageDecoder::default());
-        let mut cstate: ClientState = Default::default();
+        // Leave room for the longest clipboard text accepted by the guest,
+        // along with the ClientCutText header.
+        let msg_decoder =
+            ClientMessageDecoder { buffer_limit: 8 + clipboard::MAX_TEXT_LEN };
+        let mut decoder = FramedRead::new(conn, msg_decoder);
+        let mut cstate = ClientState::new(read_only);
         loop {
             tokio::select! {
                 biased;
@@ -235,6 +368,21 @@ impl VncServer {
                 _ = self.wait_for_next_frame(&mut cstate) => {
                     self.send_fbu(decoder.get_mut(), &mut cstate).await?;
                 }
+                text = async { cut_text.as_mut().unwrap().recv().await },
+                    if cut_text.is_some() =>
+                {
+                    match text {
+                        Ok(text) => {
+                            let conn = decoder.get_mut();
+                            ServerCutText(text).write_to(conn).await?;
+                            conn.flush().await?;
+                        }
+                        // Falling behind is harmless, since only the most
+                        // recent text matters.  (The sender, held by the
+                        // server, is never closed while clients remain.)
+                        Err(_) => {}
+                    }
+                }
             }
         }
     }
@@ -247,18 +395,31 @@ impl VncServer {
     ) {
         match msg {
             ClientMessage::KeyEvent(ke) => {
-                let state = self.state.lock().unwrap();
                 trace!(self.log, "VNC key event: {:?}", ke);
+                if cstate.read_only {
+                    return;
+                }
+                let state = self.state.lock().unwrap();
                 if let Some(devs) = state.devices.as_ref() {
                     devs.keyboard.key_event(ke);
                 }
             }
             ClientMessage::PointerEvent(pe) => {
                 trace!(self.log, "VNC pointer event: {:?}", pe);
+                if cstate.read_only {
+                    return;
+                }
                 // TODO: wire to tablet device
             }
-            ClientMessage::ClientCutText(_) => {
-                trace!(self.log, "Ignoring VNC CutText request");
+            ClientMessage::ClientCutText(text) => {
+                trace!(self.log, "VNC cut text: {} bytes", text.len());
+                if cstate.read_only {
+                    return;
+                }
+                let state = self.state.lock().unwrap();
+                if let Some(clipboard) = state.clipboard.as_ref() {
+                    clipboard.send(text);
+                }
             }
             ClientMessage::FramebufferUpdateRequest(req) => {
                 cstate.fbu_req = Some(req);
@@ -318,14 +479,27 @@ impl VncServer {
         Ok(())
     }
 
-    fn update_frame(&self, cstate: &mut ClientState) -> bool {
-        let state = self.state.lock().unwrap();
+    /// Reads the framebuffer, unless it was already read (on behalf of another
+    /// client) within the last frame interval, in which case that capture is
+    /// reused.
+    fn capture(&self) -> Option<FrameSnap> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(snap) = state.last_capture.as_ref() {
+            if snap.when.elapsed().as_micros() < self.frame_int_us as u128 {
+                return Some(snap.clone());
+            }
+        }
 
-        if let Some(mut new_valid_frame) = state
+        let snap = state
             .devices
             .as_ref()
-            .and_then(|devs| devs.display.read_framebuffer(spec_valid))
-        {
+            .and_then(|devs| devs.display.read_framebuffer(spec_valid));
+        state.last_capture = snap.clone();
+        snap
+    }
+
+    fn update_frame(&self, cstate: &mut ClientState) -> bool {
+        if let Some(mut new_valid_frame) = self.capture() {
             new_valid_frame.frame.convert(cstate.output_fourcc);
             cstate.last_snap = Some((new_valid_frame, FrameKind::Valid));
             true
@@ -381,14 +555,46 @@ impl VncServer {
         }
     }
 
+    /// Captures the current contents of the display, without regard for (or
+    /// disturbing) any connected VNC client.  The frame is scaled down to fit
+    /// within `max_width` and `max_height`, if they are provided.
+    ///
+    /// Returns `None` if no display is attached.  If the guest has yet to
+    /// configure a valid framebuffer, the blank frame a VNC client would be
+    /// shown is returned instead.
+    pub fn screenshot(
+        &self,
+        max_width: Option<u16>,
+        max_height: Option<u16>,
+    ) -> Option<Frame> {
+        let snap = {
+            let state = self.state.lock().unwrap();
+            let devs = state.devices.as_ref()?;
+            devs.display
+                .read_framebuffer(spec_valid)
+                .unwrap_or_else(|| blank_frame(UNINIT_FOURCC))
+        };
+
+        let spec = snap.frame.spec();
+        let (width, height) =
+            fit_within(spec.width, spec.height, max_width, max_height);
+        if (width, height) == (spec.width, spec.height) {
+            Some(snap.frame)
+        } else {
+            Some(snap.frame.scaled(width, height))
+        }
+    }
+
     pub async fn stop(&self) {
         {
             let mut state = self.state.lock().unwrap();
             state.is_stopped = true;
             state.devices = None;
+            state.last_capture = None;
+            state.clipboard = None;
         }
 
-        let _client = self.wait_client_gone().await;
+        self.wait_clients_gone().await;
     }
 }
 
@@ -436,6 +642,7 @@ impl TcpSock {
                             let conn_res = vnc.connect(
                                 Box::new(sock) as Box<dyn Connection + 'static>,
                                 addr.to_string(),
+                                false,
                             )
                             .await;
                             if let Err(e) = conn_res {
@@ -474,3 +681,110 @@ fn spec_valid(spec: &Spec) -> bool {
     spec.width.get() < MAX_RES.width as usize
         && spec.height.get() < MAX_RES.height as usize
 }
+
+/// Compute the dimensions of a `width` by `height` frame, scaled down to fit
+/// within `max_width` and/or `max_height` while preserving its aspect ratio.
+fn fit_within(
+    width: NonZeroUsize,
+    height: NonZeroUsize,
+    max_width: Option<u16>,
+    max_height: Option<u16>,
+) -> (NonZeroUsize, NonZeroUsize) {
+    let (w, h) = (width.get() as f64, height.get() as f64);
+    let scale_w = max_width.map_or(1.0, |max| f64::from(max) / w);
+    let scale_h = max_height.map_or(1.0, |max| f64::from(max) / h);
+    let scale = scale_w.min(scale_h);
+    if scale >= 1.0 {
+        return (width, height);
+    }
+
+    let scale_dim = |dim: f64| {
+        let scaled = ((dim * scale).round() as usize).max(1);
+        NonZeroUsize::new(scaled).expect("scaled dimension is non-zero")
+    };
+    (scale_dim(w), scale_dim(h))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn dims(width: usize, height: usize) -> (NonZeroUsize, NonZeroUsize) {
+        (NonZeroUsize::new(width).unwrap(), NonZeroUsize::new(height).unwrap())
+    }
+
+    #[test]
+    fn screenshot_scaling() {
+        let (w, h) = dims(1024, 768);
+        assert_eq!(fit_within(w, h, None, None), dims(1024, 768));
+        assert_eq!(fit_within(w, h, Some(2048), None), dims(1024, 768));
+        assert_eq!(fit_within(w, h, Some(512), None), dims(512, 384));
+        assert_eq!(fit_within(w, h, None, Some(384)), dims(512, 384));
+        assert_eq!(fit_within(w, h, Some(512), Some(192)), dims(256, 192));
+        assert_eq!(fit_within(w, h, Some(0), None), dims(1, 1));
+    }
+
+    fn test_server() -> Arc<VncServer> {
+        VncServer::new(
+            Logger::root(slog::Discard, slog::o!()),
+            VncAuth::default(),
+        )
+    }
+
+    #[test]
+    fn bearer_token() {
+        let open = VncAuth::default();
+        assert!(open.check_token(None));
+        assert!(open.check_token(Some("Bearer whatever")));
+
+        let auth =
+            VncAuth { token: Some("t0ken".to_string()), ..Default::default() };
+        assert!(auth.check_token(Some("Bearer t0ken")));
+        assert!(!auth.check_token(None));
+        assert!(!auth.check_token(Some("t0ken")));
+        assert!(!auth.check_token(Some("Bearer t0ke")));
+        assert!(!auth.check_token(Some("Bearer t0ken2")));
+        assert!(!auth.check_token(Some("Basic t0ken")));
+    }
+
+    #[test]
+    fn client_limit() {
+        let vnc = test_server();
+        let mut keys = Vec::new();
+        for i in 0..MAX_CLIENTS {
+            let (key, _hup) =
+                vnc.add_client(i.to_string(), i % 2 == 0).unwrap();
+            keys.push(key);
+        }
+        assert!(matches!(
+            vnc.add_client("extra".to_string(), true),
+            Err(ConnectError::TooManyClients)
+        ));
+
+        vnc.remove_client(keys[0]);
+        assert!(vnc.add_client("extra".to_string(), true).is_ok());
+    }
+
+    #[tokio::test]
+    async fn stop_hangs_up_clients() {
+        let vnc = test_server();
+        let mut tasks = Vec::new();
+        for i in 0..2 {
+            let (key, hup) = vnc.add_client(i.to_string(), false).unwrap();
+            let vnc = vnc.clone();
+            tasks.push(tokio::spawn(async move {
+                let _ = hup.await;
+                vnc.remove_client(key);
+            }));
+        }
+
+        vnc.stop().await;
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert!(matches!(
+            vnc.add_client("late".to_string(), false),
+            Err(ConnectError::ServerStopped)
+        ));
+    }
+}
@@ -10,6 +10,7 @@ use std::sync::Arc;
 
 use propolis::usdt::register_probes;
 use propolis_server::{
+    api_auth::ApiAuth,
     config,
     server::{self, MetricsEndpointConfig},
     vnc,
@@ -17,7 +18,7 @@ use propolis_server::{
 
 use anyhow::{anyhow, Context};
 use clap::Parser;
-use dropshot::{ConfigDropshot, HandlerTaskMode, HttpServerStarter};
+use dropshot::{ConfigDropshot, ConfigTls, HandlerTaskMode, HttpServerStarter};
 use slog::{info, Logger};
 
 /// Threads to spawn for tokio runtime handling the API (dropshot, etc)
@@ -98,6 +99,27 @@ enum Args {
         #[clap(name = "VNC_IP:PORT", action)]
         vnc_addr: Option<SocketAddr>,
 
+        /// File holding the password VNC clients must supply (overrides the
+        /// `vnc.password_file` config option)
+        #[clap(long, action)]
+        vnc_password_file: Option<PathBuf>,
+
+        /// PEM certificate chain for VNC clients connecting via VeNCrypt
+        /// (overrides the `vnc.tls_cert` config option)
+        #[clap(long, action, requires = "vnc_tls_key")]
+        vnc_tls_cert: Option<PathBuf>,
+
+        /// PEM private key for the VeNCrypt certificate (overrides the
+        /// `vnc.tls_key` config option)
+        #[clap(long, action, requires = "vnc_tls_cert")]
+        vnc_tls_key: Option<PathBuf>,
+
+        /// File holding the bearer token required of `/instance/vnc`
+        /// websocket clients and `/instance/screenshot` requests (overrides
+        /// the `vnc.token_file` config option)
+        #[clap(long, action)]
+        vnc_token_file: Option<PathBuf>,
+
         /// Logging level for the server
         #[clap(long, default_value_t = slog::Level::Info, value_parser = parse_log_level)]
         log_level: slog::Level,
@@ -138,11 +160,28 @@ fn run_server(
 
     let use_reservoir = config::reservoir_decide(&log);
 
+    let vnc_auth = vnc::VncAuth::load(&config_app.vnc)
+        .context("loading VNC authentication")?;
+    let api_auth = ApiAuth::load(&config_app.api, &config_app.migration)
+        .context("loading API authentication")?;
+
+    // `ApiAuth::load` has already checked that the certificate and key are
+    // given together.
+    let config_tls = match (&config_app.api.tls_cert, &config_app.api.tls_key) {
+        (Some(cert_file), Some(key_file)) => Some(ConfigTls::AsFile {
+            cert_file: cert_file.clone(),
+            key_file: key_file.clone(),
+        }),
+        _ => None,
+    };
+
     let context = server::DropshotEndpointContext::new(
         config_app,
         use_reservoir,
         log.new(slog::o!()),
         config_metrics,
+        vnc_auth,
+        api_auth,
     );
 
     // Spawn the runtime for handling API processing
@@ -166,11 +205,12 @@ fn run_server(
 
     info!(log, "Starting server...");
 
-    let server = HttpServerStarter::new(
+    let server = HttpServerStarter::new_with_tls(
         &config_dropshot,
         server::api(),
         Arc::new(context),
         &log,
+        config_tls,
     )
     .map_err(|error| anyhow!("Failed to start server: {}", error))?
     .start();
@@ -272,8 +312,31 @@ fn main() -> anyhow::Result<()> {
     match args {
         Args::OpenApi => run_openapi()
             .map_err(|e| anyhow!("Cannot generate OpenAPI spec: {}", e)),
-        Args::Run { cfg, propolis_addr, metric_addr, vnc_addr, log_level } => {
-            let config = config::parse(cfg)?;
+        Args::Run {
+            cfg,
+            propolis_addr,
+            metric_addr,
+            vnc_addr,
+            vnc_password_file,
+            vnc_tls_cert,
+            vnc_tls_key,
+            vnc_token_file,
+            log_level,
+        } => {
+            let mut config = config::parse(cfg)?;
+
+            // VNC authentication given on the command line takes precedence
+            // over that in the config file.
+            if vnc_password_file.is_some() {
+                config.vnc.password_file = vnc_password_file;
+            }
+            if vnc_tls_cert.is_some() {
+                config.vnc.tls_cert = vnc_tls_cert;
+                config.vnc.tls_key = vnc_tls_key;
+            }
+            if vnc_token_file.is_some() {
+                config.vnc.token_file = vnc_token_file;
+            }
 
             // Dropshot configuration.
             let config_dropshot = ConfigDropshot {
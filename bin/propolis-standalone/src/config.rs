@@ -64,6 +64,12 @@ pub struct Main {
 
     /// Request bootrom override boot order using the devices specified
     pub boot_order: Option<Vec<String>>,
+
+    /// Record the exits taken by vCPUs, and how they were handled, to a trace
+    /// at this path, for later replay against device emulation.
+    ///
+    /// Default: None, exits are not recorded
+    pub exit_trace: Option<String>,
 }
 
 /// A hard-coded device, either enabled by default or accessible locally
@@ -22,6 +22,7 @@ use tokio::runtime;
 
 use propolis::chardev::{BlockingSource, Sink, Source, UDSock};
 use propolis::common::{GB, MB};
+use propolis::exit_trace::ExitRecorder;
 use propolis::firmware::smbios;
 use propolis::hw::chipset::{i440fx, Chipset};
 use propolis::hw::ps2::ctrl::PS2Ctrl;
@@ -256,6 +257,7 @@ struct InstInner {
     eq: Arc<EventQueue>,
     cv: Condvar,
     config: config::Config,
+    exit_trace: Option<ExitRecorder>,
 }
 
 struct Instance(Arc<InstInner>);
@@ -264,6 +266,7 @@ impl Instance {
         machine: propolis::Machine,
         config: config::Config,
         from_restore: bool,
+        exit_trace: Option<ExitRecorder>,
         log: slog::Logger,
     ) -> Self {
         let this = Self(Arc::new(InstInner {
@@ -278,6 +281,7 @@ impl Instance {
             eq: EventQueue::new(),
             cv: Condvar::new(),
             config,
+            exit_trace,
         }));
 
         // Some gymnastics required for the split borrow through the MutexGuard
@@ -606,7 +610,13 @@ impl Instance {
                 Ok(exit) => exit,
             };
 
-            entry = vcpu.process_vmexit(&exit).unwrap_or_else(|| {
+            let handled = vcpu.process_vmexit(&exit);
+            if let Some(trace) = inner.exit_trace.as_ref() {
+                if let Err(e) = trace.record(vcpu.id, &exit, handled.as_ref()) {
+                    slog::warn!(&log, "failed to record exit: {:?}", e);
+                }
+            }
+            entry = handled.unwrap_or_else(|| {
                 match exit.kind {
                     VmExitKind::Inout(pio) => {
                         slog::error!(
@@ -1031,8 +1041,20 @@ fn setup_instance(
         cpus, lowmem, highmem;);
     let machine = build_machine(vm_name, cpus, lowmem, highmem, use_reservoir)
         .context("Failed to create VM Machine")?;
-    let inst =
-        Instance::new(machine, config.clone(), from_restore, log.clone());
+    let exit_trace = config
+        .main
+        .exit_trace
+        .as_ref()
+        .map(ExitRecorder::create)
+        .transpose()
+        .context("Failed to create exit trace")?;
+    let inst = Instance::new(
+        machine,
+        config.clone(),
+        from_restore,
+        exit_trace,
+        log.clone(),
+    );
     slog::info!(log, "VM created"; "name" => vm_name);
 
     let (romfp, rom_len) =
@@ -1071,6 +1093,7 @@ fn setup_instance(
     let chipset_pm = i440fx::Piix3PM::create(
         machine.hdl.clone(),
         chipset_hb.power_pin(),
+        chipset_lpc.sci_pin(),
         log.new(slog::o!("device" => "piix3pm")),
     );
 
@@ -1177,10 +1200,38 @@ fn setup_instance(
                 "pci-virtio-viona" => {
                     let vnic_name =
                         dev.options.get("vnic").unwrap().as_str().unwrap();
+                    let mac_addr = dev
+                        .options
+                        .get("mac_address")
+                        .map(|v| {
+                            v.as_str()
+                                .and_then(hw::virtio::viona::parse_mac_addr)
+                                .context("invalid mac_address")
+                        })
+                        .transpose()?;
+                    let mtu = dev
+                        .options
+                        .get("mtu")
+                        .map(|v| {
+                            v.as_integer()
+                                .and_then(|n| u16::try_from(n).ok())
+                                .context("invalid mtu")
+                        })
+                        .transpose()?;
+                    let allow_promisc = dev
+                        .options
+                        .get("allow_promiscuous")
+                        .and_then(|v| v.as_bool())
+                        .unwrap_or(false);
                     let bdf = bdf.unwrap();
 
                     let viona = hw::virtio::PciVirtioViona::new(
-                        vnic_name, 0x100, &hdl,
+                        vnic_name,
+                        0x100,
+                        mac_addr,
+                        mtu,
+                        allow_promisc,
+                        &hdl,
                     )?;
                     guard.inventory.register_instance(&viona, &bdf.to_string());
                     chipset_pci_attach(bdf, viona);
@@ -1209,6 +1260,91 @@ fn setup_instance(
                     block::attach(nvme.clone(), backend).unwrap();
                     chipset_pci_attach(bdf, nvme);
                 }
+                "pci-virtio-9p" => {
+                    let opt_str = |key: &str| {
+                        dev.options
+                            .get(key)
+                            .and_then(|v| v.as_str())
+                            .map(str::to_string)
+                            .ok_or_else(|| {
+                                anyhow::anyhow!("missing p9fs option {key}")
+                            })
+                    };
+                    let source = opt_str("source")?;
+                    let target = opt_str("target")?;
+                    let chunk_size =
+                        dev.options
+                            .get("chunk_size")
+                            .and_then(|v| v.as_integer())
+                            .unwrap_or(65536) as u32;
+                    let read_only = dev
+                        .options
+                        .get("read_only")
+                        .and_then(|v| v.as_bool())
+                        .unwrap_or(false);
+                    let bdf = bdf.unwrap();
+
+                    let handler = hw::virtio::p9fs::HostFSHandler::new(
+                        source,
+                        target,
+                        chunk_size,
+                        read_only,
+                        log.new(slog::o!("dev" => "p9fs")),
+                    );
+                    let vio9p =
+                        hw::virtio::PciVirtio9pfs::new(0x40, Arc::new(handler));
+                    guard.inventory.register_instance(&vio9p, &bdf.to_string());
+                    chipset_pci_attach(bdf, vio9p);
+                }
+                "pci-virtio-fs" => {
+                    let opt_str = |key: &str| {
+                        dev.options
+                            .get(key)
+                            .and_then(|v| v.as_str())
+                            .ok_or_else(|| {
+                                anyhow::anyhow!(
+                                    "missing virtio-fs option {key}"
+                                )
+                            })
+                    };
+                    let source = opt_str("source")?;
+                    let tag = opt_str("tag")?;
+                    let cache =
+                        match dev.options.get("cache").and_then(|v| v.as_str())
+                        {
+                            None | Some("auto") => {
+                                hw::virtio::virtiofs::CachePolicy::Auto
+                            }
+                            Some("never") => {
+                                hw::virtio::virtiofs::CachePolicy::Never
+                            }
+                            Some("always") => {
+                                hw::virtio::virtiofs::CachePolicy::Always
+                            }
+                            Some(other) => {
+                                anyhow::bail!(
+                                    "invalid virtio-fs cache policy {other:?}"
+                                )
+                            }
+                        };
+                    let read_only = dev
+                        .options
+                        .get("read_only")
+                        .and_then(|v| v.as_bool())
+                        .unwrap_or(false);
+                    let bdf = bdf.unwrap();
+
+                    let vfs = hw::virtio::PciVirtioFs::new(
+                        0x100,
+                        tag,
+                        std::path::Path::new(source),
+                        cache,
+                        read_only,
+                        log.new(slog::o!("dev" => name.to_string())),
+                    )?;
+                    guard.inventory.register_instance(&vfs, &bdf.to_string());
+                    chipset_pci_attach(bdf, vfs);
+                }
                 qemu::pvpanic::DEVICE_NAME => {
                     let enable_isa = dev
                         .options
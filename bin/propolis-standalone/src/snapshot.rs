@@ -19,10 +19,7 @@ use std::path::Path;
 use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use bhyve_api::{
-    vdi_field_entry_v1, vdi_time_info_v1, ApiVersion, VAI_BOOT_HRTIME,
-    VDC_VMM_ARCH, VDC_VMM_TIME,
-};
+use bhyve_api::{vdi_field_entry_v1, ApiVersion, VAI_BOOT_HRTIME};
 use propolis::{
     chardev::UDSock,
     common::{GuestAddr, GuestRegion, GB},
@@ -441,12 +438,11 @@ pub struct VmGlobalState {
 
 fn export_global(hdl: &VmmHdl) -> io::Result<VmGlobalState> {
     if hdl.api_version()? > ApiVersion::V11 {
-        let info = hdl.data_op(VDC_VMM_TIME, 1).read::<vdi_time_info_v1>()?;
+        let info = hdl.read_time_data()?;
 
-        Ok(VmGlobalState { boot_hrtime: info.vt_boot_hrtime })
+        Ok(VmGlobalState { boot_hrtime: info.boot_hrtime })
     } else {
-        let arch_entries: Vec<bhyve_api::vdi_field_entry_v1> =
-            hdl.data_op(VDC_VMM_ARCH, 1).read_all()?;
+        let arch_entries = hdl.arch_data_read()?;
         let boot_ent = arch_entries
             .iter()
             .find(|ent| ent.vfe_ident == VAI_BOOT_HRTIME)
@@ -457,17 +453,16 @@ fn export_global(hdl: &VmmHdl) -> io::Result<VmGlobalState> {
 }
 fn import_global(hdl: &VmmHdl, state: &VmGlobalState) -> io::Result<()> {
     if hdl.api_version()? > ApiVersion::V11 {
-        let mut info =
-            hdl.data_op(VDC_VMM_TIME, 1).read::<vdi_time_info_v1>()?;
+        let mut info = hdl.read_time_data()?;
 
-        info.vt_boot_hrtime = state.boot_hrtime;
-        hdl.data_op(VDC_VMM_TIME, 1).write(&info)?;
+        info.boot_hrtime = state.boot_hrtime;
+        hdl.write_time_data(&info)?;
 
         Ok(())
     } else {
         let arch_entry =
             vdi_field_entry_v1::new(VAI_BOOT_HRTIME, state.boot_hrtime as u64);
-        hdl.data_op(VDC_VMM_ARCH, 1).write(&arch_entry)?;
+        hdl.arch_data_write(&[arch_entry])?;
         Ok(())
     }
 }
@@ -0,0 +1,557 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Inspect snapshot tarballs written by `propolis-standalone` without restoring
+//! them into a VM.
+//!
+//! The snapshot format is defined in `propolis-standalone/src/snapshot.rs`:
+//! - `config.toml`: VM configuration data
+//! - `global.json`: Global state data for the instance
+//! - `devices/*.json`: Exported state for each device
+//! - `memory/<start>-<end>.bin`: Raw memory covering guest-physical address
+//!   range [start, end), with those addresses formatted in hex.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use serde::Deserialize;
+use serde_json::Value;
+
+const DEVICE_DIR: &str = "devices";
+const MEMORY_DIR: &str = "memory";
+const CONFIG_NAME: &str = "config.toml";
+const GLOBAL_NAME: &str = "global.json";
+
+const MB: u64 = 1024 * 1024;
+const GB: u64 = 1024 * MB;
+
+/// Guest-physical address at which memory above the low-memory limit is
+/// mapped by `propolis-standalone`.
+const HIGHMEM_START: u64 = 4 * GB;
+
+/// Maximum amount of memory `propolis-standalone` maps below 4 GiB.
+const LOWMEM_LIMIT: u64 = 3 * GB;
+
+#[derive(clap::Parser, Debug)]
+struct Opts {
+    #[clap(subcommand)]
+    cmd: Command,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// List devices (with payload kinds and versions) and memory segments
+    List {
+        /// Snapshot file
+        snapshot: PathBuf,
+    },
+    /// Pretty-print the state of one or all devices
+    Show {
+        /// Snapshot file
+        snapshot: PathBuf,
+        /// Name of the device to print (all devices if omitted)
+        device: Option<String>,
+    },
+    /// Compare the device state in two snapshots
+    Diff {
+        /// First snapshot file
+        a: PathBuf,
+        /// Second snapshot file
+        b: PathBuf,
+    },
+    /// Check that memory segments are well-formed and cover guest RAM
+    CheckMem {
+        /// Snapshot file
+        snapshot: PathBuf,
+    },
+    /// Extract a range of guest-physical memory to a file
+    Extract {
+        /// Snapshot file
+        snapshot: PathBuf,
+        /// Starting guest-physical address (hex with 0x prefix, or decimal)
+        #[clap(value_parser = parse_num)]
+        start: u64,
+        /// Length of the range in bytes (hex with 0x prefix, or decimal)
+        #[clap(value_parser = parse_num)]
+        len: u64,
+        /// Output file
+        #[clap(short, long)]
+        output: PathBuf,
+    },
+}
+
+fn parse_num(s: &str) -> Result<u64, std::num::ParseIntError> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
+/// Device state as written by `propolis-standalone`.
+#[derive(Deserialize)]
+struct SnapshotDevice {
+    instance_name: String,
+    payload: Vec<SnapshotDevicePayload>,
+}
+
+#[derive(Deserialize)]
+struct SnapshotDevicePayload {
+    kind: String,
+    version: u32,
+    data: String,
+}
+
+impl SnapshotDevicePayload {
+    /// Parses the payload's data, which is itself serialized as JSON.
+    fn value(&self) -> anyhow::Result<Value> {
+        serde_json::from_str(&self.data).with_context(|| {
+            format!("could not parse {} v{} payload", self.kind, self.version)
+        })
+    }
+}
+
+/// The subset of the `propolis-standalone` config needed to determine the
+/// expected guest memory layout.
+#[derive(Deserialize)]
+struct SnapshotConfig {
+    main: SnapshotConfigMain,
+}
+
+#[derive(Deserialize)]
+struct SnapshotConfigMain {
+    /// Guest memory size (MiB)
+    memory: u64,
+}
+
+/// A memory segment stored in the snapshot.
+struct MemSegment {
+    /// First guest-physical address covered by this segment.
+    start: u64,
+    /// Guest-physical address immediately past the end of this segment.
+    end: u64,
+    /// Size of the tar entry holding the segment's data.
+    size: u64,
+    /// Offset of the segment's data in the snapshot file.
+    file_off: u64,
+}
+
+/// The contents of a snapshot, less the raw memory data.
+struct Snapshot {
+    path: PathBuf,
+    config: Option<String>,
+    global: Option<Value>,
+    devices: BTreeMap<String, SnapshotDevice>,
+    memory: Vec<MemSegment>,
+}
+
+impl Snapshot {
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| {
+            format!("failed to open snapshot {}", path.display())
+        })?;
+        let mut archive = tar::Archive::new(file);
+
+        let mut snapshot = Snapshot {
+            path: path.to_owned(),
+            config: None,
+            global: None,
+            devices: BTreeMap::new(),
+            memory: Vec::new(),
+        };
+
+        for ent in archive.entries_with_seek()? {
+            let mut ent = ent?;
+            if !ent.header().entry_type().is_file() {
+                continue;
+            }
+            let ent_path = ent.path()?.into_owned();
+            let mut parts = ent_path.components();
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(name), None, None)
+                    if (name.as_ref() as &Path) == Path::new(CONFIG_NAME) =>
+                {
+                    let mut config = String::new();
+                    ent.read_to_string(&mut config)
+                        .context("config should be valid utf-8")?;
+                    snapshot.config = Some(config);
+                }
+                (Some(name), None, None)
+                    if (name.as_ref() as &Path) == Path::new(GLOBAL_NAME) =>
+                {
+                    snapshot.global = Some(
+                        serde_json::from_reader(&mut ent)
+                            .context("could not parse global data")?,
+                    );
+                }
+                (Some(dir), Some(name), None)
+                    if (dir.as_ref() as &Path) == Path::new(DEVICE_DIR) =>
+                {
+                    let dev: SnapshotDevice = serde_json::from_reader(&mut ent)
+                        .with_context(|| {
+                            format!("could not parse device file {name:?}")
+                        })?;
+                    snapshot.devices.insert(dev.instance_name.clone(), dev);
+                }
+                (Some(dir), Some(name), None)
+                    if (dir.as_ref() as &Path) == Path::new(MEMORY_DIR) =>
+                {
+                    let name = name.as_os_str().to_string_lossy();
+                    let (start, end) = parse_mem_name(&name)?;
+                    snapshot.memory.push(MemSegment {
+                        start,
+                        end,
+                        size: ent.size(),
+                        file_off: ent.raw_file_position(),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        snapshot.memory.sort_by_key(|seg| seg.start);
+        Ok(snapshot)
+    }
+
+    /// Yields the expected guest memory regions, as `[start, end)` pairs,
+    /// derived from the snapshot's config.
+    fn expected_memory(&self) -> anyhow::Result<Vec<(u64, u64)>> {
+        let Some(config) = self.config.as_ref() else {
+            bail!("snapshot has no {CONFIG_NAME}");
+        };
+        let config: SnapshotConfig =
+            toml::from_str(config).context("could not parse config")?;
+
+        let memsize = config.main.memory * MB;
+        let lowmem = memsize.min(LOWMEM_LIMIT);
+        let highmem = memsize.saturating_sub(LOWMEM_LIMIT);
+
+        let mut regions = vec![(0, lowmem)];
+        if highmem > 0 {
+            regions.push((HIGHMEM_START, HIGHMEM_START + highmem));
+        }
+        Ok(regions)
+    }
+}
+
+fn parse_mem_name(name: &str) -> anyhow::Result<(u64, u64)> {
+    if let Some(addrs) = name.strip_suffix(".bin") {
+        let mut fields = addrs.split('-');
+        if let (Some(start), Some(end), None) =
+            (fields.next(), fields.next(), fields.next())
+        {
+            let start = u64::from_str_radix(start, 16)?;
+            let end = u64::from_str_radix(end, 16)?;
+            if start >= end {
+                bail!("bad memory bounds {start} {end}");
+            }
+            return Ok((start, end));
+        }
+        bail!("could not parse bounds of memory file {name}");
+    } else {
+        bail!("memory file '{name}' does not end with .bin")
+    }
+}
+
+fn cmd_list(snapshot: &Snapshot) {
+    println!("Devices:");
+    for (name, dev) in snapshot.devices.iter() {
+        println!("  {name}");
+        for payload in dev.payload.iter() {
+            println!(
+                "    {} v{} ({} bytes)",
+                payload.kind,
+                payload.version,
+                payload.data.len()
+            );
+        }
+    }
+
+    println!("Memory:");
+    for seg in snapshot.memory.iter() {
+        println!(
+            "  {:#012x}-{:#012x} ({} MiB)",
+            seg.start,
+            seg.end,
+            (seg.end - seg.start) / MB
+        );
+    }
+}
+
+fn cmd_show(snapshot: &Snapshot, device: Option<&str>) -> anyhow::Result<()> {
+    let devices: Vec<&SnapshotDevice> = match device {
+        Some(name) => vec![snapshot.devices.get(name).ok_or_else(|| {
+            anyhow::anyhow!("no device named {name} in snapshot")
+        })?],
+        None => {
+            if let Some(global) = snapshot.global.as_ref() {
+                println!("{GLOBAL_NAME}:");
+                println!("{}", serde_json::to_string_pretty(global)?);
+            }
+            snapshot.devices.values().collect()
+        }
+    };
+
+    for dev in devices {
+        for payload in dev.payload.iter() {
+            println!(
+                "{} [{} v{}]:",
+                dev.instance_name, payload.kind, payload.version
+            );
+            println!("{}", serde_json::to_string_pretty(&payload.value()?)?);
+        }
+    }
+
+    Ok(())
+}
+
+/// Appends the JSON pointer of each point at which `a` and `b` differ to
+/// `diffs`, along with the differing values.
+fn diff_values(
+    path: &str,
+    a: Option<&Value>,
+    b: Option<&Value>,
+    diffs: &mut Vec<(String, Option<Value>, Option<Value>)>,
+) {
+    match (a, b) {
+        (Some(Value::Object(a)), Some(Value::Object(b))) => {
+            let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                diff_values(
+                    &format!("{path}/{key}"),
+                    a.get(key),
+                    b.get(key),
+                    diffs,
+                );
+            }
+        }
+        (Some(Value::Array(a)), Some(Value::Array(b))) => {
+            for i in 0..a.len().max(b.len()) {
+                diff_values(&format!("{path}/{i}"), a.get(i), b.get(i), diffs);
+            }
+        }
+        (a, b) if a != b => {
+            diffs.push((path.to_owned(), a.cloned(), b.cloned()));
+        }
+        _ => {}
+    }
+}
+
+fn cmd_diff(a: &Snapshot, b: &Snapshot) -> anyhow::Result<bool> {
+    let show = |v: &Option<Value>| match v {
+        Some(v) => v.to_string(),
+        None => "<absent>".to_string(),
+    };
+
+    let mut names: Vec<&String> =
+        a.devices.keys().chain(b.devices.keys()).collect();
+    names.sort();
+    names.dedup();
+
+    let mut differs = false;
+    for name in names {
+        let (dev_a, dev_b) = match (a.devices.get(name), b.devices.get(name)) {
+            (Some(dev_a), Some(dev_b)) => (dev_a, dev_b),
+            (Some(_), None) => {
+                println!("- {name}: only in {}", a.path.display());
+                differs = true;
+                continue;
+            }
+            (None, Some(_)) => {
+                println!("+ {name}: only in {}", b.path.display());
+                differs = true;
+                continue;
+            }
+            (None, None) => unreachable!(),
+        };
+
+        let mut kinds: Vec<&String> = dev_a
+            .payload
+            .iter()
+            .chain(dev_b.payload.iter())
+            .map(|p| &p.kind)
+            .collect();
+        kinds.sort();
+        kinds.dedup();
+
+        for kind in kinds {
+            let pa = dev_a.payload.iter().find(|p| &p.kind == kind);
+            let pb = dev_b.payload.iter().find(|p| &p.kind == kind);
+            let (pa, pb) = match (pa, pb) {
+                (Some(pa), Some(pb)) => (pa, pb),
+                (Some(pa), None) => {
+                    println!("- {name} [{kind} v{}]", pa.version);
+                    differs = true;
+                    continue;
+                }
+                (None, Some(pb)) => {
+                    println!("+ {name} [{kind} v{}]", pb.version);
+                    differs = true;
+                    continue;
+                }
+                (None, None) => unreachable!(),
+            };
+
+            if pa.version != pb.version {
+                println!(
+                    "! {name} [{kind}]: version v{} != v{}",
+                    pa.version, pb.version
+                );
+                differs = true;
+                continue;
+            }
+
+            let mut diffs = Vec::new();
+            diff_values("", Some(&pa.value()?), Some(&pb.value()?), &mut diffs);
+            for (path, va, vb) in diffs {
+                println!(
+                    "! {name} [{kind} v{}] {path}: {} != {}",
+                    pa.version,
+                    show(&va),
+                    show(&vb)
+                );
+                differs = true;
+            }
+        }
+    }
+
+    if a.global != b.global {
+        println!("! {GLOBAL_NAME}: {} != {}", show(&a.global), show(&b.global));
+        differs = true;
+    }
+
+    Ok(differs)
+}
+
+fn cmd_check_mem(snapshot: &Snapshot) -> anyhow::Result<bool> {
+    let mut ok = true;
+
+    for seg in snapshot.memory.iter() {
+        if seg.size != seg.end - seg.start {
+            println!(
+                "segment {:#x}-{:#x}: data is {} bytes, expected {}",
+                seg.start,
+                seg.end,
+                seg.size,
+                seg.end - seg.start
+            );
+            ok = false;
+        }
+    }
+
+    for pair in snapshot.memory.windows(2) {
+        if pair[1].start < pair[0].end {
+            println!(
+                "segments {:#x}-{:#x} and {:#x}-{:#x} overlap",
+                pair[0].start, pair[0].end, pair[1].start, pair[1].end
+            );
+            ok = false;
+        }
+    }
+
+    for (start, end) in snapshot.expected_memory()? {
+        // Segments are sorted by start address, so walk them to find any gaps
+        // in this region.
+        let mut covered = start;
+        for seg in snapshot.memory.iter() {
+            if seg.end <= covered || seg.start >= end {
+                continue;
+            }
+            if seg.start > covered {
+                println!(
+                    "gap in guest memory at {covered:#x}-{:#x}",
+                    seg.start
+                );
+                ok = false;
+            }
+            covered = covered.max(seg.end);
+        }
+        if covered < end {
+            println!("gap in guest memory at {covered:#x}-{end:#x}");
+            ok = false;
+        }
+    }
+
+    if ok {
+        println!("memory segments OK");
+    }
+    Ok(ok)
+}
+
+fn cmd_extract(
+    snapshot: &Snapshot,
+    start: u64,
+    len: u64,
+    output: &Path,
+) -> anyhow::Result<()> {
+    let end = start
+        .checked_add(len)
+        .ok_or_else(|| anyhow::anyhow!("range end overflows"))?;
+
+    let mut input = File::open(&snapshot.path)?;
+    let mut out = File::create(output).with_context(|| {
+        format!("failed to create output file {}", output.display())
+    })?;
+
+    let mut cur = start;
+    while cur < end {
+        let seg = snapshot
+            .memory
+            .iter()
+            .find(|seg| seg.start <= cur && cur < seg.end)
+            .ok_or_else(|| {
+                anyhow::anyhow!("address {cur:#x} not present in snapshot")
+            })?;
+
+        let chunk_end = end.min(seg.end);
+        input.seek(SeekFrom::Start(seg.file_off + (cur - seg.start)))?;
+        let copied =
+            io::copy(&mut (&mut input).take(chunk_end - cur), &mut out)?;
+        if copied != chunk_end - cur {
+            bail!(
+                "snapshot truncated in segment {:#x}-{:#x}",
+                seg.start,
+                seg.end
+            );
+        }
+        cur = chunk_end;
+    }
+
+    out.flush()?;
+    println!("wrote {len} bytes to {}", output.display());
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    let opts = Opts::parse();
+
+    match opts.cmd {
+        Command::List { snapshot } => {
+            cmd_list(&Snapshot::open(&snapshot)?);
+        }
+        Command::Show { snapshot, device } => {
+            cmd_show(&Snapshot::open(&snapshot)?, device.as_deref())?;
+        }
+        Command::Diff { a, b } => {
+            if cmd_diff(&Snapshot::open(&a)?, &Snapshot::open(&b)?)? {
+                std::process::exit(1);
+            }
+        }
+        Command::CheckMem { snapshot } => {
+            if !cmd_check_mem(&Snapshot::open(&snapshot)?)? {
+                std::process::exit(1);
+            }
+        }
+        Command::Extract { snapshot, start, len, output } => {
+            cmd_extract(&Snapshot::open(&snapshot)?, start, len, &output)?;
+        }
+    }
+
+    Ok(())
+}
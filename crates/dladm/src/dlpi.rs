@@ -0,0 +1,115 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Minimal libdlpi bindings for observing the traffic on a datalink.
+
+use std::ffi::{CStr, CString};
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+use crate::sys;
+
+/// A DLPI handle to a datalink, opened in raw mode so that received messages
+/// carry their full link-layer header.
+pub struct Dlpi {
+    inner: sys::dlpi_handle_t,
+}
+impl Dlpi {
+    /// Opens `link` and binds the handle to every SAP, such that it receives
+    /// all of the link's traffic once it is made promiscuous.
+    pub fn open_raw(link: &str) -> Result<Self> {
+        let name = CString::new(link)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let mut hdl: sys::dlpi_handle_t = std::ptr::null_mut();
+        Self::handle_dlpi_err(unsafe {
+            sys::dlpi_open(name.as_ptr(), &mut hdl, sys::DLPI_RAW)
+        })?;
+        let this = Self { inner: hdl };
+        Self::handle_dlpi_err(unsafe {
+            sys::dlpi_bind(this.inner, sys::DLPI_ANY_SAP, std::ptr::null_mut())
+        })?;
+        Ok(this)
+    }
+
+    /// Enables promiscuous reception of traffic at the given level.
+    pub fn promisc_on(&self, level: Promisc) -> Result<()> {
+        Self::handle_dlpi_err(unsafe {
+            sys::dlpi_promiscon(self.inner, level as u32)
+        })
+    }
+
+    /// Receives a message into `buf`, waiting for up to `timeout` for one to
+    /// arrive.
+    ///
+    /// Returns `None` if the wait timed out, or the number of bytes placed in
+    /// `buf` and the total length of the message, which may be larger if the
+    /// message was truncated to fit.
+    pub fn recv(
+        &self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<(usize, usize)>> {
+        let mut len = buf.len();
+        let mut info = sys::dlpi_recvinfo_t {
+            dri_destaddr: [0; sys::DLPI_PHYSADDR_MAX],
+            dri_destaddrlen: 0,
+            dri_destaddrtype: 0,
+            dri_totmsglen: 0,
+        };
+        let msec = timeout.as_millis().try_into().unwrap_or(i32::MAX);
+        let res = unsafe {
+            sys::dlpi_recv(
+                self.inner,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+                buf.as_mut_ptr().cast(),
+                &mut len,
+                msec,
+                &mut info,
+            )
+        };
+        match res {
+            sys::DLPI_ETIMEDOUT => Ok(None),
+            res => {
+                Self::handle_dlpi_err(res)?;
+                Ok(Some((len, info.dri_totmsglen.max(len))))
+            }
+        }
+    }
+
+    fn handle_dlpi_err(v: i32) -> Result<()> {
+        match v {
+            sys::DLPI_SUCCESS => Ok(()),
+            sys::DL_SYSERR => Err(Error::last_os_error()),
+            e => {
+                let msg = unsafe { CStr::from_ptr(sys::dlpi_strerror(e)) };
+                Err(Error::new(
+                    ErrorKind::Other,
+                    msg.to_string_lossy().into_owned(),
+                ))
+            }
+        }
+    }
+}
+impl Drop for Dlpi {
+    fn drop(&mut self) {
+        unsafe { sys::dlpi_close(self.inner) }
+        self.inner = std::ptr::null_mut();
+    }
+}
+// Safety: a DLPI handle is not tied to the thread which opened it, and all
+// operations on it are made through `&self` by a single owner at a time.
+unsafe impl Send for Dlpi {}
+
+/// Promiscuous reception levels
+#[derive(Copy, Clone, Debug)]
+#[repr(u32)]
+pub enum Promisc {
+    /// All traffic on the link, regardless of destination address
+    Phys = sys::DL_PROMISC_PHYS,
+    /// Traffic for all SAPs
+    Sap = sys::DL_PROMISC_SAP,
+    /// All multicast traffic
+    Multi = sys::DL_PROMISC_MULTI,
+}
@@ -9,6 +9,8 @@ use std::process::{Command, Stdio};
 #[allow(non_camel_case_types)]
 mod sys;
 
+pub mod dlpi;
+
 use libc::c_void;
 use sys::{datalink_class, dladm_handle_t, dladm_status};
 
@@ -121,6 +123,29 @@ impl Handle {
         mac.copy_from_slice(&addr[..]);
         Ok(())
     }
+    /// Sets the MAC address of the VNIC `name`.  The change is temporary,
+    /// lasting until the VNIC is next brought up from its persistent
+    /// configuration.
+    pub fn set_vnic_mac(
+        &self,
+        name: &str,
+        mac: &[u8; ETHERADDRL],
+    ) -> Result<()> {
+        // dladm modify-vnic -t -m 2:8:20:2d:e9:24 <VNIC_NAME>
+        let addr =
+            mac.iter().map(|b| format!("{b:x}")).collect::<Vec<_>>().join(":");
+        let status = Command::new("dladm")
+            .args(["modify-vnic", "-t", "-m", &addr])
+            .arg(name)
+            .stderr(Stdio::null())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(Error::new(ErrorKind::Other, "failed dladm"));
+        }
+        Ok(())
+    }
     fn get_misc_mac(
         &self,
         linkid: sys::datalink_id_t,
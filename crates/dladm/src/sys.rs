@@ -67,6 +67,79 @@ mod compat {
 #[cfg(not(target_os = "illumos"))]
 pub use compat::*;
 
+#[cfg(target_os = "illumos")]
+#[link(name = "dlpi")]
+extern "C" {
+    pub fn dlpi_open(
+        linkname: *const c_char,
+        dhp: *mut dlpi_handle_t,
+        flags: c_uint,
+    ) -> c_int;
+    pub fn dlpi_close(dh: dlpi_handle_t);
+    pub fn dlpi_bind(
+        dh: dlpi_handle_t,
+        sap: c_uint,
+        boundsap: *mut c_uint,
+    ) -> c_int;
+    pub fn dlpi_promiscon(dh: dlpi_handle_t, level: c_uint) -> c_int;
+    pub fn dlpi_recv(
+        dh: dlpi_handle_t,
+        saddrp: *mut c_void,
+        saddrlenp: *mut usize,
+        msgbuf: *mut c_void,
+        msglenp: *mut usize,
+        msec: c_int,
+        recvp: *mut dlpi_recvinfo_t,
+    ) -> c_int;
+    pub fn dlpi_strerror(err: c_int) -> *const c_char;
+}
+
+#[cfg(not(target_os = "illumos"))]
+mod dlpi_compat {
+    #![allow(unused)]
+    use super::*;
+
+    pub unsafe extern "C" fn dlpi_open(
+        linkname: *const c_char,
+        dhp: *mut dlpi_handle_t,
+        flags: c_uint,
+    ) -> c_int {
+        panic!("illumos only");
+    }
+    pub unsafe extern "C" fn dlpi_close(dh: dlpi_handle_t) {
+        panic!("illumos only");
+    }
+    pub unsafe extern "C" fn dlpi_bind(
+        dh: dlpi_handle_t,
+        sap: c_uint,
+        boundsap: *mut c_uint,
+    ) -> c_int {
+        panic!("illumos only");
+    }
+    pub unsafe extern "C" fn dlpi_promiscon(
+        dh: dlpi_handle_t,
+        level: c_uint,
+    ) -> c_int {
+        panic!("illumos only");
+    }
+    pub unsafe extern "C" fn dlpi_recv(
+        dh: dlpi_handle_t,
+        saddrp: *mut c_void,
+        saddrlenp: *mut usize,
+        msgbuf: *mut c_void,
+        msglenp: *mut usize,
+        msec: c_int,
+        recvp: *mut dlpi_recvinfo_t,
+    ) -> c_int {
+        panic!("illumos only");
+    }
+    pub unsafe extern "C" fn dlpi_strerror(err: c_int) -> *const c_char {
+        panic!("illumos only");
+    }
+}
+#[cfg(not(target_os = "illumos"))]
+pub use dlpi_compat::*;
+
 /* opaque dladm handle to libdladm functions */
 pub enum dladm_handle {}
 pub type dladm_handle_t = *mut dladm_handle;
@@ -89,6 +162,34 @@ pub enum boolean_t {
 const MAXMACADDRLEN: usize = 20;
 const MAXNAMELEN: usize = 256;
 
+/* opaque dlpi handle to libdlpi functions */
+pub enum dlpi_impl {}
+pub type dlpi_handle_t = *mut dlpi_impl;
+#[repr(C)]
+pub struct dlpi_recvinfo_t {
+    pub dri_destaddr: [c_uchar; DLPI_PHYSADDR_MAX],
+    pub dri_destaddrlen: c_uchar,
+    pub dri_destaddrtype: c_int,
+    pub dri_totmsglen: usize,
+}
+
+pub const DLPI_PHYSADDR_MAX: usize = 64;
+
+/* dlpi_open() flags */
+pub const DLPI_RAW: c_uint = 0x0004;
+
+pub const DLPI_ANY_SAP: c_uint = c_uint::MAX;
+
+/* promiscuous levels, from <sys/dlpi.h> */
+pub const DL_PROMISC_PHYS: c_uint = 0x01;
+pub const DL_PROMISC_SAP: c_uint = 0x02;
+pub const DL_PROMISC_MULTI: c_uint = 0x03;
+
+/* libdlpi return values, other than the DLPI primitive errors below them */
+pub const DLPI_SUCCESS: c_int = 10000;
+pub const DLPI_ETIMEDOUT: c_int = 10006;
+pub const DL_SYSERR: c_int = 0x04;
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, FromRepr)]
 #[repr(i32)]
 pub enum datalink_class {
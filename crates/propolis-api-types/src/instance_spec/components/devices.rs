@@ -98,6 +98,23 @@ pub struct VirtioNic {
 
     /// The PCI path at which to attach this device.
     pub pci_path: PciPath,
+
+    /// The MAC address to present to the guest, as six colon-separated
+    /// hexadecimal octets. Defaults to the backend's MAC address. A differing
+    /// address is assigned to the backend, which must then be a VNIC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac_address: Option<String>,
+
+    /// The MTU to present to the guest, which may not exceed the backend's.
+    /// Defaults to the backend's MTU.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<u16>,
+
+    /// If true, the guest may make the NIC promiscuous, either outright or by
+    /// asking for unicast traffic addressed to MACs other than its own.
+    /// Otherwise the device refuses such requests.
+    #[serde(default)]
+    pub allow_promiscuous: bool,
 }
 
 impl MigrationElement for VirtioNic {
@@ -112,6 +129,38 @@ impl MigrationElement for VirtioNic {
     {
         backend_name_matches(&self.backend_name, &other.backend_name)?;
         pci_path_matches(&self.pci_path, &other.pci_path)?;
+        // Addresses are compared by value, since they may be written in
+        // either case and with or without leading zeroes.
+        let mac_addr = |mac: &Option<String>| {
+            mac.as_deref().map(|s| propolis_types::parse_mac_addr(s).ok_or(s))
+        };
+        if mac_addr(&self.mac_address) != mac_addr(&other.mac_address) {
+            return Err(MigrationCompatibilityError::ComponentConfiguration(
+                format!(
+                    "NIC MAC address mismatch (self: {0:?}, other: {1:?})",
+                    self.mac_address, other.mac_address
+                ),
+            )
+            .into());
+        }
+        if self.mtu != other.mtu {
+            return Err(MigrationCompatibilityError::ComponentConfiguration(
+                format!(
+                    "NIC MTU mismatch (self: {0:?}, other: {1:?})",
+                    self.mtu, other.mtu
+                ),
+            )
+            .into());
+        }
+        if self.allow_promiscuous != other.allow_promiscuous {
+            return Err(MigrationCompatibilityError::ComponentConfiguration(
+                format!(
+                    "NIC allow_promiscuous mismatch (self: {0}, other: {1})",
+                    self.allow_promiscuous, other.allow_promiscuous
+                ),
+            )
+            .into());
+        }
         Ok(())
     }
 }
@@ -119,7 +168,17 @@ impl MigrationElement for VirtioNic {
 /// A serial port identifier, which determines what I/O ports a guest can use to
 /// access a port.
 #[derive(
-    Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema,
+    Clone,
+    Copy,
+    Deserialize,
+    Serialize,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    JsonSchema,
 )]
 #[serde(deny_unknown_fields, rename_all = "snake_case")]
 pub enum SerialPortNumber {
@@ -254,6 +313,174 @@ impl MigrationElement for Option<QemuPvpanic> {
     }
 }
 
+/// A virtio-9p device that shares a directory on the host with the guest.
+/// This device doesn't support live migration.
+#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct P9fs {
+    /// The host source path to mount into the guest.
+    pub source: String,
+
+    /// The 9P target filesystem tag.
+    pub target: String,
+
+    /// The chunk size to use in the 9P protocol. Vanilla Helios images should
+    /// use 8192. Falcon Helios base images and Linux can use up to 65536.
+    pub chunk_size: u32,
+
+    /// The PCI path at which to attach the guest to this P9 filesystem.
+    pub pci_path: PciPath,
+
+    /// If true, the guest may not modify the shared directory.
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// How long a virtio-fs guest may cache file data, attributes, and names.
+#[derive(
+    Clone,
+    Copy,
+    Deserialize,
+    Serialize,
+    Debug,
+    PartialEq,
+    Eq,
+    JsonSchema,
+    Default,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum VirtioFsCachePolicy {
+    /// Nothing is cached. Use this if the host modifies the shared directory
+    /// while the guest is using it.
+    Never,
+
+    /// Attributes and names are cached briefly and file data is cached while
+    /// a file is open.
+    #[default]
+    Auto,
+
+    /// Everything is cached indefinitely. Use this only if the guest has
+    /// exclusive use of the shared directory.
+    Always,
+}
+
+/// A virtio-fs device that shares a directory on the host with the guest.
+/// This device doesn't support live migration.
+#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioFs {
+    /// The host directory to share with the guest.
+    pub source: String,
+
+    /// The tag the guest uses to mount the filesystem. At most 36 bytes.
+    pub tag: String,
+
+    /// The guest's caching policy.
+    #[serde(default)]
+    pub cache: VirtioFsCachePolicy,
+
+    /// If true, the guest may not modify the shared directory.
+    #[serde(default)]
+    pub read_only: bool,
+
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+}
+
+/// A virtio-console device through which clipboard text is exchanged between
+/// the guest and VNC clients. The guest finds it as the virtio-serial port
+/// named `org.oxide.clipboard.0`.
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioClipboard {
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+}
+
+/// Where the host end of a virtio-serial port leads.
+#[derive(
+    Clone, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema, Default,
+)]
+#[serde(
+    deny_unknown_fields,
+    rename_all = "snake_case",
+    tag = "type",
+    content = "value"
+)]
+pub enum VirtioSerialPortBackend {
+    /// The port's recent output is kept in memory, and clients connect to it
+    /// through the `/instance/virtio-serial/{device}/{port}` websocket.
+    #[default]
+    Buffer,
+
+    /// As with `buffer`, but the port's output is also appended to the file
+    /// at this path.
+    File(String),
+
+    /// The port is connected to a Unix domain socket bound at this path,
+    /// which accepts one client at a time.
+    UnixSocket(String),
+}
+
+/// A named port on a virtio-serial device.
+#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioSerialPort {
+    /// The port's name, by which the guest finds it, e.g.
+    /// `org.qemu.guest_agent.0`.
+    pub name: String,
+
+    /// The host end of the port.
+    #[serde(default)]
+    pub backend: VirtioSerialPortBackend,
+}
+
+/// A virtio-console device with a set of named virtio-serial ports.
+#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VirtioSerial {
+    /// The device's ports. Port names must be unique, and there may be at
+    /// most 31 ports.
+    pub ports: Vec<VirtioSerialPort>,
+
+    /// The PCI path at which to attach this device.
+    pub pci_path: PciPath,
+}
+
+/// The serial channel over which the server talks to a qemu-guest-agent in the
+/// guest. The channel is reserved for the agent, and so isn't reachable
+/// through the server's serial console websockets.
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(
+    deny_unknown_fields,
+    rename_all = "snake_case",
+    tag = "type",
+    content = "value"
+)]
+pub enum GuestAgentChannel {
+    /// One of the spec's COM ports.
+    SerialPort(SerialPortNumber),
+
+    /// A port on one of the spec's virtio-serial devices. The port must use
+    /// the `buffer` backend.
+    VirtioSerial {
+        /// The name of the virtio-serial device.
+        device: String,
+
+        /// The name of the port on that device.
+        port: String,
+    },
+}
+
+/// A qemu-guest-agent in the guest, through which the server can ask the
+/// guest to freeze its filesystems, run commands, and so on.
+#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct GuestAgent {
+    /// The channel over which the agent is reached.
+    pub channel: GuestAgentChannel,
+}
+
 //
 // Structs for Falcon devices. These devices don't support live migration.
 //
@@ -285,24 +512,6 @@ pub struct SoftNpuP9 {
     pub pci_path: PciPath,
 }
 
-#[cfg(feature = "falcon")]
-#[derive(Clone, Deserialize, Serialize, Debug, JsonSchema)]
-#[serde(deny_unknown_fields)]
-pub struct P9fs {
-    /// The host source path to mount into the guest.
-    pub source: String,
-
-    /// The 9P target filesystem tag.
-    pub target: String,
-
-    /// The chunk size to use in the 9P protocol. Vanilla Helios images should
-    /// use 8192. Falcon Helios base images and Linux can use up to 65536.
-    pub chunk_size: u32,
-
-    /// The PCI path at which to attach the guest to this P9 filesystem.
-    pub pci_path: PciPath,
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
@@ -362,8 +571,22 @@ mod test {
         let d1 = VirtioNic {
             backend_name: "storage_backend".to_string(),
             pci_path: PciPath::new(0, 5, 0).unwrap(),
+            mac_address: None,
+            mtu: None,
+            allow_promiscuous: false,
         };
         assert!(d1.can_migrate_from_element(&d1).is_ok());
+
+        // The same MAC address may be written differently.
+        let d1 = VirtioNic {
+            mac_address: Some("02:08:20:ab:cd:0f".to_string()),
+            ..d1
+        };
+        let d2 = VirtioNic {
+            mac_address: Some("02:08:20:AB:CD:F".to_string()),
+            ..d1.clone()
+        };
+        assert!(d1.can_migrate_from_element(&d2).is_ok());
     }
 
     #[test]
@@ -371,9 +594,15 @@ mod test {
         let d1 = VirtioNic {
             backend_name: "storage_backend".to_string(),
             pci_path: PciPath::new(0, 5, 0).unwrap(),
+            mac_address: None,
+            mtu: None,
+            allow_promiscuous: false,
         };
 
-        let d2 = VirtioNic { backend_name: "other_backend".to_string(), ..d1 };
+        let d2 = VirtioNic {
+            backend_name: "other_backend".to_string(),
+            ..d1.clone()
+        };
         assert!(d1.can_migrate_from_element(&d2).is_err());
 
         let d2 = VirtioNic {
@@ -381,6 +610,18 @@ mod test {
             ..d1.clone()
         };
         assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = VirtioNic {
+            mac_address: Some("02:08:20:ab:cd:ef".to_string()),
+            ..d1.clone()
+        };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = VirtioNic { mtu: Some(9000), ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
+
+        let d2 = VirtioNic { allow_promiscuous: true, ..d1.clone() };
+        assert!(d1.can_migrate_from_element(&d2).is_err());
     }
 
     #[test]
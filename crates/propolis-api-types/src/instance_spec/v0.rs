@@ -109,14 +109,36 @@ pub struct DeviceSpecV0 {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub qemu_pvpanic: Option<components::devices::QemuPvpanic>,
 
+    // As with `qemu_pvpanic`, this field is optional for compatibility with
+    // Propolis versions that don't support it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p9fs: Option<components::devices::P9fs>,
+
+    // Likewise, virtio-fs devices are omitted when there are none.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub virtio_fs: HashMap<SpecKey, components::devices::VirtioFs>,
+
+    // As with `p9fs`, this field is optional for compatibility with Propolis
+    // versions that don't support it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clipboard: Option<components::devices::VirtioClipboard>,
+
+    // As with `virtio_fs`, virtio-serial devices are omitted when there are
+    // none.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub virtio_serial: HashMap<SpecKey, components::devices::VirtioSerial>,
+
+    // As with `clipboard`, this field is optional for compatibility with
+    // Propolis versions that don't support it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_agent: Option<components::devices::GuestAgent>,
+
     #[cfg(feature = "falcon")]
     pub softnpu_pci_port: Option<components::devices::SoftNpuPciPort>,
     #[cfg(feature = "falcon")]
     pub softnpu_ports: HashMap<SpecKey, components::devices::SoftNpuPort>,
     #[cfg(feature = "falcon")]
     pub softnpu_p9: Option<components::devices::SoftNpuP9>,
-    #[cfg(feature = "falcon")]
-    pub p9fs: Option<components::devices::P9fs>,
 }
 
 impl DeviceSpecV0 {
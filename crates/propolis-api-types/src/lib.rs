@@ -6,6 +6,7 @@
 
 use std::{fmt, net::SocketAddr};
 
+use chrono::{DateTime, Utc};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -63,6 +64,11 @@ pub struct InstanceSpecEnsureRequest {
     pub properties: InstanceProperties,
     pub instance_spec: VersionedInstanceSpec,
     pub migrate: Option<InstanceMigrateInitiateRequest>,
+
+    /// If set, initializes the instance from a previously saved state file
+    /// instead of booting it from scratch. Mutually exclusive with `migrate`.
+    #[serde(default)]
+    pub restore: Option<InstanceRestoreRequest>,
 }
 
 #[derive(Clone, Deserialize, Serialize, JsonSchema)]
@@ -82,6 +88,26 @@ pub struct InstanceMigrateInitiateResponse {
     pub migration_id: Uuid,
 }
 
+/// A request to save an instance's state to a file on the server's host.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceSaveRequest {
+    /// The path of the file to write. Any existing file at this path is
+    /// replaced once the save completes.
+    pub path: String,
+
+    /// If true, the instance stops after its state is saved. Otherwise it
+    /// resumes running.
+    #[serde(default)]
+    pub stop_after_save: bool,
+}
+
+/// Identifies a saved state file from which to initialize a new instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceRestoreRequest {
+    /// The path of a file written by a previous save request.
+    pub path: String,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
 pub struct InstanceMigrateStartRequest {
     pub migration_id: Uuid,
@@ -169,6 +195,89 @@ pub struct InstanceStateMonitorResponse {
     pub migration: InstanceMigrateStatusResponse,
 }
 
+/// Connect to a stream of an Instance's events via websocket, optionally
+/// replaying recent events first.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct InstanceEventStreamRequest {
+    /// The sequence number of the first event to send. If this is not
+    /// provided, only events recorded after the connection is made are sent.
+    pub from_seq: Option<u64>,
+    /// The stream to which `from_seq` belongs. If this is provided and doesn't
+    /// match the server's current stream, all of the current stream's retained
+    /// events are sent instead.
+    pub stream_id: Option<Uuid>,
+}
+
+/// A message sent as JSON text by the `/instance/events` websocket.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(tag = "message", rename_all = "snake_case")]
+pub enum InstanceEventMessage {
+    /// An event in the life of the Instance.
+    Event(InstanceEvent),
+    /// Some of the requested events can't be sent, either because they have
+    /// been discarded or because they belong to a different stream. Sent
+    /// before any replayed events.
+    Gap(InstanceEventGap),
+}
+
+/// Where the events following a gap in the `/instance/events` websocket
+/// resume.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct InstanceEventGap {
+    /// The stream of the events which follow.
+    pub stream_id: Uuid,
+    /// The sequence number of the first event which follows.
+    pub next_seq: u64,
+}
+
+/// An event in the life of an Instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct InstanceEvent {
+    /// The stream to which the event belongs. Each server process starts a new
+    /// stream, whose sequence numbers start again from zero, so a client
+    /// resuming from a sequence number must also check that it comes from the
+    /// same stream.
+    pub stream_id: Uuid,
+    /// The event's sequence number, which increases by one with each event
+    /// recorded in the stream.
+    pub seq: u64,
+    /// The time at which the server recorded the event.
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: InstanceEventKind,
+}
+
+/// What happened to an Instance.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InstanceEventKind {
+    /// The Instance moved to a new state. `gen` is the generation number
+    /// `/instance/state-monitor` reports for the change.
+    StateChange { gen: u64, state: InstanceState },
+    /// A migration into or out of the Instance moved to a new phase.
+    MigrationChange { gen: u64, migration: InstanceMigrateStatusResponse },
+    /// The guest reported a kernel panic through its pvpanic device.
+    GuestPanic { host_handled: bool, guest_handled: bool },
+    /// The guest was reset.
+    Reboot { cause: RebootCause },
+    /// A device failed, and needs to be reset by the guest.
+    DeviceError { device: String, message: String },
+}
+
+/// The reason an Instance's guest was reset.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RebootCause {
+    /// The VM entered its reset state at the request of a vCPU.
+    VcpuReset,
+    /// A vCPU triple-faulted.
+    TripleFault { vcpu: i32 },
+    /// The guest reset the chipset.
+    ChipsetReset,
+    /// A reboot was requested through the API.
+    Requested,
+}
+
 /// Requested state of an Instance.
 #[derive(Clone, Copy, Deserialize, Serialize, JsonSchema)]
 pub struct InstanceStateChange {
@@ -180,6 +289,9 @@ pub enum InstanceStateRequested {
     Run,
     Stop,
     Reboot,
+    // Presses the guest's ACPI power button and waits for it to power off,
+    // stopping the instance forcibly if it doesn't do so in time.
+    SoftStop,
 }
 
 /// Current state of an Instance.
@@ -251,6 +363,9 @@ pub struct InstanceSerialConsoleHistoryRequest {
     /// range runs to the end of the available buffer, the data returned will be shorter than
     /// `max_bytes`.
     pub max_bytes: Option<u64>,
+    /// The serial port whose history to read. Defaults to COM1.
+    #[serde(default)]
+    pub port: Option<instance_spec::components::devices::SerialPortNumber>,
 }
 
 /// Contents of an Instance's serial console buffer.
@@ -269,14 +384,64 @@ pub struct InstanceSerialConsoleHistoryResponse {
 #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq)]
 pub struct InstanceSerialConsoleStreamRequest {
     /// Character index in the serial buffer from which to read, counting the bytes output since
-    /// instance start. If this is provided, `most_recent` must *not* be provided.
-    // TODO: if neither is specified, send enough serial buffer history to reconstruct
-    //  the current contents and cursor state of an interactive terminal
+    /// instance start. If this is provided, `most_recent` must *not* be provided. If neither is
+    /// provided, the server instead sends VT100 commands that redraw the current contents and
+    /// cursor state of the terminal the console is driving.
     pub from_start: Option<u64>,
     /// Character index in the serial buffer from which to read, counting *backward* from the most
     /// recently buffered data retrieved from the instance. (See note on `from_start` about mutual
     /// exclusivity)
     pub most_recent: Option<u64>,
+    /// The serial port to connect to. Defaults to COM1.
+    #[serde(default)]
+    pub port: Option<instance_spec::components::devices::SerialPortNumber>,
+}
+
+/// Identifies a port on one of an Instance's virtio-serial devices.
+#[derive(Deserialize, JsonSchema)]
+pub struct VirtioSerialPathParams {
+    /// The name of the virtio-serial device in the instance spec.
+    pub device: String,
+    /// The name of the port on that device.
+    pub port: String,
+}
+
+/// Connect to a virtio-serial port via websocket, optionally sending bytes
+/// from the buffered history first.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct InstanceVirtioSerialStreamRequest {
+    /// Character index in the port's buffer from which to read, counting the
+    /// bytes output since instance start. If this is provided, `most_recent`
+    /// must *not* be provided. If neither is provided, only output produced
+    /// after the connection is made is sent.
+    pub from_start: Option<u64>,
+    /// Character index in the port's buffer from which to read, counting
+    /// *backward* from the most recently buffered data retrieved from the
+    /// instance. (See note on `from_start` about mutual exclusivity)
+    pub most_recent: Option<u64>,
+}
+
+/// Connect to an Instance's display via a websocket carrying the VNC (RFB)
+/// protocol.  Any number of clients may be connected at once, up to a limit.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct InstanceVncRequest {
+    /// If set, the client is shown the display, but not the guest's
+    /// clipboard, and its keyboard and pointer input is ignored.
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// Request a screenshot of an Instance's display.
+///
+/// The screenshot is scaled down, preserving its aspect ratio, to fit within
+/// whichever of `max_width` and `max_height` are provided. It is never scaled
+/// up.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct InstanceScreenshotRequest {
+    /// Maximum width (in pixels) of the screenshot.
+    pub max_width: Option<u16>,
+    /// Maximum height (in pixels) of the screenshot.
+    pub max_height: Option<u16>,
 }
 
 /// Control message(s) sent through the websocket to serial console clients.
@@ -432,6 +597,145 @@ pub struct VolumeStatus {
     pub active: bool,
 }
 
+#[derive(Deserialize, JsonSchema)]
+pub struct NicPathParams {
+    /// The name of the network device in the instance spec.
+    pub name: String,
+}
+
+/// A request to change the link state a network device presents to the guest.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct NicLinkStateRequest {
+    /// True to report the link as up, false to report it as down.
+    pub link_up: bool,
+}
+
+/// A request to start capturing a network device's packets.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+pub struct NicCaptureStartRequest {
+    /// The maximum size of the capture file, in bytes. Defaults to 4 MiB, and
+    /// may be at most 64 MiB.
+    pub max_bytes: Option<u64>,
+
+    /// The maximum number of packets to capture. Unlimited by default.
+    pub max_packets: Option<u64>,
+
+    /// The number of bytes of each packet to capture, from 1 to 65535.
+    /// Defaults to 65535.
+    pub snaplen: Option<u32>,
+}
+
+/// The progress of a network device's most recent packet capture.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct NicCaptureStatus {
+    /// True if packets are still being captured.
+    pub running: bool,
+
+    /// The number of packets captured.
+    pub packets: u64,
+
+    /// The size of the capture file, in bytes.
+    pub bytes: u64,
+
+    /// True if the capture stopped because it reached its byte or packet
+    /// limit.
+    pub limit_reached: bool,
+
+    /// The error that stopped the capture, if there was one.
+    pub error: Option<String>,
+}
+
+/// The result of freezing or thawing the guest's filesystems.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestAgentFsFreezeResponse {
+    /// The number of filesystems frozen or thawed.
+    pub filesystems: u32,
+}
+
+/// A network interface in the guest, as reported by the guest agent.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestNetworkInterface {
+    /// The interface's name in the guest.
+    pub name: String,
+
+    /// The interface's MAC address, if it has one.
+    pub hardware_address: Option<String>,
+
+    /// The addresses assigned to the interface.
+    pub ip_addresses: Vec<GuestIpAddress>,
+}
+
+/// An IP address assigned to a guest network interface.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestIpAddress {
+    /// The address itself.
+    pub address: std::net::IpAddr,
+
+    /// The length of the address's network prefix, in bits.
+    pub prefix: u8,
+}
+
+/// A request to run a program in the guest.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestAgentExecRequest {
+    /// The path of the program to run.
+    pub path: String,
+
+    /// The program's arguments, not including its name.
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    /// Data to send to the program's standard input.
+    #[serde(default)]
+    pub input: Vec<u8>,
+
+    /// How long to wait for the program to exit, in seconds. Defaults to 30.
+    pub timeout_secs: Option<u64>,
+}
+
+/// The outcome of a program run in the guest.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestAgentExecResponse {
+    /// The program's exit code, if it exited normally.
+    pub exit_code: Option<i32>,
+
+    /// The signal that terminated the program, if one did.
+    pub signal: Option<i32>,
+
+    /// The program's standard output.
+    pub stdout: Vec<u8>,
+
+    /// The program's standard error.
+    pub stderr: Vec<u8>,
+
+    /// True if the guest agent truncated the program's output.
+    pub truncated: bool,
+}
+
+/// Identifies a file in the guest.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestAgentFileRequest {
+    /// The file's path in the guest.
+    pub path: String,
+}
+
+/// The contents of a file in the guest.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestAgentFileContents {
+    /// The file's contents.
+    pub data: Vec<u8>,
+}
+
+/// A request to write a file in the guest, replacing any existing contents.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct GuestAgentFileWriteRequest {
+    /// The file's path in the guest.
+    pub path: String,
+
+    /// The file's new contents.
+    pub data: Vec<u8>,
+}
+
 /// Error codes used to populate the `error_code` field of Dropshot API responses.
 #[derive(
     Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize, JsonSchema,
@@ -9,7 +9,10 @@ use std::collections::BTreeSet;
 use propolis_api_types::instance_spec::{
     components::{
         board::Board,
-        devices::{PciPciBridge, QemuPvpanic, SerialPort, SerialPortNumber},
+        devices::{
+            GuestAgent, P9fs, PciPciBridge, QemuPvpanic, SerialPort,
+            SerialPortNumber, VirtioClipboard, VirtioFs, VirtioSerial,
+        },
     },
     v0::{DeviceSpecV0, InstanceSpecV0, NetworkDeviceV0, StorageDeviceV0},
     PciPath,
@@ -20,17 +23,17 @@ use thiserror::Error;
 use propolis_api_types::instance_spec::{
     components::{
         backends::DlpiNetworkBackend,
-        devices::{P9fs, SoftNpuP9, SoftNpuPciPort, SoftNpuPort},
+        devices::{SoftNpuP9, SoftNpuPciPort, SoftNpuPort},
     },
     v0::NetworkBackendV0,
 };
 
-use super::{ParsedNetworkDevice, ParsedStorageDevice};
+use crate::{ParsedNetworkDevice, ParsedStorageDevice};
 
 /// Errors that can arise while building an instance spec from component parts.
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, Error)]
-pub(crate) enum SpecBuilderError {
+pub enum SpecBuilderError {
     #[error("A device with name {0} already exists")]
     DeviceNameInUse(String),
 
@@ -48,7 +51,7 @@ pub(crate) enum SpecBuilderError {
     SoftNpuPortInUse(String),
 }
 
-pub(crate) struct SpecBuilder {
+pub struct SpecBuilder {
     spec: InstanceSpecV0,
     pci_paths: BTreeSet<PciPath>,
 }
@@ -75,7 +78,7 @@ impl PciComponent for NetworkDeviceV0 {
 }
 
 impl SpecBuilder {
-    pub(super) fn new(board: Board) -> Self {
+    pub fn new(board: Board) -> Self {
         Self {
             spec: InstanceSpecV0 {
                 devices: DeviceSpecV0 { board, ..Default::default() },
@@ -100,7 +103,7 @@ impl SpecBuilder {
     }
 
     /// Adds a storage device with an associated backend.
-    pub(super) fn add_storage_device(
+    pub fn add_storage_device(
         &mut self,
         ParsedStorageDevice {
             device_name,
@@ -132,7 +135,7 @@ impl SpecBuilder {
     }
 
     /// Adds a network device with an associated backend.
-    pub(super) fn add_network_device(
+    pub fn add_network_device(
         &mut self,
         ParsedNetworkDevice {
             device_name,
@@ -224,6 +227,79 @@ impl SpecBuilder {
         Ok(self)
     }
 
+    pub fn set_p9fs(&mut self, p9fs: P9fs) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.p9fs.is_some() {
+            return Err(SpecBuilderError::DeviceNameInUse("p9fs".to_string()));
+        }
+
+        self.register_pci_device(p9fs.pci_path)?;
+        self.spec.devices.p9fs = Some(p9fs);
+        Ok(self)
+    }
+
+    /// Adds a virtio-fs shared directory device.
+    pub fn add_virtio_fs(
+        &mut self,
+        device_name: String,
+        fs: VirtioFs,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.virtio_fs.contains_key(&device_name) {
+            return Err(SpecBuilderError::DeviceNameInUse(device_name));
+        }
+
+        self.register_pci_device(fs.pci_path)?;
+        let _old = self.spec.devices.virtio_fs.insert(device_name, fs);
+        assert!(_old.is_none());
+        Ok(self)
+    }
+
+    /// Adds the clipboard channel device.
+    pub fn set_clipboard(
+        &mut self,
+        clipboard: VirtioClipboard,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.clipboard.is_some() {
+            return Err(SpecBuilderError::DeviceNameInUse(
+                "clipboard".to_string(),
+            ));
+        }
+
+        self.register_pci_device(clipboard.pci_path)?;
+        self.spec.devices.clipboard = Some(clipboard);
+        Ok(self)
+    }
+
+    /// Adds a virtio-serial device with a set of named ports.
+    pub fn add_virtio_serial(
+        &mut self,
+        device_name: String,
+        serial: VirtioSerial,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.virtio_serial.contains_key(&device_name) {
+            return Err(SpecBuilderError::DeviceNameInUse(device_name));
+        }
+
+        self.register_pci_device(serial.pci_path)?;
+        let _old = self.spec.devices.virtio_serial.insert(device_name, serial);
+        assert!(_old.is_none());
+        Ok(self)
+    }
+
+    /// Designates the channel over which the guest agent is reached.
+    pub fn set_guest_agent(
+        &mut self,
+        agent: GuestAgent,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.guest_agent.is_some() {
+            return Err(SpecBuilderError::DeviceNameInUse(
+                "guest agent".to_string(),
+            ));
+        }
+
+        self.spec.devices.guest_agent = Some(agent);
+        Ok(self)
+    }
+
     #[cfg(feature = "falcon")]
     pub fn set_softnpu_pci_port(
         &mut self,
@@ -244,13 +320,6 @@ impl SpecBuilder {
         Ok(self)
     }
 
-    #[cfg(feature = "falcon")]
-    pub fn set_p9fs(&mut self, p9fs: P9fs) -> Result<&Self, SpecBuilderError> {
-        self.register_pci_device(p9fs.pci_path)?;
-        self.spec.devices.p9fs = Some(p9fs);
-        Ok(self)
-    }
-
     #[cfg(feature = "falcon")]
     pub fn add_softnpu_port(
         &mut self,
@@ -9,7 +9,12 @@ use std::str::{FromStr, ParseBoolError};
 use propolis_api_types::instance_spec::{
     components::{
         backends::{FileStorageBackend, VirtioNetworkBackend},
-        devices::{NvmeDisk, PciPciBridge, VirtioDisk, VirtioNic},
+        devices::{
+            GuestAgent, GuestAgentChannel, NvmeDisk, P9fs, PciPciBridge,
+            SerialPortNumber, VirtioClipboard, VirtioDisk, VirtioFs,
+            VirtioFsCachePolicy, VirtioNic, VirtioSerial, VirtioSerialPort,
+            VirtioSerialPortBackend,
+        },
     },
     v0::{
         NetworkBackendV0, NetworkDeviceV0, StorageBackendV0, StorageDeviceV0,
@@ -20,15 +25,15 @@ use thiserror::Error;
 
 #[cfg(feature = "falcon")]
 use propolis_api_types::instance_spec::components::devices::{
-    P9fs, SoftNpuP9, SoftNpuPciPort, SoftNpuPort,
+    SoftNpuP9, SoftNpuPciPort, SoftNpuPort,
 };
 
-use crate::config;
+use propolis_server_config as config;
 
-use super::{ParsedNetworkDevice, ParsedStorageDevice};
+use crate::{ParsedNetworkDevice, ParsedStorageDevice};
 
 #[derive(Debug, Error)]
-pub(crate) enum ConfigTomlError {
+pub enum ConfigTomlError {
     #[error("unrecognized device type {0:?}")]
     UnrecognizedDeviceType(String),
 
@@ -62,13 +67,44 @@ pub(crate) enum ConfigTomlError {
     #[error("failed to get VNIC name for device {0:?}")]
     NoVnicName(String),
 
-    #[cfg(feature = "falcon")]
+    #[error("invalid MAC address for network device {0:?}")]
+    InvalidMacAddress(String),
+
+    #[error("invalid MTU for network device {0:?}")]
+    InvalidMtu(String),
+
+    #[error("failed to parse promiscuity option for network device {0:?}")]
+    NicPromiscuousParseFailed(String, #[source] ParseBoolError),
+
     #[error("failed to get source for p9 device {0:?}")]
     NoP9Source(String),
 
-    #[cfg(feature = "falcon")]
-    #[error("failed to get source for p9 device {0:?}")]
+    #[error("failed to get target for p9 device {0:?}")]
     NoP9Target(String),
+
+    #[error("failed to parse read-only option for p9 device {0:?}")]
+    P9ReadonlyParseFailed(String, #[source] ParseBoolError),
+
+    #[error("failed to get source for virtio-fs device {0:?}")]
+    NoVirtioFsSource(String),
+
+    #[error("failed to get tag for virtio-fs device {0:?}")]
+    NoVirtioFsTag(String),
+
+    #[error("invalid cache policy {policy:?} for virtio-fs device {name:?}")]
+    InvalidVirtioFsCachePolicy { policy: String, name: String },
+
+    #[error("failed to parse read-only option for virtio-fs device {0:?}")]
+    VirtioFsReadonlyParseFailed(String, #[source] ParseBoolError),
+
+    #[error("failed to get ports for virtio-serial device {0:?}")]
+    NoVirtioSerialPorts(String),
+
+    #[error("invalid port {port} for virtio-serial device {name:?}")]
+    InvalidVirtioSerialPort { port: usize, name: String },
+
+    #[error("invalid channel for guest agent {0:?}")]
+    InvalidGuestAgentChannel(String),
 }
 
 #[cfg(feature = "falcon")]
@@ -77,7 +113,6 @@ pub(super) struct ParsedSoftNpu {
     pub(super) pci_ports: Vec<SoftNpuPciPort>,
     pub(super) ports: Vec<SoftNpuPort>,
     pub(super) p9_devices: Vec<SoftNpuP9>,
-    pub(super) p9fs: Vec<P9fs>,
 }
 
 #[derive(Default)]
@@ -85,6 +120,11 @@ pub(super) struct ParsedConfig {
     pub(super) disks: Vec<ParsedStorageDevice>,
     pub(super) nics: Vec<ParsedNetworkDevice>,
     pub(super) pci_bridges: Vec<ParsedPciPciBridge>,
+    pub(super) p9fs: Vec<P9fs>,
+    pub(super) virtio_fs: Vec<ParsedVirtioFs>,
+    pub(super) clipboard: Vec<VirtioClipboard>,
+    pub(super) virtio_serial: Vec<ParsedVirtioSerial>,
+    pub(super) guest_agent: Option<GuestAgent>,
 
     #[cfg(feature = "falcon")]
     pub(super) softnpu: ParsedSoftNpu,
@@ -139,6 +179,41 @@ impl TryFrom<&config::Config> for ParsedConfig {
                         device,
                     )?);
                 }
+                "pci-virtio-9p" => {
+                    parsed
+                        .p9fs
+                        .push(parse_p9fs_from_config(device_name, device)?);
+                }
+                "pci-virtio-fs" => {
+                    parsed.virtio_fs.push(ParsedVirtioFs {
+                        name: device_name.to_owned(),
+                        fs: parse_virtio_fs_from_config(device_name, device)?,
+                    });
+                }
+                "pci-virtio-clipboard" => {
+                    let pci_path: PciPath =
+                        device.get("pci-path").ok_or_else(|| {
+                            ConfigTomlError::InvalidPciPath(
+                                device_name.to_owned(),
+                            )
+                        })?;
+                    parsed.clipboard.push(VirtioClipboard { pci_path });
+                }
+                "pci-virtio-serial" => {
+                    parsed.virtio_serial.push(ParsedVirtioSerial {
+                        name: device_name.to_owned(),
+                        serial: parse_virtio_serial_from_config(
+                            device_name,
+                            device,
+                        )?,
+                    });
+                }
+                "qemu-guest-agent" => {
+                    parsed.guest_agent = Some(parse_guest_agent_from_config(
+                        device_name,
+                        device,
+                    )?);
+                }
                 #[cfg(feature = "falcon")]
                 "softnpu-pci-port" => {
                     parsed.softnpu.pci_ports.push(
@@ -161,13 +236,6 @@ impl TryFrom<&config::Config> for ParsedConfig {
                         parse_softnpu_p9_from_config(device_name, device)?,
                     );
                 }
-                #[cfg(feature = "falcon")]
-                "pci-virtio-9p" => {
-                    parsed
-                        .softnpu
-                        .p9fs
-                        .push(parse_p9fs_from_config(device_name, device)?);
-                }
                 _ => {
                     return Err(ConfigTomlError::UnrecognizedDeviceType(
                         driver.to_owned(),
@@ -289,9 +357,43 @@ pub(super) fn parse_network_device_from_config(
         vnic_name: vnic_name.to_owned(),
     });
 
+    let mac_address = match device.options.get("mac_address") {
+        None => None,
+        Some(value) => Some(
+            value
+                .as_str()
+                .filter(|s| propolis_types::parse_mac_addr(s).is_some())
+                .ok_or_else(|| {
+                    ConfigTomlError::InvalidMacAddress(name.to_owned())
+                })?
+                .to_owned(),
+        ),
+    };
+
+    let mtu = match device.options.get("mtu") {
+        None => None,
+        Some(value) => Some(
+            value
+                .as_integer()
+                .and_then(|n| u16::try_from(n).ok())
+                .ok_or_else(|| ConfigTomlError::InvalidMtu(name.to_owned()))?,
+        ),
+    };
+
+    let allow_promiscuous = match device.options.get("allow_promiscuous") {
+        Some(toml::Value::Boolean(allow)) => *allow,
+        Some(toml::Value::String(v)) => v.parse::<bool>().map_err(|e| {
+            ConfigTomlError::NicPromiscuousParseFailed(name.to_owned(), e)
+        })?,
+        _ => false,
+    };
+
     let device_spec = NetworkDeviceV0::VirtioNic(VirtioNic {
         backend_name: backend_name.clone(),
         pci_path,
+        mac_address,
+        mtu,
+        allow_promiscuous,
     });
 
     Ok(ParsedNetworkDevice {
@@ -307,6 +409,16 @@ pub(super) struct ParsedPciPciBridge {
     pub(super) bridge: PciPciBridge,
 }
 
+pub(super) struct ParsedVirtioFs {
+    pub(super) name: String,
+    pub(super) fs: VirtioFs,
+}
+
+pub(super) struct ParsedVirtioSerial {
+    pub(super) name: String,
+    pub(super) serial: VirtioSerial,
+}
+
 pub(super) fn parse_pci_bridge_from_config(
     bridge: &config::PciBridge,
 ) -> Result<ParsedPciPciBridge, ConfigTomlError> {
@@ -324,6 +436,161 @@ pub(super) fn parse_pci_bridge_from_config(
     })
 }
 
+pub(super) fn parse_p9fs_from_config(
+    name: &str,
+    device: &config::Device,
+) -> Result<P9fs, ConfigTomlError> {
+    let source = device
+        .get_string("source")
+        .ok_or_else(|| ConfigTomlError::NoP9Source(name.to_owned()))?;
+    let target = device
+        .get_string("target")
+        .ok_or_else(|| ConfigTomlError::NoP9Target(name.to_owned()))?;
+    let pci_path: PciPath = device
+        .get("pci-path")
+        .ok_or_else(|| ConfigTomlError::InvalidPciPath(name.to_owned()))?;
+
+    let chunk_size = device.get("chunk_size").unwrap_or(65536);
+    let read_only = match device.options.get("read_only") {
+        Some(toml::Value::Boolean(ro)) => *ro,
+        Some(toml::Value::String(v)) => v.parse::<bool>().map_err(|e| {
+            ConfigTomlError::P9ReadonlyParseFailed(name.to_owned(), e)
+        })?,
+        _ => false,
+    };
+
+    Ok(P9fs {
+        source: source.to_owned(),
+        target: target.to_owned(),
+        chunk_size,
+        pci_path,
+        read_only,
+    })
+}
+
+pub(super) fn parse_virtio_fs_from_config(
+    name: &str,
+    device: &config::Device,
+) -> Result<VirtioFs, ConfigTomlError> {
+    let source = device
+        .get_string("source")
+        .ok_or_else(|| ConfigTomlError::NoVirtioFsSource(name.to_owned()))?;
+    let tag = device
+        .get_string("tag")
+        .ok_or_else(|| ConfigTomlError::NoVirtioFsTag(name.to_owned()))?;
+    let pci_path: PciPath = device
+        .get("pci-path")
+        .ok_or_else(|| ConfigTomlError::InvalidPciPath(name.to_owned()))?;
+
+    let cache = match device.get_string("cache") {
+        None | Some("auto") => VirtioFsCachePolicy::Auto,
+        Some("never") => VirtioFsCachePolicy::Never,
+        Some("always") => VirtioFsCachePolicy::Always,
+        Some(policy) => {
+            return Err(ConfigTomlError::InvalidVirtioFsCachePolicy {
+                policy: policy.to_owned(),
+                name: name.to_owned(),
+            })
+        }
+    };
+    let read_only = match device.options.get("read_only") {
+        Some(toml::Value::Boolean(ro)) => *ro,
+        Some(toml::Value::String(v)) => v.parse::<bool>().map_err(|e| {
+            ConfigTomlError::VirtioFsReadonlyParseFailed(name.to_owned(), e)
+        })?,
+        _ => false,
+    };
+
+    Ok(VirtioFs {
+        source: source.to_owned(),
+        tag: tag.to_owned(),
+        cache,
+        read_only,
+        pci_path,
+    })
+}
+
+/// Parses a virtio-serial device, the ports of which are given as an array of
+/// tables, e.g.
+///
+/// ```toml
+/// ports = [
+///     { name = "org.qemu.guest_agent.0", socket = "/tmp/qga.sock" },
+///     { name = "org.example.log.0", file = "/var/log/guest.log" },
+///     { name = "org.example.shell.0" },
+/// ]
+/// ```
+///
+/// A port with neither a `socket` nor a `file` is reachable through the
+/// server's websocket.
+pub(super) fn parse_virtio_serial_from_config(
+    name: &str,
+    device: &config::Device,
+) -> Result<VirtioSerial, ConfigTomlError> {
+    let pci_path: PciPath = device
+        .get("pci-path")
+        .ok_or_else(|| ConfigTomlError::InvalidPciPath(name.to_owned()))?;
+    let ports = device
+        .options
+        .get("ports")
+        .and_then(toml::Value::as_array)
+        .ok_or_else(|| ConfigTomlError::NoVirtioSerialPorts(name.to_owned()))?;
+
+    let ports = ports
+        .iter()
+        .enumerate()
+        .map(|(idx, port)| {
+            let invalid = || ConfigTomlError::InvalidVirtioSerialPort {
+                port: idx,
+                name: name.to_owned(),
+            };
+            let port = port.as_table().ok_or_else(invalid)?;
+            let get = |key: &str| {
+                port.get(key).map(|v| v.as_str().ok_or_else(invalid))
+            };
+            let port_name = get("name").ok_or_else(invalid)??;
+            let backend = match (get("socket"), get("file")) {
+                (None, None) => VirtioSerialPortBackend::Buffer,
+                (Some(path), None) => {
+                    VirtioSerialPortBackend::UnixSocket(path?.to_owned())
+                }
+                (None, Some(path)) => {
+                    VirtioSerialPortBackend::File(path?.to_owned())
+                }
+                (Some(_), Some(_)) => return Err(invalid()),
+            };
+            Ok(VirtioSerialPort { name: port_name.to_owned(), backend })
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(VirtioSerial { ports, pci_path })
+}
+
+/// Parses a guest agent, the channel of which is either a COM port (`com1`
+/// through `com4`) or a virtio-serial device and port name separated by a
+/// slash, e.g. `serial0/org.qemu.guest_agent.0`.
+pub(super) fn parse_guest_agent_from_config(
+    name: &str,
+    device: &config::Device,
+) -> Result<GuestAgent, ConfigTomlError> {
+    let invalid = || ConfigTomlError::InvalidGuestAgentChannel(name.to_owned());
+    let channel = match device.get_string("channel").ok_or_else(invalid)? {
+        "com1" => GuestAgentChannel::SerialPort(SerialPortNumber::Com1),
+        "com2" => GuestAgentChannel::SerialPort(SerialPortNumber::Com2),
+        "com3" => GuestAgentChannel::SerialPort(SerialPortNumber::Com3),
+        "com4" => GuestAgentChannel::SerialPort(SerialPortNumber::Com4),
+        channel => {
+            let (device, port) = channel.split_once('/').ok_or_else(invalid)?;
+            GuestAgentChannel::VirtioSerial {
+                device: device.to_owned(),
+                port: port.to_owned(),
+            }
+        }
+    };
+
+    Ok(GuestAgent { channel })
+}
+
 #[cfg(feature = "falcon")]
 pub(super) fn parse_softnpu_p9_from_config(
     name: &str,
@@ -362,27 +629,3 @@ pub(super) fn parse_softnpu_port_from_config(
         backend_name: vnic_name.to_owned(),
     })
 }
-
-#[cfg(feature = "falcon")]
-pub(super) fn parse_p9fs_from_config(
-    name: &str,
-    device: &config::Device,
-) -> Result<P9fs, ConfigTomlError> {
-    let source = device
-        .get_string("source")
-        .ok_or_else(|| ConfigTomlError::NoP9Source(name.to_owned()))?;
-    let target = device
-        .get_string("target")
-        .ok_or_else(|| ConfigTomlError::NoP9Target(name.to_owned()))?;
-    let pci_path: PciPath = device
-        .get("pci-path")
-        .ok_or_else(|| ConfigTomlError::InvalidPciPath(name.to_owned()))?;
-
-    let chunk_size = device.get("chunk_size").unwrap_or(65536);
-    Ok(P9fs {
-        source: source.to_owned(),
-        target: target.to_owned(),
-        chunk_size,
-        pci_path,
-    })
-}
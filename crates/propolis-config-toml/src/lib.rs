@@ -0,0 +1,339 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Converts a propolis-server config TOML into an instance spec.
+//!
+//! propolis-server uses this to interpret the config TOML it is started with,
+//! and propolis-cli uses it to author specs for the `/instance/spec` endpoint
+//! from the same format.
+
+use builder::{SpecBuilder, SpecBuilderError};
+use propolis_api_types::instance_spec::components::board::{
+    Board, Chipset, I440Fx,
+};
+use propolis_api_types::instance_spec::components::devices::{
+    QemuPvpanic, SerialPortNumber,
+};
+use propolis_api_types::instance_spec::{v0::*, PciPath};
+use propolis_server_config as config;
+use thiserror::Error;
+
+pub mod builder;
+mod config_toml;
+
+pub use config_toml::ConfigTomlError;
+
+/// Describes a storage device/backend pair parsed from an input source like an
+/// API request or a config TOML entry.
+pub struct ParsedStorageDevice {
+    pub device_name: String,
+    pub device_spec: StorageDeviceV0,
+    pub backend_name: String,
+    pub backend_spec: StorageBackendV0,
+}
+
+/// Describes a network device/backend pair parsed from an input source like an
+/// API request or a config TOML entry.
+pub struct ParsedNetworkDevice {
+    pub device_name: String,
+    pub device_spec: NetworkDeviceV0,
+    pub backend_name: String,
+    pub backend_spec: NetworkBackendV0,
+}
+
+/// Errors that can occur while building an instance spec from a config TOML.
+#[derive(Debug, Error)]
+pub enum SpecFromConfigError {
+    #[error(transparent)]
+    InnerBuilderError(#[from] SpecBuilderError),
+
+    #[error("error parsing config TOML")]
+    ConfigToml(#[from] ConfigTomlError),
+}
+
+/// Generates NIC device and backend names from the NIC's PCI path. This is
+/// needed because the `name` field in a propolis-client
+/// `NetworkInterfaceRequest` is actually the name of the host vNIC to bind to,
+/// and that can change between incarnations of an instance. The PCI path is
+/// unique to each NIC but must remain stable over a migration, so it's suitable
+/// for use in this naming scheme.
+///
+/// N.B. Migrating a NIC requires the source and target to agree on these names,
+///      so changing this routine's behavior will prevent Propolis processes
+///      with the old behavior from migrating processes with the new behavior.
+pub fn pci_path_to_nic_names(path: PciPath) -> (String, String) {
+    (format!("vnic-{}", path), format!("vnic-{}-backend", path))
+}
+
+/// Creates a spec builder for a board with the given vCPU count and memory
+/// size (in MiB), whose chipset is configured from the supplied config TOML.
+pub fn builder_from_config(
+    cpus: u8,
+    memory_mb: u64,
+    config: &config::Config,
+) -> Result<SpecBuilder, SpecFromConfigError> {
+    let enable_pcie = config.chipset.options.get("enable-pcie").map_or_else(
+        || Ok(false),
+        |v| {
+            v.as_bool().ok_or_else(|| {
+                ConfigTomlError::EnablePcieParseFailed(v.to_string())
+            })
+        },
+    )?;
+
+    let mut builder = SpecBuilder::new(Board {
+        cpus,
+        memory_mb,
+        chipset: Chipset::I440Fx(I440Fx { enable_pcie }),
+    });
+
+    builder.add_pvpanic_device(QemuPvpanic { enable_isa: true })?;
+
+    Ok(builder)
+}
+
+/// Adds all the devices and backends specified in the supplied configuration
+/// TOML to the spec under construction in `builder`.
+pub fn add_devices_from_config(
+    builder: &mut SpecBuilder,
+    config: &config::Config,
+) -> Result<(), SpecFromConfigError> {
+    let parsed = config_toml::ParsedConfig::try_from(config)?;
+    for disk in parsed.disks {
+        builder.add_storage_device(disk)?;
+    }
+
+    for nic in parsed.nics {
+        builder.add_network_device(nic)?;
+    }
+
+    for bridge in parsed.pci_bridges {
+        builder.add_pci_bridge(bridge.name, bridge.bridge)?;
+    }
+
+    for p9fs in parsed.p9fs {
+        builder.set_p9fs(p9fs)?;
+    }
+
+    for fs in parsed.virtio_fs {
+        builder.add_virtio_fs(fs.name, fs.fs)?;
+    }
+
+    for clipboard in parsed.clipboard {
+        builder.set_clipboard(clipboard)?;
+    }
+
+    for serial in parsed.virtio_serial {
+        builder.add_virtio_serial(serial.name, serial.serial)?;
+    }
+
+    if let Some(agent) = parsed.guest_agent {
+        builder.set_guest_agent(agent)?;
+    }
+
+    #[cfg(feature = "falcon")]
+    add_parsed_softnpu_devices(builder, parsed.softnpu)?;
+
+    Ok(())
+}
+
+#[cfg(feature = "falcon")]
+fn add_parsed_softnpu_devices(
+    builder: &mut SpecBuilder,
+    devices: config_toml::ParsedSoftNpu,
+) -> Result<(), SpecBuilderError> {
+    for pci_port in devices.pci_ports {
+        builder.set_softnpu_pci_port(pci_port)?;
+    }
+
+    for port in devices.ports {
+        builder.add_softnpu_port(port.name.clone(), port)?;
+    }
+
+    for p9 in devices.p9_devices {
+        builder.set_softnpu_p9(p9)?;
+    }
+
+    Ok(())
+}
+
+/// Adds the serial ports given to every instance: COM1 through COM4, except
+/// when SoftNpu has claimed COM4 for ASIC management.
+pub fn add_default_serial_ports(
+    builder: &mut SpecBuilder,
+) -> Result<(), SpecBuilderError> {
+    for port in [
+        SerialPortNumber::Com1,
+        SerialPortNumber::Com2,
+        SerialPortNumber::Com3,
+        #[cfg(not(feature = "falcon"))]
+        SerialPortNumber::Com4,
+    ] {
+        builder.add_serial_port(port)?;
+    }
+    Ok(())
+}
+
+/// Builds a complete instance spec from a config TOML alone: a board with the
+/// given vCPU count and memory size (in MiB), the devices and backends listed
+/// in the TOML, and the default serial ports.
+pub fn spec_from_config(
+    cpus: u8,
+    memory_mb: u64,
+    config: &config::Config,
+) -> Result<InstanceSpecV0, SpecFromConfigError> {
+    let mut builder = builder_from_config(cpus, memory_mb, config)?;
+    add_devices_from_config(&mut builder, config)?;
+    add_default_serial_ports(&mut builder)?;
+    Ok(builder.finish())
+}
+
+#[cfg(test)]
+mod test {
+    use propolis_api_types::instance_spec::components::devices::{
+        GuestAgentChannel, VirtioSerialPortBackend,
+    };
+
+    use crate::config::Config;
+
+    use super::*;
+
+    #[test]
+    fn spec_from_config_toml() {
+        let raw = r#"
+bootrom = "/path/to/bootrom"
+
+[block_dev.disk0_be]
+type = "file"
+path = "/path/to/disk.img"
+
+[dev.disk0]
+driver = "pci-nvme"
+block_dev = "disk0_be"
+pci-path = "0.5.0"
+
+[[pci_bridge]]
+pci-path = "0.6.0"
+downstream-bus = 1
+"#;
+        let config: Config = toml::de::from_str(raw).unwrap();
+        let spec = spec_from_config(2, 1024, &config).unwrap();
+        assert_eq!(spec.devices.board.cpus, 2);
+        assert_eq!(spec.devices.board.memory_mb, 1024);
+        assert!(matches!(
+            spec.devices.storage_devices.get("disk0"),
+            Some(StorageDeviceV0::NvmeDisk(_))
+        ));
+        assert!(spec.backends.storage_backends.contains_key("disk0_be"));
+        assert_eq!(spec.devices.pci_pci_bridges.len(), 1);
+        assert!(spec.devices.serial_ports.contains_key("com1"));
+
+        // Devices sharing a PCI path are rejected, as by the server.
+        let raw = format!(
+            "{raw}\n[[pci_bridge]]\npci-path = \"0.5.0\"\ndownstream-bus = 2\n"
+        );
+        let config: Config = toml::de::from_str(&raw).unwrap();
+        assert!(matches!(
+            spec_from_config(2, 1024, &config).err(),
+            Some(SpecFromConfigError::InnerBuilderError(
+                SpecBuilderError::PciPathInUse(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn duplicate_p9fs_from_config_toml() {
+        let raw = r#"
+[dev.share0]
+driver = "pci-virtio-9p"
+source = "/tmp/share0"
+target = "share0"
+pci-path = "0.6.0"
+"#;
+        let config: Config = toml::de::from_str(raw).unwrap();
+        let spec = spec_from_config(2, 1024, &config).unwrap();
+        assert!(spec.devices.p9fs.is_some());
+
+        // Only one 9P device is supported, so a second is rejected rather
+        // than replacing the first.
+        let raw = format!(
+            "{raw}\n{}",
+            raw.replace("share0", "share1").replace("0.6.0", "0.7.0")
+        );
+        let config: Config = toml::de::from_str(&raw).unwrap();
+        assert!(matches!(
+            spec_from_config(2, 1024, &config).err(),
+            Some(SpecFromConfigError::InnerBuilderError(
+                SpecBuilderError::DeviceNameInUse(_)
+            ))
+        ));
+    }
+
+    #[test]
+    fn virtio_serial_from_config_toml() {
+        let raw = r#"
+[dev.serial0]
+driver = "pci-virtio-serial"
+pci-path = "0.7.0"
+ports = [
+    { name = "org.qemu.guest_agent.0", socket = "/tmp/qga.sock" },
+    { name = "org.example.log.0", file = "/tmp/guest.log" },
+    { name = "org.example.shell.0" },
+]
+"#;
+        let config: Config = toml::de::from_str(raw).unwrap();
+        let spec = spec_from_config(2, 1024, &config).unwrap();
+        let serial = spec.devices.virtio_serial.get("serial0").unwrap();
+        let backends: Vec<_> =
+            serial.ports.iter().map(|p| p.backend.clone()).collect();
+        assert_eq!(
+            backends,
+            [
+                VirtioSerialPortBackend::UnixSocket("/tmp/qga.sock".into()),
+                VirtioSerialPortBackend::File("/tmp/guest.log".into()),
+                VirtioSerialPortBackend::Buffer,
+            ]
+        );
+
+        // A port may not lead to both a socket and a file.
+        let raw = raw.replace(
+            "/tmp/guest.log\"",
+            "/tmp/guest.log\", socket = \"/tmp/s\"",
+        );
+        let config: Config = toml::de::from_str(&raw).unwrap();
+        assert!(matches!(
+            spec_from_config(2, 1024, &config).err(),
+            Some(SpecFromConfigError::ConfigToml(
+                ConfigTomlError::InvalidVirtioSerialPort { port: 1, .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn guest_agent_from_config_toml() {
+        let raw = r#"
+[dev.qga]
+driver = "qemu-guest-agent"
+channel = "serial0/org.qemu.guest_agent.0"
+"#;
+        let config: Config = toml::de::from_str(raw).unwrap();
+        let spec = spec_from_config(2, 1024, &config).unwrap();
+        assert_eq!(
+            spec.devices.guest_agent.unwrap().channel,
+            GuestAgentChannel::VirtioSerial {
+                device: "serial0".to_string(),
+                port: "org.qemu.guest_agent.0".to_string(),
+            }
+        );
+
+        let config: Config =
+            toml::de::from_str(&raw.replace("serial0/", "")).unwrap();
+        assert!(matches!(
+            spec_from_config(2, 1024, &config).err(),
+            Some(SpecFromConfigError::ConfigToml(
+                ConfigTomlError::InvalidGuestAgentChannel(_)
+            ))
+        ));
+    }
+}
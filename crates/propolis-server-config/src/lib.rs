@@ -34,6 +34,21 @@ pub struct Config {
 
     #[serde(default, rename = "cpuid")]
     pub cpuid_profiles: BTreeMap<String, CpuidProfile>,
+
+    #[serde(default)]
+    pub serial_log: Option<SerialLog>,
+
+    #[serde(default)]
+    pub vnc: Vnc,
+
+    #[serde(default)]
+    pub shutdown: Shutdown,
+
+    #[serde(default)]
+    pub api: Api,
+
+    #[serde(default)]
+    pub migration: Migration,
 }
 impl Default for Config {
     fn default() -> Self {
@@ -45,6 +60,11 @@ impl Default for Config {
             devices: BTreeMap::new(),
             block_devs: BTreeMap::new(),
             cpuid_profiles: BTreeMap::new(),
+            serial_log: None,
+            vnc: Vnc::default(),
+            shutdown: Shutdown::default(),
+            api: Api::default(),
+            migration: Migration::default(),
         }
     }
 }
@@ -122,6 +142,118 @@ pub struct BlockDevice {
     pub options: BTreeMap<String, toml::Value>,
 }
 
+/// Settings for persisting serial console output to log files.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SerialLog {
+    /// The directory in which to write logs. Each serial port writes to a file
+    /// named for the port, e.g. `com1.log`.
+    pub directory: PathBuf,
+
+    /// The size, in bytes, past which a log file is rotated. Log files are
+    /// never rotated if this is not set.
+    #[serde(default)]
+    pub max_size: Option<u64>,
+
+    /// The number of rotated log files to keep for each port in addition to
+    /// the one currently being written.
+    #[serde(default = "SerialLog::default_max_files")]
+    pub max_files: usize,
+
+    /// Whether to prefix each line of output with the time at which it was
+    /// received.
+    #[serde(default)]
+    pub timestamps: bool,
+}
+
+impl SerialLog {
+    fn default_max_files() -> usize {
+        4
+    }
+}
+
+/// Authentication required of VNC clients.
+#[derive(Clone, Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Vnc {
+    /// A file holding the password which clients must supply via VNC
+    /// Authentication.
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+
+    /// A PEM file holding the certificate chain to present to clients. Along
+    /// with `tls_key`, this requires clients to connect using VeNCrypt.
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// A PEM file holding the private key for `tls_cert`.
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+
+    /// A file holding the bearer token which clients of the `/instance/vnc`
+    /// websocket, and of `/instance/screenshot`, must present.
+    #[serde(default)]
+    pub token_file: Option<PathBuf>,
+}
+
+/// Settings for stopping instances at the guest's request.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Shutdown {
+    /// How long, in seconds, to wait for the guest to power off after its ACPI
+    /// power button is pressed by a soft stop request, before the instance is
+    /// stopped forcibly.
+    #[serde(default = "Shutdown::default_soft_stop_timeout_secs")]
+    pub soft_stop_timeout_secs: u64,
+}
+
+impl Shutdown {
+    fn default_soft_stop_timeout_secs() -> u64 {
+        60
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self { soft_stop_timeout_secs: Self::default_soft_stop_timeout_secs() }
+    }
+}
+
+/// Authentication required of clients of the server's API.
+#[derive(Clone, Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Api {
+    /// A PEM file holding the certificate chain to present to clients. Along
+    /// with `tls_key`, this serves the API over HTTPS rather than HTTP.
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// A PEM file holding the private key for `tls_cert`.
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+
+    /// A file holding the bearer token which clients must present with every
+    /// request.
+    #[serde(default)]
+    pub token_file: Option<PathBuf>,
+}
+
+/// Authentication of the servers at either end of a live migration.
+#[derive(Clone, Default, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Migration {
+    /// A PEM file holding the certificate(s) of the authority which issues
+    /// other servers' certificates. A migration source's API certificate, and
+    /// the certificates presented by migration peers, must be signed by it.
+    #[serde(default)]
+    pub ca_cert: Option<PathBuf>,
+
+    /// A PEM file holding the certificate chain this server presents to its
+    /// migration peers. Along with `key`, this requires migrations into and
+    /// out of this server to authenticate both ends with mutual TLS.
+    #[serde(default)]
+    pub cert: Option<PathBuf>,
+
+    /// A PEM file holding the private key for `cert`.
+    #[serde(default)]
+    pub key: Option<PathBuf>,
+}
+
 /// Errors which may be returned when parsing the server configuration.
 #[derive(Error, Debug)]
 pub enum ParseError {
@@ -214,5 +346,81 @@ path = "/etc/passwd"
             bdev1.options.get("path").map(Value::as_str).unwrap(),
             Some("/etc/passwd")
         );
+
+        assert_eq!(cfg.serial_log, None);
+    }
+
+    #[test]
+    fn parse_serial_log_config() {
+        let raw = r#"
+bootrom = "/path/to/bootrom"
+
+[serial_log]
+directory = "/var/log/propolis"
+max_size = 1048576
+timestamps = true
+"#;
+        let cfg: Config = toml::de::from_str(raw).unwrap();
+        assert_eq!(
+            cfg.serial_log,
+            Some(SerialLog {
+                directory: "/var/log/propolis".into(),
+                max_size: Some(1048576),
+                max_files: 4,
+                timestamps: true,
+            })
+        );
+        assert_eq!(cfg.shutdown.soft_stop_timeout_secs, 60);
+    }
+
+    #[test]
+    fn parse_shutdown_config() {
+        let raw = r#"
+bootrom = "/path/to/bootrom"
+
+[shutdown]
+soft_stop_timeout_secs = 300
+"#;
+        let cfg: Config = toml::de::from_str(raw).unwrap();
+        assert_eq!(cfg.shutdown, Shutdown { soft_stop_timeout_secs: 300 });
+    }
+
+    #[test]
+    fn parse_api_and_migration_config() {
+        let raw = r#"
+bootrom = "/path/to/bootrom"
+
+[api]
+tls_cert = "/etc/propolis/api-cert.pem"
+tls_key = "/etc/propolis/api-key.pem"
+token_file = "/etc/propolis/api-token"
+
+[migration]
+ca_cert = "/etc/propolis/ca.pem"
+cert = "/etc/propolis/migration-cert.pem"
+key = "/etc/propolis/migration-key.pem"
+"#;
+        let cfg: Config = toml::de::from_str(raw).unwrap();
+        assert_eq!(
+            cfg.api,
+            Api {
+                tls_cert: Some("/etc/propolis/api-cert.pem".into()),
+                tls_key: Some("/etc/propolis/api-key.pem".into()),
+                token_file: Some("/etc/propolis/api-token".into()),
+            }
+        );
+        assert_eq!(
+            cfg.migration,
+            Migration {
+                ca_cert: Some("/etc/propolis/ca.pem".into()),
+                cert: Some("/etc/propolis/migration-cert.pem".into()),
+                key: Some("/etc/propolis/migration-key.pem".into()),
+            }
+        );
+
+        let cfg: Config =
+            toml::de::from_str(r#"bootrom = "/path/to/bootrom""#).unwrap();
+        assert_eq!(cfg.api, Api::default());
+        assert_eq!(cfg.migration, Migration::default());
     }
 }
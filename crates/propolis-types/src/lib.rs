@@ -119,9 +119,26 @@ impl Display for PciPath {
     }
 }
 
+/// Parses a MAC address written as six colon-separated hexadecimal octets,
+/// such as `02:08:20:ab:cd:ef`.
+pub fn parse_mac_addr(s: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut octets = s.split(':');
+    for b in mac.iter_mut() {
+        let octet = octets.next()?;
+        if !(1..=2).contains(&octet.len())
+            || !octet.bytes().all(|c| c.is_ascii_hexdigit())
+        {
+            return None;
+        }
+        *b = u8::from_str_radix(octet, 16).ok()?;
+    }
+    octets.next().is_none().then_some(mac)
+}
+
 #[cfg(test)]
 mod test {
-    use super::PciPath;
+    use super::{parse_mac_addr, PciPath};
     use std::str::FromStr;
 
     const TEST_CASES: &[(&str, Result<PciPath, ()>)] = &[
@@ -151,4 +168,17 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn mac_addrs() {
+        assert_eq!(
+            parse_mac_addr("02:08:20:ab:CD:e"),
+            Some([0x02, 0x08, 0x20, 0xab, 0xcd, 0x0e])
+        );
+        assert_eq!(parse_mac_addr("02:08:20:ab:cd"), None);
+        assert_eq!(parse_mac_addr("02:08:20:ab:cd:ef:01"), None);
+        assert_eq!(parse_mac_addr("02:08:20:ab::ef"), None);
+        assert_eq!(parse_mac_addr("02:08:20:ab:cd:+f"), None);
+        assert_eq!(parse_mac_addr("02-08-20-ab-cd-ef"), None);
+    }
 }
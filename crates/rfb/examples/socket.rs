@@ -13,10 +13,8 @@ use slog::info;
 use tokio::net::TcpListener;
 use tokio_util::codec::FramedRead;
 
-use rfb::proto::{
-    ClientMessageDecoder, PixelFormat, ProtoVersion, Resolution, SecurityType,
-    SecurityTypes,
-};
+use rfb::auth::Security;
+use rfb::proto::{ClientMessageDecoder, PixelFormat, ProtoVersion, Resolution};
 use rgb_frame::FourCC;
 
 mod shared;
@@ -74,20 +72,17 @@ async fn main() -> Result<()> {
     .unwrap();
 
     loop {
-        let (mut sock, addr) = listener.accept().await.unwrap();
+        let (sock, addr) = listener.accept().await.unwrap();
 
         info!(log, "New connection from {:?}", addr);
         let log_child = log.new(slog::o!("sock" => addr));
 
         let init_res = rfb::server::initialize(
-            &mut sock,
+            sock,
             rfb::server::InitParams {
                 version: ProtoVersion::Rfb38,
 
-                sec_types: SecurityTypes(vec![
-                    SecurityType::None,
-                    SecurityType::VncAuthentication,
-                ]),
+                security: Security::None,
 
                 name: "rfb-example-server".to_string(),
 
@@ -100,10 +95,13 @@ async fn main() -> Result<()> {
         )
         .await;
 
-        if let Err(e) = init_res {
-            slog::info!(log_child, "Error during client init {:?}", e);
-            continue;
-        }
+        let sock = match init_res {
+            Ok((sock, _client_init)) => sock,
+            Err(e) => {
+                slog::info!(log_child, "Error during client init {:?}", e);
+                continue;
+            }
+        };
 
         let be_clone = backend.clone();
         let input_pf = pf.clone();
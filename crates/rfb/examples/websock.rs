@@ -18,10 +18,8 @@ use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio_tungstenite::tungstenite::protocol::Role;
 use tokio_util::codec::FramedRead;
 
-use rfb::proto::{
-    ClientMessageDecoder, PixelFormat, ProtoVersion, Resolution, SecurityType,
-    SecurityTypes,
-};
+use rfb::auth::Security;
+use rfb::proto::{ClientMessageDecoder, PixelFormat, ProtoVersion, Resolution};
 use rfb::{self, tungstenite::BinaryWs};
 use rgb_frame::FourCC;
 
@@ -49,20 +47,17 @@ struct AppCtx {
 }
 
 async fn run_server(
-    mut sock: BinaryWs<impl AsyncRead + AsyncWrite + Unpin>,
+    sock: BinaryWs<impl AsyncRead + AsyncWrite + Unpin>,
     be: ExampleBackend,
     input_pf: PixelFormat,
     log: &slog::Logger,
 ) {
     let init_res = rfb::server::initialize(
-        &mut sock,
+        sock,
         rfb::server::InitParams {
             version: ProtoVersion::Rfb38,
 
-            sec_types: SecurityTypes(vec![
-                SecurityType::None,
-                SecurityType::VncAuthentication,
-            ]),
+            security: Security::None,
 
             name: "rfb-ws-example".to_string(),
 
@@ -75,15 +70,16 @@ async fn run_server(
     )
     .await;
 
-    match init_res {
-        Ok(client_init) => {
+    let sock = match init_res {
+        Ok((sock, client_init)) => {
             slog::debug!(log, "Client initialized {:?}", client_init);
+            sock
         }
         Err(e) => {
             slog::info!(log, "Error during client init {:?}", e);
             return;
         }
-    }
+    };
 
     let mut output_pf = input_pf.clone();
     let mut decoder = FramedRead::new(sock, ClientMessageDecoder::default());
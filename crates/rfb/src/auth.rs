@@ -0,0 +1,239 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2024 Oxide Computer Company
+
+//! Client authentication, performed during the security handshake.
+//!
+//! Besides the VNC Authentication scheme of RFC 6143, this supports the
+//! VeNCrypt extension, which wraps the connection in TLS before (optionally)
+//! performing VNC Authentication within it.
+
+use std::fmt;
+
+use des::cipher::generic_array::GenericArray;
+use des::cipher::{BlockEncrypt, KeyInit};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::proto::{SecurityResult, SecurityType};
+use crate::server::{InitError, Result};
+
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::ServerConfig;
+
+/// Number of significant bytes in a VNC Authentication password
+pub const VNC_PASSWORD_LEN: usize = 8;
+/// Length of the challenge (and response) in VNC Authentication
+pub const VNC_CHALLENGE_LEN: usize = 16;
+
+/// VeNCrypt subtype: TLS with an X.509 certificate, without further auth
+pub const VENCRYPT_X509_NONE: u32 = 260;
+/// VeNCrypt subtype: TLS with an X.509 certificate, then VNC Authentication
+pub const VENCRYPT_X509_VNC: u32 = 261;
+
+/// Password for VNC Authentication (RFC 6143 section 7.2.2).
+///
+/// Only the first [VNC_PASSWORD_LEN] bytes of a password are significant: it
+/// is truncated, or padded with zeroes, to that length.
+#[derive(Clone)]
+pub struct VncPassword([u8; VNC_PASSWORD_LEN]);
+
+impl VncPassword {
+    pub fn new(password: &[u8]) -> Self {
+        let mut key = [0u8; VNC_PASSWORD_LEN];
+        let len = password.len().min(VNC_PASSWORD_LEN);
+        key[..len].copy_from_slice(&password[..len]);
+        Self(key)
+    }
+
+    /// Computes the expected response to `challenge`: the challenge encrypted
+    /// with DES, keyed by the password.
+    pub fn response(
+        &self,
+        challenge: &[u8; VNC_CHALLENGE_LEN],
+    ) -> [u8; VNC_CHALLENGE_LEN] {
+        // For historical reasons, the bits of each byte of the key are
+        // reversed relative to what DES expects.
+        let key = self.0.map(u8::reverse_bits);
+        let cipher = des::Des::new(GenericArray::from_slice(&key));
+
+        let mut resp = *challenge;
+        for block in resp.chunks_exact_mut(8) {
+            cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        }
+        resp
+    }
+
+    /// Checks a client's `response` to `challenge`.
+    pub fn verify(
+        &self,
+        challenge: &[u8; VNC_CHALLENGE_LEN],
+        response: &[u8; VNC_CHALLENGE_LEN],
+    ) -> bool {
+        // Compare every byte, so that the time taken does not reveal how much
+        // of the response was correct.
+        self.response(challenge)
+            .iter()
+            .zip(response.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+    }
+}
+
+impl fmt::Debug for VncPassword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("VncPassword(..)")
+    }
+}
+
+/// Security required of clients during the handshake
+#[derive(Clone, Debug, Default)]
+pub enum Security {
+    /// No authentication
+    #[default]
+    None,
+    /// VNC Authentication with the given password
+    Vnc(VncPassword),
+    /// VeNCrypt, with the connection wrapped in TLS.  If a password is given,
+    /// clients must also complete VNC Authentication within the TLS session.
+    #[cfg(feature = "tls")]
+    VeNCrypt { tls: Arc<ServerConfig>, password: Option<VncPassword> },
+}
+
+impl Security {
+    /// Security types to offer to clients
+    pub fn offered(&self) -> Vec<SecurityType> {
+        match self {
+            // vncviewer won't work without offering VncAuth, even though it
+            // doesn't ask to use it.
+            Security::None => {
+                vec![SecurityType::None, SecurityType::VncAuthentication]
+            }
+            Security::Vnc(_) => vec![SecurityType::VncAuthentication],
+            #[cfg(feature = "tls")]
+            Security::VeNCrypt { .. } => vec![SecurityType::VeNCrypt],
+        }
+    }
+}
+
+/// Performs VNC Authentication of the client on `s`, failing the handshake if
+/// its response is incorrect.
+pub(crate) async fn vnc_auth(
+    s: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    password: &VncPassword,
+) -> Result<()> {
+    let challenge: [u8; VNC_CHALLENGE_LEN] = rand::random();
+    s.write_all(&challenge).await?;
+    s.flush().await?;
+
+    let mut response = [0u8; VNC_CHALLENGE_LEN];
+    s.read_exact(&mut response).await?;
+
+    if !password.verify(&challenge, &response) {
+        let failure =
+            SecurityResult::Failure("authentication failed".to_string());
+        failure.write_to(s).await?;
+        s.flush().await?;
+        return Err(InitError::AuthenticationFailed);
+    }
+    Ok(())
+}
+
+/// Negotiates the VeNCrypt version and subtype with the client on `s`, in
+/// preparation for the TLS handshake.
+#[cfg(feature = "tls")]
+pub(crate) async fn vencrypt_negotiate(
+    s: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    subtype: u32,
+) -> Result<()> {
+    // Only version 0.2 is supported
+    s.write_all(&[0, 2]).await?;
+    s.flush().await?;
+
+    let mut version = [0u8; 2];
+    s.read_exact(&mut version).await?;
+    if version != [0, 2] {
+        s.write_u8(0xff).await?;
+        s.flush().await?;
+        return Err(InitError::UnsupportedVeNCryptVersion(
+            version[0], version[1],
+        ));
+    }
+    s.write_u8(0).await?;
+
+    s.write_u8(1).await?;
+    s.write_u32(subtype).await?;
+    s.flush().await?;
+
+    let choice = s.read_u32().await?;
+    if choice != subtype {
+        s.write_u8(0).await?;
+        s.flush().await?;
+        return Err(InitError::UnsupportedVeNCryptSubtype(choice));
+    }
+    s.write_u8(1).await?;
+    s.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn vnc_response() {
+        // The FIPS 81 DES test vector, with the bits of each key byte reversed
+        // in the manner VNC Authentication expects.
+        let password =
+            VncPassword::new(&[0xc8, 0x2c, 0xea, 0x9e, 0xd9, 0x3d, 0xfb, 0x8f]);
+        let block = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+        let expected = [0x85, 0xe8, 0x13, 0x54, 0x0f, 0x0a, 0xb4, 0x05];
+
+        let challenge: [u8; 16] = [block, block].concat().try_into().unwrap();
+        let response: [u8; 16] =
+            [expected, expected].concat().try_into().unwrap();
+
+        assert_eq!(password.response(&challenge), response);
+        assert!(password.verify(&challenge, &response));
+        assert!(!VncPassword::new(b"password").verify(&challenge, &response));
+    }
+
+    #[test]
+    fn password_truncated() {
+        let challenge = [0x5a; VNC_CHALLENGE_LEN];
+        assert_eq!(
+            VncPassword::new(b"password").response(&challenge),
+            VncPassword::new(b"password123").response(&challenge)
+        );
+        assert_eq!(
+            VncPassword::new(b"pass").response(&challenge),
+            VncPassword::new(b"pass\0\0\0\0").response(&challenge)
+        );
+    }
+
+    #[tokio::test]
+    async fn vnc_auth_handshake() {
+        let password = VncPassword::new(b"secret");
+        for (attempt, ok) in [(b"secret", true), (b"wrong!", false)] {
+            let (mut server, mut client) = tokio::io::duplex(64);
+            let client_pw = VncPassword::new(attempt);
+            let client = tokio::spawn(async move {
+                let mut challenge = [0u8; VNC_CHALLENGE_LEN];
+                client.read_exact(&mut challenge).await.unwrap();
+                client
+                    .write_all(&client_pw.response(&challenge))
+                    .await
+                    .unwrap();
+                client
+            });
+
+            let res = vnc_auth(&mut server, &password).await;
+            assert_eq!(res.is_ok(), ok);
+            drop(client.await.unwrap());
+        }
+    }
+}
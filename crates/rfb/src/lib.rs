@@ -4,6 +4,7 @@
 //
 // Copyright 2022 Oxide Computer Company
 
+pub mod auth;
 pub mod encodings;
 pub mod keysym;
 pub mod proto;
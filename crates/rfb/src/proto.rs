@@ -90,6 +90,7 @@ pub struct SecurityTypes(pub Vec<SecurityType>);
 pub enum SecurityType {
     None,
     VncAuthentication,
+    VeNCrypt,
 }
 
 impl SecurityTypes {
@@ -115,6 +116,7 @@ impl SecurityType {
         match t {
             1 => Ok(SecurityType::None),
             2 => Ok(SecurityType::VncAuthentication),
+            19 => Ok(SecurityType::VeNCrypt),
             v => Err(ProtocolError::InvalidSecurityType(v)),
         }
     }
@@ -123,8 +125,9 @@ impl SecurityType {
         stream: &mut (impl AsyncWrite + Unpin),
     ) -> Result<()> {
         let val = match self {
-            SecurityType::None => 0,
-            SecurityType::VncAuthentication => 1,
+            SecurityType::None => 1,
+            SecurityType::VncAuthentication => 2,
+            SecurityType::VeNCrypt => 19,
         };
         stream.write_u8(val).await?;
 
@@ -148,7 +151,10 @@ impl SecurityResult {
                 stream.write_u32(0).await?;
             }
             SecurityResult::Failure(s) => {
+                let len = u32::try_from(s.len())
+                    .map_err(|_| ProtocolError::TooLarge(s.len()))?;
                 stream.write_u32(1).await?;
+                stream.write_u32(len).await?;
                 stream.write_all(s.as_bytes()).await?;
             }
         };
@@ -214,6 +220,36 @@ impl FramebufferUpdate {
     }
 }
 
+// Section 7.6.4
+pub struct ServerCutText(pub String);
+
+impl ServerCutText {
+    pub async fn write_to(
+        self,
+        stream: &mut (impl AsyncWrite + Unpin),
+    ) -> Result<()> {
+        let text = latin1_encode(&self.0);
+
+        stream.write_u8(3).await?;
+        stream.write_all(&[0u8; 3]).await?;
+        stream.write_u32(text.len() as u32).await?;
+        stream.write_all(&text).await?;
+
+        Ok(())
+    }
+}
+
+/// Encodes `text` as ISO 8859-1 (Latin-1), the encoding of cut text.
+/// Characters outside of Latin-1 are replaced with `?`.
+pub fn latin1_encode(text: &str) -> Vec<u8> {
+    text.chars().map(|c| u8::try_from(c).unwrap_or(b'?')).collect()
+}
+
+/// Decodes ISO 8859-1 (Latin-1) `text`, in which every byte is a character.
+pub fn latin1_decode(text: &[u8]) -> String {
+    text.iter().map(|&b| char::from(b)).collect()
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Position {
     pub x: u16,
@@ -492,10 +528,10 @@ impl Decoder for ClientMessageDecoder {
                 if src.len() < 8 {
                     return Ok(None);
                 }
-                // 3 bytes of padding + i32 len + string
+                // 3 bytes of padding + u32 len + string
                 let data_len =
                     u32::from_be_bytes(src[4..8].try_into().unwrap());
-                1 + size_of::<i32>() + data_len as usize
+                3 + size_of::<u32>() + data_len as usize
             }
         };
         let total_sz_reqd = 1 + msg_sz_reqd;
@@ -555,12 +591,8 @@ impl Decoder for ClientMessageDecoder {
                 src.advance(3);
 
                 let len = src.get_u32() as usize;
-                let buf = src[..len].to_vec();
-
-                // TODO: The encoding RFB uses is ISO 8859-1 (Latin-1), which is
-                // a subset of utf-8. Determine if this is the right approach.
-                let text = String::from_utf8(buf)
-                    .map_err(|_| ProtocolError::InvalidTextEncoding)?;
+                let text = latin1_decode(&src[..len]);
+                src.advance(len);
 
                 Ok(Some(ClientMessage::ClientCutText(text)))
             }
@@ -713,3 +745,40 @@ mod raw {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn client_cut_text() {
+        let mut msg = vec![6, 0, 0, 0, 0, 0, 0, 4];
+        msg.extend_from_slice(&[b'a', 0xe9, b'b', 0xff]);
+        let mut decoder = ClientMessageDecoder::default();
+
+        // Nothing is decoded until the whole message is buffered
+        let mut src = BytesMut::from(&msg[..msg.len() - 1]);
+        assert!(decoder.decode(&mut src).unwrap().is_none());
+
+        // Followed by a KeyEvent, which should be decoded in turn
+        let mut src = BytesMut::from(&msg[..]);
+        src.extend_from_slice(&[4, 1, 0, 0, 0, 0, 0, 0x61]);
+        match decoder.decode(&mut src).unwrap() {
+            Some(ClientMessage::ClientCutText(text)) => {
+                assert_eq!(text, "a\u{e9}b\u{ff}")
+            }
+            _ => panic!("expected ClientCutText"),
+        }
+        assert!(matches!(
+            decoder.decode(&mut src).unwrap(),
+            Some(ClientMessage::KeyEvent(_))
+        ));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn latin1() {
+        assert_eq!(latin1_encode("a\u{e9}\u{263a}"), vec![b'a', 0xe9, b'?']);
+        assert_eq!(latin1_decode(&latin1_encode("caf\u{e9}")), "caf\u{e9}");
+    }
+}
@@ -4,9 +4,13 @@
 //
 // Copyright 2022 Oxide Computer Company
 
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
 use thiserror::Error;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
 
+use crate::auth::{self, Security};
 use crate::proto::{
     ClientInit, PixelFormat, ProtoVersion, Resolution, SecurityResult,
     SecurityType, SecurityTypes, ServerInit,
@@ -20,6 +24,15 @@ pub enum InitError {
     #[error("unsupported security type {0:?}")]
     UnsupportedSecurityType(SecurityType),
 
+    #[error("client failed authentication")]
+    AuthenticationFailed,
+
+    #[error("unsupported VeNCrypt version {0}.{1}")]
+    UnsupportedVeNCryptVersion(u8, u8),
+
+    #[error("unsupported VeNCrypt subtype {0}")]
+    UnsupportedVeNCryptSubtype(u32),
+
     #[error("protocol error {source}")]
     Protocol {
         #[from]
@@ -38,8 +51,8 @@ pub type Result<T> = std::result::Result<T, InitError>;
 pub struct InitParams {
     /// Supported protocol version
     pub version: ProtoVersion,
-    /// Supported security types
-    pub sec_types: SecurityTypes,
+    /// Security required of the client
+    pub security: Security,
 
     /// Server name
     pub name: String,
@@ -50,40 +63,120 @@ pub struct InitParams {
     pub format: PixelFormat,
 }
 
-async fn rfb_handshake(
-    s: &mut (impl AsyncRead + AsyncWrite + Unpin),
+/// Connection to a client which has completed the handshake.  Depending on
+/// the security negotiated, it may have been wrapped in TLS.
+pub enum RfbStream<S> {
+    Plain(S),
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::server::TlsStream<S>>),
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for RfbStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            RfbStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            RfbStream::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for RfbStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            RfbStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            RfbStream::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            RfbStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            RfbStream::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            RfbStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            RfbStream::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+async fn rfb_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    mut s: S,
     version: ProtoVersion,
-    sec_types: SecurityTypes,
-) -> Result<()> {
+    security: &Security,
+) -> Result<RfbStream<S>> {
     // ProtocolVersion handshake
-    version.write_to(s).await?;
+    version.write_to(&mut s).await?;
     s.flush().await?;
 
-    let client_version = ProtoVersion::read_from(s).await?;
+    let client_version = ProtoVersion::read_from(&mut s).await?;
     if client_version < version {
         return Err(InitError::UnsupportedVersion(client_version));
     }
 
     // Security Handshake
-    let supported_types = sec_types.clone();
-    supported_types.write_to(s).await?;
+    let sec_types = SecurityTypes(security.offered());
+    sec_types.clone().write_to(&mut s).await?;
     s.flush().await?;
 
-    let client_choice = SecurityType::read_from(s).await?;
+    let client_choice = SecurityType::read_from(&mut s).await?;
     if !sec_types.0.contains(&client_choice) {
         let failure =
             SecurityResult::Failure("unsupported security type".to_string());
-        failure.write_to(s).await?;
+        failure.write_to(&mut s).await?;
         return Err(InitError::UnsupportedSecurityType(client_choice));
     }
 
+    let mut s = match security {
+        Security::None => RfbStream::Plain(s),
+        Security::Vnc(password) => {
+            auth::vnc_auth(&mut s, password).await?;
+            RfbStream::Plain(s)
+        }
+        #[cfg(feature = "tls")]
+        Security::VeNCrypt { tls, password } => {
+            let subtype = match password {
+                Some(_) => auth::VENCRYPT_X509_VNC,
+                None => auth::VENCRYPT_X509_NONE,
+            };
+            auth::vencrypt_negotiate(&mut s, subtype).await?;
+
+            let acceptor = tokio_rustls::TlsAcceptor::from(tls.clone());
+            let mut tls_s = acceptor.accept(s).await?;
+            if let Some(password) = password {
+                auth::vnc_auth(&mut tls_s, password).await?;
+            }
+            RfbStream::Tls(Box::new(tls_s))
+        }
+    };
+
     let res = SecurityResult::Success;
-    res.write_to(s).await?;
+    res.write_to(&mut s).await?;
     s.flush().await?;
 
-    Ok(())
+    Ok(s)
 }
-
 async fn rfb_initialization(
     s: &mut (impl AsyncRead + AsyncWrite + Unpin),
     initial_resolution: Resolution,
@@ -99,17 +192,19 @@ async fn rfb_initialization(
     Ok(client_init)
 }
 
-/// Perform server initialization handshake with client
-pub async fn initialize(
-    sock: &mut (impl AsyncRead + AsyncWrite + Unpin),
+/// Perform server initialization handshake with client, returning the
+/// connection over which the session should proceed.
+pub async fn initialize<S: AsyncRead + AsyncWrite + Unpin>(
+    sock: S,
     params: InitParams,
-) -> Result<ClientInit> {
-    assert!(
-        !params.sec_types.0.is_empty(),
-        "at least one security type must be defined"
-    );
-
-    rfb_handshake(sock, params.version, params.sec_types).await?;
-    rfb_initialization(sock, params.resolution, params.format, params.name)
-        .await
+) -> Result<(RfbStream<S>, ClientInit)> {
+    let mut s = rfb_handshake(sock, params.version, &params.security).await?;
+    let client_init = rfb_initialization(
+        &mut s,
+        params.resolution,
+        params.format,
+        params.name,
+    )
+    .await?;
+    Ok((s, client_init))
 }
@@ -7,6 +7,8 @@
 use std::mem::MaybeUninit;
 use std::num::NonZeroUsize;
 
+mod png;
+
 #[derive(Clone, Copy)]
 pub struct Spec {
     /// Width of Frame in pixels
@@ -39,6 +41,7 @@ impl Spec {
 }
 
 /// A frame of pixel data and accompanying metadata
+#[derive(Clone)]
 pub struct Frame {
     spec: Spec,
     data: Vec<u8>,
@@ -106,6 +109,37 @@ impl Frame {
         &mut self.data
     }
 
+    /// Create a copy of this frame, scaled to `width` by `height` pixels.
+    ///
+    /// Pixels are sampled from the nearest corresponding source pixel, which is
+    /// adequate for shrinking a frame down for a preview.
+    pub fn scaled(&self, width: NonZeroUsize, height: NonZeroUsize) -> Self {
+        let spec = Spec::new(width.get(), height.get(), self.spec.fourcc);
+        let bytepp = self.spec.fourcc.bytes_per_pixel().get();
+        let (src_w, src_h) = (self.spec.width.get(), self.spec.height.get());
+
+        let mut scaled = Self::new(spec);
+        let dst_stride = scaled.spec.stride.get();
+        for y in 0..height.get() {
+            let src_y = y * src_h / height.get();
+            let src_row = &self.data[src_y * self.spec.stride.get()..];
+            let dst_row = &mut scaled.data[y * dst_stride..];
+            for x in 0..width.get() {
+                let src_x = x * src_w / width.get();
+                dst_row[x * bytepp..(x + 1) * bytepp].copy_from_slice(
+                    &src_row[src_x * bytepp..(src_x + 1) * bytepp],
+                );
+            }
+        }
+        scaled
+    }
+
+    /// Encode the contents of this frame as a PNG image (with 8-bit RGB
+    /// pixels, discarding any alpha channel).
+    pub fn to_png(&self) -> Vec<u8> {
+        png::encode(self)
+    }
+
     /// Convert between recognized 4-byte pixel formats
     pub fn convert(&mut self, target: FourCC) {
         let source = self.spec.fourcc;
@@ -193,3 +227,24 @@ impl FourCC {
         unsafe { NonZeroUsize::new_unchecked(4) }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scale_frame() {
+        let mut frame = Frame::new(Spec::new(4, 2, FourCC::XR24));
+        for (i, pixel) in frame.bytes_mut().chunks_exact_mut(4).enumerate() {
+            pixel[0] = i as u8;
+        }
+
+        let scaled = frame.scaled(
+            NonZeroUsize::new(2).unwrap(),
+            NonZeroUsize::new(1).unwrap(),
+        );
+        assert_eq!(scaled.spec().width.get(), 2);
+        assert_eq!(scaled.spec().height.get(), 1);
+        assert_eq!(scaled.bytes(), &[0, 0, 0, 0, 2, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,103 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+//
+// Copyright 2024 Oxide Computer Company
+
+//! A minimal PNG encoder, sufficient for emitting frames as screenshots.
+
+use std::io::Write;
+
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+
+use crate::Frame;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Bit depth of each color channel
+const BIT_DEPTH: u8 = 8;
+/// Color type for (non-palette) RGB pixels
+const COLOR_TYPE_RGB: u8 = 2;
+/// Filter type applied to each scanline: None
+const FILTER_NONE: u8 = 0;
+
+pub(crate) fn encode(frame: &Frame) -> Vec<u8> {
+    let spec = frame.spec();
+    let (width, height) = (spec.width.get(), spec.height.get());
+    let bytepp = spec.fourcc.bytes_per_pixel().get();
+    let (r, g, b, _a) = spec.fourcc.le_idx_rgba();
+
+    // Each scanline is its filter type, followed by the RGB pixel data
+    let mut raw = Vec::with_capacity(height * (1 + width * 3));
+    for row in frame.bytes().chunks_exact(spec.stride.get()).take(height) {
+        raw.push(FILTER_NONE);
+        for pixel in row.chunks_exact(bytepp).take(width) {
+            raw.extend_from_slice(&[pixel[r], pixel[g], pixel[b]]);
+        }
+    }
+
+    let mut zlib = ZlibEncoder::new(Vec::new(), Compression::fast());
+    zlib.write_all(&raw).expect("writes to Vec do not fail");
+    let idat = zlib.finish().expect("writes to Vec do not fail");
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth and color type, followed by the compression, filter and
+    // interlace methods (all of which must be, or are, 0)
+    ihdr.extend_from_slice(&[BIT_DEPTH, COLOR_TYPE_RGB, 0, 0, 0]);
+
+    let mut out = Vec::with_capacity(SIGNATURE.len() + idat.len() + 64);
+    out.extend_from_slice(&SIGNATURE);
+    write_chunk(&mut out, b"IHDR", &ihdr);
+    write_chunk(&mut out, b"IDAT", &idat);
+    write_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    out.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use crate::{FourCC, Frame, Spec};
+
+    #[test]
+    fn encode_frame() {
+        let mut frame = Frame::new(Spec::new(2, 2, FourCC::XR24));
+        // One red pixel in the top left, in x:R:G:B little-endian order
+        frame.bytes_mut()[..4].copy_from_slice(&[0x00, 0x00, 0xff, 0x00]);
+
+        let png = frame.to_png();
+        assert_eq!(&png[..8], &super::SIGNATURE);
+
+        // IHDR: length, type, then 2x2 dimensions
+        assert_eq!(&png[8..16], &[0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(&png[16..24], &[0, 0, 0, 2, 0, 0, 0, 2]);
+
+        // IDAT follows the 25-byte IHDR chunk
+        let idat_len =
+            u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(&png[37..41], b"IDAT");
+        let mut raw = Vec::new();
+        ZlibDecoder::new(&png[41..41 + idat_len])
+            .read_to_end(&mut raw)
+            .unwrap();
+        assert_eq!(raw, [0, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+    }
+}
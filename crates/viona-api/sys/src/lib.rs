@@ -70,6 +70,7 @@ mod structs {
     }
 
     #[repr(C)]
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
     pub enum viona_promisc_t {
         VIONA_PROMISC_NONE = 0,
         VIONA_PROMISC_MULTI,
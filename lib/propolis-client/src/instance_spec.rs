@@ -9,14 +9,15 @@ use std::collections::BTreeSet;
 use thiserror::Error;
 
 use crate::types::{
-    Board, Chipset, DeviceSpecV0, I440Fx, InstanceSpecV0, NetworkBackendV0,
-    NetworkDeviceV0, PciPath, PciPciBridge, SerialPort, SerialPortNumber,
-    StorageBackendV0, StorageDeviceV0,
+    Board, Chipset, DeviceSpecV0, GuestAgent, I440Fx, InstanceSpecV0,
+    NetworkBackendV0, NetworkDeviceV0, P9fs, PciPath, PciPciBridge, SerialPort,
+    SerialPortNumber, StorageBackendV0, StorageDeviceV0, VirtioClipboard,
+    VirtioFs, VirtioSerial,
 };
 
 #[cfg(feature = "falcon")]
 use crate::types::{
-    DlpiNetworkBackend, P9fs, SoftNpuP9, SoftNpuPciPort, SoftNpuPort,
+    DlpiNetworkBackend, SoftNpuP9, SoftNpuPciPort, SoftNpuPort,
 };
 
 /// Errors that can arise while building an instance spec from component parts.
@@ -184,6 +185,64 @@ impl SpecBuilderV0 {
         }
     }
 
+    /// Adds a virtio-9p filesystem device.
+    pub fn set_p9fs(&mut self, p9fs: P9fs) -> Result<&Self, SpecBuilderError> {
+        self.register_pci_device(p9fs.pci_path)?;
+        self.spec.devices.p9fs = Some(p9fs);
+        Ok(self)
+    }
+
+    /// Adds a virtio-fs shared directory device.
+    pub fn add_virtio_fs(
+        &mut self,
+        device_name: String,
+        fs: VirtioFs,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.virtio_fs.contains_key(&device_name) {
+            return Err(SpecBuilderError::DeviceNameInUse(device_name));
+        }
+
+        self.register_pci_device(fs.pci_path)?;
+        let _old = self.spec.devices.virtio_fs.insert(device_name, fs);
+        assert!(_old.is_none());
+        Ok(self)
+    }
+
+    /// Adds the clipboard channel device.
+    pub fn set_clipboard(
+        &mut self,
+        clipboard: VirtioClipboard,
+    ) -> Result<&Self, SpecBuilderError> {
+        self.register_pci_device(clipboard.pci_path)?;
+        self.spec.devices.clipboard = Some(clipboard);
+        Ok(self)
+    }
+
+    /// Adds a virtio-serial device with a set of named ports.
+    pub fn add_virtio_serial(
+        &mut self,
+        device_name: String,
+        serial: VirtioSerial,
+    ) -> Result<&Self, SpecBuilderError> {
+        if self.spec.devices.virtio_serial.contains_key(&device_name) {
+            return Err(SpecBuilderError::DeviceNameInUse(device_name));
+        }
+
+        self.register_pci_device(serial.pci_path)?;
+        let _old = self.spec.devices.virtio_serial.insert(device_name, serial);
+        assert!(_old.is_none());
+        Ok(self)
+    }
+
+    /// Designates the channel over which the guest agent is reached.
+    pub fn set_guest_agent(
+        &mut self,
+        agent: GuestAgent,
+    ) -> Result<&Self, SpecBuilderError> {
+        self.spec.devices.guest_agent = Some(agent);
+        Ok(self)
+    }
+
     /// Yields the completed spec, consuming the builder.
     pub fn finish(self) -> InstanceSpecV0 {
         self.spec
@@ -228,10 +287,4 @@ impl SpecBuilderV0 {
         self.spec.devices.softnpu_p9 = Some(p9);
         Ok(self)
     }
-
-    pub fn set_p9fs(&mut self, p9fs: P9fs) -> Result<&Self, SpecBuilderError> {
-        self.register_pci_device(p9fs.pci_path)?;
-        self.spec.devices.p9fs = Some(p9fs);
-        Ok(self)
-    }
 }
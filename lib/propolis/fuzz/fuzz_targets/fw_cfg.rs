@@ -0,0 +1,128 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Drives the fw_cfg device through its legacy I/O ports and its DMA
+//! interface, with access descriptors (and the data they refer to) in guest
+//! memory under the fuzzer's control.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use propolis::common::GuestAddr;
+use propolis::hw::qemu::fwcfg::{Entry, FwCfg, LegacyId};
+use propolis::hw::qemu::ramfb::RamFb;
+use propolis::lifecycle::Lifecycle;
+use propolis::testing::TestMachine;
+use propolis_fuzz::{MemWrite, Width};
+
+const FW_CFG_IOP_SELECTOR: u16 = 0x0510;
+const FW_CFG_IOP_DATA: u16 = 0x0511;
+const FW_CFG_IOP_DMA_HI: u16 = 0x0514;
+const FW_CFG_IOP_DMA_LO: u16 = 0x0518;
+
+/// Length of a DMA access descriptor: control, length, then address
+const DMA_ACCESS_LEN: u64 = 16;
+
+#[derive(Arbitrary, Debug)]
+enum Port {
+    Selector,
+    Data,
+    DmaHi,
+    DmaLo,
+}
+impl Port {
+    fn port(&self) -> u16 {
+        match self {
+            Port::Selector => FW_CFG_IOP_SELECTOR,
+            Port::Data => FW_CFG_IOP_DATA,
+            Port::DmaHi => FW_CFG_IOP_DMA_HI,
+            Port::DmaLo => FW_CFG_IOP_DMA_LO,
+        }
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    /// Rewrite part of RAM, where DMA access descriptors and buffers live
+    Mem(MemWrite),
+    Read {
+        port: Port,
+        width: Width,
+    },
+    Write {
+        port: Port,
+        width: Width,
+        val: u32,
+    },
+    /// Place a DMA access descriptor at `off` in RAM, and then start the
+    /// transfer by writing its address to the DMA registers
+    Dma {
+        off: u16,
+        ctrl: u32,
+        len: u32,
+        addr: u64,
+    },
+    Reset,
+}
+
+/// Writes a DMA access descriptor into RAM (with all fields big-endian, as
+/// the device expects) and kicks off the transfer.
+fn dma(tm: &TestMachine, off: u16, ctrl: u32, len: u32, addr: u64) {
+    let max = tm.ram_size() as u64 - DMA_ACCESS_LEN;
+    let desc = GuestAddr(tm.ram_base().0 + u64::from(off) % max);
+    let mem = tm.mem();
+    mem.write(desc, &ctrl.to_be());
+    mem.write(GuestAddr(desc.0 + 4), &len.to_be());
+    mem.write(GuestAddr(desc.0 + 8), &addr.to_be());
+    drop(mem);
+
+    let _ = tm.pio_write(FW_CFG_IOP_DMA_HI, 4, ((desc.0 >> 32) as u32).to_be());
+    let _ = tm.pio_write(FW_CFG_IOP_DMA_LO, 4, (desc.0 as u32).to_be());
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    /// Contents for a named (file) entry
+    file: Vec<u8>,
+    ops: Vec<Op>,
+}
+
+fuzz_target!(|input: Input| {
+    let tm = propolis_fuzz::machine();
+
+    let fwcfg = FwCfg::new();
+    fwcfg
+        .insert_legacy(
+            LegacyId::RamSize,
+            Entry::Bytes((tm.ram_size() as u64).to_le_bytes().to_vec()),
+        )
+        .unwrap();
+    fwcfg.insert_named("opt/fuzz", Entry::Bytes(input.file.clone())).unwrap();
+
+    // Writable, through DMA, by way of the ramfb config
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let ramfb = RamFb::create(log);
+    ramfb.attach(&tm.machine().acc_mem);
+    fwcfg.insert_named(RamFb::FWCFG_ENTRY_NAME, Entry::RamFb).unwrap();
+    fwcfg.attach_ramfb(Some(ramfb));
+    fwcfg.attach(&tm.machine().bus_pio, &tm.machine().acc_mem);
+
+    for op in input.ops.iter() {
+        match op {
+            Op::Mem(mw) => mw.apply(&tm),
+            Op::Read { port, width } => {
+                let _ = tm.pio_read(port.port(), width.bytes());
+            }
+            Op::Write { port, width, val } => {
+                let _ = tm.pio_write(port.port(), width.bytes(), *val);
+            }
+            Op::Dma { off, ctrl, len, addr } => {
+                dma(&tm, *off, *ctrl, *len, *addr)
+            }
+            Op::Reset => fwcfg.reset(),
+        }
+    }
+});
@@ -0,0 +1,186 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Feeds arbitrary submission queue entries (and register accesses) to an NVMe
+//! controller, exercising the parsing of admin and NVM commands, and of the
+//! PRPs they carry.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use propolis::common::{GuestAddr, PAGE_SIZE};
+use propolis::hw::nvme::PciNvme;
+use propolis::hw::pci;
+use propolis::testing::nvme::{Command, HostCompQueue, CQE_LEN, SQE_LEN};
+use propolis::testing::TestMachine;
+use propolis_fuzz::{MemWrite, Width};
+
+const REGS_BAR: u64 = 0xc000_0000;
+const MSIX_BAR: u64 = 0xc001_0000;
+/// Size of the register window covered: the controller registers, and the
+/// doorbells of the admin and I/O queue pairs
+const REGS_LEN: u16 = 0x1010;
+
+const DISK_BLOCKS: usize = 16;
+const QUEUE_SIZE: u16 = 8;
+const IO_QID: u16 = 1;
+
+const REG_CC: u64 = 0x14;
+const REG_AQA: u64 = 0x24;
+const REG_ASQ: u64 = 0x28;
+const REG_ACQ: u64 = 0x30;
+const REG_DOORBELLS: u64 = 0x1000;
+
+const CC_EN: u32 = 1 << 0;
+const CC_IOSQES_SHIFT: u32 = 16;
+const CC_IOCQES_SHIFT: u32 = 20;
+
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    /// Rewrite part of RAM, which holds PRP lists and data buffers (as well
+    /// as the queues themselves)
+    Mem(MemWrite),
+    /// Submit a raw command on the admin queue
+    Admin([u8; SQE_LEN]),
+    /// Submit a raw command on the I/O queue
+    Io([u8; SQE_LEN]),
+    /// Consume any completions posted by the controller
+    Reap,
+    /// Access a controller register or doorbell
+    Read {
+        off: u16,
+        width: Width,
+    },
+    Write {
+        off: u16,
+        width: Width,
+        val: u32,
+    },
+}
+
+/// The guest's side of a submission/completion queue pair
+struct QueuePair {
+    qid: u16,
+    sq_base: GuestAddr,
+    sq_tail: u16,
+    cq: HostCompQueue,
+}
+impl QueuePair {
+    fn new(tm: &TestMachine, qid: u16) -> Self {
+        let sq_base = tm.alloc(usize::from(QUEUE_SIZE) * SQE_LEN, PAGE_SIZE);
+        let cq_base = tm.alloc(usize::from(QUEUE_SIZE) * CQE_LEN, PAGE_SIZE);
+        let cq = HostCompQueue::new(&tm.mem(), cq_base, QUEUE_SIZE);
+        Self { qid, sq_base, sq_tail: 0, cq }
+    }
+
+    fn sq_doorbell(&self) -> u64 {
+        REG_DOORBELLS + 8 * u64::from(self.qid)
+    }
+
+    fn cq_doorbell(&self) -> u64 {
+        self.sq_doorbell() + 4
+    }
+
+    /// Places `sqe` at the tail of the submission queue and rings its
+    /// doorbell.  Whether the queue has room is left for the controller to
+    /// sort out.
+    fn submit(&mut self, tm: &TestMachine, sqe: &[u8; SQE_LEN]) {
+        let off = usize::from(self.sq_tail) * SQE_LEN;
+        tm.mem().write_many(GuestAddr(self.sq_base.0 + off as u64), &sqe[..]);
+        self.sq_tail = (self.sq_tail + 1) % QUEUE_SIZE;
+        write(tm, self.sq_doorbell(), 4, u64::from(self.sq_tail));
+    }
+
+    fn reap(&mut self, tm: &TestMachine) {
+        let mut popped = false;
+        while self.cq.pop(&tm.mem()).is_some() {
+            popped = true;
+        }
+        if popped {
+            write(tm, self.cq_doorbell(), 4, u64::from(self.cq.head()));
+        }
+    }
+}
+
+fn write(tm: &TestMachine, reg: u64, bytes: u8, val: u64) {
+    let _ = tm.mmio_write(REGS_BAR + reg, bytes, val);
+}
+
+/// Sets up the admin queues and enables the controller, then creates an I/O
+/// queue pair.  The controller is driven by the fuzzer from there.
+fn ctrl_init(tm: &TestMachine) -> (QueuePair, QueuePair) {
+    let mut admin = QueuePair::new(tm, 0);
+    let qsize = u64::from(QUEUE_SIZE - 1);
+    write(tm, REG_AQA, 4, (qsize << 16) | qsize);
+    write(tm, REG_ASQ, 8, admin.sq_base.0);
+    write(tm, REG_ACQ, 8, admin.cq.base().0);
+    let cc = CC_EN | (6 << CC_IOSQES_SHIFT) | (4 << CC_IOCQES_SHIFT);
+    write(tm, REG_CC, 4, u64::from(cc));
+
+    // Admin commands are processed synchronously with the doorbell write
+    let io = QueuePair::new(tm, IO_QID);
+    let qsize = u32::from(QUEUE_SIZE - 1);
+    let create_cq = Command {
+        opcode: ADMIN_CREATE_IO_CQ,
+        prp1: io.cq.base().0,
+        cdw10: (qsize << 16) | u32::from(IO_QID),
+        cdw11: 0b1,
+        ..Default::default()
+    };
+    admin.submit(tm, &create_cq.to_bytes());
+    let create_sq = Command {
+        opcode: ADMIN_CREATE_IO_SQ,
+        cid: 1,
+        prp1: io.sq_base.0,
+        cdw10: (qsize << 16) | u32::from(IO_QID),
+        cdw11: (u32::from(IO_QID) << 16) | 0b1,
+        ..Default::default()
+    };
+    admin.submit(tm, &create_sq.to_bytes());
+    admin.reap(tm);
+
+    (admin, io)
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let tm = propolis_fuzz::machine();
+    let bus = tm.pci_bus();
+
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let dev = PciNvme::create("propolis-fuzz".to_string(), None, log);
+    let backend = propolis_fuzz::attach_disk(dev.clone(), DISK_BLOCKS);
+    bus.attach(pci::BusLocation::new(5, 0).unwrap(), dev.clone(), None);
+    propolis::testing::pci_init(
+        &*dev,
+        &[(0, REGS_BAR as u32), (1, 0), (4, MSIX_BAR as u32)],
+    );
+
+    let (mut admin, mut io) = ctrl_init(&tm);
+    for op in ops.iter() {
+        match op {
+            Op::Mem(mw) => mw.apply(&tm),
+            Op::Admin(sqe) => admin.submit(&tm, sqe),
+            Op::Io(sqe) => io.submit(&tm, sqe),
+            Op::Reap => {
+                admin.reap(&tm);
+                io.reap(&tm);
+            }
+            Op::Read { off, width } => {
+                let off = u64::from(width.align(off % REGS_LEN));
+                let _ = tm.mmio_read(REGS_BAR + off, width.bytes());
+            }
+            Op::Write { off, width, val } => {
+                let off = u64::from(width.align(off % REGS_LEN));
+                write(&tm, off, width.bytes(), u64::from(*val));
+            }
+        }
+    }
+
+    propolis_fuzz::quiesce(&*dev, &backend);
+});
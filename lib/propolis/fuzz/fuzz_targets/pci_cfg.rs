@@ -0,0 +1,84 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Issues arbitrary config space accesses to PCI devices, exercising the
+//! standard header (including BAR placement) and capabilities such as MSI-X.
+
+#![no_main]
+
+use std::sync::Arc;
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use propolis::hw::nvme::PciNvme;
+use propolis::hw::pci;
+use propolis::hw::virtio::PciVirtioBlock;
+use propolis::lifecycle::Lifecycle;
+use propolis::testing::{pci_cfg_read, pci_cfg_write};
+use propolis_fuzz::Width;
+
+/// Extent of (extended) config space
+const CFG_LEN: u16 = pci::bits::LEN_CFG_ECAM as u16;
+
+#[derive(Arbitrary, Debug)]
+enum Kind {
+    Nvme,
+    VirtioBlock,
+}
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Read {
+        off: u16,
+        width: Width,
+    },
+    Write {
+        off: u16,
+        width: Width,
+        val: u32,
+    },
+    /// Reset the device, as a reboot of the instance would
+    Reset,
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    kind: Kind,
+    ops: Vec<Op>,
+}
+
+fuzz_target!(|input: Input| {
+    let tm = propolis_fuzz::machine();
+    let bus = tm.pci_bus();
+
+    let (dev, lifecycle): (Arc<dyn pci::Endpoint>, Arc<dyn Lifecycle>) =
+        match input.kind {
+            Kind::Nvme => {
+                let log = slog::Logger::root(slog::Discard, slog::o!());
+                let dev =
+                    PciNvme::create("propolis-fuzz".to_string(), None, log);
+                (dev.clone(), dev)
+            }
+            Kind::VirtioBlock => {
+                let dev = PciVirtioBlock::new(16);
+                (dev.clone(), dev)
+            }
+        };
+    bus.attach(pci::BusLocation::new(4, 0).unwrap(), dev.clone(), None);
+
+    for op in input.ops.iter() {
+        match op {
+            Op::Read { off, width } => {
+                let off = width.align(off % CFG_LEN);
+                pci_cfg_read(&*dev, usize::from(off), width.bytes());
+            }
+            Op::Write { off, width, val } => {
+                let off = width.align(off % CFG_LEN);
+                pci_cfg_write(&*dev, usize::from(off), width.bytes(), *val);
+            }
+            Op::Reset => lifecycle.reset(),
+        }
+    }
+});
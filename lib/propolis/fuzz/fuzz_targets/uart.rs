@@ -0,0 +1,74 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Accesses the register file of a 16550 UART in an arbitrary order, with
+//! traffic from the host side of the serial console interleaved.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use propolis::chardev::{Sink, Source};
+use propolis::hw::uart::LpcUart;
+use propolis::intr_pins::NoOpPin;
+use propolis::lifecycle::Lifecycle;
+use propolis_fuzz::Width;
+
+const COM1: u16 = 0x3f8;
+const REGS_LEN: u16 = 8;
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    /// Guest accesses to the UART registers
+    Read {
+        off: u16,
+        width: Width,
+    },
+    Write {
+        off: u16,
+        width: Width,
+        val: u32,
+    },
+    /// Host-side console traffic
+    HostWrite(u8),
+    HostRead,
+    Discard(u8),
+    AutoDiscard(bool),
+    Pause,
+    Resume,
+    Reset,
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let tm = propolis_fuzz::machine();
+    let uart = LpcUart::new(Box::new(NoOpPin {}));
+    uart.attach(&tm.machine().bus_pio, COM1);
+
+    for op in ops.iter() {
+        match op {
+            Op::Read { off, width } => {
+                let off = width.align(off % REGS_LEN);
+                let _ = tm.pio_read(COM1 + off, width.bytes());
+            }
+            Op::Write { off, width, val } => {
+                let off = width.align(off % REGS_LEN);
+                let _ = tm.pio_write(COM1 + off, width.bytes(), *val);
+            }
+            Op::HostWrite(data) => {
+                Sink::write(&*uart, *data);
+            }
+            Op::HostRead => {
+                Source::read(&*uart);
+            }
+            Op::Discard(count) => {
+                uart.discard(usize::from(*count));
+            }
+            Op::AutoDiscard(active) => uart.set_autodiscard(*active),
+            Op::Pause => uart.pause(),
+            Op::Resume => uart.resume(),
+            Op::Reset => Lifecycle::reset(&*uart),
+        }
+    }
+});
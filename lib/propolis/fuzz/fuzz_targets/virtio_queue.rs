@@ -0,0 +1,104 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Feeds arbitrary descriptor chains (and register accesses) to a virtio-block
+//! device, exercising `VirtQueue` parsing of the rings in guest memory.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use propolis::common::PAGE_SIZE;
+use propolis::hw::pci;
+use propolis::hw::virtio::PciVirtioBlock;
+use propolis_fuzz::{MemWrite, Width};
+
+const IO_BAR: u16 = 0xc000;
+const MSIX_BAR: u32 = 0xc000_0000;
+/// Size of the legacy register window, including device config
+const IO_BAR_LEN: u16 = 0x40;
+
+const QUEUE_SIZE: u16 = 16;
+const DISK_BLOCKS: usize = 16;
+
+// Legacy register offsets, relative to the I/O BAR
+const REG_FEAT_DRIVER: u16 = 0x04;
+const REG_QUEUE_PFN: u16 = 0x08;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_STATUS: u16 = 0x12;
+
+const STATUS_ACK: u32 = 1 << 0;
+const STATUS_DRIVER: u32 = 1 << 1;
+const STATUS_DRIVER_OK: u32 = 1 << 2;
+const STATUS_FEATURES_OK: u32 = 1 << 3;
+
+#[derive(Arbitrary, Debug)]
+enum Op {
+    /// Rewrite part of RAM, which holds the queue and anything it refers to
+    Mem(MemWrite),
+    /// Notify the device that the queue has been updated
+    Notify,
+    /// Access a legacy transport register
+    Read {
+        off: u16,
+        width: Width,
+    },
+    Write {
+        off: u16,
+        width: Width,
+        val: u32,
+    },
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    features: u32,
+    ops: Vec<Op>,
+}
+
+fuzz_target!(|input: Input| {
+    let tm = propolis_fuzz::machine();
+    let bus = tm.pci_bus();
+
+    let dev = PciVirtioBlock::new(QUEUE_SIZE);
+    let backend = propolis_fuzz::attach_disk(dev.clone(), DISK_BLOCKS);
+    bus.attach(pci::BusLocation::new(4, 0).unwrap(), dev.clone(), None);
+    propolis::testing::pci_init(
+        &*dev,
+        &[(0, u32::from(IO_BAR)), (1, MSIX_BAR)],
+    );
+
+    // Bring the device up with its queue at the start of RAM, so that the
+    // fuzzer need only fill in the rings.  It remains free to move the queue,
+    // or reset the device, through the registers.
+    let write = |reg: u16, bytes: u8, val: u32| {
+        let _ = tm.pio_write(IO_BAR + reg, bytes, val);
+    };
+    write(REG_STATUS, 1, STATUS_ACK | STATUS_DRIVER);
+    write(REG_FEAT_DRIVER, 4, input.features);
+    write(REG_STATUS, 1, STATUS_ACK | STATUS_DRIVER | STATUS_FEATURES_OK);
+    write(REG_QUEUE_PFN, 4, (tm.ram_base().0 / PAGE_SIZE as u64) as u32);
+    write(
+        REG_STATUS,
+        1,
+        STATUS_ACK | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK,
+    );
+
+    for op in input.ops.iter() {
+        match op {
+            Op::Mem(mw) => mw.apply(&tm),
+            Op::Notify => write(REG_QUEUE_NOTIFY, 2, 0),
+            Op::Read { off, width } => {
+                let off = width.align(off % IO_BAR_LEN);
+                let _ = tm.pio_read(IO_BAR + off, width.bytes());
+            }
+            Op::Write { off, width, val } => {
+                write(width.align(off % IO_BAR_LEN), width.bytes(), *val);
+            }
+        }
+    }
+
+    propolis_fuzz::quiesce(&*dev, &backend);
+});
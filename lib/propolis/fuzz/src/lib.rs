@@ -0,0 +1,120 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Pieces shared by the fuzz targets.
+//!
+//! Each target attaches a device to a [TestMachine] and then lets the fuzzer
+//! play the part of the guest: scribbling over RAM and accessing the device's
+//! registers in whatever order it pleases.  Any panic is a bug, since a guest
+//! able to provoke one can take down the whole instance.
+//!
+//! Devices reach guest memory only through [MemCtx](propolis::vmm::MemCtx),
+//! which refuses accesses falling outside of RAM.  Were an access to escape
+//! those checks, it would land outside the mapping backing the test machine
+//! and fault, which libFuzzer reports as a crash just as it does panics.
+
+use std::num::NonZeroUsize;
+use std::sync::{Arc, OnceLock};
+
+use arbitrary::Arbitrary;
+use tokio::runtime::Runtime;
+
+use propolis::block::{self, Backend};
+use propolis::common::GuestAddr;
+use propolis::lifecycle::Lifecycle;
+use propolis::testing::{Builder, TestMachine};
+
+/// RAM given to each test machine: small enough that fuzzer-chosen offsets
+/// frequently land within it.
+pub const RAM_SIZE: usize = 64 * 1024;
+
+/// Block size of the disks attached to storage devices
+pub const BLOCK_SZ: usize = 512;
+
+/// Width of a register access
+#[derive(Arbitrary, Copy, Clone, Debug)]
+pub enum Width {
+    Byte,
+    Word,
+    Dword,
+}
+impl Width {
+    pub fn bytes(self) -> u8 {
+        match self {
+            Width::Byte => 1,
+            Width::Word => 2,
+            Width::Dword => 4,
+        }
+    }
+
+    /// Rounds `off` down to be naturally aligned for an access of this width
+    pub fn align(self, off: u16) -> u16 {
+        off & !(u16::from(self.bytes()) - 1)
+    }
+}
+
+/// Fuzzer-provided contents for a region of RAM
+#[derive(Arbitrary, Debug)]
+pub struct MemWrite {
+    /// Offset from the start of RAM, wrapped to fit within it
+    pub off: u16,
+    pub data: Vec<u8>,
+}
+impl MemWrite {
+    /// Writes the data into RAM, truncating anything which would run off its
+    /// end.
+    pub fn apply(&self, tm: &TestMachine) {
+        let off = usize::from(self.off) % tm.ram_size();
+        let len = self.data.len().min(tm.ram_size() - off);
+        let addr = GuestAddr(tm.ram_base().0 + off as u64);
+        tm.mem().write_many(addr, &self.data[..len]);
+    }
+}
+
+/// A runtime on which block backends run their workers, shared by all
+/// iterations of a target.
+pub fn runtime() -> &'static Runtime {
+    static RT: OnceLock<Runtime> = OnceLock::new();
+    RT.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("fuzz runtime built")
+    })
+}
+
+/// Builds a test machine with [RAM_SIZE] bytes of RAM.
+pub fn machine() -> TestMachine {
+    Builder::new().ram_size(RAM_SIZE).build().expect("test machine built")
+}
+
+/// Creates an in-memory disk of `blocks` blocks, and attaches it to `dev`.
+pub fn attach_disk(
+    dev: Arc<dyn block::Device>,
+    blocks: usize,
+) -> Arc<block::InMemoryBackend> {
+    let backend = block::InMemoryBackend::create(
+        vec![0; blocks * BLOCK_SZ],
+        block::BackendOpts {
+            block_size: Some(BLOCK_SZ as u32),
+            ..Default::default()
+        },
+        NonZeroUsize::new(1).unwrap(),
+    )
+    .expect("in-memory backend created");
+    block::attach(dev, backend.clone()).expect("backend attached");
+    runtime().block_on(backend.start()).expect("backend started");
+    backend
+}
+
+/// Waits for `dev` to finish with any requests the fuzzer left outstanding,
+/// then stops its disk, so that nothing from this iteration outlives it.
+pub fn quiesce(dev: &dyn Lifecycle, backend: &block::InMemoryBackend) {
+    dev.pause();
+    runtime().block_on(async {
+        dev.paused().await;
+        backend.stop().await;
+    });
+    dev.halt();
+}
@@ -17,18 +17,38 @@ pub type SourceNotifier = Box<dyn Fn(&dyn Source) + Send + Sync + 'static>;
 pub type BlockingSourceConsumer = Box<dyn Fn(&[u8]) + Send + Sync + 'static>;
 
 pub trait Sink: Send + Sync + 'static {
-    // XXX: make this slice based
     fn write(&self, data: u8) -> bool;
 
+    /// Write as much of `data` as the sink will accept, returning the number of
+    /// bytes written.  Sinks which can accept data in bulk should override the
+    /// default, which writes a byte at a time.
+    fn write_bytes(&self, data: &[u8]) -> usize {
+        data.iter().take_while(|b| self.write(**b)).count()
+    }
+
     /// Set notifier callback for when sink becomes writable.  If that callback acquires any
     /// exclusion resources (locks, etc), they must not be held setting the notifier.
     fn set_notifier(&self, f: Option<SinkNotifier>);
 }
 
 pub trait Source: Send + Sync + 'static {
-    // XXX: make this slice based
     fn read(&self) -> Option<u8>;
 
+    /// Read as much data as is available and fits in `buf`, returning the
+    /// number of bytes read.  Sources which can yield data in bulk should
+    /// override the default, which reads a byte at a time.
+    fn read_bytes(&self, buf: &mut [u8]) -> usize {
+        let mut count = 0;
+        for slot in buf.iter_mut() {
+            match self.read() {
+                Some(b) => *slot = b,
+                None => break,
+            }
+            count += 1;
+        }
+        count
+    }
+
     fn discard(&self, count: usize) -> usize;
     fn set_autodiscard(&self, active: bool);
     /// Set notifier callback for when source becomes readable.  If that callback acquires any
@@ -142,10 +142,7 @@ impl SourceBuffer {
         let mut copied = copy_and_consume(&mut inner.buf, buf);
         // Can also attempt to read direct from the Source
         if copied < buf.len() {
-            if let Some(b) = source.read() {
-                buf[copied] = b;
-                copied += 1;
-            }
+            copied += source.read_bytes(&mut buf[copied..]);
         }
         inner.last_poll = Some(Instant::now());
         copied
@@ -155,9 +152,13 @@ impl SourceBuffer {
         if self.poll_active.load(Ordering::Acquire) {
             let mut inner = self.inner.lock().unwrap();
             if !inner.is_full() {
-                if let Some(c) = source.read() {
-                    inner.buf.push(c);
-                }
+                // Fill the buffer as far as the Source allows, without
+                // growing it beyond its capacity.
+                let len = inner.buf.len();
+                let cap = inner.buf.capacity();
+                inner.buf.resize(cap, 0);
+                let nread = source.read_bytes(&mut inner.buf[len..]);
+                inner.buf.truncate(len + nread);
                 // If the buffer is not full and polling is still active, elide
                 // the notification to the Source consumer.
                 if !inner.is_full() && self.poll_active.load(Ordering::Acquire)
@@ -240,14 +241,8 @@ impl SinkBuffer {
                 // If the buffer started empty, try to kick the sink into
                 // accepting data.
                 if inner.buf.is_empty() {
-                    while !data.is_empty() {
-                        if sink.write(data[0]) {
-                            data = &data[1..];
-                            nwritten += 1;
-                        } else {
-                            break;
-                        }
-                    }
+                    nwritten = sink.write_bytes(data);
+                    data = &data[nwritten..];
                 }
 
                 // Push whatever is left into the buffer
@@ -274,11 +269,12 @@ impl SinkBuffer {
 
     fn notify(&self, sink: &dyn Sink) {
         let mut inner = self.inner.lock().unwrap();
-        while let Some(c) = inner.buf.pop_front() {
-            if !sink.write(c) {
-                inner.buf.push_front(c);
+        while !inner.buf.is_empty() {
+            let nwritten = sink.write_bytes(inner.buf.as_slices().0);
+            if nwritten == 0 {
                 break;
             }
+            inner.buf.drain(..nwritten);
         }
         if inner.buf.is_empty() || !inner.wait_empty {
             self.notify.notify_one();
@@ -617,6 +613,24 @@ mod test {
         assert_eq!(output[1], 0x0B);
     }
 
+    #[tokio::test]
+    async fn read_bytes_bulk() {
+        let uart = Arc::new(TestUart::new(8, 8));
+        let rpoll = SourceBuffer::new(Params::test_defaults());
+        rpoll.attach(uart.as_ref());
+
+        // If the guest writes several bytes, but the source notifies once...
+        for b in 0..6 {
+            uart.push_source(b);
+        }
+        uart.notify_source().await;
+
+        let mut output = [0u8; 16];
+        // ... We can read them all at once.
+        assert_eq!(6, rpoll.read(&mut output, uart.as_ref()).await.unwrap());
+        assert_eq!(output[..6], [0, 1, 2, 3, 4, 5]);
+    }
+
     #[tokio::test]
     async fn read_bytes_blocking() {
         let uart = Arc::new(TestUart::new(4, 4));
@@ -15,6 +15,7 @@ use crate::chardev::{pollers, Sink, Source};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf, SocketAddr};
 use tokio::net::UnixListener;
+use tokio::task::JoinHandle;
 
 const BUF_SIZE: usize = 512;
 const POLL_INTERVAL_MS: usize = 10;
@@ -61,19 +62,21 @@ impl UDSock {
 
         Ok(this)
     }
+    /// Spawns a task relaying data between `sink`/`source` and clients of
+    /// the socket, one client at a time.  Aborting the returned task stops
+    /// the relay.
     pub fn spawn(
         self: &Arc<Self>,
         sink: Arc<dyn Sink>,
         source: Arc<dyn Source>,
-    ) {
+    ) -> JoinHandle<()> {
         self.sink_buf.attach(sink.as_ref());
         self.source_buf.attach(source.as_ref());
 
         let this = Arc::clone(self);
-        let _task = tokio::spawn(async move {
+        tokio::spawn(async move {
             let _ = this.run(sink, source).await;
-            todo!("get async task hdl");
-        });
+        })
     }
 
     fn notify_connected(&self, addr: Option<SocketAddr>) {
@@ -131,6 +134,10 @@ impl UDSock {
         let mut buf = [0u8; BUF_SIZE];
         loop {
             let num = readh.read(&mut buf).await?;
+            if num == 0 {
+                // The client hung up
+                return Ok(());
+            }
             sink_buf.write(&buf[..num], sink).await;
         }
     }
@@ -0,0 +1,169 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Recording of the exits taken by vCPUs, so that they may be replayed against
+//! emulated devices later.
+//!
+//! A trace is stored as JSON lines: one [TraceRecord] per line, in the order
+//! the exits were processed.  Being line-oriented, a trace which was cut short
+//! (by the instance crashing, for example) remains readable up to its last
+//! complete record.
+
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Error, ErrorKind, Result, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::exits::{VmEntry, VmExit};
+
+/// An exit taken by a vCPU, and how propolis handled it
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+pub struct TraceRecord {
+    /// The vCPU which took the exit
+    pub vcpuid: i32,
+    pub exit: VmExit,
+    /// The entry emitted by [Vcpu::process_vmexit](crate::vcpu::Vcpu), or
+    /// `None` if the exit was left to its caller.
+    pub entry: Option<VmEntry>,
+}
+
+/// Writes [TraceRecord]s to a trace as they are made.  It may be shared
+/// between the threads driving each vCPU.
+pub struct ExitRecorder {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+impl ExitRecorder {
+    /// Records to `out`.
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self { out: Mutex::new(Box::new(out)) }
+    }
+
+    /// Records to a file at `path`, replacing any existing contents.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Records that vCPU `vcpuid` took `exit`, which was handled by emitting
+    /// `entry`.
+    pub fn record(
+        &self,
+        vcpuid: i32,
+        exit: &VmExit,
+        entry: Option<&VmEntry>,
+    ) -> Result<()> {
+        let rec = TraceRecord { vcpuid, exit: *exit, entry: entry.copied() };
+        let mut line = serde_json::to_vec(&rec)?;
+        line.push(b'\n');
+
+        let mut out = self.out.lock().unwrap();
+        out.write_all(&line)
+    }
+
+    /// Flushes any buffered records to the trace.
+    pub fn flush(&self) -> Result<()> {
+        self.out.lock().unwrap().flush()
+    }
+}
+impl Drop for ExitRecorder {
+    fn drop(&mut self) {
+        let _ = self.out.get_mut().unwrap().flush();
+    }
+}
+
+/// Reads all of the complete records in a trace.  Every record is written
+/// with a trailing newline, so an unterminated last line is what remains of a
+/// record cut short, and is ignored.
+pub fn read_trace(mut input: impl BufRead) -> Result<Vec<TraceRecord>> {
+    let mut records = Vec::new();
+    let mut line = String::new();
+    for idx in 1.. {
+        line.clear();
+        if input.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+            break;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        let rec = serde_json::from_str(&line).map_err(|e| {
+            Error::new(ErrorKind::InvalidData, format!("trace line {idx}: {e}"))
+        })?;
+        records.push(rec);
+    }
+    Ok(records)
+}
+
+/// Reads all of the records in the trace file at `path`.
+pub fn read_trace_file(path: impl AsRef<Path>) -> Result<Vec<TraceRecord>> {
+    read_trace(std::io::BufReader::new(File::open(path)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::exits::{InoutReq, InoutRes, IoPort, VmExitKind};
+
+    /// A `Write` whose contents remain accessible after it is handed off
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let buf = SharedBuf::default();
+        let rec = ExitRecorder::new(buf.clone());
+
+        let port = IoPort { port: 0x3f8, bytes: 1 };
+        let exit = VmExit {
+            rip: 0xfff0,
+            inst_len: 1,
+            kind: VmExitKind::Inout(InoutReq::In(port)),
+        };
+        let entry = VmEntry::InoutFulfill(InoutRes::In(port, 0x5a));
+        rec.record(0, &exit, Some(&entry)).unwrap();
+        rec.record(1, &VmExit::default(), None).unwrap();
+        drop(rec);
+
+        let data = buf.0.lock().unwrap().clone();
+        let trace = read_trace(&data[..]).unwrap();
+        assert_eq!(trace.len(), 2);
+
+        assert_eq!(trace[0].vcpuid, 0);
+        assert_eq!(trace[0].exit.rip, 0xfff0);
+        assert!(matches!(
+            trace[0].exit.kind,
+            VmExitKind::Inout(InoutReq::In(IoPort { port: 0x3f8, bytes: 1 }))
+        ));
+        assert_eq!(trace[0].entry, Some(entry));
+
+        assert_eq!(trace[1].vcpuid, 1);
+        assert!(matches!(trace[1].exit.kind, VmExitKind::Bogus));
+        assert_eq!(trace[1].entry, None);
+    }
+
+    #[test]
+    fn truncated_trace() {
+        let line = r#"{"vcpuid":0,"exit":{"rip":0,"inst_len":0,"kind":"Bogus"},"entry":"Run"}"#;
+        let data = format!("{line}\n{}", &line[..20]);
+        let trace = read_trace(data.as_bytes()).unwrap();
+        assert_eq!(trace.len(), 1);
+        let trace = read_trace(format!("{line}\n\n").as_bytes()).unwrap();
+        assert_eq!(trace.len(), 1);
+
+        // A damaged record is still an error when it isn't the last.
+        let data = format!("{}\n{line}\n", &line[..20]);
+        assert!(read_trace(data.as_bytes()).is_err());
+    }
+}
@@ -7,12 +7,15 @@
 use std::os::raw::c_void;
 use std::time::Duration;
 
+use serde::{Deserialize, Serialize};
+
 use bhyve_api::{
     vm_entry, vm_entry_cmds, vm_entry_payload, vm_exit, vm_exitcode,
     vm_suspend_how,
 };
 
 /// Describes the reason for exiting execution of a vCPU.
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct VmExit {
     /// The instruction pointer of the guest at the time of exit.
     pub rip: u64,
@@ -37,43 +40,43 @@ impl VmExit {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct IoPort {
     pub port: u16,
     pub bytes: u8,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub enum InoutReq {
     In(IoPort),
     Out(IoPort, u32),
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct MmioReadReq {
     pub addr: u64,
     pub bytes: u8,
 }
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct MmioWriteReq {
     pub addr: u64,
     pub data: u64,
     pub bytes: u8,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub enum MmioReq {
     Read(MmioReadReq),
     Write(MmioWriteReq),
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct SvmDetail {
     pub exit_code: u64,
     pub info1: u64,
     pub info2: u64,
 }
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct VmxDetail {
     pub status: i32,
     pub exit_reason: u32,
@@ -93,7 +96,7 @@ impl From<&bhyve_api::vm_exit_vmx> for VmxDetail {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct InstEmul {
     pub inst_data: [u8; 15],
     pub len: u8,
@@ -113,20 +116,20 @@ impl From<&bhyve_api::vm_inst_emul> for InstEmul {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub enum Suspend {
     Halt,
     Reset,
     TripleFault(i32),
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub struct SuspendDetail {
     pub kind: Suspend,
     pub when: Duration,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
 pub enum VmExitKind {
     Bogus,
     Inout(InoutReq),
@@ -331,6 +334,7 @@ impl VmExitKind {
     }
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub enum InoutRes {
     In(IoPort, u32),
     Out(IoPort),
@@ -346,16 +350,19 @@ impl InoutRes {
     }
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct MmioReadRes {
     pub addr: u64,
     pub data: u64,
     pub bytes: u8,
 }
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct MmioWriteRes {
     pub addr: u64,
     pub bytes: u8,
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub enum MmioRes {
     Read(MmioReadRes),
     Write(MmioWriteRes),
@@ -378,6 +385,7 @@ impl MmioRes {
     }
 }
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub enum VmEntry {
     Run,
     InoutFulfill(InoutRes),
@@ -117,16 +117,13 @@ pub mod migrate {
 
     impl AtPitV1 {
         pub(super) fn read(hdl: &vmm::VmmHdl) -> std::io::Result<Self> {
-            let vdi = hdl
-                .data_op(bhyve_api::VDC_ATPIT, 1)
-                .read::<bhyve_api::vdi_atpit_v1>()?;
+            let vdi = hdl.read_device_state::<bhyve_api::vdi_atpit_v1>()?;
 
             Ok(vdi.into())
         }
 
         pub(super) fn write(self, hdl: &vmm::VmmHdl) -> std::io::Result<()> {
-            hdl.data_op(bhyve_api::VDC_ATPIT, 1)
-                .write::<bhyve_api::vdi_atpit_v1>(&self.into())?;
+            hdl.write_device_state::<bhyve_api::vdi_atpit_v1>(self.into())?;
 
             Ok(())
         }
@@ -104,9 +104,7 @@ pub mod migrate {
     }
     impl PmTimerV1 {
         pub(super) fn read(hdl: &vmm::VmmHdl) -> std::io::Result<Self> {
-            let vdi = hdl
-                .data_op(bhyve_api::VDC_PM_TIMER, 1)
-                .read::<bhyve_api::vdi_pm_timer_v1>()?;
+            let vdi = hdl.read_device_state::<bhyve_api::vdi_pm_timer_v1>()?;
 
             Ok(Self {
                 // vdi_pm_timer_v1 also carries the ioport to which the pmtimer
@@ -122,7 +120,7 @@ pub mod migrate {
                 // The IO-port field is ignored for writes
                 vpt_ioport: 0,
             };
-            hdl.data_op(bhyve_api::VDC_PM_TIMER, 1).write(&vdi)?;
+            hdl.write_device_state(vdi)?;
             Ok(())
         }
     }
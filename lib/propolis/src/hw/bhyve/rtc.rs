@@ -169,16 +169,13 @@ pub mod migrate {
 
     impl BhyveRtcV2 {
         pub(super) fn read(hdl: &vmm::VmmHdl) -> std::io::Result<Self> {
-            let vdi = hdl
-                .data_op(bhyve_api::VDC_RTC, 2)
-                .read::<bhyve_api::vdi_rtc_v2>()?;
+            let vdi = hdl.read_device_state::<bhyve_api::vdi_rtc_v2>()?;
 
             Ok(vdi.into())
         }
 
         pub(super) fn write(self, hdl: &vmm::VmmHdl) -> std::io::Result<()> {
-            hdl.data_op(bhyve_api::VDC_RTC, 2)
-                .write::<bhyve_api::vdi_rtc_v2>(&self.into())?;
+            hdl.write_device_state::<bhyve_api::vdi_rtc_v2>(self.into())?;
 
             Ok(())
         }
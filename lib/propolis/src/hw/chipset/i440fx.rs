@@ -102,8 +102,6 @@ struct IrqConfig {
 
     lnk_pins: [Arc<LNKPin>; 4],
 
-    #[allow(unused)]
-    // XXX: wire up SCI notifications
     sci_pin: Arc<LNKPin>,
 }
 impl IrqConfig {
@@ -450,6 +448,11 @@ impl Piix3Lpc {
             .pin_handle(irq)
             .map(|pin| Box::new(pin) as Box<dyn IntrPin>)
     }
+
+    /// Yields the pin on which the ACPI PM device raises SCIs.
+    pub fn sci_pin(&self) -> Arc<dyn IntrPin> {
+        Arc::clone(&self.irq_config.sci_pin) as Arc<dyn IntrPin>
+    }
 }
 impl pci::Device for Piix3Lpc {
     fn device_state(&self) -> &pci::DeviceState {
@@ -734,6 +737,12 @@ impl PMRegs {
     fn reset(&mut self) {
         *self = Self::default();
     }
+    /// Indicates whether an enabled event is pending and the guest has asked
+    /// for such events to be signaled by SCI.
+    fn sci_pending(&self) -> bool {
+        self.pm_ctrl.contains(PmCntrl::SCI_EN)
+            && (self.pm_status.bits() & self.pm_ena.bits()) != 0
+    }
     fn pmtimer_port(&self) -> u16 {
         self.pm_base.checked_add(PM_TMR_OFFSET).unwrap()
     }
@@ -787,12 +796,14 @@ pub struct Piix3PM {
 
     regs: Mutex<PMRegs>,
     power_pin: Arc<dyn IntrPin>,
+    sci_pin: Arc<dyn IntrPin>,
     log: slog::Logger,
 }
 impl Piix3PM {
     pub fn create(
         hdl: Arc<VmmHdl>,
         power_pin: Arc<dyn IntrPin>,
+        sci_pin: Arc<dyn IntrPin>,
         log: slog::Logger,
     ) -> Arc<Self> {
         let pci_state = pci::Builder::new(pci::Ident {
@@ -820,10 +831,28 @@ impl Piix3PM {
 
             regs: Mutex::new(regs),
             power_pin,
+            sci_pin,
             log,
         })
     }
 
+    /// Presses the ACPI power button, raising an SCI if the guest has enabled
+    /// power button events.
+    ///
+    /// Returns `false` if the guest has not enabled them, in which case it is
+    /// not expected to respond to the press.
+    pub fn press_power_button(&self) -> bool {
+        let mut regs = self.regs.lock().unwrap();
+        regs.pm_status.insert(PmSts::PWRBTN_STS);
+        self.update_sci(&regs);
+        regs.pm_ctrl.contains(PmCntrl::SCI_EN)
+            && regs.pm_ena.contains(PmEn::PWRBTN_EN)
+    }
+
+    fn update_sci(&self, regs: &PMRegs) {
+        self.sci_pin.set_state(regs.sci_pending());
+    }
+
     pub fn attach(self: &Arc<Self>, pio: &PioBus) {
         // XXX: static registration for now
         let this = Arc::clone(&self);
@@ -938,9 +967,11 @@ impl Piix3PM {
                 let val = PmSts::from_bits_truncate(wo.read_u16());
                 // status bits are W1C
                 regs.pm_status.remove(val);
+                self.update_sci(&regs);
             }
             PmReg::PmEn => {
                 regs.pm_ena = PmEn::from_bits_truncate(wo.read_u16());
+                self.update_sci(&regs);
             }
             PmReg::PmCntrl => {
                 regs.pm_ctrl = PmCntrl::from_bits_truncate(wo.read_u16());
@@ -954,6 +985,7 @@ impl Piix3PM {
                         self.power_pin.pulse();
                     }
                 }
+                self.update_sci(&regs);
             }
             PmReg::PmTmr
             | PmReg::GpSts
@@ -999,6 +1031,7 @@ impl Lifecycle for Piix3PM {
         // allowed, it will need to be more cognizant of the state inside the
         // BhyvePmTimer device.
         self.regs.lock().unwrap().reset();
+        self.sci_pin.deassert();
 
         self.pmtimer.reset();
     }
@@ -1036,6 +1069,9 @@ impl MigrateMulti for Piix3PM {
         let data: migrate::Piix3PmV1 = offer.take()?;
         let xlated_regs: PMRegs = data.try_into()?;
 
+        // The SCI's level follows from the imported registers, and the kernel
+        // VMM's view of it is imported separately.
+        self.sci_pin.import_state(xlated_regs.sci_pending());
         *self.regs.lock().unwrap() = xlated_regs;
 
         MigrateMulti::import(&self.pci_state, offer, ctx)?;
@@ -1091,7 +1127,7 @@ mod test {
     use crate::hw::pci::device::test::*;
     use crate::hw::pci::test::Scaffold;
     use crate::hw::pci::{self, Endpoint};
-    use crate::intr_pins::NoOpPin;
+    use crate::intr_pins::{FuncPin, NoOpPin};
     use crate::vmm::VmmHdl;
 
     use slog::{Discard, Logger};
@@ -1170,7 +1206,7 @@ mod test {
         let log = Logger::root(Discard, slog::o!());
         let power_pin = Arc::new(NoOpPin {});
 
-        let pm = Piix3PM::create(hdl, power_pin, log);
+        let pm = Piix3PM::create(hdl, power_pin, Arc::new(NoOpPin {}), log);
         let _bus = setup_attach(&scaffold, pm.clone());
 
         cfg_read(pm.as_ref() as &dyn Endpoint);
@@ -1183,9 +1219,43 @@ mod test {
         let log = Logger::root(Discard, slog::o!());
         let power_pin = Arc::new(NoOpPin {});
 
-        let pm = Piix3PM::create(hdl, power_pin, log);
+        let pm = Piix3PM::create(hdl, power_pin, Arc::new(NoOpPin {}), log);
         let _bus = setup_attach(&scaffold, pm.clone());
 
         cfg_write(pm.as_ref() as &dyn Endpoint);
     }
+
+    #[test]
+    fn pm_power_button_raises_sci() {
+        let hdl = Arc::new(VmmHdl::new_test(0).unwrap());
+        let log = Logger::root(Discard, slog::o!());
+        let sci_pin = Arc::new(FuncPin::new(Box::new(|_| {})));
+        let pm =
+            Piix3PM::create(hdl, Arc::new(NoOpPin {}), sci_pin.clone(), log);
+        let pm_write = |offset: usize, val: u16| {
+            let buf = val.to_le_bytes();
+            pm.pio_rw(
+                PMBASE_DEFAULT + offset as u16,
+                RWOp::Write(&mut WriteOp::from_buf(offset, &buf)),
+            );
+        };
+
+        // A guest that hasn't enabled power button events ignores the press.
+        assert!(!pm.press_power_button());
+        assert!(!sci_pin.is_asserted());
+
+        // Enabling them raises the already-pending event.
+        pm_write(2, PmEn::PWRBTN_EN.bits());
+        pm_write(4, PmCntrl::SCI_EN.bits());
+        assert!(sci_pin.is_asserted());
+
+        // Clearing the status lowers the SCI, and pressing again raises it.
+        pm_write(0, PmSts::PWRBTN_STS.bits());
+        assert!(!sci_pin.is_asserted());
+        assert!(pm.press_power_button());
+        assert!(sci_pin.is_asserted());
+
+        pm.reset();
+        assert!(!sci_pin.is_asserted());
+    }
 }
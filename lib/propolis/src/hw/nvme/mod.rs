@@ -21,7 +21,7 @@ use lazy_static::lazy_static;
 use thiserror::Error;
 
 mod admin;
-mod bits;
+pub(crate) mod bits;
 mod cmds;
 mod queue;
 mod requests;
@@ -16,12 +16,24 @@ use crate::pio::{PioBus, PioFn};
 /// future.
 ///
 /// [pvpanic device]: https://www.qemu.org/docs/master/specs/pvpanic.html
-#[derive(Debug)]
 pub struct QemuPvpanic {
     counts: Mutex<PanicCounts>,
+    notifier: Mutex<Option<Box<PanicNotifier>>>,
     log: slog::Logger,
 }
 
+/// A function called each time the guest reports a kernel panic, with whether
+/// the panic is to be handled by the host and by the guest, respectively.
+pub type PanicNotifier = dyn Fn(bool, bool) + Send + Sync + 'static;
+
+impl std::fmt::Debug for QemuPvpanic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("QemuPvpanic")
+            .field("counts", &self.counts)
+            .finish_non_exhaustive()
+    }
+}
+
 /// Counts the number of guest kernel panics reported using the [`QemuPvpanic`]
 /// virtual device.
 #[derive(Copy, Clone, Debug)]
@@ -56,6 +68,7 @@ impl QemuPvpanic {
                 host_handled: 0,
                 guest_handled: 0,
             }),
+            notifier: Mutex::new(None),
             log,
         })
     }
@@ -68,6 +81,11 @@ impl QemuPvpanic {
         pio.register(Self::IOPORT, 1, piofn).unwrap();
     }
 
+    /// Sets a function to be called each time the guest reports a panic.
+    pub fn set_notifier(&self, notifier: Box<PanicNotifier>) {
+        *self.notifier.lock().unwrap() = Some(notifier);
+    }
+
     /// Returns the current panic counts reported by the guest.
     pub fn panic_counts(&self) -> PanicCounts {
         *self.counts.lock().unwrap()
@@ -99,6 +117,11 @@ impl QemuPvpanic {
                 if guest_handled {
                     counts.guest_handled += 1;
                 }
+                drop(counts);
+
+                if let Some(notifier) = self.notifier.lock().unwrap().as_ref() {
+                    notifier(host_handled, guest_handled);
+                }
             }
         }
     }
@@ -115,6 +115,7 @@ pub struct FramebufferSpec {
     pub fourcc: u32,
 }
 
+#[derive(Clone)]
 pub struct FrameSnap {
     pub frame: Frame,
     pub when: Instant,
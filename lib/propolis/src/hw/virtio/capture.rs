@@ -0,0 +1,300 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Packet capture for guest network devices.
+//!
+//! Viona moves packets between the guest's rings and its vNIC entirely in the
+//! kernel, so there is no point in the userspace device through which they
+//! pass.  Instead, a capture opens the vNIC promiscuously through DLPI and
+//! records everything crossing it, telling the directions apart by the guest's
+//! MAC address.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use crate::util::pcapng::{Direction, Writer};
+
+use dladm::dlpi::{Dlpi, Promisc};
+
+/// How often the capture thread checks whether it has been asked to stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const ETHERADDRL: usize = 6;
+
+/// Bounds on the size of a capture.  Once either limit would be exceeded, the
+/// capture stops on its own.
+#[derive(Copy, Clone, Debug)]
+pub struct CaptureLimits {
+    /// Maximum size of the capture file, in bytes
+    pub max_bytes: usize,
+    /// Maximum number of packets to capture, if any
+    pub max_packets: Option<u64>,
+    /// Number of bytes of each packet to retain
+    pub snaplen: u32,
+}
+
+/// A summary of the progress of a capture
+#[derive(Clone, Debug)]
+pub struct CaptureStatus {
+    /// Whether packets are still being captured
+    pub running: bool,
+    /// Number of packets captured
+    pub packets: u64,
+    /// Size of the capture file, in bytes
+    pub bytes: usize,
+    /// Whether the capture stopped because it reached one of its limits
+    pub limit_reached: bool,
+    /// The error which stopped the capture, if any
+    pub error: Option<String>,
+}
+
+/// A packet capture facility for a single network device.
+///
+/// At most one capture runs at a time.  The file from the most recent capture
+/// remains available after it stops, until another is started.
+pub struct PacketCapture {
+    link: String,
+    mac_addr: [u8; ETHERADDRL],
+    session: Mutex<Option<Session>>,
+}
+impl PacketCapture {
+    pub fn new(link: &str, mac_addr: [u8; ETHERADDRL]) -> Self {
+        Self { link: link.to_string(), mac_addr, session: Mutex::new(None) }
+    }
+
+    /// Starts a new capture, discarding the results of any previous one.
+    ///
+    /// Like [PacketCapture::stop], this blocks on the previous capture's
+    /// thread, and should be kept off of async tasks.
+    pub fn start(&self, limits: CaptureLimits) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+        if let Some(s) = session.as_ref() {
+            if s.shared.state.lock().unwrap().running {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("a capture is already running on {}", self.link),
+                ));
+            }
+        }
+        if let Some(old) = session.take() {
+            old.stop();
+        }
+
+        let dlpi = Dlpi::open_raw(&self.link)?;
+        dlpi.promisc_on(Promisc::Phys)?;
+        dlpi.promisc_on(Promisc::Sap)?;
+        dlpi.promisc_on(Promisc::Multi)?;
+
+        let shared = Arc::new(Shared {
+            stop: AtomicBool::new(false),
+            state: Mutex::new(State::new(&self.link, limits)),
+        });
+        let thread_shared = shared.clone();
+        let mac_addr = self.mac_addr;
+        let thread = std::thread::Builder::new()
+            .name(format!("capture {}", self.link))
+            .spawn(move || capture_loop(dlpi, &thread_shared, mac_addr))?;
+
+        *session = Some(Session { shared, thread: Some(thread) });
+        Ok(())
+    }
+
+    /// Stops the running capture, if there is one.
+    ///
+    /// This blocks until the capture thread notices, which may take up to its
+    /// polling interval, so it should be kept off of async tasks.
+    pub fn stop(&self) {
+        let mut session = self.session.lock().unwrap();
+        if let Some(s) = session.as_mut() {
+            s.shared.stop.store(true, Ordering::Release);
+            if let Some(thread) = s.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    /// The status of the most recent capture, if there has been one.
+    pub fn status(&self) -> Option<CaptureStatus> {
+        let session = self.session.lock().unwrap();
+        session.as_ref().map(|s| s.shared.state.lock().unwrap().status())
+    }
+
+    /// A copy of the pcapng file from the most recent capture, if there has
+    /// been one.  A running capture is not interrupted: the copy holds the
+    /// packets captured so far.
+    pub fn data(&self) -> Option<Vec<u8>> {
+        let session = self.session.lock().unwrap();
+        session
+            .as_ref()
+            .map(|s| s.shared.state.lock().unwrap().writer.as_bytes().to_vec())
+    }
+}
+impl Drop for PacketCapture {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct Session {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+impl Session {
+    fn stop(mut self) {
+        self.shared.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+struct Shared {
+    stop: AtomicBool,
+    state: Mutex<State>,
+}
+
+struct State {
+    writer: Writer,
+    limits: CaptureLimits,
+    packets: u64,
+    running: bool,
+    limit_reached: bool,
+    error: Option<String>,
+}
+impl State {
+    fn new(link: &str, limits: CaptureLimits) -> Self {
+        Self {
+            writer: Writer::new(link, limits.snaplen),
+            limits,
+            packets: 0,
+            running: true,
+            limit_reached: false,
+            error: None,
+        }
+    }
+
+    /// Records a packet, unless doing so would exceed the capture's limits,
+    /// in which case the capture is stopped instead.
+    fn record(
+        &mut self,
+        ts: SystemTime,
+        dir: Direction,
+        data: &[u8],
+        orig_len: usize,
+    ) {
+        let full_packets =
+            self.limits.max_packets.is_some_and(|max| self.packets >= max);
+        let full_bytes = self.writer.as_bytes().len()
+            + self.writer.packet_len(data)
+            > self.limits.max_bytes;
+        if full_packets || full_bytes {
+            self.limit_reached = true;
+            self.running = false;
+            return;
+        }
+
+        self.writer.packet(ts, dir, data, orig_len);
+        self.packets += 1;
+    }
+
+    fn status(&self) -> CaptureStatus {
+        CaptureStatus {
+            running: self.running,
+            packets: self.packets,
+            bytes: self.writer.as_bytes().len(),
+            limit_reached: self.limit_reached,
+            error: self.error.clone(),
+        }
+    }
+}
+
+fn capture_loop(dlpi: Dlpi, shared: &Shared, mac_addr: [u8; ETHERADDRL]) {
+    let snaplen = shared.state.lock().unwrap().limits.snaplen;
+    let mut buf = vec![0u8; snaplen as usize];
+
+    while !shared.stop.load(Ordering::Acquire) {
+        let res = dlpi.recv(&mut buf, POLL_INTERVAL);
+        let ts = SystemTime::now();
+
+        let mut state = shared.state.lock().unwrap();
+        match res {
+            Ok(None) => {}
+            Ok(Some((len, orig_len))) => {
+                let data = &buf[..len];
+                state.record(ts, direction(data, &mac_addr), data, orig_len);
+            }
+            Err(e) => {
+                state.error = Some(e.to_string());
+                state.running = false;
+            }
+        }
+        if !state.running {
+            return;
+        }
+    }
+    shared.state.lock().unwrap().running = false;
+}
+
+/// Frames sourced from the guest's MAC address were sent by the guest, and
+/// everything else was bound for it.
+fn direction(frame: &[u8], mac_addr: &[u8; ETHERADDRL]) -> Direction {
+    match frame.get(ETHERADDRL..ETHERADDRL * 2) {
+        Some(src) if src == mac_addr => Direction::Outbound,
+        _ => Direction::Inbound,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limits() {
+        let ts = SystemTime::now();
+        let pkt = [0u8; 64];
+
+        let mut state = State::new(
+            "net0",
+            CaptureLimits {
+                max_bytes: 1 << 20,
+                max_packets: Some(2),
+                snaplen: 128,
+            },
+        );
+        state.record(ts, Direction::Inbound, &pkt, pkt.len());
+        state.record(ts, Direction::Inbound, &pkt, pkt.len());
+        assert!(state.running);
+        state.record(ts, Direction::Inbound, &pkt, pkt.len());
+        let status = state.status();
+        assert!(!status.running && status.limit_reached);
+        assert_eq!(status.packets, 2);
+
+        let mut state = State::new(
+            "net0",
+            CaptureLimits { max_bytes: 0, max_packets: None, snaplen: 128 },
+        );
+        let header_len = state.writer.as_bytes().len();
+        state.limits.max_bytes = header_len + state.writer.packet_len(&pkt);
+        state.record(ts, Direction::Inbound, &pkt, pkt.len());
+        assert!(state.running);
+        state.record(ts, Direction::Inbound, &pkt, pkt.len());
+        let status = state.status();
+        assert!(!status.running && status.limit_reached);
+        assert_eq!(status.packets, 1);
+        assert_eq!(status.bytes, state.limits.max_bytes);
+    }
+
+    #[test]
+    fn directions() {
+        let mac = [2, 8, 0x20, 1, 2, 3];
+        let mut frame = [0u8; 14];
+        assert_eq!(direction(&frame, &mac), Direction::Inbound);
+        frame[6..12].copy_from_slice(&mac);
+        assert_eq!(direction(&frame, &mac), Direction::Outbound);
+        assert_eq!(direction(&frame[..8], &mac), Direction::Inbound);
+    }
+}
@@ -0,0 +1,754 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A virtio-console device with multiple named ports (virtio-serial).
+//!
+//! Each port carries a stream of bytes between the guest and the host, which
+//! sees it as a [Sink] (for data bound for the guest) and a [Source] (for data
+//! written by the guest).  Guests find ports by name: Linux, for example,
+//! links `/dev/virtio-ports/<name>` to each of them.
+
+use std::collections::VecDeque;
+use std::io;
+use std::num::NonZeroU16;
+use std::sync::{Arc, Mutex};
+
+use crate::chardev::*;
+use crate::common::*;
+use crate::hw::pci;
+use crate::migrate::*;
+use crate::util::regmap::RegMap;
+use crate::vmm::MemCtx;
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState};
+use super::queue::{read_buf, write_buf, Chain, VirtQueue, VirtQueues};
+use super::{VirtioDevice, VqChange};
+
+use lazy_static::lazy_static;
+
+/// Maximum number of ports a device may have
+pub const MAX_PORTS: usize = 31;
+
+/// Bytes buffered in each direction of a port before the host (for data bound
+/// for the guest) or guest (for data bound for the host) is made to wait.
+const PORT_BUF_SIZE: usize = 0x4000;
+
+/// One end of a port: data written to its [Sink] is delivered to the guest,
+/// and data written by the guest may be read from its [Source].
+pub struct ConsolePort {
+    id: u32,
+    name: String,
+    rxq: Arc<VirtQueue>,
+    txq: Arc<VirtQueue>,
+    state: Mutex<PortState>,
+    notify_readable: NotifierCell<dyn Source>,
+    notify_writable: NotifierCell<dyn Sink>,
+}
+
+#[derive(Default)]
+struct PortState {
+    to_guest: VecDeque<u8>,
+    from_guest: VecDeque<u8>,
+    /// The guest has the port open
+    guest_open: bool,
+    auto_discard: bool,
+    paused: bool,
+    /// A transmit buffer from which there was only room to take part of the
+    /// guest's data
+    tx_partial: Option<Chain>,
+}
+
+impl ConsolePort {
+    fn new(
+        id: u32,
+        name: String,
+        rxq: Arc<VirtQueue>,
+        txq: Arc<VirtQueue>,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            id,
+            name,
+            rxq,
+            txq,
+            state: Mutex::new(PortState {
+                auto_discard: true,
+                ..Default::default()
+            }),
+            notify_readable: NotifierCell::new(),
+            notify_writable: NotifierCell::new(),
+        })
+    }
+
+    /// The name by which the guest finds this port
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether a program in the guest has the port open
+    pub fn guest_connected(&self) -> bool {
+        self.state.lock().unwrap().guest_open
+    }
+
+    fn set_guest_open(&self, open: bool) {
+        self.state.lock().unwrap().guest_open = open;
+    }
+
+    /// Copies data bound for the guest into whatever receive buffers it has
+    /// made available.
+    fn deliver_locked(&self, state: &mut PortState) {
+        if state.paused {
+            return;
+        }
+        let Some(mem) = self.rxq.acc_mem.access() else {
+            return;
+        };
+        while !state.to_guest.is_empty() {
+            let mut chain = Chain::with_capacity(4);
+            if self.rxq.pop_avail(&mut chain, &mem).is_none() {
+                break;
+            }
+            let len = chain.remain_write_bytes().min(state.to_guest.len());
+            let data: Vec<u8> = state.to_guest.drain(..len).collect();
+            write_buf(&data, &mut chain, &mem);
+            self.rxq.push_used(&mut chain, &mem);
+        }
+    }
+
+    /// Takes data written by the guest from its transmit buffers, so long as
+    /// there is room to hold it.
+    fn receive_locked(&self, state: &mut PortState) {
+        if state.paused {
+            return;
+        }
+        let Some(mem) = self.txq.acc_mem.access() else {
+            return;
+        };
+        while state.from_guest.len() < PORT_BUF_SIZE {
+            let mut chain = match state.tx_partial.take() {
+                Some(chain) => chain,
+                None => {
+                    let mut chain = Chain::with_capacity(4);
+                    if self.txq.pop_avail(&mut chain, &mem).is_none() {
+                        break;
+                    }
+                    chain
+                }
+            };
+            let room = PORT_BUF_SIZE - state.from_guest.len();
+            let mut data = vec![0u8; chain.remain_read_bytes().min(room)];
+            let len = read_buf(&mut data, &mut chain, &mem);
+            if !state.auto_discard {
+                state.from_guest.extend(&data[..len]);
+            }
+            // Hold on to the buffer until the rest of its data fits, unless
+            // nothing could be read from it.
+            if len > 0 && chain.remain_read_bytes() > 0 {
+                state.tx_partial = Some(chain);
+            } else {
+                self.txq.push_used(&mut chain, &mem);
+            }
+        }
+    }
+
+    /// Handles the guest making receive buffers available.
+    fn rx_notify(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.deliver_locked(&mut state);
+        let writable = !state.paused && state.to_guest.len() < PORT_BUF_SIZE;
+
+        // As with the UARTs, the state lock cannot be held while dispatching
+        // notifications, since the callbacks may immediately write more data.
+        drop(state);
+        if writable {
+            self.notify_writable.notify(self as &dyn Sink);
+        }
+    }
+
+    /// Handles the guest making data available in transmit buffers.
+    fn tx_notify(&self) {
+        let mut pending = {
+            let mut state = self.state.lock().unwrap();
+            self.receive_locked(&mut state);
+            state.from_guest.len()
+        };
+
+        // Consumers may read only part of what is buffered when notified.
+        // Keep notifying for as long as that drains the buffer.
+        while pending > 0 {
+            self.notify_readable.notify(self as &dyn Source);
+            let remaining = self.state.lock().unwrap().from_guest.len();
+            if remaining >= pending {
+                break;
+            }
+            pending = remaining;
+        }
+    }
+
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.to_guest.clear();
+        state.from_guest.clear();
+        state.guest_open = false;
+        state.tx_partial = None;
+    }
+
+    /// Rebuilds a partially-consumed transmit buffer from its migrated state.
+    fn restore_partial(
+        &self,
+        partial: migrate::PartialChainV1,
+        mem: &MemCtx,
+    ) -> Result<Chain, MigrateStateError> {
+        let mut chain = Chain::with_capacity(4);
+        let valid =
+            self.txq.chain_at(partial.desc_idx, &mut chain, mem).is_some()
+                && chain.read_skip(partial.consumed as usize);
+        if !valid {
+            return Err(MigrateStateError::ImportFailed(format!(
+                "virtio-console: invalid partial buffer {} on port {}",
+                partial.desc_idx, self.id
+            )));
+        }
+        Ok(chain)
+    }
+
+    fn set_paused(&self, paused: bool) {
+        self.state.lock().unwrap().paused = paused;
+        if !paused {
+            self.rx_notify();
+            self.tx_notify();
+        }
+    }
+}
+
+impl Sink for ConsolePort {
+    fn write(&self, data: u8) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.paused || state.to_guest.len() >= PORT_BUF_SIZE {
+            return false;
+        }
+        state.to_guest.push_back(data);
+        self.deliver_locked(&mut state);
+        true
+    }
+    fn write_bytes(&self, data: &[u8]) -> usize {
+        // Queue everything there is room for before delivering any of it, so
+        // that it fills as few of the guest's buffers as possible.
+        let mut state = self.state.lock().unwrap();
+        if state.paused {
+            return 0;
+        }
+        let room = PORT_BUF_SIZE.saturating_sub(state.to_guest.len());
+        let len = room.min(data.len());
+        state.to_guest.extend(&data[..len]);
+        self.deliver_locked(&mut state);
+        len
+    }
+    fn set_notifier(&self, f: Option<SinkNotifier>) {
+        self.notify_writable.set(f);
+    }
+}
+
+impl Source for ConsolePort {
+    fn read(&self) -> Option<u8> {
+        let mut state = self.state.lock().unwrap();
+        let res = state.from_guest.pop_front();
+        if res.is_some() && state.from_guest.len() < PORT_BUF_SIZE {
+            // Room has been made for anything the guest was made to wait on
+            self.receive_locked(&mut state);
+        }
+        res
+    }
+    fn read_bytes(&self, buf: &mut [u8]) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let len = buf.len().min(state.from_guest.len());
+        for (dst, src) in buf.iter_mut().zip(state.from_guest.drain(..len)) {
+            *dst = src;
+        }
+        if len > 0 {
+            self.receive_locked(&mut state);
+        }
+        len
+    }
+    fn discard(&self, count: usize) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let discarded = count.min(state.from_guest.len());
+        state.from_guest.drain(..discarded);
+        self.receive_locked(&mut state);
+        discarded
+    }
+    fn set_autodiscard(&self, active: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.auto_discard = active;
+        if active {
+            state.from_guest.clear();
+        }
+    }
+    fn set_notifier(&self, f: Option<SourceNotifier>) {
+        self.notify_readable.set(f);
+    }
+}
+
+#[derive(Default)]
+struct CtrlState {
+    /// Control messages waiting for the guest to make receive buffers
+    /// available
+    pending: VecDeque<Vec<u8>>,
+}
+
+pub struct PciVirtioConsole {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+    ports: Vec<Arc<ConsolePort>>,
+    ctrl: Mutex<CtrlState>,
+}
+
+impl PciVirtioConsole {
+    /// Creates a device with a port for each of `names`, in order.
+    pub fn new(queue_size: u16, names: &[&str]) -> io::Result<Arc<Self>> {
+        if names.is_empty() || names.len() > MAX_PORTS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("virtio-console must have 1 to {MAX_PORTS} ports"),
+            ));
+        }
+        for (idx, name) in names.iter().enumerate() {
+            if name.is_empty() || names[..idx].contains(name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid or duplicate port name {name:?}"),
+                ));
+            }
+        }
+
+        // A receive and transmit queue for each port, plus the two control
+        // queues.
+        let queue_count = 2 * (names.len() + 1);
+        let queues = VirtQueues::new(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(queue_count as u16).unwrap(),
+        );
+        // One MSI-X entry for config changes and one for each queue.
+        let msix_count = Some(queue_count as u16 + 1);
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_CONSOLE,
+            VIRTIO_SUB_DEV_CONSOLE,
+            pci::bits::CLASS_COMMUNICATION,
+            VIRTIO_CONSOLE_CFG_SIZE,
+        );
+
+        let ports = names
+            .iter()
+            .enumerate()
+            .map(|(id, name)| {
+                let (rxq, txq) = port_queues(id as u32);
+                ConsolePort::new(
+                    id as u32,
+                    name.to_string(),
+                    virtio_state.queues[rxq as usize].clone(),
+                    virtio_state.queues[txq as usize].clone(),
+                )
+            })
+            .collect();
+
+        Ok(Arc::new(Self {
+            virtio_state,
+            pci_state,
+            ports,
+            ctrl: Mutex::new(CtrlState::default()),
+        }))
+    }
+
+    /// The device's ports, in order
+    pub fn ports(&self) -> &[Arc<ConsolePort>] {
+        &self.ports
+    }
+
+    /// Finds the port named `name`.
+    pub fn port(&self, name: &str) -> Option<&Arc<ConsolePort>> {
+        self.ports.iter().find(|port| port.name == name)
+    }
+
+    /// Queues a control message for the guest.
+    fn ctrl_send(&self, id: u32, event: u16, value: u16, data: &[u8]) {
+        let mut msg = Vec::with_capacity(CTRL_MSG_LEN + data.len());
+        msg.extend_from_slice(&id.to_le_bytes());
+        msg.extend_from_slice(&event.to_le_bytes());
+        msg.extend_from_slice(&value.to_le_bytes());
+        msg.extend_from_slice(data);
+
+        let mut ctrl = self.ctrl.lock().unwrap();
+        ctrl.pending.push_back(msg);
+        self.ctrl_flush(&mut ctrl);
+    }
+
+    /// Delivers pending control messages into whatever receive buffers the
+    /// guest has made available.
+    fn ctrl_flush(&self, ctrl: &mut CtrlState) {
+        let vq = &self.virtio_state.queues[CTRL_RXQ as usize];
+        let Some(mem) = vq.acc_mem.access() else {
+            return;
+        };
+        while let Some(msg) = ctrl.pending.front() {
+            let mut chain = Chain::with_capacity(4);
+            if vq.pop_avail(&mut chain, &mem).is_none() {
+                break;
+            }
+            write_buf(msg, &mut chain, &mem);
+            vq.push_used(&mut chain, &mem);
+            ctrl.pending.pop_front();
+        }
+    }
+
+    /// Handles the control messages sent by the guest.
+    fn ctrl_receive(&self) {
+        let vq = &self.virtio_state.queues[CTRL_TXQ as usize];
+        let mut msgs = Vec::new();
+        if let Some(mem) = vq.acc_mem.access() {
+            loop {
+                let mut chain = Chain::with_capacity(4);
+                if vq.pop_avail(&mut chain, &mem).is_none() {
+                    break;
+                }
+                let mut buf = [0u8; CTRL_MSG_LEN];
+                let len = read_buf(&mut buf, &mut chain, &mem);
+                vq.push_used(&mut chain, &mem);
+                if len == CTRL_MSG_LEN {
+                    msgs.push(buf);
+                }
+            }
+        }
+
+        for buf in msgs {
+            let id = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+            let event = u16::from_le_bytes(buf[4..6].try_into().unwrap());
+            let value = u16::from_le_bytes(buf[6..8].try_into().unwrap());
+            self.ctrl_handle(id, event, value);
+        }
+    }
+
+    fn ctrl_handle(&self, id: u32, event: u16, value: u16) {
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for port in self.ports.iter() {
+                    self.ctrl_send(port.id, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if value == 1 => {
+                if let Some(port) = self.ports.get(id as usize) {
+                    self.ctrl_send(
+                        id,
+                        VIRTIO_CONSOLE_PORT_NAME,
+                        1,
+                        port.name.as_bytes(),
+                    );
+                    // The host end of every port is always open
+                    self.ctrl_send(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                if let Some(port) = self.ports.get(id as usize) {
+                    port.set_guest_open(value == 1);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl VirtioDevice for PciVirtioConsole {
+    fn cfg_rw(&self, mut rwo: RWOp) {
+        CONSOLE_DEV_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => match id {
+                ConsoleReg::Cols | ConsoleReg::Rows => ro.write_u16(0),
+                ConsoleReg::MaxNrPorts => ro.write_u32(self.ports.len() as u32),
+                ConsoleReg::EmergWrite => ro.write_u32(0),
+            },
+            // Emergency writes are not offered, and the rest is read-only
+            RWOp::Write(_) => {}
+        })
+    }
+
+    fn get_features(&self) -> u32 {
+        VIRTIO_CONSOLE_F_MULTIPORT
+    }
+
+    fn set_features(&self, feat: u32) -> Result<(), ()> {
+        // Without multiport, only the first port is usable, and the guest has
+        // no way to indicate that it is open.
+        if feat & VIRTIO_CONSOLE_F_MULTIPORT == 0 {
+            self.ports[0].set_guest_open(true);
+        }
+        Ok(())
+    }
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>) {
+        match vq.id {
+            CTRL_RXQ => {
+                let mut ctrl = self.ctrl.lock().unwrap();
+                self.ctrl_flush(&mut ctrl);
+            }
+            CTRL_TXQ => self.ctrl_receive(),
+            qid => {
+                let (port, is_rx) = queue_port(qid);
+                if let Some(port) = self.ports.get(port as usize) {
+                    if is_rx {
+                        port.rx_notify();
+                    } else {
+                        port.tx_notify();
+                    }
+                }
+            }
+        }
+    }
+
+    fn queue_change(
+        &self,
+        vq: &Arc<VirtQueue>,
+        change: VqChange,
+    ) -> Result<(), ()> {
+        if let VqChange::Reset = change {
+            match vq.id {
+                CTRL_RXQ => self.ctrl.lock().unwrap().pending.clear(),
+                CTRL_TXQ => {}
+                qid => {
+                    let (port, is_rx) = queue_port(qid);
+                    if let Some(port) = self.ports.get(port as usize) {
+                        if is_rx {
+                            port.reset();
+                        } else {
+                            // A held transmit buffer belongs to the queue
+                            // being reset, and must not be returned to it.
+                            port.state.lock().unwrap().tx_partial = None;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Lifecycle for PciVirtioConsole {
+    fn type_name(&self) -> &'static str {
+        "pci-virtio-console"
+    }
+    fn reset(&self) {
+        self.virtio_state.reset(self);
+    }
+    fn pause(&self) {
+        for port in self.ports.iter() {
+            port.set_paused(true);
+        }
+    }
+    fn resume(&self) {
+        for port in self.ports.iter() {
+            port.set_paused(false);
+        }
+    }
+    fn migrate(&'_ self) -> Migrator<'_> {
+        Migrator::Multi(self)
+    }
+}
+
+impl MigrateMulti for PciVirtioConsole {
+    fn export(
+        &self,
+        output: &mut PayloadOutputs,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::export(self, output, ctx)?;
+
+        let ports = self
+            .ports
+            .iter()
+            .map(|port| {
+                let state = port.state.lock().unwrap();
+                // A partially-consumed transmit buffer remains owned by the
+                // device, so the destination must pick up where it left off.
+                let tx_partial = state.tx_partial.as_ref().map(|chain| {
+                    migrate::PartialChainV1 {
+                        desc_idx: chain.head_idx().unwrap(),
+                        consumed: chain.consumed_read_bytes() as u32,
+                    }
+                });
+                migrate::PortV1 {
+                    to_guest: state.to_guest.iter().copied().collect(),
+                    from_guest: state.from_guest.iter().copied().collect(),
+                    guest_open: state.guest_open,
+                    tx_partial,
+                }
+            })
+            .collect();
+        let ctrl_pending =
+            self.ctrl.lock().unwrap().pending.iter().cloned().collect();
+
+        output.push(migrate::ConsoleV1 { ports, ctrl_pending }.into())
+    }
+
+    fn import(
+        &self,
+        offer: &mut PayloadOffers,
+        ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        <dyn PciVirtio>::import(self, offer, ctx)?;
+
+        let input: migrate::ConsoleV1 = offer.take()?;
+        if input.ports.len() != self.ports.len() {
+            return Err(MigrateStateError::ImportFailed(format!(
+                "virtio-console: port count mismatch {} vs {}",
+                input.ports.len(),
+                self.ports.len()
+            )));
+        }
+        for (port, saved) in self.ports.iter().zip(input.ports) {
+            let tx_partial = match saved.tx_partial {
+                Some(partial) => Some(port.restore_partial(partial, ctx.mem)?),
+                None => None,
+            };
+            let mut state = port.state.lock().unwrap();
+            state.to_guest = saved.to_guest.into();
+            state.from_guest = saved.from_guest.into();
+            state.guest_open = saved.guest_open;
+            state.tx_partial = tx_partial;
+        }
+        self.ctrl.lock().unwrap().pending = input.ctrl_pending.into();
+
+        Ok(())
+    }
+}
+
+impl PciVirtio for PciVirtioConsole {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+
+/// The receive and transmit queues of port `id`.  The control queues sit
+/// between those of the first and second ports.
+fn port_queues(id: u32) -> (u16, u16) {
+    match id {
+        0 => (0, 1),
+        id => (2 + 2 * id as u16, 3 + 2 * id as u16),
+    }
+}
+
+/// The port served by (non-control) queue `qid`, and whether it is that
+/// port's receive queue.
+fn queue_port(qid: u16) -> (u32, bool) {
+    let port = match qid {
+        0 | 1 => 0,
+        qid => u32::from(qid / 2 - 1),
+    };
+    (port, qid % 2 == 0)
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum ConsoleReg {
+    Cols,
+    Rows,
+    MaxNrPorts,
+    EmergWrite,
+}
+
+lazy_static! {
+    static ref CONSOLE_DEV_REGS: RegMap<ConsoleReg> = {
+        let layout = [
+            (ConsoleReg::Cols, 2),
+            (ConsoleReg::Rows, 2),
+            (ConsoleReg::MaxNrPorts, 4),
+            (ConsoleReg::EmergWrite, 4),
+        ];
+        RegMap::create_packed(VIRTIO_CONSOLE_CFG_SIZE, &layout, None)
+    };
+}
+
+mod bits {
+    pub const VIRTIO_CONSOLE_CFG_SIZE: usize = 12;
+
+    pub const VIRTIO_CONSOLE_F_MULTIPORT: u32 = 1 << 1;
+
+    pub const CTRL_RXQ: u16 = 2;
+    pub const CTRL_TXQ: u16 = 3;
+
+    /// Length of `struct virtio_console_control`
+    pub const CTRL_MSG_LEN: usize = 8;
+
+    // Control message events
+    pub const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+    pub const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+    pub const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+    pub const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+    pub const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+}
+use bits::*;
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    /// A transmit buffer from which only part of the guest's data was taken
+    #[derive(Deserialize, Serialize)]
+    pub struct PartialChainV1 {
+        /// Head descriptor of the buffer's chain
+        pub desc_idx: u16,
+        /// Bytes already taken from the buffer
+        pub consumed: u32,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct PortV1 {
+        pub to_guest: Vec<u8>,
+        pub from_guest: Vec<u8>,
+        pub guest_open: bool,
+        pub tx_partial: Option<PartialChainV1>,
+    }
+
+    #[derive(Deserialize, Serialize)]
+    pub struct ConsoleV1 {
+        pub ports: Vec<PortV1>,
+        /// Control messages not yet delivered to the guest
+        pub ctrl_pending: Vec<Vec<u8>>,
+    }
+    impl Schema<'_> for ConsoleV1 {
+        fn id() -> SchemaId {
+            ("pci-virtio-console", 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn queue_layout() {
+        assert_eq!(port_queues(0), (0, 1));
+        assert_eq!(port_queues(1), (4, 5));
+        assert_eq!(port_queues(2), (6, 7));
+        for id in 0..MAX_PORTS as u32 {
+            let (rxq, txq) = port_queues(id);
+            assert_eq!(queue_port(rxq), (id, true));
+            assert_eq!(queue_port(txq), (id, false));
+        }
+    }
+
+    #[test]
+    fn port_names() {
+        assert!(PciVirtioConsole::new(16, &[]).is_err());
+        assert!(PciVirtioConsole::new(16, &["a", "a"]).is_err());
+        assert!(PciVirtioConsole::new(16, &[""]).is_err());
+
+        let dev = PciVirtioConsole::new(16, &["a", "b"]).unwrap();
+        assert_eq!(dev.ports().len(), 2);
+        assert_eq!(dev.port("b").unwrap().id, 1);
+        assert!(dev.port("c").is_none());
+    }
+}
@@ -0,0 +1,41 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Conversion of host error numbers into those of Linux, which the 9P2000.L
+//! and FUSE protocols use on the wire regardless of the server's native
+//! values.
+
+// Linux error numbers that differ from those of illumos.
+pub const L_ENOSYS: u32 = 38;
+pub const L_EILSEQ: u32 = 84;
+pub const L_ENODATA: u32 = 61;
+pub const L_EOPNOTSUPP: u32 = 95;
+
+/// Converts a host error number into the equivalent Linux error number.
+pub fn linux_errno(host: i32) -> u32 {
+    #[cfg(target_os = "illumos")]
+    let linux = match host {
+        libc::EDEADLK => 35,
+        libc::ENAMETOOLONG => 36,
+        libc::ENOLCK => 37,
+        libc::ENOSYS => L_ENOSYS as i32,
+        libc::ENOTEMPTY => 39,
+        libc::ELOOP => 40,
+        libc::EOVERFLOW => 75,
+        libc::EILSEQ => L_EILSEQ as i32,
+        libc::ENOTSUP | libc::EOPNOTSUPP => L_EOPNOTSUPP as i32,
+        libc::ESTALE => 116,
+        libc::EDQUOT => 122,
+        other => other,
+    };
+    #[cfg(not(target_os = "illumos"))]
+    let linux = host;
+
+    linux as u32
+}
+
+/// Converts an I/O error into a Linux error number.
+pub fn io_errno(e: &std::io::Error) -> u32 {
+    linux_errno(e.raw_os_error().unwrap_or(libc::EIO))
+}
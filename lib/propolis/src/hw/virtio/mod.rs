@@ -8,19 +8,25 @@ use std::sync::Arc;
 mod bits;
 
 pub mod block;
-#[cfg(feature = "falcon")]
+pub mod capture;
+pub mod console;
+mod errno;
 pub mod p9fs;
 pub mod pci;
-mod queue;
+pub(crate) mod queue;
 #[cfg(feature = "falcon")]
 pub mod softnpu;
 pub mod viona;
+pub mod virtiofs;
 
 use crate::common::*;
 use queue::VirtQueue;
 
 pub use block::PciVirtioBlock;
+pub use console::PciVirtioConsole;
+pub use p9fs::PciVirtio9pfs;
 pub use viona::PciVirtioViona;
+pub use virtiofs::PciVirtioFs;
 
 pub trait VirtioDevice: Send + Sync + 'static + Lifecycle {
     /// Read/write device-specific virtio configuration space
@@ -0,0 +1,2042 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::mem::size_of;
+use std::num::NonZeroU16;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{
+    DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt,
+};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::common::*;
+use crate::hw::pci;
+use crate::migrate::Migrator;
+use crate::util::regmap::RegMap;
+use crate::vmm::MemCtx;
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState};
+use super::queue::{write_buf, Chain, VirtQueue, VirtQueues};
+use super::VirtioDevice;
+
+use ispf::WireSize;
+use lazy_static::lazy_static;
+use libc::{
+    DT_DIR, DT_LNK, DT_REG, E2BIG, EACCES, EBADF, EBUSY, EILSEQ, EINVAL,
+    EISDIR, ENOENT, ENOLCK, EOVERFLOW, ERANGE, EROFS,
+};
+use p9ds::proto::{
+    self, Dirent, MessageType, P9Version, Qid, QidType, Rattach, Rclunk,
+    Rgetattr, Rlerror, Rlopen, Rread, Rreaddir, Rstatfs, Rwalk, Rwrite,
+    Tattach, Tgetattr, Tread, Treaddir, Tstatfs, Twalk, Version,
+    P9_GETATTR_BASIC,
+};
+use slog::{warn, Logger};
+
+mod wire;
+
+/// This const is to add headroom into serialized P9 data packets. These packets
+/// go through a virtio transport. It's been observed with Linux guests that we
+/// cannot fill up an entire `msize` packet running through that transport for
+/// RREAD message types, as the packet gets truncated by a small number of bytes
+/// (13 is most often observed) and the PDU size will no longer match the the
+/// RREAD header stated size.
+const P9FS_VIRTIO_READ_HEADROOM: usize = 20;
+
+/// Mode bits the guest may set on files in the share.  Files are created as
+/// the user running propolis, so setuid, setgid and sticky bits are withheld.
+const GUEST_MODE_MASK: u32 = 0o777;
+
+#[usdt::provider(provider = "propolis")]
+mod probes {
+    fn p9fs_cfg_read() {}
+}
+
+/// A virtio-9p filesystem device speaking 9P2000.L. It's been tested with
+/// illumos and Linux guests.
+///
+/// The design centers around a P9Handler trait that allows various different
+/// types of P9 devices to be implemented. This file includes a `HostFSHandler`
+/// implementation that allows mounting host filesystems in the guest, either
+/// read-only or writable. Another implementation is in the SoftNpu device that
+/// supports P4 program transfer via p9fs.
+pub struct PciVirtio9pfs {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+    handler: Arc<dyn P9Handler>,
+}
+
+impl PciVirtio9pfs {
+    pub fn new(queue_size: u16, handler: Arc<dyn P9Handler>) -> Arc<Self> {
+        let queues = VirtQueues::new(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(1).unwrap(),
+        );
+        let msix_count = Some(2); //guess
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_9P,
+            VIRTIO_SUB_DEV_9P_TRANSPORT,
+            pci::bits::CLASS_STORAGE,
+            VIRTIO_9P_CFG_SIZE,
+        );
+        Arc::new(Self { virtio_state, pci_state, handler })
+    }
+}
+
+impl VirtioDevice for PciVirtio9pfs {
+    fn cfg_rw(&self, mut rwo: RWOp) {
+        P9FS_DEV_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => {
+                probes::p9fs_cfg_read!(|| ());
+                match id {
+                    P9fsReg::TagLen => {
+                        ro.write_u16(self.handler.target().len() as u16);
+                    }
+                    P9fsReg::Tag => {
+                        let mut bs = [0; 256];
+                        for (i, x) in self.handler.target().bytes().enumerate()
+                        {
+                            if i == 256 {
+                                break;
+                            }
+                            bs[i] = x;
+                        }
+                        ro.write_bytes(&bs);
+                        ro.fill(0);
+                    }
+                }
+            }
+            RWOp::Write(_) => {}
+        })
+    }
+
+    fn get_features(&self) -> u32 {
+        VIRTIO_9P_F_MOUNT_TAG
+    }
+
+    fn set_features(&self, _feat: u32) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>) {
+        self.handler.handle_req(vq);
+    }
+}
+
+impl Lifecycle for PciVirtio9pfs {
+    fn type_name(&self) -> &'static str {
+        "pci-virtio-9pfs"
+    }
+    fn reset(&self) {
+        self.virtio_state.reset(self);
+    }
+    fn migrate(&'_ self) -> Migrator<'_> {
+        Migrator::NonMigratable
+    }
+}
+
+impl PciVirtio for PciVirtio9pfs {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum P9fsReg {
+    TagLen,
+    Tag,
+}
+
+lazy_static! {
+    static ref P9FS_DEV_REGS: RegMap<P9fsReg> = {
+        let layout = [(P9fsReg::TagLen, 2), (P9fsReg::Tag, 256)];
+        RegMap::create_packed(VIRTIO_9P_CFG_SIZE, &layout, None)
+    };
+}
+
+struct Fid {
+    pathbuf: PathBuf,
+    file: Option<fs::File>,
+    xattr: Option<Xattr>,
+}
+
+impl Fid {
+    fn new(pathbuf: PathBuf) -> Self {
+        Self { pathbuf, file: None, xattr: None }
+    }
+}
+
+/// The extended attribute state of a fid created by `Txattrwalk` or converted
+/// by `Txattrcreate`.
+enum Xattr {
+    /// The value (or, for an empty name, the list of names) being read.
+    Read(Vec<u8>),
+
+    /// A value being written, which is stored when the fid is clunked.
+    Write { name: String, size: u64, flags: u32, data: Vec<u8> },
+}
+
+struct Fileserver {
+    fids: HashMap<u32, Fid>,
+}
+
+impl Fileserver {
+    /// Updates the paths of all fids at or below `from` to reflect that `from`
+    /// has been renamed to `to`.
+    fn rename_fids(&mut self, from: &Path, to: &Path) {
+        for fid in self.fids.values_mut() {
+            if let Ok(rest) = fid.pathbuf.strip_prefix(from) {
+                fid.pathbuf = if rest.as_os_str().is_empty() {
+                    to.to_path_buf()
+                } else {
+                    to.join(rest)
+                };
+            }
+        }
+    }
+}
+
+pub(crate) mod bits {
+    use std::mem::size_of;
+
+    // features
+    pub const VIRTIO_9P_F_MOUNT_TAG: u32 = 0x1;
+
+    pub const VIRTIO_9P_MAX_TAG_SIZE: usize = 256;
+    pub const VIRTIO_9P_CFG_SIZE: usize =
+        VIRTIO_9P_MAX_TAG_SIZE + size_of::<u16>();
+}
+use bits::*;
+
+pub trait P9Handler: Sync + Send + 'static {
+    fn source(&self) -> &str;
+    fn target(&self) -> &str;
+    fn msize(&self) -> u32;
+    fn handle_version(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx);
+    fn handle_attach(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx);
+    fn handle_walk(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx);
+    fn handle_open(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx);
+    fn handle_readdir(
+        &self,
+        msg_buf: &[u8],
+        chain: &mut Chain,
+        mem: &MemCtx,
+        msize: u32,
+    );
+    fn handle_read(
+        &self,
+        msg_buf: &[u8],
+        chain: &mut Chain,
+        mem: &MemCtx,
+        msize: u32,
+    );
+    fn handle_write(
+        &self,
+        msg_buf: &[u8],
+        chain: &mut Chain,
+        mem: &MemCtx,
+        msize: u32,
+    );
+    fn handle_clunk(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx);
+    fn handle_getattr(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx);
+    fn handle_statfs(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx);
+
+    // Handlers for messages that modify the filesystem. Handlers that don't
+    // support modification can rely on these defaults, which fail the request.
+
+    fn handle_lcreate(&self, _msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        write_error(wire::L_EOPNOTSUPP, chain, mem)
+    }
+    fn handle_mkdir(&self, _msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        write_error(wire::L_EOPNOTSUPP, chain, mem)
+    }
+    fn handle_symlink(&self, _msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        write_error(wire::L_EOPNOTSUPP, chain, mem)
+    }
+    fn handle_readlink(
+        &self,
+        _msg_buf: &[u8],
+        chain: &mut Chain,
+        mem: &MemCtx,
+    ) {
+        write_error(wire::L_EOPNOTSUPP, chain, mem)
+    }
+    fn handle_rename(&self, _msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        write_error(wire::L_EOPNOTSUPP, chain, mem)
+    }
+    fn handle_renameat(
+        &self,
+        _msg_buf: &[u8],
+        chain: &mut Chain,
+        mem: &MemCtx,
+    ) {
+        write_error(wire::L_EOPNOTSUPP, chain, mem)
+    }
+    fn handle_unlinkat(
+        &self,
+        _msg_buf: &[u8],
+        chain: &mut Chain,
+        mem: &MemCtx,
+    ) {
+        write_error(wire::L_EOPNOTSUPP, chain, mem)
+    }
+    fn handle_remove(&self, _msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        write_error(wire::L_EOPNOTSUPP, chain, mem)
+    }
+    fn handle_setattr(&self, _msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        write_error(wire::L_EOPNOTSUPP, chain, mem)
+    }
+    fn handle_fsync(&self, _msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        write_error(wire::L_EOPNOTSUPP, chain, mem)
+    }
+    fn handle_xattrwalk(
+        &self,
+        _msg_buf: &[u8],
+        chain: &mut Chain,
+        mem: &MemCtx,
+    ) {
+        write_error(wire::L_EOPNOTSUPP, chain, mem)
+    }
+    fn handle_xattrcreate(
+        &self,
+        _msg_buf: &[u8],
+        chain: &mut Chain,
+        mem: &MemCtx,
+    ) {
+        write_error(wire::L_EOPNOTSUPP, chain, mem)
+    }
+
+    fn handle_req(&self, vq: &Arc<VirtQueue>) {
+        let Some(mem) = vq.acc_mem.access() else {
+            return;
+        };
+
+        let mut chain = Chain::with_capacity(1);
+        let Some((_idx, _clen)) = vq.pop_avail(&mut chain, &mem) else {
+            return;
+        };
+
+        //TODO better as uninitialized?
+        let mut data = Vec::new();
+        let msize = self.msize();
+        data.resize(msize as usize, 0);
+        let buf = data.as_mut_slice();
+
+        // TODO copy pasta from tail end of Chain::read function. Seemingly
+        // cannot use Chain::read as-is because it expects a statically sized
+        // type.
+        let mut done = 0;
+        let _total = chain.for_remaining_type(true, |addr, len| {
+            let remain = &mut buf[done..];
+            if let Some(copied) = mem.read_into(addr, remain, len) {
+                let need_more = copied != remain.len();
+                done += copied;
+                (copied, need_more)
+            } else {
+                (0, false)
+            }
+        });
+
+        // The message must hold at least a header, and its stated size must
+        // not exceed what was actually copied out of the chain (which is itself
+        // bounded by msize).
+        let len = if done >= wire::HEADER_SIZE {
+            u32::from_le_bytes(buf[0..4].try_into().unwrap()) as usize
+        } else {
+            0
+        };
+        if len < wire::HEADER_SIZE || len > done {
+            write_error(EINVAL as u32, &mut chain, &mem);
+            vq.push_used(&mut chain, &mem);
+            return;
+        }
+        let msg = &data[..len];
+
+        // Messages that p9ds doesn't model are dispatched on their raw type.
+        match msg[4] {
+            wire::TLCREATE => self.handle_lcreate(msg, &mut chain, &mem),
+            wire::TMKDIR => self.handle_mkdir(msg, &mut chain, &mem),
+            wire::TSYMLINK => self.handle_symlink(msg, &mut chain, &mem),
+            wire::TREADLINK => self.handle_readlink(msg, &mut chain, &mem),
+            wire::TRENAME => self.handle_rename(msg, &mut chain, &mem),
+            wire::TRENAMEAT => self.handle_renameat(msg, &mut chain, &mem),
+            wire::TUNLINKAT => self.handle_unlinkat(msg, &mut chain, &mem),
+            wire::TREMOVE => self.handle_remove(msg, &mut chain, &mem),
+            wire::TSETATTR => self.handle_setattr(msg, &mut chain, &mem),
+            wire::TFSYNC => self.handle_fsync(msg, &mut chain, &mem),
+            wire::TXATTRWALK => self.handle_xattrwalk(msg, &mut chain, &mem),
+            wire::TXATTRCREATE => {
+                self.handle_xattrcreate(msg, &mut chain, &mem)
+            }
+            typ => self.dispatch(typ, msg, &mut chain, &mem, msize),
+        }
+
+        vq.push_used(&mut chain, &mem);
+    }
+
+    /// Dispatches a message whose type p9ds models.
+    fn dispatch(
+        &self,
+        typ: u8,
+        msg: &[u8],
+        chain: &mut Chain,
+        mem: &MemCtx,
+        msize: u32,
+    ) {
+        let typ = match MessageType::try_from(typ) {
+            Ok(typ) => typ,
+            Err(_) => return write_error(wire::L_EOPNOTSUPP, chain, mem),
+        };
+
+        match typ {
+            MessageType::Tversion => self.handle_version(msg, chain, mem),
+            MessageType::Tattach => self.handle_attach(msg, chain, mem),
+            MessageType::Twalk => self.handle_walk(msg, chain, mem),
+            MessageType::Tlopen => self.handle_open(msg, chain, mem),
+            MessageType::Treaddir => {
+                self.handle_readdir(msg, chain, mem, msize)
+            }
+            MessageType::Tread => self.handle_read(msg, chain, mem, msize),
+            MessageType::Twrite => self.handle_write(msg, chain, mem, msize),
+            MessageType::Tclunk => self.handle_clunk(msg, chain, mem),
+            MessageType::Tgetattr => self.handle_getattr(msg, chain, mem),
+            MessageType::Tstatfs => self.handle_statfs(msg, chain, mem),
+
+            //TODO: There are still p9fs operations that are not implemented
+            //      (e.g. Tlink, Tmknod, and locking). If you hit an
+            //      EOPNOTSUPP, this is the place to start for adding a new
+            //      message type handler.
+            _ => write_error(wire::L_EOPNOTSUPP, chain, mem),
+        }
+    }
+}
+/// Serves a directory tree on the host to the guest.
+///
+/// Every path the guest operates on is confined to the shared root: walks
+/// cannot ascend above it, and the directory containing any file the guest
+/// touches must resolve (following symlinks) to a location beneath it. Symlinks
+/// themselves may point anywhere, since the guest resolves them on its side;
+/// the server never follows a symlink in the final component of a path.
+pub struct HostFSHandler {
+    max_chunk_size: u32,
+    msize: Mutex<u32>,
+    source: String,
+    target: String,
+    root: PathBuf,
+    read_only: bool,
+    fileserver: Mutex<Box<Fileserver>>,
+    log: Logger,
+}
+
+impl HostFSHandler {
+    pub fn new(
+        source: String,
+        target: String,
+        max_chunk_size: u32,
+        read_only: bool,
+        log: Logger,
+    ) -> Self {
+        let fileserver =
+            Mutex::new(Box::new(Fileserver { fids: HashMap::new() }));
+
+        // Resolve the root up front so that confinement checks can compare
+        // canonical paths. If this fails, attaching will report the error.
+        let root = fs::canonicalize(&source)
+            .unwrap_or_else(|_| PathBuf::from(&source));
+        Self {
+            source,
+            target,
+            root,
+            read_only,
+            max_chunk_size,
+            msize: Mutex::new(max_chunk_size),
+            fileserver,
+            log,
+        }
+    }
+
+    fn lock_fileserver(
+        &self,
+    ) -> Result<std::sync::MutexGuard<'_, Box<Fileserver>>, u32> {
+        self.fileserver.lock().map_err(|_| ENOLCK as u32)
+    }
+
+    fn check_writable(&self) -> Result<(), u32> {
+        if self.read_only {
+            Err(EROFS as u32)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks that `path` lies within the shared root without following a
+    /// symlink in its final component, returning the path with its parent
+    /// directory resolved.
+    fn confine(&self, path: &Path) -> Result<PathBuf, u32> {
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) if path != self.root => (parent, name),
+            _ => return self.confine_dir(path),
+        };
+
+        Ok(self.confine_dir(parent)?.join(name))
+    }
+
+    /// Checks that `path`, following any symlinks, lies within the shared
+    /// root, returning the resolved path.
+    fn confine_dir(&self, path: &Path) -> Result<PathBuf, u32> {
+        let resolved =
+            fs::canonicalize(path).map_err(|e| wire::io_errno(&e))?;
+        if !resolved.starts_with(&self.root) {
+            warn!(self.log, "p9fs: path escapes shared root: {:?}", path);
+            return Err(EACCES as u32);
+        }
+
+        Ok(resolved)
+    }
+
+    /// Returns the path of the entry named `name` in the directory `dir`,
+    /// checking that both are valid and within the shared root.
+    fn child(&self, dir: &Path, name: &str) -> Result<PathBuf, u32> {
+        check_name(name)?;
+        Ok(self.confine_dir(dir)?.join(name))
+    }
+
+    fn do_read(
+        &self,
+        msg: &Tread,
+        fid: &mut Fid,
+        chain: &mut Chain,
+        mem: &MemCtx,
+        msize: u32,
+    ) {
+        if let Some(Xattr::Read(value)) = &fid.xattr {
+            let start = usize::min(msg.offset as usize, value.len());
+            let end = usize::min(start + msg.count as usize, value.len());
+            let response = Rread::new(value[start..end].to_vec());
+            let mut out = ispf::to_bytes_le(&response).unwrap();
+            let buf = out.as_mut_slice();
+            return write_buf(buf, chain, mem);
+        }
+
+        let file = match fid.file {
+            Some(ref f) => f,
+            None => {
+                // the file is not open
+                warn!(self.log, "read: file not open: {:?}", &fid.pathbuf,);
+                return write_error(EINVAL as u32, chain, mem);
+            }
+        };
+        let metadata = match file.metadata() {
+            Ok(m) => m,
+            Err(e) => {
+                let ecode = e.raw_os_error().unwrap_or(0);
+                warn!(
+                    self.log,
+                    "read: metadata for {:?}: {:?}", &fid.pathbuf, e,
+                );
+                return write_error(ecode as u32, chain, mem);
+            }
+        };
+
+        // bail with empty response if offset is greater than file size
+        if metadata.len() < msg.offset {
+            warn!(
+                self.log,
+                "read: offset > file size: {} > {}",
+                msg.offset,
+                metadata.len(),
+            );
+            let response = Rread::new(Vec::new());
+            let mut out = ispf::to_bytes_le(&response).unwrap();
+            let buf = out.as_mut_slice();
+            return write_buf(buf, chain, mem);
+        }
+
+        let read_count = u32::min(msize, msg.count);
+
+        let overhead = size_of::<u32>() // Rread.size
+            + size_of::<MessageType>()  // Rread.typ
+            + size_of::<u16>()          // Rread.tag
+            + size_of::<u32>()          // Rread.data.len
+            + P9FS_VIRTIO_READ_HEADROOM;
+        let space_left = match (read_count as usize).checked_sub(overhead) {
+            Some(space_left) => space_left,
+            None => {
+                warn!(self.log, "read: count {} is too small", read_count);
+                return write_error(EINVAL as u32, chain, mem);
+            }
+        };
+
+        let buflen =
+            std::cmp::min(space_left, (metadata.len() - msg.offset) as usize);
+
+        p9_write_file(&file, chain, mem, buflen, msg.offset as i64);
+    }
+
+    fn do_statfs(&self, fid: &mut Fid, chain: &mut Chain, mem: &MemCtx) {
+        let path = match self.confine(&fid.pathbuf) {
+            Ok(path) => path,
+            Err(ecode) => return write_error(ecode, chain, mem),
+        };
+        let cpath = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(cpath) => cpath,
+            Err(_) => return write_error(EINVAL as u32, chain, mem),
+        };
+        let sfs = unsafe {
+            let mut sfs: libc::statvfs = std::mem::zeroed::<libc::statvfs>();
+            libc::statvfs(cpath.as_ptr(), &mut sfs);
+            sfs
+        };
+
+        // fstype: u32
+        let fstype = 0;
+        // bsize: u32
+        let bsize = sfs.f_bsize;
+        // blocks: u64
+        let blocks = sfs.f_blocks;
+        // bfree: u64
+        let bfree = sfs.f_bfree;
+        // bavail: u64
+        let bavail = sfs.f_bavail;
+        // files: u64
+        let files = sfs.f_files;
+        // ffree: u64
+        let ffree = sfs.f_ffree;
+        // fsid: u64
+        let fsid = sfs.f_fsid;
+        // namelen: u32
+        let namelen = sfs.f_namemax;
+
+        let resp = Rstatfs::new(
+            fstype,
+            bsize as u32,
+            blocks,
+            bfree,
+            bavail,
+            files,
+            ffree,
+            fsid,
+            namelen as u32,
+        );
+
+        let mut out = ispf::to_bytes_le(&resp).unwrap();
+        let buf = out.as_mut_slice();
+        write_buf(buf, chain, mem);
+    }
+
+    fn do_getattr(&self, fid: &mut Fid, chain: &mut Chain, mem: &MemCtx) {
+        let path = match self.confine(&fid.pathbuf) {
+            Ok(path) => path,
+            Err(ecode) => return write_error(ecode, chain, mem),
+        };
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(m) => m,
+            Err(e) => {
+                let ecode = e.raw_os_error().unwrap_or(0);
+                return write_error(ecode as u32, chain, mem);
+            }
+        };
+
+        // valid: u64,
+        let valid = P9_GETATTR_BASIC;
+        // qid: Qid,
+        let qid = Qid {
+            typ: wire::qid_type(&metadata),
+            version: metadata.mtime() as u32, //todo something better from ufs?
+            path: metadata.ino(),
+        };
+        // mode: u32,
+        let mode = metadata.mode();
+        // uid: u32,
+        //let uid = metadata.uid();
+        let uid = 0; //squash for now
+                     // gid: u32,
+                     //let gid = metadata.gid();
+        let gid = 0; //squash for now
+                     // nlink: u64,
+        let nlink = metadata.nlink();
+        // rdev: u64,
+        let rdev = metadata.rdev();
+        // attrsize: u64,
+        let attrsize = metadata.size();
+        // blksize: u64,
+        let blksize = metadata.blksize();
+        // blocks: u64,
+        let blocks = metadata.blocks();
+        // atime_sec: u64,
+        let atime_sec = metadata.atime();
+        // atime_nsec: u64,
+        let atime_nsec = metadata.atime_nsec();
+        // mtime_sec: u64,
+        let mtime_sec = metadata.mtime();
+        // mtime_nsec: u64,
+        let mtime_nsec = metadata.mtime_nsec();
+        // ctime_sec: u64,
+        let ctime_sec = metadata.ctime();
+        // ctime_nsec: u64,
+        let ctime_nsec = metadata.ctime_nsec();
+        // btime_sec: u64,
+        let btime_sec = 0; // reserved for future use in spec
+                           // btime_nsec: u64,
+        let btime_nsec = 0; // reserved for future use in spec
+                            // gen: u64,
+        let gen = 0; // reserved for future use in spec
+                     // data_version: u64,
+        let data_version = 0; // reserved for future use in spec
+
+        let resp = Rgetattr::new(
+            valid,
+            qid,
+            mode,
+            uid,
+            gid,
+            nlink,
+            rdev,
+            attrsize,
+            blksize,
+            blocks,
+            atime_sec as u64,
+            atime_nsec as u64,
+            mtime_sec as u64,
+            mtime_nsec as u64,
+            ctime_sec as u64,
+            ctime_nsec as u64,
+            btime_sec as u64,
+            btime_nsec as u64,
+            gen,
+            data_version,
+        );
+
+        let mut out = ispf::to_bytes_le(&resp).unwrap();
+        let buf = out.as_mut_slice();
+        write_buf(buf, chain, mem);
+    }
+
+    fn do_walk(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let msg: Twalk =
+            ispf::from_bytes_le(msg_buf).map_err(|_| EINVAL as u32)?;
+        let mut fs = self.lock_fileserver()?;
+
+        // check to see if fid exists
+        let fid = fs.fids.get(&msg.fid).ok_or_else(|| {
+            warn!(self.log, "walk: fid {} not found", msg.fid);
+            ENOENT as u32
+        })?;
+
+        // Walk one element at a time, producing a qid for each. Per the
+        // protocol, failing on the first element is an error, but failing on
+        // a later one returns the qids of the elements that were walked and
+        // leaves newfid unassigned.
+        let nwname = msg.wname.len();
+        let mut qids = Vec::with_capacity(nwname);
+        let mut newpath = fid.pathbuf.clone();
+        for n in msg.wname {
+            match n.value.as_str() {
+                "." => {}
+                ".." => {
+                    // As at the root of a Unix filesystem, ".." at the shared
+                    // root refers to the root itself.
+                    if newpath != self.root {
+                        newpath.pop();
+                    }
+                }
+                name => {
+                    if let Err(ecode) = check_name(name) {
+                        if qids.is_empty() {
+                            return Err(ecode);
+                        }
+                        break;
+                    }
+                    newpath.push(name);
+                }
+            }
+
+            let qid = self.confine(&newpath).and_then(|path| {
+                fs::symlink_metadata(path)
+                    .map(|m| qid_from_metadata(&m))
+                    .map_err(|e| wire::io_errno(&e))
+            });
+            match qid {
+                Ok(qid) => qids.push(qid),
+                Err(ecode) if qids.is_empty() => {
+                    warn!(self.log, "walk: {:?}: error {}", newpath, ecode);
+                    return Err(ecode);
+                }
+                Err(_) => break,
+            }
+        }
+
+        // The spec says to throw an error if newfid is in use, but in an
+        // effort to support clients who don't explicitly clunk fids, just
+        // replace it.
+        if qids.len() == nwname {
+            fs.fids.insert(msg.newfid, Fid::new(newpath));
+        }
+
+        Ok(ispf::to_bytes_le(&Rwalk::new(qids)).unwrap())
+    }
+
+    fn do_open(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let fid_num = rd.u32()?;
+        let flags = rd.u32()?;
+
+        if flags & wire::L_O_ACCMODE != wire::L_O_RDONLY
+            || flags & wire::L_O_TRUNC != 0
+        {
+            self.check_writable()?;
+        }
+
+        let mut fs = self.lock_fileserver()?;
+        let fid = fs.fids.get_mut(&fid_num).ok_or_else(|| {
+            warn!(self.log, "open: fid {} not found", fid_num);
+            ENOENT as u32
+        })?;
+
+        let path = self.confine(&fid.pathbuf)?;
+        let file =
+            open_options(flags, libc::O_NOFOLLOW).open(&path).map_err(|e| {
+                warn!(self.log, "open: {:?}: {:?}", &path, e);
+                wire::io_errno(&e)
+            })?;
+        let qid = file
+            .metadata()
+            .map(|m| qid_from_metadata(&m))
+            .map_err(|e| wire::io_errno(&e))?;
+        fid.file = Some(file);
+
+        Ok(ispf::to_bytes_le(&Rlopen::new(qid, 0)).unwrap())
+    }
+
+    fn do_lcreate(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let fid_num = rd.u32()?;
+        let name = rd.string()?;
+        let flags = rd.u32()?;
+        let mode = rd.u32()?;
+        let _gid = rd.u32()?;
+        self.check_writable()?;
+
+        let mut fs = self.lock_fileserver()?;
+        let fid = fs.fids.get_mut(&fid_num).ok_or(ENOENT as u32)?;
+        let path = self.child(&fid.pathbuf, &name)?;
+
+        let mut custom = libc::O_NOFOLLOW | libc::O_CREAT;
+        if flags & wire::L_O_EXCL != 0 {
+            custom |= libc::O_EXCL;
+        }
+        let file = open_options(flags, custom)
+            .mode(mode & GUEST_MODE_MASK)
+            .open(&path)
+            .map_err(|e| wire::io_errno(&e))?;
+        let qid = file
+            .metadata()
+            .map(|m| qid_from_metadata(&m))
+            .map_err(|e| wire::io_errno(&e))?;
+
+        // The fid now represents the newly created file.
+        fid.pathbuf = path;
+        fid.file = Some(file);
+
+        Ok(wire::Writer::new(wire::RLCREATE, rd.tag())
+            .qid(&qid)
+            .u32(0)
+            .finish())
+    }
+
+    fn do_mkdir(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let dfid = rd.u32()?;
+        let name = rd.string()?;
+        let mode = rd.u32()?;
+        let _gid = rd.u32()?;
+        self.check_writable()?;
+
+        let fs = self.lock_fileserver()?;
+        let dir = &fs.fids.get(&dfid).ok_or(ENOENT as u32)?.pathbuf;
+        let path = self.child(dir, &name)?;
+        fs::DirBuilder::new()
+            .mode(mode & GUEST_MODE_MASK)
+            .create(&path)
+            .map_err(|e| wire::io_errno(&e))?;
+
+        let qid = lstat_qid(&path)?;
+        Ok(wire::Writer::new(wire::RMKDIR, rd.tag()).qid(&qid).finish())
+    }
+
+    fn do_symlink(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let dfid = rd.u32()?;
+        let name = rd.string()?;
+        let target = rd.string()?;
+        let _gid = rd.u32()?;
+        self.check_writable()?;
+
+        let fs = self.lock_fileserver()?;
+        let dir = &fs.fids.get(&dfid).ok_or(ENOENT as u32)?.pathbuf;
+        let path = self.child(dir, &name)?;
+        std::os::unix::fs::symlink(&target, &path)
+            .map_err(|e| wire::io_errno(&e))?;
+
+        let qid = lstat_qid(&path)?;
+        Ok(wire::Writer::new(wire::RSYMLINK, rd.tag()).qid(&qid).finish())
+    }
+
+    fn do_readlink(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let fid_num = rd.u32()?;
+
+        let fs = self.lock_fileserver()?;
+        let fid = fs.fids.get(&fid_num).ok_or(ENOENT as u32)?;
+        let path = self.confine(&fid.pathbuf)?;
+        let target = fs::read_link(path).map_err(|e| wire::io_errno(&e))?;
+        let target = target.to_str().ok_or(wire::L_EILSEQ)?;
+
+        Ok(wire::Writer::new(wire::RREADLINK, rd.tag()).string(target).finish())
+    }
+
+    fn do_rename(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let fid_num = rd.u32()?;
+        let dfid = rd.u32()?;
+        let name = rd.string()?;
+        self.check_writable()?;
+
+        let mut fs = self.lock_fileserver()?;
+        let from = &fs.fids.get(&fid_num).ok_or(ENOENT as u32)?.pathbuf;
+        if *from == self.root {
+            return Err(EBUSY as u32);
+        }
+        let from = self.confine(from)?;
+        let dir = &fs.fids.get(&dfid).ok_or(ENOENT as u32)?.pathbuf;
+        let to = self.child(dir, &name)?;
+
+        fs::rename(&from, &to).map_err(|e| wire::io_errno(&e))?;
+        fs.rename_fids(&from, &to);
+        Ok(wire::Writer::new(wire::RRENAME, rd.tag()).finish())
+    }
+
+    fn do_renameat(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let old_dfid = rd.u32()?;
+        let old_name = rd.string()?;
+        let new_dfid = rd.u32()?;
+        let new_name = rd.string()?;
+        self.check_writable()?;
+
+        let mut fs = self.lock_fileserver()?;
+        let old_dir = &fs.fids.get(&old_dfid).ok_or(ENOENT as u32)?.pathbuf;
+        let from = self.child(old_dir, &old_name)?;
+        let new_dir = &fs.fids.get(&new_dfid).ok_or(ENOENT as u32)?.pathbuf;
+        let to = self.child(new_dir, &new_name)?;
+
+        fs::rename(&from, &to).map_err(|e| wire::io_errno(&e))?;
+        fs.rename_fids(&from, &to);
+        Ok(wire::Writer::new(wire::RRENAMEAT, rd.tag()).finish())
+    }
+
+    fn do_unlinkat(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let dfid = rd.u32()?;
+        let name = rd.string()?;
+        let flags = rd.u32()?;
+        self.check_writable()?;
+
+        let fs = self.lock_fileserver()?;
+        let dir = &fs.fids.get(&dfid).ok_or(ENOENT as u32)?.pathbuf;
+        let path = self.child(dir, &name)?;
+        if flags & wire::L_AT_REMOVEDIR != 0 {
+            fs::remove_dir(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+        .map_err(|e| wire::io_errno(&e))?;
+
+        Ok(wire::Writer::new(wire::RUNLINKAT, rd.tag()).finish())
+    }
+
+    fn do_remove(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let fid_num = rd.u32()?;
+
+        // The fid is clunked whether or not the removal succeeds.
+        let fid = self.lock_fileserver()?.fids.remove(&fid_num);
+        let fid = fid.ok_or(ENOENT as u32)?;
+        self.check_writable()?;
+        if fid.pathbuf == self.root {
+            return Err(EBUSY as u32);
+        }
+
+        let path = self.confine(&fid.pathbuf)?;
+        let metadata =
+            fs::symlink_metadata(&path).map_err(|e| wire::io_errno(&e))?;
+        if metadata.is_dir() {
+            fs::remove_dir(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+        .map_err(|e| wire::io_errno(&e))?;
+
+        Ok(wire::Writer::new(wire::RREMOVE, rd.tag()).finish())
+    }
+
+    fn do_setattr(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let fid_num = rd.u32()?;
+        let valid = rd.u32()?;
+        let mode = rd.u32()?;
+        let _uid = rd.u32()?;
+        let _gid = rd.u32()?;
+        let size = rd.u64()?;
+        let atime = (rd.u64()?, rd.u64()?);
+        let mtime = (rd.u64()?, rd.u64()?);
+        self.check_writable()?;
+
+        let fs = self.lock_fileserver()?;
+        let fid = fs.fids.get(&fid_num).ok_or(ENOENT as u32)?;
+        let path = self.confine(&fid.pathbuf)?;
+        let metadata =
+            fs::symlink_metadata(&path).map_err(|e| wire::io_errno(&e))?;
+
+        // Symlinks have no meaningful mode of their own, and setting one
+        // would follow the link, so mode changes to them are ignored.
+        if valid & wire::P9_SETATTR_MODE != 0 && !metadata.is_symlink() {
+            fs::set_permissions(
+                &path,
+                fs::Permissions::from_mode(mode & GUEST_MODE_MASK),
+            )
+            .map_err(|e| wire::io_errno(&e))?;
+        }
+
+        // File ownership is squashed (see `do_getattr`), so requests to change
+        // it (P9_SETATTR_UID and P9_SETATTR_GID) are accepted but have no
+        // effect.
+
+        if valid & wire::P9_SETATTR_SIZE != 0 {
+            if metadata.is_dir() {
+                return Err(EISDIR as u32);
+            }
+            fs::OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&path)
+                .and_then(|f| f.set_len(size))
+                .map_err(|e| wire::io_errno(&e))?;
+        }
+
+        if valid & (wire::P9_SETATTR_ATIME | wire::P9_SETATTR_MTIME) != 0 {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let now = (now.as_secs(), now.subsec_nanos() as u64);
+            let pick = |set: u32, set_to: u32, given, current| {
+                if valid & set == 0 {
+                    current
+                } else if valid & set_to != 0 {
+                    given
+                } else {
+                    now
+                }
+            };
+            let atime = pick(
+                wire::P9_SETATTR_ATIME,
+                wire::P9_SETATTR_ATIME_SET,
+                atime,
+                (metadata.atime() as u64, metadata.atime_nsec() as u64),
+            );
+            let mtime = pick(
+                wire::P9_SETATTR_MTIME,
+                wire::P9_SETATTR_MTIME_SET,
+                mtime,
+                (metadata.mtime() as u64, metadata.mtime_nsec() as u64),
+            );
+            set_times(&path, atime, mtime)?;
+        }
+
+        Ok(wire::Writer::new(wire::RSETATTR, rd.tag()).finish())
+    }
+
+    fn do_fsync(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let fid_num = rd.u32()?;
+
+        let fs = self.lock_fileserver()?;
+        let fid = fs.fids.get(&fid_num).ok_or(ENOENT as u32)?;
+        let file = fid.file.as_ref().ok_or(EBADF as u32)?;
+        file.sync_all().map_err(|e| wire::io_errno(&e))?;
+
+        Ok(wire::Writer::new(wire::RFSYNC, rd.tag()).finish())
+    }
+
+    fn do_write(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let fid_num = rd.u32()?;
+        let offset = rd.u64()?;
+        let data = rd.bytes()?;
+        self.check_writable()?;
+
+        let mut fs = self.lock_fileserver()?;
+        let fid = fs.fids.get_mut(&fid_num).ok_or(ENOENT as u32)?;
+        let written = match (&mut fid.xattr, &fid.file) {
+            (Some(Xattr::Write { size, data: value, .. }), _) => {
+                if value.len() as u64 + data.len() as u64 > *size {
+                    return Err(E2BIG as u32);
+                }
+                value.extend_from_slice(data);
+                data.len()
+            }
+            (Some(Xattr::Read(_)), _) | (None, None) => {
+                return Err(EBADF as u32)
+            }
+            (None, Some(file)) => {
+                file.write_at(data, offset).map_err(|e| wire::io_errno(&e))?
+            }
+        };
+
+        Ok(ispf::to_bytes_le(&Rwrite::new(written as u32)).unwrap())
+    }
+
+    fn do_clunk(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let fid_num = rd.u32()?;
+        let fid = self.lock_fileserver()?.fids.remove(&fid_num);
+
+        // A pending extended attribute is stored once all of it has been
+        // written.
+        if let Some(Fid {
+            pathbuf,
+            xattr: Some(Xattr::Write { name, size, flags, data }),
+            ..
+        }) = fid
+        {
+            if data.len() as u64 != size {
+                return Err(EINVAL as u32);
+            }
+            let path = self.confine(&pathbuf)?;
+            if size == 0 {
+                xattr::remove(&path, &name)?;
+            } else {
+                xattr::set(&path, &name, &data, flags)?;
+            }
+        }
+
+        Ok(ispf::to_bytes_le(&Rclunk::new()).unwrap())
+    }
+
+    fn do_xattrwalk(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let fid_num = rd.u32()?;
+        let newfid = rd.u32()?;
+        let name = rd.string()?;
+
+        let mut fs = self.lock_fileserver()?;
+        let fid = fs.fids.get(&fid_num).ok_or(ENOENT as u32)?;
+        let path = self.confine(&fid.pathbuf)?;
+
+        // An empty name requests the list of attribute names.
+        let value = if name.is_empty() {
+            xattr::list(&path)?
+        } else {
+            xattr::get(&path, &name)?
+        };
+        let size = value.len() as u64;
+        fs.fids.insert(
+            newfid,
+            Fid { pathbuf: path, file: None, xattr: Some(Xattr::Read(value)) },
+        );
+
+        Ok(wire::Writer::new(wire::RXATTRWALK, rd.tag()).u64(size).finish())
+    }
+
+    fn do_xattrcreate(&self, msg_buf: &[u8]) -> Result<Vec<u8>, u32> {
+        let mut rd = wire::Reader::new(msg_buf);
+        let fid_num = rd.u32()?;
+        let name = rd.string()?;
+        let size = rd.u64()?;
+        let flags = rd.u32()?;
+        self.check_writable()?;
+        if size > wire::XATTR_SIZE_MAX {
+            return Err(E2BIG as u32);
+        }
+
+        let mut fs = self.lock_fileserver()?;
+        let fid = fs.fids.get_mut(&fid_num).ok_or(ENOENT as u32)?;
+        fid.file = None;
+        fid.xattr = Some(Xattr::Write { name, size, flags, data: Vec::new() });
+
+        Ok(wire::Writer::new(wire::RXATTRCREATE, rd.tag()).finish())
+    }
+}
+
+impl P9Handler for HostFSHandler {
+    fn source(&self) -> &str {
+        &self.source
+    }
+
+    fn target(&self) -> &str {
+        &self.target
+    }
+
+    fn msize(&self) -> u32 {
+        match self.msize.lock() {
+            Ok(msize) => *msize,
+            Err(e) => {
+                warn!(self.log, "handle_req: failed to get msize lock: {}", e);
+                self.max_chunk_size
+            }
+        }
+    }
+
+    fn handle_version(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        let mut msg: Version = match ispf::from_bytes_le(msg_buf) {
+            Ok(msg) => msg,
+            Err(_) => return write_error(EINVAL as u32, chain, mem),
+        };
+        msg.version = P9Version::V2000L.to_string();
+        msg.typ = MessageType::Rversion;
+        if msg.msize > self.max_chunk_size {
+            warn!(
+                self.log,
+                "request exceeds max chunk size {} > {}",
+                msg.msize,
+                self.max_chunk_size
+            );
+            return write_error(EOVERFLOW as u32, chain, mem);
+        }
+        // TODO this is likely bad for multiple clients with different msizes,
+        // should be a session level variable.
+        match self.msize.lock() {
+            Ok(mut msize) => *msize = msg.msize,
+            Err(e) => {
+                warn!(
+                    self.log,
+                    "handle_version: failed to get msize lock: {}", e
+                );
+            }
+        }
+        let mut out = ispf::to_bytes_le(&msg).unwrap();
+        let buf = out.as_mut_slice();
+        write_buf(buf, chain, mem);
+    }
+
+    fn handle_attach(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        //NOTE:
+        //  - multiple file trees not supported, aname is ignored
+        //  - authentication not supported afid is ignored
+        //  - users not tracked, uname is ignored
+
+        // deserialize message
+        let msg: Tattach = match ispf::from_bytes_le(msg_buf) {
+            Ok(msg) => msg,
+            Err(_) => return write_error(EINVAL as u32, chain, mem),
+        };
+
+        // grab inode number for qid uniqe file id
+        let qpath = match fs::metadata(&self.root) {
+            Err(e) => {
+                let ecode = e.raw_os_error().unwrap_or(0);
+                return write_error(ecode as u32, chain, mem);
+            }
+            Ok(m) => m.ino(),
+        };
+
+        match self.fileserver.lock() {
+            Ok(mut fs) => {
+                // check to see if fid is in use
+                match fs.fids.get(&msg.fid) {
+                    Some(_) => {
+                        warn!(self.log, "attach fid in use: {}", msg.fid);
+                        // The spec says to throw an error here, but in an
+                        // effort to support clients who don't explicitly cluck
+                        // fids, and considering the fact that we do not support
+                        // multiple fs trees, just carry on
+                        //return write_error(EEXIST as u32, chain, mem);
+                    }
+                    None => {
+                        // create fid entry
+                        fs.fids.insert(msg.fid, Fid::new(self.root.clone()));
+                    }
+                };
+            }
+            Err(_) => {
+                return write_error(ENOLCK as u32, chain, mem);
+            }
+        }
+
+        // send response
+        let response =
+            Rattach::new(Qid { typ: QidType::Dir, version: 0, path: qpath });
+        let mut out = ispf::to_bytes_le(&response).unwrap();
+        let buf = out.as_mut_slice();
+        write_buf(buf, chain, mem);
+    }
+
+    fn handle_walk(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        reply(self.do_walk(msg_buf), chain, mem)
+    }
+
+    fn handle_open(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        reply(self.do_open(msg_buf), chain, mem)
+    }
+
+    fn handle_readdir(
+        &self,
+        msg_buf: &[u8],
+        chain: &mut Chain,
+        mem: &MemCtx,
+        msize: u32,
+    ) {
+        let msg: Treaddir = match ispf::from_bytes_le(msg_buf) {
+            Ok(msg) => msg,
+            Err(_) => return write_error(EINVAL as u32, chain, mem),
+        };
+
+        // get the path for the requested fid
+        let pathbuf = match self.fileserver.lock() {
+            Ok(fs) => match fs.fids.get(&msg.fid) {
+                Some(f) => f.pathbuf.clone(),
+                None => {
+                    warn!(self.log, "readdir: fid {} not found", msg.fid);
+                    return write_error(ENOENT as u32, chain, mem);
+                }
+            },
+            Err(_) => {
+                return write_error(ENOLCK as u32, chain, mem);
+            }
+        };
+        let pathbuf = match self.confine_dir(&pathbuf) {
+            Ok(path) => path,
+            Err(ecode) => return write_error(ecode, chain, mem),
+        };
+
+        // read the directory at the provided path
+        let mut dir = match fs::read_dir(&pathbuf) {
+            Ok(r) => match r.collect::<Result<Vec<fs::DirEntry>, _>>() {
+                Ok(d) => d,
+                Err(e) => {
+                    let ecode = e.raw_os_error().unwrap_or(0);
+                    warn!(
+                        self.log,
+                        "readdir: collect: {:?}: {:?}", &pathbuf, e
+                    );
+                    return write_error(ecode as u32, chain, mem);
+                }
+            },
+            Err(e) => {
+                let ecode = e.raw_os_error().unwrap_or(0);
+                warn!(self.log, "readdir: {:?}: {:?}", &pathbuf, e);
+                return write_error(ecode as u32, chain, mem);
+            }
+        };
+
+        // bail with out of range error if offset is greater than entries
+        if (dir.len() as u64) < msg.offset {
+            return write_error(ERANGE as u32, chain, mem);
+        }
+
+        // need to sort to ensure consistent offsets
+        dir.sort_by_key(|a| a.path());
+
+        let overhead = size_of::<u32>() // Rreaddir.size
+            + size_of::<MessageType>()  // Rreaddir.typ
+            + size_of::<u16>()          // Rreaddir.tag
+            + size_of::<u32>(); // Rreaddir.data.len
+        let Some(mut space_left) = (msize as usize).checked_sub(overhead)
+        else {
+            return write_error(EINVAL as u32, chain, mem);
+        };
+
+        let mut entries: Vec<proto::Dirent> = Vec::new();
+
+        let mut offset = msg.offset + 1;
+        for de in &dir[msg.offset as usize..] {
+            let metadata = match de.metadata() {
+                Ok(m) => m,
+                Err(e) => {
+                    let ecode = e.raw_os_error().unwrap_or(0);
+                    warn!(
+                        self.log,
+                        "readdir: metadata: {:?}: {:?}",
+                        &de.path(),
+                        e
+                    );
+                    return write_error(ecode as u32, chain, mem);
+                }
+            };
+
+            let typ = wire::qid_type(&metadata);
+            let ftyp = if metadata.is_dir() {
+                DT_DIR
+            } else if metadata.is_symlink() {
+                DT_LNK
+            } else {
+                DT_REG
+            };
+
+            let qid = Qid { typ, version: 0, path: metadata.ino() };
+
+            let name = match de.file_name().into_string() {
+                Ok(n) => n,
+                Err(_) => {
+                    // getting a bit esoteric with our error codes here...
+                    return write_error(EILSEQ as u32, chain, mem);
+                }
+            };
+
+            let dirent = Dirent { qid, offset, typ: ftyp, name };
+
+            if space_left <= dirent.wire_size() {
+                break;
+            }
+
+            space_left -= dirent.wire_size();
+            entries.push(dirent);
+            offset += 1;
+        }
+
+        let response = Rreaddir::new(entries);
+        let mut out = ispf::to_bytes_le(&response).unwrap();
+        let buf = out.as_mut_slice();
+        write_buf(buf, chain, mem);
+    }
+
+    fn handle_read(
+        &self,
+        msg_buf: &[u8],
+        chain: &mut Chain,
+        mem: &MemCtx,
+        msize: u32,
+    ) {
+        let msg: Tread = match ispf::from_bytes_le(msg_buf) {
+            Ok(msg) => msg,
+            Err(_) => return write_error(EINVAL as u32, chain, mem),
+        };
+
+        // get  the requested fid
+        match self.fileserver.lock() {
+            Ok(ref mut fs) => match fs.fids.get_mut(&msg.fid) {
+                Some(ref mut fid) => self.do_read(&msg, fid, chain, mem, msize),
+                None => {
+                    warn!(self.log, "read: fid {} not found", msg.fid);
+                    return write_error(ENOENT as u32, chain, mem);
+                }
+            },
+            Err(_) => {
+                return write_error(ENOLCK as u32, chain, mem);
+            }
+        };
+    }
+
+    fn handle_write(
+        &self,
+        msg_buf: &[u8],
+        chain: &mut Chain,
+        mem: &MemCtx,
+        _msize: u32,
+    ) {
+        reply(self.do_write(msg_buf), chain, mem)
+    }
+
+    fn handle_clunk(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        reply(self.do_clunk(msg_buf), chain, mem)
+    }
+
+    fn handle_getattr(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        let msg: Tgetattr = match ispf::from_bytes_le(msg_buf) {
+            Ok(msg) => msg,
+            Err(_) => return write_error(EINVAL as u32, chain, mem),
+        };
+        match self.fileserver.lock() {
+            Ok(ref mut fs) => match fs.fids.get_mut(&msg.fid) {
+                Some(ref mut fid) => self.do_getattr(fid, chain, mem),
+                None => {
+                    warn!(self.log, "getattr: fid {} not found", msg.fid);
+                    return write_error(ENOENT as u32, chain, mem);
+                }
+            },
+            Err(_) => {
+                return write_error(ENOLCK as u32, chain, mem);
+            }
+        }
+    }
+
+    fn handle_statfs(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        let msg: Tstatfs = match ispf::from_bytes_le(msg_buf) {
+            Ok(msg) => msg,
+            Err(_) => return write_error(EINVAL as u32, chain, mem),
+        };
+        match self.fileserver.lock() {
+            Ok(ref mut fs) => match fs.fids.get_mut(&msg.fid) {
+                Some(ref mut fid) => self.do_statfs(fid, chain, mem),
+                None => {
+                    warn!(self.log, "statfs: fid {} not found", msg.fid);
+                    return write_error(ENOENT as u32, chain, mem);
+                }
+            },
+            Err(_) => {
+                return write_error(ENOLCK as u32, chain, mem);
+            }
+        }
+    }
+
+    fn handle_lcreate(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        reply(self.do_lcreate(msg_buf), chain, mem)
+    }
+
+    fn handle_mkdir(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        reply(self.do_mkdir(msg_buf), chain, mem)
+    }
+
+    fn handle_symlink(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        reply(self.do_symlink(msg_buf), chain, mem)
+    }
+
+    fn handle_readlink(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        reply(self.do_readlink(msg_buf), chain, mem)
+    }
+
+    fn handle_rename(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        reply(self.do_rename(msg_buf), chain, mem)
+    }
+
+    fn handle_renameat(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        reply(self.do_renameat(msg_buf), chain, mem)
+    }
+
+    fn handle_unlinkat(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        reply(self.do_unlinkat(msg_buf), chain, mem)
+    }
+
+    fn handle_remove(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        reply(self.do_remove(msg_buf), chain, mem)
+    }
+
+    fn handle_setattr(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        reply(self.do_setattr(msg_buf), chain, mem)
+    }
+
+    fn handle_fsync(&self, msg_buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
+        reply(self.do_fsync(msg_buf), chain, mem)
+    }
+
+    fn handle_xattrwalk(
+        &self,
+        msg_buf: &[u8],
+        chain: &mut Chain,
+        mem: &MemCtx,
+    ) {
+        reply(self.do_xattrwalk(msg_buf), chain, mem)
+    }
+
+    fn handle_xattrcreate(
+        &self,
+        msg_buf: &[u8],
+        chain: &mut Chain,
+        mem: &MemCtx,
+    ) {
+        reply(self.do_xattrcreate(msg_buf), chain, mem)
+    }
+}
+
+/// Checks that `name` names a single entry in a directory.
+fn check_name(name: &str) -> Result<(), u32> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.contains('/') => Ok(()),
+        _ => Err(EINVAL as u32),
+    }
+}
+
+/// Builds the options with which to open a file given the Linux open flags
+/// `flags` and additional host flags `custom`.
+fn open_options(flags: u32, custom: i32) -> fs::OpenOptions {
+    let mut opts = fs::OpenOptions::new();
+    match flags & wire::L_O_ACCMODE {
+        wire::L_O_WRONLY => opts.write(true),
+        wire::L_O_RDWR => opts.read(true).write(true),
+        _ => opts.read(true),
+    };
+    if flags & wire::L_O_APPEND != 0 {
+        opts.append(true);
+    }
+    if flags & wire::L_O_TRUNC != 0 {
+        opts.truncate(true);
+    }
+    opts.custom_flags(custom);
+    opts
+}
+
+fn qid_from_metadata(metadata: &fs::Metadata) -> Qid {
+    Qid { typ: wire::qid_type(metadata), version: 0, path: metadata.ino() }
+}
+
+fn lstat_qid(path: &Path) -> Result<Qid, u32> {
+    fs::symlink_metadata(path)
+        .map(|m| qid_from_metadata(&m))
+        .map_err(|e| wire::io_errno(&e))
+}
+
+/// Sets the access and modification times of `path`, without following a
+/// symlink at `path`, to the given `(seconds, nanoseconds)` pairs.
+fn set_times(
+    path: &Path,
+    atime: (u64, u64),
+    mtime: (u64, u64),
+) -> Result<(), u32> {
+    let cpath =
+        CString::new(path.as_os_str().as_bytes()).map_err(|_| EINVAL as u32)?;
+    let times = [
+        libc::timespec { tv_sec: atime.0 as _, tv_nsec: atime.1 as _ },
+        libc::timespec { tv_sec: mtime.0 as _, tv_nsec: mtime.1 as _ },
+    ];
+    let res = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            cpath.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if res != 0 {
+        return Err(wire::io_errno(&std::io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+/// Writes the result of a handler to the guest: either the encoded response or
+/// an `Rlerror` carrying the error code.
+fn reply(result: Result<Vec<u8>, u32>, chain: &mut Chain, mem: &MemCtx) {
+    match result {
+        Ok(out) => write_buf(&out, chain, mem),
+        Err(ecode) => write_error(ecode, chain, mem),
+    }
+}
+
+/// Access to extended attributes on the host. On illumos these are stored as
+/// files in a hidden attribute directory associated with each file.
+#[cfg(target_os = "illumos")]
+mod xattr {
+    use std::ffi::{CStr, CString};
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::Path;
+
+    use super::wire;
+    use libc::EINVAL;
+
+    fn attr_name(name: &str) -> Result<CString, u32> {
+        super::check_name(name)?;
+        CString::new(name).map_err(|_| EINVAL as u32)
+    }
+
+    fn open_file(path: &Path) -> Result<File, u32> {
+        std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(path)
+            .map_err(|e| wire::io_errno(&e))
+    }
+
+    fn openat(
+        file: &File,
+        name: &CStr,
+        flags: i32,
+    ) -> Result<OwnedFd, std::io::Error> {
+        let fd = unsafe {
+            libc::openat(
+                file.as_raw_fd(),
+                name.as_ptr(),
+                flags | libc::O_XATTR,
+                0o644,
+            )
+        };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    /// Maps a failure to find an attribute to the error Linux reports for it.
+    fn missing_is_enodata(e: std::io::Error) -> u32 {
+        if e.raw_os_error() == Some(libc::ENOENT) {
+            wire::L_ENODATA
+        } else {
+            wire::io_errno(&e)
+        }
+    }
+
+    pub(super) fn get(path: &Path, name: &str) -> Result<Vec<u8>, u32> {
+        let name = attr_name(name)?;
+        let file = open_file(path)?;
+        let fd =
+            openat(&file, &name, libc::O_RDONLY).map_err(missing_is_enodata)?;
+        let mut value = Vec::new();
+        File::from(fd)
+            .read_to_end(&mut value)
+            .map_err(|e| wire::io_errno(&e))?;
+        Ok(value)
+    }
+
+    pub(super) fn set(
+        path: &Path,
+        name: &str,
+        value: &[u8],
+        flags: u32,
+    ) -> Result<(), u32> {
+        let name = attr_name(name)?;
+        let file = open_file(path)?;
+        let mut oflags = libc::O_WRONLY | libc::O_TRUNC;
+        if flags & wire::XATTR_REPLACE == 0 {
+            oflags |= libc::O_CREAT;
+        }
+        if flags & wire::XATTR_CREATE != 0 {
+            oflags |= libc::O_EXCL;
+        }
+        let fd = openat(&file, &name, oflags).map_err(missing_is_enodata)?;
+        File::from(fd).write_all(value).map_err(|e| wire::io_errno(&e))
+    }
+
+    pub(super) fn remove(path: &Path, name: &str) -> Result<(), u32> {
+        let name = attr_name(name)?;
+        let file = open_file(path)?;
+        let dir = openat(&file, c".", libc::O_RDONLY)
+            .map_err(|e| wire::io_errno(&e))?;
+        let res = unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) };
+        if res != 0 {
+            return Err(missing_is_enodata(std::io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the names of the attributes of `path`, each terminated by a
+    /// NUL, as Linux's listxattr(2) does. System attributes that illumos
+    /// exposes in the attribute directory are omitted.
+    pub(super) fn list(path: &Path) -> Result<Vec<u8>, u32> {
+        let file = open_file(path)?;
+        let dir = openat(&file, c".", libc::O_RDONLY)
+            .map_err(|e| wire::io_errno(&e))?;
+
+        let mut names = Vec::new();
+        unsafe {
+            // On success the DIR owns the descriptor and closedir closes it.
+            let dirp = libc::fdopendir(dir.as_raw_fd());
+            if dirp.is_null() {
+                return Err(wire::io_errno(&std::io::Error::last_os_error()));
+            }
+            std::mem::forget(dir);
+
+            loop {
+                let ent = libc::readdir(dirp);
+                if ent.is_null() {
+                    break;
+                }
+
+                let name = CStr::from_ptr((*ent).d_name.as_ptr()).to_bytes();
+                if name == b"."
+                    || name == b".."
+                    || name.starts_with(b"SUNWattr_")
+                {
+                    continue;
+                }
+                names.extend_from_slice(name);
+                names.push(0);
+            }
+            libc::closedir(dirp);
+        }
+
+        Ok(names)
+    }
+}
+
+/// Extended attributes are only supported on illumos hosts.
+#[cfg(not(target_os = "illumos"))]
+mod xattr {
+    use std::path::Path;
+
+    use super::wire::L_EOPNOTSUPP;
+
+    pub(super) fn get(_path: &Path, _name: &str) -> Result<Vec<u8>, u32> {
+        Err(L_EOPNOTSUPP)
+    }
+
+    pub(super) fn set(
+        _path: &Path,
+        _name: &str,
+        _value: &[u8],
+        _flags: u32,
+    ) -> Result<(), u32> {
+        Err(L_EOPNOTSUPP)
+    }
+
+    pub(super) fn remove(_path: &Path, _name: &str) -> Result<(), u32> {
+        Err(L_EOPNOTSUPP)
+    }
+
+    pub(super) fn list(_path: &Path) -> Result<Vec<u8>, u32> {
+        Err(L_EOPNOTSUPP)
+    }
+}
+
+pub(crate) fn write_error(ecode: u32, chain: &mut Chain, mem: &MemCtx) {
+    let msg = Rlerror::new(ecode);
+    let mut out = ispf::to_bytes_le(&msg).unwrap();
+    let buf = out.as_mut_slice();
+    write_buf(buf, chain, mem);
+}
+
+fn p9_write_file(
+    file: &File,
+    chain: &mut Chain,
+    mem: &MemCtx,
+    count: usize,
+    offset: i64,
+) {
+    // Form the rread header. Unfortunately we can't do this with the Rread
+    // structure because the count is baked into the data field which is tied
+    // to the length of the vector and filling that vector is what we're
+    // explicitly trying to avoid here.
+    #[repr(C, packed)]
+    #[derive(Copy, Clone)]
+    struct Header {
+        size: u32,
+        typ: u8,
+        tag: u16,
+        count: u32,
+    }
+
+    let size = size_of::<Header>() + count;
+
+    let h = Header {
+        size: size as u32,
+        typ: MessageType::Rread as u8,
+        tag: 0,
+        count: count as u32,
+    };
+
+    chain.write(&h, mem);
+
+    // Send the header to the guest from the buffer constructed above. Then
+    // send the actual file data
+    // If the guest's buffers can't be mapped or the file can't be read, the
+    // copy stops short, leaving the guest with fewer bytes than the header
+    // promised.
+    let mut done = 0;
+    let _total = chain.for_remaining_type(false, |addr, len| {
+        let Some(sub_mapping) = mem.writable_region(&GuestRegion(addr, len))
+        else {
+            return (0, false);
+        };
+        let len = usize::min(len, count - done);
+        let off = offset + done as i64;
+        let Ok(mapped) = sub_mapping.pread(file, len, off) else {
+            return (0, false);
+        };
+        done += mapped;
+
+        let need_more = mapped != 0 && done < count;
+        (mapped, need_more)
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ROOT_FID: u32 = 1;
+
+    fn handler(dir: &Path, read_only: bool) -> HostFSHandler {
+        let log = Logger::root(slog::Discard, slog::o!());
+        let source = dir.to_str().unwrap().to_string();
+        let handler = HostFSHandler::new(
+            source,
+            "test".to_string(),
+            8192,
+            read_only,
+            log,
+        );
+        let root = Fid::new(handler.root.clone());
+        handler.fileserver.lock().unwrap().fids.insert(ROOT_FID, root);
+        handler
+    }
+
+    fn walk_msg(fid: u32, newfid: u32, names: &[&str]) -> Vec<u8> {
+        let mut msg = vec![0, 0, 0, 0, MessageType::Twalk as u8, 0, 0];
+        msg.extend_from_slice(&fid.to_le_bytes());
+        msg.extend_from_slice(&newfid.to_le_bytes());
+        msg.extend_from_slice(&(names.len() as u16).to_le_bytes());
+        for name in names {
+            msg.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            msg.extend_from_slice(name.as_bytes());
+        }
+        let len = msg.len() as u32;
+        msg[..4].copy_from_slice(&len.to_le_bytes());
+        msg
+    }
+
+    #[test]
+    fn create_write_rename_and_remove() {
+        let dir = tempfile::tempdir().unwrap();
+        let h = handler(dir.path(), false);
+
+        let mkdir = wire::Writer::new(wire::TMKDIR, 0)
+            .u32(ROOT_FID)
+            .string("sub")
+            .u32(0o755)
+            .u32(0)
+            .finish();
+        h.do_mkdir(&mkdir).unwrap();
+        assert!(dir.path().join("sub").is_dir());
+
+        h.do_walk(&walk_msg(ROOT_FID, 2, &["sub"])).unwrap();
+        let create = wire::Writer::new(wire::TLCREATE, 0)
+            .u32(2)
+            .string("file")
+            .u32(wire::L_O_RDWR)
+            .u32(0o644)
+            .u32(0)
+            .finish();
+        h.do_lcreate(&create).unwrap();
+
+        let mut write = wire::Writer::new(MessageType::Twrite as u8, 0)
+            .u32(2)
+            .u64(0)
+            .u32(5)
+            .finish();
+        write.extend_from_slice(b"hello");
+        write[..4].copy_from_slice(&(write.len() as u32).to_le_bytes());
+        h.do_write(&write).unwrap();
+        assert_eq!(fs::read(dir.path().join("sub/file")).unwrap(), b"hello");
+
+        let rename = wire::Writer::new(wire::TRENAMEAT, 0)
+            .u32(ROOT_FID)
+            .string("sub")
+            .u32(ROOT_FID)
+            .string("renamed")
+            .finish();
+        h.do_renameat(&rename).unwrap();
+        assert_eq!(
+            h.fileserver.lock().unwrap().fids[&2].pathbuf,
+            h.root.join("renamed/file")
+        );
+
+        let remove = wire::Writer::new(wire::TREMOVE, 0).u32(2).finish();
+        h.do_remove(&remove).unwrap();
+        let unlink = wire::Writer::new(wire::TUNLINKAT, 0)
+            .u32(ROOT_FID)
+            .string("renamed")
+            .u32(wire::L_AT_REMOVEDIR)
+            .finish();
+        h.do_unlinkat(&unlink).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn paths_are_confined_to_root() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("share")).unwrap();
+        std::os::unix::fs::symlink("..", dir.path().join("share/up")).unwrap();
+        let h = handler(&dir.path().join("share"), false);
+
+        // ".." at the root stays at the root.
+        h.do_walk(&walk_msg(ROOT_FID, 2, &[".."])).unwrap();
+        assert_eq!(h.fileserver.lock().unwrap().fids[&2].pathbuf, h.root);
+
+        // Names may not contain separators.
+        let mkdir = wire::Writer::new(wire::TMKDIR, 0)
+            .u32(ROOT_FID)
+            .string("../escape")
+            .u32(0o755)
+            .u32(0)
+            .finish();
+        assert_eq!(h.do_mkdir(&mkdir), Err(EINVAL as u32));
+
+        // A symlink pointing outside the root can't be used as a directory.
+        h.do_walk(&walk_msg(ROOT_FID, 3, &["up"])).unwrap();
+        let mkdir = wire::Writer::new(wire::TMKDIR, 0)
+            .u32(3)
+            .string("escape")
+            .u32(0o755)
+            .u32(0)
+            .finish();
+        assert_eq!(h.do_mkdir(&mkdir), Err(EACCES as u32));
+        assert!(!dir.path().join("escape").exists());
+    }
+
+    #[test]
+    fn special_mode_bits_are_withheld() {
+        let dir = tempfile::tempdir().unwrap();
+        let h = handler(dir.path(), false);
+        let mode_of = |name: &str| {
+            fs::metadata(dir.path().join(name)).unwrap().permissions().mode()
+        };
+
+        let mkdir = wire::Writer::new(wire::TMKDIR, 0)
+            .u32(ROOT_FID)
+            .string("sub")
+            .u32(0o3755)
+            .u32(0)
+            .finish();
+        h.do_mkdir(&mkdir).unwrap();
+        assert_eq!(mode_of("sub") & 0o7000, 0);
+
+        h.do_walk(&walk_msg(ROOT_FID, 2, &["sub"])).unwrap();
+        let create = wire::Writer::new(wire::TLCREATE, 0)
+            .u32(2)
+            .string("file")
+            .u32(wire::L_O_RDWR)
+            .u32(0o4755)
+            .u32(0)
+            .finish();
+        h.do_lcreate(&create).unwrap();
+        assert_eq!(mode_of("sub/file") & 0o7000, 0);
+
+        // The fid now represents the created file
+        let setattr = wire::Writer::new(wire::TSETATTR, 0)
+            .u32(2)
+            .u32(wire::P9_SETATTR_MODE)
+            .u32(0o6711)
+            .u32(0)
+            .u32(0)
+            .u64(0)
+            .u64(0)
+            .u64(0)
+            .u64(0)
+            .u64(0)
+            .finish();
+        h.do_setattr(&setattr).unwrap();
+        assert_eq!(mode_of("sub/file") & 0o7777, 0o711);
+    }
+
+    #[test]
+    fn read_only_rejects_modification() {
+        let dir = tempfile::tempdir().unwrap();
+        let h = handler(dir.path(), true);
+
+        let mkdir = wire::Writer::new(wire::TMKDIR, 0)
+            .u32(ROOT_FID)
+            .string("sub")
+            .u32(0o755)
+            .u32(0)
+            .finish();
+        assert_eq!(h.do_mkdir(&mkdir), Err(EROFS as u32));
+        assert!(!dir.path().join("sub").exists());
+    }
+}
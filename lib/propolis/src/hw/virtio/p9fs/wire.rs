@@ -0,0 +1,214 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Encoding and decoding for the 9P2000.L messages that modify a filesystem.
+//!
+//! The `p9ds` crate models the messages needed to browse and read a tree.
+//! The remaining messages are simple sequences of little-endian integers,
+//! length-prefixed strings, and qids, so they are handled directly here
+//! instead.
+
+use libc::EINVAL;
+use p9ds::proto::{Qid, QidType};
+
+// Message type numbers from the 9P2000.L specification.
+pub const TLCREATE: u8 = 14;
+pub const RLCREATE: u8 = 15;
+pub const TSYMLINK: u8 = 16;
+pub const RSYMLINK: u8 = 17;
+pub const TRENAME: u8 = 20;
+pub const RRENAME: u8 = 21;
+pub const TREADLINK: u8 = 22;
+pub const RREADLINK: u8 = 23;
+pub const TSETATTR: u8 = 26;
+pub const RSETATTR: u8 = 27;
+pub const TXATTRWALK: u8 = 30;
+pub const RXATTRWALK: u8 = 31;
+pub const TXATTRCREATE: u8 = 32;
+pub const RXATTRCREATE: u8 = 33;
+pub const TFSYNC: u8 = 50;
+pub const RFSYNC: u8 = 51;
+pub const TMKDIR: u8 = 72;
+pub const RMKDIR: u8 = 73;
+pub const TRENAMEAT: u8 = 74;
+pub const RRENAMEAT: u8 = 75;
+pub const TUNLINKAT: u8 = 76;
+pub const RUNLINKAT: u8 = 77;
+pub const TREMOVE: u8 = 122;
+pub const RREMOVE: u8 = 123;
+
+/// The size of the `size[4] type[1] tag[2]` header that begins every message.
+pub const HEADER_SIZE: usize = 7;
+
+// Open flags as defined by Linux, which 9P2000.L uses on the wire regardless
+// of the server's native values.
+pub const L_O_ACCMODE: u32 = 0o3;
+pub const L_O_RDONLY: u32 = 0o0;
+pub const L_O_WRONLY: u32 = 0o1;
+pub const L_O_RDWR: u32 = 0o2;
+pub const L_O_EXCL: u32 = 0o200;
+pub const L_O_TRUNC: u32 = 0o1000;
+pub const L_O_APPEND: u32 = 0o2000;
+
+/// Linux's `AT_REMOVEDIR` flag for `Tunlinkat`.
+pub const L_AT_REMOVEDIR: u32 = 0x200;
+
+// Bits in the `valid` field of `Tsetattr`.
+pub const P9_SETATTR_MODE: u32 = 0x1;
+pub const P9_SETATTR_UID: u32 = 0x2;
+pub const P9_SETATTR_GID: u32 = 0x4;
+pub const P9_SETATTR_SIZE: u32 = 0x8;
+pub const P9_SETATTR_ATIME: u32 = 0x10;
+pub const P9_SETATTR_MTIME: u32 = 0x20;
+pub const P9_SETATTR_ATIME_SET: u32 = 0x80;
+pub const P9_SETATTR_MTIME_SET: u32 = 0x100;
+
+// Flags for `Txattrcreate`, matching those of Linux's setxattr(2).
+pub const XATTR_CREATE: u32 = 0x1;
+pub const XATTR_REPLACE: u32 = 0x2;
+
+/// The largest extended attribute value a guest may set.
+pub const XATTR_SIZE_MAX: u64 = 64 * 1024;
+
+// Error codes in `Rlerror` messages are always Linux values.
+pub use super::super::errno::{io_errno, L_EILSEQ, L_ENODATA, L_EOPNOTSUPP};
+
+/// Decodes the body of a message. Each accessor returns an error code suitable
+/// for an `Rlerror` if the message is too short or malformed.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Creates a reader over `msg`, a complete message including its header.
+    pub fn new(msg: &'a [u8]) -> Self {
+        Self { buf: msg, pos: HEADER_SIZE }
+    }
+
+    /// Returns the message's tag.
+    pub fn tag(&self) -> u16 {
+        u16::from_le_bytes([self.buf[5], self.buf[6]])
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], u32> {
+        let end = self.pos + N;
+        let bytes = self.buf.get(self.pos..end).ok_or(EINVAL as u32)?;
+        self.pos = end;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    pub fn u32(&mut self) -> Result<u32, u32> {
+        self.take::<4>().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, u32> {
+        self.take::<8>().map(u64::from_le_bytes)
+    }
+
+    pub fn string(&mut self) -> Result<String, u32> {
+        let len = u16::from_le_bytes(self.take::<2>()?) as usize;
+        let bytes = self.slice(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| L_EILSEQ)
+    }
+
+    /// Decodes a block of data prefixed by a 32-bit count, as in `Twrite`.
+    pub fn bytes(&mut self) -> Result<&'a [u8], u32> {
+        let len = self.u32()? as usize;
+        self.slice(len)
+    }
+
+    fn slice(&mut self, len: usize) -> Result<&'a [u8], u32> {
+        let end = self.pos + len;
+        let bytes = self.buf.get(self.pos..end).ok_or(EINVAL as u32)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+/// Encodes a response message.
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    /// Starts a response of type `typ` to the request with tag `tag`.
+    pub fn new(typ: u8, tag: u16) -> Self {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.push(typ);
+        buf.extend_from_slice(&tag.to_le_bytes());
+        Self { buf }
+    }
+
+    pub fn u32(mut self, val: u32) -> Self {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    pub fn u64(mut self, val: u64) -> Self {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    pub fn string(mut self, val: &str) -> Self {
+        self.buf.extend_from_slice(&(val.len() as u16).to_le_bytes());
+        self.buf.extend_from_slice(val.as_bytes());
+        self
+    }
+
+    pub fn qid(mut self, qid: &Qid) -> Self {
+        self.buf.extend_from_slice(&ispf::to_bytes_le(qid).unwrap());
+        self
+    }
+
+    /// Completes the message, filling in its size.
+    pub fn finish(mut self) -> Vec<u8> {
+        let size = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&size.to_le_bytes());
+        self.buf
+    }
+}
+
+/// Returns the qid type that describes a file with the given metadata.
+pub fn qid_type(metadata: &std::fs::Metadata) -> QidType {
+    if metadata.is_dir() {
+        QidType::Dir
+    } else if metadata.is_symlink() {
+        QidType::Link
+    } else {
+        QidType::File
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reader_decodes_fields() {
+        let mut msg = vec![0, 0, 0, 0, TMKDIR, 0x34, 0x12];
+        msg.extend_from_slice(&7u32.to_le_bytes());
+        msg.extend_from_slice(&3u16.to_le_bytes());
+        msg.extend_from_slice(b"dir");
+        msg.extend_from_slice(&0o755u32.to_le_bytes());
+
+        let mut rd = Reader::new(&msg);
+        assert_eq!(rd.tag(), 0x1234);
+        assert_eq!(rd.u32(), Ok(7));
+        assert_eq!(rd.string().as_deref(), Ok("dir"));
+        assert_eq!(rd.u32(), Ok(0o755));
+        assert_eq!(rd.u32(), Err(EINVAL as u32));
+    }
+
+    #[test]
+    fn writer_fills_in_size() {
+        let qid = Qid { typ: QidType::Dir, version: 1, path: 2 };
+        let msg = Writer::new(RMKDIR, 9).qid(&qid).finish();
+        assert_eq!(msg.len(), HEADER_SIZE + 13);
+        assert_eq!(&msg[..4], &(msg.len() as u32).to_le_bytes());
+        assert_eq!(msg[4], RMKDIR);
+        assert_eq!(&msg[5..7], &9u16.to_le_bytes());
+    }
+}
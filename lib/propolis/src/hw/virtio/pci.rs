@@ -19,7 +19,7 @@ use crate::util::regmap::RegMap;
 
 use lazy_static::lazy_static;
 
-const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+pub(super) const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
 
 const VIRTIO_PCI_ISR_QUEUE: u8 = 1 << 0;
 const VIRTIO_PCI_ISR_CFG: u8 = 1 << 1;
@@ -165,12 +165,19 @@ impl<D: PciVirtio + Send + Sync + 'static> pci::Device for D {
     }
 }
 
+/// A function called when a virtio device enters the "Needs Reset" state.
+///
+/// It is called with the device's virtio state locked, and so must not access
+/// that device.
+pub type NeedsResetNotifier = dyn Fn() + Send + Sync + 'static;
+
 pub struct PciVirtioState {
     pub queues: VirtQueues,
 
     state: Mutex<VirtioState>,
     state_cv: Condvar,
     isr_state: Arc<IsrState>,
+    needs_reset_notifier: Mutex<Option<Box<NeedsResetNotifier>>>,
 
     /// Quick access to register map for MSIX (true) or non-MSIX (false)
     map_which: AtomicBool,
@@ -223,6 +230,7 @@ impl PciVirtioState {
             state: Mutex::new(VirtioState::new(queue_count)),
             state_cv: Condvar::new(),
             isr_state: IsrState::new(),
+            needs_reset_notifier: Mutex::new(None),
 
             map: RegMap::create_packed_passthru(
                 cfg_sz + LEGACY_REG_SZ,
@@ -417,9 +425,21 @@ impl PciVirtioState {
         if !state.status.contains(Status::NEEDS_RESET) {
             state.status.insert(Status::NEEDS_RESET);
             // XXX: interrupt needed?
+
+            if let Some(notifier) =
+                self.needs_reset_notifier.lock().unwrap().as_ref()
+            {
+                notifier();
+            }
         }
     }
 
+    /// Sets a function to be called each time the device enters the "Needs
+    /// Reset" state.
+    pub fn set_needs_reset_notifier(&self, notifier: Box<NeedsResetNotifier>) {
+        *self.needs_reset_notifier.lock().unwrap() = Some(notifier);
+    }
+
     /// Indicate to the guest that the VirtIO device has encountered an error of
     /// some sort and requires a reset.
     pub fn set_needs_reset(&self, _dev: &dyn VirtioDevice) {
@@ -529,6 +549,25 @@ impl PciVirtioState {
         self.state_cv.notify_all();
     }
 
+    /// Notify the guest of a change to the device-specific configuration,
+    /// through the configuration MSI-X vector or the ISR, depending on the
+    /// interrupt mode.
+    pub fn notify_config(&self, pci_state: &pci::DeviceState) {
+        let state = self.state.lock().unwrap();
+        if state.intr_mode == IntrMode::Msi {
+            let vec = state.msix_cfg_vec;
+            drop(state);
+            if let Some(hdl) = pci_state.msix_hdl() {
+                if vec < hdl.count() {
+                    hdl.fire(vec);
+                }
+            }
+        } else {
+            drop(state);
+            self.isr_state.raise_cfg();
+        }
+    }
+
     pub fn negotiated_features(&self) -> u32 {
         let state = self.state.lock().unwrap();
         state.nego_feat
@@ -564,8 +603,14 @@ impl MigrateMulti for PciVirtioState {
         offer: &mut PayloadOffers,
         _ctx: &MigrateCtx,
     ) -> Result<(), MigrateStateError> {
-        let input: migrate::PciVirtioStateV1 = offer.take()?;
-
+        self.import_state(offer.take()?)
+    }
+}
+impl PciVirtioState {
+    fn import_state(
+        &self,
+        input: migrate::PciVirtioStateV1,
+    ) -> Result<(), MigrateStateError> {
         let dev = input.device;
         let mut state = self.state.lock().unwrap();
         state.status = Status::from_bits(dev.status).ok_or_else(|| {
@@ -606,13 +651,25 @@ impl MigrateMulti for dyn PciVirtio {
     fn import(
         &self,
         offer: &mut PayloadOffers,
-        ctx: &MigrateCtx,
+        _ctx: &MigrateCtx,
+    ) -> Result<(), MigrateStateError> {
+        self.import_states(offer.take()?, offer.take()?)
+    }
+}
+impl dyn PciVirtio {
+    /// Imports the virtio and PCI state of a device.  This is separate from
+    /// [MigrateMulti::import] for devices which must translate the payloads
+    /// exported by older versions of themselves.
+    pub fn import_states(
+        &self,
+        virtio_input: migrate::PciVirtioStateV1,
+        pci_input: pci::migrate::PciStateV1,
     ) -> Result<(), MigrateStateError> {
         let ps = self.pci_state();
         let vs = self.virtio_state();
 
-        MigrateMulti::import(vs, offer, ctx)?;
-        MigrateMulti::import(ps, offer, ctx)?;
+        vs.import_state(virtio_input)?;
+        ps.import(pci_input)?;
 
         // Now that PCI state is populated, apply its calculated interrupt mode
         // to the VirtIO state.
@@ -647,6 +704,12 @@ impl IsrState {
             inner.intr_queue = true;
         });
     }
+    /// Raise config change ISR condition
+    fn raise_cfg(&self) {
+        self.sync_pin(|inner| {
+            inner.intr_cfg = true;
+        });
+    }
     /// Read ISR value, then clear it.
     fn read_clear(&self) -> u8 {
         let (mut queue, mut cfg) = (false, false);
@@ -253,17 +253,40 @@ impl VirtQueue {
         assert!(chain.idx.is_none());
         let mut avail = self.avail.lock().unwrap();
         let req = avail.read_next_avail(self.size, mem)?;
-
-        let mut desc = avail.read_ring_descr(req.desc_idx, self.size, mem)?;
-        let mut flags = DescFlag::from_bits_truncate(desc.flags);
-        let mut count = 0;
-        let mut len = 0;
-        chain.idx = Some(req.desc_idx);
         probes::virtio_vq_pop!(|| (
             self as *const VirtQueue as u64,
             req.desc_idx,
             req.avail_idx,
         ));
+        let len = self.walk_chain(&avail, req.desc_idx, chain, mem)?;
+        Some((req.avail_idx, len))
+    }
+    /// Rebuild a chain which was previously popped from the avail ring, but
+    /// not yet pushed to the used ring, starting at its head descriptor.
+    ///
+    /// Used by devices which hold on to chains across a migration.
+    pub fn chain_at(
+        &self,
+        desc_idx: u16,
+        chain: &mut Chain,
+        mem: &MemCtx,
+    ) -> Option<u32> {
+        assert!(chain.idx.is_none());
+        let avail = self.avail.lock().unwrap();
+        self.walk_chain(&avail, desc_idx, chain, mem)
+    }
+    fn walk_chain(
+        &self,
+        avail: &VqAvail,
+        desc_idx: u16,
+        chain: &mut Chain,
+        mem: &MemCtx,
+    ) -> Option<u32> {
+        let mut desc = avail.read_ring_descr(desc_idx, self.size, mem)?;
+        let mut flags = DescFlag::from_bits_truncate(desc.flags);
+        let mut count = 0;
+        let mut len = 0;
+        chain.idx = Some(desc_idx);
 
         // non-indirect descriptor(s)
         while !flags.contains(DescFlag::INDIRECT) {
@@ -287,10 +310,10 @@ impl VirtQueue {
                     desc = next;
                     flags = DescFlag::from_bits_truncate(desc.flags);
                 } else {
-                    return Some((req.avail_idx, len));
+                    return Some(len);
                 }
             } else {
-                return Some((req.avail_idx, len));
+                return Some(len);
             }
         }
         // XXX: skip indirect if not negotiated
@@ -327,7 +350,7 @@ impl VirtQueue {
                 }
             }
         }
-        Some((req.avail_idx, len))
+        Some(len)
     }
     pub fn push_used(&self, chain: &mut Chain, mem: &MemCtx) {
         assert!(chain.idx.is_some());
@@ -583,6 +606,21 @@ impl Chain {
         });
         true
     }
+    pub fn read_skip(&mut self, len: usize) -> bool {
+        if len == 0 {
+            return true;
+        }
+        if (self.read_stat.bytes_remain as usize) < len {
+            return false;
+        }
+        let mut remain = len;
+        self.for_remaining_type(true, |_addr, blen| {
+            let to_consume = usize::min(blen, remain);
+            remain -= to_consume;
+            (to_consume, remain != 0)
+        });
+        true
+    }
     /// Fetch a string of writable guest regions from the chain, provided there
     /// are enough to cover a specified length.
     pub fn writable_bufs(&mut self, len: usize) -> Option<Vec<GuestRegion>> {
@@ -606,6 +644,14 @@ impl Chain {
         Some(bufs)
     }
 
+    /// Head descriptor index of the chain, if it has been popped
+    pub fn head_idx(&self) -> Option<u16> {
+        self.idx
+    }
+    /// Count of readable bytes which have been consumed from the chain
+    pub fn consumed_read_bytes(&self) -> usize {
+        (self.read_stat.bytes - self.read_stat.bytes_remain) as usize
+    }
     pub fn remain_write_bytes(&self) -> usize {
         self.write_stat.bytes_remain as usize
     }
@@ -750,7 +796,6 @@ pub mod migrate {
     }
 }
 
-#[cfg(feature = "falcon")]
 pub(crate) fn write_buf(buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
     // more copy pasta from Chain::write b/c like Chain:read a
     // statically sized type is expected.
@@ -768,3 +813,25 @@ pub(crate) fn write_buf(buf: &[u8], chain: &mut Chain, mem: &MemCtx) {
         }
     });
 }
+
+/// Reads from the readable portion of `chain` into `buf`, returning the number
+/// of bytes read.
+pub(crate) fn read_buf(
+    buf: &mut [u8],
+    chain: &mut Chain,
+    mem: &MemCtx,
+) -> usize {
+    let mut done = 0;
+    chain.for_remaining_type(true, |addr, len| {
+        let remain = &mut buf[done..];
+        if let Some(copied) = mem.read_into(addr, remain, len) {
+            let need_more = copied != remain.len();
+
+            done += copied;
+            (copied, need_more)
+        } else {
+            // Copy failed, so do not attempt anything else
+            (0, false)
+        }
+    })
+}
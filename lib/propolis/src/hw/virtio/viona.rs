@@ -4,10 +4,11 @@
 
 #![cfg_attr(not(target_os = "illumos"), allow(dead_code, unused_imports))]
 
+use std::collections::BTreeSet;
 use std::io::{self, Error, ErrorKind};
 use std::num::NonZeroU16;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex, Weak};
 
 use crate::common::*;
@@ -17,8 +18,11 @@ use crate::util::regmap::RegMap;
 use crate::vmm::VmmHdl;
 
 use super::bits::*;
-use super::pci::{PciVirtio, PciVirtioState};
-use super::queue::{self, VirtQueue, VirtQueues};
+use super::capture::PacketCapture;
+use super::pci::{
+    self as virtio_pci, PciVirtio, PciVirtioState, VIRTIO_MSI_NO_VECTOR,
+};
+use super::queue::{self, Chain, VirtQueue, VirtQueues};
 use super::{VirtioDevice, VqChange, VqIntr};
 
 use lazy_static::lazy_static;
@@ -68,21 +72,97 @@ enum VRingState {
     Fatal,
 }
 
+/// Receive filtering state, as configured by the guest through the control
+/// queue.
+///
+/// Viona can't filter on individual MAC addresses or VLANs, so the requested
+/// filters are reduced to a promiscuity level which admits at least the
+/// traffic the guest asked for.  The spec allows for this, as both MAC and
+/// VLAN filtering are best-effort.
+#[derive(Clone)]
+struct CtrlState {
+    promisc: bool,
+    allmulti: bool,
+    uni_macs: Vec<[u8; ETHERADDRL]>,
+    multi_macs: Vec<[u8; ETHERADDRL]>,
+    vlans: BTreeSet<u16>,
+}
+impl Default for CtrlState {
+    fn default() -> Self {
+        // Viona admits all multicast traffic by default, as guests which
+        // don't negotiate VIRTIO_NET_F_CTRL_RX have no way to ask for it.
+        Self {
+            promisc: false,
+            allmulti: true,
+            uni_macs: Vec::new(),
+            multi_macs: Vec::new(),
+            vlans: BTreeSet::new(),
+        }
+    }
+}
+impl CtrlState {
+    /// Whether the guest asked for unicast traffic not addressed to `own_mac`,
+    /// which only full promiscuity admits.
+    fn wants_promisc(&self, own_mac: &[u8; ETHERADDRL]) -> bool {
+        self.promisc || self.uni_macs.iter().any(|mac| mac != own_mac)
+    }
+
+    /// The promiscuity needed to admit the traffic the guest asked for.
+    #[cfg(not(feature = "falcon"))]
+    fn promisc_level(
+        &self,
+        own_mac: &[u8; ETHERADDRL],
+    ) -> viona_api::viona_promisc_t {
+        use viona_api::viona_promisc_t::*;
+
+        if self.wants_promisc(own_mac) {
+            VIONA_PROMISC_ALL
+        } else if self.allmulti || !self.multi_macs.is_empty() {
+            VIONA_PROMISC_MULTI
+        } else {
+            VIONA_PROMISC_NONE
+        }
+    }
+
+    /// Falcon topologies carry traffic on VLANs the guest never tells us
+    /// about, so everything is always admitted.
+    #[cfg(feature = "falcon")]
+    fn promisc_level(
+        &self,
+        _own_mac: &[u8; ETHERADDRL],
+    ) -> viona_api::viona_promisc_t {
+        viona_api::viona_promisc_t::VIONA_PROMISC_ALL_VLAN
+    }
+}
+
+/// Index of the control queue, which follows the RX and TX queues.
+const CTRL_QUEUE_IDX: u16 = 2;
+
+/// Largest control command accepted from the guest, which leaves room for MAC
+/// filter tables of well over a thousand addresses.
+const CTRL_CMD_MAX_SIZE: usize = 0x4000;
+
 struct Inner {
     poller: Option<PollerHdl>,
     vring_state: [VRingState; 2],
+    ctrl: CtrlState,
+    /// Promiscuity level last applied to the link
+    promisc: viona_api::viona_promisc_t,
 }
 impl Inner {
-    fn new() -> Self {
-        Self { poller: None, vring_state: [Default::default(); 2] }
+    fn new(promisc: viona_api::viona_promisc_t) -> Self {
+        Self {
+            poller: None,
+            vring_state: [Default::default(); 2],
+            ctrl: CtrlState::default(),
+            promisc,
+        }
     }
 
-    /// Get the `VRingState` for a given VirtQueue
-    fn for_vq(&mut self, vq: &VirtQueue) -> &mut VRingState {
-        let id = vq.id as usize;
-        assert!(id < self.vring_state.len());
-
-        &mut self.vring_state[id]
+    /// Get the `VRingState` for a given VirtQueue, or `None` for the control
+    /// queue, which is serviced in userspace rather than by a vring.
+    fn for_vq(&mut self, vq: &VirtQueue) -> Option<&mut VRingState> {
+        self.vring_state.get_mut(vq.id as usize)
     }
 }
 
@@ -95,33 +175,96 @@ pub struct PciVirtioViona {
     dev_features: u32,
     mac_addr: [u8; ETHERADDRL],
     mtu: Option<u16>,
+    /// Whether the guest may ask for traffic not addressed to `mac_addr`
+    allow_promisc: bool,
+    /// Link state presented to the guest, which is under host control
+    link_up: AtomicBool,
     hdl: VionaHdl,
     inner: Mutex<Inner>,
+    capture: PacketCapture,
 }
 impl PciVirtioViona {
+    /// Creates a device backed by the vNIC `vnic_name`.
+    ///
+    /// The guest sees the vNIC's MAC address and MTU unless `mac_addr` or
+    /// `mtu` are specified.  A differing `mac_addr` is assigned to the vNIC,
+    /// which only admits unicast traffic sent to its own address.  The MTU may
+    /// not exceed that of the vNIC.
+    ///
+    /// Unless `allow_promisc` is set, the guest is refused promiscuous mode
+    /// and unicast filters for addresses other than its own.
     pub fn new(
         vnic_name: &str,
         queue_size: u16,
+        mac_addr: Option<[u8; ETHERADDRL]>,
+        mtu: Option<u16>,
+        allow_promisc: bool,
         vm: &VmmHdl,
     ) -> io::Result<Arc<PciVirtioViona>> {
         let dlhdl = dladm::Handle::new()?;
         let info = dlhdl.query_link(vnic_name)?;
+        let mtu = match (mtu, info.mtu) {
+            (Some(mtu), _) if mtu < VIRTIO_NET_MIN_MTU => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{vnic_name}: MTU {mtu} is below the minimum of \
+                        {VIRTIO_NET_MIN_MTU}"
+                    ),
+                ));
+            }
+            (Some(mtu), Some(link_mtu)) if mtu > link_mtu => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{vnic_name}: MTU {mtu} exceeds the vNIC's MTU of \
+                        {link_mtu}"
+                    ),
+                ));
+            }
+            (mtu, link_mtu) => mtu.or(link_mtu),
+        };
+        let mac_addr = match mac_addr {
+            Some(mac) if mac != info.mac_addr => {
+                dlhdl.set_vnic_mac(vnic_name, &mac).map_err(|e| {
+                    Error::new(
+                        e.kind(),
+                        format!(
+                            "{vnic_name}: failed to give the vNIC the guest's \
+                            MAC address: {e}"
+                        ),
+                    )
+                })?;
+                mac
+            }
+            _ => info.mac_addr,
+        };
+
         let hdl = VionaHdl::new(info.link_id, vm.fd())?;
+        // Viona links initially admit multicast traffic.
+        #[cfg(not(feature = "falcon"))]
+        let promisc = viona_api::viona_promisc_t::VIONA_PROMISC_MULTI;
 
         #[cfg(feature = "falcon")]
-        if let Err(e) =
-            hdl.set_promisc(viona_api::viona_promisc_t::VIONA_PROMISC_ALL_VLAN)
+        let promisc = match hdl
+            .set_promisc(viona_api::viona_promisc_t::VIONA_PROMISC_ALL_VLAN)
         {
-            // Until/unless this support is integrated into stlouis/illumos,
-            // this is an expected failure.   This is needed to use vlans,
-            // but shouldn't affect any other use case.
-            eprintln!("failed to enable promisc mode on {vnic_name}: {e:?}");
-        }
+            Ok(()) => viona_api::viona_promisc_t::VIONA_PROMISC_ALL_VLAN,
+            Err(e) => {
+                // Until/unless this support is integrated into stlouis/illumos,
+                // this is an expected failure.   This is needed to use vlans,
+                // but shouldn't affect any other use case.
+                eprintln!(
+                    "failed to enable promisc mode on {vnic_name}: {e:?}"
+                );
+                viona_api::viona_promisc_t::VIONA_PROMISC_MULTI
+            }
+        };
 
-        // TX and RX
-        let queue_count = NonZeroU16::new(2).unwrap();
-        // interrupts for TX, RX, and device config
-        let msix_count = Some(3);
+        // TX and RX, plus the control queue
+        let queue_count = NonZeroU16::new(3).unwrap();
+        // interrupts for TX, RX, the control queue, and device config
+        let msix_count = Some(4);
         let dev_features = hdl.get_avail_features()?;
 
         let queues =
@@ -135,18 +278,19 @@ impl PciVirtioViona {
             VIRTIO_NET_CFG_SIZE,
         );
 
-        let mut this = PciVirtioViona {
+        let this = Arc::new(PciVirtioViona {
             virtio_state,
             pci_state,
 
             dev_features,
-            mac_addr: [0; ETHERADDRL],
-            mtu: info.mtu,
+            mac_addr,
+            mtu,
+            allow_promisc,
+            link_up: AtomicBool::new(true),
             hdl,
-            inner: Mutex::new(Inner::new()),
-        };
-        this.mac_addr.copy_from_slice(&info.mac_addr);
-        let this = Arc::new(this);
+            inner: Mutex::new(Inner::new(promisc)),
+            capture: PacketCapture::new(vnic_name, mac_addr),
+        });
 
         // Spawn the interrupt poller
         let mut inner = this.inner.lock().unwrap();
@@ -157,6 +301,27 @@ impl PciVirtioViona {
         Ok(this)
     }
 
+    /// Sets the link state presented to the guest, notifying it of the change
+    /// through a configuration change interrupt.
+    ///
+    /// Only the guest's view of the link is affected: the vNIC continues to
+    /// carry any traffic the guest sends or which arrives for it.
+    pub fn set_link_up(&self, up: bool) {
+        if self.link_up.swap(up, Ordering::AcqRel) != up {
+            self.virtio_state.notify_config(&self.pci_state);
+        }
+    }
+
+    /// The link state presented to the guest.
+    pub fn link_up(&self) -> bool {
+        self.link_up.load(Ordering::Acquire)
+    }
+
+    /// The packet capture facility for this device's vNIC.
+    pub fn capture(&self) -> &PacketCapture {
+        &self.capture
+    }
+
     fn process_interrupts(&self) {
         if let Some(mem) = self.pci_state.acc_mem.access() {
             self.hdl
@@ -172,11 +337,16 @@ impl PciVirtioViona {
         match id {
             NetReg::Mac => ro.write_bytes(&self.mac_addr),
             NetReg::Status => {
-                // Always report link up
-                ro.write_u16(VIRTIO_NET_S_LINK_UP);
+                if self.link_up() {
+                    ro.write_u16(VIRTIO_NET_S_LINK_UP);
+                } else {
+                    ro.write_u16(0);
+                }
             }
             NetReg::MaxVqPairs => {
-                // hard-wired to single vq pair for now
+                // Viona drives a single pair of rings (see `VIONA_VQ_MAX`), so
+                // VIRTIO_NET_F_MQ is not offered, and there is only ever the
+                // one pair.
                 ro.write_u16(1);
             }
             NetReg::Mtu => {
@@ -197,7 +367,9 @@ impl PciVirtioViona {
                 continue;
             }
 
-            let rs = inner.for_vq(vq);
+            let Some(rs) = inner.for_vq(vq) else {
+                continue;
+            };
             match *rs {
                 VRingState::Ready | VRingState::Run | VRingState::Paused => {
                     // Ensure the ring is paused for a consistent snapshot
@@ -236,7 +408,9 @@ impl PciVirtioViona {
         let mut inner = self.inner.lock().unwrap();
         let mut res = Ok(());
         for vq in self.virtio_state.queues.iter() {
-            let rs = inner.for_vq(vq);
+            let Some(rs) = inner.for_vq(vq) else {
+                continue;
+            };
 
             // The existing state machine for vrings in Viona does not allow for
             // a Paused -> Running transition, requiring instead that the vring
@@ -283,7 +457,9 @@ impl PciVirtioViona {
     fn queues_kill(&self) {
         let mut inner = self.inner.lock().unwrap();
         for vq in self.virtio_state.queues.iter() {
-            let rs = inner.for_vq(vq);
+            let Some(rs) = inner.for_vq(vq) else {
+                continue;
+            };
             match *rs {
                 VRingState::Init => {
                     // Already at rest
@@ -302,6 +478,96 @@ impl PciVirtioViona {
         }
     }
 
+    /// Applies the promiscuity level which admits the traffic `ctrl` asks for,
+    /// if it differs from the level last applied.  Since viona may not support
+    /// changing the level, it is only changed when necessary.
+    fn apply_promisc(
+        &self,
+        inner: &mut Inner,
+        ctrl: &CtrlState,
+    ) -> io::Result<()> {
+        let level = ctrl.promisc_level(&self.mac_addr);
+        if level != inner.promisc {
+            self.hdl.set_promisc(level)?;
+            inner.promisc = level;
+        }
+        Ok(())
+    }
+
+    /// Process requests on the control queue, which (unlike the RX and TX
+    /// queues) is serviced in userspace.
+    fn ctrl_process(&self, vq: &VirtQueue) {
+        let Some(mem) = vq.acc_mem.access() else {
+            return;
+        };
+
+        loop {
+            let mut chain = Chain::with_capacity(4);
+            if vq.pop_avail(&mut chain, &mem).is_none() {
+                break;
+            }
+
+            // The command and its data are followed by a single writable byte
+            // for the acknowledgement.
+            let cmd_len = chain.remain_read_bytes();
+            let res = if cmd_len <= CTRL_CMD_MAX_SIZE {
+                let mut cmd = vec![0u8; cmd_len];
+                let len = queue::read_buf(&mut cmd, &mut chain, &mem);
+                cmd.truncate(len);
+                self.ctrl_command(&cmd)
+            } else {
+                Err(())
+            };
+            let ack = match res {
+                Ok(()) => VIRTIO_NET_OK,
+                Err(()) => VIRTIO_NET_ERR,
+            };
+            chain.write(&ack, &mem);
+            vq.push_used(&mut chain, &mem);
+        }
+    }
+
+    fn ctrl_command(&self, cmd: &[u8]) -> Result<(), ()> {
+        if cmd.len() < 2 {
+            return Err(());
+        }
+        let (class, command, data) = (cmd[0], cmd[1], &cmd[2..]);
+
+        let mut inner = self.inner.lock().unwrap();
+        let mut ctrl = inner.ctrl.clone();
+        match (class, command) {
+            (VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_PROMISC) => {
+                ctrl.promisc = ctrl_data::<1>(data)?[0] != 0;
+            }
+            (VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI) => {
+                ctrl.allmulti = ctrl_data::<1>(data)?[0] != 0;
+            }
+            (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET) => {
+                let (uni_macs, rest) = mac_table(data)?;
+                let (multi_macs, rest) = mac_table(rest)?;
+                if !rest.is_empty() {
+                    return Err(());
+                }
+                ctrl.uni_macs = uni_macs;
+                ctrl.multi_macs = multi_macs;
+            }
+            (VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_ADD) => {
+                ctrl.vlans.insert(vlan_id(data)?);
+            }
+            (VIRTIO_NET_CTRL_VLAN, VIRTIO_NET_CTRL_VLAN_DEL) => {
+                ctrl.vlans.remove(&vlan_id(data)?);
+            }
+            _ => return Err(()),
+        }
+        if ctrl.wants_promisc(&self.mac_addr) && !self.allow_promisc {
+            return Err(());
+        }
+
+        self.apply_promisc(&mut inner, &ctrl).map_err(|_| ())?;
+        inner.ctrl = ctrl;
+        Ok(())
+    }
+
     fn poller_start(&self) {
         let mut inner = self.inner.lock().unwrap();
         let poller = inner.poller.as_mut().expect("poller should be spawned");
@@ -333,7 +599,11 @@ impl VirtioDevice for PciVirtioViona {
         });
     }
     fn get_features(&self) -> u32 {
-        let mut feat = VIRTIO_NET_F_MAC;
+        let mut feat = VIRTIO_NET_F_MAC
+            | VIRTIO_NET_F_STATUS
+            | VIRTIO_NET_F_CTRL_VQ
+            | VIRTIO_NET_F_CTRL_RX
+            | VIRTIO_NET_F_CTRL_VLAN;
         // We drop the "VIRTIO_NET_F_MTU" flag from feat if we are unable to
         // query it. This can happen when executing within a non-global Zone.
         //
@@ -350,8 +620,14 @@ impl VirtioDevice for PciVirtioViona {
     }
 
     fn queue_notify(&self, vq: &Arc<VirtQueue>) {
+        if vq.id == CTRL_QUEUE_IDX {
+            self.ctrl_process(vq);
+            return;
+        }
         let mut inner = self.inner.lock().unwrap();
-        let rs = inner.for_vq(vq);
+        let Some(rs) = inner.for_vq(vq) else {
+            return;
+        };
         match rs {
             VRingState::Ready | VRingState::Run => {
                 if self.hdl.ring_kick(vq.id).is_err() {
@@ -369,7 +645,11 @@ impl VirtioDevice for PciVirtioViona {
         change: VqChange,
     ) -> Result<(), ()> {
         let mut inner = self.inner.lock().unwrap();
-        let rs = inner.for_vq(vq);
+        let Some(rs) = inner.for_vq(vq) else {
+            // The control queue is accessed directly from userspace, so there
+            // is no vring state to update.
+            return Ok(());
+        };
 
         match change {
             VqChange::Reset => {
@@ -439,6 +719,13 @@ impl Lifecycle for PciVirtioViona {
     }
     fn reset(&self) {
         self.virtio_state.reset(self);
+
+        let mut inner = self.inner.lock().unwrap();
+        let ctrl = CtrlState::default();
+        if let Err(e) = self.apply_promisc(&mut inner, &ctrl) {
+            eprintln!("failed to reset viona promiscuity: {e:?}");
+        }
+        inner.ctrl = ctrl;
     }
     fn start(&self) -> anyhow::Result<()> {
         // This device initializes into a paused state. Starting it is
@@ -457,6 +744,7 @@ impl Lifecycle for PciVirtioViona {
         }
     }
     fn halt(&self) {
+        self.capture.stop();
         self.poller_stop(true);
         // Destroy any in-kernel state to prevent it from impeding instance
         // destruction.
@@ -483,15 +771,50 @@ impl MigrateMulti for PciVirtioViona {
         output: &mut PayloadOutputs,
         ctx: &MigrateCtx,
     ) -> Result<(), MigrateStateError> {
-        <dyn PciVirtio>::export(self, output, ctx)
+        <dyn PciVirtio>::export(self, output, ctx)?;
+
+        let inner = self.inner.lock().unwrap();
+        let ctrl = &inner.ctrl;
+        output.push(
+            migrate::VionaCtrlV1 {
+                promisc: ctrl.promisc,
+                allmulti: ctrl.allmulti,
+                uni_macs: ctrl.uni_macs.clone(),
+                multi_macs: ctrl.multi_macs.clone(),
+                vlans: ctrl.vlans.iter().copied().collect(),
+                link_up: self.link_up(),
+            }
+            .into(),
+        )
     }
 
     fn import(
         &self,
         offer: &mut PayloadOffers,
-        ctx: &MigrateCtx,
+        _ctx: &MigrateCtx,
     ) -> Result<(), MigrateStateError> {
-        <dyn PciVirtio>::import(self, offer, ctx)?;
+        let mut virtio_input: virtio_pci::migrate::PciVirtioStateV1 =
+            offer.take()?;
+        let mut pci_input: pci::migrate::PciStateV1 = offer.take()?;
+
+        // Sources which predate the control queue export neither it nor its
+        // MSI-X vector.  Their guests can't have negotiated its use, so it is
+        // imported as it was at reset.
+        if virtio_input.queues.len() == usize::from(CTRL_QUEUE_IDX) {
+            let ctrlq = &self.virtio_state.queues[CTRL_QUEUE_IDX as usize];
+            virtio_input.queues.push(ctrlq.export());
+            virtio_input.device.msix_queue_vec.push(VIRTIO_MSI_NO_VECTOR);
+            if let Some(msix) = pci_input.msix.as_mut() {
+                msix.count += 1;
+                msix.entries.push(pci::migrate::MsixEntryV1 {
+                    addr: 0,
+                    data: 0,
+                    is_vec_masked: true,
+                    is_pending: false,
+                });
+            }
+        }
+        <dyn PciVirtio>::import_states(self, virtio_input, pci_input)?;
 
         let feat = self.virtio_state.negotiated_features();
         self.hdl.set_features(feat).map_err(|e| {
@@ -500,6 +823,33 @@ impl MigrateMulti for PciVirtioViona {
             ))
         })?;
 
+        // Sources which predate the control queue don't send its state, and
+        // their guests have configured no filters.
+        let Some(input) = offer.take_optional::<migrate::VionaCtrlV1>()? else {
+            return Ok(());
+        };
+        let ctrl = CtrlState {
+            promisc: input.promisc,
+            allmulti: input.allmulti,
+            uni_macs: input.uni_macs,
+            multi_macs: input.multi_macs,
+            vlans: input.vlans.into_iter().collect(),
+        };
+        if ctrl.wants_promisc(&self.mac_addr) && !self.allow_promisc {
+            return Err(MigrateStateError::ImportFailed(
+                "guest filters require promiscuity, which is not allowed"
+                    .to_string(),
+            ));
+        }
+        // Failing to apply the guest's filters leaves the link admitting the
+        // traffic it did at creation, which is not worth failing migration.
+        let mut inner = self.inner.lock().unwrap();
+        if let Err(e) = self.apply_promisc(&mut inner, &ctrl) {
+            eprintln!("failed to restore viona promiscuity: {e:?}");
+        }
+        inner.ctrl = ctrl;
+        self.link_up.store(input.link_up, Ordering::Release);
+
         Ok(())
     }
 }
@@ -649,7 +999,6 @@ impl VionaHdl {
     }
 
     /// Set the desired promiscuity level on this interface.
-    #[cfg(feature = "falcon")]
     fn set_promisc(&self, p: viona_api::viona_promisc_t) -> io::Result<()> {
         self.0.ioctl_usize(viona_api::VNA_IOC_SET_PROMISC, p as usize)?;
         Ok(())
@@ -842,6 +1191,8 @@ impl Drop for Poller {
     }
 }
 
+pub use propolis_types::parse_mac_addr;
+
 pub(crate) mod bits {
     #![allow(unused)]
 
@@ -849,9 +1200,64 @@ pub(crate) mod bits {
     pub const VIRTIO_NET_S_ANNOUNCE: u16 = 1 << 1;
 
     pub const VIRTIO_NET_CFG_SIZE: usize = 0xc;
+
+    /// Smallest MTU a device may offer with VIRTIO_NET_F_MTU
+    pub const VIRTIO_NET_MIN_MTU: u16 = 68;
+
+    // Control queue command classes and commands
+    pub const VIRTIO_NET_CTRL_RX: u8 = 0;
+    pub const VIRTIO_NET_CTRL_RX_PROMISC: u8 = 0;
+    pub const VIRTIO_NET_CTRL_RX_ALLMULTI: u8 = 1;
+
+    pub const VIRTIO_NET_CTRL_MAC: u8 = 1;
+    pub const VIRTIO_NET_CTRL_MAC_TABLE_SET: u8 = 0;
+
+    pub const VIRTIO_NET_CTRL_VLAN: u8 = 2;
+    pub const VIRTIO_NET_CTRL_VLAN_ADD: u8 = 0;
+    pub const VIRTIO_NET_CTRL_VLAN_DEL: u8 = 1;
+
+    // Control queue acknowledgements
+    pub const VIRTIO_NET_OK: u8 = 0;
+    pub const VIRTIO_NET_ERR: u8 = 1;
 }
 use bits::*;
 
+/// Interpret control command data as exactly `N` bytes
+fn ctrl_data<const N: usize>(data: &[u8]) -> Result<[u8; N], ()> {
+    data.try_into().map_err(|_| ())
+}
+
+/// Parse a `virtio_net_ctrl_mac` table from the front of `data`, returning its
+/// addresses and the remaining data.
+fn mac_table(data: &[u8]) -> Result<(Vec<[u8; ETHERADDRL]>, &[u8]), ()> {
+    if data.len() < 4 {
+        return Err(());
+    }
+    let (count, rest) = data.split_at(4);
+    let count = u32::from_le_bytes(ctrl_data(count)?) as usize;
+    let len = count.checked_mul(ETHERADDRL).ok_or(())?;
+    if rest.len() < len {
+        return Err(());
+    }
+    let (macs, rest) = rest.split_at(len);
+
+    let macs = macs
+        .chunks_exact(ETHERADDRL)
+        .map(ctrl_data)
+        .collect::<Result<_, _>>()?;
+    Ok((macs, rest))
+}
+
+fn vlan_id(data: &[u8]) -> Result<u16, ()> {
+    let vid = u16::from_le_bytes(ctrl_data(data)?);
+    // VLAN IDs are 12 bits wide
+    if vid < 4096 {
+        Ok(vid)
+    } else {
+        Err(())
+    }
+}
+
 /// Check that available viona API matches expectations of propolis crate
 pub(crate) fn check_api_version() -> Result<(), crate::api_version::Error> {
     let fd = viona_api::VionaFd::open()?;
@@ -866,3 +1272,57 @@ pub(crate) fn check_api_version() -> Result<(), crate::api_version::Error> {
         Ok(())
     }
 }
+
+pub mod migrate {
+    use crate::migrate::*;
+
+    use serde::{Deserialize, Serialize};
+
+    /// Guest-configured state of the control queue, along with the link
+    /// state presented to the guest
+    #[derive(Deserialize, Serialize)]
+    pub struct VionaCtrlV1 {
+        pub promisc: bool,
+        pub allmulti: bool,
+        pub uni_macs: Vec<[u8; 6]>,
+        pub multi_macs: Vec<[u8; 6]>,
+        pub vlans: Vec<u16>,
+        pub link_up: bool,
+    }
+    impl Schema<'_> for VionaCtrlV1 {
+        fn id() -> SchemaId {
+            ("pci-virtio-viona-ctrl", 1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mac_tables() {
+        let mut data = Vec::new();
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&[2, 0, 0, 0, 0, 1]);
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&[1, 0, 0x5e, 0, 0, 1]);
+        data.extend_from_slice(&[0x33, 0x33, 0, 0, 0, 1]);
+
+        let (uni, rest) = mac_table(&data).unwrap();
+        assert_eq!(uni, vec![[2, 0, 0, 0, 0, 1]]);
+        let (multi, rest) = mac_table(rest).unwrap();
+        assert_eq!(multi.len(), 2);
+        assert!(rest.is_empty());
+
+        // A table claiming more entries than are present is rejected.
+        assert!(mac_table(&data[..8]).is_err());
+    }
+
+    #[test]
+    fn vlan_ids() {
+        assert_eq!(vlan_id(&100u16.to_le_bytes()), Ok(100));
+        assert!(vlan_id(&4096u16.to_le_bytes()).is_err());
+        assert!(vlan_id(&[1]).is_err());
+    }
+}
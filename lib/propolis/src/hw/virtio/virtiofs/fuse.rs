@@ -0,0 +1,289 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Encoding and decoding for the FUSE messages carried by virtio-fs.
+//!
+//! Every request begins with a `fuse_in_header` and every reply with a
+//! `fuse_out_header`; the arguments that follow are fixed-layout structures of
+//! little-endian integers, sometimes followed by NUL-terminated names or raw
+//! data. The layouts here follow version 7.31 of the protocol, as described
+//! in Linux's `include/uapi/linux/fuse.h`.
+
+use libc::EINVAL;
+
+pub const FUSE_KERNEL_VERSION: u32 = 7;
+pub const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+
+/// The node ID of the root of the shared directory.
+pub const FUSE_ROOT_ID: u64 = 1;
+
+// Request opcodes.
+pub const FUSE_LOOKUP: u32 = 1;
+pub const FUSE_FORGET: u32 = 2;
+pub const FUSE_GETATTR: u32 = 3;
+pub const FUSE_SETATTR: u32 = 4;
+pub const FUSE_READLINK: u32 = 5;
+pub const FUSE_SYMLINK: u32 = 6;
+pub const FUSE_MKDIR: u32 = 9;
+pub const FUSE_UNLINK: u32 = 10;
+pub const FUSE_RMDIR: u32 = 11;
+pub const FUSE_RENAME: u32 = 12;
+pub const FUSE_LINK: u32 = 13;
+pub const FUSE_OPEN: u32 = 14;
+pub const FUSE_READ: u32 = 15;
+pub const FUSE_WRITE: u32 = 16;
+pub const FUSE_STATFS: u32 = 17;
+pub const FUSE_RELEASE: u32 = 18;
+pub const FUSE_FSYNC: u32 = 20;
+pub const FUSE_FLUSH: u32 = 25;
+pub const FUSE_INIT: u32 = 26;
+pub const FUSE_OPENDIR: u32 = 27;
+pub const FUSE_READDIR: u32 = 28;
+pub const FUSE_RELEASEDIR: u32 = 29;
+pub const FUSE_FSYNCDIR: u32 = 30;
+pub const FUSE_ACCESS: u32 = 34;
+pub const FUSE_CREATE: u32 = 35;
+pub const FUSE_INTERRUPT: u32 = 36;
+pub const FUSE_DESTROY: u32 = 38;
+pub const FUSE_BATCH_FORGET: u32 = 42;
+pub const FUSE_RENAME2: u32 = 45;
+
+// Flags negotiated by `FUSE_INIT`.
+pub const FUSE_ASYNC_READ: u32 = 1 << 0;
+pub const FUSE_ATOMIC_O_TRUNC: u32 = 1 << 3;
+pub const FUSE_BIG_WRITES: u32 = 1 << 5;
+pub const FUSE_MAX_PAGES: u32 = 1 << 22;
+
+// Flags in `fuse_open_out`.
+pub const FOPEN_DIRECT_IO: u32 = 1 << 0;
+pub const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+pub const FOPEN_CACHE_DIR: u32 = 1 << 3;
+
+// Bits in the `valid` field of `fuse_setattr_in`.
+pub const FATTR_MODE: u32 = 1 << 0;
+pub const FATTR_SIZE: u32 = 1 << 3;
+pub const FATTR_ATIME: u32 = 1 << 4;
+pub const FATTR_MTIME: u32 = 1 << 5;
+pub const FATTR_ATIME_NOW: u32 = 1 << 7;
+pub const FATTR_MTIME_NOW: u32 = 1 << 8;
+
+/// `RENAME_NOREPLACE` from Linux's renameat2(2).
+pub const RENAME_NOREPLACE: u32 = 1 << 0;
+
+// Open flags as defined by Linux, which FUSE uses on the wire regardless of
+// the server's native values.
+pub const L_O_ACCMODE: u32 = 0o3;
+pub const L_O_WRONLY: u32 = 0o1;
+pub const L_O_RDWR: u32 = 0o2;
+pub const L_O_CREAT: u32 = 0o100;
+pub const L_O_EXCL: u32 = 0o200;
+pub const L_O_TRUNC: u32 = 0o1000;
+pub const L_O_APPEND: u32 = 0o2000;
+
+/// The size of `fuse_in_header`.
+pub const IN_HEADER_SIZE: usize = 40;
+
+/// The size of `fuse_out_header`.
+pub const OUT_HEADER_SIZE: usize = 16;
+
+/// The size of `fuse_write_in`.
+pub const WRITE_IN_SIZE: usize = 40;
+
+/// The fields of `fuse_in_header` that the server uses.
+pub struct InHeader {
+    pub opcode: u32,
+    pub unique: u64,
+    pub nodeid: u64,
+}
+
+/// Decodes a request. Each accessor returns `EINVAL` if the request is too
+/// short or malformed.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Decodes the request's header, leaving the reader at its arguments.
+    pub fn header(&mut self) -> Result<InHeader, i32> {
+        let _len = self.u32()?;
+        let opcode = self.u32()?;
+        let unique = self.u64()?;
+        let nodeid = self.u64()?;
+        self.skip(IN_HEADER_SIZE - 24)?;
+        Ok(InHeader { opcode, unique, nodeid })
+    }
+
+    fn slice(&mut self, len: usize) -> Result<&'a [u8], i32> {
+        let end = self.pos.checked_add(len).ok_or(EINVAL)?;
+        let bytes = self.buf.get(self.pos..end).ok_or(EINVAL)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn skip(&mut self, len: usize) -> Result<(), i32> {
+        self.slice(len).map(|_| ())
+    }
+
+    pub fn u32(&mut self) -> Result<u32, i32> {
+        Ok(u32::from_le_bytes(self.slice(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, i32> {
+        Ok(u64::from_le_bytes(self.slice(8)?.try_into().unwrap()))
+    }
+
+    /// Decodes a NUL-terminated name.
+    pub fn name(&mut self) -> Result<&'a str, i32> {
+        let rest = &self.buf[self.pos..];
+        let len = rest.iter().position(|b| *b == 0).ok_or(EINVAL)?;
+        let name = self.slice(len)?;
+        self.skip(1)?;
+        std::str::from_utf8(name).map_err(|_| libc::EILSEQ)
+    }
+
+    /// Returns `len` bytes of raw data.
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], i32> {
+        self.slice(len)
+    }
+}
+
+/// Encodes the arguments of a reply. The `fuse_out_header` is added when the
+/// reply is sent.
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u16(mut self, val: u16) -> Self {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    pub fn u32(mut self, val: u32) -> Self {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    pub fn u64(mut self, val: u64) -> Self {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    pub fn bytes(mut self, val: &[u8]) -> Self {
+        self.buf.extend_from_slice(val);
+        self
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Builds a complete reply to the request `unique` from its arguments, or
+/// from a negated Linux error number.
+pub fn reply(unique: u64, result: Result<Vec<u8>, u32>) -> Vec<u8> {
+    let (error, args) = match result {
+        Ok(args) => (0i32, args),
+        Err(errno) => (-(errno as i32), Vec::new()),
+    };
+    let len = (OUT_HEADER_SIZE + args.len()) as u32;
+    let mut out = Vec::with_capacity(len as usize);
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(&error.to_le_bytes());
+    out.extend_from_slice(&unique.to_le_bytes());
+    out.extend_from_slice(&args);
+    out
+}
+
+/// Appends a `fuse_attr` describing a file with the given metadata.
+pub fn attr(w: Writer, ino: u64, m: &std::fs::Metadata) -> Writer {
+    use std::os::unix::fs::MetadataExt;
+
+    // Ownership is squashed to root, as with the 9P server.
+    w.u64(ino)
+        .u64(m.size())
+        .u64(m.blocks())
+        .u64(m.atime() as u64)
+        .u64(m.mtime() as u64)
+        .u64(m.ctime() as u64)
+        .u32(m.atime_nsec() as u32)
+        .u32(m.mtime_nsec() as u32)
+        .u32(m.ctime_nsec() as u32)
+        .u32(m.mode())
+        .u32(m.nlink() as u32)
+        .u32(0)
+        .u32(0)
+        .u32(m.rdev() as u32)
+        .u32(m.blksize() as u32)
+        .u32(0)
+}
+
+/// Appends a `fuse_dirent` to `buf` if it fits within `limit` bytes, returning
+/// whether it did.
+pub fn push_dirent(
+    buf: &mut Vec<u8>,
+    limit: usize,
+    ino: u64,
+    off: u64,
+    typ: u32,
+    name: &[u8],
+) -> bool {
+    const DIRENT_HEADER_SIZE: usize = 24;
+    let size = DIRENT_HEADER_SIZE + name.len();
+    let padded = (size + 7) & !7;
+    if buf.len() + padded > limit {
+        return false;
+    }
+
+    buf.extend_from_slice(&ino.to_le_bytes());
+    buf.extend_from_slice(&off.to_le_bytes());
+    buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&typ.to_le_bytes());
+    buf.extend_from_slice(name);
+    buf.resize(buf.len() + padded - size, 0);
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reader_decodes_header_and_names() {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&0u32.to_le_bytes());
+        msg.extend_from_slice(&FUSE_LOOKUP.to_le_bytes());
+        msg.extend_from_slice(&7u64.to_le_bytes());
+        msg.extend_from_slice(&FUSE_ROOT_ID.to_le_bytes());
+        msg.resize(IN_HEADER_SIZE, 0);
+        msg.extend_from_slice(b"file\0");
+
+        let mut rd = Reader::new(&msg);
+        let hdr = rd.header().unwrap();
+        assert_eq!(hdr.opcode, FUSE_LOOKUP);
+        assert_eq!(hdr.unique, 7);
+        assert_eq!(hdr.nodeid, FUSE_ROOT_ID);
+        assert_eq!(rd.name(), Ok("file"));
+        assert_eq!(rd.u32(), Err(EINVAL));
+    }
+
+    #[test]
+    fn dirents_are_padded() {
+        let mut buf = Vec::new();
+        assert!(push_dirent(&mut buf, 64, 2, 1, 8, b"a"));
+        assert_eq!(buf.len(), 32);
+        assert!(!push_dirent(&mut buf, 64, 3, 2, 8, b"too-long-name"));
+        assert_eq!(buf.len(), 32);
+    }
+}
@@ -0,0 +1,202 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::io;
+use std::num::NonZeroU16;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::common::*;
+use crate::hw::pci;
+use crate::migrate::Migrator;
+use crate::util::regmap::RegMap;
+
+use super::bits::*;
+use super::pci::{PciVirtio, PciVirtioState};
+use super::queue::{read_buf, write_buf, Chain, VirtQueue, VirtQueues};
+use super::VirtioDevice;
+
+use lazy_static::lazy_static;
+use slog::Logger;
+
+mod fuse;
+mod server;
+
+use server::Server;
+
+/// How long the guest may cache file data, attributes, and names.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum CachePolicy {
+    /// Nothing is cached: every access goes to the host. Use this when the
+    /// shared directory is also modified on the host while the guest is using
+    /// it.
+    Never,
+
+    /// Attributes and names are cached briefly and file data is cached while
+    /// a file is open.
+    #[default]
+    Auto,
+
+    /// Everything is cached for as long as the guest likes. Use this only when
+    /// the guest has exclusive use of the shared directory.
+    Always,
+}
+
+/// A virtio-fs device, which shares a directory on the host with the guest
+/// using the FUSE protocol. Linux guests can mount it with
+/// `mount -t virtiofs <tag> <dir>`.
+///
+/// The device has a single request queue, which it services synchronously, and
+/// doesn't support the DAX window or notification queue.
+pub struct PciVirtioFs {
+    virtio_state: PciVirtioState,
+    pci_state: pci::DeviceState,
+    tag: String,
+    server: Server,
+}
+
+impl PciVirtioFs {
+    pub fn new(
+        queue_size: u16,
+        tag: &str,
+        source: &Path,
+        cache: CachePolicy,
+        read_only: bool,
+        log: Logger,
+    ) -> io::Result<Arc<Self>> {
+        if tag.is_empty() || tag.len() > VIRTIO_FS_MAX_TAG_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "virtio-fs tag must be 1 to {} bytes long",
+                    VIRTIO_FS_MAX_TAG_SIZE
+                ),
+            ));
+        }
+        let server = Server::new(source, cache, read_only, log)?;
+
+        // One high-priority queue and one request queue.
+        let queues = VirtQueues::new(
+            NonZeroU16::new(queue_size).unwrap(),
+            NonZeroU16::new(2).unwrap(),
+        );
+        // One MSI-X entry for config changes and one for each queue.
+        let msix_count = Some(3);
+        let (virtio_state, pci_state) = PciVirtioState::create(
+            queues,
+            msix_count,
+            VIRTIO_DEV_FS,
+            VIRTIO_SUB_DEV_FS,
+            pci::bits::CLASS_STORAGE,
+            VIRTIO_FS_CFG_SIZE,
+        );
+        Ok(Arc::new(Self {
+            virtio_state,
+            pci_state,
+            tag: tag.to_string(),
+            server,
+        }))
+    }
+
+    fn handle_req(&self, vq: &Arc<VirtQueue>) -> Option<()> {
+        let mem = vq.acc_mem.access()?;
+
+        let mut chain = Chain::with_capacity(4);
+        vq.pop_avail(&mut chain, &mem)?;
+
+        // A request larger than any the guest should send is read only far
+        // enough to fail it.
+        let req_len = chain.remain_read_bytes();
+        let mut req = vec![0; req_len.min(server::MAX_REQUEST_SIZE)];
+        let len = read_buf(&mut req, &mut chain, &mem);
+        req.truncate(len);
+
+        let out_cap = chain.remain_write_bytes();
+        let reply = if req_len > server::MAX_REQUEST_SIZE {
+            self.server.reject_oversized(&req)
+        } else {
+            self.server.handle(&req, out_cap)
+        };
+        if let Some(reply) = reply {
+            write_buf(&reply, &mut chain, &mem);
+        }
+        vq.push_used(&mut chain, &mem);
+        Some(())
+    }
+}
+
+impl VirtioDevice for PciVirtioFs {
+    fn cfg_rw(&self, mut rwo: RWOp) {
+        FS_DEV_REGS.process(&mut rwo, |id, rwo| match rwo {
+            RWOp::Read(ro) => match id {
+                FsReg::Tag => {
+                    let mut bs = [0; VIRTIO_FS_MAX_TAG_SIZE];
+                    bs[..self.tag.len()].copy_from_slice(self.tag.as_bytes());
+                    ro.write_bytes(&bs);
+                    ro.fill(0);
+                }
+                FsReg::NumRequestQueues => ro.write_u32(1),
+            },
+            RWOp::Write(_) => {}
+        })
+    }
+
+    fn get_features(&self) -> u32 {
+        0
+    }
+
+    fn set_features(&self, _feat: u32) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn queue_notify(&self, vq: &Arc<VirtQueue>) {
+        while self.handle_req(vq).is_some() {}
+    }
+}
+
+impl Lifecycle for PciVirtioFs {
+    fn type_name(&self) -> &'static str {
+        "pci-virtio-fs"
+    }
+    fn reset(&self) {
+        self.virtio_state.reset(self);
+    }
+    fn migrate(&'_ self) -> Migrator<'_> {
+        Migrator::NonMigratable
+    }
+}
+
+impl PciVirtio for PciVirtioFs {
+    fn virtio_state(&self) -> &PciVirtioState {
+        &self.virtio_state
+    }
+    fn pci_state(&self) -> &pci::DeviceState {
+        &self.pci_state
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum FsReg {
+    Tag,
+    NumRequestQueues,
+}
+
+lazy_static! {
+    static ref FS_DEV_REGS: RegMap<FsReg> = {
+        let layout = [
+            (FsReg::Tag, VIRTIO_FS_MAX_TAG_SIZE),
+            (FsReg::NumRequestQueues, 4),
+        ];
+        RegMap::create_packed(VIRTIO_FS_CFG_SIZE, &layout, None)
+    };
+}
+
+mod bits {
+    use std::mem::size_of;
+
+    pub const VIRTIO_FS_MAX_TAG_SIZE: usize = 36;
+    pub const VIRTIO_FS_CFG_SIZE: usize =
+        VIRTIO_FS_MAX_TAG_SIZE + size_of::<u32>();
+}
+use bits::*;
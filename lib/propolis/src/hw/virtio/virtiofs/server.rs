@@ -0,0 +1,1053 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A FUSE server that exports a directory on the host.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{
+    DirBuilderExt, DirEntryExt, FileExt, MetadataExt, OpenOptionsExt,
+    PermissionsExt,
+};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use libc::{
+    EBADF, EEXIST, EINVAL, EIO, EISDIR, ENOENT, ENOSYS, ENOTDIR, EPROTO, EROFS,
+};
+use slog::{info, warn, Logger};
+
+use super::fuse::{self, Reader, Writer};
+use super::CachePolicy;
+use crate::hw::virtio::errno::linux_errno;
+
+/// The largest amount of data the guest may write in one request.
+const MAX_WRITE: u32 = 128 * 1024;
+
+/// The largest request the guest may send, which is a write of `MAX_WRITE`
+/// bytes.
+pub const MAX_REQUEST_SIZE: usize =
+    fuse::IN_HEADER_SIZE + fuse::WRITE_IN_SIZE + MAX_WRITE as usize;
+
+/// Mode bits the guest may set on files in the share.  Files are created as
+/// the user running propolis, so setuid, setgid and sticky bits are withheld.
+const GUEST_MODE_MASK: u32 = 0o777;
+
+// Directory entry types, as in `d_type`.
+const DT_DIR: u32 = 4;
+const DT_REG: u32 = 8;
+const DT_LNK: u32 = 10;
+
+type Result<T> = std::result::Result<T, i32>;
+
+fn errno(e: io::Error) -> i32 {
+    e.raw_os_error().unwrap_or(EIO)
+}
+
+/// A file or directory the guest has looked up.
+struct Inode {
+    /// The path at which the guest last looked up this inode.
+    path: PathBuf,
+
+    /// The host device and inode numbers identifying the file.
+    key: (u64, u64),
+
+    /// The number of lookups the guest has yet to forget.
+    lookups: u64,
+}
+
+/// An entry in a snapshot of a directory taken when it's opened.
+struct DirEntry {
+    name: Vec<u8>,
+    ino: u64,
+    typ: u32,
+}
+
+#[derive(Default)]
+struct State {
+    inodes: HashMap<u64, Inode>,
+    by_key: HashMap<(u64, u64), u64>,
+    next_nodeid: u64,
+    files: HashMap<u64, File>,
+    dirs: HashMap<u64, Vec<DirEntry>>,
+    next_fh: u64,
+}
+
+impl State {
+    fn path(&self, nodeid: u64) -> Result<&Path> {
+        self.inodes.get(&nodeid).map(|i| i.path.as_path()).ok_or(ENOENT)
+    }
+
+    /// Records a lookup of the file at `path`, returning its node ID.
+    fn lookup(&mut self, path: PathBuf, metadata: &fs::Metadata) -> u64 {
+        let key = (metadata.dev(), metadata.ino());
+        if let Some(inode) =
+            self.by_key.get(&key).and_then(|id| self.inodes.get_mut(id))
+        {
+            inode.path = path;
+            inode.lookups += 1;
+            return self.by_key[&key];
+        }
+
+        let nodeid = self.next_nodeid;
+        self.next_nodeid += 1;
+        self.inodes.insert(nodeid, Inode { path, key, lookups: 1 });
+        self.by_key.insert(key, nodeid);
+        nodeid
+    }
+
+    fn forget(&mut self, nodeid: u64, count: u64) {
+        if nodeid == fuse::FUSE_ROOT_ID {
+            return;
+        }
+        if let Some(inode) = self.inodes.get_mut(&nodeid) {
+            inode.lookups = inode.lookups.saturating_sub(count);
+            if inode.lookups == 0 {
+                let key = inode.key;
+                self.inodes.remove(&nodeid);
+                self.by_key.remove(&key);
+            }
+        }
+    }
+
+    /// Updates the paths of all inodes at or below `from` to reflect that
+    /// `from` has been renamed to `to`.
+    fn rename(&mut self, from: &Path, to: &Path) {
+        for inode in self.inodes.values_mut() {
+            if let Ok(rest) = inode.path.strip_prefix(from) {
+                inode.path = if rest.as_os_str().is_empty() {
+                    to.to_path_buf()
+                } else {
+                    to.join(rest)
+                };
+            }
+        }
+    }
+
+    fn file(&self, fh: u64) -> Result<&File> {
+        self.files.get(&fh).ok_or(EBADF)
+    }
+
+    fn alloc_fh(&mut self) -> u64 {
+        self.next_fh += 1;
+        self.next_fh
+    }
+}
+
+/// Serves FUSE requests against a directory tree on the host.
+///
+/// As with the 9P server, every path the guest operates on is confined to the
+/// shared root: the directory containing any file the guest touches must
+/// resolve to a location beneath it, and symlinks in the final component of a
+/// path are never followed.
+pub struct Server {
+    root: PathBuf,
+    cache: CachePolicy,
+    read_only: bool,
+    state: Mutex<State>,
+    log: Logger,
+}
+
+impl Server {
+    pub fn new(
+        source: &Path,
+        cache: CachePolicy,
+        read_only: bool,
+        log: Logger,
+    ) -> io::Result<Self> {
+        let root = fs::canonicalize(source)?;
+        let metadata = fs::metadata(&root)?;
+        if !metadata.is_dir() {
+            return Err(io::Error::from_raw_os_error(ENOTDIR));
+        }
+
+        let this =
+            Self { root, cache, read_only, state: Default::default(), log };
+        this.reset();
+        Ok(this)
+    }
+
+    /// Discards all inodes and open files other than the root.
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state =
+            State { next_nodeid: fuse::FUSE_ROOT_ID, ..Default::default() };
+        if let Ok(metadata) = fs::metadata(&self.root) {
+            state.lookup(self.root.clone(), &metadata);
+        }
+    }
+
+    /// Handles the request `req`, returning the reply to send, if any. The
+    /// reply will fit in `out_cap` bytes.
+    pub fn handle(&self, req: &[u8], out_cap: usize) -> Option<Vec<u8>> {
+        let mut rd = Reader::new(req);
+        let hdr = match rd.header() {
+            Ok(hdr) => hdr,
+            Err(_) => {
+                warn!(self.log, "virtio-fs: malformed request header");
+                return None;
+            }
+        };
+        let max = out_cap.saturating_sub(fuse::OUT_HEADER_SIZE);
+        let nodeid = hdr.nodeid;
+
+        let result = match hdr.opcode {
+            // These requests have no reply.
+            fuse::FUSE_FORGET => {
+                if let Ok(count) = rd.u64() {
+                    self.state.lock().unwrap().forget(nodeid, count);
+                }
+                return None;
+            }
+            fuse::FUSE_BATCH_FORGET => {
+                let _ = self.batch_forget(&mut rd);
+                return None;
+            }
+            // Requests are handled synchronously, so by the time an interrupt
+            // arrives there is nothing left to interrupt.
+            fuse::FUSE_INTERRUPT => return None,
+
+            fuse::FUSE_INIT => self.init(&mut rd),
+            fuse::FUSE_DESTROY => {
+                self.reset();
+                Ok(Vec::new())
+            }
+            fuse::FUSE_LOOKUP => self.lookup(nodeid, &mut rd),
+            fuse::FUSE_GETATTR => self.getattr(nodeid),
+            fuse::FUSE_SETATTR => self.setattr(nodeid, &mut rd),
+            fuse::FUSE_READLINK => self.readlink(nodeid),
+            fuse::FUSE_SYMLINK => self.symlink(nodeid, &mut rd),
+            fuse::FUSE_MKDIR => self.mkdir(nodeid, &mut rd),
+            fuse::FUSE_UNLINK => self.unlink(nodeid, &mut rd, false),
+            fuse::FUSE_RMDIR => self.unlink(nodeid, &mut rd, true),
+            fuse::FUSE_RENAME => self.rename(nodeid, &mut rd, false),
+            fuse::FUSE_RENAME2 => self.rename(nodeid, &mut rd, true),
+            fuse::FUSE_LINK => self.link(nodeid, &mut rd),
+            fuse::FUSE_OPEN => self.open(nodeid, &mut rd),
+            fuse::FUSE_CREATE => self.create(nodeid, &mut rd),
+            fuse::FUSE_READ => self.read(&mut rd, max),
+            fuse::FUSE_WRITE => self.write(&mut rd),
+            fuse::FUSE_STATFS => self.statfs(),
+            fuse::FUSE_RELEASE => {
+                let fh = rd.u64();
+                fh.map(|fh| {
+                    self.state.lock().unwrap().files.remove(&fh);
+                    Vec::new()
+                })
+            }
+            fuse::FUSE_FLUSH | fuse::FUSE_FSYNCDIR => Ok(Vec::new()),
+            fuse::FUSE_FSYNC => self.fsync(&mut rd),
+            fuse::FUSE_OPENDIR => self.opendir(nodeid),
+            fuse::FUSE_READDIR => self.readdir(&mut rd, max),
+            fuse::FUSE_RELEASEDIR => {
+                let fh = rd.u64();
+                fh.map(|fh| {
+                    self.state.lock().unwrap().dirs.remove(&fh);
+                    Vec::new()
+                })
+            }
+            fuse::FUSE_ACCESS => self.access(nodeid, &mut rd),
+
+            // Unsupported requests, including those for extended attributes
+            // and locking, fail with ENOSYS, which Linux guests remember so
+            // that they don't issue them again.
+            _ => Err(ENOSYS),
+        };
+
+        Some(fuse::reply(hdr.unique, result.map_err(linux_errno)))
+    }
+
+    /// Fails a request larger than `MAX_REQUEST_SIZE`, of which `req` holds
+    /// the beginning, returning the reply to send, if any.
+    pub fn reject_oversized(&self, req: &[u8]) -> Option<Vec<u8>> {
+        let hdr = Reader::new(req).header().ok()?;
+        warn!(self.log, "virtio-fs: request too large: opcode {}", hdr.opcode);
+        match hdr.opcode {
+            fuse::FUSE_FORGET
+            | fuse::FUSE_BATCH_FORGET
+            | fuse::FUSE_INTERRUPT => None,
+            _ => Some(fuse::reply(hdr.unique, Err(linux_errno(EINVAL)))),
+        }
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(EROFS)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The time for which the guest may cache names and attributes.
+    fn ttl(&self) -> u64 {
+        match self.cache {
+            CachePolicy::Never => 0,
+            CachePolicy::Auto => 1,
+            CachePolicy::Always => 24 * 60 * 60,
+        }
+    }
+
+    /// Checks that `path` lies within the shared root without following a
+    /// symlink in its final component, returning the path with its parent
+    /// directory resolved.
+    fn confine(&self, path: &Path) -> Result<PathBuf> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) if path != self.root => {
+                Ok(self.confine_dir(parent)?.join(name))
+            }
+            _ => self.confine_dir(path),
+        }
+    }
+
+    /// Checks that `path`, following any symlinks, lies within the shared
+    /// root, returning the resolved path.
+    fn confine_dir(&self, path: &Path) -> Result<PathBuf> {
+        let resolved = fs::canonicalize(path).map_err(errno)?;
+        if !resolved.starts_with(&self.root) {
+            warn!(self.log, "virtio-fs: path escapes shared root: {:?}", path);
+            return Err(libc::EACCES);
+        }
+
+        Ok(resolved)
+    }
+
+    /// Returns the path of the entry named `name` in the directory with node
+    /// ID `parent`, checking that both are valid and within the shared root.
+    fn child(&self, state: &State, parent: u64, name: &str) -> Result<PathBuf> {
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) if !name.contains('/') => {}
+            _ => return Err(EINVAL),
+        }
+
+        Ok(self.confine_dir(state.path(parent)?)?.join(name))
+    }
+
+    /// Returns the confined path of the inode with node ID `nodeid`.
+    fn node_path(&self, state: &State, nodeid: u64) -> Result<PathBuf> {
+        self.confine(state.path(nodeid)?)
+    }
+
+    /// Encodes a `fuse_entry_out` for a newly looked-up file.
+    fn entry(&self, w: Writer, nodeid: u64, m: &fs::Metadata) -> Writer {
+        let ttl = self.ttl();
+        let w = w.u64(nodeid).u64(0).u64(ttl).u64(ttl).u32(0).u32(0);
+        fuse::attr(w, m.ino(), m)
+    }
+
+    /// Records a lookup of `path` and replies with its entry.
+    fn reply_entry(&self, state: &mut State, path: PathBuf) -> Result<Vec<u8>> {
+        let m = fs::symlink_metadata(&path).map_err(errno)?;
+        let nodeid = state.lookup(path, &m);
+        Ok(self.entry(Writer::new(), nodeid, &m).finish())
+    }
+
+    fn open_flags(&self, dir: bool) -> u32 {
+        match (self.cache, dir) {
+            (CachePolicy::Never, false) => fuse::FOPEN_DIRECT_IO,
+            (CachePolicy::Always, false) => fuse::FOPEN_KEEP_CACHE,
+            (CachePolicy::Always, true) => {
+                fuse::FOPEN_KEEP_CACHE | fuse::FOPEN_CACHE_DIR
+            }
+            _ => 0,
+        }
+    }
+
+    fn init(&self, rd: &mut Reader) -> Result<Vec<u8>> {
+        let major = rd.u32()?;
+        let minor = rd.u32()?;
+        let max_readahead = rd.u32()?;
+        let flags = rd.u32()?;
+        if major != fuse::FUSE_KERNEL_VERSION {
+            warn!(self.log, "virtio-fs: unsupported FUSE version {}", major);
+            return Err(EPROTO);
+        }
+        info!(
+            self.log,
+            "virtio-fs: guest initialized FUSE {}.{}", major, minor
+        );
+
+        let flags = flags
+            & (fuse::FUSE_ASYNC_READ
+                | fuse::FUSE_ATOMIC_O_TRUNC
+                | fuse::FUSE_BIG_WRITES
+                | fuse::FUSE_MAX_PAGES);
+        let w = Writer::new()
+            .u32(fuse::FUSE_KERNEL_VERSION)
+            .u32(minor.min(fuse::FUSE_KERNEL_MINOR_VERSION))
+            .u32(max_readahead)
+            .u32(flags)
+            .u16(16) // max_background
+            .u16(12) // congestion_threshold
+            .u32(MAX_WRITE)
+            .u32(1) // time_gran
+            .u16((MAX_WRITE / 4096) as u16) // max_pages
+            .u16(0) // map_alignment
+            .u32(0) // flags2
+            .bytes(&[0; 28]);
+        Ok(w.finish())
+    }
+
+    fn batch_forget(&self, rd: &mut Reader) -> Result<()> {
+        let count = rd.u32()?;
+        let _dummy = rd.u32()?;
+        let mut state = self.state.lock().unwrap();
+        for _ in 0..count {
+            let nodeid = rd.u64()?;
+            let nlookup = rd.u64()?;
+            state.forget(nodeid, nlookup);
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, parent: u64, rd: &mut Reader) -> Result<Vec<u8>> {
+        let name = rd.name()?;
+        let mut state = self.state.lock().unwrap();
+        let path = self.child(&state, parent, name)?;
+        self.reply_entry(&mut state, path)
+    }
+
+    fn attr_out(&self, m: &fs::Metadata) -> Vec<u8> {
+        let w = Writer::new().u64(self.ttl()).u32(0).u32(0);
+        fuse::attr(w, m.ino(), m).finish()
+    }
+
+    fn getattr(&self, nodeid: u64) -> Result<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let path = self.node_path(&state, nodeid)?;
+        let m = fs::symlink_metadata(path).map_err(errno)?;
+        Ok(self.attr_out(&m))
+    }
+
+    fn setattr(&self, nodeid: u64, rd: &mut Reader) -> Result<Vec<u8>> {
+        let valid = rd.u32()?;
+        let _padding = rd.u32()?;
+        let _fh = rd.u64()?;
+        let size = rd.u64()?;
+        let _lock_owner = rd.u64()?;
+        let atime = rd.u64()?;
+        let mtime = rd.u64()?;
+        let _ctime = rd.u64()?;
+        let atimensec = rd.u32()?;
+        let mtimensec = rd.u32()?;
+        let _ctimensec = rd.u32()?;
+        let mode = rd.u32()?;
+        self.check_writable()?;
+
+        let state = self.state.lock().unwrap();
+        let path = self.node_path(&state, nodeid)?;
+        let m = fs::symlink_metadata(&path).map_err(errno)?;
+
+        // Setting the mode of a symlink would follow it, so such requests are
+        // ignored.
+        if valid & fuse::FATTR_MODE != 0 && !m.is_symlink() {
+            fs::set_permissions(
+                &path,
+                fs::Permissions::from_mode(mode & GUEST_MODE_MASK),
+            )
+            .map_err(errno)?;
+        }
+
+        // Ownership is squashed, so changes to it are accepted but have no
+        // effect.
+
+        if valid & fuse::FATTR_SIZE != 0 {
+            if m.is_dir() {
+                return Err(EISDIR);
+            }
+            fs::OpenOptions::new()
+                .write(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .open(&path)
+                .and_then(|f| f.set_len(size))
+                .map_err(errno)?;
+        }
+
+        if valid & (fuse::FATTR_ATIME | fuse::FATTR_MTIME) != 0 {
+            let time = |set: u32,
+                        now: u32,
+                        secs: u64,
+                        nsecs: u32,
+                        cur: (i64, i64)| {
+                if valid & set == 0 {
+                    libc::timespec { tv_sec: cur.0 as _, tv_nsec: cur.1 as _ }
+                } else if valid & now != 0 {
+                    libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_NOW as _ }
+                } else {
+                    libc::timespec { tv_sec: secs as _, tv_nsec: nsecs as _ }
+                }
+            };
+            let times = [
+                time(
+                    fuse::FATTR_ATIME,
+                    fuse::FATTR_ATIME_NOW,
+                    atime,
+                    atimensec,
+                    (m.atime(), m.atime_nsec()),
+                ),
+                time(
+                    fuse::FATTR_MTIME,
+                    fuse::FATTR_MTIME_NOW,
+                    mtime,
+                    mtimensec,
+                    (m.mtime(), m.mtime_nsec()),
+                ),
+            ];
+            let cpath = CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| EINVAL)?;
+            let res = unsafe {
+                libc::utimensat(
+                    libc::AT_FDCWD,
+                    cpath.as_ptr(),
+                    times.as_ptr(),
+                    libc::AT_SYMLINK_NOFOLLOW,
+                )
+            };
+            if res != 0 {
+                return Err(errno(io::Error::last_os_error()));
+            }
+        }
+
+        let m = fs::symlink_metadata(&path).map_err(errno)?;
+        Ok(self.attr_out(&m))
+    }
+
+    fn readlink(&self, nodeid: u64) -> Result<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let path = self.node_path(&state, nodeid)?;
+        let target = fs::read_link(path).map_err(errno)?;
+        Ok(target.as_os_str().as_bytes().to_vec())
+    }
+
+    fn symlink(&self, parent: u64, rd: &mut Reader) -> Result<Vec<u8>> {
+        let name = rd.name()?;
+        let target = rd.name()?;
+        self.check_writable()?;
+
+        let mut state = self.state.lock().unwrap();
+        let path = self.child(&state, parent, name)?;
+        std::os::unix::fs::symlink(target, &path).map_err(errno)?;
+        self.reply_entry(&mut state, path)
+    }
+
+    fn mkdir(&self, parent: u64, rd: &mut Reader) -> Result<Vec<u8>> {
+        // The guest applies its umask to the mode before sending it.
+        let mode = rd.u32()?;
+        let _umask = rd.u32()?;
+        let name = rd.name()?;
+        self.check_writable()?;
+
+        let mut state = self.state.lock().unwrap();
+        let path = self.child(&state, parent, name)?;
+        fs::DirBuilder::new()
+            .mode(mode & GUEST_MODE_MASK)
+            .create(&path)
+            .map_err(errno)?;
+        self.reply_entry(&mut state, path)
+    }
+
+    fn unlink(
+        &self,
+        parent: u64,
+        rd: &mut Reader,
+        dir: bool,
+    ) -> Result<Vec<u8>> {
+        let name = rd.name()?;
+        self.check_writable()?;
+
+        let state = self.state.lock().unwrap();
+        let path = self.child(&state, parent, name)?;
+        if dir { fs::remove_dir(&path) } else { fs::remove_file(&path) }
+            .map_err(errno)?;
+        Ok(Vec::new())
+    }
+
+    fn rename(
+        &self,
+        parent: u64,
+        rd: &mut Reader,
+        has_flags: bool,
+    ) -> Result<Vec<u8>> {
+        let newdir = rd.u64()?;
+        let flags = if has_flags {
+            let flags = rd.u32()?;
+            let _padding = rd.u32()?;
+            flags
+        } else {
+            0
+        };
+        let oldname = rd.name()?;
+        let newname = rd.name()?;
+        self.check_writable()?;
+        if flags & !fuse::RENAME_NOREPLACE != 0 {
+            return Err(EINVAL);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let from = self.child(&state, parent, oldname)?;
+        let to = self.child(&state, newdir, newname)?;
+        if flags & fuse::RENAME_NOREPLACE != 0
+            && fs::symlink_metadata(&to).is_ok()
+        {
+            return Err(EEXIST);
+        }
+
+        fs::rename(&from, &to).map_err(errno)?;
+        state.rename(&from, &to);
+        Ok(Vec::new())
+    }
+
+    fn link(&self, parent: u64, rd: &mut Reader) -> Result<Vec<u8>> {
+        let oldnodeid = rd.u64()?;
+        let name = rd.name()?;
+        self.check_writable()?;
+
+        let mut state = self.state.lock().unwrap();
+        let from = self.node_path(&state, oldnodeid)?;
+        let to = self.child(&state, parent, name)?;
+        fs::hard_link(&from, &to).map_err(errno)?;
+        self.reply_entry(&mut state, to)
+    }
+
+    /// Builds the options with which to open a file given the Linux open
+    /// flags `flags` and additional host flags `custom`.
+    fn open_options(&self, flags: u32, custom: i32) -> Result<fs::OpenOptions> {
+        let access = flags & fuse::L_O_ACCMODE;
+        if access != 0 || flags & (fuse::L_O_TRUNC | fuse::L_O_CREAT) != 0 {
+            self.check_writable()?;
+        }
+
+        let mut opts = fs::OpenOptions::new();
+        match access {
+            fuse::L_O_WRONLY => opts.write(true),
+            fuse::L_O_RDWR => opts.read(true).write(true),
+            _ => opts.read(true),
+        };
+        if flags & fuse::L_O_APPEND != 0 {
+            opts.append(true);
+        }
+        if flags & fuse::L_O_TRUNC != 0 {
+            opts.truncate(true);
+        }
+        opts.custom_flags(libc::O_NOFOLLOW | custom);
+        Ok(opts)
+    }
+
+    fn open(&self, nodeid: u64, rd: &mut Reader) -> Result<Vec<u8>> {
+        let flags = rd.u32()?;
+        let opts = self.open_options(flags, 0)?;
+
+        let mut state = self.state.lock().unwrap();
+        let path = self.node_path(&state, nodeid)?;
+        let file = opts.open(path).map_err(errno)?;
+        let fh = state.alloc_fh();
+        state.files.insert(fh, file);
+
+        Ok(Writer::new().u64(fh).u32(self.open_flags(false)).u32(0).finish())
+    }
+
+    fn create(&self, parent: u64, rd: &mut Reader) -> Result<Vec<u8>> {
+        let flags = rd.u32()?;
+        let mode = rd.u32()?;
+        let _umask = rd.u32()?;
+        let _padding = rd.u32()?;
+        let name = rd.name()?;
+
+        let mut custom = libc::O_CREAT;
+        if flags & fuse::L_O_EXCL != 0 {
+            custom |= libc::O_EXCL;
+        }
+        let mut opts = self.open_options(flags | fuse::L_O_CREAT, custom)?;
+        opts.mode(mode & GUEST_MODE_MASK);
+
+        let mut state = self.state.lock().unwrap();
+        let path = self.child(&state, parent, name)?;
+        let file = opts.open(&path).map_err(errno)?;
+        let m = file.metadata().map_err(errno)?;
+        let nodeid = state.lookup(path, &m);
+        let fh = state.alloc_fh();
+        state.files.insert(fh, file);
+
+        let w = self.entry(Writer::new(), nodeid, &m);
+        Ok(w.u64(fh).u32(self.open_flags(false)).u32(0).finish())
+    }
+
+    fn read(&self, rd: &mut Reader, max: usize) -> Result<Vec<u8>> {
+        let fh = rd.u64()?;
+        let offset = rd.u64()?;
+        let size = rd.u32()? as usize;
+
+        let state = self.state.lock().unwrap();
+        let file = state.file(fh)?;
+        let mut buf = vec![0; size.min(max)];
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset.checked_add(done as u64).ok_or(EINVAL)?;
+            match file.read_at(&mut buf[done..], pos) {
+                Ok(0) => break,
+                Ok(n) => done += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(errno(e)),
+            }
+        }
+        buf.truncate(done);
+        Ok(buf)
+    }
+
+    fn write(&self, rd: &mut Reader) -> Result<Vec<u8>> {
+        let fh = rd.u64()?;
+        let offset = rd.u64()?;
+        let size = rd.u32()?;
+        let _write_flags = rd.u32()?;
+        let _lock_owner = rd.u64()?;
+        let _flags = rd.u32()?;
+        let _padding = rd.u32()?;
+        let data = rd.bytes(size as usize)?;
+        self.check_writable()?;
+
+        let state = self.state.lock().unwrap();
+        state.file(fh)?.write_all_at(data, offset).map_err(errno)?;
+        Ok(Writer::new().u32(size).u32(0).finish())
+    }
+
+    fn fsync(&self, rd: &mut Reader) -> Result<Vec<u8>> {
+        let fh = rd.u64()?;
+        let fsync_flags = rd.u32()?;
+
+        let state = self.state.lock().unwrap();
+        let file = state.file(fh)?;
+        // The low bit of the flags requests that only data be synced.
+        if fsync_flags & 1 != 0 { file.sync_data() } else { file.sync_all() }
+            .map_err(errno)?;
+        Ok(Vec::new())
+    }
+
+    fn statfs(&self) -> Result<Vec<u8>> {
+        let cpath = CString::new(self.root.as_os_str().as_bytes())
+            .map_err(|_| EINVAL)?;
+        let mut sfs = unsafe { std::mem::zeroed::<libc::statvfs>() };
+        if unsafe { libc::statvfs(cpath.as_ptr(), &mut sfs) } != 0 {
+            return Err(errno(io::Error::last_os_error()));
+        }
+
+        let w = Writer::new()
+            .u64(sfs.f_blocks as u64)
+            .u64(sfs.f_bfree as u64)
+            .u64(sfs.f_bavail as u64)
+            .u64(sfs.f_files as u64)
+            .u64(sfs.f_ffree as u64)
+            .u32(sfs.f_bsize as u32)
+            .u32(sfs.f_namemax as u32)
+            .u32(sfs.f_frsize as u32)
+            .u32(0)
+            .bytes(&[0; 24]);
+        Ok(w.finish())
+    }
+
+    fn opendir(&self, nodeid: u64) -> Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let path = self.node_path(&state, nodeid)?;
+        let m = fs::symlink_metadata(&path).map_err(errno)?;
+        if !m.is_dir() {
+            return Err(ENOTDIR);
+        }
+
+        // Take a snapshot of the directory so that offsets remain stable
+        // while the guest reads it.
+        let parent_ino = match path.parent() {
+            Some(parent) if path != self.root => {
+                fs::metadata(parent).map_err(errno)?.ino()
+            }
+            _ => m.ino(),
+        };
+        let mut entries = vec![
+            DirEntry { name: b".".to_vec(), ino: m.ino(), typ: DT_DIR },
+            DirEntry { name: b"..".to_vec(), ino: parent_ino, typ: DT_DIR },
+        ];
+        let mut children = Vec::new();
+        for de in fs::read_dir(&path).map_err(errno)? {
+            let de = de.map_err(errno)?;
+            let ft = de.file_type().map_err(errno)?;
+            let typ = if ft.is_dir() {
+                DT_DIR
+            } else if ft.is_symlink() {
+                DT_LNK
+            } else {
+                DT_REG
+            };
+            children.push(DirEntry {
+                name: de.file_name().as_bytes().to_vec(),
+                ino: de.ino(),
+                typ,
+            });
+        }
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+        entries.extend(children);
+
+        let fh = state.alloc_fh();
+        state.dirs.insert(fh, entries);
+        Ok(Writer::new().u64(fh).u32(self.open_flags(true)).u32(0).finish())
+    }
+
+    fn readdir(&self, rd: &mut Reader, max: usize) -> Result<Vec<u8>> {
+        let fh = rd.u64()?;
+        let offset = rd.u64()? as usize;
+        let size = rd.u32()? as usize;
+
+        let state = self.state.lock().unwrap();
+        let entries = state.dirs.get(&fh).ok_or(EBADF)?;
+        let limit = size.min(max);
+        let mut buf = Vec::new();
+        for (i, de) in entries.iter().enumerate().skip(offset) {
+            let next = (i + 1) as u64;
+            if !fuse::push_dirent(
+                &mut buf, limit, de.ino, next, de.typ, &de.name,
+            ) {
+                break;
+            }
+        }
+        Ok(buf)
+    }
+
+    fn access(&self, nodeid: u64, rd: &mut Reader) -> Result<Vec<u8>> {
+        let mask = rd.u32()?;
+        let state = self.state.lock().unwrap();
+        let path = self.node_path(&state, nodeid)?;
+        fs::symlink_metadata(path).map_err(errno)?;
+
+        // Permissions are otherwise left to the guest, as ownership is
+        // squashed.
+        if mask & libc::W_OK as u32 != 0 {
+            self.check_writable()?;
+        }
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Req(Vec<u8>);
+
+    impl Req {
+        fn new(opcode: u32, nodeid: u64) -> Self {
+            let mut buf = Vec::new();
+            buf.extend_from_slice(&0u32.to_le_bytes());
+            buf.extend_from_slice(&opcode.to_le_bytes());
+            buf.extend_from_slice(&1u64.to_le_bytes());
+            buf.extend_from_slice(&nodeid.to_le_bytes());
+            buf.resize(fuse::IN_HEADER_SIZE, 0);
+            Self(buf)
+        }
+
+        fn u32(mut self, val: u32) -> Self {
+            self.0.extend_from_slice(&val.to_le_bytes());
+            self
+        }
+
+        fn u64(mut self, val: u64) -> Self {
+            self.0.extend_from_slice(&val.to_le_bytes());
+            self
+        }
+
+        fn name(mut self, name: &str) -> Self {
+            self.0.extend_from_slice(name.as_bytes());
+            self.0.push(0);
+            self
+        }
+
+        fn bytes(mut self, data: &[u8]) -> Self {
+            self.0.extend_from_slice(data);
+            self
+        }
+    }
+
+    /// Sends `req`, returning the reply's arguments or its negated error.
+    fn send(server: &Server, req: Req) -> std::result::Result<Vec<u8>, i32> {
+        let out = server.handle(&req.0, 1 << 20).unwrap();
+        let error = i32::from_le_bytes(out[4..8].try_into().unwrap());
+        if error != 0 {
+            Err(error)
+        } else {
+            Ok(out[fuse::OUT_HEADER_SIZE..].to_vec())
+        }
+    }
+
+    fn u64_at(buf: &[u8], off: usize) -> u64 {
+        u64::from_le_bytes(buf[off..off + 8].try_into().unwrap())
+    }
+
+    fn server(dir: &Path, read_only: bool) -> Server {
+        let log = Logger::root(slog::Discard, slog::o!());
+        Server::new(dir, CachePolicy::Auto, read_only, log).unwrap()
+    }
+
+    #[test]
+    fn create_write_read_and_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let s = server(dir.path(), false);
+
+        let out = send(
+            &s,
+            Req::new(fuse::FUSE_CREATE, fuse::FUSE_ROOT_ID)
+                .u32(fuse::L_O_RDWR)
+                .u32(0o644)
+                .u32(0)
+                .u32(0)
+                .name("file"),
+        )
+        .unwrap();
+        let nodeid = u64_at(&out, 0);
+        // The file handle follows the 128-byte `fuse_entry_out`.
+        let fh = u64_at(&out, 128);
+
+        let write = Req::new(fuse::FUSE_WRITE, nodeid)
+            .u64(fh)
+            .u64(0)
+            .u32(5)
+            .u32(0)
+            .u64(0)
+            .u32(0)
+            .u32(0)
+            .bytes(b"hello");
+        send(&s, write).unwrap();
+        let read = Req::new(fuse::FUSE_READ, nodeid)
+            .u64(fh)
+            .u64(1)
+            .u32(16)
+            .u32(0)
+            .u64(0)
+            .u32(0)
+            .u32(0);
+        assert_eq!(send(&s, read).unwrap(), b"ello");
+
+        let rename = Req::new(fuse::FUSE_RENAME, fuse::FUSE_ROOT_ID)
+            .u64(fuse::FUSE_ROOT_ID)
+            .name("file")
+            .name("renamed");
+        send(&s, rename).unwrap();
+        assert_eq!(fs::read(dir.path().join("renamed")).unwrap(), b"hello");
+        send(&s, Req::new(fuse::FUSE_GETATTR, nodeid).u32(0).u32(0).u64(0))
+            .unwrap();
+    }
+
+    #[test]
+    fn readdir_lists_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("b"), b"").unwrap();
+        fs::create_dir(dir.path().join("a")).unwrap();
+        let s = server(dir.path(), false);
+
+        let out =
+            send(&s, Req::new(fuse::FUSE_OPENDIR, fuse::FUSE_ROOT_ID)).unwrap();
+        let fh = u64_at(&out, 0);
+        let read = Req::new(fuse::FUSE_READDIR, fuse::FUSE_ROOT_ID)
+            .u64(fh)
+            .u64(2)
+            .u32(4096);
+        let buf = send(&s, read).unwrap();
+
+        // Skipping "." and "..", entries are returned in name order, each
+        // with the offset of the entry that follows it.
+        assert_eq!(u64_at(&buf, 8), 3);
+        assert_eq!(&buf[24..25], b"a");
+        assert_eq!(u64_at(&buf, 32 + 8), 4);
+        assert_eq!(&buf[32 + 24..32 + 25], b"b");
+        assert_eq!(buf.len(), 64);
+    }
+
+    #[test]
+    fn names_are_confined_to_root() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("share")).unwrap();
+        std::os::unix::fs::symlink("..", dir.path().join("share/up")).unwrap();
+        let s = server(&dir.path().join("share"), false);
+
+        let lookup = Req::new(fuse::FUSE_LOOKUP, fuse::FUSE_ROOT_ID).name("..");
+        assert_eq!(send(&s, lookup), Err(-EINVAL));
+
+        let lookup = Req::new(fuse::FUSE_LOOKUP, fuse::FUSE_ROOT_ID).name("up");
+        let up = u64_at(&send(&s, lookup).unwrap(), 0);
+        let mkdir = Req::new(fuse::FUSE_MKDIR, up).u32(0o755).u32(0).name("x");
+        assert_eq!(send(&s, mkdir), Err(-libc::EACCES));
+        assert!(!dir.path().join("x").exists());
+    }
+
+    #[test]
+    fn special_mode_bits_are_withheld() {
+        let dir = tempfile::tempdir().unwrap();
+        let s = server(dir.path(), false);
+        let mode_of = |name: &str| {
+            fs::metadata(dir.path().join(name)).unwrap().permissions().mode()
+        };
+
+        let mkdir = Req::new(fuse::FUSE_MKDIR, fuse::FUSE_ROOT_ID)
+            .u32(0o3755)
+            .u32(0)
+            .name("sub");
+        send(&s, mkdir).unwrap();
+        assert_eq!(mode_of("sub") & 0o7000, 0);
+
+        let create = Req::new(fuse::FUSE_CREATE, fuse::FUSE_ROOT_ID)
+            .u32(fuse::L_O_RDWR)
+            .u32(0o4755)
+            .u32(0)
+            .u32(0)
+            .name("file");
+        let nodeid = u64_at(&send(&s, create).unwrap(), 0);
+        assert_eq!(mode_of("file") & 0o7000, 0);
+
+        // `fuse_setattr_in`, changing only the mode
+        let setattr = Req::new(fuse::FUSE_SETATTR, nodeid)
+            .u32(fuse::FATTR_MODE)
+            .u32(0)
+            .u64(0)
+            .u64(0)
+            .u64(0)
+            .u64(0)
+            .u64(0)
+            .u64(0)
+            .u32(0)
+            .u32(0)
+            .u32(0)
+            .u32(0o6711)
+            .u32(0)
+            .u32(0)
+            .u32(0)
+            .u32(0);
+        send(&s, setattr).unwrap();
+        assert_eq!(mode_of("file") & 0o7777, 0o711);
+    }
+
+    #[test]
+    fn read_only_rejects_modification() {
+        let dir = tempfile::tempdir().unwrap();
+        let s = server(dir.path(), true);
+
+        let mkdir = Req::new(fuse::FUSE_MKDIR, fuse::FUSE_ROOT_ID)
+            .u32(0o755)
+            .u32(0)
+            .name("sub");
+        assert_eq!(send(&s, mkdir), Err(-EROFS));
+        assert!(!dir.path().join("sub").exists());
+    }
+
+    #[test]
+    fn oversized_requests_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let s = server(dir.path(), false);
+
+        let write = Req::new(fuse::FUSE_WRITE, fuse::FUSE_ROOT_ID);
+        let out = s.reject_oversized(&write.0).unwrap();
+        let error = i32::from_le_bytes(out[4..8].try_into().unwrap());
+        assert_eq!(error, -EINVAL);
+
+        let forget = Req::new(fuse::FUSE_FORGET, fuse::FUSE_ROOT_ID);
+        assert!(s.reject_oversized(&forget.0).is_none());
+    }
+}
@@ -21,6 +21,7 @@ pub mod block;
 pub mod chardev;
 pub mod common;
 pub mod cpuid;
+pub mod exit_trace;
 pub mod exits;
 pub mod firmware;
 pub mod hw;
@@ -30,6 +31,8 @@ pub mod migrate;
 pub mod mmio;
 pub mod pio;
 pub mod tasks;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod util;
 pub mod vcpu;
 pub mod vmm;
@@ -147,6 +147,14 @@ impl<'a> PayloadOffers<'a> {
             .parse()
     }
 
+    /// Like [`Self::take()`], but for payloads which a device may omit, such
+    /// as those added after older versions of that device were exported.
+    pub fn take_optional<T: Schema<'a>>(
+        &mut self,
+    ) -> Result<Option<T>, MigrateStateError> {
+        self.take_schema(T::id()).map(|mut offer| offer.parse()).transpose()
+    }
+
     /// Returns `true` if all of the payload offers been consumed via
     /// [`Self::take()`].
     pub fn is_consumed(&self) -> bool {
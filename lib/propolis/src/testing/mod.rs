@@ -0,0 +1,519 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Scaffolding for exercising device emulation without bhyve.
+//!
+//! A [`TestMachine`] is a [`Machine`] backed by a [`MockVmm`] rather than a
+//! kernel VMM instance, with its "guest memory" held in a tempfile.  Devices
+//! can be attached to its PIO and MMIO buses (or to a PCI bus built atop them)
+//! and driven through the same dispatch paths used for vCPU exits, while MSIs
+//! they send are recorded for inspection rather than delivered.
+//!
+//! The [`virtq`] and [`nvme`] modules lay out the driver side of virtqueues and
+//! NVMe submission/completion queues in guest memory.
+//!
+//! Device state can be carried from one machine to another with
+//! [`TestMachine::migrate_to()`], which exercises the same export and import
+//! paths as a live migration.
+//!
+//! Exits recorded from a real instance (see [`crate::exit_trace`]) can be
+//! replayed against the devices attached to a machine with
+//! [`TestMachine::replay()`], or checked against the entries recorded alongside
+//! them with [`TestMachine::verify_replay()`].
+//!
+//! This module is only available with the `testing` feature enabled.
+
+use std::io::{Error, ErrorKind, Result};
+use std::sync::Mutex;
+
+use crate::accessors::{Guard, MemAccessor, MsiAccessor};
+use crate::common::{GuestAddr, RWOp, ReadOp, WriteOp, PAGE_SIZE};
+use crate::exit_trace::TraceRecord;
+use crate::exits::VmEntry;
+use crate::hw::pci;
+use crate::lifecycle::Lifecycle;
+use crate::migrate::{
+    MigrateCtx, MigrateStateError, Migrator, PayloadOffer, PayloadOffers,
+    PayloadOutput, PayloadOutputs,
+};
+use crate::vmm::{Machine, MemCtx, MockVmm};
+
+pub mod nvme;
+pub mod virtq;
+
+/// A message-signalled interrupt sent by a device under test
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Msi {
+    pub addr: u64,
+    pub data: u64,
+}
+
+/// Builder for a [`TestMachine`].
+///
+/// The machine has a region of ROM at address 0, followed immediately by its
+/// RAM.  Both default to 1MiB.
+pub struct Builder {
+    rom_size: usize,
+    ram_size: usize,
+}
+impl Builder {
+    pub fn new() -> Self {
+        Self { rom_size: 1024 * 1024, ram_size: 1024 * 1024 }
+    }
+
+    /// Sets the size of the ROM region, which must be page-aligned.
+    pub fn rom_size(mut self, size: usize) -> Self {
+        self.rom_size = size;
+        self
+    }
+
+    /// Sets the size of the RAM region, which must be page-aligned and
+    /// nonzero.
+    pub fn ram_size(mut self, size: usize) -> Self {
+        self.ram_size = size;
+        self
+    }
+
+    pub fn build(self) -> Result<TestMachine> {
+        if self.rom_size % PAGE_SIZE != 0
+            || self.ram_size % PAGE_SIZE != 0
+            || self.ram_size == 0
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "ROM and RAM sizes must be page-aligned, and RAM nonzero",
+            ));
+        }
+        let machine = Machine::new_test_sized(self.rom_size, self.ram_size)?;
+        let ram_base = self.rom_size as u64;
+        Ok(TestMachine {
+            machine,
+            ram_base,
+            ram_size: self.ram_size,
+            next_alloc: Mutex::new(ram_base),
+        })
+    }
+}
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A fake machine on which device models can be driven.  See the
+/// [module-level documentation](self).
+pub struct TestMachine {
+    machine: Machine,
+    ram_base: u64,
+    ram_size: usize,
+    next_alloc: Mutex<u64>,
+}
+impl TestMachine {
+    /// Builds a machine with the default ROM and RAM sizes.
+    pub fn new() -> Result<Self> {
+        Builder::new().build()
+    }
+
+    /// The underlying [`Machine`], whose buses and accessors may be handed
+    /// to devices being attached.
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// A new child of the machine's memory accessor, for attaching devices.
+    pub fn acc_mem(&self) -> MemAccessor {
+        self.machine.acc_mem.child(None)
+    }
+
+    /// A new child of the machine's MSI accessor, for attaching devices.
+    pub fn acc_msi(&self) -> MsiAccessor {
+        self.machine.acc_msi.child(None)
+    }
+
+    /// Accesses guest memory, for reading and writing it as a driver would.
+    pub fn mem(&self) -> Guard<'_, MemCtx> {
+        self.machine.acc_mem.access().expect("test machine memory accessible")
+    }
+
+    /// Builds a PCI bus whose BARs are mapped onto the machine's PIO and MMIO
+    /// buses, and whose devices are given access to its memory and MSIs.
+    pub fn pci_bus(&self) -> pci::Bus {
+        pci::Bus::new(
+            &self.machine.bus_pio,
+            &self.machine.bus_mmio,
+            self.acc_mem(),
+            self.acc_msi(),
+        )
+    }
+
+    /// The address at which RAM begins
+    pub fn ram_base(&self) -> GuestAddr {
+        GuestAddr(self.ram_base)
+    }
+
+    /// The size of RAM, in bytes
+    pub fn ram_size(&self) -> usize {
+        self.ram_size
+    }
+
+    /// Allocates `len` bytes of RAM, aligned to `align` (a power of two),
+    /// for use as queues or data buffers.  Allocations are never freed.
+    ///
+    /// # Panics
+    ///
+    /// If RAM has been exhausted.
+    pub fn alloc(&self, len: usize, align: usize) -> GuestAddr {
+        assert!(align.is_power_of_two());
+        let mut next = self.next_alloc.lock().unwrap();
+        let mask = align as u64 - 1;
+        let addr = (*next + mask) & !mask;
+        let end = addr + len as u64;
+        assert!(
+            end <= self.ram_base + self.ram_size as u64,
+            "test machine RAM exhausted"
+        );
+        *next = end;
+        GuestAddr(addr)
+    }
+
+    /// Allocates `count` zeroed pages of RAM.
+    pub fn alloc_pages(&self, count: usize) -> GuestAddr {
+        let len = count * PAGE_SIZE;
+        let addr = self.alloc(len, PAGE_SIZE);
+        self.mem().write_byte(addr, 0, len);
+        addr
+    }
+
+    /// Emulates an `in` instruction of `bytes` width from `port`.
+    pub fn pio_read(&self, port: u16, bytes: u8) -> Result<u32> {
+        self.machine.bus_pio.handle_in(port, bytes).map_err(Error::from)
+    }
+
+    /// Emulates an `out` instruction of `bytes` width to `port`.
+    pub fn pio_write(&self, port: u16, bytes: u8, val: u32) -> Result<()> {
+        self.machine.bus_pio.handle_out(port, bytes, val).map_err(Error::from)
+    }
+
+    /// Emulates an MMIO read of `bytes` width from `addr`.
+    pub fn mmio_read(&self, addr: u64, bytes: u8) -> Result<u64> {
+        self.machine
+            .bus_mmio
+            .handle_read(addr as usize, bytes)
+            .map_err(Error::from)
+    }
+
+    /// Emulates an MMIO write of `bytes` width to `addr`.
+    pub fn mmio_write(&self, addr: u64, bytes: u8, val: u64) -> Result<()> {
+        self.machine
+            .bus_mmio
+            .handle_write(addr as usize, bytes, val)
+            .map_err(Error::from)
+    }
+
+    /// The mock hypervisor underlying this machine, through which vCPU exits
+    /// may be scripted and interrupts observed.
+    pub fn mock(&self) -> &MockVmm {
+        self.machine.hdl.mock().expect("test machine is backed by a mock")
+    }
+
+    /// Takes the MSIs sent by devices on this machine since the last call, in
+    /// the order they were sent.
+    pub fn take_msis(&self) -> Vec<Msi> {
+        self.mock()
+            .take_msis()
+            .into_iter()
+            .map(|(addr, data)| Msi { addr, data })
+            .collect()
+    }
+
+    /// Handles each exit in `trace`, in order, as the vCPU loop of an instance
+    /// would, returning the entry emitted for each (or `None` for those left
+    /// to the caller).
+    ///
+    /// The machine has a single vCPU, on which every exit is handled
+    /// regardless of the vCPU which originally took it.  Exits are dispatched
+    /// to the PIO and MMIO buses directly, rather than through the mock
+    /// hypervisor.
+    pub fn replay(&self, trace: &[TraceRecord]) -> Vec<Option<VmEntry>> {
+        let vcpu = &self.machine.vcpus[0];
+        trace.iter().map(|rec| vcpu.process_vmexit(&rec.exit)).collect()
+    }
+
+    /// Replays `trace` as in [`TestMachine::replay()`], failing at the first
+    /// exit whose handling differs from that recorded.
+    pub fn verify_replay(&self, trace: &[TraceRecord]) -> Result<()> {
+        let entries = self.replay(trace);
+        for (idx, (rec, entry)) in trace.iter().zip(entries).enumerate() {
+            if rec.entry != entry {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "exit {idx} ({:?}) was handled with {:?}, \
+                        but {:?} was recorded",
+                        rec.exit, entry, rec.entry
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the contents of RAM, and the state of `dev`, to `dst` as a
+    /// migration would.  The device state payloads are round-tripped through
+    /// JSON on the way.
+    ///
+    /// `dst` must have the same memory layout as this machine, and `dst_dev`
+    /// should be a freshly created device of the same kind as `dev`, attached
+    /// to `dst` in the same way.  As with a real migration, `dev` should be
+    /// paused, and its outstanding requests drained, beforehand.
+    pub fn migrate_to(
+        &self,
+        dev: &dyn Lifecycle,
+        dst: &TestMachine,
+        dst_dev: &dyn Lifecycle,
+    ) -> std::result::Result<(), MigrateStateError> {
+        assert_eq!(
+            (self.ram_base, self.ram_size),
+            (dst.ram_base, dst.ram_size),
+            "migration target must have the same memory layout"
+        );
+
+        let src_mem = self.mem();
+        let dst_mem = dst.mem();
+
+        let mut ram = vec![0u8; self.ram_size];
+        let copied = src_mem
+            .read_into(self.ram_base(), &mut ram, self.ram_size)
+            .and_then(|_| dst_mem.write_from(dst.ram_base(), &ram, ram.len()));
+        if copied != Some(self.ram_size) {
+            return Err(MigrateStateError::Io(Error::new(
+                ErrorKind::Other,
+                "failed to copy RAM",
+            )));
+        }
+        *dst.next_alloc.lock().unwrap() = *self.next_alloc.lock().unwrap();
+
+        let payloads = export_payloads(dev, &MigrateCtx { mem: &src_mem })?;
+        import_payloads(dst_dev, &payloads, &MigrateCtx { mem: &dst_mem })
+    }
+}
+
+/// A device state payload, serialized as it would be for transfer
+struct SavedPayload {
+    kind: &'static str,
+    version: u32,
+    data: String,
+}
+
+fn export_payloads(
+    dev: &dyn Lifecycle,
+    ctx: &MigrateCtx,
+) -> std::result::Result<Vec<SavedPayload>, MigrateStateError> {
+    let outputs: Vec<PayloadOutput> = match dev.migrate() {
+        Migrator::NonMigratable => {
+            return Err(MigrateStateError::NonMigratable);
+        }
+        Migrator::Empty => Vec::new(),
+        Migrator::Single(mech) => vec![mech.export(ctx)?],
+        Migrator::Multi(mech) => {
+            let mut outputs = PayloadOutputs::new();
+            mech.export(&mut outputs, ctx)?;
+            outputs.into_iter().collect()
+        }
+    };
+
+    outputs
+        .into_iter()
+        .map(|out| {
+            let data = serde_json::to_string(&out.payload)
+                .map_err(|e| MigrateStateError::Io(e.into()))?;
+            Ok(SavedPayload { kind: out.kind, version: out.version, data })
+        })
+        .collect()
+}
+
+fn import_payloads(
+    dev: &dyn Lifecycle,
+    payloads: &[SavedPayload],
+    ctx: &MigrateCtx,
+) -> std::result::Result<(), MigrateStateError> {
+    let mut desers: Vec<_> = payloads
+        .iter()
+        .map(|p| serde_json::Deserializer::from_str(&p.data))
+        .collect();
+    let mut offers: Vec<PayloadOffer> = payloads
+        .iter()
+        .zip(desers.iter_mut())
+        .map(|(p, deser)| PayloadOffer {
+            kind: p.kind,
+            version: p.version,
+            payload: Box::new(<dyn erased_serde::Deserializer>::erase(deser)),
+        })
+        .collect();
+
+    match dev.migrate() {
+        Migrator::NonMigratable => Err(MigrateStateError::NonMigratable),
+        Migrator::Empty if offers.is_empty() => Ok(()),
+        Migrator::Single(mech) if offers.len() == 1 => {
+            mech.import(offers.pop().unwrap(), ctx)
+        }
+        Migrator::Multi(mech) => {
+            let mut offers = PayloadOffers::new(offers);
+            mech.import(&mut offers, ctx)?;
+            match offers.remaining().count() {
+                0 => Ok(()),
+                n => Err(MigrateStateError::ImportFailed(format!(
+                    "{n} payload(s) left unconsumed"
+                ))),
+            }
+        }
+        Migrator::Empty | Migrator::Single(_) => {
+            Err(MigrateStateError::ImportFailed(format!(
+                "unexpected payload count {}",
+                offers.len()
+            )))
+        }
+    }
+}
+
+/// Reads `bytes` (1, 2 or 4) from offset `off` of the config space of `dev`.
+pub fn pci_cfg_read(dev: &dyn pci::Endpoint, off: usize, bytes: u8) -> u32 {
+    let mut buf = [0u8; 4];
+    let mut ro = ReadOp::from_buf(off, &mut buf[..bytes as usize]);
+    dev.cfg_rw(RWOp::Read(&mut ro));
+    u32::from_le_bytes(buf)
+}
+
+/// Writes `bytes` (1, 2 or 4) of `val` to offset `off` of the config space of
+/// `dev`.
+pub fn pci_cfg_write(dev: &dyn pci::Endpoint, off: usize, bytes: u8, val: u32) {
+    let buf = val.to_le_bytes();
+    let mut wo = WriteOp::from_buf(off, &buf[..bytes as usize]);
+    dev.cfg_rw(RWOp::Write(&mut wo));
+}
+
+/// Programs the (32-bit) BAR registers of `dev` as `(bar number, address)`
+/// pairs and enables decoding and bus mastering, as firmware would.
+pub fn pci_init(dev: &dyn pci::Endpoint, bars: &[(usize, u32)]) {
+    const PCI_REG_COMMAND: usize = 0x04;
+    const PCI_REG_BAR0: usize = 0x10;
+
+    for (n, addr) in bars {
+        pci_cfg_write(dev, PCI_REG_BAR0 + 4 * n, 4, *addr);
+    }
+    let cmd = pci::bits::RegCmd::IO_EN
+        | pci::bits::RegCmd::MMIO_EN
+        | pci::bits::RegCmd::BUSMSTR_EN
+        | pci::bits::RegCmd::INTX_DIS;
+    pci_cfg_write(dev, PCI_REG_COMMAND, 2, u32::from(cmd.bits()));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pio::PioFn;
+    use std::sync::Arc;
+
+    #[test]
+    fn sizing() {
+        let tm = Builder::new()
+            .rom_size(PAGE_SIZE)
+            .ram_size(4 * PAGE_SIZE)
+            .build()
+            .unwrap();
+        assert_eq!(tm.ram_base(), GuestAddr(PAGE_SIZE as u64));
+
+        let mem = tm.mem();
+        let end = GuestAddr(tm.ram_base().0 + tm.ram_size() as u64 - 4);
+        assert!(mem.write(end, &0xdead_beefu32));
+        assert_eq!(mem.read::<u32>(end), Some(0xdead_beef));
+        // Beyond the end of RAM
+        assert!(!mem.write(GuestAddr(end.0 + 4), &0u32));
+        // ROM is not writable by the guest
+        assert!(!mem.write(GuestAddr(0), &0u32));
+
+        assert!(Builder::new().ram_size(100).build().is_err());
+    }
+
+    #[test]
+    fn alloc() {
+        let tm = Builder::new().ram_size(2 * PAGE_SIZE).build().unwrap();
+        let a = tm.alloc(3, 1);
+        let b = tm.alloc(8, 8);
+        assert_eq!(a, tm.ram_base());
+        assert_eq!(b.0, tm.ram_base().0 + 8);
+        let c = tm.alloc_pages(1);
+        assert_eq!(c.0, tm.ram_base().0 + PAGE_SIZE as u64);
+    }
+
+    #[test]
+    fn pio_dispatch() {
+        let tm = TestMachine::new().unwrap();
+        let reg = Arc::new(Mutex::new(0u32));
+        let dev_reg = reg.clone();
+        let piofn = Arc::new(move |_port: u16, rwo: RWOp| match rwo {
+            RWOp::Read(ro) => ro.write_u32(*dev_reg.lock().unwrap()),
+            RWOp::Write(wo) => *dev_reg.lock().unwrap() = wo.read_u32(),
+        }) as Arc<PioFn>;
+        tm.machine().bus_pio.register(0x510, 4, piofn).unwrap();
+
+        tm.pio_write(0x510, 4, 0x1234_5678).unwrap();
+        assert_eq!(*reg.lock().unwrap(), 0x1234_5678);
+        assert_eq!(tm.pio_read(0x510, 4).unwrap(), 0x1234_5678);
+        assert!(tm.pio_read(0x520, 4).is_err());
+    }
+
+    #[test]
+    fn trace_replay() {
+        use crate::exits::{InoutReq, InoutRes, IoPort, VmExit, VmExitKind};
+
+        let tm = TestMachine::new().unwrap();
+        let reg = Arc::new(Mutex::new(0u32));
+        let piofn = Arc::new(move |_port: u16, rwo: RWOp| match rwo {
+            RWOp::Read(ro) => ro.write_u32(*reg.lock().unwrap()),
+            RWOp::Write(wo) => *reg.lock().unwrap() = wo.read_u32(),
+        }) as Arc<PioFn>;
+        tm.machine().bus_pio.register(0x510, 4, piofn).unwrap();
+
+        let port = IoPort { port: 0x510, bytes: 4 };
+        let rec = |req, res| TraceRecord {
+            vcpuid: 0,
+            exit: VmExit { rip: 0, inst_len: 1, kind: VmExitKind::Inout(req) },
+            entry: res,
+        };
+        let mut trace = vec![
+            rec(
+                InoutReq::Out(port, 0xabcd),
+                Some(VmEntry::InoutFulfill(InoutRes::Out(port))),
+            ),
+            rec(
+                InoutReq::In(port),
+                Some(VmEntry::InoutFulfill(InoutRes::In(port, 0xabcd))),
+            ),
+            rec(InoutReq::In(IoPort { port: 0x520, bytes: 1 }), None),
+        ];
+        tm.verify_replay(&trace).unwrap();
+
+        // A device which behaves differently than when recorded
+        trace[1].entry = Some(VmEntry::InoutFulfill(InoutRes::In(port, 0)));
+        let err = tm.verify_replay(&trace).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn msi_capture() {
+        let tm = TestMachine::new().unwrap();
+        let acc = tm.acc_msi();
+        acc.send(0xfee0_0000, 0x41).unwrap();
+        acc.send(0xfee0_1000, 0x42).unwrap();
+        assert_eq!(
+            tm.take_msis(),
+            vec![
+                Msi { addr: 0xfee0_0000, data: 0x41 },
+                Msi { addr: 0xfee0_1000, data: 0x42 },
+            ]
+        );
+        assert!(tm.take_msis().is_empty());
+    }
+}
@@ -0,0 +1,255 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The host side of NVMe submission and completion queues.
+//!
+//! See NVMe 1.0e Section 4 Data Structures.
+
+use crate::common::GuestAddr;
+use crate::vmm::MemCtx;
+
+/// Size of a Submission Queue Entry, in bytes
+pub const SQE_LEN: usize = 64;
+/// Size of a Completion Queue Entry, in bytes
+pub const CQE_LEN: usize = 16;
+
+/// A command to be placed in a submission queue
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Command {
+    pub opcode: u8,
+    pub cid: u16,
+    pub nsid: u32,
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+impl Command {
+    /// Encodes the command as a Submission Queue Entry.
+    pub fn to_bytes(&self) -> [u8; SQE_LEN] {
+        let mut buf = [0u8; SQE_LEN];
+        let cdw0 = u32::from(self.opcode) | u32::from(self.cid) << 16;
+        buf[0..4].copy_from_slice(&cdw0.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.nsid.to_le_bytes());
+        // Bytes 15:08 are reserved and 23:16 hold the (unused) MPTR
+        buf[24..32].copy_from_slice(&self.prp1.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.prp2.to_le_bytes());
+        let cdws = [
+            self.cdw10, self.cdw11, self.cdw12, self.cdw13, self.cdw14,
+            self.cdw15,
+        ];
+        for (i, cdw) in cdws.iter().enumerate() {
+            let off = 40 + i * 4;
+            buf[off..off + 4].copy_from_slice(&cdw.to_le_bytes());
+        }
+        buf
+    }
+}
+
+/// A Completion Queue Entry posted by the controller
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Completion {
+    /// Command specific value (DW0)
+    pub dw0: u32,
+    /// Submission Queue Head Pointer
+    pub sqhd: u16,
+    /// Submission Queue Identifier
+    pub sqid: u16,
+    /// Command Identifier
+    pub cid: u16,
+    /// Status Field, without the Phase Tag
+    pub status: u16,
+}
+impl Completion {
+    /// Decodes a Completion Queue Entry, returning it with its Phase Tag.
+    pub fn from_bytes(buf: &[u8; CQE_LEN]) -> (Self, bool) {
+        let u16_at = |off: usize| u16::from_le_bytes([buf[off], buf[off + 1]]);
+        let sf = u16_at(14);
+        let comp = Self {
+            dw0: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+            sqhd: u16_at(8),
+            sqid: u16_at(10),
+            cid: u16_at(12),
+            status: sf >> 1,
+        };
+        (comp, sf & 1 != 0)
+    }
+
+    /// Status Code (SC)
+    pub fn status_code(&self) -> u8 {
+        self.status as u8
+    }
+
+    /// Status Code Type (SCT)
+    pub fn status_code_type(&self) -> u8 {
+        (self.status >> 8) as u8 & 0b111
+    }
+
+    /// Whether the command completed successfully
+    pub fn is_success(&self) -> bool {
+        self.status == 0
+    }
+}
+
+/// The host's view of a submission queue residing in guest memory.
+pub struct HostSubQueue {
+    base: GuestAddr,
+    size: u16,
+    tail: u16,
+}
+impl HostSubQueue {
+    /// Creates a queue of `size` entries at `base`, which must have
+    /// `size * SQE_LEN` bytes of memory behind it.
+    pub fn new(base: GuestAddr, size: u16) -> Self {
+        assert!(size >= 2);
+        Self { base, size, tail: 0 }
+    }
+
+    pub fn base(&self) -> GuestAddr {
+        self.base
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Places `cmd` at the tail of the queue, returning the new tail, which
+    /// must be written to the queue's doorbell for the controller to see it.
+    ///
+    /// It is up to the caller not to overrun the controller's head.
+    pub fn push(&mut self, mem: &MemCtx, cmd: &Command) -> u16 {
+        let addr = self.base.0 + u64::from(self.tail) * SQE_LEN as u64;
+        assert!(mem.write(GuestAddr(addr), &cmd.to_bytes()));
+        self.tail = (self.tail + 1) % self.size;
+        self.tail
+    }
+}
+
+/// The host's view of a completion queue residing in guest memory.
+pub struct HostCompQueue {
+    base: GuestAddr,
+    size: u16,
+    head: u16,
+    phase: bool,
+}
+impl HostCompQueue {
+    /// Creates a queue of `size` entries at `base`, zeroing the
+    /// `size * CQE_LEN` bytes of memory behind it so that no entries appear
+    /// to have been posted.
+    pub fn new(mem: &MemCtx, base: GuestAddr, size: u16) -> Self {
+        assert!(size >= 2);
+        assert!(mem.write_byte(base, 0, usize::from(size) * CQE_LEN));
+        Self { base, size, head: 0, phase: true }
+    }
+
+    pub fn base(&self) -> GuestAddr {
+        self.base
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Takes the entry at the head of the queue, if the controller has posted
+    /// one.  The new head should then be written to the queue's doorbell.
+    pub fn pop(&mut self, mem: &MemCtx) -> Option<Completion> {
+        let addr = self.base.0 + u64::from(self.head) * CQE_LEN as u64;
+        let buf = mem.read::<[u8; CQE_LEN]>(GuestAddr(addr))?;
+        let (comp, phase) = Completion::from_bytes(&buf);
+        if phase != self.phase {
+            return None;
+        }
+        self.head += 1;
+        if self.head == self.size {
+            self.head = 0;
+            self.phase = !self.phase;
+        }
+        Some(comp)
+    }
+
+    /// The current head of the queue
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hw::nvme::bits::{CompletionQueueEntry, SubmissionQueueEntry};
+    use crate::testing::TestMachine;
+
+    #[test]
+    fn sqe_layout() {
+        let tm = TestMachine::new().unwrap();
+        let base = tm.alloc(2 * SQE_LEN, SQE_LEN);
+        let mem = tm.mem();
+
+        let mut sq = HostSubQueue::new(base, 2);
+        let cmd = Command {
+            opcode: 0x02,
+            cid: 0x1234,
+            nsid: 1,
+            prp1: 0x10_0000,
+            prp2: 0x20_0000,
+            cdw10: 10,
+            cdw12: 12,
+            cdw15: 15,
+            ..Default::default()
+        };
+        assert_eq!(sq.push(&mem, &cmd), 1);
+
+        let sqe = mem.read::<SubmissionQueueEntry>(base).unwrap();
+        assert_eq!(sqe.opcode(), 0x02);
+        assert_eq!(sqe.cid(), 0x1234);
+        assert_eq!({ sqe.nsid }, 1);
+        assert_eq!({ sqe.prp1 }, 0x10_0000);
+        assert_eq!({ sqe.prp2 }, 0x20_0000);
+        assert_eq!({ sqe.cdw10 }, 10);
+        assert_eq!({ sqe.cdw12 }, 12);
+        assert_eq!({ sqe.cdw15 }, 15);
+
+        assert_eq!(sq.push(&mem, &cmd), 0);
+    }
+
+    #[test]
+    fn cq_phase() {
+        let tm = TestMachine::new().unwrap();
+        let base = tm.alloc(2 * CQE_LEN, CQE_LEN);
+        let mem = tm.mem();
+
+        let mut cq = HostCompQueue::new(&mem, base, 2);
+        assert!(cq.pop(&mem).is_none());
+
+        let post = |slot: u64, cid: u16, phase: bool| {
+            let mut cqe = CompletionQueueEntry {
+                sqid: 1,
+                cid,
+                status_phase: 0x2 << 1,
+                ..Default::default()
+            };
+            cqe.set_phase(phase);
+            assert!(mem.write(GuestAddr(base.0 + slot * CQE_LEN as u64), &cqe));
+        };
+
+        post(0, 7, true);
+        let comp = cq.pop(&mem).unwrap();
+        assert_eq!((comp.sqid, comp.cid, comp.status_code()), (1, 7, 2));
+        assert!(!comp.is_success());
+        assert!(cq.pop(&mem).is_none());
+
+        post(1, 8, true);
+        assert_eq!(cq.pop(&mem).unwrap().cid, 8);
+        assert_eq!(cq.head(), 0);
+
+        // Stale entry from the previous pass through the queue
+        assert!(cq.pop(&mem).is_none());
+        post(0, 9, false);
+        assert_eq!(cq.pop(&mem).unwrap().cid, 9);
+    }
+}
@@ -0,0 +1,213 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The driver side of a split virtqueue, in the legacy layout: descriptor
+//! table, then available ring, then used ring at the next page boundary.
+
+use std::num::Wrapping;
+
+use crate::common::{GuestAddr, PAGE_SIZE};
+use crate::vmm::MemCtx;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+const DESC_LEN: u64 = 16;
+const USED_ELEM_LEN: u64 = 8;
+
+/// A buffer to be made available to the device as part of a chain
+#[derive(Copy, Clone, Debug)]
+pub struct Buf {
+    pub addr: GuestAddr,
+    pub len: u32,
+    /// Whether the device may write to (rather than read from) the buffer
+    pub writable: bool,
+}
+impl Buf {
+    pub fn readable(addr: GuestAddr, len: u32) -> Self {
+        Self { addr, len, writable: false }
+    }
+    pub fn writable(addr: GuestAddr, len: u32) -> Self {
+        Self { addr, len, writable: true }
+    }
+}
+
+/// An entry the device has placed in the used ring
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Used {
+    /// Descriptor index at the head of the chain
+    pub id: u16,
+    /// Number of bytes the device reports having written
+    pub len: u32,
+}
+
+/// A driver's view of a split virtqueue residing in guest memory.
+pub struct DriverQueue {
+    base: GuestAddr,
+    size: u16,
+    free: Vec<u16>,
+    chain_lens: Vec<u16>,
+    avail_idx: Wrapping<u16>,
+    used_idx: Wrapping<u16>,
+}
+impl DriverQueue {
+    /// The number of bytes of guest memory occupied by a queue of `size`
+    /// entries
+    pub fn mem_len(size: u16) -> usize {
+        let (_avail, used) = Self::offsets(size);
+        (used + 6 + USED_ELEM_LEN * u64::from(size)) as usize
+    }
+
+    /// Creates a queue of `size` entries at `base`, which must be page-aligned
+    /// and have at least [`DriverQueue::mem_len()`] bytes of memory behind it.
+    /// The rings are zeroed.
+    pub fn new(mem: &MemCtx, base: GuestAddr, size: u16) -> Self {
+        assert!(size.is_power_of_two());
+        assert_eq!(base.0 % PAGE_SIZE as u64, 0);
+        assert!(mem.write_byte(base, 0, Self::mem_len(size)));
+
+        Self {
+            base,
+            size,
+            free: (0..size).rev().collect(),
+            chain_lens: vec![0; size as usize],
+            avail_idx: Wrapping(0),
+            used_idx: Wrapping(0),
+        }
+    }
+
+    /// The address of the queue, as programmed into the device
+    pub fn base(&self) -> GuestAddr {
+        self.base
+    }
+
+    /// The number of descriptors not currently part of an outstanding chain
+    pub fn free_descs(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Makes a chain of `bufs` available to the device, returning the index
+    /// of its head descriptor, or `None` if too few descriptors are free.
+    ///
+    /// The device must still be notified that the queue has been updated.
+    pub fn push(&mut self, mem: &MemCtx, bufs: &[Buf]) -> Option<u16> {
+        if bufs.is_empty() || bufs.len() > self.free.len() {
+            return None;
+        }
+        let descs: Vec<u16> =
+            (0..bufs.len()).map(|_| self.free.pop().unwrap()).collect();
+
+        for (i, (buf, idx)) in bufs.iter().zip(descs.iter()).enumerate() {
+            let mut flags = 0;
+            if buf.writable {
+                flags |= VIRTQ_DESC_F_WRITE;
+            }
+            let next = match descs.get(i + 1) {
+                Some(next) => {
+                    flags |= VIRTQ_DESC_F_NEXT;
+                    *next
+                }
+                None => 0,
+            };
+            let addr = self.base.0 + DESC_LEN * u64::from(*idx);
+            assert!(mem.write(GuestAddr(addr), &buf.addr.0));
+            assert!(mem.write(GuestAddr(addr + 8), &buf.len));
+            assert!(mem.write(GuestAddr(addr + 12), &flags));
+            assert!(mem.write(GuestAddr(addr + 14), &next));
+        }
+
+        let head = descs[0];
+        self.chain_lens[head as usize] = descs.len() as u16;
+
+        let (avail, _used) = Self::offsets(self.size);
+        let slot = self.avail_idx.0 % self.size;
+        let ring_addr = self.base.0 + avail + 4 + 2 * u64::from(slot);
+        assert!(mem.write(GuestAddr(ring_addr), &head));
+        self.avail_idx += 1;
+        assert!(
+            mem.write(GuestAddr(self.base.0 + avail + 2), &self.avail_idx.0)
+        );
+
+        Some(head)
+    }
+
+    /// Takes the next entry from the used ring, if the device has added one,
+    /// freeing the descriptors of its chain.
+    pub fn pop_used(&mut self, mem: &MemCtx) -> Option<Used> {
+        let (_avail, used) = Self::offsets(self.size);
+        let dev_idx = mem.read::<u16>(GuestAddr(self.base.0 + used + 2))?;
+        if dev_idx == self.used_idx.0 {
+            return None;
+        }
+
+        let slot = self.used_idx.0 % self.size;
+        let elem_addr =
+            self.base.0 + used + 4 + USED_ELEM_LEN * u64::from(slot);
+        let id = mem.read::<u32>(GuestAddr(elem_addr))? as u16;
+        let len = mem.read::<u32>(GuestAddr(elem_addr + 4))?;
+        self.used_idx += 1;
+
+        // Walk the chain to return its descriptors to the free list
+        let mut idx = id;
+        for _ in 0..self.chain_lens[id as usize] {
+            self.free.push(idx);
+            let addr = self.base.0 + DESC_LEN * u64::from(idx);
+            idx = mem.read::<u16>(GuestAddr(addr + 14))?;
+        }
+        self.chain_lens[id as usize] = 0;
+
+        Some(Used { id, len })
+    }
+
+    /// Offsets of the available and used rings from the base of the queue
+    fn offsets(size: u16) -> (u64, u64) {
+        let avail = DESC_LEN * u64::from(size);
+        let avail_len = 2 * (u64::from(size) + 3);
+        let mask = PAGE_SIZE as u64 - 1;
+        let used = (avail + avail_len + mask) & !mask;
+        (avail, used)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hw::virtio::queue::{Chain, VirtQueue};
+    use crate::testing::TestMachine;
+
+    #[test]
+    fn round_trip() {
+        let tm = TestMachine::new().unwrap();
+        let size = 16;
+        let base = tm.alloc(DriverQueue::mem_len(size), PAGE_SIZE);
+        let req = tm.alloc(8, 8);
+        let resp = tm.alloc(8, 8);
+
+        let mem = tm.mem();
+        let mut dq = DriverQueue::new(&mem, base, size);
+        assert!(mem.write(req, &0x1122_3344_5566_7788u64));
+
+        let vq = VirtQueue::new(0, size);
+        vq.map_legacy(base.0);
+
+        let head = dq
+            .push(&mem, &[Buf::readable(req, 8), Buf::writable(resp, 8)])
+            .unwrap();
+        assert_eq!(dq.free_descs(), 14);
+
+        let mut chain = Chain::with_capacity(2);
+        let (_, len) = vq.pop_avail(&mut chain, &mem).unwrap();
+        assert_eq!(len, 16);
+        let mut val = 0u64;
+        assert!(chain.read(&mut val, &mem));
+        assert!(chain.write(&!val, &mem));
+        assert!(dq.pop_used(&mem).is_none());
+        vq.push_used(&mut chain, &mem);
+
+        assert_eq!(dq.pop_used(&mem), Some(Used { id: head, len: 8 }));
+        assert_eq!(dq.free_descs(), 16);
+        assert_eq!(mem.read::<u64>(resp), Some(!0x1122_3344_5566_7788u64));
+        assert!(dq.pop_used(&mem).is_none());
+    }
+}
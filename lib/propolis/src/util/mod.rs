@@ -3,6 +3,7 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod aspace;
+pub mod pcapng;
 pub mod regmap;
 
 mod ioctl {
@@ -0,0 +1,223 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A minimal writer for the pcapng capture file format, covering a single
+//! section with a single Ethernet interface.
+//!
+//! See <https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-02.html>.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const BT_SHB: u32 = 0x0a0d_0d0a;
+const BT_IDB: u32 = 0x0000_0001;
+const BT_EPB: u32 = 0x0000_0006;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const LINKTYPE_ETHERNET: u16 = 1;
+
+const OPT_ENDOFOPT: u16 = 0;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+
+/// Size of the fixed portion of an enhanced packet block, including the block
+/// type and both length fields, but not the packet data or options
+const EPB_FIXED_LEN: usize = 32;
+/// Size of the `epb_flags` option and the end-of-options marker
+const EPB_OPTS_LEN: usize = 12;
+
+/// Direction of a captured packet, relative to the guest
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// Sent by the guest
+    Outbound,
+    /// Delivered to the guest
+    Inbound,
+}
+
+/// Accumulates a pcapng capture in memory.
+pub struct Writer {
+    buf: Vec<u8>,
+    snaplen: u32,
+}
+impl Writer {
+    /// Starts a new capture for an interface named `if_name`, which retains at
+    /// most `snaplen` bytes of each packet.
+    pub fn new(if_name: &str, snaplen: u32) -> Self {
+        let mut this = Self { buf: Vec::new(), snaplen };
+
+        // Section header, of unspecified length
+        this.block(BT_SHB, |buf| {
+            put_u32(buf, BYTE_ORDER_MAGIC);
+            put_u16(buf, 1);
+            put_u16(buf, 0);
+            buf.extend_from_slice(&(-1i64).to_le_bytes());
+        });
+
+        // Interface description, with nanosecond timestamps
+        this.block(BT_IDB, |buf| {
+            put_u16(buf, LINKTYPE_ETHERNET);
+            put_u16(buf, 0);
+            put_u32(buf, snaplen);
+            put_opt(buf, IF_NAME, if_name.as_bytes());
+            put_opt(buf, IF_TSRESOL, &[9]);
+            put_opt(buf, OPT_ENDOFOPT, &[]);
+        });
+
+        this
+    }
+
+    /// The number of bytes that recording `data` would add to the capture.
+    pub fn packet_len(&self, data: &[u8]) -> usize {
+        let caplen = data.len().min(self.snaplen as usize);
+        EPB_FIXED_LEN + pad4(caplen) + EPB_OPTS_LEN
+    }
+
+    /// Records a packet seen at time `ts`. `data` is truncated to the
+    /// capture's snap length if need be, and `orig_len` is the length of the
+    /// packet on the wire.
+    pub fn packet(
+        &mut self,
+        ts: SystemTime,
+        dir: Direction,
+        data: &[u8],
+        orig_len: usize,
+    ) {
+        let data = &data[..data.len().min(self.snaplen as usize)];
+        let ts = ts
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let flags = match dir {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+        };
+
+        self.block(BT_EPB, |buf| {
+            // Interface ID
+            put_u32(buf, 0);
+            put_u32(buf, (ts >> 32) as u32);
+            put_u32(buf, ts as u32);
+            put_u32(buf, data.len() as u32);
+            put_u32(buf, orig_len.max(data.len()) as u32);
+            put_padded(buf, data);
+            put_opt(buf, EPB_FLAGS, &u32::to_le_bytes(flags));
+            put_opt(buf, OPT_ENDOFOPT, &[]);
+        });
+    }
+
+    /// The capture as it stands, which is a valid pcapng file.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Appends a block of type `btype` whose body is produced by `body`,
+    /// wrapping it in the type and length fields.
+    fn block(&mut self, btype: u32, body: impl FnOnce(&mut Vec<u8>)) {
+        let start = self.buf.len();
+        put_u32(&mut self.buf, btype);
+        put_u32(&mut self.buf, 0);
+        body(&mut self.buf);
+
+        let len = (self.buf.len() - start + 4) as u32;
+        self.buf[start + 4..start + 8].copy_from_slice(&len.to_le_bytes());
+        put_u32(&mut self.buf, len);
+    }
+}
+
+fn pad4(len: usize) -> usize {
+    (len + 3) & !3
+}
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+fn put_padded(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(data);
+    buf.resize(buf.len() + pad4(data.len()) - data.len(), 0);
+}
+fn put_opt(buf: &mut Vec<u8>, code: u16, val: &[u8]) {
+    put_u16(buf, code);
+    put_u16(buf, val.len() as u16);
+    put_padded(buf, val);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn u32_at(buf: &[u8], off: usize) -> u32 {
+        u32::from_le_bytes(buf[off..off + 4].try_into().unwrap())
+    }
+
+    /// Splits a capture into its blocks, checking that the leading and
+    /// trailing lengths of each agree.
+    fn blocks(buf: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut res = Vec::new();
+        let mut off = 0;
+        while off < buf.len() {
+            let btype = u32_at(buf, off);
+            let len = u32_at(buf, off + 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(buf, off + len - 4) as usize, len);
+            res.push((btype, &buf[off + 8..off + len - 4]));
+            off += len;
+        }
+        assert_eq!(off, buf.len());
+        res
+    }
+
+    #[test]
+    fn headers() {
+        let w = Writer::new("net0", 1500);
+        let blocks = blocks(w.as_bytes());
+        assert_eq!(blocks.len(), 2);
+
+        let (btype, shb) = blocks[0];
+        assert_eq!(btype, BT_SHB);
+        assert_eq!(u32_at(shb, 0), BYTE_ORDER_MAGIC);
+
+        let (btype, idb) = blocks[1];
+        assert_eq!(btype, BT_IDB);
+        assert_eq!(u16::from_le_bytes([idb[0], idb[1]]), LINKTYPE_ETHERNET);
+        assert_eq!(u32_at(idb, 4), 1500);
+        // if_name option, padded out to a multiple of 4 bytes
+        assert_eq!(&idb[8..12], &[2, 0, 4, 0]);
+        assert_eq!(&idb[12..16], b"net0");
+    }
+
+    #[test]
+    fn packets() {
+        let mut w = Writer::new("net0", 8);
+        let before = w.as_bytes().len();
+        let ts = UNIX_EPOCH + Duration::from_nanos(0x1_0000_0002);
+
+        let pkt = [0xaau8; 10];
+        let expected_len = w.packet_len(&pkt);
+        w.packet(ts, Direction::Outbound, &pkt, pkt.len());
+        assert_eq!(w.as_bytes().len() - before, expected_len);
+
+        let parsed = blocks(w.as_bytes());
+        let (btype, epb) = parsed[2];
+        assert_eq!(btype, BT_EPB);
+        assert_eq!(u32_at(epb, 4), 1);
+        assert_eq!(u32_at(epb, 8), 2);
+        // Truncated to the snap length, but with the original length kept
+        assert_eq!(u32_at(epb, 12), 8);
+        assert_eq!(u32_at(epb, 16), 10);
+        assert_eq!(&epb[20..28], &[0xaa; 8]);
+        // epb_flags: outbound
+        assert_eq!(&epb[28..32], &[2, 0, 4, 0]);
+        assert_eq!(u32_at(epb, 32), 0b10);
+
+        w.packet(ts, Direction::Inbound, &[1, 2, 3], 3);
+        let parsed = blocks(w.as_bytes());
+        let (_, epb) = parsed[3];
+        assert_eq!(&epb[20..24], &[1, 2, 3, 0]);
+        assert_eq!(u32_at(epb, 28), 0b01);
+    }
+}
@@ -4,7 +4,7 @@
 
 //! Virtual CPU functionality.
 
-use std::io::{Error, ErrorKind, Result};
+use std::io::Result;
 use std::sync::Arc;
 
 use crate::common::Lifecycle;
@@ -17,8 +17,6 @@ use crate::tasks;
 use crate::vmm::VmmHdl;
 use migrate::VcpuReadWrite;
 
-use bhyve_api::ApiVersion;
-
 #[usdt::provider(provider = "propolis")]
 mod probes {
     fn vm_entry(vcpuid: u32) {}
@@ -62,43 +60,17 @@ impl Vcpu {
 
     /// Sets the capabilities of the virtual CPU.
     pub fn set_default_capabs(&self) -> Result<()> {
-        // Enable exit-on-HLT so the host CPU does not spin in VM context when
-        // the guest enters a HLT instruction.
-        let mut cap = bhyve_api::vm_capability {
-            cpuid: self.id,
-            captype: bhyve_api::vm_cap_type::VM_CAP_HALT_EXIT as i32,
-            capval: 1,
-            allcpus: 0,
-        };
-        unsafe { self.hdl.ioctl(bhyve_api::VM_SET_CAPABILITY, &mut cap) }
+        self.hdl.vcpu_set_default_capabs(self.id)
     }
 
     /// Sets the value of a register within the CPU.
     pub fn set_reg(&self, reg: bhyve_api::vm_reg_name, val: u64) -> Result<()> {
-        let mut regcmd = bhyve_api::vm_register {
-            cpuid: self.id,
-            regnum: reg as i32,
-            regval: val,
-        };
-
-        unsafe {
-            self.hdl.ioctl(bhyve_api::VM_SET_REGISTER, &mut regcmd)?;
-        }
-        Ok(())
+        self.hdl.vcpu_set_reg(self.id, reg, val)
     }
 
     /// Gets the value of a register within the CPU.
     pub fn get_reg(&self, reg: bhyve_api::vm_reg_name) -> Result<u64> {
-        let mut regcmd = bhyve_api::vm_register {
-            cpuid: self.id,
-            regnum: reg as i32,
-            regval: 0,
-        };
-
-        unsafe {
-            self.hdl.ioctl(bhyve_api::VM_GET_REGISTER, &mut regcmd)?;
-        }
-        Ok(regcmd.regval)
+        self.hdl.vcpu_get_reg(self.id, reg)
     }
 
     /// Set a segment register `reg` to a particular value `seg`.
@@ -110,16 +82,7 @@ impl Vcpu {
         reg: bhyve_api::vm_reg_name,
         seg: &bhyve_api::seg_desc,
     ) -> Result<()> {
-        let mut req = bhyve_api::vm_seg_desc {
-            cpuid: self.id,
-            regnum: reg as i32,
-            desc: *seg,
-        };
-
-        unsafe {
-            self.hdl.ioctl(bhyve_api::VM_SET_SEGMENT_DESCRIPTOR, &mut req)?;
-        }
-        Ok(())
+        self.hdl.vcpu_set_segreg(self.id, reg, seg)
     }
 
     /// Get the contents of segment register `reg`
@@ -130,16 +93,7 @@ impl Vcpu {
         &self,
         reg: bhyve_api::vm_reg_name,
     ) -> Result<bhyve_api::seg_desc> {
-        let mut req = bhyve_api::vm_seg_desc {
-            cpuid: self.id,
-            regnum: reg as i32,
-            desc: bhyve_api::seg_desc::default(),
-        };
-
-        unsafe {
-            self.hdl.ioctl(bhyve_api::VM_GET_SEGMENT_DESCRIPTOR, &mut req)?;
-        }
-        Ok(req.desc)
+        self.hdl.vcpu_get_segreg(self.id, reg)
     }
 
     /// Configure the (in-kernel) `cpuid` emulation state for this vCPU.
@@ -147,29 +101,7 @@ impl Vcpu {
     /// If `values` contains no cpuid entries, then legacy emulation handling
     /// will be used.
     pub fn set_cpuid(&self, values: cpuid::Set) -> Result<()> {
-        let mut config = bhyve_api::vm_vcpu_cpuid_config {
-            vvcc_vcpuid: self.id,
-            ..Default::default()
-        };
-        if values.is_empty() {
-            config.vvcc_flags = bhyve_api::VCC_FLAG_LEGACY_HANDLING;
-            unsafe {
-                self.hdl.ioctl(bhyve_api::VM_SET_CPUID, &mut config)?;
-            }
-        } else {
-            if values.vendor.is_intel() {
-                config.vvcc_flags |= bhyve_api::VCC_FLAG_INTEL_FALLBACK;
-            }
-            let mut entries: Vec<bhyve_api::vcpu_cpuid_entry> = values.into();
-            entries.sort_by(bhyve_api::vcpu_cpuid_entry::eval_sort);
-            config.vvcc_nent = entries.len() as u32;
-            config.vvcc_entries = entries.as_mut_ptr() as *mut libc::c_void;
-            unsafe {
-                self.hdl.ioctl(bhyve_api::VM_SET_CPUID, &mut config)?;
-            }
-        }
-
-        Ok(())
+        self.hdl.vcpu_set_cpuid(self.id, values)
     }
 
     /// Query the configured (in-kernel) `cpuid` emulation state for this vCPU.
@@ -177,95 +109,19 @@ impl Vcpu {
     /// If legacy cpuid handling is configured, the resulting [Set](cpuid::Set)
     /// will contain no entries.
     pub fn get_cpuid(&self) -> Result<cpuid::Set> {
-        let mut config = bhyve_api::vm_vcpu_cpuid_config {
-            vvcc_vcpuid: self.id,
-            vvcc_nent: 0,
-            ..Default::default()
-        };
-        // Query the number of entries configured in-kernel
-        //
-        // We expect an error (E2BIG) when attempting a VM_GET_CPUID with a
-        // vvcc_nent which falls below the number of entries stored in the
-        // kernel.  When that occurs, vvcc_nent will be updated with that
-        // existing count so we may allocate an array to receive it on a
-        // subsquent ioctl.
-        let count = match unsafe {
-            self.hdl.ioctl(bhyve_api::VM_GET_CPUID, &mut config)
-        } {
-            Err(_) if config.vvcc_nent != 0 => Ok(config.vvcc_nent),
-            Ok(_) => {
-                assert_eq!(config.vvcc_nent, 0);
-                Ok(0)
-            }
-            Err(e) => Err(e),
-        }?;
-
-        let mut entries = Vec::with_capacity(count as usize);
-        entries.fill(bhyve_api::vcpu_cpuid_entry::default());
-        config.vvcc_entries = entries.as_mut_ptr() as *mut libc::c_void;
-        unsafe {
-            self.hdl.ioctl(bhyve_api::VM_GET_CPUID, &mut config)?;
-        }
-
-        if config.vvcc_flags & bhyve_api::VCC_FLAG_LEGACY_HANDLING != 0 {
-            // Since the legacy handling takes care of vendor-specific handling
-            // (by nature of doing the cpuid queries against the host CPU) it
-            // ignores the INTEL_FALLBACK flag.  We must determine the vendor
-            // kind by querying it.
-            let vendor = cpuid::VendorKind::try_from(cpuid::host_query(
-                cpuid::Ident(0, None),
-            ))
-            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
-
-            return Ok(cpuid::Set::new(vendor));
-        }
-        let intel_fallback =
-            config.vvcc_flags & bhyve_api::VCC_FLAG_INTEL_FALLBACK != 0;
-        let mut set = cpuid::Set::new(match intel_fallback {
-            true => cpuid::VendorKind::Intel,
-            false => cpuid::VendorKind::Amd,
-        });
-
-        for entry in entries {
-            let (ident, value) = cpuid::from_raw(entry);
-            let conflict = set.insert(ident, value);
-            if conflict.is_some() {
-                return Err(Error::new(
-                    ErrorKind::InvalidData,
-                    format!(
-                        "conflicting entry at eax:{:x} ecx:{:x?})",
-                        ident.0, ident.1
-                    ),
-                ));
-            }
-        }
-        Ok(set)
+        self.hdl.vcpu_get_cpuid(self.id)
     }
 
     /// Issues a command to reset all state for the virtual CPU (including registers and
     /// pending interrupts).
     pub fn reboot_state(&self) -> Result<()> {
-        let mut vvr = bhyve_api::vm_vcpu_reset {
-            vcpuid: self.id,
-            kind: bhyve_api::vcpu_reset_kind::VRK_RESET as u32,
-        };
-
-        unsafe {
-            self.hdl.ioctl(bhyve_api::VM_RESET_CPU, &mut vvr)?;
-        }
-
-        Ok(())
+        self.hdl.vcpu_reset(self.id)
     }
     /// Activates the virtual CPU.
     ///
     /// Fails if the CPU has already been activated.
     pub fn activate(&self) -> Result<()> {
-        let mut cpu = self.id;
-
-        unsafe {
-            self.hdl.ioctl(bhyve_api::VM_ACTIVATE_CPU, &mut cpu)?;
-        }
-        Ok(())
+        self.hdl.vcpu_activate(self.id)
     }
 
     /// Set the state of a virtual CPU.
@@ -274,26 +130,12 @@ impl Vcpu {
         state: u32,
         sipi_vector: Option<u8>,
     ) -> Result<()> {
-        let mut state = bhyve_api::vm_run_state {
-            vcpuid: self.id,
-            state,
-            sipi_vector: sipi_vector.unwrap_or(0),
-            ..Default::default()
-        };
-        unsafe {
-            self.hdl.ioctl(bhyve_api::VM_SET_RUN_STATE, &mut state)?;
-        }
-        Ok(())
+        self.hdl.vcpu_set_run_state(self.id, state, sipi_vector)
     }
 
     /// Get the state of the virtual CPU.
     pub fn get_run_state(&self) -> Result<bhyve_api::vm_run_state> {
-        let mut state =
-            bhyve_api::vm_run_state { vcpuid: self.id, ..Default::default() };
-        unsafe {
-            self.hdl.ioctl(bhyve_api::VM_GET_RUN_STATE, &mut state)?;
-        }
-        Ok(state)
+        self.hdl.vcpu_get_run_state(self.id)
     }
 
     /// Executes the guest by running the virtual CPU.
@@ -310,49 +152,20 @@ impl Vcpu {
         entry: &VmEntry,
         exit_when_consistent: bool,
     ) -> Result<VmExit> {
-        let mut exit: bhyve_api::vm_exit = Default::default();
-        let mut entry = entry.to_raw(self.id, &mut exit);
-
-        let api_version = self.hdl.api_version()?;
-
-        if exit_when_consistent {
-            if api_version >= ApiVersion::V15 {
-                entry.cmd |=
-                    bhyve_api::vm_entry_cmds::VEC_FLAG_EXIT_CONSISTENT as u32;
-            } else {
-                // On older platforms without EXIT_CONSISTENT, we may spend more
-                // time inside VM_RUN than desired, but there is little else
-                // that can be done.
-            }
-        }
         probes::vm_entry!(|| (self.id as u32));
-        let _res = unsafe { self.hdl.ioctl(bhyve_api::VM_RUN, &mut entry)? };
-        probes::vm_exit!(|| (self.id as u32, exit.rip, exit.exitcode as u32));
+        let exit = self.hdl.vcpu_run(self.id, entry, exit_when_consistent)?;
+        probes::vm_exit!(|| (
+            self.id as u32,
+            exit.rip,
+            exit.kind.code() as u32
+        ));
 
-        Ok(VmExit::parse(&exit, api_version))
+        Ok(exit)
     }
 
     /// Issue a "barrier" for the vCPU, forcing an exit from guest context
     pub fn barrier(&self) -> Result<()> {
-        if self.hdl.api_version()? >= ApiVersion::V16 {
-            // Use the official barrier operation, if available
-            self.hdl
-                .ioctl_usize(bhyve_api::VM_VCPU_BARRIER, self.id as usize)?;
-        } else {
-            // Prior to first-class support for a barrier, just force the vCPU
-            // out of guest context by reading %rax.  If the vCPU thread happens
-            // to be on its way into VM_RUN, but not already there, this old
-            // method can fail to incur a proper exit.
-            let mut regcmd = bhyve_api::vm_register {
-                cpuid: self.id,
-                regnum: bhyve_api::vm_reg_name::VM_REG_GUEST_RAX as i32,
-                regval: 0,
-            };
-            unsafe {
-                self.hdl.ioctl(bhyve_api::VM_GET_REGISTER, &mut regcmd)?;
-            }
-        }
-        Ok(())
+        self.hdl.vcpu_barrier(self.id)
     }
 
     /// Emit a barrier `Fn`, suitable for use as a
@@ -369,8 +182,7 @@ impl Vcpu {
 
     /// Send a Non Maskable Interrupt (NMI) to the vcpu.
     pub fn inject_nmi(&self) -> Result<()> {
-        let mut vm_nmi = bhyve_api::vm_nmi { cpuid: self.cpuid() };
-        unsafe { self.hdl.ioctl(bhyve_api::VM_INJECT_NMI, &mut vm_nmi) }
+        self.hdl.vcpu_inject_nmi(self.id)
     }
 
     /// Process [`VmExit`] in the context of this vCPU, emitting a [`VmEntry`]
@@ -496,8 +308,8 @@ impl MigrateMulti for Vcpu {
 }
 
 pub mod migrate {
+    use std::io;
     use std::io::Result;
-    use std::{convert::TryInto, io};
 
     use super::Vcpu;
     use crate::cpuid;
@@ -874,11 +686,7 @@ pub mod migrate {
         fn read(vcpu: &Vcpu) -> Result<Self> {
             let run_state = vcpu.get_run_state()?;
 
-            let vmm_arch: Vec<bhyve_api::vdi_field_entry_v1> = vcpu
-                .hdl
-                .data_op(bhyve_api::VDC_VMM_ARCH, 1)
-                .for_vcpu(vcpu.id)
-                .read_all()?;
+            let vmm_arch = vcpu.hdl.vcpu_get_arch(vcpu.id)?;
 
             // Load all of the pending interrupt/exception state
             //
@@ -952,11 +760,8 @@ pub mod migrate {
             // When hosts with illumos#15143 integrated become common, the
             // overall required version for propolis can grow to encompass V10
             // and this check can be elided.
-            if bhyve_api::api_version()? >= ApiVersion::V10 {
-                vcpu.hdl
-                    .data_op(bhyve_api::VDC_VMM_ARCH, 1)
-                    .for_vcpu(vcpu.id)
-                    .write_many(&ents)?;
+            if vcpu.hdl.api_version()? >= ApiVersion::V10 {
+                vcpu.hdl.vcpu_set_arch(vcpu.id, &ents)?;
             }
 
             Ok(())
@@ -1163,11 +968,7 @@ pub mod migrate {
 
     impl VcpuReadWrite for VcpuMsrsV1 {
         fn read(vcpu: &Vcpu) -> Result<Self> {
-            let raw_msrs: Vec<bhyve_api::vdi_field_entry_v1> = vcpu
-                .hdl
-                .data_op(bhyve_api::VDC_MSR, 1)
-                .for_vcpu(vcpu.id)
-                .read_all()?;
+            let raw_msrs = vcpu.hdl.vcpu_get_msrs(vcpu.id)?;
 
             let mut filtered: Vec<MsrEntry> = raw_msrs
                 .into_iter()
@@ -1202,10 +1003,7 @@ pub mod migrate {
                 })
                 .collect();
 
-            vcpu.hdl
-                .data_op(bhyve_api::VDC_MSR, 1)
-                .for_vcpu(vcpu.id)
-                .write_many(&raw_msrs)?;
+            vcpu.hdl.vcpu_set_msrs(vcpu.id, &raw_msrs)?;
 
             Ok(())
         }
@@ -1213,52 +1011,16 @@ pub mod migrate {
 
     impl VcpuReadWrite for FpuStateV1 {
         fn read(vcpu: &Vcpu) -> Result<Self> {
-            let mut fpu_area_desc = bhyve_api::vm_fpu_desc::default();
-
-            unsafe {
-                vcpu.hdl
-                    .ioctl(bhyve_api::VM_DESC_FPU_AREA, &mut fpu_area_desc)?;
-            }
-            let len = fpu_area_desc.vfd_req_size as usize;
-            let mut fpu = Vec::with_capacity(len);
-            fpu.resize_with(len, u8::default);
-
-            let mut fpu_req = bhyve_api::vm_fpu_state {
-                vcpuid: vcpu.cpuid(),
-                buf: fpu.as_mut_ptr() as *mut libc::c_void,
-                len: fpu_area_desc.vfd_req_size,
-            };
-            unsafe {
-                vcpu.hdl.ioctl(bhyve_api::VM_GET_FPU, &mut fpu_req)?;
-            }
-
-            Ok(Self { blob: fpu })
+            Ok(Self { blob: vcpu.hdl.vcpu_get_fpu(vcpu.id)? })
         }
 
-        fn write(mut self, vcpu: &Vcpu) -> Result<()> {
-            let mut fpu_req = bhyve_api::vm_fpu_state {
-                vcpuid: vcpu.cpuid(),
-                buf: self.blob.as_mut_ptr() as *mut _,
-                len: self.blob.len().try_into().map_err(|_| {
-                    io::Error::new(
-                        io::ErrorKind::Other,
-                        "fpu blob size too large",
-                    )
-                })?,
-            };
-            unsafe {
-                vcpu.hdl.ioctl(bhyve_api::VM_SET_FPU, &mut fpu_req)?;
-            }
-            Ok(())
+        fn write(self, vcpu: &Vcpu) -> Result<()> {
+            vcpu.hdl.vcpu_set_fpu(vcpu.id, &self.blob)
         }
     }
     impl VcpuReadWrite for LapicV1 {
         fn read(vcpu: &Vcpu) -> Result<Self> {
-            let mut vdi = vcpu
-                .hdl
-                .data_op(bhyve_api::VDC_LAPIC, 1)
-                .for_vcpu(vcpu.id)
-                .read::<bhyve_api::vdi_lapic_v1>()?;
+            let mut vdi = vcpu.hdl.vcpu_get_lapic(vcpu.id)?;
 
             // A timer target without a value in ICR is nonsensical
             if vdi.vl_timer_target != 0 && vdi.vl_lapic.vlp_icr_timer == 0 {
@@ -1285,12 +1047,7 @@ pub mod migrate {
                 ));
             }
 
-            vcpu.hdl
-                .data_op(bhyve_api::VDC_LAPIC, 1)
-                .for_vcpu(vcpu.id)
-                .write::<bhyve_api::vdi_lapic_v1>(&self.into())?;
-
-            Ok(())
+            vcpu.hdl.vcpu_set_lapic(vcpu.id, &self.into())
         }
     }
     impl VcpuReadWrite for CpuidV1 {
@@ -1308,3 +1065,133 @@ mod bits {
     pub const MSR_DEBUGCTL: u32 = 0x1d9;
     pub const MSR_EFER: u32 = 0xc0000080;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::common::RWOp;
+    use crate::mmio::MmioFn;
+    use crate::pio::PioFn;
+    use crate::vmm::Machine;
+
+    const TEST_PORT: u16 = 0x510;
+    const TEST_MMIO: u64 = 0xc000_0000;
+
+    fn machine() -> Machine {
+        let machine = Machine::new_test().unwrap();
+        machine.vcpus[0].activate().unwrap();
+        machine
+    }
+
+    /// Script an exit of `kind` for the BSP, run it, and process the resulting
+    /// exit as a vCPU thread would.
+    fn run_exit(machine: &Machine, kind: VmExitKind) -> Option<VmEntry> {
+        let vcpu = &machine.vcpus[0];
+        let mock = machine.hdl.mock().unwrap();
+        mock.push_exit(vcpu.id, VmExit { rip: 0x1000, inst_len: 1, kind });
+
+        let exit = vcpu.run(&VmEntry::Run, false).unwrap();
+        assert_eq!(exit.rip, 0x1000);
+        vcpu.process_vmexit(&exit)
+    }
+
+    /// A 4-byte register, accessible through PIO or MMIO
+    fn test_reg() -> (Arc<Mutex<u32>>, impl Fn(RWOp) + Send + Sync) {
+        let reg = Arc::new(Mutex::new(0u32));
+        let dev_reg = reg.clone();
+        let func = move |rwo: RWOp| match rwo {
+            RWOp::Read(ro) => ro.write_u32(*dev_reg.lock().unwrap()),
+            RWOp::Write(wo) => *dev_reg.lock().unwrap() = wo.read_u32(),
+        };
+        (reg, func)
+    }
+
+    #[test]
+    fn inout_exits() {
+        let machine = machine();
+        let (reg, func) = test_reg();
+        let piofn =
+            Arc::new(move |_port: u16, rwo: RWOp| func(rwo)) as Arc<PioFn>;
+        machine.bus_pio.register(TEST_PORT, 4, piofn).unwrap();
+
+        let port = IoPort { port: TEST_PORT, bytes: 4 };
+        let entry =
+            run_exit(&machine, VmExitKind::Inout(InoutReq::Out(port, 0xabcd)));
+        assert!(matches!(
+            entry,
+            Some(VmEntry::InoutFulfill(InoutRes::Out(IoPort {
+                port: TEST_PORT,
+                bytes: 4
+            })))
+        ));
+        assert_eq!(*reg.lock().unwrap(), 0xabcd);
+
+        let entry = run_exit(&machine, VmExitKind::Inout(InoutReq::In(port)));
+        assert!(matches!(
+            entry,
+            Some(VmEntry::InoutFulfill(InoutRes::In(_, 0xabcd)))
+        ));
+
+        // Accesses to unclaimed ports are left to the caller
+        let port = IoPort { port: TEST_PORT + 0x10, bytes: 1 };
+        let entry = run_exit(&machine, VmExitKind::Inout(InoutReq::In(port)));
+        assert!(entry.is_none());
+    }
+
+    #[test]
+    fn mmio_exits() {
+        let machine = machine();
+        let (reg, func) = test_reg();
+        let mmiofn =
+            Arc::new(move |_addr: usize, rwo: RWOp| func(rwo)) as Arc<MmioFn>;
+        machine.bus_mmio.register(TEST_MMIO as usize, 4, mmiofn).unwrap();
+
+        let write = MmioWriteReq { addr: TEST_MMIO, data: 0x1234, bytes: 4 };
+        let entry = run_exit(&machine, VmExitKind::Mmio(MmioReq::Write(write)));
+        assert!(matches!(
+            entry,
+            Some(VmEntry::MmioFulfill(MmioRes::Write(MmioWriteRes {
+                addr: TEST_MMIO,
+                bytes: 4
+            })))
+        ));
+        assert_eq!(*reg.lock().unwrap(), 0x1234);
+
+        let read = MmioReadReq { addr: TEST_MMIO, bytes: 4 };
+        let entry = run_exit(&machine, VmExitKind::Mmio(MmioReq::Read(read)));
+        assert!(matches!(
+            entry,
+            Some(VmEntry::MmioFulfill(MmioRes::Read(MmioReadRes {
+                data: 0x1234,
+                ..
+            })))
+        ));
+
+        let read = MmioReadReq { addr: TEST_MMIO + 0x1000, bytes: 4 };
+        let entry = run_exit(&machine, VmExitKind::Mmio(MmioReq::Read(read)));
+        assert!(entry.is_none());
+    }
+
+    #[test]
+    fn msr_exits_left_to_caller() {
+        let machine = machine();
+        assert!(run_exit(&machine, VmExitKind::Rdmsr(0x10)).is_none());
+        assert!(run_exit(&machine, VmExitKind::Wrmsr(0x10, 1)).is_none());
+    }
+
+    #[test]
+    fn entries_reach_hypervisor() {
+        let machine = machine();
+        let vcpu = &machine.vcpus[0];
+        let mock = machine.hdl.mock().unwrap();
+
+        let entry = run_exit(&machine, VmExitKind::Bogus).unwrap();
+        let _ = vcpu.run(&entry, true).unwrap();
+        let entries = mock.take_entries(vcpu.id);
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| matches!(entry, VmEntry::Run)));
+    }
+}
@@ -0,0 +1,757 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! [Hypervisor] implementation atop the in-kernel bhyve VMM.
+
+use std::any::Any;
+use std::io::{Error, ErrorKind, Result, Write};
+use std::os::raw::c_void;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::common::PAGE_SIZE;
+use crate::cpuid;
+use crate::exits::{VmEntry, VmExit};
+use crate::vmm::hdl::CreateOpts;
+use crate::vmm::hypervisor::{DeviceKind, DeviceState, Hypervisor};
+use crate::vmm::mem::Prot;
+use crate::vmm::time::VmTimeData;
+
+use bhyve_api::{vdi_field_entry_v1, ApiVersion};
+
+/// A VM instance within the bhyve kernel VMM, accessed through its device
+/// node at `/dev/vmm/{name}`.
+pub struct BhyveVmm {
+    inner: bhyve_api::VmmFd,
+    destroyed: AtomicBool,
+    name: String,
+}
+impl BhyveVmm {
+    /// Creates a new virtual machine with the provided `name`.
+    ///
+    /// Operates on the bhyve controller object at `/dev/vmmctl`,
+    /// which acts as an interface to the kernel module, and opens
+    /// an object at `/dev/vmm/{name}`.
+    ///
+    /// # Arguments
+    /// - `name`: The name of the VM to create.
+    /// - `opts`: Creation options (detailed in `CreateOpts`)
+    pub fn create(name: &str, opts: CreateOpts) -> Result<Self> {
+        let ctl = bhyve_api::VmmCtlFd::open()?;
+
+        let mut req = bhyve_api::vm_create_req::new(name.as_bytes())?;
+        if opts.use_reservoir {
+            req.flags |= bhyve_api::VCF_RESERVOIR_MEM;
+        }
+        if opts.track_dirty {
+            req.flags |= bhyve_api::VCF_TRACK_DIRTY;
+        }
+        let res = unsafe { ctl.ioctl(bhyve_api::VMM_CREATE_VM, &mut req) };
+        if let Err(e) = res {
+            if e.kind() != ErrorKind::AlreadyExists || !opts.force {
+                return Err(e);
+            }
+
+            // try to nuke(!) the existing vm
+            ctl.vm_destroy(name.as_bytes()).or_else(|e| match e.kind() {
+                ErrorKind::NotFound => Ok(()),
+                _ => Err(e),
+            })?;
+
+            // now attempt to create in its presumed absence
+            let _ = unsafe { ctl.ioctl(bhyve_api::VMM_CREATE_VM, &mut req) }?;
+        }
+
+        // Safety: Files opened within VMM_PATH_PREFIX are VMMs, which may not
+        // be truncated.
+        let inner = bhyve_api::VmmFd::open(name)?;
+
+        Ok(Self {
+            inner,
+            destroyed: AtomicBool::new(false),
+            name: name.to_string(),
+        })
+    }
+
+    /// Sends an ioctl to the underlying VMM.
+    pub unsafe fn ioctl<T>(&self, cmd: i32, data: *mut T) -> Result<()> {
+        if self.destroyed.load(Ordering::Acquire) {
+            return Err(Error::new(ErrorKind::NotFound, "instance destroyed"));
+        }
+
+        self.inner.ioctl(cmd, data)?;
+        Ok(())
+    }
+
+    /// Sends an ioctl (with usize param) to the underlying VMM.
+    pub fn ioctl_usize(&self, cmd: i32, data: usize) -> Result<()> {
+        if self.destroyed.load(Ordering::Acquire) {
+            return Err(Error::new(ErrorKind::NotFound, "instance destroyed"));
+        }
+
+        self.inner.ioctl_usize(cmd, data)?;
+        Ok(())
+    }
+
+    /// Prepares a read or write of the VMM data class `class`.
+    pub fn data_op(&self, class: u16, version: u16) -> bhyve_api::VmmDataOp {
+        self.inner.data_op(class, version)
+    }
+}
+
+impl Hypervisor for BhyveVmm {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn api_version(&self) -> Result<u32> {
+        self.inner.api_version()
+    }
+
+    fn fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+
+    fn create_memseg(
+        &self,
+        segid: i32,
+        size: usize,
+        segname: Option<&str>,
+    ) -> Result<()> {
+        let mut seg = bhyve_api::vm_memseg {
+            segid,
+            len: size,
+            name: [0u8; bhyve_api::VM_MAX_SEG_NAMELEN],
+        };
+        if let Some(name) = segname {
+            let name_raw = name.as_bytes();
+
+            assert!(name_raw.len() < bhyve_api::VM_MAX_SEG_NAMELEN);
+            (&mut seg.name[..]).write_all(name_raw)?;
+        }
+        unsafe { self.ioctl(bhyve_api::VM_ALLOC_MEMSEG, &mut seg) }
+    }
+
+    fn map_memseg(
+        &self,
+        segid: i32,
+        gpa: usize,
+        len: usize,
+        segoff: usize,
+        prot: Prot,
+    ) -> Result<()> {
+        assert!(segoff <= i64::MAX as usize);
+
+        let mut map = bhyve_api::vm_memmap {
+            gpa: gpa as u64,
+            segid,
+            segoff: segoff as i64,
+            len,
+            prot: i32::from(prot.bits()),
+            flags: 0,
+        };
+        unsafe { self.ioctl(bhyve_api::VM_MMAP_MEMSEG, &mut map) }
+    }
+
+    fn devmem_offset(&self, segid: i32) -> Result<usize> {
+        let mut devoff = bhyve_api::vm_devmem_offset { segid, offset: 0 };
+        unsafe {
+            self.ioctl(bhyve_api::VM_DEVMEM_GETOFFSET, &mut devoff)?;
+        }
+
+        assert!(devoff.offset >= 0);
+        Ok(devoff.offset as usize)
+    }
+
+    fn track_dirty_pages(
+        &self,
+        start_gpa: u64,
+        bitmap: &mut [u8],
+    ) -> Result<()> {
+        let mut tracker = bhyve_api::vmm_dirty_tracker {
+            vdt_start_gpa: start_gpa,
+            vdt_len: page_bitmap_len(bitmap),
+            vdt_pfns: bitmap.as_mut_ptr() as *mut c_void,
+        };
+        unsafe { self.ioctl(bhyve_api::VM_TRACK_DIRTY_PAGES, &mut tracker) }
+    }
+
+    fn set_dirty_pages(&self, start_gpa: u64, bitmap: &[u8]) -> Result<()> {
+        if !self.can_npt_operate() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "VmmHdl::set_dirty_pages requires bhyve v17 or later",
+            ));
+        }
+
+        let mut npt_op = bhyve_api::vm_npt_operation {
+            vno_gpa: start_gpa,
+            vno_len: page_bitmap_len(bitmap) as u64,
+            vno_operation: bhyve_api::VNO_OP_SET_DIRTY
+                | bhyve_api::VNO_FLAG_BITMAP_IN,
+            vno_bitmap: bitmap.as_ptr() as *mut _,
+        };
+        unsafe { self.ioctl(bhyve_api::VM_NPT_OPERATION, &mut npt_op) }
+    }
+
+    fn can_npt_operate(&self) -> bool {
+        self.api_version()
+            .map(|v| v >= ApiVersion::V17)
+            // If we couldn't read the Bhyve API version, assume the operation
+            // is unsupported.
+            .unwrap_or(false)
+    }
+
+    fn isa_assert_irq(
+        &self,
+        pic_irq: u8,
+        ioapic_irq: Option<u8>,
+    ) -> Result<()> {
+        let mut data = isa_irq(pic_irq, ioapic_irq);
+        unsafe { self.ioctl(bhyve_api::VM_ISA_ASSERT_IRQ, &mut data) }
+    }
+    fn isa_deassert_irq(
+        &self,
+        pic_irq: u8,
+        ioapic_irq: Option<u8>,
+    ) -> Result<()> {
+        let mut data = isa_irq(pic_irq, ioapic_irq);
+        unsafe { self.ioctl(bhyve_api::VM_ISA_DEASSERT_IRQ, &mut data) }
+    }
+    fn isa_pulse_irq(&self, pic_irq: u8, ioapic_irq: Option<u8>) -> Result<()> {
+        let mut data = isa_irq(pic_irq, ioapic_irq);
+        unsafe { self.ioctl(bhyve_api::VM_ISA_PULSE_IRQ, &mut data) }
+    }
+    fn isa_set_trigger_mode(&self, vec: u8, level_mode: bool) -> Result<()> {
+        let mut data = bhyve_api::vm_isa_irq_trigger {
+            atpic_irq: i32::from(vec),
+            trigger: if level_mode { 1 } else { 0 },
+        };
+        unsafe { self.ioctl(bhyve_api::VM_ISA_SET_IRQ_TRIGGER, &mut data) }
+    }
+
+    fn ioapic_assert_irq(&self, irq: u8) -> Result<()> {
+        let mut data = bhyve_api::vm_ioapic_irq { irq: i32::from(irq) };
+        unsafe { self.ioctl(bhyve_api::VM_IOAPIC_ASSERT_IRQ, &mut data) }
+    }
+    fn ioapic_deassert_irq(&self, irq: u8) -> Result<()> {
+        let mut data = bhyve_api::vm_ioapic_irq { irq: i32::from(irq) };
+        unsafe { self.ioctl(bhyve_api::VM_IOAPIC_DEASSERT_IRQ, &mut data) }
+    }
+    fn ioapic_pulse_irq(&self, irq: u8) -> Result<()> {
+        let mut data = bhyve_api::vm_ioapic_irq { irq: i32::from(irq) };
+        unsafe { self.ioctl(bhyve_api::VM_IOAPIC_PULSE_IRQ, &mut data) }
+    }
+    fn ioapic_pin_count(&self) -> Result<u8> {
+        let mut data = 0u32;
+        unsafe {
+            self.ioctl(bhyve_api::VM_IOAPIC_PINCOUNT, &mut data)?;
+        }
+        Ok(data as u8)
+    }
+
+    fn lapic_msi(&self, addr: u64, msg: u64) -> Result<()> {
+        let mut data = bhyve_api::vm_lapic_msi { msg, addr };
+        unsafe { self.ioctl(bhyve_api::VM_LAPIC_MSI, &mut data) }
+    }
+
+    fn read_time_data(&self) -> Result<VmTimeData> {
+        let raw = self
+            .data_op(bhyve_api::VDC_VMM_TIME, 1)
+            .read::<bhyve_api::vdi_time_info_v1>()?;
+        Ok(VmTimeData::from(raw))
+    }
+    fn write_time_data(&self, data: &VmTimeData) -> Result<()> {
+        let raw = bhyve_api::vdi_time_info_v1::from(*data);
+        self.data_op(bhyve_api::VDC_VMM_TIME, 1).write(&raw)?;
+        Ok(())
+    }
+
+    fn rtc_settime(&self, time: Duration) -> Result<()> {
+        self.inner.rtc_settime(time)
+    }
+    fn rtc_write(&self, offset: u8, value: u8) -> Result<()> {
+        let mut data =
+            bhyve_api::vm_rtc_data { offset: i32::from(offset), value };
+        unsafe { self.ioctl(bhyve_api::VM_RTC_WRITE, &mut data) }
+    }
+    fn rtc_read(&self, offset: u8) -> Result<u8> {
+        let mut data =
+            bhyve_api::vm_rtc_data { offset: i32::from(offset), value: 0 };
+        unsafe {
+            self.ioctl(bhyve_api::VM_RTC_READ, &mut data)?;
+        }
+        Ok(data.value)
+    }
+
+    fn pmtmr_locate(&self, port: u16) -> Result<()> {
+        unsafe { self.ioctl(bhyve_api::VM_PMTMR_LOCATE, port as *mut usize) }
+    }
+
+    fn device_state_read(&self, kind: DeviceKind) -> Result<DeviceState> {
+        Ok(match kind {
+            DeviceKind::Atpic => DeviceState::Atpic(
+                self.data_op(bhyve_api::VDC_ATPIC, 1).read()?,
+            ),
+            DeviceKind::Atpit => DeviceState::Atpit(
+                self.data_op(bhyve_api::VDC_ATPIT, 1).read()?,
+            ),
+            DeviceKind::Hpet => {
+                DeviceState::Hpet(self.data_op(bhyve_api::VDC_HPET, 1).read()?)
+            }
+            DeviceKind::Ioapic => DeviceState::Ioapic(
+                self.data_op(bhyve_api::VDC_IOAPIC, 1).read()?,
+            ),
+            DeviceKind::PmTimer => DeviceState::PmTimer(
+                self.data_op(bhyve_api::VDC_PM_TIMER, 1).read()?,
+            ),
+            DeviceKind::Rtc => {
+                DeviceState::Rtc(self.data_op(bhyve_api::VDC_RTC, 2).read()?)
+            }
+        })
+    }
+    fn device_state_write(&self, state: &DeviceState) -> Result<()> {
+        match state {
+            DeviceState::Atpic(data) => {
+                self.data_op(bhyve_api::VDC_ATPIC, 1).write(data)?
+            }
+            DeviceState::Atpit(data) => {
+                self.data_op(bhyve_api::VDC_ATPIT, 1).write(data)?
+            }
+            DeviceState::Hpet(data) => {
+                self.data_op(bhyve_api::VDC_HPET, 1).write(data)?
+            }
+            DeviceState::Ioapic(data) => {
+                self.data_op(bhyve_api::VDC_IOAPIC, 1).write(data)?
+            }
+            DeviceState::PmTimer(data) => {
+                self.data_op(bhyve_api::VDC_PM_TIMER, 1).write(data)?
+            }
+            DeviceState::Rtc(data) => {
+                self.data_op(bhyve_api::VDC_RTC, 2).write(data)?
+            }
+        }
+        Ok(())
+    }
+
+    fn arch_data_read(&self) -> Result<Vec<vdi_field_entry_v1>> {
+        Ok(self.data_op(bhyve_api::VDC_VMM_ARCH, 1).read_all()?)
+    }
+    fn arch_data_write(&self, entries: &[vdi_field_entry_v1]) -> Result<()> {
+        self.data_op(bhyve_api::VDC_VMM_ARCH, 1).write_many(entries)?;
+        Ok(())
+    }
+
+    fn suspend(
+        &self,
+        how: bhyve_api::vm_suspend_how,
+        source: Option<i32>,
+    ) -> Result<()> {
+        let mut data = bhyve_api::vm_suspend {
+            how: how as u32,
+            source: source.unwrap_or(-1),
+        };
+        unsafe { self.ioctl(bhyve_api::VM_SUSPEND, &mut data) }
+    }
+
+    fn reinit(&self, force_suspend: bool) -> Result<()> {
+        let mut data = bhyve_api::vm_reinit { flags: 0 };
+        if force_suspend {
+            data.flags |= bhyve_api::VM_REINIT_F_FORCE_SUSPEND;
+        }
+        unsafe { self.ioctl(bhyve_api::VM_REINIT, &mut data) }
+    }
+
+    fn pause(&self) -> Result<()> {
+        self.ioctl_usize(bhyve_api::VM_PAUSE, 0)
+    }
+
+    fn resume(&self) -> Result<()> {
+        self.ioctl_usize(bhyve_api::VM_RESUME, 0)
+    }
+
+    fn destroy(&self) -> Result<()> {
+        if self.destroyed.swap(true, Ordering::SeqCst) {
+            return Err(Error::new(ErrorKind::NotFound, "already destroyed"));
+        }
+
+        // Attempt destruction via the handle (rather than going through vmmctl)
+        // This is done through the [ioctl_usize] helper rather than
+        // [Self::ioctl_usize], since the latter rejects attempted operations
+        // after `destroyed` is set.
+        if let Ok(_) = self.inner.ioctl_usize(bhyve_api::VM_DESTROY_SELF, 0) {
+            return Ok(());
+        }
+
+        // If that failed (which may occur on older platforms without
+        // self-destruction), then fall back to performing the destroy through
+        // the vmmctl device.
+        let ctl = bhyve_api::VmmCtlFd::open()?;
+        ctl.vm_destroy(self.name.as_bytes()).or_else(|e| match e.kind() {
+            ErrorKind::NotFound => Ok(()),
+            _ => Err(e),
+        })
+    }
+
+    fn set_autodestruct(&self, enable_autodestruct: bool) -> Result<()> {
+        self.ioctl_usize(
+            bhyve_api::VM_SET_AUTODESTRUCT,
+            usize::from(enable_autodestruct),
+        )
+    }
+
+    fn vcpu_set_default_capabs(&self, vcpuid: i32) -> Result<()> {
+        // Enable exit-on-HLT so the host CPU does not spin in VM context when
+        // the guest enters a HLT instruction.
+        let mut cap = bhyve_api::vm_capability {
+            cpuid: vcpuid,
+            captype: bhyve_api::vm_cap_type::VM_CAP_HALT_EXIT as i32,
+            capval: 1,
+            allcpus: 0,
+        };
+        unsafe { self.ioctl(bhyve_api::VM_SET_CAPABILITY, &mut cap) }
+    }
+
+    fn vcpu_get_reg(
+        &self,
+        vcpuid: i32,
+        reg: bhyve_api::vm_reg_name,
+    ) -> Result<u64> {
+        let mut regcmd = bhyve_api::vm_register {
+            cpuid: vcpuid,
+            regnum: reg as i32,
+            regval: 0,
+        };
+
+        unsafe {
+            self.ioctl(bhyve_api::VM_GET_REGISTER, &mut regcmd)?;
+        }
+        Ok(regcmd.regval)
+    }
+    fn vcpu_set_reg(
+        &self,
+        vcpuid: i32,
+        reg: bhyve_api::vm_reg_name,
+        val: u64,
+    ) -> Result<()> {
+        let mut regcmd = bhyve_api::vm_register {
+            cpuid: vcpuid,
+            regnum: reg as i32,
+            regval: val,
+        };
+
+        unsafe {
+            self.ioctl(bhyve_api::VM_SET_REGISTER, &mut regcmd)?;
+        }
+        Ok(())
+    }
+
+    fn vcpu_get_segreg(
+        &self,
+        vcpuid: i32,
+        reg: bhyve_api::vm_reg_name,
+    ) -> Result<bhyve_api::seg_desc> {
+        let mut req = bhyve_api::vm_seg_desc {
+            cpuid: vcpuid,
+            regnum: reg as i32,
+            desc: bhyve_api::seg_desc::default(),
+        };
+
+        unsafe {
+            self.ioctl(bhyve_api::VM_GET_SEGMENT_DESCRIPTOR, &mut req)?;
+        }
+        Ok(req.desc)
+    }
+    fn vcpu_set_segreg(
+        &self,
+        vcpuid: i32,
+        reg: bhyve_api::vm_reg_name,
+        seg: &bhyve_api::seg_desc,
+    ) -> Result<()> {
+        let mut req = bhyve_api::vm_seg_desc {
+            cpuid: vcpuid,
+            regnum: reg as i32,
+            desc: *seg,
+        };
+
+        unsafe {
+            self.ioctl(bhyve_api::VM_SET_SEGMENT_DESCRIPTOR, &mut req)?;
+        }
+        Ok(())
+    }
+
+    fn vcpu_set_cpuid(&self, vcpuid: i32, values: cpuid::Set) -> Result<()> {
+        let mut config = bhyve_api::vm_vcpu_cpuid_config {
+            vvcc_vcpuid: vcpuid,
+            ..Default::default()
+        };
+        if values.is_empty() {
+            config.vvcc_flags = bhyve_api::VCC_FLAG_LEGACY_HANDLING;
+            unsafe {
+                self.ioctl(bhyve_api::VM_SET_CPUID, &mut config)?;
+            }
+        } else {
+            if values.vendor.is_intel() {
+                config.vvcc_flags |= bhyve_api::VCC_FLAG_INTEL_FALLBACK;
+            }
+            let mut entries: Vec<bhyve_api::vcpu_cpuid_entry> = values.into();
+            entries.sort_by(bhyve_api::vcpu_cpuid_entry::eval_sort);
+            config.vvcc_nent = entries.len() as u32;
+            config.vvcc_entries = entries.as_mut_ptr() as *mut libc::c_void;
+            unsafe {
+                self.ioctl(bhyve_api::VM_SET_CPUID, &mut config)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn vcpu_get_cpuid(&self, vcpuid: i32) -> Result<cpuid::Set> {
+        let mut config = bhyve_api::vm_vcpu_cpuid_config {
+            vvcc_vcpuid: vcpuid,
+            vvcc_nent: 0,
+            ..Default::default()
+        };
+        // Query the number of entries configured in-kernel
+        //
+        // We expect an error (E2BIG) when attempting a VM_GET_CPUID with a
+        // vvcc_nent which falls below the number of entries stored in the
+        // kernel.  When that occurs, vvcc_nent will be updated with that
+        // existing count so we may allocate an array to receive it on a
+        // subsquent ioctl.
+        let count =
+            match unsafe { self.ioctl(bhyve_api::VM_GET_CPUID, &mut config) } {
+                Err(_) if config.vvcc_nent != 0 => Ok(config.vvcc_nent),
+                Ok(_) => {
+                    assert_eq!(config.vvcc_nent, 0);
+                    Ok(0)
+                }
+                Err(e) => Err(e),
+            }?;
+
+        let mut entries = Vec::with_capacity(count as usize);
+        entries.fill(bhyve_api::vcpu_cpuid_entry::default());
+        config.vvcc_entries = entries.as_mut_ptr() as *mut libc::c_void;
+        unsafe {
+            self.ioctl(bhyve_api::VM_GET_CPUID, &mut config)?;
+        }
+
+        if config.vvcc_flags & bhyve_api::VCC_FLAG_LEGACY_HANDLING != 0 {
+            // Since the legacy handling takes care of vendor-specific handling
+            // (by nature of doing the cpuid queries against the host CPU) it
+            // ignores the INTEL_FALLBACK flag.  We must determine the vendor
+            // kind by querying it.
+            let vendor = cpuid::VendorKind::try_from(cpuid::host_query(
+                cpuid::Ident(0, None),
+            ))
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+
+            return Ok(cpuid::Set::new(vendor));
+        }
+        let intel_fallback =
+            config.vvcc_flags & bhyve_api::VCC_FLAG_INTEL_FALLBACK != 0;
+        let mut set = cpuid::Set::new(match intel_fallback {
+            true => cpuid::VendorKind::Intel,
+            false => cpuid::VendorKind::Amd,
+        });
+
+        for entry in entries {
+            let (ident, value) = cpuid::from_raw(entry);
+            let conflict = set.insert(ident, value);
+            if conflict.is_some() {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "conflicting entry at eax:{:x} ecx:{:x?})",
+                        ident.0, ident.1
+                    ),
+                ));
+            }
+        }
+        Ok(set)
+    }
+
+    fn vcpu_get_arch(&self, vcpuid: i32) -> Result<Vec<vdi_field_entry_v1>> {
+        Ok(self
+            .data_op(bhyve_api::VDC_VMM_ARCH, 1)
+            .for_vcpu(vcpuid)
+            .read_all()?)
+    }
+    fn vcpu_set_arch(
+        &self,
+        vcpuid: i32,
+        entries: &[vdi_field_entry_v1],
+    ) -> Result<()> {
+        self.data_op(bhyve_api::VDC_VMM_ARCH, 1)
+            .for_vcpu(vcpuid)
+            .write_many(entries)?;
+        Ok(())
+    }
+
+    fn vcpu_get_msrs(&self, vcpuid: i32) -> Result<Vec<vdi_field_entry_v1>> {
+        Ok(self.data_op(bhyve_api::VDC_MSR, 1).for_vcpu(vcpuid).read_all()?)
+    }
+    fn vcpu_set_msrs(
+        &self,
+        vcpuid: i32,
+        msrs: &[vdi_field_entry_v1],
+    ) -> Result<()> {
+        self.data_op(bhyve_api::VDC_MSR, 1)
+            .for_vcpu(vcpuid)
+            .write_many(msrs)?;
+        Ok(())
+    }
+
+    fn vcpu_get_lapic(&self, vcpuid: i32) -> Result<bhyve_api::vdi_lapic_v1> {
+        Ok(self.data_op(bhyve_api::VDC_LAPIC, 1).for_vcpu(vcpuid).read()?)
+    }
+    fn vcpu_set_lapic(
+        &self,
+        vcpuid: i32,
+        lapic: &bhyve_api::vdi_lapic_v1,
+    ) -> Result<()> {
+        self.data_op(bhyve_api::VDC_LAPIC, 1).for_vcpu(vcpuid).write(lapic)?;
+        Ok(())
+    }
+
+    fn vcpu_get_fpu(&self, vcpuid: i32) -> Result<Vec<u8>> {
+        let mut fpu_area_desc = bhyve_api::vm_fpu_desc::default();
+        unsafe {
+            self.ioctl(bhyve_api::VM_DESC_FPU_AREA, &mut fpu_area_desc)?;
+        }
+
+        let mut fpu = vec![0u8; fpu_area_desc.vfd_req_size as usize];
+        let mut fpu_req = bhyve_api::vm_fpu_state {
+            vcpuid,
+            buf: fpu.as_mut_ptr() as *mut c_void,
+            len: fpu_area_desc.vfd_req_size,
+        };
+        unsafe {
+            self.ioctl(bhyve_api::VM_GET_FPU, &mut fpu_req)?;
+        }
+        Ok(fpu)
+    }
+    fn vcpu_set_fpu(&self, vcpuid: i32, fpu: &[u8]) -> Result<()> {
+        let mut fpu_req = bhyve_api::vm_fpu_state {
+            vcpuid,
+            // The kernel only reads from the buffer when setting FPU state
+            buf: fpu.as_ptr() as *mut c_void,
+            len: fpu.len().try_into().map_err(|_| {
+                Error::new(ErrorKind::InvalidInput, "fpu blob size too large")
+            })?,
+        };
+        unsafe { self.ioctl(bhyve_api::VM_SET_FPU, &mut fpu_req) }
+    }
+
+    fn vcpu_reset(&self, vcpuid: i32) -> Result<()> {
+        let mut vvr = bhyve_api::vm_vcpu_reset {
+            vcpuid,
+            kind: bhyve_api::vcpu_reset_kind::VRK_RESET as u32,
+        };
+
+        unsafe {
+            self.ioctl(bhyve_api::VM_RESET_CPU, &mut vvr)?;
+        }
+
+        Ok(())
+    }
+
+    fn vcpu_activate(&self, vcpuid: i32) -> Result<()> {
+        let mut cpu = vcpuid;
+
+        unsafe {
+            self.ioctl(bhyve_api::VM_ACTIVATE_CPU, &mut cpu)?;
+        }
+        Ok(())
+    }
+
+    fn vcpu_set_run_state(
+        &self,
+        vcpuid: i32,
+        state: u32,
+        sipi_vector: Option<u8>,
+    ) -> Result<()> {
+        let mut state = bhyve_api::vm_run_state {
+            vcpuid,
+            state,
+            sipi_vector: sipi_vector.unwrap_or(0),
+            ..Default::default()
+        };
+        unsafe {
+            self.ioctl(bhyve_api::VM_SET_RUN_STATE, &mut state)?;
+        }
+        Ok(())
+    }
+    fn vcpu_get_run_state(
+        &self,
+        vcpuid: i32,
+    ) -> Result<bhyve_api::vm_run_state> {
+        let mut state =
+            bhyve_api::vm_run_state { vcpuid, ..Default::default() };
+        unsafe {
+            self.ioctl(bhyve_api::VM_GET_RUN_STATE, &mut state)?;
+        }
+        Ok(state)
+    }
+
+    fn vcpu_run(
+        &self,
+        vcpuid: i32,
+        entry: &VmEntry,
+        exit_when_consistent: bool,
+    ) -> Result<VmExit> {
+        let mut exit: bhyve_api::vm_exit = Default::default();
+        let mut entry = entry.to_raw(vcpuid, &mut exit);
+
+        let api_version = self.api_version()?;
+
+        if exit_when_consistent {
+            if api_version >= ApiVersion::V15 {
+                entry.cmd |=
+                    bhyve_api::vm_entry_cmds::VEC_FLAG_EXIT_CONSISTENT as u32;
+            } else {
+                // On older platforms without EXIT_CONSISTENT, we may spend more
+                // time inside VM_RUN than desired, but there is little else
+                // that can be done.
+            }
+        }
+        unsafe { self.ioctl(bhyve_api::VM_RUN, &mut entry)? };
+
+        Ok(VmExit::parse(&exit, api_version))
+    }
+
+    fn vcpu_barrier(&self, vcpuid: i32) -> Result<()> {
+        if self.api_version()? >= ApiVersion::V16 {
+            // Use the official barrier operation, if available
+            self.ioctl_usize(bhyve_api::VM_VCPU_BARRIER, vcpuid as usize)?;
+        } else {
+            // Prior to first-class support for a barrier, just force the vCPU
+            // out of guest context by reading %rax.  If the vCPU thread happens
+            // to be on its way into VM_RUN, but not already there, this old
+            // method can fail to incur a proper exit.
+            let _ = self.vcpu_get_reg(
+                vcpuid,
+                bhyve_api::vm_reg_name::VM_REG_GUEST_RAX,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn vcpu_inject_nmi(&self, vcpuid: i32) -> Result<()> {
+        let mut vm_nmi = bhyve_api::vm_nmi { cpuid: vcpuid };
+        unsafe { self.ioctl(bhyve_api::VM_INJECT_NMI, &mut vm_nmi) }
+    }
+}
+
+fn isa_irq(pic_irq: u8, ioapic_irq: Option<u8>) -> bhyve_api::vm_isa_irq {
+    bhyve_api::vm_isa_irq {
+        atpic_irq: i32::from(pic_irq),
+        ioapic_irq: ioapic_irq.map(i32::from).unwrap_or(-1),
+    }
+}
+
+fn page_bitmap_len(bitmap: &[u8]) -> usize {
+    bitmap.len() * 8 * PAGE_SIZE
+}
@@ -0,0 +1,346 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The boundary between propolis and the hypervisor executing its guests.
+//!
+//! Everything which propolis asks of the underlying VMM (setting up guest
+//! memory, running vCPUs and manipulating their state, injecting interrupts,
+//! tracking dirty pages and managing time) is expressed through the
+//! [Hypervisor] trait.  The in-kernel bhyve VMM ([BhyveVmm](super::BhyveVmm))
+//! is the implementation used for real instances, while `MockVmm` (available
+//! in tests, or with the `testing` feature) runs entirely in-process, with vCPU
+//! exits scripted by the caller.
+//!
+//! Register names, segment descriptors, run states and the state of emulated
+//! devices are described using the bhyve definitions, which serve as the
+//! common vocabulary for all implementations.
+
+use std::any::Any;
+use std::io::Result;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use crate::cpuid;
+use crate::exits::{VmEntry, VmExit};
+use crate::vmm::mem::Prot;
+use crate::vmm::time::VmTimeData;
+
+use bhyve_api::vdi_field_entry_v1;
+
+/// Data describing the state of one kind of emulated device, as carried by a
+/// [DeviceState] of kind `KIND`.
+pub trait DeviceData: Into<DeviceState> + TryFrom<DeviceState> {
+    const KIND: DeviceKind;
+}
+
+macro_rules! device_states {
+    ($($kind:ident($data:ty)),* $(,)?) => {
+        /// Identifies one of the devices emulated within the hypervisor.
+        #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+        pub enum DeviceKind {
+            $($kind,)*
+        }
+        impl DeviceKind {
+            /// The state of the device as it is at VM creation.
+            pub fn initial_state(self) -> DeviceState {
+                match self {
+                    $(Self::$kind => DeviceState::$kind(Default::default()),)*
+                }
+            }
+        }
+
+        /// The state of a device emulated within the hypervisor.
+        #[derive(Copy, Clone)]
+        pub enum DeviceState {
+            $($kind($data),)*
+        }
+        impl DeviceState {
+            pub fn kind(&self) -> DeviceKind {
+                match self {
+                    $(Self::$kind(_) => DeviceKind::$kind,)*
+                }
+            }
+        }
+
+        $(
+            impl From<$data> for DeviceState {
+                fn from(data: $data) -> Self {
+                    Self::$kind(data)
+                }
+            }
+            impl TryFrom<DeviceState> for $data {
+                type Error = DeviceState;
+
+                fn try_from(
+                    state: DeviceState,
+                ) -> std::result::Result<Self, DeviceState> {
+                    match state {
+                        DeviceState::$kind(data) => Ok(data),
+                        other => Err(other),
+                    }
+                }
+            }
+            impl DeviceData for $data {
+                const KIND: DeviceKind = DeviceKind::$kind;
+            }
+        )*
+    };
+}
+device_states! {
+    Atpic(bhyve_api::vdi_atpic_v1),
+    Atpit(bhyve_api::vdi_atpit_v1),
+    Hpet(bhyve_api::vdi_hpet_v1),
+    Ioapic(bhyve_api::vdi_ioapic_v1),
+    PmTimer(bhyve_api::vdi_pm_timer_v1),
+    Rtc(bhyve_api::vdi_rtc_v2),
+}
+
+/// Operations on a virtual machine, as carried out by a hypervisor.
+///
+/// Operations which apply to a single vCPU take its ID as their first argument.
+pub trait Hypervisor: Send + Sync + 'static {
+    /// Allows callers to reach the concrete implementation, for operations
+    /// particular to it.
+    fn as_any(&self) -> &dyn Any;
+
+    /// The version of the (bhyve) API which this hypervisor provides.
+    fn api_version(&self) -> Result<u32>;
+
+    // Guest memory
+
+    /// The file descriptor through which guest memory is mapped into the
+    /// process: guest-physical addresses at their own offset, and memory
+    /// segments at the offset reported by [Hypervisor::devmem_offset].
+    fn fd(&self) -> RawFd;
+
+    /// Allocates memory segment `segid` of `size` bytes.  A segment with a
+    /// name is treated as ROM, rather than system memory.
+    fn create_memseg(
+        &self,
+        segid: i32,
+        size: usize,
+        segname: Option<&str>,
+    ) -> Result<()>;
+
+    /// Maps `len` bytes of segment `segid`, starting from `segoff`, into the
+    /// guest-physical address space at `gpa`, with protections `prot`.
+    fn map_memseg(
+        &self,
+        segid: i32,
+        gpa: usize,
+        len: usize,
+        segoff: usize,
+        prot: Prot,
+    ) -> Result<()>;
+
+    /// The offset within [Hypervisor::fd] at which segment `segid` may be
+    /// mapped.
+    fn devmem_offset(&self, segid: i32) -> Result<usize>;
+
+    // Dirty page tracking
+
+    /// Fills `bitmap` (one bit per page from `start_gpa`) with the dirty
+    /// state of those pages, clearing it as it goes.
+    fn track_dirty_pages(
+        &self,
+        start_gpa: u64,
+        bitmap: &mut [u8],
+    ) -> Result<()>;
+
+    /// Marks the pages set in `bitmap` (one bit per page from `start_gpa`) as
+    /// dirty.
+    fn set_dirty_pages(&self, start_gpa: u64, bitmap: &[u8]) -> Result<()>;
+
+    /// Whether [Hypervisor::set_dirty_pages] is supported.
+    fn can_npt_operate(&self) -> bool;
+
+    // Interrupts
+
+    /// Asserts `pic_irq` on the legacy 8259 PIC and, if supplied, `ioapic_irq`
+    /// on the IOAPIC.
+    fn isa_assert_irq(&self, pic_irq: u8, ioapic_irq: Option<u8>)
+        -> Result<()>;
+    /// Deasserts ISA interrupts, as in [Hypervisor::isa_assert_irq].
+    fn isa_deassert_irq(
+        &self,
+        pic_irq: u8,
+        ioapic_irq: Option<u8>,
+    ) -> Result<()>;
+    /// Pulses ISA interrupts, as in [Hypervisor::isa_assert_irq].
+    fn isa_pulse_irq(&self, pic_irq: u8, ioapic_irq: Option<u8>) -> Result<()>;
+    fn isa_set_trigger_mode(&self, vec: u8, level_mode: bool) -> Result<()>;
+
+    fn ioapic_assert_irq(&self, irq: u8) -> Result<()>;
+    fn ioapic_deassert_irq(&self, irq: u8) -> Result<()>;
+    fn ioapic_pulse_irq(&self, irq: u8) -> Result<()>;
+    fn ioapic_pin_count(&self) -> Result<u8>;
+
+    /// Delivers a message-signalled interrupt to the local APIC(s).
+    fn lapic_msi(&self, addr: u64, msg: u64) -> Result<()>;
+
+    // Time, and the devices which keep it
+
+    /// Reads the guest's time data (TSC frequency and offset, boot time),
+    /// along with a snapshot of the host clocks.
+    fn read_time_data(&self) -> Result<VmTimeData>;
+    /// Overwrites the guest's time data.
+    fn write_time_data(&self, data: &VmTimeData) -> Result<()>;
+
+    /// Sets the time of the virtual RTC.
+    fn rtc_settime(&self, time: Duration) -> Result<()>;
+    fn rtc_write(&self, offset: u8, value: u8) -> Result<()>;
+    fn rtc_read(&self, offset: u8) -> Result<u8>;
+
+    /// Places the ACPI PM timer at I/O port `port`.
+    fn pmtmr_locate(&self, port: u16) -> Result<()>;
+
+    // State kept by the hypervisor, for save and restore
+
+    /// Reads the state of the emulated device `kind`.
+    fn device_state_read(&self, kind: DeviceKind) -> Result<DeviceState>;
+    /// Loads the state of an emulated device, as read by
+    /// [Hypervisor::device_state_read].
+    fn device_state_write(&self, state: &DeviceState) -> Result<()>;
+
+    /// Reads the VM-wide architectural state (`VAI_*` entries, such as the
+    /// boot time) kept by the hypervisor.
+    fn arch_data_read(&self) -> Result<Vec<vdi_field_entry_v1>>;
+    /// Updates the VM-wide architectural state entries present in `entries`,
+    /// leaving the rest untouched.
+    fn arch_data_write(&self, entries: &[vdi_field_entry_v1]) -> Result<()>;
+
+    // VM-wide lifecycle
+
+    /// Suspends the VM, causing its vCPUs to exit with a
+    /// [Suspended](crate::exits::VmExitKind::Suspended) exit.
+    fn suspend(
+        &self,
+        how: bhyve_api::vm_suspend_how,
+        source: Option<i32>,
+    ) -> Result<()>;
+
+    /// Resets the VM to its initial state, as on a reboot.
+    fn reinit(&self, force_suspend: bool) -> Result<()>;
+
+    /// Pauses emulation logic within the hypervisor (timers, etc.) so that a
+    /// consistent snapshot may be taken or loaded.
+    fn pause(&self) -> Result<()>;
+    /// Resumes from a prior [Hypervisor::pause].
+    fn resume(&self) -> Result<()>;
+
+    /// Destroys the VM.  All further operations upon it will fail.
+    fn destroy(&self) -> Result<()>;
+
+    /// Sets whether the VM should be destroyed when the last handle to it is
+    /// closed.
+    fn set_autodestruct(&self, enable_autodestruct: bool) -> Result<()>;
+
+    // vCPUs
+
+    /// Applies the capabilities propolis expects of all vCPUs, such as
+    /// exiting on HLT.
+    fn vcpu_set_default_capabs(&self, vcpuid: i32) -> Result<()>;
+
+    fn vcpu_get_reg(
+        &self,
+        vcpuid: i32,
+        reg: bhyve_api::vm_reg_name,
+    ) -> Result<u64>;
+    fn vcpu_set_reg(
+        &self,
+        vcpuid: i32,
+        reg: bhyve_api::vm_reg_name,
+        val: u64,
+    ) -> Result<()>;
+
+    fn vcpu_get_segreg(
+        &self,
+        vcpuid: i32,
+        reg: bhyve_api::vm_reg_name,
+    ) -> Result<bhyve_api::seg_desc>;
+    fn vcpu_set_segreg(
+        &self,
+        vcpuid: i32,
+        reg: bhyve_api::vm_reg_name,
+        seg: &bhyve_api::seg_desc,
+    ) -> Result<()>;
+
+    /// Configures `cpuid` emulation for the vCPU.  An empty set of `values`
+    /// selects legacy emulation, based on the host CPU.
+    fn vcpu_set_cpuid(&self, vcpuid: i32, values: cpuid::Set) -> Result<()>;
+    /// Queries the `cpuid` emulation configured for the vCPU.
+    fn vcpu_get_cpuid(&self, vcpuid: i32) -> Result<cpuid::Set>;
+
+    /// Reads the architectural state of the vCPU which is not held in its
+    /// registers, such as pending interrupts and exceptions, as `VAI_*`
+    /// entries.
+    fn vcpu_get_arch(&self, vcpuid: i32) -> Result<Vec<vdi_field_entry_v1>>;
+    /// Updates the vCPU's architectural state entries present in `entries`,
+    /// leaving the rest untouched.
+    fn vcpu_set_arch(
+        &self,
+        vcpuid: i32,
+        entries: &[vdi_field_entry_v1],
+    ) -> Result<()>;
+
+    /// Reads the MSRs of the vCPU kept by the hypervisor, as entries of MSR
+    /// number and value.
+    fn vcpu_get_msrs(&self, vcpuid: i32) -> Result<Vec<vdi_field_entry_v1>>;
+    /// Updates the vCPU's MSRs present in `msrs`, leaving the rest untouched.
+    fn vcpu_set_msrs(
+        &self,
+        vcpuid: i32,
+        msrs: &[vdi_field_entry_v1],
+    ) -> Result<()>;
+
+    /// Reads the state of the vCPU's local APIC, including its timer.
+    fn vcpu_get_lapic(&self, vcpuid: i32) -> Result<bhyve_api::vdi_lapic_v1>;
+    fn vcpu_set_lapic(
+        &self,
+        vcpuid: i32,
+        lapic: &bhyve_api::vdi_lapic_v1,
+    ) -> Result<()>;
+
+    /// Reads the FPU state of the vCPU, as an opaque blob in the
+    /// hypervisor's (XSAVE-derived) format.
+    fn vcpu_get_fpu(&self, vcpuid: i32) -> Result<Vec<u8>>;
+    /// Loads FPU state, as read by [Hypervisor::vcpu_get_fpu].
+    fn vcpu_set_fpu(&self, vcpuid: i32, fpu: &[u8]) -> Result<()>;
+
+    /// Resets all state (including registers and pending interrupts) of the
+    /// vCPU.
+    fn vcpu_reset(&self, vcpuid: i32) -> Result<()>;
+    /// Activates the vCPU, failing if it has already been activated.
+    fn vcpu_activate(&self, vcpuid: i32) -> Result<()>;
+
+    fn vcpu_set_run_state(
+        &self,
+        vcpuid: i32,
+        state: u32,
+        sipi_vector: Option<u8>,
+    ) -> Result<()>;
+    fn vcpu_get_run_state(
+        &self,
+        vcpuid: i32,
+    ) -> Result<bhyve_api::vm_run_state>;
+
+    /// Runs the vCPU, having first completed any emulation described by
+    /// `entry`, until it exits.
+    ///
+    /// When `exit_when_consistent` is set, the vCPU should exit with a
+    /// [Bogus](crate::exits::VmExitKind::Bogus) exit as soon as it reaches a
+    /// consistent state.
+    fn vcpu_run(
+        &self,
+        vcpuid: i32,
+        entry: &VmEntry,
+        exit_when_consistent: bool,
+    ) -> Result<VmExit>;
+
+    /// Forces the vCPU out of guest context, should it be running.
+    fn vcpu_barrier(&self, vcpuid: i32) -> Result<()>;
+
+    /// Injects a Non Maskable Interrupt into the vCPU.
+    fn vcpu_inject_nmi(&self, vcpuid: i32) -> Result<()>;
+}
@@ -105,16 +105,26 @@ impl Drop for Machine {
     }
 }
 
-#[cfg(test)]
+#[cfg(any(test, feature = "testing"))]
 impl Machine {
+    #[cfg(test)]
     pub(crate) fn new_test() -> Result<Self> {
-        // Create a test handle with 2M tempfile to use as our VM "memory"
-        let hdl = Arc::new(VmmHdl::new_test(2 * 1024 * 1024)?);
+        // 1M of "ROM" followed by 1M of "RAM", backed by a 2M tempfile
+        Self::new_test_sized(1024 * 1024, 1024 * 1024)
+    }
+
+    /// Create a machine backed by a tempfile rather than a real VMM, with
+    /// `rom_size` bytes of ROM at address 0, followed immediately by
+    /// `ram_size` bytes of RAM.  Both sizes must be page-aligned.
+    pub(crate) fn new_test_sized(
+        rom_size: usize,
+        ram_size: usize,
+    ) -> Result<Self> {
+        let hdl = Arc::new(VmmHdl::new_test(rom_size + ram_size)?);
 
         let mut map = PhysMap::new(MAX_PHYSMEM, hdl.clone());
-        // TODO: meaningfully populate these
-        map.add_test_rom("test-rom".to_string(), 0, 1024 * 1024)?;
-        map.add_test_mem("test-ram".to_string(), 1024 * 1024, 1024 * 1024)?;
+        map.add_test_rom("test-rom".to_string(), 0, rom_size)?;
+        map.add_test_mem("test-ram".to_string(), rom_size, ram_size)?;
 
         let bus_mmio = Arc::new(MmioBus::new(MAX_PHYSMEM));
         let bus_pio = Arc::new(PioBus::new());
@@ -178,9 +188,15 @@ impl Builder {
     /// - `name`: The name for the new instance.
     /// - `force`: If true, deletes the VM if it already exists.
     pub fn new(name: &str, opts: CreateOpts) -> Result<Self> {
-        let hdl = Arc::new(create_vm(name, opts)?);
+        Ok(Self::with_hdl(create_vm(name, opts)?))
+    }
+
+    /// Constructs a builder which produces a VM atop an existing handle, such
+    /// as one to a `MockVmm`.
+    pub fn with_hdl(hdl: VmmHdl) -> Self {
+        let hdl = Arc::new(hdl);
         let physmap = Some(PhysMap::new(MAX_PHYSMEM, hdl.clone()));
-        Ok(Self { inner_hdl: Some(hdl), max_cpu: 1, physmap })
+        Self { inner_hdl: Some(hdl), max_cpu: 1, physmap }
     }
 
     /// Creates and maps a memory segment in the guest's address space,
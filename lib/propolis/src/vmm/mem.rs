@@ -203,7 +203,10 @@ impl PhysMap {
             VmmHdl::new_test(size).expect("create tempfile backed test hdl");
         Self::new(size, Arc::new(hdl))
     }
+}
 
+#[cfg(any(test, feature = "testing"))]
+impl PhysMap {
     /// Create "memory" region on an instance backed with a fake VmmHdl
     pub(crate) fn add_test_mem(
         &mut self,
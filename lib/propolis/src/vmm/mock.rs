@@ -0,0 +1,907 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An in-process [Hypervisor], for exercising propolis without bhyve.
+//!
+//! Guest memory is backed by a tempfile, in which guest-physical addresses
+//! (and memory segments, which are placed at the address they are mapped to)
+//! sit at their own offset.  Rather than executing guest code, vCPUs exit with
+//! whatever [VmExit]s have been queued for them by the test, recording the
+//! [VmEntry] with which each run was started.
+
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::common::PAGE_SIZE;
+use crate::cpuid;
+use crate::exits::{Suspend, SuspendDetail, VmEntry, VmExit, VmExitKind};
+use crate::vcpu::MAXCPU;
+use crate::vmm::hypervisor::{DeviceKind, DeviceState, Hypervisor};
+use crate::vmm::mem::Prot;
+use crate::vmm::time::{VmTimeData, NS_PER_SEC};
+
+use bhyve_api::{vdi_field_entry_v1, vm_suspend_how};
+
+/// Number of IOAPIC pins reported, matching bhyve
+const IOAPIC_PINS: u8 = 32;
+/// Guest TSC frequency reported until time data is written
+const DEFAULT_TSC_FREQ: u64 = 1_000_000_000;
+/// Size of the FPU state blob: a legacy XSAVE area and its header
+const FPU_AREA_SIZE: usize = 576;
+
+/// An interrupt pin state change requested of the mock's interrupt
+/// controllers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PinEvent {
+    /// Assert of (PIC IRQ, optional IOAPIC IRQ)
+    IsaAssert(u8, Option<u8>),
+    IsaDeassert(u8, Option<u8>),
+    IsaPulse(u8, Option<u8>),
+    IoapicAssert(u8),
+    IoapicDeassert(u8),
+    IoapicPulse(u8),
+}
+
+#[derive(Default)]
+struct MockVcpu {
+    active: bool,
+    running: bool,
+    barrier: bool,
+    nmi_pending: bool,
+    run_state: u32,
+    sipi_vector: u8,
+    regs: BTreeMap<i32, u64>,
+    segregs: BTreeMap<i32, bhyve_api::seg_desc>,
+    cpuid: Option<cpuid::Set>,
+    arch: BTreeMap<u32, u64>,
+    msrs: BTreeMap<u32, u64>,
+    lapic: bhyve_api::vdi_lapic_v1,
+    /// FPU state, if any has been loaded since reset
+    fpu: Option<Vec<u8>>,
+
+    /// Exits to be emitted by upcoming runs of the vCPU
+    exits: VecDeque<VmExit>,
+    /// Entries passed to runs of the vCPU
+    entries: Vec<VmEntry>,
+}
+impl MockVcpu {
+    /// Clear the architectural state of the vCPU, leaving the scripted
+    /// exits and recorded entries untouched.
+    fn reset(&mut self) {
+        self.nmi_pending = false;
+        self.run_state = 0;
+        self.sipi_vector = 0;
+        self.regs.clear();
+        self.segregs.clear();
+        self.arch.clear();
+        self.msrs.clear();
+        self.lapic = Default::default();
+        self.fpu = None;
+    }
+}
+
+/// Field entries for the values in `fields`, in identifier order.
+fn field_entries(fields: &BTreeMap<u32, u64>) -> Vec<vdi_field_entry_v1> {
+    fields
+        .iter()
+        .map(|(ident, value)| vdi_field_entry_v1::new(*ident, *value))
+        .collect()
+}
+
+/// Sets the values in `fields` given by `entries`, as bhyve does on a write.
+fn set_fields(fields: &mut BTreeMap<u32, u64>, entries: &[vdi_field_entry_v1]) {
+    for ent in entries {
+        fields.insert(ent.vfe_ident, ent.vfe_value);
+    }
+}
+
+struct State {
+    destroyed: bool,
+    paused: bool,
+    suspended: Option<Suspend>,
+
+    /// Guest-physical address at which each memory segment is mapped
+    segs: BTreeMap<i32, usize>,
+    /// Dirty pages, by PFN
+    dirty: BTreeSet<u64>,
+
+    vcpus: BTreeMap<i32, MockVcpu>,
+    msis: Vec<(u64, u64)>,
+    pins: Vec<PinEvent>,
+
+    time: VmTimeData,
+    time_written: Instant,
+    rtc_regs: [u8; 256],
+
+    /// Emulated device state which has been written since reinit
+    devices: BTreeMap<DeviceKind, DeviceState>,
+    arch: BTreeMap<u32, u64>,
+}
+impl State {
+    fn check_live(&self) -> Result<()> {
+        if self.destroyed {
+            return Err(Error::new(ErrorKind::NotFound, "instance destroyed"));
+        }
+        Ok(())
+    }
+
+    fn vcpu(&mut self, vcpuid: i32) -> Result<&mut MockVcpu> {
+        self.check_live()?;
+        if vcpuid < 0 || vcpuid as usize >= MAXCPU {
+            return Err(Error::new(ErrorKind::InvalidInput, "bad vcpuid"));
+        }
+        Ok(self.vcpus.entry(vcpuid).or_default())
+    }
+}
+
+/// An in-process stand-in for a bhyve VM.
+pub struct MockVmm {
+    mem: File,
+    state: Mutex<State>,
+    /// Signalled when there is cause for a running vCPU to exit
+    cv: Condvar,
+}
+impl MockVmm {
+    /// Creates a mock VM with `mem_size` bytes of backing for guest memory,
+    /// which must cover the guest-physical addresses to be mapped.
+    pub fn new(mem_size: usize) -> Result<Self> {
+        let mem = tempfile::tempfile()?;
+        mem.set_len(mem_size as u64)?;
+
+        Ok(Self {
+            mem,
+            state: Mutex::new(State {
+                destroyed: false,
+                paused: false,
+                suspended: None,
+                segs: BTreeMap::new(),
+                dirty: BTreeSet::new(),
+                vcpus: BTreeMap::new(),
+                msis: Vec::new(),
+                pins: Vec::new(),
+                time: VmTimeData {
+                    guest_freq: DEFAULT_TSC_FREQ,
+                    ..Default::default()
+                },
+                time_written: Instant::now(),
+                rtc_regs: [0u8; 256],
+                devices: BTreeMap::new(),
+                arch: BTreeMap::new(),
+            }),
+            cv: Condvar::new(),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Queue `exit` to be emitted by a future run of vCPU `vcpuid`, waking it
+    /// should it be waiting in [Hypervisor::vcpu_run].
+    pub fn push_exit(&self, vcpuid: i32, exit: VmExit) {
+        let mut state = self.lock();
+        state.vcpus.entry(vcpuid).or_default().exits.push_back(exit);
+        self.cv.notify_all();
+    }
+
+    /// Take the [VmEntry]s with which vCPU `vcpuid` has been run since the
+    /// last call, in the order they were made.
+    pub fn take_entries(&self, vcpuid: i32) -> Vec<VmEntry> {
+        let mut state = self.lock();
+        state
+            .vcpus
+            .get_mut(&vcpuid)
+            .map(|vcpu| std::mem::take(&mut vcpu.entries))
+            .unwrap_or_default()
+    }
+
+    /// Take the MSIs which have been delivered since the last call, as
+    /// (address, data) pairs in the order they were sent.
+    pub fn take_msis(&self) -> Vec<(u64, u64)> {
+        std::mem::take(&mut self.lock().msis)
+    }
+
+    /// Take the interrupt pin state changes made since the last call, in the
+    /// order they were made.
+    pub fn take_pin_events(&self) -> Vec<PinEvent> {
+        std::mem::take(&mut self.lock().pins)
+    }
+
+    /// Returns whether an NMI has been injected into vCPU `vcpuid`, clearing
+    /// it in the process.
+    pub fn take_nmi(&self, vcpuid: i32) -> bool {
+        let mut state = self.lock();
+        state
+            .vcpus
+            .get_mut(&vcpuid)
+            .map(|vcpu| std::mem::replace(&mut vcpu.nmi_pending, false))
+            .unwrap_or(false)
+    }
+
+    /// How the VM has been suspended, if it has been since its last reinit.
+    pub fn suspended(&self) -> Option<Suspend> {
+        self.lock().suspended
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.lock().paused
+    }
+
+    pub fn is_destroyed(&self) -> bool {
+        self.lock().destroyed
+    }
+}
+
+impl Hypervisor for MockVmm {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn api_version(&self) -> Result<u32> {
+        Ok(bhyve_api::ApiVersion::current() as u32)
+    }
+
+    fn fd(&self) -> RawFd {
+        self.mem.as_raw_fd()
+    }
+
+    fn create_memseg(
+        &self,
+        _segid: i32,
+        _size: usize,
+        _segname: Option<&str>,
+    ) -> Result<()> {
+        self.lock().check_live()
+    }
+
+    fn map_memseg(
+        &self,
+        segid: i32,
+        gpa: usize,
+        len: usize,
+        segoff: usize,
+        _prot: Prot,
+    ) -> Result<()> {
+        let mut state = self.lock();
+        state.check_live()?;
+        if segoff != 0 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "mock segments must be mapped from their start",
+            ));
+        }
+        if (gpa + len) as u64 > self.mem.metadata()?.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "mapping exceeds mock memory backing",
+            ));
+        }
+        state.segs.insert(segid, gpa);
+        Ok(())
+    }
+
+    fn devmem_offset(&self, segid: i32) -> Result<usize> {
+        let state = self.lock();
+        state.check_live()?;
+        state.segs.get(&segid).copied().ok_or_else(|| {
+            Error::new(ErrorKind::NotFound, "memory segment not mapped")
+        })
+    }
+
+    fn track_dirty_pages(
+        &self,
+        start_gpa: u64,
+        bitmap: &mut [u8],
+    ) -> Result<()> {
+        let mut state = self.lock();
+        state.check_live()?;
+        let start_pfn = start_gpa / PAGE_SIZE as u64;
+        for (i, byte) in bitmap.iter_mut().enumerate() {
+            *byte = 0;
+            for bit in 0..8 {
+                let pfn = start_pfn + (i * 8 + bit) as u64;
+                if state.dirty.remove(&pfn) {
+                    *byte |= 1 << bit;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn set_dirty_pages(&self, start_gpa: u64, bitmap: &[u8]) -> Result<()> {
+        let mut state = self.lock();
+        state.check_live()?;
+        let start_pfn = start_gpa / PAGE_SIZE as u64;
+        for (i, byte) in bitmap.iter().enumerate() {
+            for bit in (0..8).filter(|bit| byte & (1 << bit) != 0) {
+                state.dirty.insert(start_pfn + (i * 8 + bit) as u64);
+            }
+        }
+        Ok(())
+    }
+
+    fn can_npt_operate(&self) -> bool {
+        true
+    }
+
+    fn isa_assert_irq(
+        &self,
+        pic_irq: u8,
+        ioapic_irq: Option<u8>,
+    ) -> Result<()> {
+        self.pin_event(PinEvent::IsaAssert(pic_irq, ioapic_irq))
+    }
+    fn isa_deassert_irq(
+        &self,
+        pic_irq: u8,
+        ioapic_irq: Option<u8>,
+    ) -> Result<()> {
+        self.pin_event(PinEvent::IsaDeassert(pic_irq, ioapic_irq))
+    }
+    fn isa_pulse_irq(&self, pic_irq: u8, ioapic_irq: Option<u8>) -> Result<()> {
+        self.pin_event(PinEvent::IsaPulse(pic_irq, ioapic_irq))
+    }
+    fn isa_set_trigger_mode(&self, _vec: u8, _level_mode: bool) -> Result<()> {
+        self.lock().check_live()
+    }
+
+    fn ioapic_assert_irq(&self, irq: u8) -> Result<()> {
+        self.pin_event(PinEvent::IoapicAssert(irq))
+    }
+    fn ioapic_deassert_irq(&self, irq: u8) -> Result<()> {
+        self.pin_event(PinEvent::IoapicDeassert(irq))
+    }
+    fn ioapic_pulse_irq(&self, irq: u8) -> Result<()> {
+        self.pin_event(PinEvent::IoapicPulse(irq))
+    }
+    fn ioapic_pin_count(&self) -> Result<u8> {
+        self.lock().check_live()?;
+        Ok(IOAPIC_PINS)
+    }
+
+    fn lapic_msi(&self, addr: u64, msg: u64) -> Result<()> {
+        let mut state = self.lock();
+        state.check_live()?;
+        state.msis.push((addr, msg));
+        Ok(())
+    }
+
+    fn read_time_data(&self) -> Result<VmTimeData> {
+        let state = self.lock();
+        state.check_live()?;
+
+        // Let time advance (for both the host and guest TSC) since the data
+        // was last written.
+        let elapsed = state.time_written.elapsed();
+        let elapsed_ns = elapsed.as_nanos() as u64;
+        let wall = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut data = state.time;
+        data.hrtime += elapsed_ns as i64;
+        data.guest_tsc += (u128::from(elapsed_ns) * u128::from(data.guest_freq)
+            / u128::from(NS_PER_SEC)) as u64;
+        data.hres_sec = wall.as_secs();
+        data.hres_ns = u64::from(wall.subsec_nanos());
+        Ok(data)
+    }
+    fn write_time_data(&self, data: &VmTimeData) -> Result<()> {
+        let mut state = self.lock();
+        state.check_live()?;
+        state.time = *data;
+        state.time_written = Instant::now();
+        Ok(())
+    }
+
+    fn rtc_settime(&self, _time: Duration) -> Result<()> {
+        self.lock().check_live()
+    }
+    fn rtc_write(&self, offset: u8, value: u8) -> Result<()> {
+        let mut state = self.lock();
+        state.check_live()?;
+        state.rtc_regs[usize::from(offset)] = value;
+        Ok(())
+    }
+    fn rtc_read(&self, offset: u8) -> Result<u8> {
+        let state = self.lock();
+        state.check_live()?;
+        Ok(state.rtc_regs[usize::from(offset)])
+    }
+
+    fn pmtmr_locate(&self, _port: u16) -> Result<()> {
+        self.lock().check_live()
+    }
+
+    fn device_state_read(&self, kind: DeviceKind) -> Result<DeviceState> {
+        let state = self.lock();
+        state.check_live()?;
+        Ok(state
+            .devices
+            .get(&kind)
+            .copied()
+            .unwrap_or_else(|| kind.initial_state()))
+    }
+    fn device_state_write(&self, data: &DeviceState) -> Result<()> {
+        let mut state = self.lock();
+        state.check_live()?;
+        state.devices.insert(data.kind(), *data);
+        Ok(())
+    }
+
+    fn arch_data_read(&self) -> Result<Vec<vdi_field_entry_v1>> {
+        let state = self.lock();
+        state.check_live()?;
+        Ok(field_entries(&state.arch))
+    }
+    fn arch_data_write(&self, entries: &[vdi_field_entry_v1]) -> Result<()> {
+        let mut state = self.lock();
+        state.check_live()?;
+        set_fields(&mut state.arch, entries);
+        Ok(())
+    }
+
+    fn suspend(&self, how: vm_suspend_how, source: Option<i32>) -> Result<()> {
+        let kind = match how {
+            vm_suspend_how::VM_SUSPEND_RESET => Suspend::Reset,
+            vm_suspend_how::VM_SUSPEND_POWEROFF
+            | vm_suspend_how::VM_SUSPEND_HALT => Suspend::Halt,
+            vm_suspend_how::VM_SUSPEND_TRIPLEFAULT => {
+                Suspend::TripleFault(source.unwrap_or(-1))
+            }
+            vm_suspend_how::VM_SUSPEND_NONE => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "invalid suspend type",
+                ));
+            }
+        };
+
+        let mut state = self.lock();
+        state.check_live()?;
+        if state.suspended.is_some() {
+            return Err(Error::new(ErrorKind::AlreadyExists, "suspended"));
+        }
+        state.suspended = Some(kind);
+        self.cv.notify_all();
+        Ok(())
+    }
+
+    fn reinit(&self, force_suspend: bool) -> Result<()> {
+        let mut state = self.lock();
+        state.check_live()?;
+        if state.suspended.is_none() {
+            if !force_suspend {
+                return Err(Error::new(ErrorKind::Other, "not suspended"));
+            }
+            // Kick any running vCPUs out, as a forced suspend would
+            state.suspended = Some(Suspend::Reset);
+            self.cv.notify_all();
+        }
+        if state.vcpus.values().any(|vcpu| vcpu.running) {
+            return Err(Error::new(ErrorKind::Other, "vCPUs still running"));
+        }
+
+        state.suspended = None;
+        state.dirty.clear();
+        state.msis.clear();
+        state.pins.clear();
+        state.devices.clear();
+        state.arch.clear();
+        for vcpu in state.vcpus.values_mut() {
+            vcpu.reset();
+            vcpu.active = false;
+            vcpu.barrier = false;
+        }
+        Ok(())
+    }
+
+    fn pause(&self) -> Result<()> {
+        let mut state = self.lock();
+        state.check_live()?;
+        state.paused = true;
+        Ok(())
+    }
+
+    fn resume(&self) -> Result<()> {
+        let mut state = self.lock();
+        state.check_live()?;
+        state.paused = false;
+        Ok(())
+    }
+
+    fn destroy(&self) -> Result<()> {
+        let mut state = self.lock();
+        if state.destroyed {
+            return Err(Error::new(ErrorKind::NotFound, "already destroyed"));
+        }
+        state.destroyed = true;
+        self.cv.notify_all();
+        Ok(())
+    }
+
+    fn set_autodestruct(&self, _enable_autodestruct: bool) -> Result<()> {
+        self.lock().check_live()
+    }
+
+    fn vcpu_set_default_capabs(&self, vcpuid: i32) -> Result<()> {
+        self.lock().vcpu(vcpuid).map(|_| ())
+    }
+
+    fn vcpu_get_reg(
+        &self,
+        vcpuid: i32,
+        reg: bhyve_api::vm_reg_name,
+    ) -> Result<u64> {
+        let mut state = self.lock();
+        let vcpu = state.vcpu(vcpuid)?;
+        Ok(vcpu.regs.get(&(reg as i32)).copied().unwrap_or(0))
+    }
+    fn vcpu_set_reg(
+        &self,
+        vcpuid: i32,
+        reg: bhyve_api::vm_reg_name,
+        val: u64,
+    ) -> Result<()> {
+        let mut state = self.lock();
+        state.vcpu(vcpuid)?.regs.insert(reg as i32, val);
+        Ok(())
+    }
+
+    fn vcpu_get_segreg(
+        &self,
+        vcpuid: i32,
+        reg: bhyve_api::vm_reg_name,
+    ) -> Result<bhyve_api::seg_desc> {
+        let mut state = self.lock();
+        let vcpu = state.vcpu(vcpuid)?;
+        Ok(vcpu.segregs.get(&(reg as i32)).copied().unwrap_or_default())
+    }
+    fn vcpu_set_segreg(
+        &self,
+        vcpuid: i32,
+        reg: bhyve_api::vm_reg_name,
+        seg: &bhyve_api::seg_desc,
+    ) -> Result<()> {
+        let mut state = self.lock();
+        state.vcpu(vcpuid)?.segregs.insert(reg as i32, *seg);
+        Ok(())
+    }
+
+    fn vcpu_set_cpuid(&self, vcpuid: i32, values: cpuid::Set) -> Result<()> {
+        let mut state = self.lock();
+        state.vcpu(vcpuid)?.cpuid = Some(values);
+        Ok(())
+    }
+    fn vcpu_get_cpuid(&self, vcpuid: i32) -> Result<cpuid::Set> {
+        let mut state = self.lock();
+        let vcpu = state.vcpu(vcpuid)?;
+        Ok(vcpu
+            .cpuid
+            .clone()
+            .unwrap_or_else(|| cpuid::Set::new(cpuid::VendorKind::Amd)))
+    }
+
+    fn vcpu_get_arch(&self, vcpuid: i32) -> Result<Vec<vdi_field_entry_v1>> {
+        let mut state = self.lock();
+        Ok(field_entries(&state.vcpu(vcpuid)?.arch))
+    }
+    fn vcpu_set_arch(
+        &self,
+        vcpuid: i32,
+        entries: &[vdi_field_entry_v1],
+    ) -> Result<()> {
+        let mut state = self.lock();
+        set_fields(&mut state.vcpu(vcpuid)?.arch, entries);
+        Ok(())
+    }
+
+    fn vcpu_get_msrs(&self, vcpuid: i32) -> Result<Vec<vdi_field_entry_v1>> {
+        let mut state = self.lock();
+        Ok(field_entries(&state.vcpu(vcpuid)?.msrs))
+    }
+    fn vcpu_set_msrs(
+        &self,
+        vcpuid: i32,
+        msrs: &[vdi_field_entry_v1],
+    ) -> Result<()> {
+        let mut state = self.lock();
+        set_fields(&mut state.vcpu(vcpuid)?.msrs, msrs);
+        Ok(())
+    }
+
+    fn vcpu_get_lapic(&self, vcpuid: i32) -> Result<bhyve_api::vdi_lapic_v1> {
+        let mut state = self.lock();
+        Ok(state.vcpu(vcpuid)?.lapic)
+    }
+    fn vcpu_set_lapic(
+        &self,
+        vcpuid: i32,
+        lapic: &bhyve_api::vdi_lapic_v1,
+    ) -> Result<()> {
+        let mut state = self.lock();
+        state.vcpu(vcpuid)?.lapic = *lapic;
+        Ok(())
+    }
+
+    fn vcpu_get_fpu(&self, vcpuid: i32) -> Result<Vec<u8>> {
+        let mut state = self.lock();
+        let vcpu = state.vcpu(vcpuid)?;
+        Ok(vcpu.fpu.clone().unwrap_or_else(|| vec![0u8; FPU_AREA_SIZE]))
+    }
+    fn vcpu_set_fpu(&self, vcpuid: i32, fpu: &[u8]) -> Result<()> {
+        let mut state = self.lock();
+        let vcpu = state.vcpu(vcpuid)?;
+        if fpu.len() != FPU_AREA_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "fpu blob size mismatch",
+            ));
+        }
+        vcpu.fpu = Some(fpu.to_vec());
+        Ok(())
+    }
+
+    fn vcpu_reset(&self, vcpuid: i32) -> Result<()> {
+        let mut state = self.lock();
+        state.vcpu(vcpuid)?.reset();
+        Ok(())
+    }
+    fn vcpu_activate(&self, vcpuid: i32) -> Result<()> {
+        let mut state = self.lock();
+        let vcpu = state.vcpu(vcpuid)?;
+        if vcpu.active {
+            return Err(Error::new(ErrorKind::AlreadyExists, "vCPU active"));
+        }
+        vcpu.active = true;
+        Ok(())
+    }
+
+    fn vcpu_set_run_state(
+        &self,
+        vcpuid: i32,
+        state: u32,
+        sipi_vector: Option<u8>,
+    ) -> Result<()> {
+        let mut guard = self.lock();
+        let vcpu = guard.vcpu(vcpuid)?;
+        vcpu.run_state = state;
+        vcpu.sipi_vector = sipi_vector.unwrap_or(0);
+        Ok(())
+    }
+    fn vcpu_get_run_state(
+        &self,
+        vcpuid: i32,
+    ) -> Result<bhyve_api::vm_run_state> {
+        let mut state = self.lock();
+        let vcpu = state.vcpu(vcpuid)?;
+        Ok(bhyve_api::vm_run_state {
+            vcpuid,
+            state: vcpu.run_state,
+            sipi_vector: vcpu.sipi_vector,
+            ..Default::default()
+        })
+    }
+
+    fn vcpu_run(
+        &self,
+        vcpuid: i32,
+        entry: &VmEntry,
+        exit_when_consistent: bool,
+    ) -> Result<VmExit> {
+        let mut state = self.lock();
+        let vcpu = state.vcpu(vcpuid)?;
+        if !vcpu.active {
+            return Err(Error::new(ErrorKind::Other, "vCPU not active"));
+        }
+        if vcpu.running {
+            return Err(Error::new(ErrorKind::Other, "vCPU already running"));
+        }
+        vcpu.entries.push(*entry);
+        vcpu.running = true;
+
+        let res = loop {
+            if state.destroyed {
+                break Err(Error::new(
+                    ErrorKind::NotFound,
+                    "instance destroyed",
+                ));
+            }
+            if let Some(kind) = state.suspended {
+                break Ok(VmExit {
+                    rip: 0,
+                    inst_len: 0,
+                    kind: VmExitKind::Suspended(SuspendDetail {
+                        kind,
+                        when: Duration::ZERO,
+                    }),
+                });
+            }
+            let vcpu = state.vcpus.get_mut(&vcpuid).unwrap();
+            if let Some(exit) = vcpu.exits.pop_front() {
+                break Ok(exit);
+            }
+            // Without any guest code to execute, the vCPU is always in a
+            // consistent state.
+            if vcpu.barrier || exit_when_consistent {
+                break Ok(VmExit::default());
+            }
+            state = self.cv.wait(state).unwrap();
+        };
+
+        let vcpu = state.vcpus.get_mut(&vcpuid).unwrap();
+        vcpu.running = false;
+        vcpu.barrier = false;
+        res
+    }
+
+    fn vcpu_barrier(&self, vcpuid: i32) -> Result<()> {
+        let mut state = self.lock();
+        let vcpu = state.vcpu(vcpuid)?;
+        if vcpu.running {
+            vcpu.barrier = true;
+            self.cv.notify_all();
+        }
+        Ok(())
+    }
+
+    fn vcpu_inject_nmi(&self, vcpuid: i32) -> Result<()> {
+        let mut state = self.lock();
+        state.vcpu(vcpuid)?.nmi_pending = true;
+        Ok(())
+    }
+}
+
+impl MockVmm {
+    fn pin_event(&self, event: PinEvent) -> Result<()> {
+        let mut state = self.lock();
+        state.check_live()?;
+        state.pins.push(event);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use bhyve_api::vm_reg_name;
+
+    fn mock() -> Arc<MockVmm> {
+        let mock = MockVmm::new(16 * PAGE_SIZE).unwrap();
+        mock.vcpu_activate(0).unwrap();
+        Arc::new(mock)
+    }
+
+    #[test]
+    fn scripted_exits_in_order() {
+        let mock = mock();
+        mock.push_exit(0, VmExit { rip: 0x1000, ..Default::default() });
+        mock.push_exit(0, VmExit { rip: 0x2000, ..Default::default() });
+
+        let first = mock.vcpu_run(0, &VmEntry::Run, false).unwrap();
+        let second = mock.vcpu_run(0, &VmEntry::Run, false).unwrap();
+        assert_eq!(first.rip, 0x1000);
+        assert_eq!(second.rip, 0x2000);
+        assert_eq!(mock.take_entries(0).len(), 2);
+    }
+
+    #[test]
+    fn barrier_wakes_run() {
+        let mock = mock();
+        let runner = {
+            let mock = mock.clone();
+            std::thread::spawn(move || mock.vcpu_run(0, &VmEntry::Run, false))
+        };
+
+        // Keep issuing barriers until the vCPU is seen to be running
+        while !runner.is_finished() {
+            mock.vcpu_barrier(0).unwrap();
+            std::thread::yield_now();
+        }
+        let exit = runner.join().unwrap().unwrap();
+        assert!(matches!(exit.kind, VmExitKind::Bogus));
+    }
+
+    #[test]
+    fn suspend_until_reinit() {
+        let mock = mock();
+        mock.suspend(vm_suspend_how::VM_SUSPEND_RESET, None).unwrap();
+
+        let exit = mock.vcpu_run(0, &VmEntry::Run, false).unwrap();
+        assert!(matches!(
+            exit.kind,
+            VmExitKind::Suspended(SuspendDetail { kind: Suspend::Reset, .. })
+        ));
+
+        mock.reinit(false).unwrap();
+        assert!(mock.suspended().is_none());
+        assert!(mock.vcpu_run(0, &VmEntry::Run, true).is_err());
+    }
+
+    #[test]
+    fn register_roundtrip() {
+        let mock = mock();
+        mock.vcpu_set_reg(0, vm_reg_name::VM_REG_GUEST_RIP, 0xfff0).unwrap();
+        assert_eq!(
+            mock.vcpu_get_reg(0, vm_reg_name::VM_REG_GUEST_RIP).unwrap(),
+            0xfff0
+        );
+
+        mock.vcpu_reset(0).unwrap();
+        assert_eq!(
+            mock.vcpu_get_reg(0, vm_reg_name::VM_REG_GUEST_RIP).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn vcpu_state_roundtrip() {
+        let mock = mock();
+        mock.vcpu_set_msrs(0, &[vdi_field_entry_v1::new(0x10, 5)]).unwrap();
+        mock.vcpu_set_msrs(0, &[vdi_field_entry_v1::new(0x1b, 7)]).unwrap();
+        let msrs = mock.vcpu_get_msrs(0).unwrap();
+        let msrs: Vec<_> =
+            msrs.iter().map(|ent| (ent.vfe_ident, ent.vfe_value)).collect();
+        assert_eq!(msrs, [(0x10, 5), (0x1b, 7)]);
+
+        let mut fpu = mock.vcpu_get_fpu(0).unwrap();
+        fpu[0] = 0x7f;
+        mock.vcpu_set_fpu(0, &fpu).unwrap();
+        assert_eq!(mock.vcpu_get_fpu(0).unwrap(), fpu);
+        assert!(mock.vcpu_set_fpu(0, &fpu[1..]).is_err());
+
+        mock.vcpu_reset(0).unwrap();
+        assert!(mock.vcpu_get_msrs(0).unwrap().is_empty());
+        assert!(mock.vcpu_get_fpu(0).unwrap().iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn device_state_roundtrip() {
+        let mock = mock();
+        let DeviceState::Rtc(mut rtc) =
+            mock.device_state_read(DeviceKind::Rtc).unwrap()
+        else {
+            panic!("state of the wrong device");
+        };
+        rtc.vr_content[0] = 0x12;
+        mock.device_state_write(&DeviceState::Rtc(rtc)).unwrap();
+
+        let DeviceState::Rtc(read) =
+            mock.device_state_read(DeviceKind::Rtc).unwrap()
+        else {
+            panic!("state of the wrong device");
+        };
+        assert_eq!(read.vr_content[0], 0x12);
+    }
+
+    #[test]
+    fn dirty_tracking() {
+        let mock = mock();
+        mock.set_dirty_pages(0, &[0b1000_0001, 0b1]).unwrap();
+
+        let mut bitmap = [0u8; 2];
+        mock.track_dirty_pages(PAGE_SIZE as u64, &mut bitmap).unwrap();
+        assert_eq!(bitmap, [0b1100_0000, 0b0]);
+
+        // Pages should be clean once tracked
+        mock.track_dirty_pages(PAGE_SIZE as u64, &mut bitmap).unwrap();
+        assert_eq!(bitmap, [0, 0]);
+    }
+
+    #[test]
+    fn destroyed_rejects_ops() {
+        let mock = mock();
+        mock.destroy().unwrap();
+        assert!(mock.destroy().is_err());
+        assert!(mock.lapic_msi(0xfee0_0000, 0).is_err());
+        assert!(mock.vcpu_run(0, &VmEntry::Run, true).is_err());
+    }
+}
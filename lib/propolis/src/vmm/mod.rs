@@ -4,14 +4,22 @@
 
 //! Representation of a VM's hardware and kernel structures.
 
+pub mod bhyve;
 pub mod hdl;
+pub mod hypervisor;
 pub mod machine;
 pub mod mem;
+#[cfg(any(test, feature = "testing"))]
+pub mod mock;
 pub mod time;
 
+pub use bhyve::BhyveVmm;
 pub use hdl::*;
+pub use hypervisor::Hypervisor;
 pub use machine::*;
 pub use mem::*;
+#[cfg(any(test, feature = "testing"))]
+pub use mock::MockVmm;
 
 /// Check that available vmm API matches expectations of propolis crate
 pub(crate) fn check_api_version() -> Result<(), crate::api_version::Error> {
@@ -81,18 +81,11 @@ pub fn import_time_data(
     hdl: &VmmHdl,
     time_info: VmTimeData,
 ) -> std::io::Result<()> {
-    let raw = bhyve_api::vdi_time_info_v1::from(time_info);
-    hdl.data_op(bhyve_api::VDC_VMM_TIME, 1).write(&raw)?;
-
-    Ok(())
+    hdl.write_time_data(&time_info)
 }
 
 pub fn export_time_data(hdl: &VmmHdl) -> std::io::Result<VmTimeData> {
-    let raw = hdl
-        .data_op(bhyve_api::VDC_VMM_TIME, 1)
-        .read::<bhyve_api::vdi_time_info_v1>()?;
-
-    Ok(VmTimeData::from(raw))
+    hdl.read_time_data()
 }
 
 /// Returns the current host hrtime and wall clock time
@@ -0,0 +1,76 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Pieces of a guest shared by the device driver test suites
+
+use std::time::{Duration, Instant};
+
+use propolis::hw::pci;
+use propolis::testing::{pci_cfg_read, pci_cfg_write, Msi, TestMachine};
+
+const PCI_REG_CAP_PTR: usize = 0x34;
+
+const MSIX_ENTRY_LEN: u64 = 16;
+const MSIX_MSGCTRL_ENABLE: u32 = 1 << 15;
+
+/// Address targeted by all MSI-X vectors the guest programs
+pub const MSI_ADDR: u64 = 0xfee0_0000;
+/// Data for MSI-X vector 0.  Subsequent vectors count up from here.
+pub const MSI_DATA_BASE: u64 = 0x40;
+
+/// How long to wait for the backend to complete a request before giving up
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Polls `f` until it yields a value.
+///
+/// # Panics
+///
+/// If nothing is yielded within a generous timeout.
+pub fn wait_for<T>(what: &str, mut f: impl FnMut() -> Option<T>) -> T {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Some(val) = f() {
+            return val;
+        }
+        assert!(Instant::now() < deadline, "timed out waiting for {what}");
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Walks the capability list of `dev`, returning the offset of capability `id`
+pub fn find_cap(dev: &dyn pci::Endpoint, id: u8) -> Option<usize> {
+    let mut off = pci_cfg_read(dev, PCI_REG_CAP_PTR, 1) as usize;
+    while off != 0 {
+        if pci_cfg_read(dev, off, 1) == u32::from(id) {
+            return Some(off);
+        }
+        off = pci_cfg_read(dev, off + 1, 1) as usize;
+    }
+    None
+}
+
+/// Fills in and unmasks the first `count` entries of the MSI-X table mapped at
+/// `table`, then enables MSI-X on the function.
+pub fn enable_msix(
+    tm: &TestMachine,
+    dev: &dyn pci::Endpoint,
+    table: u64,
+    count: u16,
+) {
+    for vec in 0..u64::from(count) {
+        let ent = table + vec * MSIX_ENTRY_LEN;
+        tm.mmio_write(ent, 8, MSI_ADDR).unwrap();
+        tm.mmio_write(ent + 8, 4, MSI_DATA_BASE + vec).unwrap();
+        tm.mmio_write(ent + 12, 4, 0).unwrap();
+    }
+
+    let cap = find_cap(dev, pci::bits::CAP_ID_MSIX).expect("MSI-X capability");
+    let ctrl = pci_cfg_read(dev, cap + 2, 2);
+    pci_cfg_write(dev, cap + 2, 2, ctrl | MSIX_MSGCTRL_ENABLE);
+}
+
+/// The message sent for MSI-X vector `vec`, as programmed by [enable_msix()]
+pub fn msi(vec: u16) -> Msi {
+    Msi { addr: MSI_ADDR, data: MSI_DATA_BASE + u64::from(vec) }
+}
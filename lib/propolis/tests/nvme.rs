@@ -0,0 +1,551 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Drives `PciNvme` the way a guest driver would: through its controller
+//! registers and doorbells, with admin and I/O queues in (fake) guest memory.
+
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
+use propolis::block::{self, Backend};
+use propolis::common::{GuestAddr, PAGE_SIZE};
+use propolis::hw::nvme::PciNvme;
+use propolis::hw::pci;
+use propolis::lifecycle::Lifecycle;
+use propolis::testing::nvme::{
+    Command, Completion, HostCompQueue, HostSubQueue, CQE_LEN, SQE_LEN,
+};
+use propolis::testing::{pci_cfg_read, Builder, TestMachine};
+
+mod common;
+use common::{msi, wait_for};
+
+/// Controller registers, in BAR0/1
+const REGS_BAR: u64 = 0xc000_0000;
+/// MSI-X table and PBA, in BAR4
+const MSIX_BAR: u64 = 0xc001_0000;
+const RAM_SIZE: usize = 4 * 1024 * 1024;
+
+const SERIAL: &str = "propolis-test";
+const LBA_SZ: usize = 512;
+const DISK_LBAS: usize = 64;
+
+// Controller register offsets
+const REG_CAP: u64 = 0x00;
+const REG_VS: u64 = 0x08;
+const REG_CC: u64 = 0x14;
+const REG_CSTS: u64 = 0x1c;
+const REG_AQA: u64 = 0x24;
+const REG_ASQ: u64 = 0x28;
+const REG_ACQ: u64 = 0x30;
+const REG_DOORBELLS: u64 = 0x1000;
+
+const CC_EN: u32 = 1 << 0;
+const CC_IOSQES_SHIFT: u32 = 16;
+const CC_IOCQES_SHIFT: u32 = 20;
+const CSTS_RDY: u32 = 1 << 0;
+const CSTS_CFS: u32 = 1 << 1;
+
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const IDENT_CNS_NAMESPACE: u32 = 0;
+const IDENT_CNS_CONTROLLER: u32 = 1;
+
+const NVM_FLUSH: u8 = 0x00;
+const NVM_WRITE: u8 = 0x01;
+const NVM_READ: u8 = 0x02;
+
+/// Status Code for Invalid Namespace or Format
+const STS_INVALID_NS: u8 = 0x0b;
+
+const ADMIN_QUEUE_SIZE: u16 = 8;
+const IO_QUEUE_SIZE: u16 = 16;
+const IO_QID: u16 = 1;
+const NSID: u32 = 1;
+
+const VEC_ADMIN: u16 = 0;
+const VEC_IO: u16 = 1;
+
+/// A request is counted as complete by the device just before its completion
+/// is posted, so allow some time for stragglers to land.
+const SETTLE_TIME: Duration = Duration::from_millis(50);
+
+/// A disk image whose every LBA is filled with its own index
+fn disk_image() -> Vec<u8> {
+    (0..DISK_LBAS).flat_map(|lba| [lba as u8; LBA_SZ]).collect()
+}
+
+/// The pair of host queues for one queue ID
+struct QueuePair {
+    qid: u16,
+    sq: HostSubQueue,
+    cq: HostCompQueue,
+}
+impl QueuePair {
+    fn sq_doorbell(&self) -> u64 {
+        REG_DOORBELLS + u64::from(self.qid) * 8
+    }
+    fn cq_doorbell(&self) -> u64 {
+        REG_DOORBELLS + u64::from(self.qid) * 8 + 4
+    }
+}
+
+/// A machine with an NVMe controller, and the guest driving it
+struct Guest {
+    tm: TestMachine,
+    _bus: pci::Bus,
+    dev: Arc<PciNvme>,
+    backend: Arc<block::InMemoryBackend>,
+    admin: Option<QueuePair>,
+    io: Option<QueuePair>,
+    next_cid: u16,
+}
+impl Guest {
+    async fn new(disk: Vec<u8>) -> Self {
+        let tm = Builder::new().ram_size(RAM_SIZE).build().unwrap();
+        let bus = tm.pci_bus();
+
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let dev = PciNvme::create(SERIAL.to_string(), None, log);
+        let backend = block::InMemoryBackend::create(
+            disk,
+            block::BackendOpts {
+                block_size: Some(LBA_SZ as u32),
+                ..Default::default()
+            },
+            NonZeroUsize::new(2).unwrap(),
+        )
+        .unwrap();
+        block::attach(dev.clone(), backend.clone()).unwrap();
+        bus.attach(pci::BusLocation::new(5, 0).unwrap(), dev.clone(), None);
+        backend.start().await.unwrap();
+
+        Self { tm, _bus: bus, dev, backend, admin: None, io: None, next_cid: 0 }
+    }
+
+    fn read32(&self, reg: u64) -> u32 {
+        self.tm.mmio_read(REGS_BAR + reg, 4).unwrap() as u32
+    }
+
+    fn write32(&self, reg: u64, val: u32) {
+        self.tm.mmio_write(REGS_BAR + reg, 4, u64::from(val)).unwrap()
+    }
+
+    fn read64(&self, reg: u64) -> u64 {
+        self.tm.mmio_read(REGS_BAR + reg, 8).unwrap()
+    }
+
+    fn write64(&self, reg: u64, val: u64) {
+        self.tm.mmio_write(REGS_BAR + reg, 8, val).unwrap()
+    }
+
+    fn pci_init(&self) {
+        propolis::testing::pci_init(
+            &*self.dev,
+            &[(0, REGS_BAR as u32), (1, 0), (4, MSIX_BAR as u32)],
+        );
+        common::enable_msix(&self.tm, &*self.dev, MSIX_BAR, 2);
+    }
+
+    /// Disables the controller, sets up the admin queues, and enables it
+    /// again.
+    fn ctrl_init(&mut self) {
+        self.write32(REG_CC, 0);
+        assert_eq!(self.read32(REG_CSTS) & CSTS_RDY, 0);
+        self.admin = None;
+        self.io = None;
+
+        let mem = self.tm.mem();
+        let sq_base =
+            self.tm.alloc(usize::from(ADMIN_QUEUE_SIZE) * SQE_LEN, PAGE_SIZE);
+        let cq_base =
+            self.tm.alloc(usize::from(ADMIN_QUEUE_SIZE) * CQE_LEN, PAGE_SIZE);
+        let sq = HostSubQueue::new(sq_base, ADMIN_QUEUE_SIZE);
+        let cq = HostCompQueue::new(&mem, cq_base, ADMIN_QUEUE_SIZE);
+        drop(mem);
+
+        // Queue sizes are 0's based
+        let qsize = u32::from(ADMIN_QUEUE_SIZE - 1);
+        self.write32(REG_AQA, (qsize << 16) | qsize);
+        self.write64(REG_ASQ, sq_base.0);
+        self.write64(REG_ACQ, cq_base.0);
+        assert_eq!(self.read64(REG_ASQ), sq_base.0);
+
+        // 64-byte SQ entries, 16-byte CQ entries, NVM command set, 4K pages
+        self.write32(
+            REG_CC,
+            CC_EN | (6 << CC_IOSQES_SHIFT) | (4 << CC_IOCQES_SHIFT),
+        );
+        wait_for("controller ready", || {
+            ((self.read32(REG_CSTS) & CSTS_RDY) != 0).then_some(())
+        });
+        assert_eq!(self.read32(REG_CSTS) & CSTS_CFS, 0);
+
+        self.admin = Some(QueuePair { qid: 0, sq, cq });
+    }
+
+    /// Places `cmd` on the I/O or admin submission queue, with a fresh
+    /// command ID (which is returned), and rings its doorbell.
+    fn submit(&mut self, io: bool, mut cmd: Command) -> u16 {
+        cmd.cid = self.next_cid;
+        self.next_cid = self.next_cid.wrapping_add(1);
+
+        let qp = if io { &mut self.io } else { &mut self.admin };
+        let qp = qp.as_mut().unwrap();
+        let tail = qp.sq.push(&self.tm.mem(), &cmd);
+        let doorbell = qp.sq_doorbell();
+        self.write32(doorbell, u32::from(tail));
+        cmd.cid
+    }
+
+    /// Waits for `count` completions on the I/O or admin completion queue,
+    /// updating its head doorbell after.
+    fn wait(&mut self, io: bool, count: usize) -> Vec<Completion> {
+        let qp = if io { &mut self.io } else { &mut self.admin };
+        let qp = qp.as_mut().unwrap();
+        let comps = (0..count)
+            .map(|_| wait_for("completion", || qp.cq.pop(&self.tm.mem())))
+            .collect();
+        let (doorbell, head) = (qp.cq_doorbell(), qp.cq.head());
+        self.write32(doorbell, u32::from(head));
+        comps
+    }
+
+    /// Issues an admin command, returning its completion.
+    fn admin(&mut self, cmd: Command) -> Completion {
+        let cid = self.submit(false, cmd);
+        let comp = self.wait(false, 1)[0];
+        assert_eq!((comp.sqid, comp.cid), (0, cid));
+        comp
+    }
+
+    /// Creates the I/O completion and submission queues.
+    fn create_io_queues(&mut self) {
+        let mem = self.tm.mem();
+        let sq_base =
+            self.tm.alloc(usize::from(IO_QUEUE_SIZE) * SQE_LEN, PAGE_SIZE);
+        let cq_base =
+            self.tm.alloc(usize::from(IO_QUEUE_SIZE) * CQE_LEN, PAGE_SIZE);
+        let sq = HostSubQueue::new(sq_base, IO_QUEUE_SIZE);
+        let cq = HostCompQueue::new(&mem, cq_base, IO_QUEUE_SIZE);
+        drop(mem);
+
+        let qsize = u32::from(IO_QUEUE_SIZE - 1);
+        // Physically contiguous, interrupts enabled
+        let comp = self.admin(Command {
+            opcode: ADMIN_CREATE_IO_CQ,
+            prp1: cq_base.0,
+            cdw10: (qsize << 16) | u32::from(IO_QID),
+            cdw11: (u32::from(VEC_IO) << 16) | 0b11,
+            ..Default::default()
+        });
+        assert!(comp.is_success(), "{comp:?}");
+        // Physically contiguous, bound to the CQ of the same ID
+        let comp = self.admin(Command {
+            opcode: ADMIN_CREATE_IO_SQ,
+            prp1: sq_base.0,
+            cdw10: (qsize << 16) | u32::from(IO_QID),
+            cdw11: (u32::from(IO_QID) << 16) | 0b1,
+            ..Default::default()
+        });
+        assert!(comp.is_success(), "{comp:?}");
+
+        self.io = Some(QueuePair { qid: IO_QID, sq, cq });
+    }
+
+    /// Issues an Identify command, returning the page of data it produced.
+    fn identify(&mut self, cns: u32, nsid: u32) -> (Completion, Vec<u8>) {
+        let page = self.tm.alloc_pages(1);
+        let comp = self.admin(Command {
+            opcode: ADMIN_IDENTIFY,
+            nsid,
+            prp1: page.0,
+            cdw10: cns,
+            ..Default::default()
+        });
+        (comp, self.read_buf(page, PAGE_SIZE))
+    }
+
+    fn rw_cmd(opcode: u8, lba: u64, count: usize, buf: GuestAddr) -> Command {
+        let len = count * LBA_SZ;
+        assert!(len <= 2 * PAGE_SIZE, "PRP lists not supported here");
+        Command {
+            opcode,
+            nsid: NSID,
+            prp1: buf.0,
+            prp2: if len > PAGE_SIZE { buf.0 + PAGE_SIZE as u64 } else { 0 },
+            cdw10: lba as u32,
+            cdw11: (lba >> 32) as u32,
+            // Number of logical blocks is 0's based
+            cdw12: (count - 1) as u32,
+            ..Default::default()
+        }
+    }
+
+    fn submit_write(&mut self, lba: u64, data: &[u8]) -> u16 {
+        let pages = data.len().div_ceil(PAGE_SIZE);
+        let buf = self.tm.alloc_pages(pages);
+        assert_eq!(
+            self.tm.mem().write_from(buf, data, data.len()),
+            Some(data.len())
+        );
+        let cmd = Self::rw_cmd(NVM_WRITE, lba, data.len() / LBA_SZ, buf);
+        self.submit(true, cmd)
+    }
+
+    /// Submits a read of `count` LBAs, returning its command ID and the
+    /// buffer it will fill.
+    fn submit_read(&mut self, lba: u64, count: usize) -> (u16, GuestAddr) {
+        let buf = self.tm.alloc_pages((count * LBA_SZ).div_ceil(PAGE_SIZE));
+        let cmd = Self::rw_cmd(NVM_READ, lba, count, buf);
+        (self.submit(true, cmd), buf)
+    }
+
+    fn submit_flush(&mut self) -> u16 {
+        self.submit(
+            true,
+            Command { opcode: NVM_FLUSH, nsid: NSID, ..Default::default() },
+        )
+    }
+
+    /// Waits for the I/O commands `cids` to complete, in any order, checking
+    /// that all succeeded.
+    fn wait_io(&mut self, cids: &[u16]) {
+        let comps = self.wait(true, cids.len());
+        assert!(comps.iter().all(|c| c.is_success()), "{comps:?}");
+        let mut done: Vec<u16> = comps.iter().map(|c| c.cid).collect();
+        done.sort();
+        let mut want = cids.to_vec();
+        want.sort();
+        assert_eq!(done, want);
+    }
+
+    fn read_buf(&self, buf: GuestAddr, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        assert_eq!(self.tm.mem().read_into(buf, &mut data, len), Some(len));
+        data
+    }
+
+    fn write_lbas(&mut self, lba: u64, data: &[u8]) {
+        let cid = self.submit_write(lba, data);
+        self.wait_io(&[cid]);
+    }
+
+    fn read_lbas(&mut self, lba: u64, count: usize) -> Vec<u8> {
+        let (cid, buf) = self.submit_read(lba, count);
+        self.wait_io(&[cid]);
+        self.read_buf(buf, count * LBA_SZ)
+    }
+
+    /// Takes the MSIs sent so far, returning how many were for the I/O queue.
+    fn io_msis(&self) -> usize {
+        let msis = self.tm.take_msis();
+        assert!(
+            msis.iter().all(|m| *m == msi(VEC_IO) || *m == msi(VEC_ADMIN)),
+            "unexpected MSIs: {msis:?}"
+        );
+        msis.iter().filter(|m| **m == msi(VEC_IO)).count()
+    }
+
+    /// Checks that nothing is completed for a while.
+    fn no_completions(&mut self) {
+        std::thread::sleep(SETTLE_TIME);
+        let io = self.io.as_mut().unwrap();
+        assert!(io.cq.pop(&self.tm.mem()).is_none());
+        assert_eq!(self.io_msis(), 0);
+    }
+
+    fn full_init(&mut self) {
+        self.pci_init();
+        self.ctrl_init();
+        self.create_io_queues();
+    }
+}
+
+#[tokio::test]
+async fn init_and_io() {
+    let image = disk_image();
+    let mut guest = Guest::new(image.clone()).await;
+    guest.pci_init();
+
+    let cap = guest.read64(REG_CAP);
+    // MQES is 0's based, and the NVM command set must be supported
+    assert!((cap & 0xffff) >= u64::from(IO_QUEUE_SIZE - 1));
+    assert_ne!(cap & (1 << 37), 0);
+    assert_eq!(guest.read32(REG_VS), 0x0001_0000);
+
+    guest.ctrl_init();
+    assert!(guest.tm.take_msis().is_empty());
+
+    let (comp, ident) = guest.identify(IDENT_CNS_CONTROLLER, 0);
+    assert!(comp.is_success(), "{comp:?}");
+    assert!(guest.tm.take_msis().contains(&msi(VEC_ADMIN)));
+    let vid = u16::from_le_bytes([ident[0], ident[1]]);
+    assert_eq!(u32::from(vid), pci_cfg_read(&*guest.dev, 0, 2));
+    assert_eq!(&ident[4..4 + SERIAL.len()], SERIAL.as_bytes());
+    // SQES and CQES
+    assert_eq!((ident[512], ident[513]), (0x66, 0x44));
+    // NN
+    assert_eq!(u32::from_le_bytes(ident[516..520].try_into().unwrap()), 1);
+
+    let (comp, ident) = guest.identify(IDENT_CNS_NAMESPACE, NSID);
+    assert!(comp.is_success(), "{comp:?}");
+    // NSZE, and LBADS of LBA format 0
+    assert_eq!(
+        u64::from_le_bytes(ident[0..8].try_into().unwrap()),
+        DISK_LBAS as u64
+    );
+    assert_eq!(ident[130], LBA_SZ.trailing_zeros() as u8);
+
+    let (comp, _) = guest.identify(IDENT_CNS_NAMESPACE, 2);
+    assert_eq!(comp.status_code(), STS_INVALID_NS);
+
+    guest.create_io_queues();
+
+    // Read, within one page and spanning two
+    assert_eq!(guest.read_lbas(3, 2), image[3 * LBA_SZ..5 * LBA_SZ]);
+    assert!(guest.io_msis() > 0);
+    assert_eq!(guest.read_lbas(8, 16), image[8 * LBA_SZ..24 * LBA_SZ]);
+
+    // Write, then read it back
+    let pattern: Vec<u8> = (0..12 * LBA_SZ).map(|i| (i % 251) as u8).collect();
+    guest.write_lbas(30, &pattern);
+    assert!(guest.io_msis() > 0);
+    assert_eq!(guest.read_lbas(30, 12), pattern);
+    assert_eq!(guest.read_lbas(42, 1), image[42 * LBA_SZ..43 * LBA_SZ]);
+
+    let cid = guest.submit_flush();
+    guest.wait_io(&[cid]);
+
+    // Several commands submitted at once
+    let cids: Vec<u16> = (0..6)
+        .map(|i| guest.submit_write(50 + i, &[0x50 + i as u8; LBA_SZ]))
+        .collect();
+    guest.wait_io(&cids);
+    for i in 0..6 {
+        assert_eq!(guest.read_lbas(50 + i, 1), vec![0x50 + i as u8; LBA_SZ]);
+    }
+    assert!(guest.io_msis() > 0);
+
+    guest.backend.stop().await;
+}
+
+#[tokio::test]
+async fn reset() {
+    let mut guest = Guest::new(disk_image()).await;
+    guest.full_init();
+    guest.write_lbas(1, &[0x11; LBA_SZ]);
+
+    // Controller reset, which tears down the I/O queues
+    guest.write32(REG_CC, 0);
+    assert_eq!(guest.read32(REG_CSTS) & CSTS_RDY, 0);
+    guest.ctrl_init();
+    guest.create_io_queues();
+    assert_eq!(guest.read_lbas(1, 1), vec![0x11; LBA_SZ]);
+    assert!(guest.io_msis() > 0);
+
+    // Reset of the whole machine, after which the device must be found anew
+    guest.dev.reset();
+    assert!(guest.tm.mmio_read(REGS_BAR + REG_CSTS, 4).is_err());
+    guest.pci_init();
+    assert_eq!(guest.read32(REG_CC), 0);
+    assert_eq!(guest.read32(REG_CSTS), 0);
+    guest.ctrl_init();
+    guest.create_io_queues();
+    guest.write_lbas(2, &[0x22; LBA_SZ]);
+    assert_eq!(guest.read_lbas(2, 1), vec![0x22; LBA_SZ]);
+    assert!(guest.io_msis() > 0);
+
+    guest.backend.stop().await;
+}
+
+#[tokio::test]
+async fn pause_resume() {
+    let mut guest = Guest::new(disk_image()).await;
+    guest.full_init();
+
+    // Commands submitted while paused are left alone until resumed
+    guest.dev.pause();
+    guest.dev.paused().await;
+    let cid = guest.submit_write(5, &[0x55; LBA_SZ]);
+    guest.no_completions();
+    guest.dev.resume();
+    guest.wait_io(&[cid]);
+    assert!(guest.io_msis() > 0);
+
+    // Pausing with commands in flight lets those finish, but no more start
+    let cids: Vec<u16> = (0..8)
+        .map(|i| guest.submit_write(40 + i, &[0x40 + i as u8; LBA_SZ]))
+        .collect();
+    guest.dev.pause();
+    guest.dev.paused().await;
+    std::thread::sleep(SETTLE_TIME);
+    let io = guest.io.as_mut().unwrap();
+    let mut done = Vec::new();
+    while let Some(comp) = io.cq.pop(&guest.tm.mem()) {
+        done.push(comp);
+    }
+    guest.tm.take_msis();
+    guest.no_completions();
+    guest.dev.resume();
+    done.extend(guest.wait(true, cids.len() - done.len()));
+    assert!(done.iter().all(|c| c.is_success()), "{done:?}");
+    let mut done: Vec<u16> = done.iter().map(|c| c.cid).collect();
+    done.sort();
+    assert_eq!(done, cids);
+    for i in 0..8 {
+        assert_eq!(guest.read_lbas(40 + i, 1), vec![0x40 + i as u8; LBA_SZ]);
+    }
+
+    guest.backend.stop().await;
+}
+
+#[tokio::test]
+async fn migrate_mid_io() {
+    // The disk is shared between source and destination in a real migration.
+    // Here, each starts from the same image, and writes before the migration
+    // are not expected to carry over.
+    let image = disk_image();
+    let mut src = Guest::new(image.clone()).await;
+    src.full_init();
+    assert_eq!(src.read_lbas(7, 1), image[7 * LBA_SZ..8 * LBA_SZ]);
+    src.tm.take_msis();
+
+    // Commands are submitted, but the device is paused before it can get to
+    // them.
+    src.dev.pause();
+    let write = src.submit_write(9, &[0x99; LBA_SZ]);
+    let (read, read_buf) = src.submit_read(2, 2);
+    let flush = src.submit_flush();
+    src.dev.paused().await;
+    src.backend.stop().await;
+
+    let mut dst = Guest::new(image.clone()).await;
+    dst.dev.pause();
+    src.tm.migrate_to(&*src.dev, &dst.tm, &*dst.dev).unwrap();
+    dst.admin = src.admin.take();
+    dst.io = src.io.take();
+    dst.next_cid = src.next_cid;
+    assert_ne!(dst.read32(REG_CSTS) & CSTS_RDY, 0);
+
+    dst.dev.resume();
+    dst.wait_io(&[write, read, flush]);
+    assert_eq!(
+        dst.read_buf(read_buf, 2 * LBA_SZ),
+        image[2 * LBA_SZ..4 * LBA_SZ]
+    );
+    assert!(dst.io_msis() > 0);
+    assert!(src.tm.take_msis().is_empty());
+
+    // The controller carries on as normal on the destination
+    assert_eq!(dst.read_lbas(9, 1), vec![0x99; LBA_SZ]);
+    let (comp, _) = dst.identify(IDENT_CNS_CONTROLLER, 0);
+    assert!(comp.is_success(), "{comp:?}");
+    dst.write_lbas(12, &[0x12; LBA_SZ]);
+    assert_eq!(dst.read_lbas(12, 1), vec![0x12; LBA_SZ]);
+
+    dst.backend.stop().await;
+}
@@ -0,0 +1,468 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Drives `PciVirtioBlock` the way a guest driver would: through its legacy
+//! PCI transport, with a virtqueue in (fake) guest memory.
+
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::Duration;
+
+use propolis::block::{self, Backend};
+use propolis::common::{GuestAddr, PAGE_SIZE};
+use propolis::hw::pci;
+use propolis::hw::virtio::PciVirtioBlock;
+use propolis::lifecycle::Lifecycle;
+use propolis::testing::virtq::{Buf, DriverQueue};
+use propolis::testing::{Builder, TestMachine};
+
+mod common;
+use common::{msi, wait_for};
+
+const IO_BAR: u16 = 0xc000;
+const MSIX_BAR: u64 = 0xc000_0000;
+const RAM_SIZE: usize = 4 * 1024 * 1024;
+
+const QUEUE_SIZE: u16 = 16;
+const SECTOR_SZ: usize = 512;
+const DISK_SECTORS: usize = 64;
+
+// Legacy register offsets, relative to the I/O BAR
+const REG_FEAT_DEVICE: u16 = 0x00;
+const REG_FEAT_DRIVER: u16 = 0x04;
+const REG_QUEUE_PFN: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0c;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_STATUS: u16 = 0x12;
+const REG_MSIX_CONFIG: u16 = 0x14;
+const REG_MSIX_QUEUE: u16 = 0x16;
+/// Device-specific config, when MSI-X is enabled
+const REG_DEV_CFG: u16 = 0x18;
+
+// Offsets into virtio-block config
+const CFG_CAPACITY: u16 = 0;
+const CFG_BLK_SIZE: u16 = 20;
+
+const STATUS_ACK: u32 = 1 << 0;
+const STATUS_DRIVER: u32 = 1 << 1;
+const STATUS_DRIVER_OK: u32 = 1 << 2;
+const STATUS_FEATURES_OK: u32 = 1 << 3;
+
+const VIRTIO_BLK_F_BLK_SIZE: u32 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u32 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const VEC_CONFIG: u16 = 0;
+const VEC_QUEUE: u16 = 1;
+
+/// A request is counted as complete by the device just before it is placed
+/// in the used ring, so allow some time for stragglers to land.
+const SETTLE_TIME: Duration = Duration::from_millis(50);
+
+/// A disk image whose every sector is filled with its own index
+fn disk_image() -> Vec<u8> {
+    (0..DISK_SECTORS).flat_map(|s| [s as u8; SECTOR_SZ]).collect()
+}
+
+/// A request made available to the device
+struct Req {
+    head: u16,
+    status: GuestAddr,
+}
+
+/// A machine with a virtio-block device, and the guest driving it
+struct Guest {
+    tm: TestMachine,
+    _bus: pci::Bus,
+    dev: Arc<PciVirtioBlock>,
+    backend: Arc<block::InMemoryBackend>,
+    vq: Option<DriverQueue>,
+}
+impl Guest {
+    async fn new(disk: Vec<u8>) -> Self {
+        let tm = Builder::new().ram_size(RAM_SIZE).build().unwrap();
+        let bus = tm.pci_bus();
+
+        let dev = PciVirtioBlock::new(QUEUE_SIZE);
+        let backend = block::InMemoryBackend::create(
+            disk,
+            block::BackendOpts {
+                block_size: Some(SECTOR_SZ as u32),
+                ..Default::default()
+            },
+            NonZeroUsize::new(2).unwrap(),
+        )
+        .unwrap();
+        block::attach(dev.clone(), backend.clone()).unwrap();
+        bus.attach(pci::BusLocation::new(4, 0).unwrap(), dev.clone(), None);
+        backend.start().await.unwrap();
+
+        Self { tm, _bus: bus, dev, backend, vq: None }
+    }
+
+    fn read(&self, reg: u16, bytes: u8) -> u32 {
+        self.tm.pio_read(IO_BAR + reg, bytes).unwrap()
+    }
+
+    fn write(&self, reg: u16, bytes: u8, val: u32) {
+        self.tm.pio_write(IO_BAR + reg, bytes, val).unwrap()
+    }
+
+    fn pci_init(&self) {
+        propolis::testing::pci_init(
+            &*self.dev,
+            &[(0, u32::from(IO_BAR)), (1, MSIX_BAR as u32)],
+        );
+    }
+
+    /// Brings the device up: reset, ACKNOWLEDGE and DRIVER, feature
+    /// negotiation, FEATURES_OK, interrupt and queue setup, then DRIVER_OK.
+    /// Returns the negotiated features.
+    fn virtio_init(&mut self) -> u32 {
+        self.write(REG_STATUS, 1, 0);
+        assert_eq!(self.read(REG_STATUS, 1), 0);
+        self.write(REG_STATUS, 1, STATUS_ACK);
+        self.write(REG_STATUS, 1, STATUS_ACK | STATUS_DRIVER);
+
+        let offered = self.read(REG_FEAT_DEVICE, 4);
+        let feat = offered & (VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH);
+        self.write(REG_FEAT_DRIVER, 4, feat);
+        let status = STATUS_ACK | STATUS_DRIVER | STATUS_FEATURES_OK;
+        self.write(REG_STATUS, 1, status);
+        assert_eq!(self.read(REG_STATUS, 1), status, "features rejected");
+        assert_eq!(self.read(REG_FEAT_DRIVER, 4), feat);
+
+        common::enable_msix(&self.tm, &*self.dev, MSIX_BAR, 2);
+        self.write(REG_MSIX_CONFIG, 2, u32::from(VEC_CONFIG));
+
+        self.write(REG_QUEUE_SELECT, 2, 0);
+        let size = self.read(REG_QUEUE_SIZE, 2) as u16;
+        assert_eq!(size, QUEUE_SIZE);
+        let base = self.tm.alloc(DriverQueue::mem_len(size), PAGE_SIZE);
+        self.vq = Some(DriverQueue::new(&self.tm.mem(), base, size));
+        self.write(REG_MSIX_QUEUE, 2, u32::from(VEC_QUEUE));
+        assert_eq!(self.read(REG_MSIX_QUEUE, 2), u32::from(VEC_QUEUE));
+        self.write(REG_QUEUE_PFN, 4, (base.0 >> 12) as u32);
+        assert_eq!(self.read(REG_QUEUE_PFN, 4), (base.0 >> 12) as u32);
+
+        self.write(REG_STATUS, 1, status | STATUS_DRIVER_OK);
+        feat
+    }
+
+    fn cfg_read(&self, off: u16, bytes: u8) -> u32 {
+        self.read(REG_DEV_CFG + off, bytes)
+    }
+
+    /// Makes a request of `rtype` available, with `data` between its header
+    /// and status byte.  The device is not notified.
+    fn submit(&mut self, rtype: u32, sector: u64, data: &[Buf]) -> Req {
+        let hdr = self.tm.alloc(16, 16);
+        let status = self.tm.alloc(1, 1);
+        let mem = self.tm.mem();
+        assert!(mem.write(hdr, &rtype));
+        assert!(mem.write(GuestAddr(hdr.0 + 4), &0u32));
+        assert!(mem.write(GuestAddr(hdr.0 + 8), &sector));
+        assert!(mem.write(status, &0xffu8));
+
+        let mut bufs = vec![Buf::readable(hdr, 16)];
+        bufs.extend_from_slice(data);
+        bufs.push(Buf::writable(status, 1));
+        let head = self.vq.as_mut().unwrap().push(&mem, &bufs).unwrap();
+        Req { head, status }
+    }
+
+    fn submit_write(&mut self, sector: u64, data: &[u8]) -> Req {
+        let buf = self.tm.alloc(data.len(), SECTOR_SZ);
+        assert_eq!(
+            self.tm.mem().write_from(buf, data, data.len()),
+            Some(data.len())
+        );
+        self.submit(
+            VIRTIO_BLK_T_OUT,
+            sector,
+            &[Buf::readable(buf, data.len() as u32)],
+        )
+    }
+
+    /// Makes a read of `count` sectors available, returning the request and
+    /// the buffer it will fill.
+    fn submit_read(&mut self, sector: u64, count: usize) -> (Req, GuestAddr) {
+        let len = count * SECTOR_SZ;
+        let buf = self.tm.alloc(len, SECTOR_SZ);
+        let req = self.submit(
+            VIRTIO_BLK_T_IN,
+            sector,
+            &[Buf::writable(buf, len as u32)],
+        );
+        (req, buf)
+    }
+
+    fn notify(&self) {
+        self.write(REG_QUEUE_NOTIFY, 2, 0);
+    }
+
+    /// Waits for `reqs` to be returned through the used ring, in any order,
+    /// and checks that each completed with `expected` status.
+    fn wait(&mut self, reqs: &[&Req], expected: u8) {
+        let mut heads: Vec<u16> = (0..reqs.len())
+            .map(|_| {
+                wait_for("used ring entry", || {
+                    self.vq.as_mut().unwrap().pop_used(&self.tm.mem())
+                })
+                .id
+            })
+            .collect();
+        heads.sort();
+        let mut want: Vec<u16> = reqs.iter().map(|r| r.head).collect();
+        want.sort();
+        assert_eq!(heads, want);
+
+        let mem = self.tm.mem();
+        for req in reqs {
+            assert_eq!(mem.read::<u8>(req.status), Some(expected));
+        }
+    }
+
+    fn read_buf(&self, buf: GuestAddr, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        assert_eq!(self.tm.mem().read_into(buf, &mut data, len), Some(len));
+        data
+    }
+
+    fn write_sectors(&mut self, sector: u64, data: &[u8]) {
+        let req = self.submit_write(sector, data);
+        self.notify();
+        self.wait(&[&req], VIRTIO_BLK_S_OK);
+    }
+
+    fn read_sectors(&mut self, sector: u64, count: usize) -> Vec<u8> {
+        let (req, buf) = self.submit_read(sector, count);
+        self.notify();
+        self.wait(&[&req], VIRTIO_BLK_S_OK);
+        self.read_buf(buf, count * SECTOR_SZ)
+    }
+
+    /// Takes the MSIs sent so far, all of which should be for the queue,
+    /// returning how many there were.
+    fn queue_msis(&self) -> usize {
+        let msis = self.tm.take_msis();
+        assert!(
+            msis.iter().all(|m| *m == msi(VEC_QUEUE)),
+            "unexpected MSIs: {msis:?}"
+        );
+        msis.len()
+    }
+
+    /// Checks that nothing is completed for a while.
+    fn no_completions(&mut self) {
+        std::thread::sleep(SETTLE_TIME);
+        assert!(self.vq.as_mut().unwrap().pop_used(&self.tm.mem()).is_none());
+        assert_eq!(self.queue_msis(), 0);
+    }
+}
+
+#[tokio::test]
+async fn init_and_io() {
+    let image = disk_image();
+    let mut guest = Guest::new(image.clone()).await;
+    guest.pci_init();
+    let feat = guest.virtio_init();
+    assert_eq!(feat, VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH);
+
+    let capacity = u64::from(guest.cfg_read(CFG_CAPACITY, 4))
+        | (u64::from(guest.cfg_read(CFG_CAPACITY + 4, 4)) << 32);
+    assert_eq!(capacity, DISK_SECTORS as u64);
+    assert_eq!(guest.cfg_read(CFG_BLK_SIZE, 4), SECTOR_SZ as u32);
+
+    // Read
+    let data = guest.read_sectors(3, 2);
+    assert_eq!(data, image[3 * SECTOR_SZ..5 * SECTOR_SZ]);
+    assert!(guest.queue_msis() > 0);
+
+    // Read scattered across multiple descriptors
+    let bufs = [
+        guest.tm.alloc(SECTOR_SZ, SECTOR_SZ),
+        guest.tm.alloc(SECTOR_SZ, SECTOR_SZ),
+    ];
+    let req = guest.submit(
+        VIRTIO_BLK_T_IN,
+        10,
+        &[
+            Buf::writable(bufs[0], SECTOR_SZ as u32),
+            Buf::writable(bufs[1], SECTOR_SZ as u32),
+        ],
+    );
+    guest.notify();
+    guest.wait(&[&req], VIRTIO_BLK_S_OK);
+    assert_eq!(guest.read_buf(bufs[0], SECTOR_SZ), vec![10u8; SECTOR_SZ]);
+    assert_eq!(guest.read_buf(bufs[1], SECTOR_SZ), vec![11u8; SECTOR_SZ]);
+
+    // Write, then read it back
+    let pattern: Vec<u8> = (0..2 * SECTOR_SZ).map(|i| i as u8 ^ 0xa5).collect();
+    guest.write_sectors(20, &pattern);
+    assert!(guest.queue_msis() > 0);
+    assert_eq!(guest.read_sectors(20, 2), pattern);
+    assert_eq!(
+        guest.read_sectors(22, 1),
+        image[22 * SECTOR_SZ..23 * SECTOR_SZ]
+    );
+
+    // Flush
+    let req = guest.submit(VIRTIO_BLK_T_FLUSH, 0, &[]);
+    guest.notify();
+    guest.wait(&[&req], VIRTIO_BLK_S_OK);
+
+    // Several requests made available at once
+    let writes: Vec<Req> = (0..4)
+        .map(|i| guest.submit_write(30 + i, &[0x30 + i as u8; SECTOR_SZ]))
+        .collect();
+    guest.notify();
+    guest.wait(&writes.iter().collect::<Vec<_>>(), VIRTIO_BLK_S_OK);
+    for i in 0..4 {
+        assert_eq!(
+            guest.read_sectors(30 + i, 1),
+            vec![0x30 + i as u8; SECTOR_SZ]
+        );
+    }
+
+    // Unsupported request types are failed by the device itself
+    let id = guest.tm.alloc(20, 1);
+    let req = guest.submit(VIRTIO_BLK_T_GET_ID, 0, &[Buf::writable(id, 20)]);
+    guest.notify();
+    guest.wait(&[&req], VIRTIO_BLK_S_UNSUPP);
+
+    guest.backend.stop().await;
+}
+
+#[tokio::test]
+async fn reset() {
+    let mut guest = Guest::new(disk_image()).await;
+    guest.pci_init();
+    guest.virtio_init();
+    guest.write_sectors(1, &[0x11; SECTOR_SZ]);
+
+    // Driver-initiated reset of the device
+    guest.write(REG_STATUS, 1, 0);
+    assert_eq!(guest.read(REG_STATUS, 1), 0);
+    assert_eq!(guest.read(REG_QUEUE_PFN, 4), 0);
+    guest.virtio_init();
+    assert_eq!(guest.read_sectors(1, 1), vec![0x11; SECTOR_SZ]);
+    assert!(guest.queue_msis() > 0);
+
+    // Reset of the whole machine, after which the device must be found anew
+    guest.dev.reset();
+    assert!(guest.tm.pio_read(IO_BAR + REG_STATUS, 1).is_err());
+    guest.pci_init();
+    assert_eq!(guest.read(REG_STATUS, 1), 0);
+    guest.virtio_init();
+    guest.write_sectors(2, &[0x22; SECTOR_SZ]);
+    assert_eq!(guest.read_sectors(2, 1), vec![0x22; SECTOR_SZ]);
+    assert!(guest.queue_msis() > 0);
+
+    guest.backend.stop().await;
+}
+
+#[tokio::test]
+async fn pause_resume() {
+    let mut guest = Guest::new(disk_image()).await;
+    guest.pci_init();
+    guest.virtio_init();
+
+    // Requests made available while paused are left alone until resumed
+    guest.dev.pause();
+    guest.dev.paused().await;
+    let req = guest.submit_write(5, &[0x55; SECTOR_SZ]);
+    guest.notify();
+    guest.no_completions();
+    guest.dev.resume();
+    guest.wait(&[&req], VIRTIO_BLK_S_OK);
+    assert!(guest.queue_msis() > 0);
+
+    // Pausing with requests in flight lets those finish, but no more start
+    let writes: Vec<Req> = (0..8)
+        .map(|i| guest.submit_write(40 + i, &[0x40 + i as u8; SECTOR_SZ]))
+        .collect();
+    guest.notify();
+    guest.dev.pause();
+    guest.dev.paused().await;
+    std::thread::sleep(SETTLE_TIME);
+    let mut done = 0;
+    while guest.vq.as_mut().unwrap().pop_used(&guest.tm.mem()).is_some() {
+        done += 1;
+    }
+    guest.tm.take_msis();
+    guest.no_completions();
+    guest.dev.resume();
+    for _ in done..writes.len() {
+        wait_for("used ring entry", || {
+            guest.vq.as_mut().unwrap().pop_used(&guest.tm.mem())
+        });
+    }
+    let mem = guest.tm.mem();
+    for req in &writes {
+        assert_eq!(mem.read::<u8>(req.status), Some(VIRTIO_BLK_S_OK));
+    }
+    drop(mem);
+    for i in 0..8 {
+        assert_eq!(
+            guest.read_sectors(40 + i, 1),
+            vec![0x40 + i as u8; SECTOR_SZ]
+        );
+    }
+
+    guest.backend.stop().await;
+}
+
+#[tokio::test]
+async fn migrate_mid_io() {
+    // The disk is shared between source and destination in a real migration.
+    // Here, each starts from the same image, and writes before the migration
+    // are not expected to carry over.
+    let image = disk_image();
+    let mut src = Guest::new(image.clone()).await;
+    src.pci_init();
+    src.virtio_init();
+    assert_eq!(src.read_sectors(7, 1), image[7 * SECTOR_SZ..8 * SECTOR_SZ]);
+    src.tm.take_msis();
+
+    // Requests are made available, but the device is paused before it can
+    // get to them.
+    src.dev.pause();
+    let write = src.submit_write(9, &[0x99; SECTOR_SZ]);
+    let (read, read_buf) = src.submit_read(2, 2);
+    let flush = src.submit(VIRTIO_BLK_T_FLUSH, 0, &[]);
+    src.notify();
+    src.dev.paused().await;
+    src.backend.stop().await;
+
+    let mut dst = Guest::new(image.clone()).await;
+    dst.dev.pause();
+    src.tm.migrate_to(&*src.dev, &dst.tm, &*dst.dev).unwrap();
+    dst.vq = src.vq.take();
+    assert_eq!(dst.read(REG_STATUS, 1) & STATUS_DRIVER_OK, STATUS_DRIVER_OK);
+
+    dst.dev.resume();
+    dst.wait(&[&write, &read, &flush], VIRTIO_BLK_S_OK);
+    assert_eq!(
+        dst.read_buf(read_buf, 2 * SECTOR_SZ),
+        image[2 * SECTOR_SZ..4 * SECTOR_SZ]
+    );
+    assert!(dst.queue_msis() > 0);
+    assert!(src.tm.take_msis().is_empty());
+
+    // The device carries on as normal on the destination
+    assert_eq!(dst.read_sectors(9, 1), vec![0x99; SECTOR_SZ]);
+    dst.write_sectors(12, &[0x12; SECTOR_SZ]);
+    assert_eq!(dst.read_sectors(12, 1), vec![0x12; SECTOR_SZ]);
+
+    dst.backend.stop().await;
+}
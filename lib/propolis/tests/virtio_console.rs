@@ -0,0 +1,216 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Drives `PciVirtioConsole` the way a guest driver would: through its legacy
+//! PCI transport, with virtqueues in (fake) guest memory.
+
+use std::sync::Arc;
+
+use propolis::chardev::{Sink, Source};
+use propolis::common::{GuestAddr, PAGE_SIZE};
+use propolis::hw::pci;
+use propolis::hw::virtio::PciVirtioConsole;
+use propolis::lifecycle::Lifecycle;
+use propolis::testing::virtq::{Buf, DriverQueue};
+use propolis::testing::{Builder, TestMachine};
+
+mod common;
+use common::wait_for;
+
+const IO_BAR: u16 = 0xc000;
+const MSIX_BAR: u64 = 0xc000_0000;
+const RAM_SIZE: usize = 4 * 1024 * 1024;
+
+const QUEUE_SIZE: u16 = 16;
+/// Receive and transmit queues of the first port, and the control queues
+const QUEUE_COUNT: u16 = 4;
+
+/// Matches the device's per-port buffering in each direction
+const PORT_BUF_SIZE: usize = 0x4000;
+
+// Legacy register offsets, relative to the I/O BAR
+const REG_FEAT_DRIVER: u16 = 0x04;
+const REG_QUEUE_PFN: u16 = 0x08;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_STATUS: u16 = 0x12;
+
+const STATUS_ACK: u32 = 1 << 0;
+const STATUS_DRIVER: u32 = 1 << 1;
+const STATUS_DRIVER_OK: u32 = 1 << 2;
+const STATUS_FEATURES_OK: u32 = 1 << 3;
+
+const RXQ: u16 = 0;
+const TXQ: u16 = 1;
+
+/// A machine with a single-port virtio-console device, and the guest driving
+/// its first port without multiport support
+struct Guest {
+    tm: TestMachine,
+    _bus: pci::Bus,
+    dev: Arc<PciVirtioConsole>,
+    rxq: Option<DriverQueue>,
+    txq: Option<DriverQueue>,
+}
+impl Guest {
+    fn new() -> Self {
+        let tm = Builder::new().ram_size(RAM_SIZE).build().unwrap();
+        let bus = tm.pci_bus();
+
+        let dev = PciVirtioConsole::new(QUEUE_SIZE, &["port0"]).unwrap();
+        bus.attach(pci::BusLocation::new(4, 0).unwrap(), dev.clone(), None);
+        // Keep what the guest writes, rather than discarding it
+        dev.ports()[0].set_autodiscard(false);
+
+        Self { tm, _bus: bus, dev, rxq: None, txq: None }
+    }
+
+    fn write(&self, reg: u16, bytes: u8, val: u32) {
+        self.tm.pio_write(IO_BAR + reg, bytes, val).unwrap()
+    }
+
+    fn read(&self, reg: u16, bytes: u8) -> u32 {
+        self.tm.pio_read(IO_BAR + reg, bytes).unwrap()
+    }
+
+    fn init(&mut self) {
+        propolis::testing::pci_init(
+            &*self.dev,
+            &[(0, u32::from(IO_BAR)), (1, MSIX_BAR as u32)],
+        );
+
+        self.write(REG_STATUS, 1, 0);
+        self.write(REG_STATUS, 1, STATUS_ACK | STATUS_DRIVER);
+        self.write(REG_FEAT_DRIVER, 4, 0);
+        let status = STATUS_ACK | STATUS_DRIVER | STATUS_FEATURES_OK;
+        self.write(REG_STATUS, 1, status);
+
+        common::enable_msix(&self.tm, &*self.dev, MSIX_BAR, QUEUE_COUNT + 1);
+        self.rxq = Some(self.setup_queue(RXQ));
+        self.txq = Some(self.setup_queue(TXQ));
+
+        self.write(REG_STATUS, 1, status | STATUS_DRIVER_OK);
+    }
+
+    fn setup_queue(&self, qid: u16) -> DriverQueue {
+        self.write(REG_QUEUE_SELECT, 2, u32::from(qid));
+        let base = self.tm.alloc(DriverQueue::mem_len(QUEUE_SIZE), PAGE_SIZE);
+        let vq = DriverQueue::new(&self.tm.mem(), base, QUEUE_SIZE);
+        self.write(REG_QUEUE_PFN, 4, (base.0 >> 12) as u32);
+        vq
+    }
+
+    /// Makes `data` available in a transmit buffer, and notifies the device.
+    fn transmit(&mut self, data: &[u8]) -> u16 {
+        let buf = self.tm.alloc(data.len(), 1);
+        assert_eq!(
+            self.tm.mem().write_from(buf, data, data.len()),
+            Some(data.len())
+        );
+        let head = self
+            .txq
+            .as_mut()
+            .unwrap()
+            .push(&self.tm.mem(), &[Buf::readable(buf, data.len() as u32)])
+            .unwrap();
+        self.write(REG_QUEUE_NOTIFY, 2, u32::from(TXQ));
+        head
+    }
+
+    /// Makes a receive buffer of `len` bytes available, and notifies the
+    /// device.
+    fn receive(&mut self, len: usize) -> GuestAddr {
+        let buf = self.tm.alloc(len, 1);
+        self.rxq
+            .as_mut()
+            .unwrap()
+            .push(&self.tm.mem(), &[Buf::writable(buf, len as u32)])
+            .unwrap();
+        self.write(REG_QUEUE_NOTIFY, 2, u32::from(RXQ));
+        buf
+    }
+
+    fn read_buf(&self, buf: GuestAddr, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        assert_eq!(self.tm.mem().read_into(buf, &mut data, len), Some(len));
+        data
+    }
+}
+
+/// Reads everything the guest has written to the port so far.
+fn drain(port: &dyn Source) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        let len = port.read_bytes(&mut buf);
+        if len == 0 {
+            return data;
+        }
+        data.extend_from_slice(&buf[..len]);
+    }
+}
+
+#[test]
+fn transmit_and_receive() {
+    let mut guest = Guest::new();
+    guest.init();
+    let port = guest.dev.ports()[0].clone();
+    assert!(port.guest_connected());
+
+    let head = guest.transmit(b"hello");
+    let used = wait_for("used ring entry", || {
+        guest.txq.as_mut().unwrap().pop_used(&guest.tm.mem())
+    });
+    assert_eq!(used.id, head);
+    assert_eq!(drain(&*port), b"hello");
+
+    assert_eq!(port.write_bytes(b"world"), 5);
+    let buf = guest.receive(16);
+    let used = wait_for("used ring entry", || {
+        guest.rxq.as_mut().unwrap().pop_used(&guest.tm.mem())
+    });
+    assert_eq!(used.len, 5);
+    assert_eq!(guest.read_buf(buf, 5), b"world");
+}
+
+#[test]
+fn migrate_mid_transmit() {
+    let mut src = Guest::new();
+    src.init();
+    let src_port = src.dev.ports()[0].clone();
+
+    // More data than the port can hold, so the device is left holding the
+    // guest's buffer with some of it still to be taken.
+    let data: Vec<u8> =
+        (0..PORT_BUF_SIZE + 100).map(|i| (i % 251) as u8).collect();
+    let head = src.transmit(&data);
+    assert!(src.txq.as_mut().unwrap().pop_used(&src.tm.mem()).is_none());
+
+    // Data for the guest, with no receive buffers for it to go into
+    assert_eq!(src_port.write_bytes(b"to the guest"), 12);
+
+    src.dev.pause();
+    let mut dst = Guest::new();
+    dst.dev.pause();
+    src.tm.migrate_to(&*src.dev, &dst.tm, &*dst.dev).unwrap();
+    dst.rxq = src.rxq.take();
+    dst.txq = src.txq.take();
+    assert_eq!(dst.read(REG_STATUS, 1) & STATUS_DRIVER_OK, STATUS_DRIVER_OK);
+    dst.dev.resume();
+
+    let dst_port = dst.dev.ports()[0].clone();
+    assert!(dst_port.guest_connected());
+    assert_eq!(drain(&*dst_port), data);
+    let used = wait_for("used ring entry", || {
+        dst.txq.as_mut().unwrap().pop_used(&dst.tm.mem())
+    });
+    assert_eq!(used.id, head);
+
+    let buf = dst.receive(16);
+    let used = wait_for("used ring entry", || {
+        dst.rxq.as_mut().unwrap().pop_used(&dst.tm.mem())
+    });
+    assert_eq!(used.len, 12);
+    assert_eq!(dst.read_buf(buf, 12), b"to the guest");
+}
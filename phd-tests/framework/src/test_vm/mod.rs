@@ -311,6 +311,7 @@ impl TestVm {
             properties,
             instance_spec: versioned_spec,
             migrate,
+            restore: None,
         };
 
         // There is a brief period where the Propolis server process has begun
@@ -60,6 +60,15 @@ pub(crate) fn cmd_clippy(strict: bool, quiet: bool) -> Result<()> {
     failed |=
         run_clippy(&["-p", "propolis-standalone", "--features", "crucible"])?;
 
+    // Check the device-model test harness and the suites built atop it
+    failed |= run_clippy(&[
+        "-p",
+        "propolis",
+        "--features",
+        "testing",
+        "--all-targets",
+    ])?;
+
     // Check PHD bits
     failed |= run_clippy(&["-p", "phd-runner"])?;
 